@@ -11,7 +11,7 @@ pub struct Key {
     pub sign_pk: CryptoSignPk,
 }
 
-#[derive(Default)]
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
 pub struct PublicKey {
     pub box_pk: CryptoBoxPk,
     pub sign_pk: CryptoSignPk,
@@ -32,7 +32,7 @@ impl Key {
         }
     }
 
-    pub fn write(&self, w: &mut std::io::Write) -> Result<(), std::io::Error> {
+    pub fn write(&self, w: &mut dyn std::io::Write) -> Result<(), std::io::Error> {
         write_header(w, KEYHEADER)?;
         w.write_all(&self.box_pk.bytes)?;
         w.write_all(&self.box_sk.bytes)?;
@@ -41,7 +41,7 @@ impl Key {
         Ok(())
     }
 
-    pub fn read_boxed_from(r: &mut std::io::Read) -> Result<Box<Key>, AsymcryptError> {
+    pub fn read_boxed_from(r: &mut dyn std::io::Read) -> Result<Box<Key>, AsymcryptError> {
         expect_header(r, KEYHEADER)?;
         let mut k = Box::<Key>::new(Default::default());
         r.read_exact(&mut k.box_pk.bytes)?;
@@ -53,12 +53,20 @@ impl Key {
 }
 
 impl PublicKey {
-    pub fn write(&self, w: &mut std::io::Write) -> Result<(), std::io::Error> {
+    pub fn write(&self, w: &mut dyn std::io::Write) -> Result<(), std::io::Error> {
         write_header(w, PUBKEYHEADER)?;
         w.write_all(&self.box_pk.bytes)?;
         w.write_all(&self.sign_pk.bytes)?;
         Ok(())
     }
+
+    pub fn read_from(r: &mut dyn std::io::Read) -> Result<PublicKey, AsymcryptError> {
+        expect_header(r, PUBKEYHEADER)?;
+        let mut k: PublicKey = Default::default();
+        r.read_exact(&mut k.box_pk.bytes)?;
+        r.read_exact(&mut k.sign_pk.bytes)?;
+        Ok(k)
+    }
 }
 
 #[derive(Debug)]
@@ -101,7 +109,7 @@ impl fmt::Display for AsymcryptError {
 }
 
 impl error::Error for AsymcryptError {
-    fn cause(&self) -> Option<&error::Error> {
+    fn cause(&self) -> Option<&dyn error::Error> {
         match *self {
             AsymcryptError::IOError(ref e) => Some(e),
             _ => None,
@@ -142,7 +150,7 @@ fn be_bytes_to_u16(hi: u8, lo: u8) -> u16 {
 const MAGIC_LEN: usize = 9;
 
 fn write_header(
-    w: &mut std::io::Write,
+    w: &mut dyn std::io::Write,
     val_type: AsymcryptHeaderType,
 ) -> Result<(), std::io::Error> {
     let magic = "asymcrypt";
@@ -154,7 +162,7 @@ fn write_header(
     w.write_all(&ver_and_val[..])
 }
 
-fn read_header(r: &mut std::io::Read) -> Result<AsymcryptHeaderType, AsymcryptError> {
+fn read_header(r: &mut dyn std::io::Read) -> Result<AsymcryptHeaderType, AsymcryptError> {
     let magic = "asymcrypt";
     let mut magic_buf: [u8; MAGIC_LEN] = [0; MAGIC_LEN];
     assert!(MAGIC_LEN == magic.len());
@@ -177,7 +185,7 @@ fn read_header(r: &mut std::io::Read) -> Result<AsymcryptHeaderType, AsymcryptEr
 }
 
 fn expect_header(
-    r: &mut std::io::Read,
+    r: &mut dyn std::io::Read,
     val_type: AsymcryptHeaderType,
 ) -> Result<(), AsymcryptError> {
     let read_val_type = read_header(r)?;
@@ -188,8 +196,11 @@ fn expect_header(
     }
 }
 
-fn read_exact_or_eof(r: &mut std::io::Read, buf: &mut [u8]) -> Result<usize, std::io::Error> {
-    let n: usize = 0;
+fn read_exact_or_eof(
+    r: &mut dyn std::io::Read,
+    mut buf: &mut [u8],
+) -> Result<usize, std::io::Error> {
+    let mut n: usize = 0;
     loop {
         match r.read(buf)? {
             0 => return Ok(n),
@@ -202,9 +213,9 @@ fn read_exact_or_eof(r: &mut std::io::Read, buf: &mut [u8]) -> Result<usize, std
 }
 
 fn encrypt(
-    in_data: &mut std::io::Read,
-    out_data: &mut std::io::Write,
-    toKey: &PublicKey,
+    in_data: &mut dyn std::io::Read,
+    out_data: &mut dyn std::io::Write,
+    to_key: &PublicKey,
 ) -> Result<(), std::io::Error> {
     const READ_SZ: usize = 16384;
     const BUF_SZ: usize = READ_SZ + CRYPTO_BOX_ZEROBYTES + 2;
@@ -219,7 +230,7 @@ fn encrypt(
     out_data.write_all(&nonce.bytes)?;
 
     loop {
-        match read_exact_or_eof(&mut in_data, &mut plain_text[CRYPTO_BOX_ZEROBYTES + 2..])? {
+        match read_exact_or_eof(in_data, &mut plain_text[CRYPTO_BOX_ZEROBYTES + 2..])? {
             0 => {
                 break;
             }
@@ -232,10 +243,10 @@ fn encrypt(
                     &mut cipher_text,
                     &plain_text,
                     &nonce,
-                    &toKey.box_pk,
+                    &to_key.box_pk,
                     &ephemeral_sk,
                 );
-                out_data.write_all(&mut cipher_text[CRYPTO_BOX_BOXZEROBYTES..])?;
+                out_data.write_all(&cipher_text[CRYPTO_BOX_BOXZEROBYTES..])?;
             }
         }
 
@@ -0,0 +1,306 @@
+//! The repository access list, per client capabilities for `serve`.
+//!
+//! `serve` knows each session's client by the name the transport gives it,
+//! see `ServeOptions::client`. Once an access list is stored, every request
+//! of a session is checked against the capabilities listed for its client,
+//! and a client not listed, or a session without a client, can do nothing:
+//!
+//! ```text
+//! put-only       store objects, indexes and manifests, and take locks,
+//!                nothing replaced
+//! list-metadata  list keys, read sizes and everything but data
+//! fetch-data     read packs, loose objects, parity and cold storage
+//!                marks, and thaw them
+//! prune          delete, and sweep a keep list, see `keeplist`
+//! admin          all of the above, replace the policy, access list and
+//!                revocations, and delete them, the log, the config or
+//!                the owner key
+//! ```
+//!
+//! Every listed client may read the config, the owner key, the policy and
+//! the access list, which it needs to open the repository, release the
+//! locks it took, and ask which objects are stored. A backup client needs
+//! no more than put-only and list-metadata, so its key can neither read
+//! old data back nor destroy it.
+//!
+//! Only prune and admin clients may overwrite what is stored, when append
+//! only mode allows it, or release the locks of other clients, such as an
+//! exclusive lock taken by `gc`. The server records who took each lock
+//! under `lock-owners`, a key no client may write. Only an admin client may
+//! replace the owner key, and only with one of the same signing key, see
+//! `rotate`, the config is never replaced.
+//!
+//! A client may also be given a signing key. A server started to
+//! authenticate clients, see `ServeOptions::authenticate`, knows a session's
+//! client only once it has signed a challenge with that key, see
+//! `protocol`, for transports such as a local socket that do not name the
+//! client themselves.
+//!
+//! Like the policy the access list is signed by the maintenance key named
+//! in the config, and carries a serial that must increase with each
+//! replacement. Without an access list every client may do everything,
+//! subject to append only mode and quotas as before, unless the server
+//! authenticates clients, when nothing is allowed.
+//!
+//! Format (inside the signature envelope, see `signed`):
+//!
+//! ```text
+//! "PNBACL" u16:format_version [16]:repo_id u64:serial
+//! u32:n n * (str:client u32:capabilities bool:has_key [32]:sign_pk)
+//! ```
+//!
+//! Clients are sorted by name. `capabilities` has bit 0 for put-only, 1
+//! list-metadata, 2 fetch-data, 3 prune and 4 admin, the other bits are 0.
+//! `sign_pk` is all zero unless `has_key` is set.
+
+use super::config::RepoConfig;
+use super::manifest::RepoId;
+use super::signed;
+use super::usage::usage_key;
+use super::wire::{Decoder, Encoder};
+use super::{Repo, RepoError, ACL_FILE};
+use asymcrypt::Key;
+use std::collections::BTreeMap;
+use std::io;
+use tweetnacl::*;
+
+pub const ACL_FORMAT_VERSION: u16 = 1;
+const ACL_MAGIC: &[u8] = b"PNBACL";
+
+pub const CAP_PUT: u32 = 1;
+pub const CAP_LIST: u32 = 2;
+pub const CAP_FETCH: u32 = 4;
+pub const CAP_PRUNE: u32 = 8;
+pub const CAP_ADMIN: u32 = 16;
+const CAP_ALL: u32 = CAP_PUT | CAP_LIST | CAP_FETCH | CAP_PRUNE | CAP_ADMIN;
+
+const CAP_NAMES: &[(&str, u32)] = &[
+    ("put-only", CAP_PUT),
+    ("list-metadata", CAP_LIST),
+    ("fetch-data", CAP_FETCH),
+    ("prune", CAP_PRUNE),
+    ("admin", CAP_ADMIN),
+];
+
+// Smallest encoded client entry.
+const MIN_ENTRY_SZ: usize = 42;
+
+// Capabilities from a list such as "put-only,list-metadata".
+pub fn parse_capabilities(s: &str) -> Result<u32, RepoError> {
+    let mut caps = 0;
+    for name in s.split(',').map(|n| n.trim()).filter(|n| !n.is_empty()) {
+        match CAP_NAMES.iter().find(|(n, _)| *n == name) {
+            Some((_, cap)) => caps |= cap,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unknown capability '{}'", name),
+                )
+                .into())
+            }
+        }
+    }
+    Ok(caps)
+}
+
+pub fn capability_names(caps: u32) -> Vec<&'static str> {
+    CAP_NAMES
+        .iter()
+        .filter(|(_, cap)| caps & cap != 0)
+        .map(|(n, _)| *n)
+        .collect()
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Acl {
+    pub repo_id: RepoId,
+    pub serial: u64,
+    pub clients: BTreeMap<String, u32>,
+    // Keys clients authenticate with, only for clients listed.
+    pub keys: BTreeMap<String, CryptoSignPk>,
+}
+
+impl Acl {
+    // Whether `client` may do what needs `caps`, admin allows anything.
+    pub fn allows(&self, client: Option<&str>, caps: u32) -> bool {
+        match client.and_then(|c| self.clients.get(c)) {
+            Some(have) => have & CAP_ADMIN != 0 || have & caps == caps,
+            None => false,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.fixed(ACL_MAGIC)
+            .u16(ACL_FORMAT_VERSION)
+            .fixed(&self.repo_id.bytes)
+            .u64(self.serial)
+            .u32(self.clients.len() as u32);
+        for (client, caps) in self.clients.iter() {
+            let key = self.keys.get(client);
+            e.str(client)
+                .u32(*caps)
+                .bool(key.is_some())
+                .fixed(&key.cloned().unwrap_or_default().bytes);
+        }
+        e.into_vec()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Acl, RepoError> {
+        let mut d = Decoder::new(buf);
+        if d.fixed(ACL_MAGIC.len())? != ACL_MAGIC {
+            return Err(RepoError::InvalidDataError);
+        }
+        if d.u16()? != ACL_FORMAT_VERSION {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        let mut repo_id: RepoId = Default::default();
+        d.fixed_into(&mut repo_id.bytes)?;
+        let serial = d.u64()?;
+        let n = d.count(MIN_ENTRY_SZ)?;
+        let mut clients = BTreeMap::new();
+        let mut keys = BTreeMap::new();
+        for _ in 0..n {
+            let client = d.str()?.to_string();
+            let caps = d.u32()?;
+            let has_key = d.bool()?;
+            let mut pk: CryptoSignPk = Default::default();
+            d.fixed_into(&mut pk.bytes)?;
+            if has_key {
+                keys.insert(client.clone(), pk);
+            }
+            if caps & !CAP_ALL != 0 || clients.insert(client, caps).is_some() {
+                return Err(RepoError::InvalidDataError);
+            }
+        }
+        d.finish()?;
+        Ok(Acl {
+            repo_id,
+            serial,
+            clients,
+            keys,
+        })
+    }
+
+    pub fn sign(&self, sk: &CryptoSignSk) -> Vec<u8> {
+        signed::sign(&self.encode(), sk)
+    }
+
+    pub fn open(sm: &[u8], pk: &CryptoSignPk) -> Result<Acl, RepoError> {
+        Acl::decode(&signed::open(sm, pk)?)
+    }
+
+    // Open a stored access list for the repository described by `config`.
+    pub fn open_for(sm: &[u8], config: &RepoConfig) -> Result<Acl, RepoError> {
+        let pk = match config.maintenance_pk {
+            Some(ref pk) => pk,
+            None => return Err(RepoError::SignatureFailedError),
+        };
+        let acl = Acl::open(sm, pk)?;
+        if acl.repo_id != config.repo_id {
+            return Err(RepoError::RepoMismatchError);
+        }
+        Ok(acl)
+    }
+}
+
+impl Repo {
+    // The stored access list, None if there is none.
+    pub fn acl(&self) -> Result<Option<Acl>, RepoError> {
+        match self.raw.get(ACL_FILE) {
+            Ok(sm) => Ok(Some(Acl::open_for(&sm, &self.config)?)),
+            Err(ref e) if e.is_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Replace the access list with one granting `clients` their
+    // capabilities, and `keys` to authenticate with. Only the maintenance
+    // key named in the config can sign it.
+    pub fn set_acl(
+        &self,
+        clients: BTreeMap<String, u32>,
+        keys: BTreeMap<String, CryptoSignPk>,
+        maintenance: &Key,
+    ) -> Result<Acl, RepoError> {
+        for (client, caps) in clients.iter() {
+            usage_key(client)?;
+            if caps & !CAP_ALL != 0 {
+                return Err(RepoError::InvalidDataError);
+            }
+        }
+        if let Some(client) = keys.keys().find(|c| !clients.contains_key(*c)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("client '{}' has a key but is not listed", client),
+            )
+            .into());
+        }
+        let acl = Acl {
+            repo_id: self.config.repo_id,
+            serial: self.acl()?.map_or(0, |acl| acl.serial) + 1,
+            clients,
+            keys,
+        };
+        let sm = acl.sign(&maintenance.sign_sk);
+        // Refuse to store an access list nobody will accept.
+        Acl::open_for(&sm, &self.config)?;
+        self.raw.put(ACL_FILE, &sm)?;
+        Ok(acl)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_acl() {
+    assert_eq!(
+        parse_capabilities("put-only, list-metadata").unwrap(),
+        CAP_PUT | CAP_LIST
+    );
+    assert!(parse_capabilities("put-only,delete").is_err());
+    assert_eq!(
+        capability_names(CAP_FETCH | CAP_PRUNE),
+        vec!["fetch-data", "prune"]
+    );
+
+    let key = Key::new();
+    let maintenance = Key::new();
+    let config = RepoConfig {
+        maintenance_pk: Some(maintenance.sign_pk.clone()),
+        ..Default::default()
+    };
+    let storage = std::sync::Arc::new(super::storage::mem::MemStorage::new());
+    let r = Repo::init(storage, config, &key).unwrap();
+    assert_eq!(r.acl().unwrap(), None);
+    let mut clients = BTreeMap::new();
+    clients.insert("laptop".to_string(), CAP_PUT | CAP_LIST);
+    clients.insert("ops".to_string(), CAP_ADMIN);
+    let mut keys = BTreeMap::new();
+    keys.insert("laptop".to_string(), key.sign_pk.clone());
+    r.set_acl(clients.clone(), BTreeMap::new(), &maintenance)
+        .unwrap();
+    let acl = r.set_acl(clients.clone(), keys, &maintenance).unwrap();
+    assert_eq!(r.acl().unwrap().as_ref(), Some(&acl));
+    assert_eq!(acl.serial, 2);
+    assert_eq!(acl.keys.get("laptop"), Some(&key.sign_pk));
+    assert!(acl.allows(Some("laptop"), CAP_PUT) && acl.allows(Some("laptop"), 0));
+    assert!(!acl.allows(Some("laptop"), CAP_PUT | CAP_PRUNE));
+    assert!(acl.allows(Some("ops"), CAP_PRUNE));
+    assert!(!acl.allows(Some("stranger"), 0) && !acl.allows(None, 0));
+
+    assert!(Acl::decode(&acl.encode()[..acl.encode().len() - 1]).is_err());
+
+    match Acl::open(&acl.sign(&key.sign_sk), &maintenance.sign_pk) {
+        Err(RepoError::SignatureFailedError) => (),
+        _ => panic!("expected the signature to fail"),
+    }
+    let mut bad = BTreeMap::new();
+    bad.insert("a/b".to_string(), CAP_PUT);
+    assert!(r.set_acl(bad, BTreeMap::new(), &maintenance).is_err());
+    let mut stray = BTreeMap::new();
+    stray.insert("stranger".to_string(), key.sign_pk.clone());
+    assert!(r.set_acl(clients, stray, &maintenance).is_err());
+    // Only the maintenance key signs access lists.
+    assert!(r.set_acl(BTreeMap::new(), BTreeMap::new(), &key).is_err());
+}
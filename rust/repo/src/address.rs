@@ -0,0 +1,174 @@
+//! Content addresses.
+//!
+//! Objects are addressed by HMAC-SHA512-256 of their plaintext keyed with
+//! a secret address key, so the storage server cannot confirm guesses about
+//! file contents from addresses alone.
+
+use super::namespace::Namespace;
+use super::wire::{Decoder, Encoder};
+use super::RepoError;
+use std::fmt;
+use tweetnacl::*;
+
+pub const ADDRESS_SZ: usize = 32;
+pub const ADDRESS_KEY_SZ: usize = 32;
+const NAMESPACE_KEY_MAGIC: &[u8] = b"PNBNSKEY";
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Address {
+    pub bytes: [u8; ADDRESS_SZ],
+}
+
+impl Address {
+    pub fn from_hex(s: &str) -> Result<Address, RepoError> {
+        let mut a: Address = Default::default();
+        from_hex(s, &mut a.bytes)?;
+        Ok(a)
+    }
+
+    pub fn to_hex(&self) -> String {
+        to_hex(&self.bytes)
+    }
+
+    pub fn encode(&self, e: &mut Encoder) {
+        e.fixed(&self.bytes);
+    }
+
+    pub fn decode(d: &mut Decoder) -> Result<Address, RepoError> {
+        let mut a: Address = Default::default();
+        d.fixed_into(&mut a.bytes)?;
+        Ok(a)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+impl fmt::Debug for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Address({})", self.to_hex())
+    }
+}
+
+pub struct AddressKey {
+    pub bytes: [u8; ADDRESS_KEY_SZ],
+}
+
+impl Default for AddressKey {
+    fn default() -> AddressKey {
+        AddressKey {
+            bytes: [0; ADDRESS_KEY_SZ],
+        }
+    }
+}
+
+impl AddressKey {
+    pub fn new() -> AddressKey {
+        let mut k: AddressKey = Default::default();
+        random_bytes(&mut k.bytes);
+        k
+    }
+
+    pub fn address(&self, data: &[u8]) -> Address {
+        hmac_sha512_256(&self.bytes, data)
+    }
+
+    // The key of `namespace` in a repository that isolates namespaces, see
+    // `policy`, the first 32 bytes of the sha512 of
+    // "PNBNSKEY" || key || namespace. No address can be a namespace key.
+    pub fn for_namespace(&self, namespace: &Namespace) -> AddressKey {
+        let mut buf = Vec::with_capacity(NAMESPACE_KEY_MAGIC.len() + ADDRESS_KEY_SZ + 64);
+        buf.extend_from_slice(NAMESPACE_KEY_MAGIC);
+        buf.extend_from_slice(&self.bytes);
+        buf.extend_from_slice(namespace.as_str().as_bytes());
+        let mut h = [0; CRYPTO_HASH_BYTES];
+        crypto_hash(&mut h, &buf);
+        let mut k: AddressKey = Default::default();
+        k.bytes.copy_from_slice(&h[..ADDRESS_KEY_SZ]);
+        // XXX As in `drop`, these may be optimized away.
+        buf.iter_mut().for_each(|b| *b = 0);
+        h.iter_mut().for_each(|b| *b = 0);
+        k
+    }
+}
+
+impl Drop for AddressKey {
+    fn drop(&mut self) {
+        // XXX This may be optimized away, see tweetnacl.
+        self.bytes = [0; ADDRESS_KEY_SZ];
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes.iter() {
+        s.push_str(&format!("{:02x}", b));
+    }
+    s
+}
+
+pub fn from_hex(s: &str, out: &mut [u8]) -> Result<(), RepoError> {
+    if s.len() != out.len() * 2 || !s.is_ascii() {
+        return Err(RepoError::InvalidDataError);
+    }
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+            .map_err(|_| RepoError::InvalidDataError)?;
+    }
+    Ok(())
+}
+
+const HMAC_BLOCK_SZ: usize = 128;
+
+pub fn hmac_sha512_256(key: &[u8; ADDRESS_KEY_SZ], data: &[u8]) -> Address {
+    let mut inner = Vec::with_capacity(HMAC_BLOCK_SZ + data.len());
+    let mut outer = Vec::with_capacity(HMAC_BLOCK_SZ + CRYPTO_HASH_BYTES);
+    let mut pad = [0; HMAC_BLOCK_SZ];
+    pad[..ADDRESS_KEY_SZ].copy_from_slice(&key[..]);
+
+    inner.extend(pad.iter().map(|b| b ^ 0x36));
+    inner.extend_from_slice(data);
+    let mut h = [0; CRYPTO_HASH_BYTES];
+    crypto_hash(&mut h, &inner);
+
+    outer.extend(pad.iter().map(|b| b ^ 0x5c));
+    outer.extend_from_slice(&h);
+    crypto_hash(&mut h, &outer);
+
+    let mut a: Address = Default::default();
+    a.bytes.copy_from_slice(&h[..ADDRESS_SZ]);
+    a
+}
+
+// Tests --------------------
+
+#[test]
+fn test_hex_round_trip() {
+    let k = AddressKey::new();
+    let a = k.address(b"hello");
+    assert_eq!(Address::from_hex(&a.to_hex()).unwrap(), a);
+    assert!(Address::from_hex("zz").is_err());
+    let laptop = k.for_namespace(&Namespace::new("laptop").unwrap());
+    let desktop = k.for_namespace(&Namespace::new("desktop").unwrap());
+    assert_ne!(laptop.address(b"hello"), a);
+    assert_ne!(laptop.address(b"hello"), desktop.address(b"hello"));
+    assert_eq!(
+        laptop.bytes,
+        k.for_namespace(&Namespace::new("laptop").unwrap()).bytes
+    );
+}
+
+#[test]
+fn test_hmac_sha512_256() {
+    // RFC 4231 test case 2 uses a short key, pad it out like we do.
+    let mut key = [0; ADDRESS_KEY_SZ];
+    key[..4].copy_from_slice(b"Jefe");
+    let a = hmac_sha512_256(&key, b"what do ya want for nothing?");
+    assert_eq!(
+        a.to_hex(),
+        "164b7a7bfcf819e2e395fbe73b56e0a387bd64222e831fd610270cd7ea250554"
+    );
+}
@@ -0,0 +1,264 @@
+//! Amending the metadata of snapshots.
+//!
+//! `Repo::amend` replaces the tags and description of a snapshot. Snapshot
+//! objects are never rewritten, their address is what everything else
+//! knows them by, so an amendment object holding the new metadata is
+//! stored instead, see `tree`, and the snapshot's head in the manifest is
+//! made to name it, see `manifest`. Amending again replaces the amendment,
+//! the one before is left to `gc`. `Repo::read_head_snapshot` reads a
+//! snapshot with its amendment applied, which is what `list` shows and
+//! filters on.
+//!
+//! The amendment is stored and indexed like the objects of a backup, see
+//! `transaction`, then the manifest is replaced. Like `prune` the head is
+//! updated again if a commit replaced the manifest in between.
+//!
+//! `Repo::rename` moves a snapshot to another namespace, for a backup made
+//! under the wrong name. It is an amendment too, keeping the tags and
+//! description, and `list` shows and filters the snapshot under its new
+//! namespace from then on. The head keeps the namespace the snapshot was
+//! written in, which namespace isolation, retention locks, revocations and
+//! `prune` go by, so a rename cannot move a snapshot into the keeping of
+//! another writer. Amending keeps a rename, and renaming keeps the tags
+//! and description.
+
+use super::address::{Address, AddressKey};
+use super::datetime::unix_now;
+use super::manifest::SnapshotHead;
+use super::namespace::Namespace;
+use super::object::ObjectKind;
+use super::transaction::MAX_COMMIT_ATTEMPTS;
+use super::tree::{check_metadata, Amendment};
+use super::{Repo, RepoError};
+use asymcrypt::Key;
+
+impl Repo {
+    // Set the tags and description of `snapshot`, returning its new head.
+    pub fn amend(
+        &self,
+        snapshot: &Address,
+        tags: &[String],
+        description: &str,
+        address_key: &AddressKey,
+        key: &Key,
+    ) -> Result<SnapshotHead, RepoError> {
+        check_metadata(tags, description)?;
+        let mut tags = tags.to_vec();
+        tags.sort();
+        tags.dedup();
+        self.write_amendment(snapshot, address_key, key, |amendment| {
+            amendment.tags = tags;
+            amendment.description = description.to_string();
+        })
+    }
+
+    // List `snapshot` under `namespace`, returning its new head.
+    pub fn rename(
+        &self,
+        snapshot: &Address,
+        namespace: &Namespace,
+        address_key: &AddressKey,
+        key: &Key,
+    ) -> Result<SnapshotHead, RepoError> {
+        self.write_amendment(snapshot, address_key, key, |amendment| {
+            amendment.namespace = Some(namespace.clone())
+        })
+    }
+
+    // Store an amendment of `snapshot`, its current metadata changed by
+    // `f`, and make its head name it.
+    fn write_amendment(
+        &self,
+        snapshot: &Address,
+        address_key: &AddressKey,
+        key: &Key,
+        f: impl FnOnce(&mut Amendment),
+    ) -> Result<SnapshotHead, RepoError> {
+        let head = match self
+            .manifest()?
+            .heads
+            .into_iter()
+            .find(|h| h.address == *snapshot)
+        {
+            Some(head) => head,
+            None => return Err(RepoError::MissingObjectError),
+        };
+        let index = self.load_index()?;
+        let current = self.read_head_snapshot(&index, &key.box_sk, &head)?;
+        let mut amendment = Amendment {
+            snapshot: *snapshot,
+            time: unix_now(),
+            tags: current.tags,
+            description: current.description,
+            namespace: self
+                .read_amendment(&index, &key.box_sk, &head)?
+                .and_then(|a| a.namespace),
+        };
+        f(&mut amendment);
+        let buf = amendment.encode();
+        let address = address_key.address(&buf);
+        let mut tx = self.begin(Default::default())?;
+        tx.add(&address, ObjectKind::Amendment, &buf)?;
+        tx.commit_objects()?;
+        self.update_head(snapshot, key, |head| head.amendment = Some(address))
+    }
+
+    // Change the head of `snapshot` with `f` and commit the manifest, again
+    // if a commit replaced it meanwhile.
+    fn update_head(
+        &self,
+        snapshot: &Address,
+        key: &Key,
+        f: impl Fn(&mut SnapshotHead),
+    ) -> Result<SnapshotHead, RepoError> {
+        for _ in 0..MAX_COMMIT_ATTEMPTS {
+            let mut m = self.manifest()?;
+            let head = match m.heads.iter_mut().find(|h| h.address == *snapshot) {
+                Some(head) => {
+                    f(head);
+                    head.clone()
+                }
+                // Forgotten meanwhile.
+                None => return Err(RepoError::MissingObjectError),
+            };
+            self.commit_manifest(&m, key)?;
+            if self.manifest()?.heads.contains(&head) {
+                return Ok(head);
+            }
+        }
+        Err(RepoError::StorageError(
+            "the manifest kept changing while amending".to_string(),
+        ))
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_amend() {
+    use super::gc::GcOptions;
+    use super::namespace::Namespace;
+    use std::time::Duration;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let ns = Namespace::new("laptop").unwrap();
+    let dir = super::storage::local::test_dir("amend");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("file"), b"data").unwrap();
+    let opts = super::backup::BackupOptions {
+        tags: vec!["daily".to_string()],
+        description: "before the upgrade".to_string(),
+        ..Default::default()
+    };
+    let (head, _) = r.backup(&dir, &ns, &ak, &key, &opts).unwrap();
+    let index = r.load_index().unwrap();
+    let s = r.read_head_snapshot(&index, &key.box_sk, &head).unwrap();
+    assert_eq!(
+        (&s.tags[..], &s.description[..]),
+        (&opts.tags[..], "before the upgrade")
+    );
+
+    let tags = vec!["keep".to_string(), "env=prod".to_string()];
+    r.amend(&head.address, &tags, "first", &ak, &key).unwrap();
+    let amended = r
+        .amend(&head.address, &tags, "last good", &ak, &key)
+        .unwrap();
+    assert_eq!(amended.address, head.address);
+    assert_eq!(r.manifest().unwrap().heads, vec![amended.clone()]);
+    let index = r.load_index().unwrap();
+    let s = r.read_head_snapshot(&index, &key.box_sk, &amended).unwrap();
+    assert_eq!(s.tags, vec!["env=prod", "keep"]);
+    assert_eq!(s.description, "last good");
+    // The snapshot object itself is unchanged.
+    let original = r.read_snapshot(&index, &key.box_sk, &head.address).unwrap();
+    assert_eq!(original.tags, vec!["daily"]);
+
+    // gc keeps the amendment in use and collects the one replaced.
+    let grace = GcOptions {
+        grace: Duration::from_secs(0),
+        ..Default::default()
+    };
+    let stats = r.gc(&key.box_sk, &grace).unwrap();
+    assert_eq!(stats.deleted_packs.len(), 1);
+    let index = r.load_index().unwrap();
+    assert!(r.read_head_snapshot(&index, &key.box_sk, &amended).is_ok());
+
+    let bad = vec!["bad tag".to_string()];
+    assert!(r.amend(&head.address, &bad, "", &ak, &key).is_err());
+    let missing = Address { bytes: [7; 32] };
+    match r.amend(&missing, &tags, "", &ak, &key) {
+        Err(RepoError::MissingObjectError) => (),
+        _ => panic!("expected a missing snapshot"),
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_rename() {
+    use super::list::ListOptions;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let ns = Namespace::new("laptop").unwrap();
+    let dir = super::storage::local::test_dir("rename");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("file"), b"data").unwrap();
+    let opts = super::backup::BackupOptions {
+        tags: vec!["daily".to_string()],
+        ..Default::default()
+    };
+    let (h1, _) = r.backup(&dir, &ns, &ak, &key, &opts).unwrap();
+    std::fs::write(dir.join("file"), b"more data").unwrap();
+    let (h2, _) = r.backup(&dir, &ns, &ak, &key, &opts).unwrap();
+    let typo = Namespace::new("lpatop").unwrap();
+    let renamed = r.rename(&h2.address, &typo, &ak, &key).unwrap();
+
+    // The head keeps its namespace and names an amendment recording the
+    // new one, with the tags as they were.
+    assert_eq!(renamed.namespace, ns);
+    assert_eq!(
+        r.manifest().unwrap().heads,
+        vec![h1.clone(), renamed.clone()]
+    );
+    let index = r.load_index().unwrap();
+    let amendment = r
+        .read_amendment(&index, &key.box_sk, &renamed)
+        .unwrap()
+        .unwrap();
+    assert_eq!(amendment.snapshot, h2.address);
+    assert_eq!(amendment.namespace, Some(typo.clone()));
+    assert_eq!(amendment.tags, vec!["daily"]);
+
+    // Amending keeps the rename.
+    let tags = vec!["keep".to_string()];
+    let amended = r.amend(&h2.address, &tags, "moved", &ak, &key).unwrap();
+    let index = r.load_index().unwrap();
+    let amendment = r
+        .read_amendment(&index, &key.box_sk, &amended)
+        .unwrap()
+        .unwrap();
+    assert_eq!(amendment.namespace, Some(typo.clone()));
+    assert_eq!(amendment.tags, tags);
+
+    let opts = ListOptions {
+        namespace: Some(typo.clone()),
+        ..Default::default()
+    };
+    let listed = r.list_snapshots(&key.box_sk, &opts).unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].head.address, h2.address);
+    assert_eq!(listed[0].head.namespace, typo);
+    let opts = ListOptions {
+        namespace: Some(ns),
+        ..Default::default()
+    };
+    let listed = r.list_snapshots(&key.box_sk, &opts).unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].head.address, h1.address);
+
+    let missing = Address { bytes: [7; 32] };
+    match r.rename(&missing, &typo, &ak, &key) {
+        Err(RepoError::MissingObjectError) => (),
+        _ => panic!("expected a missing snapshot"),
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,214 @@
+//! Whole repository export and import as a tar stream.
+//!
+//! An export holds every stored value as is, nothing is decrypted or
+//! re-encrypted, so it can be written by anyone who can read the
+//! repository and seeds an offsite copy from a disk drive rather than over
+//! the network. Locks are left out.
+//!
+//! The stream is a plain ustar archive, see `ustar`, with one regular
+//! file per stored value, named by its key written as `storage::local`
+//! writes it, so unpacking an export with tar gives a local repository.
+//! The manifest comes last. It is read before anything else is listed and
+//! commits index their packs before replacing the manifest, so every pack
+//! it refers to is in the archive.
+//!
+//! Import needs an empty destination and writes the manifest only after
+//! everything else is stored and the config and manifest verify against
+//! the owner key, an interrupted import never looks like a repository.
+//! Pack sizes are checked against their indexes, contents are
+//! authenticated when first decrypted, as always.
+
+use super::lock::{is_lock_key, LockMode};
+use super::storage::{check_key, escape_key, unescape_key, StorageEngine};
+use super::ustar::{
+    checksum_ok, field, finish_header, numeric, octal_max, padding, put, put_octal, write_end,
+    write_padding, BLOCK_SZ, GID, MAGIC, MODE, MTIME, NAME, PREFIX, SIZE, TYPEFLAG, UID,
+};
+use super::{Repo, RepoError, MANIFEST_FILE, PACKS_DIR};
+use asymcrypt::PublicKey;
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct ArchiveStats {
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+fn write_entry(w: &mut dyn Write, key: &str, data: &[u8]) -> Result<(), RepoError> {
+    let name = escape_key(key);
+    let (prefix, name) = if name.len() <= NAME.1 {
+        ("", &name[..])
+    } else {
+        match name[..name.len().min(PREFIX.1 + 1)].rfind('/') {
+            Some(i) if name.len() - i - 1 <= NAME.1 => (&name[..i], &name[i + 1..]),
+            _ => return Err(RepoError::InvalidKeyError),
+        }
+    };
+    if data.len() as u64 > octal_max(SIZE) {
+        return Err(RepoError::ObjectTooLargeError);
+    }
+    let mut h = [0u8; BLOCK_SZ];
+    put(&mut h, NAME, name.as_bytes());
+    put_octal(&mut h, MODE, 0o644);
+    put_octal(&mut h, UID, 0);
+    put_octal(&mut h, GID, 0);
+    put_octal(&mut h, SIZE, data.len() as u64);
+    put_octal(&mut h, MTIME, 0);
+    h[TYPEFLAG] = b'0';
+    put(&mut h, PREFIX, prefix.as_bytes());
+    finish_header(&mut h);
+
+    w.write_all(&h)?;
+    w.write_all(data)?;
+    write_padding(w, data.len() as u64)?;
+    Ok(())
+}
+
+// The next entry, or None at the end of the archive.
+fn read_entry(r: &mut dyn Read) -> Result<Option<(String, Vec<u8>)>, RepoError> {
+    let mut h = [0u8; BLOCK_SZ];
+    r.read_exact(&mut h)?;
+    if h.iter().all(|b| *b == 0) {
+        return Ok(None);
+    }
+    if &h[MAGIC.0..MAGIC.0 + 6] != b"ustar\0" || !checksum_ok(&h) {
+        return Err(RepoError::InvalidDataError);
+    }
+    if h[TYPEFLAG] != b'0' && h[TYPEFLAG] != 0 {
+        return Err(RepoError::InvalidDataError);
+    }
+    let text = |f| match std::str::from_utf8(field(&h, f)) {
+        Ok(s) => Ok(s.to_string()),
+        Err(_) => Err(RepoError::InvalidDataError),
+    };
+    let prefix = text(PREFIX)?;
+    let name = text(NAME)?;
+    let name = if prefix.is_empty() {
+        name
+    } else {
+        format!("{}/{}", prefix, name)
+    };
+    let key = unescape_key(&name);
+    check_key(&key)?;
+
+    let size = numeric(&h, SIZE).map_err(|_| RepoError::InvalidDataError)?;
+    let mut data = Vec::new();
+    r.take(size).read_to_end(&mut data)?;
+    if data.len() as u64 != size {
+        return Err(RepoError::InvalidDataError);
+    }
+    r.read_exact(&mut [0; BLOCK_SZ][..padding(size) as usize])?;
+    Ok(Some((key, data)))
+}
+
+impl Repo {
+    pub fn export(&self, w: &mut dyn Write) -> Result<ArchiveStats, RepoError> {
+        let mut lock = self.lock(LockMode::Shared)?;
+        let manifest = self.storage().get(MANIFEST_FILE)?;
+        let mut stats: ArchiveStats = Default::default();
+        for k in self.storage().list_prefix("")? {
+            if k == MANIFEST_FILE || is_lock_key(&k) {
+                continue;
+            }
+            lock.refresh()?;
+            let data = self.storage().get(&k)?;
+            write_entry(w, &k, &data)?;
+            stats.entries += 1;
+            stats.bytes += data.len() as u64;
+        }
+        write_entry(w, MANIFEST_FILE, &manifest)?;
+        write_end(w)?;
+        w.flush()?;
+        stats.entries += 1;
+        stats.bytes += manifest.len() as u64;
+        Ok(stats)
+    }
+
+    // Import an export into empty storage, then open it as a repository
+    // owned by `owner`.
+    pub fn import(
+        storage: Arc<dyn StorageEngine>,
+        owner: &PublicKey,
+        r: &mut dyn Read,
+    ) -> Result<(Repo, ArchiveStats), RepoError> {
+        if !storage.list_prefix("")?.is_empty() {
+            return Err(RepoError::RepoExistsError);
+        }
+        let mut stats: ArchiveStats = Default::default();
+        let mut manifest = None;
+        while let Some((key, data)) = read_entry(r)? {
+            stats.entries += 1;
+            stats.bytes += data.len() as u64;
+            if key == MANIFEST_FILE {
+                manifest = Some(data);
+            } else if !is_lock_key(&key) {
+                storage.put(&key, &data)?;
+            }
+        }
+        let manifest = manifest.ok_or(RepoError::InvalidDataError)?;
+
+        let repo = Repo::open(storage, owner)?;
+        repo.open_manifest(&manifest)?;
+        for id in repo.list_pack_indexes()? {
+            let key = format!("{}/{}", PACKS_DIR, id.to_hex());
+            if repo.storage().size(&key)? != repo.read_pack_index(&id)?.pack_size {
+                return Err(RepoError::InvalidDataError);
+            }
+        }
+        repo.storage().put(MANIFEST_FILE, &manifest)?;
+        Ok((repo, stats))
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_export_import() {
+    use super::address::Address;
+    use super::gc::test_commit_tree;
+    use super::storage::mem::MemStorage;
+    let (r, key) = super::test_repo();
+    test_commit_tree(&r, &key, 1, &[Address { bytes: [3; 32] }]);
+    let mut buf = Vec::new();
+    let stats = r.export(&mut buf).unwrap();
+    assert_eq!(buf.len() % BLOCK_SZ, 0);
+    assert_eq!(stats.entries, r.storage().list_prefix("").unwrap().len());
+    assert!(r.list_locks().unwrap().is_empty());
+
+    let dest = Arc::new(MemStorage::new());
+    let (imported, istats) = Repo::import(dest.clone(), &key.pub_key(), &mut &buf[..]).unwrap();
+    assert_eq!(istats, stats);
+    assert_eq!(imported.manifest().unwrap(), r.manifest().unwrap());
+    for k in r.storage().list_prefix("").unwrap() {
+        assert_eq!(dest.get(&k).unwrap(), r.storage().get(&k).unwrap());
+    }
+
+    match Repo::import(dest, &key.pub_key(), &mut &buf[..]) {
+        Err(RepoError::RepoExistsError) => (),
+        _ => panic!("expected a populated destination to be refused"),
+    }
+
+    // Damaged or truncated archives never leave a manifest behind.
+    let mut bad = buf.clone();
+    bad[BLOCK_SZ * 3 + 10] ^= 1;
+    for archive in [&buf[..buf.len() - 3 * BLOCK_SZ], &bad[..]].iter() {
+        let dest = Arc::new(MemStorage::new());
+        assert!(Repo::import(dest.clone(), &key.pub_key(), &mut &archive[..]).is_err());
+        assert!(!dest.exists(MANIFEST_FILE).unwrap());
+    }
+}
+
+#[test]
+fn test_tar_names() {
+    let long = format!("{}/{}", "d".repeat(150), "f".repeat(90));
+    for key in ["config", "ns/Laptop", &long[..]].iter() {
+        let mut buf = Vec::new();
+        write_entry(&mut buf, key, b"data").unwrap();
+        let (k, data) = read_entry(&mut &buf[..]).unwrap().unwrap();
+        assert_eq!(&k, key);
+        assert_eq!(data, b"data");
+    }
+    let mut buf = Vec::new();
+    assert!(write_entry(&mut buf, &"x".repeat(101), b"").is_err());
+}
@@ -0,0 +1,219 @@
+//! Signed attestations of audits.
+//!
+//! `Repo::audit_remote` needs nothing secret of the repository, see
+//! `audit`, and what it saw is attested in a statement the auditor signs
+//! with a key of their own: which repository, audited against which owner
+//! key and when, the manifest as it stood, by counter and hash, see
+//! `manifest`, how many checks passed, warned and failed, a hash of the
+//! report itself, and what the server's operation log held, see `oplog`.
+//! Anyone holding the auditor's public key can later show what the
+//! repository looked like to the auditor, and successive attestations
+//! whose manifest counters go backwards show a rollback.
+//!
+//! Format (inside the signature envelope, see `signed`):
+//!
+//! ```text
+//! "PNBATTEST" u16:format_version [16]:repo_id [32]:owner_sign_pk
+//! u64:unix_time u64:manifest_counter [32]:manifest_hash u64:checks
+//! u64:warnings u64:errors [32]:report_hash bool:has_log u64:log_operations
+//! u64:log_sealed u64:log_seals
+//! ```
+//!
+//! `report_hash` is the first 32 bytes of the sha512 of the report as
+//! `FsckReport::write_to` writes it. The manifest counter and hash are 0
+//! if the manifest did not verify, and the log counts are 0 unless
+//! `has_log` is set, which it is only for a log that checked out.
+
+use super::datetime::unix_now;
+use super::fsck::{FsckReport, Verdict};
+use super::manifest::{ManifestHash, RepoId, MANIFEST_HASH_SZ};
+use super::oplog::LogReport;
+use super::signed;
+use super::wire::{Decoder, Encoder};
+use super::{Repo, RepoError};
+use tweetnacl::*;
+
+pub const ATTESTATION_FORMAT_VERSION: u16 = 1;
+const ATTESTATION_MAGIC: &[u8] = b"PNBATTEST";
+
+pub fn report_hash(report: &FsckReport) -> [u8; 32] {
+    let mut buf = Vec::new();
+    report.write_to(&mut buf).unwrap();
+    let mut h = [0; CRYPTO_HASH_BYTES];
+    crypto_hash(&mut h, &buf);
+    let mut c = [0; 32];
+    c.copy_from_slice(&h[..32]);
+    c
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Attestation {
+    pub repo_id: RepoId,
+    pub owner: CryptoSignPk,
+    pub time: u64,
+    pub manifest_counter: u64,
+    pub manifest_hash: ManifestHash,
+    pub checks: u64,
+    pub warnings: u64,
+    pub errors: u64,
+    pub report_hash: [u8; 32],
+    // Operations in the server's log, and how many a seal covers.
+    pub log: Option<LogReport>,
+}
+
+impl Attestation {
+    pub fn new(
+        repo: &Repo,
+        manifest_counter: u64,
+        manifest_hash: &ManifestHash,
+        report: &FsckReport,
+        log: Option<&LogReport>,
+    ) -> Attestation {
+        Attestation {
+            repo_id: repo.config().repo_id,
+            owner: repo.owner().sign_pk.clone(),
+            time: unix_now(),
+            manifest_counter,
+            manifest_hash: *manifest_hash,
+            checks: report.entries.len() as u64,
+            warnings: report.count(Verdict::Warning) as u64,
+            errors: report.count(Verdict::Error) as u64,
+            report_hash: report_hash(report),
+            log: log.cloned(),
+        }
+    }
+
+    // Whether this attests to `report`.
+    pub fn covers(&self, report: &FsckReport) -> bool {
+        self.report_hash == report_hash(report)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let log = self.log.clone().unwrap_or_default();
+        let mut e = Encoder::new();
+        e.fixed(ATTESTATION_MAGIC)
+            .u16(ATTESTATION_FORMAT_VERSION)
+            .fixed(&self.repo_id.bytes)
+            .fixed(&self.owner.bytes)
+            .u64(self.time)
+            .u64(self.manifest_counter)
+            .fixed(&self.manifest_hash)
+            .u64(self.checks)
+            .u64(self.warnings)
+            .u64(self.errors)
+            .fixed(&self.report_hash)
+            .bool(self.log.is_some())
+            .u64(log.operations)
+            .u64(log.sealed)
+            .u64(log.seals);
+        e.into_vec()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Attestation, RepoError> {
+        let mut d = Decoder::new(buf);
+        if d.fixed(ATTESTATION_MAGIC.len())? != ATTESTATION_MAGIC {
+            return Err(RepoError::InvalidDataError);
+        }
+        if d.u16()? != ATTESTATION_FORMAT_VERSION {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        let mut repo_id: RepoId = Default::default();
+        d.fixed_into(&mut repo_id.bytes)?;
+        let mut owner: CryptoSignPk = Default::default();
+        d.fixed_into(&mut owner.bytes)?;
+        let time = d.u64()?;
+        let manifest_counter = d.u64()?;
+        let mut manifest_hash = [0; MANIFEST_HASH_SZ];
+        d.fixed_into(&mut manifest_hash)?;
+        let checks = d.u64()?;
+        let warnings = d.u64()?;
+        let errors = d.u64()?;
+        let mut report_hash = [0; 32];
+        d.fixed_into(&mut report_hash)?;
+        let has_log = d.bool()?;
+        let log = LogReport {
+            operations: d.u64()?,
+            sealed: d.u64()?,
+            seals: d.u64()?,
+        };
+        d.finish()?;
+        Ok(Attestation {
+            repo_id,
+            owner,
+            time,
+            manifest_counter,
+            manifest_hash,
+            checks,
+            warnings,
+            errors,
+            report_hash,
+            log: Some(log).filter(|_| has_log),
+        })
+    }
+
+    pub fn sign(&self, sk: &CryptoSignSk) -> Vec<u8> {
+        signed::sign(&self.encode(), sk)
+    }
+
+    pub fn open(sm: &[u8], pk: &CryptoSignPk) -> Result<Attestation, RepoError> {
+        Attestation::decode(&signed::open(sm, pk)?)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_audit_remote() {
+    use super::address::Address;
+    use super::audit::AuditOptions;
+    use super::oplog::{OpLog, Operation};
+    let (r, key) = super::test_repo();
+    super::gc::test_commit_tree(&r, &key, 1, &[Address { bytes: [1; 32] }]);
+
+    // The server's log, of which the auditor holds a copy and the public key.
+    let dir = super::storage::local::test_dir("audit-remote");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("serve.log");
+    let mut key_file = Vec::new();
+    asymcrypt::Key::new().write(&mut key_file).unwrap();
+    let log_key = asymcrypt::Key::read_boxed_from(&mut &key_file[..]).unwrap();
+    let log_pk = log_key.sign_pk.clone();
+    let log = OpLog::open(&path, log_key).unwrap();
+    log.record(&Operation::default()).unwrap();
+    log.seal().unwrap();
+    log.record(&Operation::default()).unwrap();
+
+    let auditor = Repo::open(r.storage().clone(), &key.pub_key()).unwrap();
+    let opts = AuditOptions {
+        log: Some((path.clone(), log_pk)),
+        ..Default::default()
+    };
+    let (report, attestation) = auditor.audit_remote(&opts).unwrap();
+    assert!(report.is_ok());
+    assert_eq!(report.count(Verdict::Warning), 1);
+    assert!(attestation.covers(&report));
+    assert_eq!(attestation.manifest_counter, 2);
+    assert_eq!(attestation.errors, 0);
+    let log = attestation.log.clone().unwrap();
+    assert_eq!((log.operations, log.sealed), (2, 1));
+
+    let (pk, sk) = boxed_crypto_sign_keypair();
+    let sm = attestation.sign(&sk);
+    assert_eq!(Attestation::open(&sm, &pk).unwrap(), attestation);
+    match Attestation::open(&sm, &key.sign_pk) {
+        Err(RepoError::SignatureFailedError) => (),
+        _ => panic!("expected the signature to fail"),
+    }
+    let buf = attestation.encode();
+    assert!(Attestation::decode(&buf[..buf.len() - 1]).is_err());
+
+    // A tampered log fails the audit, and is not attested to.
+    let mut bad = std::fs::read(&path).unwrap();
+    let last = bad.len() - 1;
+    bad[last] ^= 1;
+    std::fs::write(&path, &bad).unwrap();
+    let (report, attestation) = auditor.audit_remote(&opts).unwrap();
+    assert_eq!(attestation.errors, 1);
+    assert!(!report.is_ok() && attestation.log.is_none());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,307 @@
+//! Integrity audits without the repository secret key.
+//!
+//! `audit` checks a repository with nothing but the owner public key, so
+//! it can run on the storage server or be handed to a third party who
+//! must never be able to read backups. The owner key has to come from
+//! somewhere trusted, the copy stored in the repository proves nothing
+//! and is only compared against it.
+//!
+//! It checks the config, policy and manifest signatures, so the snapshot
+//! heads are those the owner published, the pack index checksums, that
+//! every snapshot head is an indexed snapshot object, and the envelope of
+//! every pack and loose object: headers and trailers, and that each index
+//! entry is a whole sealed object inside its pack, see
+//! `pack::check_envelope`. With `read_data` packs stored with parity are
+//! read in full and checked block by block against the parity checksums.
+//! Packs without parity have no checksum an auditor can verify, their
+//! contents are only authenticated by decryption, see `fsck`.
+//!
+//! An auditor may only have read access, so no lock is taken. Anything gc
+//! or repack deletes during the audit is reported as missing.
+//!
+//! Given `AuditOptions::log`, a copy of the server's operation log and the
+//! public half of the key sealing it, see `oplog`, the audit also checks
+//! the log's chain and seals, and warns of operations no seal covers yet.
+//!
+//! The report has the same format as that of `fsck`. `audit_remote` is
+//! what `packnback audit-remote` runs: the audit, and an attestation of
+//! what it saw for the auditor to sign, see `attestation`.
+
+use super::attestation::Attestation;
+use super::fsck::{object_subject, FsckReport, Verdict};
+use super::index::{PackIndex, RepoIndex};
+use super::loose::check_loose;
+use super::manifest::{manifest_hash, ManifestHash};
+use super::object::ObjectKind;
+use super::oplog::verify_log;
+use super::pack::check_envelope;
+use super::parity::parity_key;
+use super::storage::StorageObject;
+use super::{
+    Repo, RepoError, CONFIG_FILE, INDEXES_DIR, MANIFEST_FILE, OWNER_KEY_FILE, PACKS_DIR,
+    POLICY_FILE,
+};
+use asymcrypt::PublicKey;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use tweetnacl::*;
+
+#[derive(Clone, Default, Debug)]
+pub struct AuditOptions {
+    // Read packs stored with parity in full and check their blocks.
+    pub read_data: bool,
+    // The server's operation log, and the public key sealing it.
+    pub log: Option<(PathBuf, CryptoSignPk)>,
+}
+
+impl Repo {
+    pub fn audit(&self, opts: &AuditOptions) -> Result<FsckReport, RepoError> {
+        let mut report: FsckReport = Default::default();
+
+        // Repo::open already verified the config and policy.
+        report.add(Verdict::Ok, CONFIG_FILE, "");
+        if self.storage().exists(POLICY_FILE)? {
+            report.add(Verdict::Ok, POLICY_FILE, "");
+        }
+        let stored_owner = self
+            .storage()
+            .get(OWNER_KEY_FILE)
+            .and_then(|buf| Ok(PublicKey::read_from(&mut &buf[..])?));
+        match stored_owner {
+            Ok(ref owner) if owner == self.owner() => report.add(Verdict::Ok, OWNER_KEY_FILE, ""),
+            Ok(_) => report.add(
+                Verdict::Error,
+                OWNER_KEY_FILE,
+                "the stored owner key is not the one audited against",
+            ),
+            Err(err) => report.add(Verdict::Error, OWNER_KEY_FILE, &err.to_string()),
+        }
+        let heads = match self.manifest() {
+            Ok(m) => {
+                report.add(Verdict::Ok, MANIFEST_FILE, "");
+                m.heads
+            }
+            Err(err) => {
+                report.add(Verdict::Error, MANIFEST_FILE, &err.to_string());
+                Vec::new()
+            }
+        };
+        if let Some((ref path, ref pk)) = opts.log {
+            let subject = path.display().to_string();
+            match verify_log(path, pk) {
+                Ok(log) if log.sealed < log.operations => report.add(
+                    Verdict::Warning,
+                    &subject,
+                    &format!(
+                        "{} operations are not sealed yet",
+                        log.operations - log.sealed
+                    ),
+                ),
+                Ok(_) => report.add(Verdict::Ok, &subject, ""),
+                Err(err) => report.add(Verdict::Error, &subject, &err.to_string()),
+            }
+        }
+
+        let mut indexes: Vec<PackIndex> = Vec::new();
+        for id in self.list_pack_indexes()? {
+            let subject = format!("{}/{}", INDEXES_DIR, id.to_hex());
+            match self.read_pack_index(&id) {
+                Ok(idx) => {
+                    report.add(Verdict::Ok, &subject, "");
+                    indexes.push(idx);
+                }
+                Err(err) => report.add(Verdict::Error, &subject, &err.to_string()),
+            }
+        }
+        let mut unchecked = 0;
+        for idx in indexes.iter() {
+            let key = format!("{}/{}", PACKS_DIR, idx.pack_id.to_hex());
+            let result = self.fsck_pack(idx, None).and_then(|_| {
+                let mut pack = StorageObject::new(self.storage().clone(), &key);
+                check_envelope(&mut pack, idx.entries())
+            });
+            report.check(&key, result);
+            if !opts.read_data {
+                continue;
+            }
+            match self.read_parity(&idx.pack_id) {
+                Ok(Some(parity)) => {
+                    report.add(Verdict::Ok, &parity_key(&idx.pack_id), "");
+                    match self.storage().get(&key) {
+                        Ok(pack) => match parity.damaged_blocks(&pack).len() {
+                            0 => (),
+                            n => report.add(
+                                Verdict::Error,
+                                &key,
+                                &format!("{} blocks fail their parity checksum", n),
+                            ),
+                        },
+                        Err(err) => report.add(Verdict::Error, &key, &err.to_string()),
+                    }
+                }
+                Ok(None) => unchecked += 1,
+                Err(err) => report.add(Verdict::Error, &parity_key(&idx.pack_id), &err.to_string()),
+            }
+        }
+        if unchecked > 0 {
+            report.add(
+                Verdict::Warning,
+                &format!("{}/*", PACKS_DIR),
+                &format!(
+                    "{} packs have no parity, their data was not checked",
+                    unchecked
+                ),
+            );
+        }
+        let indexed: HashSet<_> = indexes.iter().map(|idx| idx.pack_id).collect();
+        for id in self.list_packs()? {
+            if !indexed.contains(&id) {
+                let subject = format!("{}/{}", PACKS_DIR, id.to_hex());
+                report.add(Verdict::Warning, &subject, "pack has no index");
+            }
+        }
+
+        for address in self.list_loose()? {
+            let key = self.loose_key(&address);
+            let result = self.storage().get(&key).and_then(|buf| {
+                if check_loose(&buf)?.0 != address {
+                    return Err(RepoError::CorruptOrTamperedDataError);
+                }
+                Ok(())
+            });
+            report.check(&key, result);
+        }
+
+        let index = RepoIndex::build(indexes.iter());
+        for h in heads.iter() {
+            let kind = match index.lookup(&h.address) {
+                Some(loc) => Ok(loc.kind),
+                None => self
+                    .storage()
+                    .get(&self.loose_key(&h.address))
+                    .and_then(|buf| Ok(check_loose(&buf)?.1)),
+            };
+            let result = match kind {
+                Ok(ObjectKind::Snapshot) => Ok(()),
+                Ok(_) => Err(RepoError::InvalidDataError),
+                Err(ref e) if e.is_not_found() => Err(RepoError::MissingObjectError),
+                Err(e) => Err(e),
+            };
+            report.check(&object_subject(&h.address), result);
+        }
+        Ok(report)
+    }
+
+    // Audit, and attest to what was seen: the manifest, the operation log
+    // and the report.
+    pub fn audit_remote(
+        &self,
+        opts: &AuditOptions,
+    ) -> Result<(FsckReport, Attestation), RepoError> {
+        let manifest = self
+            .storage()
+            .get(MANIFEST_FILE)
+            .and_then(|sm| Ok((self.open_manifest(&sm)?, manifest_hash(&sm))));
+        let (counter, hash) = match manifest {
+            Ok((m, hash)) => (m.counter, hash),
+            Err(_) => (0, ManifestHash::default()),
+        };
+        let report = self.audit(opts)?;
+        let log = match opts.log {
+            Some((ref path, ref pk)) => verify_log(path, pk).ok(),
+            None => None,
+        };
+        let attestation = Attestation::new(self, counter, &hash, &report, log.as_ref());
+        Ok((report, attestation))
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_audit() {
+    use super::address::Address;
+    use super::pack::PackerOptions;
+    let (r, key) = super::test_repo();
+    super::gc::test_commit_tree(&r, &key, 1, &[Address { bytes: [1; 32] }]);
+    // A second snapshot stored with parity, and a loose object.
+    let mut tx = r
+        .begin(PackerOptions {
+            parity: Some(Default::default()),
+            ..Default::default()
+        })
+        .unwrap();
+    let snapshot = Address { bytes: [9; 32] };
+    tx.add(&snapshot, ObjectKind::Snapshot, &[0; 4]).unwrap();
+    let head = super::manifest::SnapshotHead {
+        address: snapshot,
+        timestamp: 2,
+        namespace: super::namespace::Namespace::new("laptop").unwrap(),
+        retain_until: 0,
+        amendment: None,
+    };
+    tx.commit(head, &key).unwrap();
+    let loose = Address { bytes: [3; 32] };
+    r.put_loose(&loose, ObjectKind::Chunk, b"loose").unwrap();
+
+    // An auditor only has the public key.
+    let auditor = Repo::open(r.storage().clone(), &key.pub_key()).unwrap();
+    let opts = AuditOptions {
+        read_data: true,
+        ..Default::default()
+    };
+    let report = auditor.audit(&opts).unwrap();
+    assert!(report.is_ok());
+    // The first pack has no parity.
+    assert_eq!(report.count(Verdict::Warning), 1);
+
+    // Bit rot is found in packs with parity.
+    let s = r.storage();
+    let id = r
+        .list_packs()
+        .unwrap()
+        .into_iter()
+        .find(|id| r.read_parity(id).unwrap().is_some())
+        .unwrap();
+    let pack_key = format!("{}/{}", PACKS_DIR, id.to_hex());
+    let mut buf = s.get(&pack_key).unwrap();
+    buf[200] ^= 1;
+    s.put(&pack_key, &buf).unwrap();
+    assert_eq!(auditor.audit(&opts).unwrap().count(Verdict::Error), 1);
+    assert!(auditor.audit(&Default::default()).unwrap().is_ok());
+
+    // A truncated pack, a renamed loose object, a forged manifest and a
+    // swapped owner key.
+    s.put(&pack_key, &buf[..buf.len() - 1]).unwrap();
+    s.rename(
+        &r.loose_key(&loose),
+        &r.loose_key(&Address { bytes: [4; 32] }),
+    )
+    .unwrap();
+    let other = asymcrypt::Key::new();
+    let m = r.manifest().unwrap();
+    s.put(MANIFEST_FILE, &m.sign(&other.sign_sk)).unwrap();
+    let mut pk = Vec::new();
+    other.pub_key().write(&mut pk).unwrap();
+    s.put(OWNER_KEY_FILE, &pk).unwrap();
+    let report = auditor.audit(&Default::default()).unwrap();
+    let errors: Vec<&str> = report
+        .entries
+        .iter()
+        .filter(|e| e.verdict == Verdict::Error)
+        .map(|e| &e.subject[..])
+        .collect();
+    assert_eq!(
+        errors,
+        vec![
+            OWNER_KEY_FILE,
+            MANIFEST_FILE,
+            &pack_key[..],
+            &format!(
+                "{}/{}",
+                crate::LOOSE_DIR,
+                Address { bytes: [4; 32] }.to_hex()
+            )[..],
+        ]
+    );
+}
@@ -0,0 +1,1982 @@
+//! Backing up a directory tree.
+//!
+//! `Repo::backup` walks a directory depth first in one transaction, see
+//! `transaction`. Files are chunked and stored through the parallel
+//! pipeline in `upload`, then each directory is stored as a tree object
+//! once everything in it is, see `tree`. Small files and trees are
+//! addressed in the calling thread and sealed in batches of up to
+//! `BackupOptions::pending_bytes`, larger files are streamed through the
+//! pipeline once the batch before them is stored. A snapshot object
+//! naming the root tree is committed last. Chunks already in the
+//! repository or earlier in the backup are not stored again, so an
+//! interrupted backup run again resumes from its last checkpoint, see
+//! `transaction`. Directories are listed and their entries stat'ed by
+//! `BackupOptions::scan_threads` threads ahead of the walk, see `scan`.
+//!
+//! Paths can be left out by the rules in `BackupOptions::exclude`, see
+//! `exclude`. Sockets, fifos and devices are stored, see `special`,
+//! unless `BackupOptions::skip_specials` is set. Problems with the source
+//! never fail a backup: a file or directory that cannot be read,
+//! denied or failing with an I/O error say, or that vanishes during the
+//! walk, is left out and recorded in the snapshot and the returned stats.
+//! Only errors writing to the repository, or an unreadable root, fail the
+//! backup. The summary tells the three apart, see `json`: exit code 0 for
+//! a complete backup, 1 for one that completed but left paths out and 2
+//! for one that failed.
+//!
+//! Symlinks are stored as links unless `BackupOptions::symlinks` says to
+//! follow them, when what they point to is stored in their place. A link
+//! that dangles, or that leads to a directory it is itself inside, is
+//! still stored as a link, the loop recorded as an error. The directory
+//! backed up is always followed if it is a link, like any path given on a
+//! command line, so storing links is also following only the arguments.
+//!
+//! A directory another filesystem is mounted on, found by its device or
+//! in the mount table, see `mounts`, is walked into unless it holds a
+//! pseudo filesystem such as `/proc`, or `BackupOptions::one_file_system`
+//! is set. Bind mounts count as other filesystems. A mountpoint not
+//! walked into is stored as an empty directory and recorded in the
+//! snapshot.
+//!
+//! Extended attributes and POSIX ACLs are stored with each entry, less
+//! those `BackupOptions::xattrs` skips, see `xattr`. Attributes that
+//! cannot be read are recorded, the entry is stored without them.
+//!
+//! Modification times are stored to the nanosecond. Access times are only
+//! stored with `BackupOptions::atime`, as reading a file changes its
+//! access time and so the address of every tree above it, and birth times
+//! with `BackupOptions::birthtime` where the filesystem keeps them. Both
+//! are taken before the file is read.
+//!
+//! With `BackupOptions::max_memory` the worker threads and their zstd
+//! contexts, queues, batches and packs are cut down to fit the budget, see `memory`, and a budget
+//! too small for the repository's index fails the backup at the start.
+//!
+//! `Repo::backup_stream` stores a single stream, such as a database dump,
+//! as a snapshot of a tree holding just that one file.
+//!
+//! `Repo::backup_tar` imports a tar archive as a snapshot of the tree it
+//! holds, see `tar`, so old archives can join the repository. Archives
+//! may list entries in any order, and list one more than once, the last
+//! one winning as when unpacking, so the tree is built in memory and
+//! stored once the archive has been read. Directories the archive does
+//! not list are owned by root, mode 755 and modified now. Hard links are
+//! stored as copies of the file linked to, which shares its chunks.
+//! Entries climbing out of the archive with `..`, links to files not seen
+//! earlier and entry types that cannot be stored, GNU sparse files say,
+//! are left out and recorded. A malformed or truncated archive fails the
+//! import.
+//!
+//! `BackupOptions::progress` is kept up to date as the backup goes, see
+//! `progress`. Its total is the size of the last snapshot of the same
+//! source in the namespace, unless the caller set one.
+//!
+//! With `BackupOptions::stat_cache`, files unchanged since the last backup
+//! of the same directory are not read again, their chunks come from the
+//! stat cache, see `statcache`.
+//!
+//! `BackupStats::write_ndjson` emits a record of the backup and one per
+//! path left out by an error, see `json`:
+//!
+//! ```text
+//! {"type": "backup", "address": hex, "namespace": s, "time": n,
+//!  "files": n, "dirs": n, "symlinks": n, "specials": n, "skipped": n,
+//!  "excluded": n,
+//!  "bytes": n, "cached": n, "new_chunks": n, "new_bytes": n,
+//!  "compressed_bytes": n,
+//!  "errors": n, "mounts": [s, ...], "changed": [s, ...], "dry_run": b,
+//!  "put_bytes": n, "read_files": n, "read_bytes": n, "stored_bytes": n,
+//!  "prepare_seconds": x, "walk_seconds": x, "commit_seconds": x,
+//!  "quotas": [{"scope": "client"|"repository", "quota": n,
+//!              "stored_bytes": n, "headroom": n}, ...],
+//!  "check": {"every": n, "time": n|null, "warnings": n, "errors": n,
+//!            "overdue": b}|null}
+//! ```
+//!
+//! They tell where a slow backup spent its time. `files` and `bytes` are
+//! what the snapshot holds, `read_files` and `read_bytes` what was read
+//! to store it, the rest coming from the stat cache, `new_bytes` what was
+//! left of that after deduplication, `compressed_bytes` what that and the
+//! new trees came to once compressed and `stored_bytes` the packs they
+//! were sealed into, which are put as they fill. The backup's
+//! time is split into loading the index and stat cache, walking the tree
+//! while reading and storing it, and committing the snapshot. `quotas`
+//! are those the server holds the client to, see `usage`, with what is
+//! left of them once the backup is stored, none for storage that enforces
+//! none. `check` is how the server's last structural check of the
+//! repository went, see `checks`, null for storage that runs none, and
+//! `time` null if it never ran. `write_report` prints the same for
+//! people, and the check is flagged overdue when it is.
+//!
+//! With `BackupOptions::dry_run` a backup reads, chunks and looks up every
+//! chunk as usual against storage that drops every write, see
+//! `storage::discard`, and does not commit. Its stats tell what a real
+//! backup would add: `new_bytes` of objects in `new_chunks` new chunks
+//! and trees, and `put_bytes` put to storage once sealed into packs and
+//! indexed. The head returned names a snapshot that was never stored,
+//! and the stat cache is left as it was.
+//!
+//! A file modified while it is read, its size, modification or change
+//! time differing after, is read again, up to `MAX_FILE_ATTEMPTS` times,
+//! so a snapshot does not mix two versions of one file. If it keeps
+//! changing the last version read is kept, never cached, and listed in
+//! the snapshot's `changed`, see `tree`, and the stats. The summary counts
+//! it with the errors, a live database or log backed up this way may not
+//! be usable. Changes between files are not detected, a snapshot is only
+//! consistent if the tree is quiet or itself a snapshot.
+//!
+//! The snapshot names the owners and groups of what it stores as this
+//! machine knows them, see `owners`, for a restore elsewhere. Snapshots
+//! of a tar stream or standard input name none.
+
+use super::address::{Address, AddressKey};
+use super::checks::CheckStatus;
+use super::chunker::Chunker;
+use super::datetime::unix_now;
+use super::exclude::{ExcludeOptions, Filter};
+use super::index::RepoIndex;
+use super::json::{json_path, write_errors, Summary};
+use super::manifest::SnapshotHead;
+use super::memory;
+use super::mounts::{is_pseudo, MountTable};
+use super::namespace::Namespace;
+use super::object::ObjectKind;
+use super::owners;
+use super::progress::{human_bytes, Progress};
+use super::scan::{scan_dir, Listing, Scanner};
+use super::special;
+use super::statcache::{CachedFile, StatCache};
+use super::storage::discard::DiscardStorage;
+use super::tar::{Header, TarReader};
+use super::transaction::Transaction;
+use super::tree::{
+    check_metadata, is_valid_name, EntryKind, Snapshot, SnapshotError, Tree, TreeEntry, NAMES_UNIX,
+};
+use super::upload::UploadOptions;
+use super::usage::Quota;
+use super::xattr::{self, XattrOptions};
+use super::{Repo, RepoError};
+use asymcrypt::Key;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+pub const MAX_FILE_ATTEMPTS: usize = 3;
+pub const MAX_PENDING_BYTES: u64 = 32 * 1024 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Symlinks {
+    #[default]
+    Store,
+    Follow,
+}
+
+#[derive(Clone, Debug)]
+pub struct BackupOptions {
+    pub upload: UploadOptions,
+    // Tags and a description recorded in the snapshot, see `tree`.
+    pub tags: Vec<String>,
+    pub description: String,
+    pub exclude: ExcludeOptions,
+    // Stay on the filesystem of the directory backed up.
+    pub one_file_system: bool,
+    pub symlinks: Symlinks,
+    // Leave out sockets, fifos and devices.
+    pub skip_specials: bool,
+    // Store access and birth times, see `tree`.
+    pub atime: bool,
+    pub birthtime: bool,
+    // Which extended attributes to store, see `xattr`.
+    pub xattrs: XattrOptions,
+    // The cache root of the stat cache, None to read every file.
+    pub stat_cache: Option<PathBuf>,
+    // Read every file even if the stat cache has it, and rewrite the cache.
+    pub force_rescan: bool,
+    // Threads listing directories ahead of the walk, 0 to list each one
+    // as it is walked.
+    pub scan_threads: usize,
+    // Small files and trees sealed together.
+    pub pending_bytes: u64,
+    // Cut threads, queues and packs down to fit, see `memory`.
+    pub max_memory: Option<u64>,
+    pub progress: Option<Arc<Progress>>,
+    // Read, chunk and deduplicate as usual but store nothing.
+    pub dry_run: bool,
+}
+
+impl Default for BackupOptions {
+    fn default() -> BackupOptions {
+        BackupOptions {
+            upload: Default::default(),
+            tags: Vec::new(),
+            description: String::new(),
+            exclude: Default::default(),
+            one_file_system: false,
+            symlinks: Default::default(),
+            skip_specials: false,
+            atime: false,
+            birthtime: false,
+            xattrs: Default::default(),
+            stat_cache: None,
+            force_rescan: false,
+            scan_threads: 8,
+            pending_bytes: MAX_PENDING_BYTES,
+            max_memory: None,
+            progress: None,
+            dry_run: false,
+        }
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct BackupStats {
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    // Sockets, fifos and devices stored, and those left out by
+    // `BackupOptions::skip_specials`.
+    pub specials: u64,
+    pub skipped: u64,
+    // Paths left out by the exclude rules, not counting those below an
+    // excluded directory.
+    pub excluded: u64,
+    pub bytes: u64,
+    // Files whose chunks came from the stat cache.
+    pub cached: u64,
+    pub new_chunks: u64,
+    pub new_bytes: u64,
+    // The new chunks and trees once compressed.
+    pub compressed_bytes: u64,
+    pub errors: Vec<SnapshotError>,
+    // Mountpoints whose filesystem was not backed up.
+    pub mounts: Vec<Vec<u8>>,
+    // Files that kept changing while they were read.
+    pub changed: Vec<Vec<u8>>,
+    // Nothing was stored, the snapshot was never committed.
+    pub dry_run: bool,
+    // What a dry run would have put, packs, parity and indexes.
+    pub put_bytes: u64,
+    // Files read rather than taken from the stat cache, and the bytes
+    // read, rereads of files that changed included.
+    pub read_files: u64,
+    pub read_bytes: u64,
+    // Bytes of the packs stored, or that would have been on a dry run.
+    pub stored_bytes: u64,
+    // Loading the index and stat cache, walking, reading and storing,
+    // and committing.
+    pub prepare_time: Duration,
+    pub walk_time: Duration,
+    pub commit_time: Duration,
+    // The server's quotas and their use after the backup.
+    pub quotas: Vec<Quota>,
+    // How the server's checks of the repository went.
+    pub check: CheckStatus,
+}
+
+impl BackupStats {
+    pub fn write_ndjson(&self, w: &mut dyn Write, head: &SnapshotHead) -> io::Result<()> {
+        let mounts: Vec<String> = self.mounts.iter().map(|m| json_path(m)).collect();
+        let changed: Vec<String> = self.changed.iter().map(|c| json_path(c)).collect();
+        let quotas: Vec<String> = self
+            .quotas
+            .iter()
+            .map(|q| {
+                format!(
+                    "{{\"scope\": \"{}\", \"quota\": {}, \"stored_bytes\": {}, \"headroom\": {}}}",
+                    q.scope.name(),
+                    q.quota,
+                    q.stored_bytes,
+                    q.headroom()
+                )
+            })
+            .collect();
+        let check = match self.check.every {
+            Some(every) => {
+                let last = self.check.last.unwrap_or_default();
+                format!(
+                    "{{\"every\": {}, \"time\": {}, \"warnings\": {}, \"errors\": {}, \"overdue\": {}}}",
+                    every,
+                    self.check
+                        .last
+                        .map_or("null".to_string(), |last| last.time.to_string()),
+                    last.warnings,
+                    last.errors,
+                    self.check.is_overdue(unix_now())
+                )
+            }
+            None => "null".to_string(),
+        };
+        // Namespaces never need escaping, see `namespace`.
+        writeln!(
+            w,
+            "{{\"type\": \"backup\", \"address\": \"{}\", \"namespace\": \"{}\", \"time\": {}, \
+             \"files\": {}, \"dirs\": {}, \"symlinks\": {}, \"specials\": {}, \"skipped\": {}, \
+             \"excluded\": {}, \"bytes\": {}, \"cached\": {}, \"new_chunks\": {}, \"new_bytes\": {}, \
+             \"compressed_bytes\": {}, \"errors\": {}, \"mounts\": [{}], \"changed\": [{}], \
+             \"dry_run\": {}, \
+             \"put_bytes\": {}, \"read_files\": {}, \"read_bytes\": {}, \"stored_bytes\": {}, \
+             \"prepare_seconds\": {:.3}, \"walk_seconds\": {:.3}, \"commit_seconds\": {:.3}, \
+             \"quotas\": [{}], \"check\": {}}}",
+            head.address.to_hex(),
+            head.namespace,
+            head.timestamp,
+            self.files,
+            self.dirs,
+            self.symlinks,
+            self.specials,
+            self.skipped,
+            self.excluded,
+            self.bytes,
+            self.cached,
+            self.new_chunks,
+            self.new_bytes,
+            self.compressed_bytes,
+            self.errors.len(),
+            mounts.join(", "),
+            changed.join(", "),
+            self.dry_run,
+            self.put_bytes,
+            self.read_files,
+            self.read_bytes,
+            self.stored_bytes,
+            self.prepare_time.as_secs_f64(),
+            self.walk_time.as_secs_f64(),
+            self.commit_time.as_secs_f64(),
+            quotas.join(", "),
+            check
+        )?;
+        write_errors(w, &self.errors)
+    }
+
+    // The summary printed after a backup, see the module documentation.
+    pub fn write_report(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            w,
+            "files   {} found, {} read, {} from the stat cache",
+            self.files, self.read_files, self.cached
+        )?;
+        writeln!(
+            w,
+            "data    {} found, {} read, {} new after deduplication, {} compressed, {} stored",
+            human_bytes(self.bytes),
+            human_bytes(self.read_bytes),
+            human_bytes(self.new_bytes),
+            human_bytes(self.compressed_bytes),
+            human_bytes(self.stored_bytes)
+        )?;
+        writeln!(
+            w,
+            "time    {:.1}s preparing, {:.1}s reading and storing, {:.1}s committing",
+            self.prepare_time.as_secs_f64(),
+            self.walk_time.as_secs_f64(),
+            self.commit_time.as_secs_f64()
+        )?;
+        for q in self.quotas.iter() {
+            writeln!(w, "quota   {}", q)?;
+        }
+        if self.check.every.is_some() {
+            writeln!(w, "check   {}", self.check)?;
+        }
+        Ok(())
+    }
+
+    pub fn summary(&self) -> Summary {
+        Summary {
+            errors: (self.errors.len() + self.changed.len()) as u64,
+            ..Summary::new("put")
+        }
+    }
+}
+
+// Remembers a read error so it can be told apart from repository errors
+// once `add_stream` returns, and counts what it reads as progress.
+struct SourceReader<'p, R: Read> {
+    inner: R,
+    error: Option<io::Error>,
+    progress: Option<&'p Progress>,
+}
+
+impl<'p, R: Read> Read for SourceReader<'p, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf).inspect_err(|e| {
+            if e.kind() != io::ErrorKind::Interrupted {
+                self.error = Some(io::Error::new(e.kind(), e.to_string()));
+            }
+        })?;
+        if let Some(p) = self.progress {
+            p.add_read(n as u64);
+        }
+        Ok(n)
+    }
+}
+
+fn unchanged(before: &Metadata, after: &Metadata) -> bool {
+    before.len() == after.len()
+        && before.mtime() == after.mtime()
+        && before.mtime_nsec() == after.mtime_nsec()
+        && before.ctime() == after.ctime()
+        && before.ctime_nsec() == after.ctime_nsec()
+}
+
+fn entry_for(name: &[u8], kind: EntryKind, meta: &Metadata) -> TreeEntry {
+    TreeEntry {
+        name: name.to_vec(),
+        kind,
+        mode: meta.mode() & 0o7777,
+        uid: meta.uid(),
+        gid: meta.gid(),
+        mtime: meta.mtime().max(0) as u64,
+        mtime_nsec: meta.mtime_nsec() as u32,
+        size: 0,
+        refs: Vec::new(),
+        target: Vec::new(),
+        link: 0,
+        xattrs: Vec::new(),
+        device: (0, 0),
+        atime: None,
+        birthtime: None,
+    }
+}
+
+// Add the access and birth times `opts` asks for to `ent`.
+fn add_times(ent: &mut TreeEntry, meta: &Metadata, opts: &BackupOptions) {
+    if opts.atime {
+        ent.atime = Some((meta.atime().max(0) as u64, meta.atime_nsec() as u32));
+    }
+    if opts.birthtime {
+        ent.birthtime = meta
+            .created()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| (d.as_secs(), d.subsec_nanos()));
+    }
+}
+
+// The tree of a tar archive being imported.
+#[derive(Default)]
+struct TarDir {
+    // From the directory's own entry, if the archive has one.
+    ent: Option<TreeEntry>,
+    dirs: BTreeMap<Vec<u8>, TarDir>,
+    others: BTreeMap<Vec<u8>, TreeEntry>,
+}
+
+impl TarDir {
+    // The directory at `path`, made along with its parents if missing.
+    fn dir(&mut self, path: &[Vec<u8>]) -> &mut TarDir {
+        let mut dir = self;
+        for name in path {
+            dir.others.remove(name);
+            dir = dir.dirs.entry(name.clone()).or_default();
+        }
+        dir
+    }
+
+    fn insert(&mut self, path: &[Vec<u8>], ent: TreeEntry) {
+        let (name, parent) = path.split_last().unwrap();
+        let parent = self.dir(parent);
+        parent.dirs.remove(name);
+        parent.others.insert(name.clone(), ent);
+    }
+
+    fn file(&self, path: &[Vec<u8>]) -> Option<&TreeEntry> {
+        let (name, parent) = path.split_last()?;
+        let mut dir = self;
+        for name in parent {
+            dir = dir.dirs.get(name)?;
+        }
+        dir.others
+            .get(name)
+            .filter(|ent| ent.kind == EntryKind::File)
+    }
+}
+
+// The names along a path in an archive, None if it climbs out.
+fn tar_path(path: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut names = Vec::new();
+    for name in path.split(|b| *b == b'/') {
+        match name {
+            b"" | b"." => (),
+            name if is_valid_name(name) => names.push(name.to_vec()),
+            _ => return None,
+        }
+    }
+    Some(names)
+}
+
+fn tar_entry_for(name: &[u8], kind: EntryKind, h: &Header) -> TreeEntry {
+    TreeEntry {
+        name: name.to_vec(),
+        kind,
+        mode: h.mode,
+        uid: h.uid,
+        gid: h.gid,
+        mtime: h.mtime,
+        mtime_nsec: h.mtime_nsec,
+        size: 0,
+        refs: Vec::new(),
+        target: Vec::new(),
+        link: 0,
+        xattrs: Vec::new(),
+        device: (0, 0),
+        atime: h.atime,
+        birthtime: None,
+    }
+}
+
+// Objects addressed but not yet stored.
+#[derive(Default)]
+struct Pending {
+    objects: Vec<(Address, ObjectKind, Vec<u8>)>,
+    addresses: HashSet<Address>,
+    bytes: u64,
+}
+
+// Files with more than one hard link, see `tree`.
+#[derive(Default)]
+struct Links {
+    // The entry stored for each device and inode.
+    inodes: HashMap<(u64, u64), TreeEntry>,
+    ids: HashSet<u64>,
+}
+
+impl Links {
+    // A link id for inode `ino`, the inode number itself unless taken.
+    fn id(&mut self, ino: u64) -> u64 {
+        let (mut id, mut fallback) = (ino, u64::MAX);
+        while id == 0 || !self.ids.insert(id) {
+            id = fallback;
+            fallback -= 1;
+        }
+        id
+    }
+}
+
+struct Walk<'t, 'r> {
+    tx: &'t mut Transaction<'r>,
+    address_key: &'t AddressKey,
+    present: RepoIndex,
+    pending: Pending,
+    opts: &'t BackupOptions,
+    // `opts.upload` reporting to `opts.progress`.
+    upload: UploadOptions,
+    filter: Filter<'t>,
+    scanner: Scanner,
+    // From the root to the directory being walked.
+    names: Vec<Vec<u8>>,
+    // The absolute path and device of the root.
+    root: PathBuf,
+    root_dev: u64,
+    mounts: MountTable,
+    // Device and inode of each directory from the root to the one being
+    // walked, to find symlink loops.
+    ancestors: Vec<(u64, u64)>,
+    // From the last backup and for the next one.
+    old_stat_cache: StatCache,
+    stat_cache: StatCache,
+    links: Links,
+    // Owner and group ids of the entries stored, to name in the snapshot.
+    uids: BTreeSet<u32>,
+    gids: BTreeSet<u32>,
+    started: i64,
+    stats: BackupStats,
+}
+
+impl<'t, 'r> Walk<'t, 'r> {
+    // Record a problem with the source, leaving `path` out.
+    fn source_error<T>(&mut self, path: &Path, err: io::Error) -> Option<T> {
+        let message = if err.kind() == io::ErrorKind::NotFound {
+            "vanished during the backup".to_string()
+        } else {
+            err.to_string()
+        };
+        self.stats.errors.push(SnapshotError {
+            path: path.as_os_str().as_bytes().to_vec(),
+            message,
+        });
+        None
+    }
+
+    fn store(&mut self, kind: ObjectKind, data: &[u8]) -> Result<Address, RepoError> {
+        let address = self.address_key.address(data);
+        if !self.present.contains(&address)
+            && !self.tx.contains(&address)
+            && self.pending.addresses.insert(address)
+        {
+            if kind == ObjectKind::Chunk {
+                self.stats.new_chunks += 1;
+                self.stats.new_bytes += data.len() as u64;
+            }
+            self.pending.objects.push((address, kind, data.to_vec()));
+            self.pending.bytes += data.len() as u64;
+            if self.pending.bytes >= self.opts.pending_bytes {
+                self.flush()?;
+            }
+        }
+        Ok(address)
+    }
+
+    // Seal and store the pending objects on the upload workers.
+    fn flush(&mut self) -> Result<(), RepoError> {
+        if self.pending.objects.is_empty() {
+            return Ok(());
+        }
+        let pending = std::mem::take(&mut self.pending);
+        let up = self.tx.add_objects(
+            pending.objects,
+            self.address_key,
+            &self.present,
+            &self.upload,
+        )?;
+        self.stats.compressed_bytes += up.compressed_bytes;
+        Ok(())
+    }
+
+    fn report(&self) {
+        if let Some(ref p) = self.opts.progress {
+            p.set_walked(self.stats.files, self.stats.dirs, self.stats.bytes);
+        }
+    }
+
+    // Store the contents of an open file, returning its chunks and length.
+    fn contents(
+        &mut self,
+        f: &mut dyn Read,
+        len: u64,
+    ) -> Result<io::Result<(Vec<Address>, u64)>, RepoError> {
+        let params = self.tx.repo().config().chunker.clone();
+        if len <= params.min_size as u64 {
+            let mut buf = Vec::new();
+            if let Err(err) = f.read_to_end(&mut buf) {
+                return Ok(Err(err));
+            }
+            let mut chunks = Vec::new();
+            for data in Chunker::new(&buf[..], &params)? {
+                chunks.push(self.store(ObjectKind::Chunk, &data?)?);
+            }
+            self.stats.read_bytes += buf.len() as u64;
+            return Ok(Ok((chunks, buf.len() as u64)));
+        }
+        // So the stream deduplicates against everything before it.
+        self.flush()?;
+        let mut r = SourceReader {
+            inner: f,
+            error: None,
+            progress: self.opts.progress.as_deref(),
+        };
+        let upload = self
+            .tx
+            .add_stream(&mut r, self.address_key, &self.present, &self.upload);
+        match (upload, r.error) {
+            (Ok(up), _) => {
+                self.stats.new_chunks += up.new_chunks as u64;
+                self.stats.new_bytes += up.new_bytes;
+                self.stats.compressed_bytes += up.compressed_bytes;
+                self.stats.read_bytes += up.bytes;
+                Ok(Ok((up.addresses, up.bytes)))
+            }
+            (Err(_), Some(err)) => Ok(Err(err)),
+            (Err(err), None) => Err(err),
+        }
+    }
+
+    // The stat cache entry of an unchanged file whose chunks are all still
+    // in the repository.
+    fn cached(&self, rel: &[u8], meta: &Metadata) -> Option<CachedFile> {
+        if self.opts.force_rescan {
+            return None;
+        }
+        self.old_stat_cache
+            .get(rel, meta)
+            .filter(|f| f.refs.iter().all(|a| self.present.contains(a)))
+            .cloned()
+    }
+
+    fn file(
+        &mut self,
+        path: &Path,
+        name: &[u8],
+        meta: &Metadata,
+    ) -> Result<Option<TreeEntry>, RepoError> {
+        let caching = self.opts.stat_cache.is_some();
+        let rel = if caching {
+            let mut rel = Vec::new();
+            for n in self.names.iter() {
+                rel.extend_from_slice(n);
+                rel.push(b'/');
+            }
+            rel.extend_from_slice(name);
+            rel
+        } else {
+            Vec::new()
+        };
+        if let Some(f) = self.cached(&rel, meta) {
+            self.stats.files += 1;
+            self.stats.bytes += f.size;
+            self.stats.cached += 1;
+            let mut ent = entry_for(name, EntryKind::File, meta);
+            ent.size = f.size;
+            ent.refs = f.refs.clone();
+            self.stat_cache.insert(rel, f);
+            return Ok(Some(ent));
+        }
+        for attempt in 1..=MAX_FILE_ATTEMPTS {
+            let mut f = match File::open(path) {
+                Ok(f) => f,
+                Err(err) => return Ok(self.source_error(path, err)),
+            };
+            let before = match f.metadata() {
+                Ok(meta) => meta,
+                Err(err) => return Ok(self.source_error(path, err)),
+            };
+            let (chunks, size) = match self.contents(&mut f, before.len())? {
+                Ok(stored) => stored,
+                Err(err) => return Ok(self.source_error(path, err)),
+            };
+            let after = match f.metadata() {
+                Ok(meta) => meta,
+                Err(err) => return Ok(self.source_error(path, err)),
+            };
+            let changed = !unchanged(&before, &after);
+            if changed && attempt < MAX_FILE_ATTEMPTS {
+                continue;
+            }
+            if changed {
+                self.stats
+                    .changed
+                    .push(path.as_os_str().as_bytes().to_vec());
+            } else if caching && after.ctime() < self.started {
+                self.stat_cache
+                    .insert(rel.clone(), CachedFile::new(&after, chunks.clone()));
+            }
+            self.stats.files += 1;
+            self.stats.read_files += 1;
+            self.stats.bytes += size;
+            let mut ent = entry_for(name, EntryKind::File, &after);
+            ent.size = size;
+            ent.refs = chunks;
+            return Ok(Some(ent));
+        }
+        unreachable!()
+    }
+
+    // Like `file`, reading the hard links to one file once.
+    fn linked_file(
+        &mut self,
+        path: &Path,
+        name: &[u8],
+        meta: &Metadata,
+    ) -> Result<Option<TreeEntry>, RepoError> {
+        if meta.nlink() < 2 {
+            return self.file(path, name, meta);
+        }
+        let inode = (meta.dev(), meta.ino());
+        if let Some(first) = self.links.inodes.get(&inode) {
+            let ent = TreeEntry {
+                name: name.to_vec(),
+                ..first.clone()
+            };
+            self.stats.files += 1;
+            self.stats.bytes += ent.size;
+            return Ok(Some(ent));
+        }
+        let mut ent = match self.file(path, name, meta)? {
+            Some(ent) => ent,
+            None => return Ok(None),
+        };
+        ent.link = self.links.id(meta.ino());
+        self.links.inodes.insert(inode, ent.clone());
+        Ok(Some(ent))
+    }
+
+    // The entry for `path`, given the result of its `lstat`.
+    fn entry(
+        &mut self,
+        path: &Path,
+        name: &[u8],
+        meta: io::Result<Metadata>,
+    ) -> Result<Option<TreeEntry>, RepoError> {
+        let mut meta = match meta {
+            Ok(meta) => meta,
+            Err(err) => return Ok(self.source_error(path, err)),
+        };
+        let mut followed = false;
+        if meta.file_type().is_symlink() && self.opts.symlinks == Symlinks::Follow {
+            match fs::metadata(path) {
+                Ok(target)
+                    if target.is_dir()
+                        && self.ancestors.contains(&(target.dev(), target.ino())) =>
+                {
+                    let err = io::Error::other("symlink loop, stored as a link");
+                    self.source_error::<()>(path, err);
+                }
+                Ok(target) => {
+                    meta = target;
+                    followed = true;
+                }
+                // Dangling, stored as a link.
+                Err(_) => (),
+            }
+        }
+        let ft = meta.file_type();
+        self.names.push(name.to_vec());
+        let excluded = self.filter.excludes(&self.names, ft.is_dir());
+        self.names.pop();
+        if excluded {
+            self.stats.excluded += 1;
+            return Ok(None);
+        }
+        let mut ent = if ft.is_file() {
+            match self.linked_file(path, name, &meta)? {
+                Some(ent) => ent,
+                None => return Ok(None),
+            }
+        } else if ft.is_dir() {
+            self.names.push(name.to_vec());
+            let skipped = self.skipped_mount(&meta);
+            self.names.pop();
+            let listing = if skipped {
+                self.stats.mounts.push(path.as_os_str().as_bytes().to_vec());
+                Vec::new()
+            } else {
+                match self.scanner.take(path) {
+                    Ok(listing) => listing,
+                    Err(err) => return Ok(self.source_error(path, err)),
+                }
+            };
+            let mut ent = entry_for(name, EntryKind::Dir, &meta);
+            self.names.push(name.to_vec());
+            self.ancestors.push((meta.dev(), meta.ino()));
+            let tree = self.dir(path, listing);
+            self.ancestors.pop();
+            self.names.pop();
+            ent.refs.push(tree?);
+            ent
+        } else if ft.is_symlink() {
+            let target = match fs::read_link(path) {
+                Ok(target) => target,
+                Err(err) => return Ok(self.source_error(path, err)),
+            };
+            self.stats.symlinks += 1;
+            let mut ent = entry_for(name, EntryKind::Symlink, &meta);
+            ent.target = target.as_os_str().as_bytes().to_vec();
+            ent
+        } else if let Some(kind) = special::kind(&ft) {
+            if self.opts.skip_specials {
+                self.stats.skipped += 1;
+                return Ok(None);
+            }
+            self.stats.specials += 1;
+            let mut ent = entry_for(name, kind, &meta);
+            if let EntryKind::BlockDevice | EntryKind::CharDevice = kind {
+                ent.device = special::device_numbers(meta.rdev());
+            }
+            ent
+        } else {
+            self.stats.skipped += 1;
+            return Ok(None);
+        };
+        add_times(&mut ent, &meta, self.opts);
+        match xattr::read(path, followed, &self.opts.xattrs) {
+            Ok(xattrs) => ent.xattrs = xattrs,
+            Err(err) => {
+                let err = io::Error::new(err.kind(), format!("extended attributes: {}", err));
+                self.source_error::<()>(path, err);
+            }
+        }
+        Ok(Some(ent))
+    }
+
+    // Whether the directory at `names`, with `meta`, is a mountpoint not
+    // to walk into.
+    fn skipped_mount(&self, meta: &Metadata) -> bool {
+        let path: PathBuf = self
+            .names
+            .iter()
+            .fold(self.root.clone(), |p, n| p.join(OsStr::from_bytes(n)));
+        let fstype = self.mounts.fstype(&path);
+        fstype.is_some_and(is_pseudo)
+            || (self.opts.one_file_system && (meta.dev() != self.root_dev || fstype.is_some()))
+    }
+
+    // Store the tree of a directory holding `listing`.
+    fn dir(&mut self, path: &Path, listing: Listing) -> Result<Address, RepoError> {
+        let mut tree: Tree = Default::default();
+        if let Err(err) = self.filter.enter(path, self.names.len()) {
+            let ignore = path.join(super::exclude::IGNORE_FILE);
+            self.source_error::<()>(&ignore, err);
+        }
+        // Have the subdirectories `entry` will walk into scanned meanwhile,
+        // making the same checks it does.
+        for (name, meta) in listing.iter() {
+            if let Ok(ref meta) = *meta {
+                if meta.is_dir() {
+                    self.names.push(name.as_bytes().to_vec());
+                    let walked =
+                        !self.filter.excludes(&self.names, true) && !self.skipped_mount(meta);
+                    self.names.pop();
+                    if walked {
+                        self.scanner.submit(path.join(name));
+                    }
+                }
+            }
+        }
+        for (name, meta) in listing {
+            if let Some(ent) = self.entry(&path.join(&name), name.as_bytes(), meta)? {
+                self.uids.insert(ent.uid);
+                self.gids.insert(ent.gid);
+                tree.entries.push(ent);
+            }
+            self.report();
+        }
+        self.filter.leave();
+        self.tx.refresh()?;
+        self.stats.dirs += 1;
+        self.store(ObjectKind::Tree, &tree.encode())
+    }
+
+    fn archive_error(&mut self, path: &[u8], message: &str) {
+        self.stats.errors.push(SnapshotError {
+            path: path.to_vec(),
+            message: message.to_string(),
+        });
+    }
+
+    // Add the entry `h` of an archive to `root`.
+    fn tar_entry(
+        &mut self,
+        tar: &mut TarReader<&mut dyn Read>,
+        h: Header,
+        root: &mut TarDir,
+    ) -> Result<(), RepoError> {
+        let path = match tar_path(&h.path) {
+            Some(path) => path,
+            None => {
+                self.archive_error(&h.path, "path is outside the archive");
+                return Ok(());
+            }
+        };
+        let name = match path.last() {
+            Some(name) => name.clone(),
+            // The archive root itself, as `./`.
+            None if h.typeflag == b'5' => return Ok(()),
+            None => {
+                self.archive_error(&h.path, "not a directory at the archive root");
+                return Ok(());
+            }
+        };
+        match h.typeflag {
+            b'0' | b'\0' | b'7' => {
+                let (chunks, size) = self.contents(tar, h.size)??;
+                self.stats.read_files += 1;
+                let mut ent = tar_entry_for(&name, EntryKind::File, &h);
+                ent.size = size;
+                ent.refs = chunks;
+                root.insert(&path, ent);
+            }
+            b'1' => {
+                let target = tar_path(&h.link).and_then(|link| root.file(&link).cloned());
+                match target {
+                    Some(mut ent) => {
+                        ent.name = name;
+                        root.insert(&path, ent);
+                    }
+                    None => self.archive_error(&h.path, "hard link to a file not in the archive"),
+                }
+            }
+            b'2' => {
+                let mut ent = tar_entry_for(&name, EntryKind::Symlink, &h);
+                ent.target = h.link.clone();
+                root.insert(&path, ent);
+            }
+            b'5' => root.dir(&path).ent = Some(tar_entry_for(&name, EntryKind::Dir, &h)),
+            b'3' | b'4' | b'6' if self.opts.skip_specials => self.stats.skipped += 1,
+            b'3' | b'4' | b'6' => {
+                let kind = match h.typeflag {
+                    b'3' => EntryKind::CharDevice,
+                    b'4' => EntryKind::BlockDevice,
+                    _ => EntryKind::Fifo,
+                };
+                let mut ent = tar_entry_for(&name, kind, &h);
+                if kind != EntryKind::Fifo {
+                    ent.device = h.device;
+                }
+                root.insert(&path, ent);
+            }
+            _ => self.archive_error(&h.path, "unsupported tar entry type"),
+        }
+        Ok(())
+    }
+
+    // Store the trees of `dir` and below, those the archive did not list
+    // are modified at `now`. Only what is stored counts in the stats, not
+    // entries replaced by later ones.
+    fn tar_dir(&mut self, dir: TarDir, now: u64) -> Result<Address, RepoError> {
+        let mut tree: Tree = Default::default();
+        for (name, sub) in dir.dirs {
+            let mut ent = sub.ent.clone().unwrap_or_else(|| TreeEntry {
+                name: name.clone(),
+                kind: EntryKind::Dir,
+                mode: 0o755,
+                uid: 0,
+                gid: 0,
+                mtime: now,
+                mtime_nsec: 0,
+                size: 0,
+                refs: Vec::new(),
+                target: Vec::new(),
+                link: 0,
+                xattrs: Vec::new(),
+                device: (0, 0),
+                atime: None,
+                birthtime: None,
+            });
+            ent.refs = vec![self.tar_dir(sub, now)?];
+            tree.entries.push(ent);
+        }
+        for ent in dir.others.into_values() {
+            match ent.kind {
+                EntryKind::File => {
+                    self.stats.files += 1;
+                    self.stats.bytes += ent.size;
+                }
+                EntryKind::Symlink => self.stats.symlinks += 1,
+                _ => self.stats.specials += 1,
+            }
+            tree.entries.push(ent);
+        }
+        tree.entries.sort_by(|a, b| a.name.cmp(&b.name));
+        self.tx.refresh()?;
+        self.stats.dirs += 1;
+        self.store(ObjectKind::Tree, &tree.encode())
+    }
+}
+
+// The name of this machine, empty if it cannot be found.
+pub fn hostname() -> String {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .filter_map(|p| fs::read_to_string(p).ok())
+        .map(|h| h.trim().to_string())
+        .find(|h| !h.is_empty())
+        .unwrap_or_default()
+}
+
+fn new_snapshot(
+    root: Address,
+    source: &[u8],
+    key_namespace: Option<&Namespace>,
+    stats: &BackupStats,
+    opts: &BackupOptions,
+) -> Snapshot {
+    let mut tags = opts.tags.clone();
+    tags.sort();
+    tags.dedup();
+    Snapshot {
+        root,
+        time: unix_now(),
+        source: source.to_vec(),
+        host: hostname(),
+        tags,
+        files: stats.files,
+        dirs: stats.dirs,
+        bytes: stats.bytes,
+        errors: stats.errors.clone(),
+        mounts: stats.mounts.clone(),
+        description: opts.description.clone(),
+        name_encoding: NAMES_UNIX,
+        changed: stats.changed.clone(),
+        users: Vec::new(),
+        groups: Vec::new(),
+        key_namespace: key_namespace.map_or(String::new(), |ns| ns.to_string()),
+    }
+}
+
+// Add `snapshot` and commit it.
+// The bytes of the newest snapshot of `source` in `namespace`, about what
+// backing it up again reads.
+fn last_backup_bytes(
+    r: &Repo,
+    index: &RepoIndex,
+    key: &Key,
+    namespace: &Namespace,
+    source: &[u8],
+) -> Option<u64> {
+    let mut heads = r.manifest().ok()?.heads;
+    heads.retain(|h| h.namespace == *namespace);
+    heads.sort_by_key(|h| std::cmp::Reverse(h.timestamp));
+    heads
+        .iter()
+        .filter_map(|h| r.read_snapshot(index, &key.box_sk, &h.address).ok())
+        .find(|s| s.source == source)
+        .map(|s| s.bytes)
+}
+
+// Commit `snapshot`, or on a dry run count what committing would put.
+fn commit_snapshot(
+    repo: &Repo,
+    mut tx: Transaction,
+    snapshot: &Snapshot,
+    namespace: &Namespace,
+    (address_key, key): (&AddressKey, &Key),
+    dry: Option<&DryRun>,
+    stats: &mut BackupStats,
+) -> Result<SnapshotHead, RepoError> {
+    let started = Instant::now();
+    let buf = snapshot.encode();
+    let address = address_key.address(&buf);
+    tx.add(&address, ObjectKind::Snapshot, &buf)?;
+    tx.flush()?;
+    stats.stored_bytes = tx.stored_bytes();
+    let head = SnapshotHead {
+        address,
+        timestamp: snapshot.time,
+        namespace: namespace.clone(),
+        retain_until: 0,
+        amendment: None,
+    };
+    match dry {
+        Some((_, storage)) => {
+            tx.checkpoint()?;
+            stats.dry_run = true;
+            stats.put_bytes = storage.put_bytes();
+        }
+        None => tx.commit(head.clone(), key)?,
+    }
+    stats.commit_time = started.elapsed();
+    // The backup is stored, not knowing what is left is no reason to fail.
+    stats.quotas = repo.quotas().unwrap_or_default();
+    stats.check = repo.check_status().unwrap_or_default();
+    Ok(head)
+}
+
+type DryRun = (Repo, Arc<DiscardStorage>);
+
+fn dry_run(repo: &Repo, opts: &BackupOptions) -> Option<DryRun> {
+    Some(repo.discarding()).filter(|_| opts.dry_run)
+}
+
+impl Repo {
+    // Back up the directory at `path` as a new snapshot in `namespace`.
+    pub fn backup(
+        &self,
+        path: &Path,
+        namespace: &Namespace,
+        address_key: &AddressKey,
+        key: &Key,
+        opts: &BackupOptions,
+    ) -> Result<(SnapshotHead, BackupStats), RepoError> {
+        let started = Instant::now();
+        let own_key = self.namespace_address_key(address_key, namespace);
+        let address_key = own_key.as_ref().unwrap_or(address_key);
+        let key_namespace = own_key.as_ref().map(|_| namespace);
+        check_metadata(&opts.tags, &opts.description)?;
+        let listing = scan_dir(path)?;
+        let root_meta = fs::metadata(path)?;
+        let source: PathBuf = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let source = source.as_os_str().as_bytes();
+        let repo_id = self.config().repo_id;
+        let old_stat_cache = match opts.stat_cache {
+            Some(ref dir) => StatCache::load(dir, &repo_id, namespace, source, address_key),
+            None => Default::default(),
+        };
+        let dry = dry_run(self, opts);
+        let repo = dry.as_ref().map_or(self, |(r, _)| r);
+        let present = repo.load_index()?;
+        let opts = &memory::fit(opts, &self.config().chunker, present.len())?;
+        let mut tx = repo.begin(opts.upload.packer.clone())?;
+        let mut walk = Walk {
+            tx: &mut tx,
+            address_key,
+            present,
+            pending: Default::default(),
+            opts,
+            upload: UploadOptions {
+                progress: opts.progress.clone(),
+                ..opts.upload.clone()
+            },
+            filter: Filter::new(&opts.exclude),
+            scanner: Scanner::new(opts.scan_threads),
+            names: Vec::new(),
+            root: PathBuf::from(OsStr::from_bytes(source)),
+            root_dev: root_meta.dev(),
+            mounts: MountTable::load(),
+            ancestors: vec![(root_meta.dev(), root_meta.ino())],
+            old_stat_cache,
+            stat_cache: Default::default(),
+            links: Default::default(),
+            uids: Default::default(),
+            gids: Default::default(),
+            started: unix_now() as i64,
+            stats: Default::default(),
+        };
+        if let Some(ref p) = opts.progress {
+            if p.total().is_none() {
+                let last = last_backup_bytes(self, &walk.present, key, namespace, source);
+                p.set_total(last.unwrap_or(0));
+            }
+        }
+        walk.stats.prepare_time = started.elapsed();
+        let root = walk.dir(path, listing)?;
+        walk.flush()?;
+        walk.report();
+        walk.stats.walk_time = started.elapsed() - walk.stats.prepare_time;
+        let (mut stats, stat_cache) = (walk.stats, walk.stat_cache);
+        let mut snapshot = new_snapshot(root, source, key_namespace, &stats, opts);
+        snapshot.users = owners::user_names(&walk.uids);
+        snapshot.groups = owners::group_names(&walk.gids);
+        let keys = (address_key, key);
+        let head = commit_snapshot(
+            self,
+            tx,
+            &snapshot,
+            namespace,
+            keys,
+            dry.as_ref(),
+            &mut stats,
+        )?;
+        if let (Some(ref dir), None) = (&opts.stat_cache, &dry) {
+            // Failing to save only costs the next backup a full read.
+            let _ = stat_cache.save(dir, &repo_id, namespace, source, address_key);
+        }
+        Ok((head, stats))
+    }
+
+    // Back up a stream, such as a database dump or a tar pipe, as a
+    // snapshot of a tree holding one file called `name`. The file is owned
+    // by root, readable only by its owner and modified now. Unlike files
+    // in a tree, a stream that fails to read fails the backup.
+    pub fn backup_stream(
+        &self,
+        r: &mut dyn Read,
+        name: &str,
+        namespace: &Namespace,
+        address_key: &AddressKey,
+        key: &Key,
+        opts: &BackupOptions,
+    ) -> Result<(SnapshotHead, BackupStats), RepoError> {
+        let started = Instant::now();
+        let own_key = self.namespace_address_key(address_key, namespace);
+        let address_key = own_key.as_ref().unwrap_or(address_key);
+        let key_namespace = own_key.as_ref().map(|_| namespace);
+        if !is_valid_name(name.as_bytes()) {
+            return Err(RepoError::InvalidDataError);
+        }
+        check_metadata(&opts.tags, &opts.description)?;
+        let dry = dry_run(self, opts);
+        let repo = dry.as_ref().map_or(self, |(r, _)| r);
+        let present = repo.load_index()?;
+        let opts = &memory::fit(opts, &self.config().chunker, present.len())?;
+        let mut tx = repo.begin(opts.upload.packer.clone())?;
+        let mut walk = Walk {
+            tx: &mut tx,
+            address_key,
+            present,
+            pending: Default::default(),
+            opts,
+            upload: UploadOptions {
+                progress: opts.progress.clone(),
+                ..opts.upload.clone()
+            },
+            filter: Filter::new(&opts.exclude),
+            scanner: Scanner::new(0),
+            names: Vec::new(),
+            root: PathBuf::new(),
+            root_dev: 0,
+            mounts: Default::default(),
+            ancestors: Vec::new(),
+            old_stat_cache: Default::default(),
+            stat_cache: Default::default(),
+            links: Default::default(),
+            uids: Default::default(),
+            gids: Default::default(),
+            started: 0,
+            stats: Default::default(),
+        };
+        let prepare_time = started.elapsed();
+        let mut r = SourceReader {
+            inner: r,
+            error: None,
+            progress: opts.progress.as_deref(),
+        };
+        let up = walk
+            .tx
+            .add_stream(&mut r, address_key, &walk.present, &walk.upload)?;
+        let file = TreeEntry {
+            name: name.as_bytes().to_vec(),
+            kind: EntryKind::File,
+            mode: 0o600,
+            uid: 0,
+            gid: 0,
+            mtime: unix_now(),
+            mtime_nsec: 0,
+            size: up.bytes,
+            refs: up.addresses,
+            target: Vec::new(),
+            link: 0,
+            xattrs: Vec::new(),
+            device: (0, 0),
+            atime: None,
+            birthtime: None,
+        };
+        let tree = Tree {
+            entries: vec![file],
+        };
+        let root = walk.store(ObjectKind::Tree, &tree.encode())?;
+        walk.flush()?;
+        let mut stats = BackupStats {
+            files: 1,
+            dirs: 1,
+            bytes: up.bytes,
+            new_chunks: up.new_chunks as u64,
+            new_bytes: up.new_bytes,
+            compressed_bytes: up.compressed_bytes + walk.stats.compressed_bytes,
+            read_files: 1,
+            read_bytes: up.bytes,
+            prepare_time,
+            walk_time: started.elapsed() - prepare_time,
+            ..Default::default()
+        };
+        if let Some(ref p) = opts.progress {
+            p.set_walked(stats.files, stats.dirs, stats.bytes);
+        }
+        let snapshot = new_snapshot(root, name.as_bytes(), key_namespace, &stats, opts);
+        let keys = (address_key, key);
+        let head = commit_snapshot(
+            self,
+            tx,
+            &snapshot,
+            namespace,
+            keys,
+            dry.as_ref(),
+            &mut stats,
+        )?;
+        Ok((head, stats))
+    }
+
+    // Import the tar archive read from `r` as a snapshot of the tree it
+    // holds, with `source` recorded as its source.
+    pub fn backup_tar(
+        &self,
+        r: &mut dyn Read,
+        source: &[u8],
+        namespace: &Namespace,
+        address_key: &AddressKey,
+        key: &Key,
+        opts: &BackupOptions,
+    ) -> Result<(SnapshotHead, BackupStats), RepoError> {
+        let started = Instant::now();
+        let own_key = self.namespace_address_key(address_key, namespace);
+        let address_key = own_key.as_ref().unwrap_or(address_key);
+        let key_namespace = own_key.as_ref().map(|_| namespace);
+        check_metadata(&opts.tags, &opts.description)?;
+        let dry = dry_run(self, opts);
+        let repo = dry.as_ref().map_or(self, |(r, _)| r);
+        let present = repo.load_index()?;
+        let opts = &memory::fit(opts, &self.config().chunker, present.len())?;
+        let mut tx = repo.begin(opts.upload.packer.clone())?;
+        let mut walk = Walk {
+            tx: &mut tx,
+            address_key,
+            present,
+            pending: Default::default(),
+            opts,
+            upload: UploadOptions {
+                progress: opts.progress.clone(),
+                ..opts.upload.clone()
+            },
+            filter: Filter::new(&opts.exclude),
+            scanner: Scanner::new(0),
+            names: Vec::new(),
+            root: PathBuf::new(),
+            root_dev: 0,
+            mounts: Default::default(),
+            ancestors: Vec::new(),
+            old_stat_cache: Default::default(),
+            stat_cache: Default::default(),
+            links: Default::default(),
+            uids: Default::default(),
+            gids: Default::default(),
+            started: 0,
+            stats: Default::default(),
+        };
+        walk.stats.prepare_time = started.elapsed();
+        let mut tar = TarReader::new(r);
+        let mut root: TarDir = Default::default();
+        while let Some(h) = tar.next_header()? {
+            walk.tar_entry(&mut tar, h, &mut root)?;
+            walk.report();
+        }
+        let root = walk.tar_dir(root, unix_now())?;
+        walk.flush()?;
+        walk.report();
+        walk.stats.walk_time = started.elapsed() - walk.stats.prepare_time;
+        let mut stats = walk.stats;
+        let snapshot = new_snapshot(root, source, key_namespace, &stats, opts);
+        let keys = (address_key, key);
+        let head = commit_snapshot(
+            self,
+            tx,
+            &snapshot,
+            namespace,
+            keys,
+            dry.as_ref(),
+            &mut stats,
+        )?;
+        Ok((head, stats))
+    }
+}
+
+// Tests --------------------
+
+#[cfg(test)]
+fn test_read_tree(r: &Repo, key: &Key, index: &RepoIndex, address: &Address) -> Tree {
+    let (kind, buf) = r.read_object(index, &key.box_sk, address).unwrap();
+    assert_eq!(kind, ObjectKind::Tree);
+    Tree::decode(&buf).unwrap()
+}
+
+#[test]
+fn test_backup() {
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let ns = Namespace::new("laptop").unwrap();
+    let dir = super::storage::local::test_dir("backup");
+    let big = super::chunker::test_data(3 << 20, 1);
+    fs::create_dir_all(dir.join("sub/empty")).unwrap();
+    fs::write(dir.join("small"), b"hello").unwrap();
+    fs::write(dir.join("empty"), b"").unwrap();
+    fs::write(dir.join("sub/big"), &big).unwrap();
+    fs::write(dir.join("sub/copy"), &big).unwrap();
+    symlink("../small", dir.join("sub/link")).unwrap();
+    fs::set_permissions(dir.join("small"), fs::Permissions::from_mode(0o600)).unwrap();
+
+    let (head, stats) = r.backup(&dir, &ns, &ak, &key, &Default::default()).unwrap();
+    assert_eq!((stats.files, stats.dirs, stats.symlinks), (4, 3, 1));
+    assert_eq!(stats.bytes, 5 + 2 * big.len() as u64);
+    assert!(stats.errors.is_empty());
+    let mut out = Vec::new();
+    stats.write_ndjson(&mut out, &head).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with(&format!(
+        "{{\"type\": \"backup\", \"address\": \"{}\", \"namespace\": \"laptop\"",
+        head.address.to_hex()
+    )));
+    assert!(out.contains(&format!(
+        "\"mounts\": [], \"changed\": [], \"dry_run\": false, \"put_bytes\": 0, \
+         \"read_files\": 4, \"read_bytes\": {}, \"stored_bytes\": {}, \"prepare_seconds\": ",
+        stats.bytes, stats.stored_bytes
+    )));
+    assert!(out.contains("\"quotas\": [], \"check\": null}"));
+    assert_eq!(stats.summary().exit_code(), 0);
+    // The copy is deduplicated.
+    assert!(stats.new_bytes < 5 + big.len() as u64 + 1024);
+    assert!(stats.stored_bytes > stats.new_bytes);
+    assert!(stats.stored_bytes < stats.new_bytes + 4096);
+    assert!(stats.compressed_bytes > 0 && stats.compressed_bytes < stats.stored_bytes);
+    let mut report = Vec::new();
+    stats.write_report(&mut report).unwrap();
+    let report = String::from_utf8(report).unwrap();
+    assert!(report.starts_with("files   4 found, 4 read, 0 from the stat cache\n"));
+    assert_eq!(report.lines().count(), 3);
+    assert_eq!(r.manifest().unwrap().heads, vec![head.clone()]);
+
+    let index = r.load_index().unwrap();
+    let (kind, buf) = r.read_object(&index, &key.box_sk, &head.address).unwrap();
+    assert_eq!(kind, ObjectKind::Snapshot);
+    let snapshot = Snapshot::decode(&buf).unwrap();
+    assert_eq!(snapshot.files, 4);
+    let root = test_read_tree(&r, &key, &index, &snapshot.root);
+    let names: Vec<&[u8]> = root.entries.iter().map(|e| &e.name[..]).collect();
+    assert_eq!(names, vec![&b"empty"[..], b"small", b"sub"]);
+    assert!(root.entries[0].refs.is_empty());
+    assert_eq!(root.entries[1].mode, 0o600);
+    let (_, chunk) = r
+        .read_object(&index, &key.box_sk, &root.entries[1].refs[0])
+        .unwrap();
+    assert_eq!(chunk, b"hello");
+    let sub = test_read_tree(&r, &key, &index, &root.entries[2].refs[0]);
+    assert_eq!(sub.entries[0].size, big.len() as u64);
+    assert!(sub.entries[0].refs.len() > 1);
+    assert_eq!(sub.entries[0].refs, sub.entries[1].refs);
+    assert_eq!(sub.entries[3].kind, EntryKind::Symlink);
+    assert_eq!(sub.entries[3].target, b"../small");
+
+    // A second backup of the same tree stores nothing new but its
+    // snapshot, and unreadable files are recorded, not fatal.
+    fs::set_permissions(dir.join("small"), fs::Permissions::from_mode(0o000)).unwrap();
+    let unreadable = File::open(dir.join("small")).is_err();
+    let (_, stats) = r.backup(&dir, &ns, &ak, &key, &Default::default()).unwrap();
+    assert_eq!(stats.new_chunks, 0);
+    assert_eq!(stats.errors.len(), unreadable as usize);
+    assert_eq!(stats.summary().exit_code(), unreadable as i32);
+    assert_eq!(r.manifest().unwrap().heads.len(), 2);
+
+    match r.backup(&dir.join("missing"), &ns, &ak, &key, &Default::default()) {
+        Err(ref e) if e.is_not_found() => (),
+        _ => panic!("expected a missing root to fail"),
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_backup_stream() {
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let ns = Namespace::new("db").unwrap();
+    let data = super::chunker::test_data(2 << 20, 2);
+    let opts = Default::default();
+    let (head, stats) = r
+        .backup_stream(&mut &data[..], "pg-dump", &ns, &ak, &key, &opts)
+        .unwrap();
+    assert_eq!(stats.bytes, data.len() as u64);
+    assert_eq!(stats.new_bytes, data.len() as u64);
+
+    let index = r.load_index().unwrap();
+    let (_, buf) = r.read_object(&index, &key.box_sk, &head.address).unwrap();
+    let snapshot = Snapshot::decode(&buf).unwrap();
+    assert_eq!(snapshot.source, b"pg-dump");
+    let root = test_read_tree(&r, &key, &index, &snapshot.root);
+    assert_eq!(root.entries.len(), 1);
+    assert_eq!(root.entries[0].name, b"pg-dump");
+    let mut restored = Vec::new();
+    for address in root.entries[0].refs.iter() {
+        restored.extend(r.read_object(&index, &key.box_sk, address).unwrap().1);
+    }
+    assert_eq!(restored, data);
+
+    // The same dump again stores nothing new.
+    let (_, stats) = r
+        .backup_stream(&mut &data[..], "pg-dump", &ns, &ak, &key, &opts)
+        .unwrap();
+    assert_eq!(stats.new_chunks, 0);
+    match r.backup_stream(&mut &data[..], "a/b", &ns, &ak, &key, &opts) {
+        Err(RepoError::InvalidDataError) => (),
+        _ => panic!("expected an invalid name to be refused"),
+    }
+}
+
+#[test]
+fn test_backup_tar() {
+    use super::restore::RestoreOptions;
+    use super::tar::test_header;
+    use std::time::{Duration, UNIX_EPOCH};
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let ns = Namespace::new("laptop").unwrap();
+    let opts = Default::default();
+
+    // A snapshot written as tar and imported again is the same tree, given
+    // times tar can hold.
+    let dir = super::storage::local::test_dir("backup-tar");
+    let big = super::chunker::test_data(3 << 20, 2);
+    fs::create_dir_all(dir.join("sub/empty")).unwrap();
+    fs::write(dir.join("small"), b"hello").unwrap();
+    fs::write(dir.join("sub/big"), &big).unwrap();
+    for (i, p) in ["small", "sub/big", "sub/empty", "sub"].iter().enumerate() {
+        let t = UNIX_EPOCH + Duration::from_secs(1_600_000_000 + i as u64);
+        File::open(dir.join(p)).unwrap().set_modified(t).unwrap();
+    }
+    let (head, _) = r.backup(&dir, &ns, &ak, &key, &opts).unwrap();
+    let mut archive = Vec::new();
+    r.restore_tar(
+        &head.address,
+        &mut archive,
+        &key.box_sk,
+        Some(&ak),
+        &RestoreOptions::default(),
+    )
+    .unwrap();
+    let (imported, stats) = r
+        .backup_tar(&mut &archive[..], b"old.tar", &ns, &ak, &key, &opts)
+        .unwrap();
+    assert_eq!(
+        (stats.files, stats.dirs, stats.bytes),
+        (2, 3, 5 + big.len() as u64)
+    );
+    assert_eq!(stats.new_chunks, 0);
+    let index = r.load_index().unwrap();
+    let root = |head: &SnapshotHead| r.read_snapshot(&index, &key.box_sk, &head.address).unwrap();
+    assert_eq!(root(&imported).root, root(&head).root);
+    assert_eq!(root(&imported).source, b"old.tar");
+    fs::remove_dir_all(&dir).unwrap();
+
+    // Entries in any order, replaced, linked, unsafe or unsupported.
+    let mut archive = Vec::new();
+    let mut add = |path: &[u8], typeflag: u8, data: &[u8], link: &[u8]| {
+        archive.extend_from_slice(&test_header(path, typeflag, data.len() as u64, link));
+        archive.extend_from_slice(data);
+        super::ustar::write_padding(&mut archive, data.len() as u64).unwrap();
+    };
+    add(b"./b/file", b'0', b"hi", b"");
+    add(b"b/", b'5', b"", b"");
+    add(b"hard", b'1', b"", b"b/file");
+    add(b"../evil", b'0', b"x", b"");
+    add(b"fifo", b'6', b"", b"");
+    add(b"b/file", b'0', b"bye", b"");
+    add(b"c/d/link", b'2', b"", b"../../hard");
+    add(b"sparse", b'S', b"", b"");
+    add(b"dangling", b'1', b"", b"nope");
+    let (head, stats) = r
+        .backup_tar(&mut &archive[..], b"", &ns, &ak, &key, &opts)
+        .unwrap();
+    assert_eq!(
+        (stats.files, stats.dirs, stats.symlinks, stats.specials),
+        (2, 4, 1, 1)
+    );
+    assert_eq!(stats.bytes, 5);
+    let errors: Vec<&[u8]> = stats.errors.iter().map(|e| &e.path[..]).collect();
+    assert_eq!(errors, vec![&b"../evil"[..], b"sparse", b"dangling"]);
+    let index = r.load_index().unwrap();
+    let snapshot = r.read_snapshot(&index, &key.box_sk, &head.address).unwrap();
+    let root = test_read_tree(&r, &key, &index, &snapshot.root);
+    let names: Vec<&[u8]> = root.entries.iter().map(|e| &e.name[..]).collect();
+    assert_eq!(names, vec![&b"b"[..], b"c", b"fifo", b"hard"]);
+    assert_eq!(root.entries[0].mode, 0o644);
+    assert_eq!(root.entries[1].mode, 0o755);
+    assert_eq!(root.entries[2].kind, EntryKind::Fifo);
+    let b = test_read_tree(&r, &key, &index, &root.entries[0].refs[0]);
+    assert_eq!(b.entries[0].size, 3);
+    assert_eq!(root.entries[3].size, 2);
+
+    // A truncated archive fails the import.
+    match r.backup_tar(&mut &archive[..700], b"", &ns, &ak, &key, &opts) {
+        Err(RepoError::IOError(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => (),
+        _ => panic!("expected a truncated archive to fail"),
+    }
+}
+
+#[test]
+fn test_backup_exclude() {
+    use super::exclude::{parse_rules, IGNORE_FILE};
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let ns = Namespace::new("laptop").unwrap();
+    let dir = super::storage::local::test_dir("backup-exclude");
+    fs::create_dir_all(dir.join("src/target")).unwrap();
+    fs::write(dir.join("src/main.rs"), b"fn main() {}").unwrap();
+    fs::write(dir.join("src/target/main"), b"binary").unwrap();
+    fs::write(dir.join("src/notes.tmp"), b"notes").unwrap();
+    fs::write(dir.join("src").join(IGNORE_FILE), b"target/\n").unwrap();
+    fs::write(dir.join("a.tmp"), b"tmp").unwrap();
+    let mut opts = BackupOptions::default();
+    opts.exclude.rules = parse_rules(b"*.tmp\n!/src/notes.tmp\n");
+    opts.exclude.ignore_files = true;
+    let (head, stats) = r.backup(&dir, &ns, &ak, &key, &opts).unwrap();
+    assert_eq!((stats.files, stats.dirs, stats.excluded), (3, 2, 2));
+    let index = r.load_index().unwrap();
+    let snapshot = r.read_snapshot(&index, &key.box_sk, &head.address).unwrap();
+    let root = test_read_tree(&r, &key, &index, &snapshot.root);
+    assert_eq!(root.entries.len(), 1);
+    let src = test_read_tree(&r, &key, &index, &root.entries[0].refs[0]);
+    let names: Vec<&[u8]> = src.entries.iter().map(|e| &e.name[..]).collect();
+    assert_eq!(
+        names,
+        vec![IGNORE_FILE.as_bytes(), b"main.rs", b"notes.tmp"]
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_backup_one_file_system() {
+    use std::process::Command;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let ns = Namespace::new("laptop").unwrap();
+    let dir = super::storage::local::test_dir("backup-mounts");
+    fs::create_dir_all(dir.join("mnt")).unwrap();
+    fs::write(dir.join("file"), b"local").unwrap();
+    let mounted = Command::new("mount")
+        .args(["-t", "tmpfs", "tmpfs"])
+        .arg(dir.join("mnt"))
+        .status()
+        .is_ok_and(|s| s.success());
+    if !mounted {
+        // Mounting needs root, skip without it.
+        fs::remove_dir_all(&dir).unwrap();
+        return;
+    }
+    fs::write(dir.join("mnt/other"), b"elsewhere").unwrap();
+    let backup = |one_file_system| {
+        let opts = BackupOptions {
+            one_file_system,
+            ..Default::default()
+        };
+        let (head, stats) = r.backup(&dir, &ns, &ak, &key, &opts).unwrap();
+        let index = r.load_index().unwrap();
+        let snapshot = r.read_snapshot(&index, &key.box_sk, &head.address).unwrap();
+        let root = test_read_tree(&r, &key, &index, &snapshot.root);
+        let mnt = test_read_tree(&r, &key, &index, &root.entries[1].refs[0]);
+        assert_eq!(snapshot.mounts, stats.mounts);
+        (stats, mnt.entries.len())
+    };
+    let (stats, n) = backup(false);
+    assert_eq!((stats.files, n), (2, 1));
+    assert!(stats.mounts.is_empty());
+    let (stats, n) = backup(true);
+    assert_eq!((stats.files, stats.dirs, n), (1, 2, 0));
+    assert_eq!(
+        stats.mounts,
+        vec![dir.join("mnt").as_os_str().as_bytes().to_vec()]
+    );
+    Command::new("umount")
+        .arg(dir.join("mnt"))
+        .status()
+        .unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_backup_symlinks() {
+    use std::os::unix::fs::symlink;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let ns = Namespace::new("laptop").unwrap();
+    let dir = super::storage::local::test_dir("backup-symlinks");
+    let outside = super::storage::local::test_dir("backup-symlinks-outside");
+    fs::create_dir_all(dir.join("d")).unwrap();
+    fs::create_dir_all(&outside).unwrap();
+    fs::write(outside.join("file"), b"outside").unwrap();
+    symlink(&outside, dir.join("out")).unwrap();
+    symlink("..", dir.join("d/up")).unwrap();
+    symlink("nowhere", dir.join("dangling")).unwrap();
+    let backup = |symlinks| {
+        let opts = BackupOptions {
+            symlinks,
+            ..Default::default()
+        };
+        let (head, stats) = r.backup(&dir, &ns, &ak, &key, &opts).unwrap();
+        let index = r.load_index().unwrap();
+        let snapshot = r.read_snapshot(&index, &key.box_sk, &head.address).unwrap();
+        let root = test_read_tree(&r, &key, &index, &snapshot.root);
+        let kinds: Vec<EntryKind> = root.entries.iter().map(|e| e.kind).collect();
+        (stats, kinds)
+    };
+    let (stats, kinds) = backup(Symlinks::Store);
+    assert_eq!((stats.files, stats.symlinks), (0, 3));
+    assert_eq!(
+        kinds,
+        vec![EntryKind::Dir, EntryKind::Symlink, EntryKind::Symlink]
+    );
+    assert!(stats.errors.is_empty());
+
+    // Following stores the outside tree, but not the loop or dangling link.
+    let (stats, kinds) = backup(Symlinks::Follow);
+    assert_eq!((stats.files, stats.symlinks), (1, 2));
+    assert_eq!(
+        kinds,
+        vec![EntryKind::Dir, EntryKind::Symlink, EntryKind::Dir]
+    );
+    assert_eq!(stats.errors.len(), 1);
+    assert!(stats.errors[0].path.ends_with(b"d/up"));
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&outside).unwrap();
+}
+
+#[test]
+fn test_backup_stat_cache() {
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let ns = Namespace::new("laptop").unwrap();
+    let dir = super::storage::local::test_dir("backup-stat-cache");
+    let cache = super::storage::local::test_dir("backup-stat-cache-root");
+    let big = super::chunker::test_data(3 << 20, 3);
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("small"), b"hello").unwrap();
+    fs::write(dir.join("sub/big"), &big).unwrap();
+    // Files changed in the second a backup starts are not cached.
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    let mut opts = BackupOptions {
+        stat_cache: Some(cache.clone()),
+        ..Default::default()
+    };
+    let backup = |opts: &BackupOptions| {
+        let (head, stats) = r.backup(&dir, &ns, &ak, &key, opts).unwrap();
+        let index = r.load_index().unwrap();
+        let snapshot = r.read_snapshot(&index, &key.box_sk, &head.address).unwrap();
+        (snapshot.root, stats)
+    };
+    let (first, stats) = backup(&opts);
+    assert_eq!((stats.files, stats.cached), (2, 0));
+    let (second, stats) = backup(&opts);
+    assert_eq!(
+        (stats.files, stats.cached, stats.bytes),
+        (2, 2, 5 + big.len() as u64)
+    );
+    assert_eq!((stats.read_files, stats.read_bytes), (0, 0));
+    assert_eq!(first, second);
+
+    // A rewrite of the same size and mtime still changes the ctime.
+    let mtime = fs::metadata(dir.join("small")).unwrap().modified().unwrap();
+    fs::write(dir.join("small"), b"jello").unwrap();
+    File::options()
+        .write(true)
+        .open(dir.join("small"))
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+    let (third, stats) = backup(&opts);
+    assert_eq!(stats.cached, 1);
+    assert_ne!(third, second);
+    opts.force_rescan = true;
+    let (_, stats) = backup(&opts);
+    assert_eq!(stats.cached, 0);
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&cache).unwrap();
+}
+
+#[test]
+fn test_backup_changing() {
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let ns = Namespace::new("laptop").unwrap();
+    let dir = super::storage::local::test_dir("backup-changing");
+    let cache = super::storage::local::test_dir("backup-changing-cache");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("quiet"), b"hello").unwrap();
+    fs::write(dir.join("live"), super::chunker::test_data(4 << 20, 5)).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    let opts = BackupOptions {
+        stat_cache: Some(cache.clone()),
+        ..Default::default()
+    };
+    // Every chmod changes the ctime, however fast the file is read.
+    let done = AtomicBool::new(false);
+    let (head, stats) = std::thread::scope(|s| {
+        s.spawn(|| {
+            for mode in [0o640, 0o644].iter().cycle() {
+                if done.load(Ordering::Relaxed) {
+                    break;
+                }
+                let perms = fs::Permissions::from_mode(*mode);
+                fs::set_permissions(dir.join("live"), perms).unwrap();
+            }
+        });
+        let backup = r.backup(&dir, &ns, &ak, &key, &opts).unwrap();
+        done.store(true, Ordering::Relaxed);
+        backup
+    });
+    let live = dir.join("live").as_os_str().as_bytes().to_vec();
+    assert_eq!(stats.changed, vec![live.clone()]);
+    assert_eq!((stats.files, stats.bytes), (2, 5 + (4 << 20)));
+    assert!(stats.errors.is_empty());
+    assert_eq!(stats.summary().exit_code(), 1);
+    let index = r.load_index().unwrap();
+    let snapshot = r.read_snapshot(&index, &key.box_sk, &head.address).unwrap();
+    assert_eq!(snapshot.changed, vec![live]);
+    let mut out = Vec::new();
+    stats.write_ndjson(&mut out, &head).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains(&format!("\"changed\": [{}]", json_path(&stats.changed[0]))));
+
+    // Only the quiet file was cached.
+    let (_, stats) = r.backup(&dir, &ns, &ak, &key, &opts).unwrap();
+    assert!(stats.changed.is_empty());
+    assert_eq!(stats.cached, 1);
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&cache).unwrap();
+}
+
+#[test]
+fn test_backup_scan_threads() {
+    use super::exclude::Rule;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let ns = Namespace::new("laptop").unwrap();
+    let dir = super::storage::local::test_dir("backup-scan-threads");
+    for i in 0..6 {
+        for j in 0..i {
+            let sub = dir.join(format!("d{}/e{}", i, j));
+            fs::create_dir_all(&sub).unwrap();
+            fs::write(sub.join("file"), format!("{} {}", i, j)).unwrap();
+        }
+    }
+    let mut opts = BackupOptions::default();
+    opts.exclude.rules = vec![Rule::exclude("d3/e1")];
+    let mut roots = Vec::new();
+    for threads in [0, 1, 8] {
+        opts.scan_threads = threads;
+        let (head, stats) = r.backup(&dir, &ns, &ak, &key, &opts).unwrap();
+        assert_eq!((stats.files, stats.dirs, stats.excluded), (14, 20, 1));
+        let index = r.load_index().unwrap();
+        roots.push(
+            r.read_snapshot(&index, &key.box_sk, &head.address)
+                .unwrap()
+                .root,
+        );
+    }
+    assert!(roots.iter().all(|root| *root == roots[0]));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_backup_progress() {
+    use super::chunker::test_data;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let ns = Namespace::new("laptop").unwrap();
+    let dir = super::storage::local::test_dir("backup-progress");
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("small"), b"small").unwrap();
+    fs::write(dir.join("sub/big"), test_data(3 << 20, 1)).unwrap();
+    let mut opts = BackupOptions {
+        progress: Some(Arc::new(Progress::new())),
+        ..Default::default()
+    };
+    let (_, stats) = r.backup(&dir, &ns, &ak, &key, &opts).unwrap();
+    let state = opts.progress.as_ref().unwrap().state();
+    assert_eq!((state.files, state.dirs), (stats.files, stats.dirs));
+    assert_eq!((state.bytes_read, state.bytes_total), (stats.bytes, None));
+    assert!(state.bytes_uploaded > stats.new_bytes);
+
+    // The next backup expects to read as much as the last one.
+    opts.progress = Some(Arc::new(Progress::new()));
+    r.backup(&dir, &ns, &ak, &key, &opts).unwrap();
+    let state = opts.progress.as_ref().unwrap().state();
+    assert_eq!(state.bytes_total, Some(stats.bytes));
+    assert_eq!(state.bytes_read, stats.bytes);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_backup_dry_run() {
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let ns = Namespace::new("laptop").unwrap();
+    let dir = super::storage::local::test_dir("backup-dry-run");
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/big"), super::chunker::test_data(3 << 20, 4)).unwrap();
+    fs::write(dir.join("small"), b"hello").unwrap();
+    let before = r.storage().list_prefix("").unwrap();
+    let opts = BackupOptions {
+        dry_run: true,
+        ..Default::default()
+    };
+    let (_, dry) = r.backup(&dir, &ns, &ak, &key, &opts).unwrap();
+    assert_eq!(r.storage().list_prefix("").unwrap(), before);
+    assert!(dry.dry_run);
+    assert!(dry.put_bytes > dry.new_bytes);
+
+    // A real backup stores what the dry run said it would.
+    let (_, stats) = r.backup(&dir, &ns, &ak, &key, &Default::default()).unwrap();
+    assert_eq!(
+        (stats.new_chunks, stats.new_bytes, stats.dry_run),
+        (dry.new_chunks, dry.new_bytes, false)
+    );
+    let mut data = &b"hello"[..];
+    let (_, dry) = r
+        .backup_stream(&mut data, "dump", &ns, &ak, &key, &opts)
+        .unwrap();
+    assert!(dry.dry_run);
+    assert_eq!(r.manifest().unwrap().heads.len(), 1);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,257 @@
+// The packnback command. `packnback serve` runs as the forced command of a
+// key in authorized_keys or as a long running server, see `repo::serve`.
+// `packnback config check` checks the settings, see `repo::settings`, and
+// the other commands work on a repository in a local directory, with the
+// key file and repository from the settings unless given. With `--json`
+// they write NDJSON ending in a summary record and exit with its
+// `exit_code`, see `repo::json`. Without it they write text and exit with
+// 1 if they failed or found errors.
+
+use asymcrypt::{Key, PublicKey};
+use repo::fsck::FsckOptions;
+use repo::json::{json_str, Summary};
+use repo::keys::{read_key, KeyRole};
+use repo::list;
+use repo::progress::human_bytes;
+use repo::serve::{serve_dir, ServeOptions};
+use repo::settings::Settings;
+use repo::storage::local::LocalStorage;
+use repo::storage::StorageEngine;
+use repo::{Repo, RepoError, OWNER_KEY_FILE};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::Arc;
+
+const USAGE: &str = "usage: packnback serve [options] dir\n       \
+                     packnback [--json] stats [--repo name] [--key path] [dir]\n       \
+                     packnback [--json] list [--repo name] [--key path] [dir]\n       \
+                     packnback [--json] fsck [--read-data] [--repair] [--repo name] [--key path] [dir]\n       \
+                     packnback [--json] config check [--repo name]\n\
+                     --repo name reads the settings of the repository called name as well";
+
+fn usage() -> RepoError {
+    io::Error::new(io::ErrorKind::InvalidInput, USAGE).into()
+}
+
+fn unset(what: &str) -> RepoError {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("no {} is given or set", what),
+    )
+    .into()
+}
+
+// Arguments of the commands on a local repository.
+struct LocalArgs<'a> {
+    // The settings of the repository called this are read as well.
+    repo: Option<&'a str>,
+    dir: Option<PathBuf>,
+    key: Option<PathBuf>,
+    // Those of the command's switches that were given.
+    switches: Vec<&'a str>,
+}
+
+impl<'a> LocalArgs<'a> {
+    fn parse(args: &'a [String], switches: &[&str]) -> Result<LocalArgs<'a>, RepoError> {
+        let mut parsed = LocalArgs {
+            repo: None,
+            dir: None,
+            key: None,
+            switches: Vec::new(),
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--repo" => parsed.repo = Some(args.next().ok_or_else(usage)?),
+                "--key" => parsed.key = Some(PathBuf::from(args.next().ok_or_else(usage)?)),
+                a if switches.contains(&a) => parsed.switches.push(a),
+                a if !a.starts_with('-') && parsed.dir.is_none() => {
+                    parsed.dir = Some(PathBuf::from(a))
+                }
+                _ => return Err(usage()),
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn has(&self, switch: &str) -> bool {
+        self.switches.contains(&switch)
+    }
+}
+
+// Open the repository in `args.dir`, or the settings' repository, with the
+// key file at `args.key` or the settings' key, which must have at least
+// `need`.
+fn open_local(args: LocalArgs, need: KeyRole) -> Result<(Repo, Box<Key>), RepoError> {
+    let flags = Settings {
+        repository: args.dir.map(|d| d.to_string_lossy().into_owned()),
+        key: args.key,
+        ..Default::default()
+    };
+    let settings = Settings::resolve(args.repo, flags)?;
+    let dir = settings.repository.ok_or_else(|| unset("repository"))?;
+    if dir.contains("://") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}: only local repositories can be opened here", dir),
+        )
+        .into());
+    }
+    let path = settings.key.ok_or_else(|| unset("key"))?;
+    let (_, key) = read_key(&mut fs::File::open(&path)?)?;
+
+    let storage: Arc<dyn StorageEngine> = Arc::new(LocalStorage::new(Path::new(&dir))?);
+    let owner = PublicKey::read_from(&mut &storage.get(OWNER_KEY_FILE)?[..])?;
+    let repo = Repo::open(storage, &owner)?;
+    // Which also refuses the key of another owner.
+    repo.require_role(&key, need)?;
+    Ok((repo, key))
+}
+
+fn stats(args: &[String], json: bool, w: &mut dyn Write) -> Result<Summary, RepoError> {
+    let (repo, key) = open_local(LocalArgs::parse(args, &[])?, KeyRole::Metadata)?;
+    let stats = repo.stats(&key.box_sk)?;
+    if json {
+        stats.write_ndjson(w)?;
+        return Ok(Summary::new("stats"));
+    }
+    writeln!(w, "stored:      {}", human_bytes(stats.stored_bytes))?;
+    writeln!(w, "parity:      {}", human_bytes(stats.parity_bytes))?;
+    writeln!(w, "unique:      {}", human_bytes(stats.unique_bytes))?;
+    writeln!(w, "logical:     {}", human_bytes(stats.logical_bytes))?;
+    writeln!(w, "objects:     {}", stats.objects)?;
+    writeln!(w, "dedup:       {:.2}x", stats.dedup_ratio())?;
+    writeln!(w, "compression: {:.2}x", stats.compression_ratio())?;
+    writeln!(
+        w,
+        "packs:       {} at {:.0}% live",
+        stats.packs.len(),
+        stats.pack_utilization() * 100.0
+    )?;
+    for s in stats.snapshots.iter() {
+        writeln!(
+            w,
+            "{} {} {} logical, {} unique",
+            s.head.address.to_hex(),
+            s.head.namespace,
+            human_bytes(s.logical_bytes),
+            human_bytes(s.unique_bytes)
+        )?;
+    }
+    Ok(Summary::new("stats"))
+}
+
+fn list(args: &[String], json: bool, w: &mut dyn Write) -> Result<Summary, RepoError> {
+    let (repo, key) = open_local(LocalArgs::parse(args, &[])?, KeyRole::Metadata)?;
+    let listings = repo.list_snapshots(&key.box_sk, &Default::default())?;
+    if json {
+        list::write_ndjson(w, &listings)?;
+    } else {
+        list::write_table(w, &listings)?;
+    }
+    Ok(Summary::new("list"))
+}
+
+fn fsck(args: &[String], json: bool, w: &mut dyn Write) -> Result<Summary, RepoError> {
+    let args = LocalArgs::parse(args, &["--read-data", "--repair"])?;
+    let opts = FsckOptions {
+        read_data: args.has("--read-data"),
+        repair: args.has("--repair"),
+    };
+    let need = if opts.read_data || opts.repair {
+        KeyRole::Decrypt
+    } else {
+        KeyRole::Metadata
+    };
+    let (repo, key) = open_local(args, need)?;
+    let report = repo.fsck(Some(&key.box_sk), &opts)?;
+    if json {
+        report.write_ndjson(w)?;
+    } else {
+        report.write_to(w)?;
+    }
+    Ok(report.summary())
+}
+
+// Report what would stop the settings from being used.
+fn config_check(args: &[String], json: bool, w: &mut dyn Write) -> Result<Summary, RepoError> {
+    let args = LocalArgs::parse(args, &[])?;
+    if args.dir.is_some() || args.key.is_some() {
+        return Err(usage());
+    }
+    let problems = Settings::resolve(args.repo, Default::default())?.check();
+    for p in problems.iter() {
+        if json {
+            writeln!(w, "{{\"type\": \"problem\", \"message\": {}}}", json_str(p))?;
+        } else {
+            writeln!(w, "{}", p)?;
+        }
+    }
+    if !json && problems.is_empty() {
+        writeln!(w, "ok")?;
+    }
+    Ok(Summary {
+        errors: problems.len() as u64,
+        ..Summary::new("config check")
+    })
+}
+
+// Run a command on a local repository, writing what it found to `w`.
+fn run(args: &[String], json: bool, w: &mut dyn Write) -> Result<Summary, RepoError> {
+    match args.first().map(String::as_str) {
+        Some("stats") => stats(&args[1..], json, w),
+        Some("list") => list(&args[1..], json, w),
+        Some("fsck") => fsck(&args[1..], json, w),
+        Some("config") if args.get(1).map(String::as_str) == Some("check") => {
+            config_check(&args[2..], json, w)
+        }
+        _ => Err(usage()),
+    }
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("serve") {
+        let served =
+            ServeOptions::parse_args(&args[1..]).and_then(|(dir, opts)| serve_dir(&dir, &opts));
+        if let Err(err) = served {
+            eprintln!("packnback: {}", err);
+            exit(1);
+        }
+        return;
+    }
+
+    // Anywhere on the command line.
+    let json = args.iter().any(|a| a == "--json");
+    args.retain(|a| a != "--json");
+    let stdout = io::stdout();
+    let mut w = stdout.lock();
+    let summary = match run(&args, json, &mut w) {
+        Ok(summary) => summary,
+        Err(err) => {
+            if !json {
+                eprintln!("packnback: {}", err);
+            }
+            // Named as a summary of the command would be.
+            let words = if args.first().map(String::as_str) == Some("config") {
+                2
+            } else {
+                1
+            };
+            Summary::failed(&args[..words.min(args.len())].join(" "), &err)
+        }
+    };
+    if json {
+        // Nothing is left to report a failure to write to.
+        let _ = summary.write_ndjson(&mut w);
+    }
+    let _ = w.flush();
+    exit(match (json, summary.exit_code()) {
+        // Failures exit with 1 as they always have.
+        (false, 2) => 1,
+        (_, code) => code,
+    });
+}
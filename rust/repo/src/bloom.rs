@@ -0,0 +1,152 @@
+//! Bloom filters over object addresses.
+//!
+//! A compact, possibly stale, answer to "might the repository already hold
+//! this object", small enough to keep in memory during a backup and cache
+//! on disk. Addresses are already uniformly distributed keyed hashes, so
+//! bit positions are taken straight from the address bytes.
+//!
+//! A negative answer is certain, a positive one must be confirmed against
+//! a pack index before an object is skipped.
+//!
+//! Format:
+//!
+//! ```text
+//! "PNBBLOOM" u16:format_version u8:k u64:n_bits [n_bits/8]:bits
+//! [32]:sha512_prefix_of_everything_above
+//! ```
+
+use super::address::Address;
+use super::wire::{Decoder, Encoder};
+use super::RepoError;
+use tweetnacl::*;
+
+pub const BLOOM_FORMAT_VERSION: u16 = 1;
+const BLOOM_MAGIC: &[u8] = b"PNBBLOOM";
+const CHECKSUM_SZ: usize = 32;
+
+// Ten bits and seven probes per address give about 1% false positives.
+const BITS_PER_ADDRESS: u64 = 10;
+const PROBES: u8 = 7;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Bloom {
+    k: u8,
+    bits: Vec<u8>,
+}
+
+fn checksum(buf: &[u8]) -> [u8; CHECKSUM_SZ] {
+    let mut h = [0; CRYPTO_HASH_BYTES];
+    crypto_hash(&mut h, buf);
+    let mut c = [0; CHECKSUM_SZ];
+    c.copy_from_slice(&h[..CHECKSUM_SZ]);
+    c
+}
+
+impl Bloom {
+    // A filter sized for `n` addresses.
+    pub fn new(n: usize) -> Bloom {
+        let n_bits = (n as u64 * BITS_PER_ADDRESS).max(64);
+        Bloom {
+            k: PROBES,
+            bits: vec![0; n_bits.div_ceil(8) as usize],
+        }
+    }
+
+    pub fn build<'a, I: IntoIterator<Item = &'a Address>>(n: usize, addresses: I) -> Bloom {
+        let mut b = Bloom::new(n);
+        for a in addresses {
+            b.insert(a);
+        }
+        b
+    }
+
+    fn probes(&self, address: &Address) -> impl Iterator<Item = usize> {
+        let mut h1 = [0; 8];
+        let mut h2 = [0; 8];
+        h1.copy_from_slice(&address.bytes[..8]);
+        h2.copy_from_slice(&address.bytes[8..16]);
+        let h1 = u64::from_le_bytes(h1);
+        let h2 = u64::from_le_bytes(h2) | 1;
+        let n_bits = self.bits.len() as u64 * 8;
+        (0..self.k as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % n_bits) as usize)
+    }
+
+    pub fn insert(&mut self, address: &Address) {
+        for bit in self.probes(address).collect::<Vec<_>>() {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    pub fn may_contain(&self, address: &Address) -> bool {
+        self.probes(address)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.fixed(BLOOM_MAGIC)
+            .u16(BLOOM_FORMAT_VERSION)
+            .u8(self.k)
+            .u64(self.bits.len() as u64 * 8)
+            .fixed(&self.bits);
+        let mut buf = e.into_vec();
+        let c = checksum(&buf);
+        buf.extend_from_slice(&c);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Bloom, RepoError> {
+        if buf.len() < CHECKSUM_SZ {
+            return Err(RepoError::InvalidDataError);
+        }
+        let (body, c) = buf.split_at(buf.len() - CHECKSUM_SZ);
+        if checksum(body)[..] != c[..] {
+            return Err(RepoError::CorruptOrTamperedDataError);
+        }
+        let mut d = Decoder::new(body);
+        if d.fixed(BLOOM_MAGIC.len())? != BLOOM_MAGIC {
+            return Err(RepoError::InvalidDataError);
+        }
+        if d.u16()? != BLOOM_FORMAT_VERSION {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        let k = d.u8()?;
+        let n_bits = d.u64()?;
+        if k == 0 || n_bits == 0 || n_bits % 8 != 0 || n_bits / 8 != d.remaining() as u64 {
+            return Err(RepoError::InvalidDataError);
+        }
+        let bits = d.fixed(d.remaining())?.to_vec();
+        d.finish()?;
+        Ok(Bloom { k, bits })
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_bloom() {
+    let addresses: Vec<Address> = (0..1000u32)
+        .map(|i| {
+            let mut h = [0; CRYPTO_HASH_BYTES];
+            crypto_hash(&mut h, &i.to_le_bytes());
+            let mut a: Address = Default::default();
+            a.bytes.copy_from_slice(&h[..32]);
+            a
+        })
+        .collect();
+    let (present, absent) = addresses.split_at(500);
+    let b = Bloom::build(present.len(), present.iter());
+    assert!(present.iter().all(|a| b.may_contain(a)));
+    let false_positives = absent.iter().filter(|a| b.may_contain(a)).count();
+    assert!(false_positives < 25);
+
+    let buf = b.encode();
+    assert_eq!(Bloom::decode(&buf).unwrap(), b);
+    let mut bad = buf.clone();
+    bad[20] ^= 1;
+    match Bloom::decode(&bad) {
+        Err(RepoError::CorruptOrTamperedDataError) => (),
+        _ => panic!("expected corruption to be detected"),
+    }
+    assert!(!Bloom::new(0).may_contain(&present[0]));
+}
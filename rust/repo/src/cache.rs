@@ -0,0 +1,231 @@
+//! Client side cache of repository indexes.
+//!
+//! An incremental backup needs the repository index before it can skip
+//! anything, and over a slow link downloading every pack index each run
+//! costs more than the backup itself. Pack indexes never change once
+//! stored, so they are kept in a local directory and only new ones are
+//! fetched. Each run still fetches the manifest and lists the remote
+//! indexes, so packs removed by `gc` or `repack` are dropped from the
+//! cache and never used for deduplication.
+//!
+//! Layout below `<cache root>/<hex repo id>/`:
+//!
+//! ```text
+//! manifest       the signed manifest as last fetched
+//! indexes/<hex>  pack indexes, byte for byte as stored remotely
+//! bloom          [32]:packs_checksum bloom_filter
+//! ```
+//!
+//! `packs_checksum` is the sha512 prefix of the ids of every indexed pack
+//! in ascending order, see `bloom` for the filter format. The filter is
+//! valid only for the cached manifest and that exact set of packs, it is
+//! discarded whenever the manifest changes and rebuilt whenever the pack
+//! set differs.
+//!
+//! Nothing cached is trusted more than the repository itself. Indexes
+//! and the filter carry checksums and the manifest is signed, anything
+//! that fails its check is treated as missing and fetched or rebuilt.
+
+use super::address::to_hex;
+use super::bloom::Bloom;
+use super::index::{PackIndex, RepoIndex};
+use super::manifest::{Manifest, RepoId};
+use super::pack::PackId;
+use super::storage::local::{LocalStorage, SyncPolicy};
+use super::storage::StorageEngine;
+use super::{Repo, RepoError, INDEXES_DIR, MANIFEST_FILE};
+use std::collections::HashSet;
+use std::path::Path;
+use tweetnacl::*;
+
+const BLOOM_FILE: &str = "bloom";
+const CHECKSUM_SZ: usize = 32;
+
+pub struct IndexCache {
+    // Never synced, losing cached data to a crash only costs a download.
+    storage: LocalStorage,
+}
+
+pub struct CachedIndex {
+    pub manifest: Manifest,
+    pub index: RepoIndex,
+    pub bloom: Bloom,
+    // Pack indexes downloaded because they were not cached.
+    pub fetched: usize,
+}
+
+fn packs_checksum(ids: &[PackId]) -> [u8; CHECKSUM_SZ] {
+    let mut buf = Vec::with_capacity(ids.len() * 16);
+    for id in ids {
+        buf.extend_from_slice(&id.bytes);
+    }
+    let mut h = [0; CRYPTO_HASH_BYTES];
+    crypto_hash(&mut h, &buf);
+    let mut c = [0; CHECKSUM_SZ];
+    c.copy_from_slice(&h[..CHECKSUM_SZ]);
+    c
+}
+
+fn get_cached(storage: &dyn StorageEngine, key: &str) -> Result<Option<Vec<u8>>, RepoError> {
+    match storage.get(key) {
+        Ok(buf) => Ok(Some(buf)),
+        Err(ref e) if e.is_not_found() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+impl IndexCache {
+    pub fn open(root: &Path, repo_id: &RepoId) -> Result<IndexCache, RepoError> {
+        let dir = root.join(to_hex(&repo_id.bytes));
+        Ok(IndexCache {
+            storage: LocalStorage::with_sync_policy(&dir, SyncPolicy::Never)?,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        self.storage.root()
+    }
+
+    pub fn clear(&self) -> Result<(), RepoError> {
+        for k in self.storage.list_prefix("")? {
+            self.storage.delete(&k)?;
+        }
+        Ok(())
+    }
+
+    fn read_index(&self, id: &PackId) -> Result<Option<PackIndex>, RepoError> {
+        let key = format!("{}/{}", INDEXES_DIR, id.to_hex());
+        Ok(match get_cached(&self.storage, &key)? {
+            Some(buf) => PackIndex::decode(&buf)
+                .ok()
+                .filter(|idx| idx.pack_id == *id),
+            None => None,
+        })
+    }
+
+    fn read_bloom(&self, ids: &[PackId]) -> Result<Option<Bloom>, RepoError> {
+        let buf = match get_cached(&self.storage, BLOOM_FILE)? {
+            Some(buf) if buf.len() > CHECKSUM_SZ => buf,
+            _ => return Ok(None),
+        };
+        if buf[..CHECKSUM_SZ] != packs_checksum(ids)[..] {
+            return Ok(None);
+        }
+        Ok(Bloom::decode(&buf[CHECKSUM_SZ..]).ok())
+    }
+}
+
+impl Repo {
+    // Like `load_index`, fetching only pack indexes not already cached.
+    pub fn load_index_cached(&self, cache: &IndexCache) -> Result<CachedIndex, RepoError> {
+        let sm = self.storage().get(MANIFEST_FILE)?;
+        let manifest = self.open_manifest(&sm)?;
+        if get_cached(&cache.storage, MANIFEST_FILE)?.as_deref() != Some(&sm[..]) {
+            match cache.storage.delete(BLOOM_FILE) {
+                Err(ref e) if e.is_not_found() => (),
+                r => r?,
+            }
+            cache.storage.put(MANIFEST_FILE, &sm)?;
+        }
+
+        let ids = self.list_pack_indexes()?;
+        let live: HashSet<String> = ids
+            .iter()
+            .map(|id| format!("{}/{}", INDEXES_DIR, id.to_hex()))
+            .collect();
+        for k in cache.storage.list_prefix(&format!("{}/", INDEXES_DIR))? {
+            if !live.contains(&k) {
+                cache.storage.delete(&k)?;
+            }
+        }
+
+        let mut index = RepoIndex::new();
+        let mut fetched = 0;
+        for id in ids.iter() {
+            let idx = match cache.read_index(id)? {
+                Some(idx) => idx,
+                None => {
+                    let idx = self.read_pack_index(id)?;
+                    let key = format!("{}/{}", INDEXES_DIR, id.to_hex());
+                    cache.storage.put(&key, &idx.encode())?;
+                    fetched += 1;
+                    idx
+                }
+            };
+            index.add_pack(&idx);
+        }
+
+        let bloom = match cache.read_bloom(&ids)? {
+            Some(bloom) => bloom,
+            None => {
+                let bloom = Bloom::build(index.len(), index.iter().map(|(a, _)| a));
+                let mut buf = packs_checksum(&ids).to_vec();
+                buf.extend_from_slice(&bloom.encode());
+                cache.storage.put(BLOOM_FILE, &buf)?;
+                bloom
+            }
+        };
+
+        Ok(CachedIndex {
+            manifest,
+            index,
+            bloom,
+            fetched,
+        })
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_index_cache() {
+    use super::address::Address;
+    use super::gc::test_commit_tree;
+    use super::storage::local::test_dir;
+    let dir = test_dir("index-cache");
+    let (r, key) = super::test_repo();
+    let cache = IndexCache::open(&dir, &r.config().repo_id).unwrap();
+    let chunk = Address { bytes: [7; 32] };
+    test_commit_tree(&r, &key, 1, &[chunk]);
+
+    let c = cache_load(&r, &cache, 1);
+    assert!(c.index.contains(&chunk));
+    assert!(c.bloom.may_contain(&chunk));
+    assert_eq!(c.manifest.heads.len(), 1);
+    cache_load(&r, &cache, 0);
+
+    // Only the new pack is fetched after another commit.
+    test_commit_tree(&r, &key, 2, &[Address { bytes: [8; 32] }]);
+    let c = cache_load(&r, &cache, 1);
+    assert_eq!(c.manifest.heads.len(), 2);
+    assert_eq!(c.index.len(), 6);
+
+    // Damaged cache entries are fetched again.
+    let id = r.list_pack_indexes().unwrap()[0];
+    let key = format!("{}/{}", INDEXES_DIR, id.to_hex());
+    let mut buf = cache.storage.get(&key).unwrap();
+    buf[30] ^= 1;
+    cache.storage.put(&key, &buf).unwrap();
+    cache.storage.put(BLOOM_FILE, b"junk").unwrap();
+    let c = cache_load(&r, &cache, 1);
+    assert!(c.bloom.may_contain(&chunk));
+
+    // Deleted packs leave the cache.
+    let idx = r.read_pack_index(&id).unwrap();
+    r.storage().delete(&key).unwrap();
+    let c = cache_load(&r, &cache, 0);
+    assert!(!c.index.contains(&idx.entries()[0].address));
+    assert!(!cache.storage.exists(&key).unwrap());
+
+    cache.clear().unwrap();
+    cache_load(&r, &cache, 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(test)]
+fn cache_load(r: &Repo, cache: &IndexCache, fetched: usize) -> CachedIndex {
+    let c = r.load_index_cached(cache).unwrap();
+    assert_eq!(c.fetched, fetched);
+    assert_eq!(c.index.len(), r.load_index().unwrap().len());
+    c
+}
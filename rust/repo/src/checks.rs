@@ -0,0 +1,386 @@
+//! Structural checks `serve` runs on a schedule.
+//!
+//! A repository host should find a damaged pack before a restore needs it,
+//! not when. With `ServeOptions::check_every` the server audits the
+//! repository it serves, or each tenant's, see `tenant`, that often: the
+//! audit an outside party runs, see `audit`, of the signatures, the pack
+//! indexes and the envelope of every pack and loose object, which needs no
+//! repository key. The owner key it checks signatures with is the copy
+//! stored in the repository, the server has no other.
+//!
+//! The outcome is kept in the repository as its check status, which only
+//! the server may write, and recorded in the operation log if there is
+//! one, see `oplog`, as a `check` operation of the status key, its error
+//! saying what the check found wrong. A check that could not finish is
+//! logged with why, and leaves the status as it was, so the repository is
+//! soon overdue.
+//!
+//! A server serving many connections, see `serve_unix` and `tls`, checks
+//! on a thread of its own. A server started per connection, as over ssh,
+//! never does, its client would wait for the check to end before its own
+//! session could. `check_dir`, from cron say, runs the checks due with the
+//! arguments the server is started with.
+//!
+//! Clients ask for the status with CHECK_STATUS, see `protocol`, which
+//! also says how often the server checks, and a backup reports it and
+//! warns when the repository is overdue, see `CheckStatus::is_overdue`. A
+//! check that found no errors counts as an fsck in the metrics, see
+//! `metrics`.
+//!
+//! ```text
+//! "PNBCHECK" u16:format_version u64:time u32:items u32:warnings u32:errors
+//! ```
+//!
+//! `time` is when the check finished and `items` what it checked, see
+//! `fsck`.
+
+use super::datetime::{unix_now, DateTime};
+use super::fsck::{FsckReport, Verdict};
+use super::oplog::{OpLog, Operation};
+use super::serve::{open_log, open_repo, ServeOptions};
+use super::storage::local::LocalStorage;
+use super::storage::StorageEngine;
+use super::tenant::{list_tenants, tenant_storage};
+use super::wire::{Decoder, Encoder};
+use super::{Repo, RepoError, CHECK_FILE, CONFIG_FILE, TENANTS_DIR};
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+pub const CHECK_FORMAT_VERSION: u16 = 1;
+const CHECK_MAGIC: &[u8] = b"PNBCHECK";
+
+// The last check of a repository.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct CheckResult {
+    pub time: u64,
+    pub items: u32,
+    pub warnings: u32,
+    pub errors: u32,
+}
+
+// What the server tells clients of its checks.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct CheckStatus {
+    // Seconds between checks, None if the server runs none.
+    pub every: Option<u64>,
+    pub last: Option<CheckResult>,
+}
+
+impl CheckResult {
+    fn new(report: &FsckReport, time: u64) -> CheckResult {
+        CheckResult {
+            time,
+            items: report.entries.len() as u32,
+            warnings: report.count(Verdict::Warning) as u32,
+            errors: report.count(Verdict::Error) as u32,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.fixed(CHECK_MAGIC)
+            .u16(CHECK_FORMAT_VERSION)
+            .u64(self.time)
+            .u32(self.items)
+            .u32(self.warnings)
+            .u32(self.errors);
+        e.into_vec()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<CheckResult, RepoError> {
+        let mut d = Decoder::new(buf);
+        if d.fixed(CHECK_MAGIC.len())? != CHECK_MAGIC {
+            return Err(RepoError::InvalidDataError);
+        }
+        if d.u16()? != CHECK_FORMAT_VERSION {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        let result = CheckResult {
+            time: d.u64()?,
+            items: d.u32()?,
+            warnings: d.u32()?,
+            errors: d.u32()?,
+        };
+        d.finish()?;
+        Ok(result)
+    }
+
+    // What the check found wrong, for the log.
+    fn problems(&self) -> Option<String> {
+        match (self.errors, self.warnings) {
+            (0, 0) => None,
+            (errors, warnings) => Some(format!("{} errors, {} warnings", errors, warnings)),
+        }
+    }
+}
+
+impl CheckStatus {
+    // Whether the server checks and has not for more than two of its
+    // intervals, or never has.
+    pub fn is_overdue(&self, now: u64) -> bool {
+        match (self.every, self.last) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(every), Some(last)) => now.saturating_sub(last.time) > every.saturating_mul(2),
+        }
+    }
+}
+
+// An interval in the units `ServeOptions::check_every` is given in.
+fn interval(secs: u64) -> String {
+    match secs {
+        s if s % (24 * 60 * 60) == 0 => format!("{}d", s / (24 * 60 * 60)),
+        s => format!("{}h", s.div_ceil(60 * 60)),
+    }
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let every = match self.every {
+            Some(every) => every,
+            None => return write!(f, "not run by the server"),
+        };
+        let last = match self.last {
+            Some(last) => last,
+            None => return write!(f, "every {}, never run yet", interval(every)),
+        };
+        write!(
+            f,
+            "every {}, last {}, ",
+            interval(every),
+            DateTime::from_unix(last.time).to_rfc3339()
+        )?;
+        match (last.errors, last.warnings) {
+            (0, 0) => write!(f, "no problems")?,
+            (errors, warnings) => write!(f, "{} errors and {} warnings", errors, warnings)?,
+        }
+        if self.is_overdue(unix_now()) {
+            write!(f, ", overdue")?;
+        }
+        Ok(())
+    }
+}
+
+// The last check of the repository in `storage`, if it had one.
+pub fn read_check(storage: &dyn StorageEngine) -> Result<Option<CheckResult>, RepoError> {
+    match storage.get(CHECK_FILE) {
+        Ok(buf) => Ok(Some(CheckResult::decode(&buf)?)),
+        Err(ref e) if e.is_not_found() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// The status of the checks of the repository in `storage`, as `serve`
+// with `opts` answers CHECK_STATUS.
+pub(crate) fn check_status(
+    storage: &dyn StorageEngine,
+    opts: &ServeOptions,
+) -> Result<CheckStatus, RepoError> {
+    Ok(CheckStatus {
+        every: opts.check_every,
+        last: read_check(storage)?,
+    })
+}
+
+impl Repo {
+    // How the server's checks of the repository went, none for storage
+    // that runs none.
+    pub fn check_status(&self) -> Result<CheckStatus, RepoError> {
+        match self.storage().check_status() {
+            Err(RepoError::UnsupportedOperationError) => Ok(Default::default()),
+            result => result,
+        }
+    }
+}
+
+// Check the repository in `storage`, `prefix` of the root, storing and
+// logging the outcome.
+pub fn check_repo(
+    storage: &Arc<dyn StorageEngine>,
+    prefix: &str,
+    log: Option<&OpLog>,
+) -> Result<CheckResult, RepoError> {
+    let result = (|| -> Result<CheckResult, RepoError> {
+        let report = open_repo(storage)?.audit(&Default::default())?;
+        let result = CheckResult::new(&report, unix_now());
+        storage.put(CHECK_FILE, &result.encode())?;
+        Ok(result)
+    })();
+    if let Some(log) = log {
+        log.record(&Operation {
+            time: unix_now(),
+            client: None,
+            op: "check".to_string(),
+            key: format!("{}{}", prefix, CHECK_FILE),
+            error: match result {
+                Ok(ref result) => result.problems(),
+                Err(ref err) => Some(err.to_string()),
+            },
+            addresses: Vec::new(),
+        })?;
+        log.seal()?;
+    }
+    result
+}
+
+// A repository served, and its prefix of the root.
+type Served = (String, Arc<dyn StorageEngine>);
+
+// The repositories of `root` served with `opts`.
+fn served(root: &Arc<dyn StorageEngine>, opts: &ServeOptions) -> Result<Vec<Served>, RepoError> {
+    let names = match opts.tenant {
+        Some(ref name) => vec![name.clone()],
+        None if opts.tenants => list_tenants(&**root)?,
+        None => {
+            let exists = root.exists(CONFIG_FILE)?;
+            return Ok(if exists {
+                vec![(String::new(), root.clone())]
+            } else {
+                Vec::new()
+            });
+        }
+    };
+    let mut served = Vec::new();
+    for name in names.iter() {
+        let storage = tenant_storage(root, name)?;
+        if storage.exists(CONFIG_FILE)? {
+            served.push((format!("{}/{}/", TENANTS_DIR, name), storage));
+        }
+    }
+    Ok(served)
+}
+
+// Check each repository served whose check is due, returning when the
+// next is due. A failed check is only logged, and due again an interval
+// later.
+pub fn run_due_checks(
+    root: &Arc<dyn StorageEngine>,
+    opts: &ServeOptions,
+    log: Option<&OpLog>,
+) -> Result<Option<u64>, RepoError> {
+    let every = match opts.check_every {
+        Some(every) => every,
+        None => return Ok(None),
+    };
+    let mut next: Option<u64> = None;
+    for (prefix, storage) in served(root, opts)?.iter() {
+        let mut due = read_check(&**storage)?.map_or(0, |last| last.time.saturating_add(every));
+        if due <= unix_now() {
+            let _ = check_repo(storage, prefix, log);
+            due = unix_now().saturating_add(every);
+        }
+        next = Some(next.map_or(due, |next| next.min(due)));
+    }
+    Ok(next)
+}
+
+// Stops the thread `schedule_checks` runs on.
+#[derive(Default)]
+pub struct Checks {
+    stopped: Mutex<bool>,
+    wake: Condvar,
+}
+
+impl Checks {
+    pub fn stop(&self) {
+        *self.stopped.lock().unwrap() = true;
+        self.wake.notify_all();
+    }
+}
+
+// Run the checks of `opts` as they fall due until `checks` is stopped,
+// see `serve_unix`.
+pub fn schedule_checks(
+    root: &Arc<dyn StorageEngine>,
+    opts: &ServeOptions,
+    checks: &Checks,
+) -> Result<(), RepoError> {
+    let every = match opts.check_every {
+        Some(every) => every,
+        None => return Ok(()),
+    };
+    let log = match opts.log {
+        Some(ref path) => Some(open_log(path, opts)?),
+        None => None,
+    };
+    let mut stopped = checks.stopped.lock().unwrap();
+    while !*stopped {
+        drop(stopped);
+        // Storage that cannot be listed now may well be later.
+        let next = run_due_checks(root, opts, log.as_ref())
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| unix_now().saturating_add(every));
+        let wait = next.saturating_sub(unix_now()).clamp(1, every);
+        stopped = checks.stopped.lock().unwrap();
+        if !*stopped {
+            stopped = checks
+                .wake
+                .wait_timeout(stopped, Duration::from_secs(wait))
+                .unwrap()
+                .0;
+        }
+    }
+    Ok(())
+}
+
+// Run the checks due of the repository in `dir` served with `opts`, for
+// a server started per connection.
+pub fn check_dir(dir: &Path, opts: &ServeOptions) -> Result<(), RepoError> {
+    let storage: Arc<dyn StorageEngine> = Arc::new(LocalStorage::new(dir)?);
+    let log = match opts.log {
+        Some(ref path) => Some(open_log(path, opts)?),
+        None => None,
+    };
+    run_due_checks(&storage, opts, log.as_ref())?;
+    Ok(())
+}
+
+// Tests --------------------
+
+#[test]
+fn test_checks() {
+    use crate::storage::mem::MemStorage;
+    let root: Arc<dyn StorageEngine> = Arc::new(MemStorage::new());
+    let storage = tenant_storage(&root, "design").unwrap();
+    let key = asymcrypt::Key::new();
+    let r = crate::Repo::init(storage.clone(), Default::default(), &key).unwrap();
+    crate::gc::test_commit_tree(&r, &key, 1, &[crate::address::Address { bytes: [1; 32] }]);
+    let opts = ServeOptions {
+        tenants: true,
+        check_every: Some(24 * 60 * 60),
+        ..Default::default()
+    };
+    let status = check_status(&*storage, &opts).unwrap();
+    assert!(status.last.is_none() && status.is_overdue(unix_now()));
+
+    let next = run_due_checks(&root, &opts, None).unwrap().unwrap();
+    assert!(next > unix_now() + 24 * 60 * 60 - 60);
+    let last = read_check(&*storage).unwrap().unwrap();
+    assert_eq!(CheckResult::decode(&last.encode()).unwrap(), last);
+    assert!(last.items > 0 && last.errors == 0, "{:?}", last);
+    // Not due again yet.
+    run_due_checks(&root, &opts, None).unwrap();
+    assert_eq!(read_check(&*storage).unwrap().unwrap(), last);
+    let status = check_status(&*storage, &opts).unwrap();
+    assert!(!status.is_overdue(unix_now()));
+    assert!(status.is_overdue(last.time + 3 * 24 * 60 * 60));
+    assert!(status.to_string().ends_with("no problems"), "{}", status);
+
+    // A damaged pack is found.
+    let pack = format!(
+        "{}/{}",
+        crate::PACKS_DIR,
+        r.list_packs().unwrap()[0].to_hex()
+    );
+    let mut data = storage.get(&pack).unwrap();
+    data.truncate(data.len() - 1);
+    storage.put(&pack, &data).unwrap();
+    let result = check_repo(&storage, "tenants/design/", None).unwrap();
+    assert!(result.errors > 0);
+    assert_eq!(read_check(&*storage).unwrap().unwrap(), result);
+    let unscheduled = check_status(&*storage, &Default::default()).unwrap();
+    assert!(!unscheduled.is_overdue(unix_now()));
+}
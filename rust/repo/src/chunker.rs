@@ -0,0 +1,200 @@
+//! Content defined chunking.
+//!
+//! Streams are cut where a rolling gear hash of the preceding bytes has
+//! its top `log2(avg_size)` bits clear, so an insertion only moves the
+//! boundaries next to it and unchanged data still deduplicates. No cut is
+//! made in the first `min_size` bytes of a chunk and one is forced at
+//! `max_size`, chunks are about `min_size + avg_size` bytes on average.
+//!
+//! The gear table is fixed, changing it would change every boundary and
+//! defeat deduplication against existing backups. Boundaries depend only
+//! on the data and the repository's chunker parameters, see `manifest`.
+
+use super::manifest::ChunkerParams;
+use super::RepoError;
+use std::io::Read;
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64 from a fixed seed.
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x7061_636b_6e62_6163;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+const GEAR: [u64; 256] = gear_table();
+
+// The length of the first chunk of `buf`, or None if more data could
+// still move the boundary.
+fn find_boundary(params: &ChunkerParams, buf: &[u8], eof: bool) -> Option<usize> {
+    let min = params.min_size as usize;
+    let max = params.max_size as usize;
+    if buf.len() <= min {
+        return if eof && !buf.is_empty() {
+            Some(buf.len())
+        } else {
+            None
+        };
+    }
+    let bits = params.avg_size.trailing_zeros();
+    let mask = if bits == 0 { 0 } else { !0u64 << (64 - bits) };
+    let mut h: u64 = 0;
+    // Bytes older than 64 no longer affect the hash, start there.
+    for (i, b) in buf
+        .iter()
+        .enumerate()
+        .take(max)
+        .skip(min.saturating_sub(64))
+    {
+        h = (h << 1).wrapping_add(GEAR[*b as usize]);
+        if i + 1 >= min && h & mask == 0 {
+            return Some(i + 1);
+        }
+    }
+    if buf.len() >= max {
+        Some(max)
+    } else if eof {
+        Some(buf.len())
+    } else {
+        None
+    }
+}
+
+pub struct Chunker<R: Read> {
+    r: R,
+    params: ChunkerParams,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Chunker<R> {
+    pub fn new(r: R, params: &ChunkerParams) -> Result<Chunker<R>, RepoError> {
+        if !params.is_valid() {
+            return Err(RepoError::InvalidDataError);
+        }
+        Ok(Chunker {
+            r,
+            params: params.clone(),
+            buf: Vec::new(),
+            eof: false,
+        })
+    }
+
+    // The next chunk, or None once the stream is exhausted.
+    pub fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, RepoError> {
+        let max = self.params.max_size as usize;
+        loop {
+            if let Some(n) = find_boundary(&self.params, &self.buf, self.eof) {
+                let rest = self.buf.split_off(n);
+                return Ok(Some(std::mem::replace(&mut self.buf, rest)));
+            }
+            if self.eof {
+                return Ok(None);
+            }
+            // Fill the buffer so each chunk is only scanned once.
+            let mut have = self.buf.len();
+            self.buf.resize(max, 0);
+            while have < max {
+                match self.r.read(&mut self.buf[have..]) {
+                    Ok(0) => {
+                        self.eof = true;
+                        break;
+                    }
+                    Ok(n) => have += n,
+                    Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                    Err(e) => {
+                        self.buf.truncate(have);
+                        return Err(e.into());
+                    }
+                }
+            }
+            self.buf.truncate(have);
+        }
+    }
+}
+
+impl<R: Read> Iterator for Chunker<R> {
+    type Item = Result<Vec<u8>, RepoError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().transpose()
+    }
+}
+
+// Tests --------------------
+
+#[cfg(test)]
+pub(crate) fn test_data(n: usize, seed: u64) -> Vec<u8> {
+    let mut x = seed;
+    (0..n)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        })
+        .collect()
+}
+
+#[test]
+fn test_chunker() {
+    let params = ChunkerParams {
+        min_size: 1024,
+        avg_size: 4096,
+        max_size: 16384,
+    };
+    let data = test_data(1 << 20, 1);
+    let chunks: Vec<Vec<u8>> = Chunker::new(&data[..], &params)
+        .unwrap()
+        .map(|c| c.unwrap())
+        .collect();
+    assert_eq!(chunks.concat(), data);
+    assert!(chunks.len() > 100 && chunks.len() < 400);
+    for c in chunks[..chunks.len() - 1].iter() {
+        assert!(c.len() >= 1024 && c.len() <= 16384);
+    }
+
+    // Reads of any size give the same chunks.
+    struct Trickle<'a>(&'a [u8]);
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(7);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+    let trickled: Vec<Vec<u8>> = Chunker::new(Trickle(&data), &params)
+        .unwrap()
+        .map(|c| c.unwrap())
+        .collect();
+    assert_eq!(trickled, chunks);
+
+    // An insertion only disturbs the chunks around it.
+    let mut shifted = b"inserted".to_vec();
+    shifted.extend_from_slice(&data);
+    let moved: Vec<Vec<u8>> = Chunker::new(&shifted[..], &params)
+        .unwrap()
+        .map(|c| c.unwrap())
+        .collect();
+    let common = moved.iter().filter(|c| chunks.contains(c)).count();
+    assert!(common >= chunks.len() - 2);
+
+    // Repetitive data is cut in the same places throughout.
+    let zeros = vec![0; 40000];
+    let sizes: Vec<usize> = Chunker::new(&zeros[..], &params)
+        .unwrap()
+        .map(|c| c.unwrap().len())
+        .collect();
+    assert_eq!(sizes.iter().sum::<usize>(), 40000);
+    assert!(sizes[..sizes.len() - 1].iter().all(|n| *n == sizes[0]));
+    assert!(Chunker::new(&b""[..], &params).unwrap().next().is_none());
+}
@@ -0,0 +1,322 @@
+//! Packs kept in archival storage.
+//!
+//! Storage classes such as S3 Glacier cost a fraction of normal storage
+//! but must be thawed, hours ahead, before they can be read. Moving packs
+//! there is done outside packnback, usually by a lifecycle rule, and
+//! recorded with `mark_cold` so restores can plan ahead instead of
+//! discovering cold packs one failed read at a time.
+//!
+//! A restore first asks for a `restore_plan`, which walks the snapshot and
+//! lists the packs it needs and which of them are cold, then thaws them
+//! all at once with `thaw_packs` and waits for the reported delay. Trees
+//! held in cold packs cannot be walked until they thaw, the plan then
+//! says it is incomplete and is asked for again afterwards.
+//!
+//! Each mark is a separate object so writers never contend for it:
+//!
+//! ```text
+//! cold/<hex pack id>: "PNBCOLD" u16:format_version str:tier u64:since
+//! ```
+
+use super::address::Address;
+use super::datetime::unix_now;
+use super::index::{PackIndex, RepoIndex};
+use super::lock::LockMode;
+use super::object::decode_refs;
+use super::pack::PackId;
+use super::storage::ThawState;
+use super::wire::{Decoder, Encoder};
+use super::{Repo, RepoError, COLD_DIR, PACKS_DIR};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use tweetnacl::CryptoBoxSk;
+
+pub const COLD_FORMAT_VERSION: u16 = 1;
+const COLD_MAGIC: &[u8] = b"PNBCOLD";
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ColdMark {
+    // Free form, for example "GLACIER" or "DEEP_ARCHIVE".
+    pub tier: String,
+    pub since: u64,
+}
+
+impl ColdMark {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.fixed(COLD_MAGIC)
+            .u16(COLD_FORMAT_VERSION)
+            .str(&self.tier)
+            .u64(self.since);
+        e.into_vec()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<ColdMark, RepoError> {
+        let mut d = Decoder::new(buf);
+        if d.fixed(COLD_MAGIC.len())? != COLD_MAGIC {
+            return Err(RepoError::InvalidDataError);
+        }
+        if d.u16()? != COLD_FORMAT_VERSION {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        let m = ColdMark {
+            tier: d.str()?.to_string(),
+            since: d.u64()?,
+        };
+        d.finish()?;
+        Ok(m)
+    }
+}
+
+pub fn cold_key(id: &PackId) -> String {
+    format!("{}/{}", COLD_DIR, id.to_hex())
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct RestorePlan {
+    // Every pack holding a needed object, cold or not.
+    pub packs: Vec<PackId>,
+    pub cold: Vec<PackId>,
+    // Sealed bytes of the needed objects.
+    pub bytes: u64,
+    // Total size of the cold packs, what a thaw retrieves.
+    pub cold_bytes: u64,
+    // False if trees in cold packs could not be walked yet.
+    pub complete: bool,
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct ThawReport {
+    pub ready: Vec<PackId>,
+    // Packs still thawing, with the seconds they are expected to take.
+    pub pending: Vec<(PackId, u64)>,
+}
+
+impl ThawReport {
+    pub fn is_ready(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    // Seconds until every pack should be readable.
+    pub fn delay(&self) -> u64 {
+        self.pending.iter().map(|(_, s)| *s).max().unwrap_or(0)
+    }
+}
+
+impl Repo {
+    pub fn mark_cold(&self, id: &PackId, tier: &str) -> Result<(), RepoError> {
+        let m = ColdMark {
+            tier: tier.to_string(),
+            since: unix_now(),
+        };
+        self.storage().put(&cold_key(id), &m.encode())
+    }
+
+    // Record that a pack is back in a normal storage class.
+    pub fn unmark_cold(&self, id: &PackId) -> Result<(), RepoError> {
+        self.storage().delete(&cold_key(id))
+    }
+
+    pub fn cold_packs(&self) -> Result<HashMap<PackId, ColdMark>, RepoError> {
+        let mut marks = HashMap::new();
+        for id in super::list_ids(&**self.storage(), COLD_DIR)? {
+            marks.insert(id, ColdMark::decode(&self.storage().get(&cold_key(&id))?)?);
+        }
+        Ok(marks)
+    }
+
+    pub fn restore_plan(
+        &self,
+        sk: &CryptoBoxSk,
+        snapshot: &Address,
+    ) -> Result<RestorePlan, RepoError> {
+        let _lock = self.lock(LockMode::Shared)?;
+        let mut indexes: Vec<PackIndex> = Vec::new();
+        for id in self.list_pack_indexes()? {
+            indexes.push(self.read_pack_index(&id)?);
+        }
+        let index = RepoIndex::build(indexes.iter());
+        let cold = self.cold_packs()?;
+
+        let mut plan = RestorePlan {
+            complete: true,
+            ..Default::default()
+        };
+        let mut packs = BTreeSet::new();
+        let mut seen = HashSet::new();
+        let mut todo = vec![*snapshot];
+        while let Some(address) = todo.pop() {
+            if !seen.insert(address) {
+                continue;
+            }
+            let loc = match index.lookup(&address) {
+                Some(loc) => *loc,
+                None => return Err(RepoError::MissingObjectError),
+            };
+            packs.insert(loc.pack_id);
+            plan.bytes += loc.length as u64;
+            if !loc.kind.has_refs() {
+                continue;
+            }
+            if cold.contains_key(&loc.pack_id) {
+                plan.complete = false;
+                continue;
+            }
+            let mut pack = self.open_pack(&loc.pack_id, sk)?;
+            todo.extend(decode_refs(&pack.read(loc.offset, loc.length)?)?);
+        }
+
+        for idx in indexes.iter() {
+            if packs.contains(&idx.pack_id) && cold.contains_key(&idx.pack_id) {
+                plan.cold.push(idx.pack_id);
+                plan.cold_bytes += idx.pack_size;
+            }
+        }
+        plan.packs = packs.into_iter().collect();
+        Ok(plan)
+    }
+
+    // Request every pack at once, the archive processes them in parallel.
+    pub fn thaw_packs(&self, ids: &[PackId]) -> Result<ThawReport, RepoError> {
+        let mut report: ThawReport = Default::default();
+        for id in ids {
+            match self
+                .storage()
+                .thaw(&format!("{}/{}", PACKS_DIR, id.to_hex()))?
+            {
+                ThawState::Ready => report.ready.push(*id),
+                ThawState::Pending(secs) => report.pending.push((*id, secs)),
+            }
+        }
+        Ok(report)
+    }
+}
+
+// Tests --------------------
+
+// Values listed in `cold` need this many more thaw calls to be readable.
+#[cfg(test)]
+struct TestColdStorage {
+    inner: super::storage::mem::MemStorage,
+    cold: std::sync::Mutex<HashMap<String, u32>>,
+}
+
+#[cfg(test)]
+impl TestColdStorage {
+    fn check(&self, key: &str) -> Result<(), RepoError> {
+        match self.cold.lock().unwrap().get(key) {
+            Some(n) if *n > 0 => Err(RepoError::ColdStorageError),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+impl super::storage::StorageEngine for TestColdStorage {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), RepoError> {
+        self.inner.put(key, data)
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, RepoError> {
+        self.check(key)?;
+        self.inner.get(key)
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: usize) -> Result<Vec<u8>, RepoError> {
+        self.check(key)?;
+        self.inner.get_range(key, offset, len)
+    }
+
+    fn size(&self, key: &str) -> Result<u64, RepoError> {
+        self.inner.size(key)
+    }
+
+    fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, RepoError> {
+        self.inner.list_prefix(prefix)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RepoError> {
+        self.inner.delete(key)
+    }
+
+    fn capabilities(&self) -> super::storage::Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn thaw(&self, key: &str) -> Result<ThawState, RepoError> {
+        match self.cold.lock().unwrap().get_mut(key) {
+            Some(n) if *n > 0 => {
+                *n -= 1;
+                Ok(ThawState::Pending(*n as u64 * 3600))
+            }
+            _ => Ok(ThawState::Ready),
+        }
+    }
+}
+
+#[test]
+fn test_cold_restore() {
+    use super::object::{encode_refs, ObjectKind};
+    use std::sync::Arc;
+    let key = asymcrypt::Key::new();
+    let storage = Arc::new(TestColdStorage {
+        inner: super::storage::mem::MemStorage::new(),
+        cold: Default::default(),
+    });
+    let r = Repo::init(storage.clone(), Default::default(), &key).unwrap();
+    let chunk = Address { bytes: [100; 32] };
+    let head1 = super::gc::test_commit_tree(&r, &key, 1, &[chunk]);
+    let old_pack = r.list_packs().unwrap()[0];
+
+    // A second snapshot whose tree reuses the chunk in the first pack.
+    let mut tx = r.begin(Default::default()).unwrap();
+    let mut tree = Encoder::new();
+    encode_refs(&mut tree, &[chunk]);
+    let tree_address = Address { bytes: [2; 32] };
+    tx.add(&tree_address, ObjectKind::Tree, &tree.into_vec())
+        .unwrap();
+    let mut snapshot = Encoder::new();
+    encode_refs(&mut snapshot, &[tree_address]);
+    let head2 = super::manifest::SnapshotHead {
+        address: Address { bytes: [3; 32] },
+        ..head1.clone()
+    };
+    tx.add(&head2.address, ObjectKind::Snapshot, &snapshot.into_vec())
+        .unwrap();
+    tx.commit(head2.clone(), &key).unwrap();
+
+    let plan = r.restore_plan(&key.box_sk, &head2.address).unwrap();
+    assert!(plan.complete && plan.cold.is_empty());
+    assert_eq!(plan.packs.len(), 2);
+
+    let pack_key = format!("{}/{}", PACKS_DIR, old_pack.to_hex());
+    storage.cold.lock().unwrap().insert(pack_key, 2);
+    r.mark_cold(&old_pack, "GLACIER").unwrap();
+    assert_eq!(r.cold_packs().unwrap()[&old_pack].tier, "GLACIER");
+    match r.open_pack(&old_pack, &key.box_sk) {
+        Err(RepoError::ColdStorageError) => (),
+        _ => panic!("expected cold pack reads to fail"),
+    }
+
+    // Only a chunk is cold, the plan is complete.
+    let plan = r.restore_plan(&key.box_sk, &head2.address).unwrap();
+    assert!(plan.complete);
+    assert_eq!(plan.cold, vec![old_pack]);
+    assert!(plan.cold_bytes > 0 && plan.bytes > 0);
+    // The first snapshot's tree is cold itself.
+    let plan = r.restore_plan(&key.box_sk, &head1.address).unwrap();
+    assert!(!plan.complete);
+    assert_eq!(plan.cold, vec![old_pack]);
+
+    let report = r.thaw_packs(&plan.cold).unwrap();
+    assert!(!report.is_ready());
+    assert_eq!(report.delay(), 3600);
+    r.thaw_packs(&plan.cold).unwrap();
+    let report = r.thaw_packs(&plan.packs).unwrap();
+    assert!(report.is_ready());
+    assert_eq!(report.ready, vec![old_pack]);
+    r.open_pack(&old_pack, &key.box_sk).unwrap();
+
+    r.unmark_cold(&old_pack).unwrap();
+    let plan = r.restore_plan(&key.box_sk, &head1.address).unwrap();
+    assert!(plan.complete && plan.cold.is_empty());
+}
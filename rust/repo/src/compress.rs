@@ -0,0 +1,105 @@
+//! Compression of objects before they are sealed.
+//!
+//! Every object is compressed with zstd before it is sealed into a pack or
+//! stored loose, unless that would not make it smaller. What is sealed
+//! starts with the codec the object was stored with, so a reader can tell
+//! compressed objects from the rest:
+//!
+//! ```text
+//! object: u8:codec body
+//! codec:  0 none, the body is the object
+//!         1 zstd, the body is one zstd frame that records its size
+//! ```
+//!
+//! Objects are addressed by what they hold, not by what is stored, so the
+//! same chunk deduplicates however it was stored. A frame that does not
+//! record its size, or records more than any object can hold, is refused
+//! before anything is decompressed.
+
+use super::RepoError;
+
+pub const CODEC_NONE: u8 = 0;
+pub const CODEC_ZSTD: u8 = 1;
+// zstd's own default, which compresses faster than most disks write.
+pub const ZSTD_LEVEL: i32 = 3;
+// What a context at `ZSTD_LEVEL` holds, its tables and window, about
+// 1.3 MiB whatever the chunk size, rounded up.
+pub const ZSTD_CONTEXT_MEMORY: u64 = 2 << 20;
+// No sealed object is longer, see `pack`.
+const MAX_OBJECT_SZ: u64 = u32::MAX as u64;
+
+// A zstd context, kept for many objects rather than set up for each.
+pub struct Compressor {
+    zstd: zstd::bulk::Compressor<'static>,
+}
+
+impl Compressor {
+    pub fn new() -> Result<Compressor, RepoError> {
+        Ok(Compressor {
+            zstd: zstd::bulk::Compressor::new(ZSTD_LEVEL)?,
+        })
+    }
+
+    // The object `data` as it is sealed, compressed if that is smaller.
+    pub fn encode(&mut self, data: &[u8]) -> Result<Vec<u8>, RepoError> {
+        let compressed = self.zstd.compress(data)?;
+        let (codec, body) = if compressed.len() < data.len() {
+            (CODEC_ZSTD, &compressed[..])
+        } else {
+            (CODEC_NONE, data)
+        };
+        let mut out = Vec::with_capacity(1 + body.len());
+        out.push(codec);
+        out.extend_from_slice(body);
+        Ok(out)
+    }
+}
+
+// The object sealed as `buf`.
+pub fn decode(buf: &[u8]) -> Result<Vec<u8>, RepoError> {
+    match buf.split_first() {
+        Some((&CODEC_NONE, body)) => Ok(body.to_vec()),
+        Some((&CODEC_ZSTD, body)) => {
+            let size = match zstd::zstd_safe::get_frame_content_size(body) {
+                Ok(Some(size)) if size <= MAX_OBJECT_SZ => size as usize,
+                _ => return Err(RepoError::InvalidDataError),
+            };
+            match zstd::bulk::decompress(body, size) {
+                Ok(data) if data.len() == size => Ok(data),
+                _ => Err(RepoError::InvalidDataError),
+            }
+        }
+        Some(_) => Err(RepoError::UnsupportedVersionError),
+        None => Err(RepoError::InvalidDataError),
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_compress() {
+    let mut c = Compressor::new().unwrap();
+    let text = b"the same line, again and again\n".repeat(100);
+    let encoded = c.encode(&text).unwrap();
+    assert_eq!(encoded[0], CODEC_ZSTD);
+    assert!(encoded.len() < text.len() / 10);
+    assert_eq!(decode(&encoded).unwrap(), text);
+
+    // What does not compress is stored as it is.
+    let mut random = vec![0; 4096];
+    tweetnacl::random_bytes(&mut random);
+    for data in [&random[..], b""].iter() {
+        let encoded = c.encode(data).unwrap();
+        assert_eq!(encoded[0], CODEC_NONE);
+        assert_eq!(decode(&encoded).unwrap(), *data);
+    }
+
+    let mut truncated = c.encode(&text).unwrap();
+    truncated.pop();
+    assert!(decode(&truncated).is_err());
+    assert!(decode(&[]).is_err());
+    match decode(&[7, 1, 2]) {
+        Err(RepoError::UnsupportedVersionError) => (),
+        _ => panic!("expected an unknown codec to be refused"),
+    }
+}
@@ -0,0 +1,149 @@
+//! The repository config file.
+//!
+//! The config is written once by `Repo::init` and signed by the repository
+//! owner. It pins the parameters every client must agree on before reading
+//! or writing anything else.
+//!
+//! Format (inside the signature envelope, see `signed`):
+//!
+//! ```text
+//! "PNBCONFIG" u16:format_version [16]:repo_id
+//! u32:chunk_min u32:chunk_avg u32:chunk_max u8:hash_algorithm
+//! bool:append_only bool:has_maintenance_key [32]:maintenance_pk
+//! bool:has_metadata_key [32]:metadata_pk
+//! ```
+//!
+//! `maintenance_pk` is all zero unless `has_maintenance_key` is set. The
+//! maintenance key is the only key that may sign a `policy`, so it is the
+//! only way to lift append only mode once the repository is created.
+//!
+//! `metadata_pk` is likewise all zero unless `has_metadata_key` is set. It
+//! is the box key metadata objects are sealed to besides the owner key, so
+//! that a host holding it can list and prune without being able to read
+//! file contents, see `keys`.
+
+use super::manifest::{ChunkerParams, HashAlgorithm, RepoId};
+use super::signed;
+use super::wire::{Decoder, Encoder};
+use super::RepoError;
+use tweetnacl::*;
+
+pub const REPO_FORMAT_VERSION: u16 = 1;
+const CONFIG_MAGIC: &[u8] = b"PNBCONFIG";
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RepoConfig {
+    pub format_version: u16,
+    pub repo_id: RepoId,
+    pub chunker: ChunkerParams,
+    pub hash: HashAlgorithm,
+    pub append_only: bool,
+    pub maintenance_pk: Option<CryptoSignPk>,
+    pub metadata_pk: Option<CryptoBoxPk>,
+}
+
+impl Default for RepoConfig {
+    fn default() -> RepoConfig {
+        RepoConfig {
+            format_version: REPO_FORMAT_VERSION,
+            repo_id: RepoId::new(),
+            chunker: Default::default(),
+            hash: HashAlgorithm::HmacSha512_256,
+            append_only: false,
+            maintenance_pk: None,
+            metadata_pk: None,
+        }
+    }
+}
+
+impl RepoConfig {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.fixed(CONFIG_MAGIC)
+            .u16(self.format_version)
+            .fixed(&self.repo_id.bytes)
+            .u32(self.chunker.min_size)
+            .u32(self.chunker.avg_size)
+            .u32(self.chunker.max_size)
+            .u8(self.hash.to_u8())
+            .bool(self.append_only);
+        match self.maintenance_pk {
+            Some(ref pk) => e.bool(true).fixed(&pk.bytes),
+            None => e.bool(false).fixed(&[0; 32]),
+        };
+        match self.metadata_pk {
+            Some(ref pk) => e.bool(true).fixed(&pk.bytes),
+            None => e.bool(false).fixed(&[0; 32]),
+        };
+        e.into_vec()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<RepoConfig, RepoError> {
+        let mut d = Decoder::new(buf);
+        if d.fixed(CONFIG_MAGIC.len())? != CONFIG_MAGIC {
+            return Err(RepoError::InvalidDataError);
+        }
+        let format_version = d.u16()?;
+        if format_version != REPO_FORMAT_VERSION {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        let mut repo_id: RepoId = Default::default();
+        d.fixed_into(&mut repo_id.bytes)?;
+        let chunker = ChunkerParams {
+            min_size: d.u32()?,
+            avg_size: d.u32()?,
+            max_size: d.u32()?,
+        };
+        if !chunker.is_valid() {
+            return Err(RepoError::InvalidDataError);
+        }
+        let hash = HashAlgorithm::from_u8(d.u8()?)?;
+        let append_only = d.bool()?;
+        let has_maintenance_key = d.bool()?;
+        let mut pk: CryptoSignPk = Default::default();
+        d.fixed_into(&mut pk.bytes)?;
+        let has_metadata_key = d.bool()?;
+        let mut mpk: CryptoBoxPk = Default::default();
+        d.fixed_into(&mut mpk.bytes)?;
+        d.finish()?;
+        Ok(RepoConfig {
+            format_version,
+            repo_id,
+            chunker,
+            hash,
+            append_only,
+            maintenance_pk: if has_maintenance_key { Some(pk) } else { None },
+            metadata_pk: if has_metadata_key { Some(mpk) } else { None },
+        })
+    }
+
+    pub fn sign(&self, sk: &CryptoSignSk) -> Vec<u8> {
+        signed::sign(&self.encode(), sk)
+    }
+
+    pub fn open(sm: &[u8], pk: &CryptoSignPk) -> Result<RepoConfig, RepoError> {
+        RepoConfig::decode(&signed::open(sm, pk)?)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_config_sign_open() {
+    let (pk, sk) = boxed_crypto_sign_keypair();
+    let c: RepoConfig = Default::default();
+    assert_eq!(RepoConfig::open(&c.sign(&sk), &pk).unwrap(), c);
+    let mut c2 = c.clone();
+    c2.append_only = true;
+    c2.maintenance_pk = Some((*pk).clone());
+    assert_eq!(RepoConfig::open(&c2.sign(&sk), &pk).unwrap(), c2);
+    let (box_pk, _) = boxed_crypto_box_keypair();
+    c2.metadata_pk = Some((*box_pk).clone());
+    assert_eq!(RepoConfig::open(&c2.sign(&sk), &pk).unwrap(), c2);
+    let mut bad = c.clone();
+    bad.format_version = 2;
+    match RepoConfig::open(&bad.sign(&sk), &pk) {
+        Err(RepoError::UnsupportedVersionError) => (),
+        _ => panic!("expected version error"),
+    }
+}
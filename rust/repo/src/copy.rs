@@ -0,0 +1,161 @@
+//! Copying snapshots between repositories.
+//!
+//! `copy_snapshot` transfers one snapshot from this repository into
+//! another, with only the objects the destination index does not already
+//! list. Objects are decrypted with the source key and sealed again into
+//! new destination packs, whose pack keys are wrapped to the destination
+//! owner, so the two repositories share no key material.
+//!
+//! Objects keep their addresses. A destination with a different address
+//! key still stores and restores the copy correctly but cannot deduplicate
+//! it against its own backups.
+//!
+//! Present objects are never descended into, a committed tree is only ever
+//! indexed along with everything below it. The copy is written in a single
+//! transaction, see `transaction`, and published by adding the source head
+//! to the destination manifest.
+
+use super::address::Address;
+use super::index::RepoIndex;
+use super::lock::LockMode;
+use super::object::decode_refs;
+use super::pack::PackerOptions;
+use super::{Repo, RepoError};
+use asymcrypt::Key;
+use std::collections::HashSet;
+use tweetnacl::CryptoBoxSk;
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct CopyStats {
+    pub copied_objects: usize,
+    pub copied_bytes: u64,
+    // Objects the destination already held.
+    pub present_objects: usize,
+}
+
+impl Repo {
+    pub fn copy_snapshot(
+        &self,
+        sk: &CryptoBoxSk,
+        snapshot: &Address,
+        dest: &Repo,
+        dest_key: &Key,
+        opts: PackerOptions,
+    ) -> Result<CopyStats, RepoError> {
+        let mut lock = self.lock(LockMode::Shared)?;
+        let head = match self
+            .manifest()?
+            .heads
+            .into_iter()
+            .find(|h| h.address == *snapshot)
+        {
+            Some(head) => head,
+            None => return Err(RepoError::MissingObjectError),
+        };
+        let index = self.load_index()?;
+        let mut tx = dest.begin(opts)?;
+        let present: RepoIndex = dest.load_index()?;
+
+        let mut stats: CopyStats = Default::default();
+        let mut seen = HashSet::new();
+        let mut todo = head.roots();
+        while let Some(address) = todo.pop() {
+            if !seen.insert(address) {
+                continue;
+            }
+            if present.contains(&address) {
+                stats.present_objects += 1;
+                continue;
+            }
+            let loc = match index.lookup(&address) {
+                Some(loc) => *loc,
+                None => return Err(RepoError::MissingObjectError),
+            };
+            let data = self
+                .open_pack(&loc.pack_id, sk)?
+                .read(loc.offset, loc.length)?;
+            if loc.kind.has_refs() {
+                todo.extend(decode_refs(&data)?);
+            }
+            tx.add(&address, loc.kind, &data)?;
+            stats.copied_objects += 1;
+            stats.copied_bytes += data.len() as u64;
+            lock.refresh()?;
+            tx.refresh()?;
+        }
+        tx.commit(head, dest_key)?;
+        Ok(stats)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_copy_snapshot() {
+    use super::gc::test_commit_tree;
+    let (src, src_key) = super::test_repo();
+    let (dest, dest_key) = super::test_repo();
+    let chunk = |b: u8| Address { bytes: [b; 32] };
+    let head1 = test_commit_tree(&src, &src_key, 1, &[chunk(100), chunk(101)]);
+    let head2 = test_commit_tree(&src, &src_key, 2, &[chunk(100), chunk(102)]);
+
+    let stats = src
+        .copy_snapshot(
+            &src_key.box_sk,
+            &head1.address,
+            &dest,
+            &dest_key,
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(stats.copied_objects, 4);
+    assert_eq!(stats.present_objects, 0);
+    assert_eq!(dest.manifest().unwrap().heads, vec![head1.clone()]);
+    assert_eq!(
+        dest.mark(&dest_key.box_sk, &dest.load_index().unwrap())
+            .unwrap()
+            .len(),
+        4
+    );
+
+    // The shared chunk is not sent again.
+    let stats = src
+        .copy_snapshot(
+            &src_key.box_sk,
+            &head2.address,
+            &dest,
+            &dest_key,
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(stats.copied_objects, 3);
+    assert_eq!(stats.present_objects, 1);
+    let stats = src
+        .copy_snapshot(
+            &src_key.box_sk,
+            &head2.address,
+            &dest,
+            &dest_key,
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(stats.copied_objects, 0);
+    assert_eq!(dest.manifest().unwrap().heads.len(), 2);
+
+    // The source key cannot read the copy.
+    let id = dest.list_packs().unwrap()[0];
+    assert!(dest.open_pack(&id, &src_key.box_sk).is_err());
+    assert!(src.list_locks().unwrap().is_empty());
+    assert!(dest.list_locks().unwrap().is_empty());
+
+    match src.copy_snapshot(
+        &src_key.box_sk,
+        &chunk(1),
+        &dest,
+        &dest_key,
+        Default::default(),
+    ) {
+        Err(RepoError::MissingObjectError) => (),
+        _ => panic!("expected an unknown snapshot to be refused"),
+    }
+}
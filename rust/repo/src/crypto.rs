@@ -0,0 +1,122 @@
+//! Symmetric sealing of objects and asymmetric wrapping of the keys.
+//!
+//! A sealed value is `[24]:nonce` followed by the xsalsa20poly1305
+//! ciphertext without the nacl zero padding. A wrapped key is
+//! `[32]:ephemeral_box_pk [24]:nonce [48]:boxed_key`, readable only with the
+//! recipient box secret key.
+
+use super::RepoError;
+use std::sync::atomic::{compiler_fence, Ordering};
+use tweetnacl::*;
+
+pub const NONCE_SZ: usize = 24;
+pub const SEAL_OVERHEAD: usize =
+    NONCE_SZ + CRYPTO_SECRETBOX_ZEROBYTES - CRYPTO_SECRETBOX_BOXZEROBYTES;
+pub const WRAPPED_KEY_SZ: usize =
+    32 + NONCE_SZ + 32 + CRYPTO_BOX_ZEROBYTES - CRYPTO_BOX_BOXZEROBYTES;
+
+// Zero a buffer that held key material. The writes are volatile so they
+// are not dropped as dead stores, as a plain assignment before the buffer
+// goes out of scope may be.
+pub fn wipe(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        unsafe { std::ptr::write_volatile(b, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+pub fn seal(k: &CryptoSecretboxKey, data: &[u8]) -> Vec<u8> {
+    let n = CryptoBoxNonce::new();
+    let mut m = vec![0; CRYPTO_SECRETBOX_ZEROBYTES + data.len()];
+    m[CRYPTO_SECRETBOX_ZEROBYTES..].copy_from_slice(data);
+    let mut c = vec![0; m.len()];
+    crypto_secretbox(&mut c, &m, &n, k);
+    let mut out = Vec::with_capacity(SEAL_OVERHEAD + data.len());
+    out.extend_from_slice(&n.bytes);
+    out.extend_from_slice(&c[CRYPTO_SECRETBOX_BOXZEROBYTES..]);
+    out
+}
+
+pub fn unseal(k: &CryptoSecretboxKey, sealed: &[u8]) -> Result<Vec<u8>, RepoError> {
+    if sealed.len() < SEAL_OVERHEAD {
+        return Err(RepoError::CorruptOrTamperedDataError);
+    }
+    let mut n: CryptoBoxNonce = Default::default();
+    n.bytes.copy_from_slice(&sealed[..NONCE_SZ]);
+    let body = &sealed[NONCE_SZ..];
+    let mut c = vec![0; CRYPTO_SECRETBOX_BOXZEROBYTES + body.len()];
+    c[CRYPTO_SECRETBOX_BOXZEROBYTES..].copy_from_slice(body);
+    let mut m = vec![0; c.len()];
+    if !crypto_secretbox_open(&mut m, &c, &n, k) {
+        return Err(RepoError::CorruptOrTamperedDataError);
+    }
+    Ok(m.split_off(CRYPTO_SECRETBOX_ZEROBYTES))
+}
+
+pub fn wrap_key(k: &CryptoSecretboxKey, to: &CryptoBoxPk) -> Vec<u8> {
+    let (ephemeral_pk, ephemeral_sk) = boxed_crypto_box_keypair();
+    let n = CryptoBoxNonce::new();
+    let mut m = [0; CRYPTO_BOX_ZEROBYTES + 32];
+    m[CRYPTO_BOX_ZEROBYTES..].copy_from_slice(&k.bytes);
+    let mut c = [0; CRYPTO_BOX_ZEROBYTES + 32];
+    crypto_box(&mut c, &m, &n, to, &ephemeral_sk);
+    wipe(&mut m);
+    let mut out = Vec::with_capacity(WRAPPED_KEY_SZ);
+    out.extend_from_slice(&ephemeral_pk.bytes);
+    out.extend_from_slice(&n.bytes);
+    out.extend_from_slice(&c[CRYPTO_BOX_BOXZEROBYTES..]);
+    out
+}
+
+pub fn unwrap_key(wrapped: &[u8], sk: &CryptoBoxSk) -> Result<CryptoSecretboxKey, RepoError> {
+    if wrapped.len() != WRAPPED_KEY_SZ {
+        return Err(RepoError::InvalidDataError);
+    }
+    let mut ephemeral_pk: CryptoBoxPk = Default::default();
+    ephemeral_pk.bytes.copy_from_slice(&wrapped[..32]);
+    let mut n: CryptoBoxNonce = Default::default();
+    n.bytes.copy_from_slice(&wrapped[32..32 + NONCE_SZ]);
+    let mut c = [0; CRYPTO_BOX_ZEROBYTES + 32];
+    c[CRYPTO_BOX_BOXZEROBYTES..].copy_from_slice(&wrapped[32 + NONCE_SZ..]);
+    let mut m = [0; CRYPTO_BOX_ZEROBYTES + 32];
+    if !crypto_box_open(&mut m, &c, &n, &ephemeral_pk, sk) {
+        // A failed open may leave a partial plaintext behind.
+        wipe(&mut m);
+        return Err(RepoError::DecryptKeyMismatchError);
+    }
+    let mut k: CryptoSecretboxKey = Default::default();
+    k.bytes.copy_from_slice(&m[CRYPTO_BOX_ZEROBYTES..]);
+    wipe(&mut m);
+    Ok(k)
+}
+
+// Tests --------------------
+
+#[test]
+fn test_seal_unseal() {
+    let k = CryptoSecretboxKey::new();
+    let mut sealed = seal(&k, b"hello world");
+    assert_eq!(sealed.len(), SEAL_OVERHEAD + 11);
+    assert_eq!(unseal(&k, &sealed).unwrap(), b"hello world");
+    assert_eq!(unseal(&k, &seal(&k, b"")).unwrap(), b"");
+    sealed[30] ^= 1;
+    assert!(unseal(&k, &sealed).is_err());
+    assert!(unseal(&k, &sealed[..10]).is_err());
+}
+
+#[test]
+fn test_wrap_unwrap_key() {
+    let k = CryptoSecretboxKey::new();
+    let (pk, sk) = boxed_crypto_box_keypair();
+    let wrapped = wrap_key(&k, &pk);
+    assert_eq!(wrapped.len(), WRAPPED_KEY_SZ);
+    assert_eq!(unwrap_key(&wrapped, &sk).unwrap().bytes, k.bytes);
+    let (_, sk2) = boxed_crypto_box_keypair();
+    match unwrap_key(&wrapped, &sk2) {
+        Err(RepoError::DecryptKeyMismatchError) => (),
+        _ => panic!("expected key mismatch"),
+    }
+    let mut buf = k.bytes;
+    wipe(&mut buf);
+    assert_eq!(buf, [0; 32]);
+}
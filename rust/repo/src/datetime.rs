@@ -0,0 +1,163 @@
+//! UTC calendar conversions for unix timestamps.
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DateTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+// Days since the unix epoch to a (year, month, day) civil date, from
+// Howard Hinnant's public domain date algorithms.
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let y = yoe + era * 400;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (if m <= 2 { y + 1 } else { y }, m, d)
+}
+
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let m = m as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+impl DateTime {
+    pub fn from_unix(t: u64) -> DateTime {
+        let days = (t / 86400) as i64;
+        let secs = (t % 86400) as u32;
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year,
+            month,
+            day,
+            hour: secs / 3600,
+            minute: secs / 60 % 60,
+            second: secs % 60,
+        }
+    }
+
+    pub fn to_unix(&self) -> u64 {
+        let days = days_from_civil(self.year, self.month, self.day);
+        (days * 86400) as u64 + (self.hour * 3600 + self.minute * 60 + self.second) as u64
+    }
+
+    pub fn now() -> DateTime {
+        DateTime::from_unix(unix_now())
+    }
+
+    // 2006-01-02T15:04:05Z
+    pub fn to_rfc3339(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+
+    // Either 2006-01-02T15:04:05Z or a date, 2006-01-02, meaning its start.
+    pub fn parse_rfc3339(s: &str) -> Option<DateTime> {
+        let (date, time) = match s.len() {
+            10 => (s, "00:00:00"),
+            20 if s.is_char_boundary(10) && s.ends_with('Z') => {
+                match (&s[..10], &s[10..11], &s[11..19]) {
+                    (date, "T", time) => (date, time),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+        let fields = |s: &str, sep: char| -> Option<Vec<u32>> {
+            s.split(sep)
+                .map(|f| match f.len() {
+                    2 | 4 if f.bytes().all(|b| b.is_ascii_digit()) => f.parse().ok(),
+                    _ => None,
+                })
+                .collect()
+        };
+        match (&fields(date, '-')?[..], &fields(time, ':')?[..]) {
+            ([year, month, day], [hour, minute, second])
+                if *year >= 1970
+                    && (1..=12).contains(month)
+                    && *day >= 1
+                    && *day <= days_in_month(*year as i64, *month)
+                    && *hour < 24
+                    && *minute < 60
+                    && *second < 60 =>
+            {
+                Some(DateTime {
+                    year: *year as i64,
+                    month: *month,
+                    day: *day,
+                    hour: *hour,
+                    minute: *minute,
+                    second: *second,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    let next = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+    (days_from_civil(next.0, next.1, 1) - days_from_civil(year, month, 1)) as u32
+}
+
+pub fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Tests --------------------
+
+#[test]
+fn test_datetime() {
+    let t = DateTime::from_unix(1_369_353_600);
+    assert_eq!(t.to_rfc3339(), "2013-05-24T00:00:00Z");
+    assert_eq!(t.to_unix(), 1_369_353_600);
+    let t = DateTime::from_unix(951_827_696);
+    assert_eq!(t.to_rfc3339(), "2000-02-29T12:34:56Z");
+    assert_eq!(t.to_unix(), 951_827_696);
+    assert_eq!(DateTime::from_unix(0).to_rfc3339(), "1970-01-01T00:00:00Z");
+}
+
+#[test]
+fn test_parse_rfc3339() {
+    let t = DateTime::parse_rfc3339("2000-02-29T12:34:56Z").unwrap();
+    assert_eq!(t.to_unix(), 951_827_696);
+    let t = DateTime::parse_rfc3339("2013-05-24").unwrap();
+    assert_eq!(t.to_unix(), 1_369_353_600);
+    for bad in [
+        "2001-02-29",
+        "2013-5-24",
+        "2013-05-24T24:00:00Z",
+        "2013-05-24 00:00:00Z",
+        "2013-05-24T00:00:00",
+        "1969-12-31",
+        "2013-05-2x",
+        "",
+    ]
+    .iter()
+    {
+        assert!(DateTime::parse_rfc3339(bad).is_none(), "{}", bad);
+    }
+}
@@ -0,0 +1,356 @@
+//! Comparing two snapshots.
+//!
+//! `Repo::diff` walks the trees of both snapshots side by side, see
+//! `tree`. A file whose chunk list, size or link target differs is
+//! modified, one where only its mode, owner or mtime differ has new
+//! metadata. Subtrees with the same address are identical and skipped,
+//! everything else is found from the tree objects alone, chunks are never
+//! fetched. An entry that changed kind, a file replaced by a directory
+//! say, is reported removed and added.
+//!
+//! `Repo::parent_snapshot` finds the snapshot to compare a snapshot with
+//! by default, the one before it in its namespace.
+//!
+//! `write_ndjson` emits one record per change, see `json`:
+//!
+//! ```text
+//! {"type": "change", "kind": "added"|"removed"|"modified"|"metadata",
+//!  "entry_kind": "file"|"dir"|"symlink", "path": s, "old_size": n,
+//!  "new_size": n}
+//! ```
+
+use super::address::Address;
+use super::index::RepoIndex;
+use super::json::{json_path, text_path};
+use super::manifest::SnapshotHead;
+use super::tree::{EntryKind, TreeEntry};
+use super::{Repo, RepoError};
+use std::cmp::Ordering;
+use std::io::{self, Write};
+use tweetnacl::CryptoBoxSk;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+    Metadata,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Change {
+    pub path: Vec<u8>,
+    pub kind: ChangeKind,
+    // The kind of the entry changed, the newer one if both exist.
+    pub entry_kind: EntryKind,
+    // File sizes, 0 for anything else and absent entries.
+    pub old_size: u64,
+    pub new_size: u64,
+}
+
+impl ChangeKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChangeKind::Added => "added",
+            ChangeKind::Removed => "removed",
+            ChangeKind::Modified => "modified",
+            ChangeKind::Metadata => "metadata",
+        }
+    }
+}
+
+impl Change {
+    pub fn size_delta(&self) -> i64 {
+        self.new_size as i64 - self.old_size as i64
+    }
+}
+
+pub(crate) fn file_size(ent: &TreeEntry) -> u64 {
+    match ent.kind {
+        EntryKind::File => ent.size,
+        _ => 0,
+    }
+}
+
+// One line per change, `+`, `-`, `M` or `U` for new metadata, the size
+// delta and the path, with a `/` after directories.
+pub fn write_changes(w: &mut dyn Write, changes: &[Change]) -> io::Result<()> {
+    for c in changes.iter() {
+        let mark = match c.kind {
+            ChangeKind::Added => '+',
+            ChangeKind::Removed => '-',
+            ChangeKind::Modified => 'M',
+            ChangeKind::Metadata => 'U',
+        };
+        let slash = if c.entry_kind == EntryKind::Dir {
+            "/"
+        } else {
+            ""
+        };
+        writeln!(
+            w,
+            "{} {:>+12} {}{}",
+            mark,
+            c.size_delta(),
+            text_path(&c.path),
+            slash
+        )?;
+    }
+    Ok(())
+}
+
+pub fn write_ndjson(w: &mut dyn Write, changes: &[Change]) -> io::Result<()> {
+    for c in changes.iter() {
+        writeln!(
+            w,
+            "{{\"type\": \"change\", \"kind\": \"{}\", \"entry_kind\": \"{}\", \"path\": {}, \
+             \"old_size\": {}, \"new_size\": {}}}",
+            c.kind.as_str(),
+            c.entry_kind.as_str(),
+            json_path(&c.path),
+            c.old_size,
+            c.new_size
+        )?;
+    }
+    Ok(())
+}
+
+struct Differ<'a> {
+    repo: &'a Repo,
+    index: RepoIndex,
+    sk: &'a CryptoBoxSk,
+    changes: Vec<Change>,
+}
+
+impl<'a> Differ<'a> {
+    fn entries(&self, tree: Option<&Address>) -> Result<Vec<TreeEntry>, RepoError> {
+        match tree {
+            Some(address) => Ok(self.repo.read_tree(&self.index, self.sk, address)?.entries),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn trees(
+        &mut self,
+        a: Option<&Address>,
+        b: Option<&Address>,
+        prefix: &[u8],
+    ) -> Result<(), RepoError> {
+        let a = self.entries(a)?;
+        let b = self.entries(b)?;
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len() {
+            let order = match (a.get(i), b.get(j)) {
+                (Some(x), Some(y)) => x.name.cmp(&y.name),
+                (Some(_), None) => Ordering::Less,
+                _ => Ordering::Greater,
+            };
+            match order {
+                Ordering::Less => {
+                    self.removed(&a[i], prefix)?;
+                    i += 1;
+                }
+                Ordering::Greater => {
+                    self.added(&b[j], prefix)?;
+                    j += 1;
+                }
+                Ordering::Equal => {
+                    self.compare(&a[i], &b[j], prefix)?;
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn push(
+        &mut self,
+        path: Vec<u8>,
+        kind: ChangeKind,
+        entry_kind: EntryKind,
+        old_size: u64,
+        new_size: u64,
+    ) {
+        self.changes.push(Change {
+            path,
+            kind,
+            entry_kind,
+            old_size,
+            new_size,
+        });
+    }
+
+    fn removed(&mut self, ent: &TreeEntry, prefix: &[u8]) -> Result<(), RepoError> {
+        let path = [prefix, &ent.name].concat();
+        self.push(
+            path.clone(),
+            ChangeKind::Removed,
+            ent.kind,
+            file_size(ent),
+            0,
+        );
+        if ent.kind == EntryKind::Dir {
+            self.trees(Some(&ent.refs[0]), None, &[&path[..], b"/"].concat())?;
+        }
+        Ok(())
+    }
+
+    fn added(&mut self, ent: &TreeEntry, prefix: &[u8]) -> Result<(), RepoError> {
+        let path = [prefix, &ent.name].concat();
+        self.push(path.clone(), ChangeKind::Added, ent.kind, 0, file_size(ent));
+        if ent.kind == EntryKind::Dir {
+            self.trees(None, Some(&ent.refs[0]), &[&path[..], b"/"].concat())?;
+        }
+        Ok(())
+    }
+
+    fn compare(&mut self, a: &TreeEntry, b: &TreeEntry, prefix: &[u8]) -> Result<(), RepoError> {
+        if a.kind != b.kind {
+            self.removed(a, prefix)?;
+            return self.added(b, prefix);
+        }
+        let path = [prefix, &b.name].concat();
+        let metadata = (a.mode, a.uid, a.gid, a.mtime, a.mtime_nsec)
+            != (b.mode, b.uid, b.gid, b.mtime, b.mtime_nsec);
+        let content =
+            a.refs != b.refs || a.size != b.size || a.target != b.target || a.device != b.device;
+        let (old_size, new_size) = (file_size(a), file_size(b));
+        if content && b.kind != EntryKind::Dir {
+            self.push(path, ChangeKind::Modified, b.kind, old_size, new_size);
+            return Ok(());
+        }
+        if metadata {
+            self.push(
+                path.clone(),
+                ChangeKind::Metadata,
+                b.kind,
+                old_size,
+                new_size,
+            );
+        }
+        if content {
+            self.trees(
+                Some(&a.refs[0]),
+                Some(&b.refs[0]),
+                &[&path[..], b"/"].concat(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Repo {
+    // Changes from snapshot `a` to snapshot `b`, in path order.
+    pub fn diff(
+        &self,
+        a: &Address,
+        b: &Address,
+        sk: &CryptoBoxSk,
+    ) -> Result<Vec<Change>, RepoError> {
+        let mut d = Differ {
+            repo: self,
+            index: self.load_index()?,
+            sk,
+            changes: Vec::new(),
+        };
+        let a = d.repo.read_snapshot(&d.index, sk, a)?.root;
+        let b = d.repo.read_snapshot(&d.index, sk, b)?.root;
+        if a != b {
+            d.trees(Some(&a), Some(&b), b"")?;
+        }
+        Ok(d.changes)
+    }
+
+    // The newest snapshot older than `address` in its namespace.
+    pub fn parent_snapshot(&self, address: &Address) -> Result<Option<SnapshotHead>, RepoError> {
+        let heads = self.manifest()?.heads;
+        let head = match heads.iter().find(|h| h.address == *address) {
+            Some(head) => head,
+            None => return Err(RepoError::MissingObjectError),
+        };
+        Ok(heads
+            .iter()
+            .filter(|h| h.namespace == head.namespace && h.timestamp < head.timestamp)
+            .max_by_key(|h| h.timestamp)
+            .cloned())
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_diff() {
+    use super::address::AddressKey;
+    use super::manifest::Manifest;
+    use super::namespace::Namespace;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let ns = Namespace::new("laptop").unwrap();
+    let dir = super::storage::local::test_dir("diff");
+    fs::create_dir_all(dir.join("same/deep")).unwrap();
+    fs::create_dir_all(dir.join("gone")).unwrap();
+    fs::write(dir.join("same/deep/file"), b"unchanged").unwrap();
+    fs::write(dir.join("gone/file"), b"bye").unwrap();
+    fs::write(dir.join("grows"), b"small").unwrap();
+    fs::write(dir.join("chmod"), b"mode").unwrap();
+    fs::write(dir.join("becomes-dir"), b"file").unwrap();
+    let (a, _) = r.backup(&dir, &ns, &ak, &key, &Default::default()).unwrap();
+
+    fs::remove_dir_all(dir.join("gone")).unwrap();
+    fs::write(dir.join("grows"), b"much larger now").unwrap();
+    fs::set_permissions(dir.join("chmod"), fs::Permissions::from_mode(0o600)).unwrap();
+    fs::remove_file(dir.join("becomes-dir")).unwrap();
+    fs::create_dir(dir.join("becomes-dir")).unwrap();
+    fs::write(dir.join("new"), b"hello").unwrap();
+    let (mut b, _) = r.backup(&dir, &ns, &ak, &key, &Default::default()).unwrap();
+    if b.timestamp == a.timestamp {
+        // Two backups within a second, order them for the parent lookup.
+        let mut m: Manifest = r.manifest().unwrap();
+        m.remove_head(&b.address);
+        b.timestamp += 1;
+        m.add_head(b.clone());
+        r.commit_manifest(&m, &key).unwrap();
+    }
+    assert_eq!(r.parent_snapshot(&b.address).unwrap(), Some(a.clone()));
+    assert_eq!(r.parent_snapshot(&a.address).unwrap(), None);
+
+    let changes = r.diff(&a.address, &b.address, &key.box_sk).unwrap();
+    let summary: Vec<(&[u8], ChangeKind, i64)> = changes
+        .iter()
+        .map(|c| (&c.path[..], c.kind, c.size_delta()))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (&b"becomes-dir"[..], ChangeKind::Removed, -4),
+            (b"becomes-dir", ChangeKind::Added, 0),
+            (b"chmod", ChangeKind::Metadata, 0),
+            (b"gone", ChangeKind::Removed, 0),
+            (b"gone/file", ChangeKind::Removed, -3),
+            (b"grows", ChangeKind::Modified, 10),
+            (b"new", ChangeKind::Added, 5),
+        ]
+    );
+    assert!(r
+        .diff(&a.address, &a.address, &key.box_sk)
+        .unwrap()
+        .is_empty());
+
+    let mut out = Vec::new();
+    write_changes(&mut out, &changes[3..6]).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "-           +0 gone/\n-           -3 gone/file\nM          +10 grows\n"
+    );
+    let mut out = Vec::new();
+    write_ndjson(&mut out, &changes[4..5]).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "{\"type\": \"change\", \"kind\": \"removed\", \"entry_kind\": \"file\", \
+         \"path\": \"gone/file\", \"old_size\": 3, \"new_size\": 0}\n"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
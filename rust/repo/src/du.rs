@@ -0,0 +1,246 @@
+//! Deduplicated disk usage of snapshots.
+//!
+//! With deduplication a snapshot's size says little about what keeping it
+//! costs. `Repo::du` answers that for the snapshots a `ListOptions`
+//! selects, see `list`, from the pack indexes and the reference lists of
+//! tree and snapshot objects, as `stats` does, chunks are never read:
+//!
+//! - `referenced_bytes` is the plaintext size of every object a snapshot
+//!   reaches, counted once however often it is referenced.
+//! - `unique_bytes` is the part of it no snapshot outside the selection
+//!   reaches, what forgetting it and a `gc` would free.
+//!
+//! Both are given per snapshot and for the selection as a whole. The
+//! selection frees more than the sum of its snapshots' `unique_bytes`:
+//! objects shared only among the selected snapshots count for none of
+//! them alone, but for all of them together. Sizes are before sealing.
+//!
+//! `write_json` emits a single JSON object, snapshots in the order the
+//! options sort them:
+//!
+//! ```text
+//! {"referenced_bytes": n, "unique_bytes": n,
+//!  "snapshots": [{"address": hex, "namespace": s, "time": n,
+//!                 "referenced_bytes": n, "unique_bytes": n}, ...]}
+//! ```
+//!
+//! `write_ndjson` emits it on one line with `"type": "du"` first, see
+//! `json`.
+
+use super::address::Address;
+use super::datetime::DateTime;
+use super::index::RepoIndex;
+use super::list::ListOptions;
+use super::lock::LockMode;
+use super::manifest::SnapshotHead;
+use super::progress::human_bytes;
+use super::stats::Graph;
+use super::{Repo, RepoError};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use tweetnacl::CryptoBoxSk;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SnapshotUsage {
+    pub head: SnapshotHead,
+    pub referenced_bytes: u64,
+    pub unique_bytes: u64,
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct DiskUsage {
+    pub referenced_bytes: u64,
+    pub unique_bytes: u64,
+    pub snapshots: Vec<SnapshotUsage>,
+}
+
+impl DiskUsage {
+    pub fn write_json(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.write_object(w, "{")
+    }
+
+    // The same object, with `"type": "du"` first, see `json`.
+    pub fn write_ndjson(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.write_object(w, "{\"type\": \"du\", ")
+    }
+
+    fn write_object(&self, w: &mut dyn Write, open: &str) -> io::Result<()> {
+        write!(
+            w,
+            "{}\"referenced_bytes\": {}, \"unique_bytes\": {}, \"snapshots\": [",
+            open, self.referenced_bytes, self.unique_bytes
+        )?;
+        for (i, s) in self.snapshots.iter().enumerate() {
+            // Namespaces never need escaping, see `namespace`.
+            write!(
+                w,
+                "{}{{\"address\": \"{}\", \"namespace\": \"{}\", \"time\": {}, \
+                 \"referenced_bytes\": {}, \"unique_bytes\": {}}}",
+                if i == 0 { "" } else { ", " },
+                s.head.address.to_hex(),
+                s.head.namespace,
+                s.head.timestamp,
+                s.referenced_bytes,
+                s.unique_bytes
+            )?;
+        }
+        writeln!(w, "]}}")
+    }
+
+    // One row per snapshot and a total, sizes in human units.
+    pub fn write_table(&self, w: &mut dyn Write) -> io::Result<()> {
+        let mut rows = vec![["ADDRESS", "TIME", "NAMESPACE", "REFERENCED", "UNIQUE"]
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<String>>()];
+        for s in self.snapshots.iter() {
+            rows.push(vec![
+                s.head.address.to_hex(),
+                DateTime::from_unix(s.head.timestamp).to_rfc3339(),
+                s.head.namespace.to_string(),
+                human_bytes(s.referenced_bytes),
+                human_bytes(s.unique_bytes),
+            ]);
+        }
+        rows.push(vec![
+            "total".to_string(),
+            String::new(),
+            String::new(),
+            human_bytes(self.referenced_bytes),
+            human_bytes(self.unique_bytes),
+        ]);
+        let mut widths = vec![0; rows[0].len()];
+        for row in rows.iter() {
+            for (width, cell) in widths.iter_mut().zip(row.iter()) {
+                *width = cell.chars().count().max(*width);
+            }
+        }
+        for row in rows.iter() {
+            let mut line = String::new();
+            for (i, (width, cell)) in widths.iter().zip(row.iter()).enumerate() {
+                if i + 1 == row.len() {
+                    line.push_str(cell);
+                } else {
+                    line.push_str(&format!("{:<w$}  ", cell, w = *width));
+                }
+            }
+            writeln!(w, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+impl Repo {
+    pub fn du(&self, sk: &CryptoBoxSk, opts: &ListOptions) -> Result<DiskUsage, RepoError> {
+        let _lock = self.lock(LockMode::Shared)?;
+        let listings = self.list_snapshots(sk, opts)?;
+        let index: RepoIndex = self.load_index()?;
+        let heads = self.manifest()?.heads;
+        let graph = Graph::load(self, &index, sk, &heads)?;
+
+        // Count the snapshots reaching each object, all of them and the
+        // selected ones.
+        let mut reached_by: HashMap<Address, u32> = HashMap::new();
+        for h in heads.iter() {
+            for a in graph.reachable(&h.address) {
+                *reached_by.entry(a).or_insert(0) += 1;
+            }
+        }
+        let mut selected_by: HashMap<Address, u32> = HashMap::new();
+        let mut usage = DiskUsage::default();
+        for l in listings.into_iter() {
+            let reached = graph.reachable(&l.head.address);
+            let mut s = SnapshotUsage {
+                head: l.head,
+                referenced_bytes: 0,
+                unique_bytes: 0,
+            };
+            for a in reached.iter() {
+                let size = graph.plain_size(a);
+                s.referenced_bytes += size;
+                if reached_by[a] == 1 {
+                    s.unique_bytes += size;
+                }
+                *selected_by.entry(*a).or_insert(0) += 1;
+            }
+            usage.snapshots.push(s);
+        }
+        for (a, n) in selected_by.iter() {
+            let size = graph.plain_size(a);
+            usage.referenced_bytes += size;
+            if reached_by[a] == *n {
+                usage.unique_bytes += size;
+            }
+        }
+        Ok(usage)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_du() {
+    use super::gc::test_commit_tree;
+    let (r, key) = super::test_repo();
+    assert_eq!(
+        r.du(&key.box_sk, &Default::default()).unwrap(),
+        Default::default()
+    );
+
+    // Chunks hold their own 32 byte address, see `test_commit_tree`.
+    let chunk = |b: u8| Address { bytes: [b; 32] };
+    let (all, pair) = (chunk(100), chunk(101));
+    let h1 = test_commit_tree(&r, &key, 1, &[all, pair, pair, chunk(102)]);
+    let h2 = test_commit_tree(&r, &key, 2, &[all, pair]);
+    let h3 = test_commit_tree(&r, &key, 3, &[all, chunk(103)]);
+    let usage = r.du(&key.box_sk, &Default::default()).unwrap();
+    let heads: Vec<&SnapshotHead> = usage.snapshots.iter().map(|s| &s.head).collect();
+    assert_eq!(heads, vec![&h1, &h2, &h3]);
+
+    // Each snapshot reaches its own object and tree, whose sizes vary
+    // with the reference count, and its chunks.
+    let graph_size = |s: &SnapshotUsage, chunks: u64| s.referenced_bytes - 32 * chunks;
+    let s = &usage.snapshots;
+    assert_eq!(s[0].unique_bytes, graph_size(&s[0], 3) + 32);
+    assert_eq!(s[1].unique_bytes, graph_size(&s[1], 2));
+    assert_eq!(s[2].unique_bytes, graph_size(&s[2], 2) + 32);
+    let unique: u64 = s.iter().map(|s| s.unique_bytes).sum();
+    assert_eq!(usage.unique_bytes, unique + 2 * 32);
+    assert_eq!(usage.unique_bytes, usage.referenced_bytes);
+
+    // Without the third snapshot the first two still share a chunk only
+    // they reach, but not the one all three do.
+    let first_two = super::list::ListOptions {
+        before: Some(3),
+        ..Default::default()
+    };
+    let usage = r.du(&key.box_sk, &first_two).unwrap();
+    assert_eq!(usage.snapshots.len(), 2);
+    let s = &usage.snapshots;
+    assert_eq!(
+        usage.unique_bytes,
+        s[0].unique_bytes + s[1].unique_bytes + 32
+    );
+    assert_eq!(usage.referenced_bytes, usage.unique_bytes + 32);
+
+    let mut json = Vec::new();
+    usage.write_json(&mut json).unwrap();
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.starts_with(&format!(
+        "{{\"referenced_bytes\": {}, \"unique_bytes\": {}, \"snapshots\": [{{\"address\": \"{}\"",
+        usage.referenced_bytes,
+        usage.unique_bytes,
+        h1.address.to_hex()
+    )));
+    let mut ndjson = Vec::new();
+    usage.write_ndjson(&mut ndjson).unwrap();
+    assert_eq!(
+        String::from_utf8(ndjson).unwrap(),
+        format!("{{\"type\": \"du\", {}", &json[1..])
+    );
+    let mut table = Vec::new();
+    usage.write_table(&mut table).unwrap();
+    let table = String::from_utf8(table).unwrap();
+    assert_eq!(table.lines().count(), 4);
+    assert!(table.lines().last().unwrap().starts_with("total  "));
+}
@@ -0,0 +1,454 @@
+//! Leaving paths out of a backup.
+//!
+//! `ExcludeOptions` holds the rules `Repo::backup` applies as it walks,
+//! see `backup`. A rule is a glob pattern that excludes the paths it
+//! matches, or includes them again if it is an include rule:
+//!
+//! - `*` matches any run of bytes but `/`, `?` any one byte but `/`,
+//!   `[a-z]` and `[!a-z]` one byte in or not in a set, and `\` makes the
+//!   next byte literal.
+//! - `**` as a whole name matches any number of names.
+//! - A pattern without a `/` matches a name at any depth. One with a `/`
+//!   other than at its end is anchored and matches paths from the backup
+//!   root, or from the directory of the ignore file holding it.
+//! - A pattern ending in `/` only matches directories.
+//!
+//! Precedence: rules are checked in order and the last one matching a
+//! path decides, a path no rule matches is included. The rules of the
+//! options come first, in the order given, then those of any ignore files
+//! from the root down, so the ignore file nearest a path wins. An excluded
+//! directory is not walked at all, so nothing below it can be included
+//! again.
+//!
+//! With `ignore_files`, a directory holding a `.packnbackignore` file adds
+//! its rules for everything below that directory. Rule files, ignore files
+//! and those given with `read_rules`, hold one rule per line:
+//!
+//! ```text
+//! # A comment, blank lines are skipped too.
+//! *.o
+//! build/
+//! !keep.o
+//! \!literal-bang
+//! ```
+//!
+//! Lines starting with `!` are include rules, trailing whitespace is
+//! dropped. With `exclude_caches`, a directory holding a `CACHEDIR.TAG`
+//! file with the standard signature is backed up holding only that tag,
+//! see https://bford.info/cachedir/.
+//!
+//! `list_excluded` walks a tree with the same rules without backing it
+//! up, to see what a backup would leave out.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+pub const IGNORE_FILE: &str = ".packnbackignore";
+pub const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+const CACHEDIR_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Pattern {
+    anchored: bool,
+    dir_only: bool,
+    names: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Rule {
+    pub pattern: Pattern,
+    pub include: bool,
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct ExcludeOptions {
+    // In order of precedence, the last matching rule decides.
+    pub rules: Vec<Rule>,
+    pub ignore_files: bool,
+    pub exclude_caches: bool,
+}
+
+// The length of the set at the start of `p`, and whether `c` is in it,
+// None if the set is not closed.
+fn class(p: &[u8], c: u8) -> Option<(usize, bool)> {
+    let mut i = 1;
+    let negate = matches!(p.get(i), Some(b'!') | Some(b'^'));
+    if negate {
+        i += 1;
+    }
+    let mut found = false;
+    let mut first = true;
+    loop {
+        let lo = *p.get(i)?;
+        if lo == b']' && !first {
+            return Some((i + 1, found != negate));
+        }
+        first = false;
+        if p.get(i + 1) == Some(&b'-') && p.get(i + 2).is_some_and(|hi| *hi != b']') {
+            found |= lo <= c && c <= p[i + 2];
+            i += 3;
+        } else {
+            found |= lo == c;
+            i += 1;
+        }
+    }
+}
+
+// Whether the glob `p` matches all of `name`.
+fn glob(p: &[u8], name: &[u8]) -> bool {
+    let (mut pi, mut ni) = (0, 0);
+    // Where to resume after the last `*` if the rest fails to match.
+    let mut star = None;
+    while ni < name.len() {
+        let c = name[ni];
+        let step = match p.get(pi) {
+            Some(b'*') => {
+                star = Some((pi, ni));
+                pi += 1;
+                continue;
+            }
+            Some(b'?') => Some(1),
+            Some(b'[') => match class(&p[pi..], c) {
+                Some((n, true)) => Some(n),
+                Some((_, false)) => None,
+                None if c == b'[' => Some(1),
+                None => None,
+            },
+            Some(b'\\') if p.get(pi + 1) == Some(&c) => Some(2),
+            Some(b'\\') if pi + 1 == p.len() && c == b'\\' => Some(1),
+            Some(b'\\') => None,
+            Some(b) if *b == c => Some(1),
+            _ => None,
+        };
+        match (step, star) {
+            (Some(n), _) => {
+                pi += n;
+                ni += 1;
+            }
+            (None, Some((sp, sn))) => {
+                pi = sp + 1;
+                ni = sn + 1;
+                star = Some((sp, sn + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    p[pi..].iter().all(|b| *b == b'*')
+}
+
+fn match_names(pattern: &[Vec<u8>], names: &[Vec<u8>]) -> bool {
+    match pattern.split_first() {
+        None => names.is_empty(),
+        Some((p, rest)) if p == b"**" => (0..=names.len()).any(|i| match_names(rest, &names[i..])),
+        Some((p, rest)) => {
+            !names.is_empty() && glob(p, &names[0]) && match_names(rest, &names[1..])
+        }
+    }
+}
+
+impl Pattern {
+    pub fn new(glob: &[u8]) -> Pattern {
+        let mut glob = glob;
+        let dir_only = glob.ends_with(b"/");
+        while glob.ends_with(b"/") {
+            glob = &glob[..glob.len() - 1];
+        }
+        Pattern {
+            anchored: glob.contains(&b'/'),
+            dir_only,
+            names: glob
+                .split(|b| *b == b'/')
+                .filter(|n| !n.is_empty())
+                .map(|n| n.to_vec())
+                .collect(),
+        }
+    }
+
+    // Whether the pattern matches the path made of `names`, relative to
+    // where the pattern applies.
+    pub fn matches(&self, names: &[Vec<u8>], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            return match_names(&self.names, names);
+        }
+        match (self.names.first(), names.last()) {
+            (Some(p), Some(name)) => glob(p, name),
+            _ => false,
+        }
+    }
+}
+
+impl Rule {
+    pub fn exclude(glob: &str) -> Rule {
+        Rule {
+            pattern: Pattern::new(glob.as_bytes()),
+            include: false,
+        }
+    }
+
+    pub fn include(glob: &str) -> Rule {
+        Rule {
+            pattern: Pattern::new(glob.as_bytes()),
+            include: true,
+        }
+    }
+}
+
+// The rules in the text of a rule file.
+pub fn parse_rules(text: &[u8]) -> Vec<Rule> {
+    let mut rules = Vec::new();
+    for line in text.split(|b| *b == b'\n') {
+        let end = line
+            .iter()
+            .rposition(|b| !b.is_ascii_whitespace())
+            .map_or(0, |i| i + 1);
+        let line = &line[..end];
+        if line.is_empty() || line[0] == b'#' {
+            continue;
+        }
+        let (include, glob) = match line {
+            [b'!', glob @ ..] => (true, glob),
+            [b'\\', b'!', ..] | [b'\\', b'#', ..] => (false, &line[1..]),
+            glob => (false, glob),
+        };
+        rules.push(Rule {
+            pattern: Pattern::new(glob),
+            include,
+        });
+    }
+    rules
+}
+
+pub fn read_rules(path: &Path) -> io::Result<Vec<Rule>> {
+    Ok(parse_rules(&fs::read(path)?))
+}
+
+fn is_cache_dir(path: &Path) -> bool {
+    let mut buf = [0; 43];
+    fs::File::open(path.join(CACHEDIR_TAG))
+        .and_then(|mut f| f.read_exact(&mut buf))
+        .is_ok()
+        && buf[..] == CACHEDIR_SIGNATURE[..]
+}
+
+struct Frame {
+    // Names below the root of the directory.
+    depth: usize,
+    // From its ignore file.
+    rules: Vec<Rule>,
+    cache: bool,
+}
+
+// The rules in effect at one point of a walk.
+pub struct Filter<'a> {
+    opts: &'a ExcludeOptions,
+    // One frame per directory entered.
+    stack: Vec<Frame>,
+}
+
+impl<'a> Filter<'a> {
+    pub fn new(opts: &'a ExcludeOptions) -> Filter<'a> {
+        Filter {
+            opts,
+            stack: Vec::new(),
+        }
+    }
+
+    // Enter the directory at `path`, `depth` names below the root. Must
+    // be paired with `leave`, even if its ignore file cannot be read.
+    pub fn enter(&mut self, path: &Path, depth: usize) -> io::Result<()> {
+        self.stack.push(Frame {
+            depth,
+            rules: Vec::new(),
+            cache: self.opts.exclude_caches && is_cache_dir(path),
+        });
+        if self.opts.ignore_files {
+            match read_rules(&path.join(IGNORE_FILE)) {
+                Ok(rules) => self.stack.last_mut().unwrap().rules = rules,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn leave(&mut self) {
+        self.stack.pop();
+    }
+
+    // Whether the path made of `names`, from the root, is excluded. Its
+    // directory must be the one last entered.
+    pub fn excludes(&self, names: &[Vec<u8>], is_dir: bool) -> bool {
+        if self.stack.last().is_some_and(|f| f.cache)
+            && names.last().is_some_and(|n| n != CACHEDIR_TAG.as_bytes())
+        {
+            return true;
+        }
+        let nearest = self.stack.iter().rev().find_map(|f| {
+            let rel = &names[f.depth..];
+            f.rules
+                .iter()
+                .rev()
+                .find(|r| r.pattern.matches(rel, is_dir))
+        });
+        let decider = nearest.or_else(|| {
+            let rules = self.opts.rules.iter().rev();
+            rules.into_iter().find(|r| r.pattern.matches(names, is_dir))
+        });
+        decider.is_some_and(|r| !r.include)
+    }
+}
+
+fn list_dir(
+    filter: &mut Filter,
+    path: &Path,
+    names: &mut Vec<Vec<u8>>,
+    excluded: &mut Vec<PathBuf>,
+) {
+    let mut listed: Vec<OsString> = match fs::read_dir(path) {
+        Ok(ents) => ents.filter_map(|e| Some(e.ok()?.file_name())).collect(),
+        Err(_) => Vec::new(),
+    };
+    listed.sort();
+    let _ = filter.enter(path, names.len());
+    for name in listed {
+        let child = path.join(&name);
+        let is_dir = fs::symlink_metadata(&child).is_ok_and(|m| m.is_dir());
+        names.push(name.as_bytes().to_vec());
+        if filter.excludes(names, is_dir) {
+            excluded.push(child);
+        } else if is_dir {
+            list_dir(filter, &child, names, excluded);
+        }
+        names.pop();
+    }
+    filter.leave();
+}
+
+// The paths below `root` a backup with `opts` would leave out, in walk
+// order. Those in excluded directories are not listed. Anything that
+// cannot be read is skipped.
+pub fn list_excluded(root: &Path, opts: &ExcludeOptions) -> Vec<PathBuf> {
+    let mut excluded = Vec::new();
+    list_dir(&mut Filter::new(opts), root, &mut Vec::new(), &mut excluded);
+    excluded
+}
+
+// Tests --------------------
+
+#[test]
+fn test_glob() {
+    for (p, name) in [
+        ("*.o", "main.o"),
+        ("*", ""),
+        ("a*b*c", "abxbc"),
+        ("?at", "cat"),
+        ("[a-c]x", "bx"),
+        ("[!a-c]x", "dx"),
+        ("[]]", "]"),
+        ("[", "["),
+        ("\\*", "*"),
+        ("**", "anything"),
+    ]
+    .iter()
+    {
+        assert!(glob(p.as_bytes(), name.as_bytes()), "{} {}", p, name);
+    }
+    for (p, name) in [
+        ("*.o", "main.c"),
+        ("a*b*c", "abxbd"),
+        ("?at", "at"),
+        ("[a-c]x", "dx"),
+        ("[!a-c]x", "ax"),
+        ("\\*", "a"),
+        ("a", "ab"),
+    ]
+    .iter()
+    {
+        assert!(!glob(p.as_bytes(), name.as_bytes()), "{} {}", p, name);
+    }
+
+    let names = |p: &str| -> Vec<Vec<u8>> { p.split('/').map(|n| n.as_bytes().to_vec()).collect() };
+    let matches =
+        |p: &str, path: &str, is_dir| Pattern::new(p.as_bytes()).matches(&names(path), is_dir);
+    assert!(matches("*.o", "src/deep/x.o", false));
+    assert!(matches("/src/*.o", "src/x.o", false));
+    assert!(!matches("src/*.o", "src/deep/x.o", false));
+    assert!(matches("src/**/*.o", "src/deep/er/x.o", false));
+    assert!(matches("src/**/*.o", "src/x.o", false));
+    assert!(matches("**/target/", "a/b/target", true));
+    assert!(!matches("target/", "target", false));
+}
+
+#[test]
+fn test_exclude() {
+    let dir = super::storage::local::test_dir("exclude");
+    for d in ["src/build", "cache/data", "keep/build", "logs"].iter() {
+        fs::create_dir_all(dir.join(d)).unwrap();
+    }
+    for f in [
+        "src/main.o",
+        "src/main.c",
+        "src/build/out",
+        "keep/build/out",
+        "keep/x.log",
+        "logs/a.log",
+        "cache/data/blob",
+        "top.log",
+    ]
+    .iter()
+    {
+        fs::write(dir.join(f), b"x").unwrap();
+    }
+    let mut tag = CACHEDIR_SIGNATURE.to_vec();
+    tag.extend_from_slice(b"\n# A cache.\n");
+    fs::write(dir.join("cache").join(CACHEDIR_TAG), &tag).unwrap();
+    fs::write(
+        dir.join("keep").join(IGNORE_FILE),
+        b"# Keep it all.\n!build/\n!*.log  \n",
+    )
+    .unwrap();
+
+    let rel = |paths: Vec<PathBuf>| -> Vec<String> {
+        paths
+            .iter()
+            .map(|p| p.strip_prefix(&dir).unwrap().to_string_lossy().into_owned())
+            .collect()
+    };
+    let mut opts = ExcludeOptions {
+        rules: parse_rules(b"*.o\nbuild/\n*.log\n!/logs/a.log\n"),
+        ..Default::default()
+    };
+    assert_eq!(
+        rel(list_excluded(&dir, &opts)),
+        vec![
+            "keep/build",
+            "keep/x.log",
+            "src/build",
+            "src/main.o",
+            "top.log"
+        ]
+    );
+    opts.ignore_files = true;
+    opts.exclude_caches = true;
+    assert_eq!(
+        rel(list_excluded(&dir, &opts)),
+        vec!["cache/data", "src/build", "src/main.o", "top.log"]
+    );
+    // The last matching rule wins.
+    opts.rules.push(Rule::exclude("logs/"));
+    assert_eq!(
+        rel(list_excluded(&dir, &opts)),
+        vec!["cache/data", "logs", "src/build", "src/main.o", "top.log"]
+    );
+    assert_eq!(
+        parse_rules(b"\\!bang\n\\#hash\n#comment\n\n"),
+        vec![Rule::exclude("!bang"), Rule::exclude("#hash")]
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,303 @@
+//! Finding paths across snapshots.
+//!
+//! `Repo::find` walks the trees of the snapshots a `ListOptions` selects,
+//! see `list`, and reports every path a glob pattern matches together with
+//! the snapshots holding it, to answer which backups still have a file.
+//! Patterns are those of `exclude`: one without a `/` matches a name at
+//! any depth, one with a `/` is anchored at the snapshot root and one
+//! ending in `/` only matches directories.
+//!
+//! Only tree and snapshot objects are read, which needs the box secret key
+//! but not the address key, and chunks are never fetched. Snapshots share
+//! most of their trees, so the matches below a tree are remembered by its
+//! address and where it sits, and each such subtree is walked once however
+//! many snapshots hold it. Snapshots whose object cannot be read are
+//! skipped.
+//!
+//! `write_ndjson` emits one record per path, the snapshots oldest first,
+//! see `json`:
+//!
+//! ```text
+//! {"type": "found", "path": s, "entry_kind": "file"|"dir"|"symlink",
+//!  "snapshots": [{"address": hex, "namespace": s, "time": n, "size": n,
+//!                 "mtime": n}, ...]}
+//! ```
+//!
+//! A path that is a file in one snapshot and a directory in another is
+//! reported once for each kind.
+
+use super::address::Address;
+use super::datetime::DateTime;
+use super::exclude::Pattern;
+use super::index::RepoIndex;
+use super::json::{json_path, text_path};
+use super::list::ListOptions;
+use super::manifest::SnapshotHead;
+use super::tree::EntryKind;
+use super::{Repo, RepoError};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::rc::Rc;
+use tweetnacl::CryptoBoxSk;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FoundIn {
+    pub head: SnapshotHead,
+    // File size, 0 for anything else.
+    pub size: u64,
+    pub mtime: u64,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Found {
+    pub path: Vec<u8>,
+    pub entry_kind: EntryKind,
+    // Oldest first.
+    pub snapshots: Vec<FoundIn>,
+}
+
+// A match below a tree, its path relative to the snapshot root.
+#[derive(Clone)]
+struct Hit {
+    path: Vec<u8>,
+    kind: EntryKind,
+    size: u64,
+    mtime: u64,
+}
+
+// A tree by address and the names leading to it.
+type TreeAt = (Address, Vec<Vec<u8>>);
+
+struct Finder<'a> {
+    repo: &'a Repo,
+    index: RepoIndex,
+    sk: &'a CryptoBoxSk,
+    pattern: &'a Pattern,
+    walked: HashMap<TreeAt, Rc<Vec<Hit>>>,
+}
+
+impl<'a> Finder<'a> {
+    // The matches below the tree at `address`, found at `names`.
+    fn walk(&mut self, address: &Address, names: &[Vec<u8>]) -> Result<Rc<Vec<Hit>>, RepoError> {
+        let key = (*address, names.to_vec());
+        if let Some(hits) = self.walked.get(&key) {
+            return Ok(hits.clone());
+        }
+        let tree = self.repo.read_tree(&self.index, self.sk, address)?;
+        let mut hits = Vec::new();
+        let mut names = names.to_vec();
+        for ent in tree.entries.iter() {
+            names.push(ent.name.clone());
+            let is_dir = ent.kind == EntryKind::Dir;
+            if self.pattern.matches(&names, is_dir) {
+                hits.push(Hit {
+                    path: names.join(&b'/'),
+                    kind: ent.kind,
+                    size: super::diff::file_size(ent),
+                    mtime: ent.mtime,
+                });
+            }
+            if is_dir {
+                hits.extend(self.walk(&ent.refs[0], &names)?.iter().cloned());
+            }
+            names.pop();
+        }
+        let hits = Rc::new(hits);
+        self.walked.insert(key, hits.clone());
+        Ok(hits)
+    }
+}
+
+pub fn write_ndjson(w: &mut dyn Write, found: &[Found]) -> io::Result<()> {
+    for f in found.iter() {
+        let snapshots: Vec<String> = f
+            .snapshots
+            .iter()
+            .map(|s| {
+                format!(
+                    "{{\"address\": \"{}\", \"namespace\": \"{}\", \"time\": {}, \
+                     \"size\": {}, \"mtime\": {}}}",
+                    s.head.address.to_hex(),
+                    s.head.namespace,
+                    s.head.timestamp,
+                    s.size,
+                    s.mtime
+                )
+            })
+            .collect();
+        writeln!(
+            w,
+            "{{\"type\": \"found\", \"path\": {}, \"entry_kind\": \"{}\", \"snapshots\": [{}]}}",
+            json_path(&f.path),
+            f.entry_kind.as_str(),
+            snapshots.join(", ")
+        )?;
+    }
+    Ok(())
+}
+
+// Each path, with a `/` after directories, then one indented line per
+// snapshot holding it.
+pub fn write_found(w: &mut dyn Write, found: &[Found]) -> io::Result<()> {
+    for f in found.iter() {
+        let slash = if f.entry_kind == EntryKind::Dir {
+            "/"
+        } else {
+            ""
+        };
+        writeln!(w, "{}{}", text_path(&f.path), slash)?;
+        for s in f.snapshots.iter() {
+            writeln!(
+                w,
+                "  {}  {}  {}  {}",
+                DateTime::from_unix(s.head.timestamp).to_rfc3339(),
+                s.head.namespace,
+                s.head.address.to_hex(),
+                s.size
+            )?;
+        }
+    }
+    Ok(())
+}
+
+impl Repo {
+    // The paths `pattern` matches in the snapshots `opts` selects, in
+    // path order.
+    pub fn find(
+        &self,
+        pattern: &Pattern,
+        sk: &CryptoBoxSk,
+        opts: &ListOptions,
+    ) -> Result<Vec<Found>, RepoError> {
+        let mut listings = self.list_snapshots(sk, opts)?;
+        listings.sort_by_key(|l| l.head.timestamp);
+        let mut f = Finder {
+            repo: self,
+            index: self.load_index()?,
+            sk,
+            pattern,
+            walked: HashMap::new(),
+        };
+        let mut found: BTreeMap<(Vec<u8>, u8), Found> = BTreeMap::new();
+        for l in listings.into_iter() {
+            let root = match l.snapshot {
+                Some(ref s) => s.root,
+                None => continue,
+            };
+            for hit in f.walk(&root, &[])?.iter() {
+                let key = (hit.path.clone(), hit.kind as u8);
+                found
+                    .entry(key)
+                    .or_insert_with(|| Found {
+                        path: hit.path.clone(),
+                        entry_kind: hit.kind,
+                        snapshots: Vec::new(),
+                    })
+                    .snapshots
+                    .push(FoundIn {
+                        head: l.head.clone(),
+                        size: hit.size,
+                        mtime: hit.mtime,
+                    });
+            }
+        }
+        Ok(found.into_values().collect())
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_find() {
+    use super::address::AddressKey;
+    use super::manifest::Manifest;
+    use super::namespace::Namespace;
+    use std::fs;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let laptop = Namespace::new("laptop").unwrap();
+    let server = Namespace::new("server").unwrap();
+    let dir = super::storage::local::test_dir("find");
+    fs::create_dir_all(dir.join("docs/old")).unwrap();
+    fs::write(dir.join("docs/report.txt"), b"first").unwrap();
+    fs::write(dir.join("docs/old/report.txt"), b"older").unwrap();
+    fs::write(dir.join("notes"), b"notes").unwrap();
+    let (a, _) = r
+        .backup(&dir, &laptop, &ak, &key, &Default::default())
+        .unwrap();
+    fs::remove_file(dir.join("docs/report.txt")).unwrap();
+    fs::write(dir.join("report.txt"), b"moved up").unwrap();
+    let (mut b, _) = r
+        .backup(&dir, &laptop, &ak, &key, &Default::default())
+        .unwrap();
+    if b.timestamp == a.timestamp {
+        // Two backups within a second, order them.
+        let mut m: Manifest = r.manifest().unwrap();
+        m.remove_head(&b.address);
+        b.timestamp += 1;
+        m.add_head(b.clone());
+        r.commit_manifest(&m, &key).unwrap();
+    }
+    let (c, _) = r
+        .backup(&dir, &server, &ak, &key, &Default::default())
+        .unwrap();
+
+    let find = |glob: &str, opts: &ListOptions| -> Vec<(String, Vec<SnapshotHead>)> {
+        r.find(&Pattern::new(glob.as_bytes()), &key.box_sk, opts)
+            .unwrap()
+            .into_iter()
+            .map(|f| {
+                let path = String::from_utf8(f.path).unwrap();
+                (path, f.snapshots.into_iter().map(|s| s.head).collect())
+            })
+            .collect()
+    };
+    let laptop_only = ListOptions {
+        namespace: Some(laptop.clone()),
+        ..Default::default()
+    };
+    assert_eq!(
+        find("*.txt", &laptop_only),
+        vec![
+            (
+                "docs/old/report.txt".to_string(),
+                vec![a.clone(), b.clone()]
+            ),
+            ("docs/report.txt".to_string(), vec![a.clone()]),
+            ("report.txt".to_string(), vec![b.clone()]),
+        ]
+    );
+    assert_eq!(
+        find("docs/*.txt", &Default::default()),
+        vec![("docs/report.txt".to_string(), vec![a.clone()])]
+    );
+    // b and c may share a second, in either order.
+    let top = find("/report.txt", &Default::default());
+    assert_eq!((top.len(), &top[0].0[..]), (1, "report.txt"));
+    assert_eq!(top[0].1.len(), 2);
+    assert!(top[0].1.contains(&b) && top[0].1.contains(&c));
+    assert_eq!(
+        find("old/", &laptop_only),
+        vec![("docs/old".to_string(), vec![a.clone(), b.clone()])]
+    );
+    assert!(find("missing", &Default::default()).is_empty());
+
+    let found = r
+        .find(&Pattern::new(b"report.txt"), &key.box_sk, &laptop_only)
+        .unwrap();
+    assert_eq!(found[1].snapshots[0].size, 5);
+    let mut out = Vec::new();
+    write_ndjson(&mut out, &found).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(out.lines().count(), 3);
+    assert!(out.starts_with(
+        "{\"type\": \"found\", \"path\": \"docs/old/report.txt\", \"entry_kind\": \"file\", \
+         \"snapshots\": [{\"address\": "
+    ));
+    let mut out = Vec::new();
+    write_found(&mut out, &found).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(out.lines().count(), 7);
+    assert!(out.starts_with("docs/old/report.txt\n  "));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,432 @@
+//! Repository consistency checks.
+//!
+//! `fsck` answers whether every snapshot in the manifest can be restored.
+//! Without the repository box key it can check the config, manifest and
+//! policy signatures, the pack index checksums, that every indexed pack
+//! exists with the right size, and that the snapshot heads are indexed.
+//! With the key it also compares each index against the encrypted table
+//! of contents of its pack and walks every tree, and with `read_data` it
+//! decrypts every reachable chunk, which authenticates it. `read_data`
+//! also checks packs stored with parity block by block, and `repair`
+//! rebuilds damaged packs from their parity before anything else reads
+//! them, see `parity`.
+//!
+//! Snapshot objects are not signed themselves. They are named by the
+//! signed manifest and authenticated by decryption, like every object.
+//!
+//! Problems are recorded in the report rather than returned as errors, so
+//! one damaged pack does not hide the state of the rest. The report has
+//! one line per checked item:
+//!
+//! ```text
+//! verdict TAB subject TAB message
+//! ```
+//!
+//! `verdict` is `ok`, `warning` or `error`. `subject` is a storage key,
+//! or `object:<hex address>` for objects, or `object:*` for all of them.
+//! Tabs and newlines in messages are replaced by spaces.
+//!
+//! `write_ndjson` emits the same as one record per item, see `json`:
+//!
+//! ```text
+//! {"type": "check", "verdict": s, "subject": s, "message": s}
+//! ```
+
+use super::address::Address;
+use super::index::{PackIndex, RepoIndex};
+use super::json::{json_str, Summary};
+use super::lock::LockMode;
+use super::object::decode_refs;
+use super::pack::PackId;
+use super::parity::parity_key;
+use super::{Repo, RepoError, CONFIG_FILE, INDEXES_DIR, MANIFEST_FILE, PACKS_DIR, POLICY_FILE};
+use std::collections::HashSet;
+use std::io::{self, Write};
+use tweetnacl::CryptoBoxSk;
+
+#[derive(Clone, Default, Debug)]
+pub struct FsckOptions {
+    // Decrypt every reachable chunk, needs the repository key.
+    pub read_data: bool,
+    // Rewrite packs that their parity can repair.
+    pub repair: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Verdict {
+    Ok,
+    Warning,
+    Error,
+}
+
+impl Verdict {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Verdict::Ok => "ok",
+            Verdict::Warning => "warning",
+            Verdict::Error => "error",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FsckEntry {
+    pub verdict: Verdict,
+    pub subject: String,
+    pub message: String,
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct FsckReport {
+    pub entries: Vec<FsckEntry>,
+}
+
+impl FsckReport {
+    pub(crate) fn add(&mut self, verdict: Verdict, subject: &str, message: &str) {
+        self.entries.push(FsckEntry {
+            verdict,
+            subject: subject.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    pub(crate) fn check(&mut self, subject: &str, result: Result<(), RepoError>) {
+        match result {
+            Ok(()) => self.add(Verdict::Ok, subject, ""),
+            Err(err) => self.add(Verdict::Error, subject, &err.to_string()),
+        }
+    }
+
+    pub fn count(&self, verdict: Verdict) -> usize {
+        self.entries.iter().filter(|e| e.verdict == verdict).count()
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.count(Verdict::Error) == 0
+    }
+
+    pub fn write_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        let clean = |s: &str| s.replace(['\t', '\n', '\r'], " ");
+        for e in self.entries.iter() {
+            writeln!(
+                w,
+                "{}\t{}\t{}",
+                e.verdict.as_str(),
+                clean(&e.subject),
+                clean(&e.message)
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn write_ndjson(&self, w: &mut dyn Write) -> io::Result<()> {
+        for e in self.entries.iter() {
+            writeln!(
+                w,
+                "{{\"type\": \"check\", \"verdict\": \"{}\", \"subject\": {}, \"message\": {}}}",
+                e.verdict.as_str(),
+                json_str(&e.subject),
+                json_str(&e.message)
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn summary(&self) -> Summary {
+        Summary {
+            warnings: self.count(Verdict::Warning) as u64,
+            errors: self.count(Verdict::Error) as u64,
+            ..Summary::new("fsck")
+        }
+    }
+}
+
+pub(crate) fn object_subject(address: &Address) -> String {
+    format!("object:{}", address.to_hex())
+}
+
+impl Repo {
+    pub fn fsck(
+        &self,
+        sk: Option<&CryptoBoxSk>,
+        opts: &FsckOptions,
+    ) -> Result<FsckReport, RepoError> {
+        // Keep gc and repack from deleting things under us.
+        let _lock = self.lock(LockMode::Shared)?;
+        let mut report: FsckReport = Default::default();
+
+        // Repo::open already verified the config and policy.
+        report.add(Verdict::Ok, CONFIG_FILE, "");
+        if self.storage().exists(POLICY_FILE)? {
+            report.add(Verdict::Ok, POLICY_FILE, "");
+        }
+        let heads = match self.manifest() {
+            Ok(m) => {
+                report.add(Verdict::Ok, MANIFEST_FILE, "");
+                m.heads
+            }
+            Err(err) => {
+                report.add(Verdict::Error, MANIFEST_FILE, &err.to_string());
+                Vec::new()
+            }
+        };
+
+        let mut indexes: Vec<PackIndex> = Vec::new();
+        for id in self.list_pack_indexes()? {
+            let subject = format!("{}/{}", INDEXES_DIR, id.to_hex());
+            match self.read_pack_index(&id) {
+                Ok(idx) => {
+                    report.add(Verdict::Ok, &subject, "");
+                    indexes.push(idx);
+                }
+                Err(err) => report.add(Verdict::Error, &subject, &err.to_string()),
+            }
+        }
+        let indexed: HashSet<_> = indexes.iter().map(|idx| idx.pack_id).collect();
+        for idx in indexes.iter() {
+            let subject = format!("{}/{}", PACKS_DIR, idx.pack_id.to_hex());
+            if opts.read_data || opts.repair {
+                self.fsck_parity(&mut report, &idx.pack_id, opts);
+            }
+            let result = self.fsck_pack(idx, sk);
+            report.check(&subject, result);
+        }
+        for id in self.list_packs()? {
+            if !indexed.contains(&id) {
+                let subject = format!("{}/{}", PACKS_DIR, id.to_hex());
+                report.add(Verdict::Warning, &subject, "pack has no index");
+            }
+        }
+
+        let index = RepoIndex::build(indexes.iter());
+        let sk = match sk {
+            Some(sk) => sk,
+            None => {
+                for address in heads.iter().flat_map(|h| h.roots()) {
+                    let result = match index.lookup(&address) {
+                        Some(_) => Ok(()),
+                        None => Err(RepoError::MissingObjectError),
+                    };
+                    report.check(&object_subject(&address), result);
+                }
+                report.add(
+                    Verdict::Warning,
+                    "object:*",
+                    "trees and data not checked without the repository key",
+                );
+                return Ok(report);
+            }
+        };
+        let mut seen = HashSet::new();
+        let mut todo: Vec<Address> = heads.iter().flat_map(|h| h.roots()).collect();
+        while let Some(address) = todo.pop() {
+            if !seen.insert(address) {
+                continue;
+            }
+            let loc = match index.lookup(&address) {
+                Some(loc) => *loc,
+                None => {
+                    report.check(
+                        &object_subject(&address),
+                        Err(RepoError::MissingObjectError),
+                    );
+                    continue;
+                }
+            };
+            if !loc.kind.has_refs() && !opts.read_data {
+                report.add(Verdict::Ok, &object_subject(&address), "");
+                continue;
+            }
+            let result = self
+                .open_pack(&loc.pack_id, sk)
+                .and_then(|mut pack| pack.read(loc.offset, loc.length));
+            let result = result.and_then(|data| {
+                if loc.kind.has_refs() {
+                    todo.extend(decode_refs(&data)?);
+                }
+                Ok(())
+            });
+            report.check(&object_subject(&address), result);
+        }
+        Ok(report)
+    }
+
+    pub(crate) fn fsck_parity(&self, report: &mut FsckReport, id: &PackId, opts: &FsckOptions) {
+        let subject = format!("{}/{}", PACKS_DIR, id.to_hex());
+        let parity = match self.read_parity(id) {
+            Ok(Some(parity)) => parity,
+            Ok(None) => return,
+            Err(err) => return report.add(Verdict::Error, &parity_key(id), &err.to_string()),
+        };
+        report.add(Verdict::Ok, &parity_key(id), "");
+        let mut pack = match self.storage().get(&subject) {
+            Ok(pack) => pack,
+            Err(ref e) if e.is_not_found() => Vec::new(),
+            Err(err) => return report.add(Verdict::Error, &subject, &err.to_string()),
+        };
+        match parity.repair(&mut pack) {
+            Ok(0) => (),
+            Ok(_) if opts.repair => match self.repair_pack(id) {
+                Ok(n) => report.add(
+                    Verdict::Warning,
+                    &subject,
+                    &format!("repaired {} damaged blocks", n),
+                ),
+                Err(err) => report.add(Verdict::Error, &subject, &err.to_string()),
+            },
+            Ok(n) => report.add(
+                Verdict::Error,
+                &subject,
+                &format!("{} damaged blocks, repairable from parity", n),
+            ),
+            Err(err) => report.add(
+                Verdict::Error,
+                &subject,
+                &format!("damaged beyond repair: {}", err),
+            ),
+        }
+    }
+
+    pub(crate) fn fsck_pack(
+        &self,
+        idx: &PackIndex,
+        sk: Option<&CryptoBoxSk>,
+    ) -> Result<(), RepoError> {
+        let key = format!("{}/{}", PACKS_DIR, idx.pack_id.to_hex());
+        let size = self.storage().size(&key)?;
+        if size != idx.pack_size {
+            return Err(RepoError::StorageError(format!(
+                "pack is {} bytes, the index says {}",
+                size, idx.pack_size
+            )));
+        }
+        if let Some(sk) = sk {
+            let toc = self.open_pack(&idx.pack_id, sk)?.read_toc()?;
+            if PackIndex::new(idx.pack_id, size, toc).entries() != idx.entries() {
+                return Err(RepoError::StorageError(
+                    "index does not match the pack contents".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_fsck_healthy() {
+    use super::gc::test_commit_tree;
+    let (r, key) = super::test_repo();
+    let chunks = [Address { bytes: [1; 32] }, Address { bytes: [2; 32] }];
+    test_commit_tree(&r, &key, 1, &chunks);
+
+    let opts = FsckOptions {
+        read_data: true,
+        ..Default::default()
+    };
+    let report = r.fsck(Some(&key.box_sk), &opts).unwrap();
+    assert!(report.is_ok());
+    assert_eq!(report.count(Verdict::Warning), 0);
+    // config, manifest, index, pack and four objects.
+    assert_eq!(report.entries.len(), 8);
+    let mut out = Vec::new();
+    report.write_to(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with("ok\tconfig\t\n"));
+    assert_eq!(out.lines().count(), 8);
+    let mut out = Vec::new();
+    report.write_ndjson(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with(
+        "{\"type\": \"check\", \"verdict\": \"ok\", \"subject\": \"config\", \"message\": \"\"}\n"
+    ));
+    assert_eq!(out.lines().count(), 8);
+    assert_eq!(report.summary().exit_code(), 0);
+
+    let report = r.fsck(None, &opts).unwrap();
+    assert!(report.is_ok());
+    assert_eq!(report.count(Verdict::Warning), 1);
+    assert!(r.list_locks().unwrap().is_empty());
+}
+
+#[test]
+fn test_fsck_damage() {
+    use super::gc::test_commit_tree;
+    let (r, key) = super::test_repo();
+    let chunks = [Address { bytes: [1; 32] }, Address { bytes: [2; 32] }];
+    test_commit_tree(&r, &key, 1, &chunks);
+    let opts = FsckOptions {
+        read_data: true,
+        ..Default::default()
+    };
+
+    // Flip a byte inside the first chunk.
+    let id = r.list_packs().unwrap()[0];
+    let idx = r.read_pack_index(&id).unwrap();
+    let ent = idx.lookup(&chunks[0]).unwrap();
+    let pack_key = format!("{}/{}", PACKS_DIR, id.to_hex());
+    let mut pack = r.storage().get(&pack_key).unwrap();
+    pack[ent.offset as usize + 20] ^= 1;
+    r.storage().put(&pack_key, &pack).unwrap();
+    let report = r.fsck(Some(&key.box_sk), &opts).unwrap();
+    assert_eq!(report.count(Verdict::Error), 1);
+    let bad = report
+        .entries
+        .iter()
+        .find(|e| e.verdict == Verdict::Error)
+        .unwrap();
+    assert_eq!(bad.subject, object_subject(&chunks[0]));
+    // Without reading data the damage goes unnoticed.
+    assert!(r
+        .fsck(Some(&key.box_sk), &Default::default())
+        .unwrap()
+        .is_ok());
+
+    // A truncated pack and then a lost index.
+    r.storage().put(&pack_key, &pack[..10]).unwrap();
+    let report = r.fsck(None, &opts).unwrap();
+    assert_eq!(report.count(Verdict::Error), 1);
+    r.storage()
+        .delete(&format!("{}/{}", INDEXES_DIR, id.to_hex()))
+        .unwrap();
+    let report = r.fsck(Some(&key.box_sk), &opts).unwrap();
+    assert_eq!(report.count(Verdict::Warning), 1);
+    assert_eq!(report.count(Verdict::Error), 1);
+    assert!(!report.is_ok());
+    assert_eq!(report.summary().exit_code(), 1);
+}
+
+#[test]
+fn test_fsck_repair() {
+    let (r, key) = super::test_repo();
+    let opts = super::pack::PackerOptions {
+        parity: Some(Default::default()),
+        ..Default::default()
+    };
+    let mut packer = r.packer(opts);
+    let address = Address { bytes: [3; 32] };
+    packer
+        .add(&address, super::object::ObjectKind::Chunk, &[9; 5000])
+        .unwrap();
+    let p = packer.finish().unwrap().pop().unwrap();
+    r.write_pack_index(&PackIndex::from_finished(&p)).unwrap();
+
+    let pack_key = format!("{}/{}", PACKS_DIR, p.id.to_hex());
+    let mut pack = r.storage().get(&pack_key).unwrap();
+    pack[p.entries[0].offset as usize + 20] ^= 1;
+    r.storage().put(&pack_key, &pack).unwrap();
+
+    let mut opts = FsckOptions {
+        read_data: true,
+        ..Default::default()
+    };
+    let report = r.fsck(Some(&key.box_sk), &opts).unwrap();
+    assert_eq!(report.count(Verdict::Error), 1);
+    opts.repair = true;
+    let report = r.fsck(Some(&key.box_sk), &opts).unwrap();
+    assert!(report.is_ok());
+    assert_eq!(report.count(Verdict::Warning), 1);
+    let report = r.fsck(Some(&key.box_sk), &opts).unwrap();
+    assert_eq!(report.count(Verdict::Warning), 0);
+}
@@ -0,0 +1,748 @@
+//! Mounting snapshots with FUSE.
+//!
+//! `mount` serves a `SnapshotFs` to the kernel until the filesystem is
+//! unmounted, with `unmount`, `umount` or `fusermount3 -u`. It speaks the
+//! kernel protocol on `/dev/fuse` directly, so there is no dependency on
+//! libfuse. Root mounts with mount(2), anyone else through the setuid
+//! `fusermount3` from the fuse3 package, which opens `/dev/fuse`, mounts
+//! it and passes the open device back over a socket named by the
+//! `_FUSE_COMMFD` variable. Requests are served one at a time, the kernel
+//! reads ahead and chunks are cached, see `snapfs`.
+//!
+//! Only what a read only filesystem needs is implemented: lookup,
+//! getattr, readlink, open, read, readdir, statfs and the matching
+//! releases. Anything else is answered with ENOSYS, which the kernel
+//! remembers for most operations. Snapshots never change, so attributes
+//! and entries are cached by the kernel for `TTL` seconds and file data
+//! for as long as it likes.
+//!
+//! Messages are native endian, see the kernel's `fuse.h`:
+//!
+//! ```text
+//! request: u32:len u32:opcode u64:unique u64:nodeid u32:uid u32:gid
+//!          u32:pid u16:total_extlen u16:padding body
+//! reply:   u32:len i32:-errno u64:unique body
+//! ```
+
+use super::snapfs::{FileAttr, SnapshotFs};
+use super::tree::EntryKind;
+use super::RepoError;
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::raw::{c_char, c_int, c_ulong, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+
+// Seconds the kernel may cache entries and attributes.
+pub const TTL: u64 = 3600;
+
+const KERNEL_VERSION: u32 = 7;
+const KERNEL_MINOR_VERSION: u32 = 31;
+
+const LOOKUP: u32 = 1;
+const FORGET: u32 = 2;
+const GETATTR: u32 = 3;
+const READLINK: u32 = 5;
+const OPEN: u32 = 14;
+const READ: u32 = 15;
+const STATFS: u32 = 17;
+const RELEASE: u32 = 18;
+const FLUSH: u32 = 25;
+const INIT: u32 = 26;
+const OPENDIR: u32 = 27;
+const READDIR: u32 = 28;
+const RELEASEDIR: u32 = 29;
+const INTERRUPT: u32 = 36;
+const DESTROY: u32 = 38;
+const BATCH_FORGET: u32 = 42;
+
+const ENOENT: i32 = 2;
+const EIO: i32 = 5;
+const ENODEV: i32 = 19;
+const ENOTDIR: i32 = 20;
+const EISDIR: i32 = 21;
+const EINVAL: i32 = 22;
+const ENOSYS: i32 = 38;
+
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+const IN_HEADER_SZ: usize = 40;
+const MAX_READ: u32 = 128 * 1024;
+// Large enough for any request the kernel sends a read only filesystem.
+const REQUEST_BUF_SZ: usize = 1 << 20;
+
+const MS_RDONLY: c_ulong = 1;
+const MS_NOSUID: c_ulong = 2;
+const MS_NODEV: c_ulong = 4;
+const MNT_DETACH: c_int = 2;
+const EPERM: i32 = 1;
+
+const FUSERMOUNT: &str = "fusermount3";
+const F_SETFD: c_int = 2;
+const SOL_SOCKET: c_int = 1;
+const SCM_RIGHTS: c_int = 1;
+const MSG_CMSG_CLOEXEC: c_int = 0x4000_0000;
+
+mod sys {
+    use std::os::raw::{c_char, c_int, c_ulong, c_void};
+
+    #[repr(C)]
+    pub struct IoVec {
+        pub base: *mut c_void,
+        pub len: usize,
+    }
+
+    #[repr(C)]
+    pub struct MsgHdr {
+        pub name: *mut c_void,
+        pub namelen: u32,
+        pub iov: *mut IoVec,
+        pub iovlen: usize,
+        pub control: *mut c_void,
+        pub controllen: usize,
+        pub flags: c_int,
+    }
+
+    #[repr(C)]
+    pub struct CmsgHdr {
+        pub len: usize,
+        pub level: c_int,
+        pub kind: c_int,
+    }
+
+    extern "C" {
+        pub fn mount(
+            source: *const c_char,
+            target: *const c_char,
+            fstype: *const c_char,
+            flags: c_ulong,
+            data: *const c_void,
+        ) -> c_int;
+        pub fn umount2(target: *const c_char, flags: c_int) -> c_int;
+        pub fn getuid() -> u32;
+        pub fn geteuid() -> u32;
+        pub fn getgid() -> u32;
+        pub fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+        pub fn recvmsg(fd: c_int, msg: *mut MsgHdr, flags: c_int) -> isize;
+    }
+}
+
+fn c_path(path: &Path) -> Result<CString, RepoError> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput).into())
+}
+
+fn errno(err: &RepoError) -> i32 {
+    match err {
+        RepoError::IOError(e) => match e.kind() {
+            io::ErrorKind::NotFound => ENOENT,
+            io::ErrorKind::NotADirectory => ENOTDIR,
+            io::ErrorKind::IsADirectory => EISDIR,
+            io::ErrorKind::InvalidInput => EINVAL,
+            _ => EIO,
+        },
+        _ => EIO,
+    }
+}
+
+fn u32_at(buf: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(buf.get(off..off + 4)?.try_into().ok()?))
+}
+
+fn u64_at(buf: &[u8], off: usize) -> Option<u64> {
+    Some(u64::from_ne_bytes(buf.get(off..off + 8)?.try_into().ok()?))
+}
+
+// Native endian writes, as in `wire` for the repository formats.
+#[derive(Default)]
+struct Out {
+    buf: Vec<u8>,
+}
+
+impl Out {
+    fn u16(&mut self, v: u16) -> &mut Out {
+        self.buf.extend_from_slice(&v.to_ne_bytes());
+        self
+    }
+
+    fn u32(&mut self, v: u32) -> &mut Out {
+        self.buf.extend_from_slice(&v.to_ne_bytes());
+        self
+    }
+
+    fn u64(&mut self, v: u64) -> &mut Out {
+        self.buf.extend_from_slice(&v.to_ne_bytes());
+        self
+    }
+
+    fn bytes(&mut self, v: &[u8]) -> &mut Out {
+        self.buf.extend_from_slice(v);
+        self
+    }
+
+    fn attr(&mut self, a: &FileAttr) -> &mut Out {
+        let file_type = match a.kind {
+            EntryKind::File => 0o100000,
+            EntryKind::Dir => 0o040000,
+            EntryKind::Symlink => 0o120000,
+            EntryKind::BlockDevice => 0o060000,
+            EntryKind::CharDevice => 0o020000,
+            EntryKind::Fifo => 0o010000,
+            EntryKind::Socket => 0o140000,
+        };
+        // The kernel's encoding of device numbers.
+        let (major, minor) = a.rdev;
+        let rdev = (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12);
+        self.u64(a.ino)
+            .u64(a.size)
+            .u64(a.size.div_ceil(512))
+            .u64(a.atime.0)
+            .u64(a.mtime)
+            .u64(a.mtime)
+            .u32(a.atime.1)
+            .u32(a.mtime_nsec)
+            .u32(a.mtime_nsec)
+            .u32(file_type | a.mode)
+            .u32(a.nlink)
+            .u32(a.uid)
+            .u32(a.gid)
+            .u32(rdev)
+            .u32(4096)
+            .u32(0)
+    }
+
+    fn entry(&mut self, a: &FileAttr) -> &mut Out {
+        self.u64(a.ino)
+            .u64(0)
+            .u64(TTL)
+            .u64(TTL)
+            .u32(0)
+            .u32(0)
+            .attr(a)
+    }
+}
+
+fn reply(unique: u64, result: Result<Vec<u8>, i32>) -> Vec<u8> {
+    let (error, body) = match result {
+        Ok(body) => (0, body),
+        Err(errno) => (-errno, Vec::new()),
+    };
+    let mut out = Out::default();
+    out.u32(16 + body.len() as u32)
+        .u32(error as u32)
+        .u64(unique)
+        .bytes(&body);
+    out.buf
+}
+
+fn dirent(out: &mut Out, ino: u64, next: u64, name: &[u8], kind: EntryKind) {
+    let dtype = match kind {
+        EntryKind::File => 8,
+        EntryKind::Dir => 4,
+        EntryKind::Symlink => 10,
+        EntryKind::BlockDevice => 6,
+        EntryKind::CharDevice => 2,
+        EntryKind::Fifo => 1,
+        EntryKind::Socket => 12,
+    };
+    out.u64(ino)
+        .u64(next)
+        .u32(name.len() as u32)
+        .u32(dtype)
+        .bytes(name);
+    out.bytes(&[0; 8][..(8 - name.len() % 8) % 8]);
+}
+
+struct Server<'a, 'b> {
+    fs: &'a mut SnapshotFs<'b>,
+}
+
+impl<'a, 'b> Server<'a, 'b> {
+    fn readdir(&mut self, ino: u64, offset: u64, size: usize) -> Result<Vec<u8>, RepoError> {
+        let mut entries = vec![
+            (ino, b".".to_vec(), EntryKind::Dir),
+            (self.fs.parent(ino)?, b"..".to_vec(), EntryKind::Dir),
+        ];
+        for child in self.fs.children(ino)? {
+            let kind = self.fs.attr(child)?.kind;
+            entries.push((child, self.fs.name(child)?.to_vec(), kind));
+        }
+        let mut out = Out::default();
+        for (i, (ino, name, kind)) in entries.iter().enumerate().skip(offset as usize) {
+            let mut ent = Out::default();
+            dirent(&mut ent, *ino, i as u64 + 1, name, *kind);
+            if out.buf.len() + ent.buf.len() > size {
+                break;
+            }
+            out.bytes(&ent.buf);
+        }
+        Ok(out.buf)
+    }
+
+    // The reply to one request, None for those that get none.
+    fn handle(&mut self, req: &[u8]) -> Option<Vec<u8>> {
+        let opcode = u32_at(req, 4)?;
+        let unique = u64_at(req, 8)?;
+        let ino = u64_at(req, 16)?;
+        let body = req.get(IN_HEADER_SZ..)?;
+        let fs_result = |r: Result<Vec<u8>, RepoError>| r.map_err(|e| errno(&e));
+        let result = match opcode {
+            FORGET | BATCH_FORGET | INTERRUPT => return None,
+            INIT => {
+                let major = u32_at(body, 0)?;
+                let minor = u32_at(body, 4)?;
+                let max_readahead = u32_at(body, 8)?;
+                let mut out = Out::default();
+                out.u32(KERNEL_VERSION)
+                    .u32(if major > KERNEL_VERSION {
+                        KERNEL_MINOR_VERSION
+                    } else {
+                        minor.min(KERNEL_MINOR_VERSION)
+                    })
+                    .u32(max_readahead)
+                    .u32(0)
+                    .u16(0)
+                    .u16(0)
+                    .u32(4096)
+                    .u32(1)
+                    .u16(0)
+                    .u16(0)
+                    .bytes(&[0; 32]);
+                Ok(out.buf)
+            }
+            LOOKUP => {
+                let name = body.split(|b| *b == 0).next()?;
+                fs_result(self.fs.lookup(ino, name).and_then(|child| {
+                    let mut out = Out::default();
+                    out.entry(&self.fs.attr(child)?);
+                    Ok(out.buf)
+                }))
+            }
+            GETATTR => fs_result(self.fs.attr(ino).map(|a| {
+                let mut out = Out::default();
+                out.u64(TTL).u32(0).u32(0).attr(&a);
+                out.buf
+            })),
+            READLINK => fs_result(self.fs.readlink(ino)),
+            OPEN | OPENDIR => fs_result(self.fs.attr(ino).map(|_| {
+                let mut out = Out::default();
+                out.u64(0).u32(FOPEN_KEEP_CACHE).u32(0);
+                out.buf
+            })),
+            READ => {
+                let offset = u64_at(body, 8)?;
+                let size = u32_at(body, 16)?.min(MAX_READ) as usize;
+                fs_result(self.fs.read(ino, offset, size))
+            }
+            READDIR => {
+                let offset = u64_at(body, 8)?;
+                let size = u32_at(body, 16)? as usize;
+                fs_result(self.readdir(ino, offset, size))
+            }
+            STATFS => {
+                let mut out = Out::default();
+                out.bytes(&[0; 40])
+                    .u32(4096)
+                    .u32(255)
+                    .u32(4096)
+                    .bytes(&[0; 28]);
+                Ok(out.buf)
+            }
+            RELEASE | RELEASEDIR | FLUSH | DESTROY => Ok(Vec::new()),
+            _ => Err(ENOSYS),
+        };
+        Some(reply(unique, result))
+    }
+}
+
+// Serve requests read from `dev` until the filesystem is unmounted. Each
+// read returns a single request, as it does from `/dev/fuse`.
+fn serve<D: Read + Write>(dev: &mut D, fs: &mut SnapshotFs) -> Result<(), RepoError> {
+    let mut server = Server { fs };
+    let mut buf = vec![0; REQUEST_BUF_SZ];
+    loop {
+        let n = match dev.read(&mut buf) {
+            Ok(n) => n,
+            // The filesystem was unmounted.
+            Err(ref e) if e.raw_os_error() == Some(ENODEV) => return Ok(()),
+            // The request was interrupted before it was read.
+            Err(ref e) if e.raw_os_error() == Some(ENOENT) => continue,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let req = &buf[..n];
+        if u32_at(req, 4) == Some(DESTROY) {
+            return Ok(());
+        }
+        if let Some(out) = server.handle(req) {
+            match dev.write(&out) {
+                Ok(_) => (),
+                // The request was interrupted and needs no reply.
+                Err(ref e) if e.raw_os_error() == Some(ENOENT) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+// Mount `fs` read only at `mountpoint` and serve it until it is unmounted.
+pub fn mount(fs: &mut SnapshotFs, mountpoint: &Path) -> Result<(), RepoError> {
+    // Safety: plain system calls.
+    let mut dev = if unsafe { sys::geteuid() } == 0 {
+        mount_as_root(mountpoint)?
+    } else {
+        fusermount(mountpoint)?
+    };
+    serve(&mut dev, fs)
+}
+
+fn mount_as_root(mountpoint: &Path) -> Result<File, RepoError> {
+    let dev = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")?;
+    // Safety: plain system calls on nul terminated strings that outlive
+    // them.
+    let (uid, gid) = unsafe { (sys::getuid(), sys::getgid()) };
+    let options = format!(
+        "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+        dev.as_raw_fd(),
+        uid,
+        gid
+    );
+    let options = CString::new(options).unwrap();
+    let target = c_path(mountpoint)?;
+    let rc = unsafe {
+        sys::mount(
+            b"packnback\0".as_ptr() as *const c_char,
+            target.as_ptr(),
+            b"fuse.packnback\0".as_ptr() as *const c_char,
+            MS_RDONLY | MS_NOSUID | MS_NODEV,
+            options.as_ptr() as *const c_void,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(dev)
+}
+
+// Have `fusermount3` mount `/dev/fuse` at `mountpoint` and hand it over.
+fn fusermount(mountpoint: &Path) -> Result<File, RepoError> {
+    let (ours, theirs) = UnixStream::pair()?;
+    let fd = theirs.as_raw_fd();
+    let mut cmd = Command::new(FUSERMOUNT);
+    cmd.arg("-o")
+        .arg("ro,nosuid,nodev,default_permissions,fsname=packnback,subtype=packnback")
+        .arg("--")
+        .arg(mountpoint)
+        .env("_FUSE_COMMFD", fd.to_string());
+    // Safety: fcntl is async signal safe, and `fd` stays open in the
+    // parent until the child has run.
+    unsafe {
+        cmd.pre_exec(move || match sys::fcntl(fd, F_SETFD, 0) {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+    let status = match cmd.status() {
+        Ok(status) => status,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("mounting as a user needs {}, from fuse3", FUSERMOUNT),
+            )
+            .into())
+        }
+        Err(e) => return Err(e.into()),
+    };
+    drop(theirs);
+    if !status.success() {
+        return Err(io::Error::other(format!("{} failed, {}", FUSERMOUNT, status)).into());
+    }
+    Ok(recv_fd(&ours)?)
+}
+
+// The descriptor sent over `sock` with SCM_RIGHTS.
+fn recv_fd(sock: &UnixStream) -> io::Result<File> {
+    let mut byte = [0u8; 1];
+    let mut iov = sys::IoVec {
+        base: byte.as_mut_ptr() as *mut c_void,
+        len: byte.len(),
+    };
+    // Room for one header and descriptor, aligned as a header.
+    let mut control = [0usize; 4];
+    let mut msg = sys::MsgHdr {
+        name: std::ptr::null_mut(),
+        namelen: 0,
+        iov: &mut iov,
+        iovlen: 1,
+        control: control.as_mut_ptr() as *mut c_void,
+        controllen: std::mem::size_of_val(&control),
+        flags: 0,
+    };
+    // Safety: every pointer in `msg` is to a live buffer of the length
+    // given, and the kernel writes no further.
+    let n = unsafe { sys::recvmsg(sock.as_raw_fd(), &mut msg, MSG_CMSG_CLOEXEC) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let hdr_sz = std::mem::size_of::<sys::CmsgHdr>();
+    // Safety: `control` is aligned for a header and larger than one.
+    let hdr = unsafe { &*(control.as_ptr() as *const sys::CmsgHdr) };
+    if msg.controllen < hdr_sz + 4 || hdr.level != SOL_SOCKET || hdr.kind != SCM_RIGHTS {
+        return Err(io::Error::other(format!(
+            "{} passed no /dev/fuse descriptor",
+            FUSERMOUNT
+        )));
+    }
+    // Safety: the kernel put a descriptor right after the header, which
+    // is now ours.
+    unsafe {
+        let fd = *((control.as_ptr() as *const u8).add(hdr_sz) as *const c_int);
+        Ok(File::from_raw_fd(fd))
+    }
+}
+
+// Detach the filesystem at `mountpoint`, which ends its `mount`.
+pub fn unmount(mountpoint: &Path) -> Result<(), RepoError> {
+    let target = c_path(mountpoint)?;
+    // Safety: as in `mount_as_root`.
+    if unsafe { sys::umount2(target.as_ptr(), MNT_DETACH) } == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() != Some(EPERM) {
+        return Err(err.into());
+    }
+    // Mounted by `fusermount3`, which unmounts for its user.
+    let status = Command::new(FUSERMOUNT)
+        .arg("-u")
+        .arg("-z")
+        .arg("--")
+        .arg(mountpoint)
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("{} -u failed, {}", FUSERMOUNT, status)).into());
+    }
+    Ok(())
+}
+
+// Tests --------------------
+
+#[cfg(test)]
+fn request(opcode: u32, ino: u64, body: &[u8]) -> Vec<u8> {
+    let mut out = Out::default();
+    out.u32((IN_HEADER_SZ + body.len()) as u32)
+        .u32(opcode)
+        .u64(77)
+        .u64(ino)
+        .bytes(&[0; 16])
+        .bytes(body);
+    out.buf
+}
+
+#[test]
+fn test_handle() {
+    let (r, key) = super::test_repo();
+    let mut fs = SnapshotFs::new(&r, &key.box_sk, 0).unwrap();
+    let mut server = Server { fs: &mut fs };
+    let init = server
+        .handle(&request(
+            INIT,
+            0,
+            &[7, 0, 0, 0, 40, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0],
+        ))
+        .unwrap();
+    assert_eq!(init.len(), 16 + 64);
+    assert_eq!((u32_at(&init, 16), u32_at(&init, 20)), (Some(7), Some(31)));
+    assert_eq!(u32_at(&init, 24), Some(0x20000));
+
+    let attr = server.handle(&request(GETATTR, 1, &[0; 16])).unwrap();
+    assert_eq!(attr.len(), 16 + 104);
+    assert_eq!(u64_at(&attr, 8), Some(77));
+    assert_eq!(u32_at(&attr, 16 + 16 + 60), Some(0o040555));
+
+    let mut read = [0; 40];
+    read[16..20].copy_from_slice(&4096u32.to_ne_bytes());
+    let dir = server.handle(&request(READDIR, 1, &read)).unwrap();
+    // Just . and .. in an empty repository.
+    assert_eq!(dir.len(), 16 + 2 * 32);
+    assert_eq!(&dir[16 + 24..16 + 25], b".");
+
+    let missing = server.handle(&request(LOOKUP, 1, b"nope\0")).unwrap();
+    assert_eq!(missing.len(), 16);
+    assert_eq!(u32_at(&missing, 4), Some(-ENOENT as u32));
+    assert_eq!(
+        u32_at(&server.handle(&request(99, 1, b"")).unwrap(), 4),
+        Some(-ENOSYS as u32)
+    );
+    assert!(server.handle(&request(FORGET, 1, &[0; 8])).is_none());
+    assert!(server.handle(&[0; 10]).is_none());
+}
+
+#[test]
+fn test_serve() {
+    use super::address::AddressKey;
+    use super::namespace::Namespace;
+    use super::snapfs::ROOT_INO;
+    use std::collections::VecDeque;
+
+    // Stands in for /dev/fuse, a request per read and ENODEV once they
+    // run out, as after an unmount.
+    #[derive(Default)]
+    struct Dev {
+        requests: VecDeque<io::Result<Vec<u8>>>,
+        replies: Vec<Vec<u8>>,
+    }
+    impl Read for Dev {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.requests.pop_front() {
+                Some(Ok(req)) => {
+                    buf[..req.len()].copy_from_slice(&req);
+                    Ok(req.len())
+                }
+                Some(Err(err)) => Err(err),
+                None => Err(io::Error::from_raw_os_error(ENODEV)),
+            }
+        }
+    }
+    impl Write for Dev {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.replies.push(buf.to_vec());
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let (r, key) = super::test_repo();
+    let ns = Namespace::new("laptop").unwrap();
+    let data = b"served without a kernel";
+    let (head, _) = r
+        .backup_stream(
+            &mut &data[..],
+            "dump",
+            &ns,
+            &AddressKey::new(),
+            &key,
+            &Default::default(),
+        )
+        .unwrap();
+    let time = super::datetime::DateTime::from_unix(head.timestamp).to_rfc3339();
+    let mut fs = SnapshotFs::new(&r, &key.box_sk, 0).unwrap();
+    let mut serve_one = |req: Vec<u8>| {
+        let mut dev = Dev {
+            requests: vec![Ok(req)].into(),
+            ..Default::default()
+        };
+        serve(&mut dev, &mut fs).unwrap();
+        assert_eq!(dev.replies.len(), 1);
+        dev.replies.pop().unwrap()
+    };
+
+    // Each lookup replies with the entry of the next step down.
+    let mut ino = ROOT_INO;
+    for name in ["laptop", &time, "dump"].iter() {
+        let name = format!("{}\0", name);
+        let entry = serve_one(request(LOOKUP, ino, name.as_bytes()));
+        assert_eq!(u32_at(&entry, 4), Some(0));
+        ino = u64_at(&entry, 16).unwrap();
+    }
+    let mut read = [0; 40];
+    read[16..20].copy_from_slice(&4096u32.to_ne_bytes());
+    let file = serve_one(request(READ, ino, &read));
+    assert_eq!(u64_at(&file, 8), Some(77));
+    assert_eq!(&file[16..], data);
+
+    // Interrupted reads are retried, forgets go unanswered and DESTROY
+    // ends serving before anything after it.
+    let mut dev = Dev {
+        requests: vec![
+            Err(io::Error::from_raw_os_error(ENOENT)),
+            Ok(request(FORGET, ino, &[0; 8])),
+            Ok(request(LOOKUP, ROOT_INO, b"nope\0")),
+            Ok(request(DESTROY, 0, b"")),
+            Ok(request(GETATTR, ROOT_INO, &[0; 16])),
+        ]
+        .into(),
+        ..Default::default()
+    };
+    serve(&mut dev, &mut fs).unwrap();
+    assert_eq!(dev.replies.len(), 1);
+    assert_eq!(u32_at(&dev.replies[0], 4), Some(-ENOENT as u32));
+    assert_eq!(dev.requests.len(), 1);
+}
+
+#[test]
+fn test_mount() {
+    use super::address::AddressKey;
+    use super::namespace::Namespace;
+    use super::snapfs::DEFAULT_CACHE_BYTES;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    use std::time::{Duration, Instant};
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let dir = super::storage::local::test_dir("fuse-src");
+    let mnt = super::storage::local::test_dir("fuse-mnt");
+    let big = super::chunker::test_data(3 << 20, 9);
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::create_dir_all(&mnt).unwrap();
+    fs::write(dir.join("sub/big"), &big).unwrap();
+    std::os::unix::fs::symlink("sub/big", dir.join("link")).unwrap();
+    let ns = Namespace::new("laptop").unwrap();
+    let (head, _) = r.backup(&dir, &ns, &ak, &key, &Default::default()).unwrap();
+    let mut snapfs = SnapshotFs::new(&r, &key.box_sk, DEFAULT_CACHE_BYTES).unwrap();
+
+    std::thread::scope(|s| {
+        let served = s.spawn(|| mount(&mut snapfs, &mnt));
+        let dev = fs::metadata(&dir).unwrap().dev();
+        let start = Instant::now();
+        while fs::metadata(&mnt).unwrap().dev() == dev {
+            if served.is_finished() {
+                // Mounting needs /dev/fuse and either root or fusermount3,
+                // `test_serve` covers the rest without them.
+                let err = served.join().unwrap().unwrap_err();
+                eprintln!("test_mount skipped, cannot mount: {}", err);
+                return;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        struct Unmount<'a>(&'a Path);
+        impl<'a> Drop for Unmount<'a> {
+            fn drop(&mut self) {
+                let _ = unmount(self.0);
+            }
+        }
+        let guard = Unmount(&mnt);
+
+        let time = super::datetime::DateTime::from_unix(head.timestamp).to_rfc3339();
+        let snap = mnt.join("laptop").join(&time);
+        let names: Vec<_> = fs::read_dir(&snap)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec!["link", "sub"]);
+        assert_eq!(fs::read(snap.join("sub/big")).unwrap(), big);
+        assert_eq!(fs::read(snap.join("link")).unwrap(), big);
+        assert_eq!(
+            fs::read_link(snap.join("link")).unwrap(),
+            Path::new("sub/big")
+        );
+        assert!(fs::metadata(snap.join("nope")).is_err());
+        assert!(fs::write(snap.join("new"), b"x").is_err());
+
+        drop(guard);
+        served.join().unwrap().unwrap();
+    });
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir(&mnt).unwrap();
+}
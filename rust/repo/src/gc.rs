@@ -0,0 +1,394 @@
+//! Garbage collection.
+//!
+//! Mark and sweep from the snapshot heads in the signed manifest. Marking
+//! follows the reference lists at the start of tree and snapshot objects,
+//! see `object`. Chunks are only ever looked up in pack indexes, never
+//! fetched or decrypted, so gc reads a small fraction of the repository.
+//!
+//! Packs are the unit of deletion. A pack whose objects are all
+//! unreferenced is deleted, index first so the pack is invisible before it
+//! goes. Packs mixing live and dead objects are kept and their dead bytes
+//! reported, `repack` reclaims those.
+//!
+//! gc holds an exclusive lock, so no transaction is in flight while it
+//! runs. Packs stored less than `GcOptions::grace` ago are kept anyway,
+//! which protects writers whose lock went stale, commits that wrote
+//! their indexes but have not yet replaced the manifest, and the
+//! checkpoints of interrupted backups until the next backup resumes them.
+//!
+//! If any referenced object is missing gc fails without deleting
+//! anything, the repository needs `fsck` before it can be collected.
+//!
+//! `gc_remote` splits the work with a server that holds no keys: the
+//! client marks and sends a signed keep list, the server sweeps, see
+//! `keeplist`.
+
+use super::address::Address;
+use super::datetime::unix_now;
+use super::index::{PackIndex, RepoIndex};
+use super::keeplist::{KeepList, MAX_KEEP_LIST_AGE};
+use super::lock::LockMode;
+use super::object::decode_refs;
+use super::pack::PackId;
+use super::{Repo, RepoError, INDEXES_DIR};
+use asymcrypt::Key;
+use std::collections::HashSet;
+use std::time::Duration;
+use tweetnacl::CryptoBoxSk;
+
+#[derive(Clone, Debug)]
+pub struct GcOptions {
+    // Never delete packs stored more recently than this.
+    pub grace: Duration,
+    // Report what would be deleted without deleting it.
+    pub dry_run: bool,
+}
+
+impl Default for GcOptions {
+    fn default() -> GcOptions {
+        GcOptions {
+            grace: Duration::from_secs(24 * 60 * 60),
+            dry_run: false,
+        }
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct GcStats {
+    pub live_objects: usize,
+    pub deleted_packs: Vec<PackId>,
+    pub reclaimed_bytes: u64,
+    // Bytes of unreferenced objects in packs that were kept.
+    pub dead_bytes: u64,
+}
+
+impl Repo {
+    // Every object reachable from the manifest heads.
+    pub fn mark(&self, sk: &CryptoBoxSk, index: &RepoIndex) -> Result<HashSet<Address>, RepoError> {
+        let roots = self
+            .manifest()?
+            .heads
+            .iter()
+            .flat_map(|h| h.roots())
+            .collect();
+        self.mark_from(roots, sk, index)
+    }
+
+    // Every object reachable from `roots`.
+    pub fn mark_from(
+        &self,
+        roots: Vec<Address>,
+        sk: &CryptoBoxSk,
+        index: &RepoIndex,
+    ) -> Result<HashSet<Address>, RepoError> {
+        let mut live = HashSet::new();
+        let mut todo = roots;
+        while let Some(address) = todo.pop() {
+            if !live.insert(address) {
+                continue;
+            }
+            let loc = match index.lookup(&address) {
+                Some(loc) => *loc,
+                None => return Err(RepoError::MissingObjectError),
+            };
+            if loc.kind.has_refs() {
+                let mut pack = self.open_pack(&loc.pack_id, sk)?;
+                let data = pack.read(loc.offset, loc.length)?;
+                todo.extend(decode_refs(&data)?);
+            }
+        }
+        Ok(live)
+    }
+
+    // Mark, then work out which packs to delete at `now`. Returns what a
+    // sweep would do and the packs holding live objects.
+    fn gc_plan(
+        &self,
+        sk: &CryptoBoxSk,
+        opts: &GcOptions,
+        now: u64,
+    ) -> Result<(GcStats, Vec<PackId>), RepoError> {
+        let mut indexes: Vec<PackIndex> = Vec::new();
+        for id in self.list_pack_indexes()? {
+            indexes.push(self.read_pack_index(&id)?);
+        }
+        let live = self.mark(sk, &RepoIndex::build(indexes.iter()))?;
+
+        let mut stats = GcStats {
+            live_objects: live.len(),
+            ..Default::default()
+        };
+        let mut keep = Vec::new();
+        for idx in indexes.iter() {
+            let dead: u64 = idx
+                .entries()
+                .iter()
+                .filter(|e| !live.contains(&e.address))
+                .map(|e| e.length as u64)
+                .sum();
+            let all_dead = idx.entries().iter().all(|e| !live.contains(&e.address));
+            let young = idx.created.saturating_add(opts.grace.as_secs()) > now;
+            if !all_dead {
+                keep.push(idx.pack_id);
+            }
+            if !all_dead || young {
+                stats.dead_bytes += dead;
+                continue;
+            }
+            stats.deleted_packs.push(idx.pack_id);
+            stats.reclaimed_bytes += idx.pack_size;
+        }
+        Ok((stats, keep))
+    }
+
+    // Delete a pack, index first so it is invisible before it goes.
+    fn sweep_pack(&self, id: &PackId) -> Result<(), RepoError> {
+        self.storage()
+            .delete(&format!("{}/{}", INDEXES_DIR, id.to_hex()))?;
+        self.delete_pack(id)
+    }
+
+    pub fn gc(&self, sk: &CryptoBoxSk, opts: &GcOptions) -> Result<GcStats, RepoError> {
+        if self.append_only() {
+            return Err(RepoError::PermissionDeniedError);
+        }
+        let _lock = self.lock(LockMode::Exclusive)?;
+        let (stats, _) = self.gc_plan(sk, opts, unix_now())?;
+        if !opts.dry_run {
+            for id in stats.deleted_packs.iter() {
+                self.sweep_pack(id)?;
+            }
+        }
+        Ok(stats)
+    }
+
+    // Collect garbage on a server that must not hold the repository key.
+    // This client marks and the server deletes, see `keeplist`. Only
+    // storage reached through `serve` supports this.
+    pub fn gc_remote(
+        &self,
+        sk: &CryptoBoxSk,
+        key: &Key,
+        opts: &GcOptions,
+    ) -> Result<GcStats, RepoError> {
+        let _lock = self.lock(LockMode::Exclusive)?;
+        let now = unix_now();
+        let (mut stats, keep) = self.gc_plan(sk, opts, now)?;
+        if opts.dry_run {
+            return Ok(stats);
+        }
+        let cutoff = now.saturating_sub(opts.grace.as_secs());
+        let list = KeepList::new(self.config.repo_id, now, cutoff, keep);
+        let swept = self.storage().collect_garbage(&list.sign(&key.sign_sk))?;
+        stats.deleted_packs = swept.deleted_packs;
+        stats.reclaimed_bytes = swept.reclaimed_bytes;
+        Ok(stats)
+    }
+
+    // Delete the packs a keep list signed by the owner leaves out, without
+    // the repository key. The client holds the exclusive lock meanwhile.
+    pub fn apply_keep_list(&self, signed: &[u8], now: u64) -> Result<GcStats, RepoError> {
+        if self.append_only() {
+            return Err(RepoError::PermissionDeniedError);
+        }
+        let list = KeepList::open(signed, &self.owner.sign_pk)?;
+        if list.repo_id != self.config.repo_id {
+            return Err(RepoError::RepoMismatchError);
+        }
+        if list.created.saturating_add(MAX_KEEP_LIST_AGE) < now
+            || list.created > now.saturating_add(MAX_KEEP_LIST_AGE)
+            || list.cutoff > list.created
+        {
+            return Err(RepoError::PermissionDeniedError);
+        }
+        let retained = self.retained()?.unwrap_or_default();
+        let mut stats: GcStats = Default::default();
+        for id in self.list_pack_indexes()? {
+            // A locked snapshot needs it, whatever the list says, see
+            // `retained`.
+            if list.contains(&id) || retained.holds(&id, now) {
+                continue;
+            }
+            let idx = self.read_pack_index(&id)?;
+            if idx.created >= list.cutoff {
+                continue;
+            }
+            // The server may keep packs longer, see `storage::grace`. It
+            // refuses the index first, so nothing is half deleted.
+            match self.sweep_pack(&id) {
+                Err(RepoError::PermissionDeniedError) => continue,
+                result => result?,
+            }
+            stats.deleted_packs.push(id);
+            stats.reclaimed_bytes += idx.pack_size;
+        }
+        Ok(stats)
+    }
+}
+
+// Tests --------------------
+
+#[cfg(test)]
+pub(crate) fn test_commit_tree(
+    r: &Repo,
+    key: &asymcrypt::Key,
+    i: u8,
+    chunks: &[Address],
+) -> super::manifest::SnapshotHead {
+    use super::object::{encode_refs, ObjectKind};
+    let mut tx = r.begin(Default::default()).unwrap();
+    let mut tree = super::wire::Encoder::new();
+    encode_refs(&mut tree, chunks);
+    tree.str("file names and such");
+    let mut tree_address: Address = Default::default();
+    tree_address.bytes[0] = i;
+    tree_address.bytes[1] = 1;
+    let mut snapshot = super::wire::Encoder::new();
+    encode_refs(&mut snapshot, &[tree_address]);
+    let mut address: Address = Default::default();
+    address.bytes[0] = i;
+    for c in chunks {
+        tx.add(c, ObjectKind::Chunk, &c.bytes).unwrap();
+    }
+    tx.add(&tree_address, ObjectKind::Tree, &tree.into_vec())
+        .unwrap();
+    tx.add(&address, ObjectKind::Snapshot, &snapshot.into_vec())
+        .unwrap();
+    let head = super::manifest::SnapshotHead {
+        address,
+        timestamp: i as u64,
+        namespace: super::namespace::Namespace::new("laptop").unwrap(),
+        retain_until: 0,
+        amendment: None,
+    };
+    tx.commit(head.clone(), key).unwrap();
+    head
+}
+
+#[cfg(test)]
+pub(crate) fn test_age_indexes(r: &Repo) {
+    for id in r.list_pack_indexes().unwrap() {
+        let mut idx = r.read_pack_index(&id).unwrap();
+        idx.created = 0;
+        r.storage()
+            .put(&format!("{}/{}", INDEXES_DIR, id.to_hex()), &idx.encode())
+            .unwrap();
+    }
+}
+
+#[test]
+fn test_gc() {
+    let (r, key) = super::test_repo();
+    let chunk = |b: u8| Address { bytes: [b; 32] };
+    let shared = chunk(100);
+    let head1 = test_commit_tree(&r, &key, 1, &[shared, chunk(101)]);
+    let head2 = test_commit_tree(&r, &key, 2, &[shared, chunk(102)]);
+    assert_eq!(r.list_packs().unwrap().len(), 2);
+
+    // Nothing is garbage yet.
+    let stats = r.gc(&key.box_sk, &Default::default()).unwrap();
+    assert_eq!(stats.live_objects, 7);
+    assert!(stats.deleted_packs.is_empty());
+    assert_eq!(stats.dead_bytes, 0);
+
+    let mut m = r.manifest().unwrap();
+    m.remove_head(&head1.address);
+    r.commit_manifest(&m, &key).unwrap();
+
+    // Recent packs survive the grace period.
+    let stats = r.gc(&key.box_sk, &Default::default()).unwrap();
+    assert!(stats.deleted_packs.is_empty());
+    assert!(stats.dead_bytes > 0);
+    test_age_indexes(&r);
+
+    // The first pack still holds a shared chunk.
+    let stats = r.gc(&key.box_sk, &Default::default()).unwrap();
+    assert_eq!(stats.live_objects, 4);
+    assert!(stats.deleted_packs.is_empty());
+
+    m.remove_head(&head2.address);
+    r.commit_manifest(&m, &key).unwrap();
+    let opts = GcOptions {
+        dry_run: true,
+        ..Default::default()
+    };
+    let stats = r.gc(&key.box_sk, &opts).unwrap();
+    assert_eq!(stats.deleted_packs.len(), 2);
+    assert_eq!(r.list_packs().unwrap().len(), 2);
+    let stats = r.gc(&key.box_sk, &Default::default()).unwrap();
+    assert_eq!(stats.deleted_packs.len(), 2);
+    assert!(stats.reclaimed_bytes > 0);
+    assert!(r.list_packs().unwrap().is_empty());
+    assert!(r.list_pack_indexes().unwrap().is_empty());
+}
+
+#[test]
+fn test_gc_refuses() {
+    let (r, key) = super::test_repo();
+    let head = test_commit_tree(&r, &key, 1, &[Address { bytes: [9; 32] }]);
+    test_age_indexes(&r);
+
+    // A missing object stops gc before it deletes anything.
+    let mut m = r.manifest().unwrap();
+    let mut missing = head.clone();
+    missing.address.bytes[0] = 0xff;
+    m.add_head(missing);
+    r.commit_manifest(&m, &key).unwrap();
+    match r.gc(&key.box_sk, &Default::default()) {
+        Err(RepoError::MissingObjectError) => (),
+        _ => panic!("expected missing object"),
+    }
+    assert_eq!(r.list_packs().unwrap().len(), 1);
+
+    let _lock = r.lock(LockMode::Shared).unwrap();
+    match r.gc(&key.box_sk, &Default::default()) {
+        Err(RepoError::RepoLockedError) => (),
+        _ => panic!("expected gc to be locked out"),
+    }
+}
+
+#[test]
+fn test_apply_keep_list() {
+    use super::manifest::RepoId;
+    let (r, key) = super::test_repo();
+    let head = test_commit_tree(&r, &key, 1, &[Address { bytes: [1; 32] }]);
+    test_commit_tree(&r, &key, 2, &[Address { bytes: [2; 32] }]);
+    let mut m = r.manifest().unwrap();
+    m.remove_head(&head.address);
+    r.commit_manifest(&m, &key).unwrap();
+    test_age_indexes(&r);
+    let now = unix_now();
+    let (planned, keep) = r.gc_plan(&key.box_sk, &Default::default(), now).unwrap();
+    assert_eq!(keep.len(), 1);
+    assert_eq!(planned.deleted_packs.len(), 1);
+
+    // Lists signed by others, for other repositories or from long ago are
+    // refused.
+    let repo_id = r.config().repo_id;
+    let list = KeepList::new(repo_id, now, now - 60, keep.clone());
+    let other = Key::new();
+    match r.apply_keep_list(&list.sign(&other.sign_sk), now) {
+        Err(RepoError::SignatureFailedError) => (),
+        _ => panic!("expected signature failure"),
+    }
+    let foreign = KeepList::new(RepoId::new(), now, now - 60, keep.clone());
+    match r.apply_keep_list(&foreign.sign(&key.sign_sk), now) {
+        Err(RepoError::RepoMismatchError) => (),
+        _ => panic!("expected repo mismatch"),
+    }
+    match r.apply_keep_list(&list.sign(&key.sign_sk), now + 2 * MAX_KEEP_LIST_AGE) {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected stale keep list"),
+    }
+    assert_eq!(r.list_packs().unwrap().len(), 2);
+
+    // A pack stored since the list was made is not in it, but survives.
+    test_commit_tree(&r, &key, 3, &[Address { bytes: [3; 32] }]);
+    let stats = r.apply_keep_list(&list.sign(&key.sign_sk), now).unwrap();
+    assert_eq!(stats.deleted_packs, planned.deleted_packs);
+    assert_eq!(stats.reclaimed_bytes, planned.reclaimed_bytes);
+    assert_eq!(r.list_packs().unwrap().len(), 2);
+    assert_eq!(r.list_pack_indexes().unwrap().len(), 2);
+    let stats = r.apply_keep_list(&list.sign(&key.sign_sk), now).unwrap();
+    assert!(stats.deleted_packs.is_empty());
+}
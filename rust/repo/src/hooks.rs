@@ -0,0 +1,247 @@
+//! Commands run around backups and restores.
+//!
+//! Hooks quiesce a database or take an LVM snapshot before a backup and
+//! undo it after, or send a notification when something fails, without
+//! wrapping the whole tool in a script. Each is a command run through
+//! `sh -c`, with stdin closed and the output of the caller, and is set in
+//! the `[hooks]` table of the settings, see `settings`:
+//!
+//! ```text
+//! pre_put    before a backup, failing stops it
+//! post_put   after a backup committed its snapshot
+//! pre_get    before a restore, failing stops it
+//! post_get   after a restore
+//! on_error   after any of the above, or the backup or restore itself,
+//!            failed
+//! ```
+//!
+//! Hooks learn what happens from their environment:
+//!
+//! ```text
+//! PACKNBACK_HOOK        the hook running
+//! PACKNBACK_NAMESPACE   the namespace backed up into, backups only
+//! PACKNBACK_SOURCE      the directory backed up, backups only
+//! PACKNBACK_TARGET      the directory restored to, restores only
+//! PACKNBACK_SNAPSHOT    hex address of the snapshot, once there is one
+//! PACKNBACK_FILES       files stored or restored, post hooks only
+//! PACKNBACK_BYTES       their bytes, post hooks only
+//! PACKNBACK_ERRORS      paths left out, post hooks only
+//! PACKNBACK_FAILED      what failed, on_error only: a hook name, put or
+//!                       get
+//! PACKNBACK_ERROR       why, on_error only
+//! ```
+//!
+//! A failing post hook fails the command after the fact: the snapshot is
+//! committed, or the files restored, all the same. A failing `on_error`
+//! hook is ignored, the error it was told about is returned.
+
+use super::address::Address;
+use super::backup::BackupStats;
+use super::manifest::SnapshotHead;
+use super::namespace::Namespace;
+use super::restore::RestoreStats;
+use super::RepoError;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Hooks {
+    pub pre_put: Option<String>,
+    pub post_put: Option<String>,
+    pub pre_get: Option<String>,
+    pub post_get: Option<String>,
+    pub on_error: Option<String>,
+}
+
+type Env = Vec<(&'static str, String)>;
+
+fn run(name: &str, cmd: &Option<String>, env: &Env) -> Result<(), RepoError> {
+    let cmd = match *cmd {
+        Some(ref cmd) => cmd,
+        None => return Ok(()),
+    };
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env("PACKNBACK_HOOK", name)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(RepoError::IOError(io::Error::other(format!(
+            "{} hook failed, {}",
+            name, status
+        ))));
+    }
+    Ok(())
+}
+
+fn path_var(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+impl Hooks {
+    // Run `f` between `pre` and `post`, with `env` and what `done` adds
+    // once `f` succeeds.
+    fn around<T, F, D>(
+        &self,
+        (pre, post, op): (&str, &str, &str),
+        (pre_cmd, post_cmd): (&Option<String>, &Option<String>),
+        mut env: Env,
+        f: F,
+        done: D,
+    ) -> Result<T, RepoError>
+    where
+        F: FnOnce() -> Result<T, RepoError>,
+        D: Fn(&T) -> Env,
+    {
+        let result = run(pre, pre_cmd, &env).map_err(|e| (pre, e));
+        let result = result.and_then(|()| f().map_err(|e| (op, e)));
+        let result = result.and_then(|v| {
+            env.extend(done(&v));
+            run(post, post_cmd, &env).map(|()| v).map_err(|e| (post, e))
+        });
+        result.map_err(|(failed, err)| {
+            env.push(("PACKNBACK_FAILED", failed.to_string()));
+            env.push(("PACKNBACK_ERROR", err.to_string()));
+            let _ = run("on_error", &self.on_error, &env);
+            err
+        })
+    }
+
+    // Run `backup`, the backup of `source` into `namespace`, with the
+    // put hooks around it.
+    pub fn backup<F>(
+        &self,
+        namespace: &Namespace,
+        source: &Path,
+        backup: F,
+    ) -> Result<(SnapshotHead, BackupStats), RepoError>
+    where
+        F: FnOnce() -> Result<(SnapshotHead, BackupStats), RepoError>,
+    {
+        let env = vec![
+            ("PACKNBACK_NAMESPACE", namespace.to_string()),
+            ("PACKNBACK_SOURCE", path_var(source)),
+        ];
+        self.around(
+            ("pre_put", "post_put", "put"),
+            (&self.pre_put, &self.post_put),
+            env,
+            backup,
+            |(head, stats)| {
+                vec![
+                    ("PACKNBACK_SNAPSHOT", head.address.to_hex()),
+                    ("PACKNBACK_FILES", stats.files.to_string()),
+                    ("PACKNBACK_BYTES", stats.bytes.to_string()),
+                    ("PACKNBACK_ERRORS", stats.errors.len().to_string()),
+                ]
+            },
+        )
+    }
+
+    // Run `restore`, the restore of `snapshot` to `target`, with the get
+    // hooks around it.
+    pub fn restore<F>(
+        &self,
+        snapshot: &Address,
+        target: &Path,
+        restore: F,
+    ) -> Result<RestoreStats, RepoError>
+    where
+        F: FnOnce() -> Result<RestoreStats, RepoError>,
+    {
+        let env = vec![
+            ("PACKNBACK_SNAPSHOT", snapshot.to_hex()),
+            ("PACKNBACK_TARGET", path_var(target)),
+        ];
+        self.around(
+            ("pre_get", "post_get", "get"),
+            (&self.pre_get, &self.post_get),
+            env,
+            restore,
+            |stats| {
+                vec![
+                    ("PACKNBACK_FILES", stats.files.to_string()),
+                    ("PACKNBACK_BYTES", stats.bytes.to_string()),
+                    ("PACKNBACK_ERRORS", stats.errors.len().to_string()),
+                ]
+            },
+        )
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_hooks() {
+    use super::address::AddressKey;
+    use std::fs;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let ns = Namespace::new("laptop").unwrap();
+    let dir = super::storage::local::test_dir("hooks");
+    let src = dir.join("src");
+    fs::create_dir_all(&src).unwrap();
+    fs::write(src.join("file"), b"data").unwrap();
+    let log = dir.join("log");
+    let append = |line: &str| Some(format!("echo \"{}\" >> {}", line, log.display()));
+    let read_log = || {
+        let log = fs::read_to_string(&log).unwrap_or_default();
+        let _ = fs::remove_file(dir.join("log"));
+        log
+    };
+
+    let hooks = Hooks {
+        pre_put: append("pre $PACKNBACK_HOOK $PACKNBACK_NAMESPACE"),
+        post_put: append("post $PACKNBACK_SNAPSHOT $PACKNBACK_FILES $PACKNBACK_ERRORS"),
+        pre_get: append("pre $PACKNBACK_SNAPSHOT"),
+        post_get: append("post $PACKNBACK_FILES $PACKNBACK_TARGET"),
+        on_error: append("error $PACKNBACK_FAILED: $PACKNBACK_ERROR"),
+    };
+    let (head, _) = hooks
+        .backup(&ns, &src, || {
+            r.backup(&src, &ns, &ak, &key, &Default::default())
+        })
+        .unwrap();
+    assert_eq!(
+        read_log(),
+        format!("pre pre_put laptop\npost {} 1 0\n", head.address.to_hex())
+    );
+    let to = dir.join("to");
+    hooks
+        .restore(&head.address, &to, || {
+            r.restore(
+                &head.address,
+                &to,
+                &key.box_sk,
+                Some(&ak),
+                &Default::default(),
+            )
+        })
+        .unwrap();
+    assert_eq!(
+        read_log(),
+        format!("pre {}\npost 1 {}\n", head.address.to_hex(), to.display())
+    );
+
+    // A failing pre hook stops the backup.
+    let failing = Hooks {
+        pre_put: Some("exit 3".to_string()),
+        ..hooks.clone()
+    };
+    let result = failing.backup(&ns, &src, || panic!("the backup ran"));
+    assert!(result.is_err());
+    assert!(read_log().starts_with("error pre_put: pre_put hook failed"));
+
+    // So does a failing backup, and the post hook does not run.
+    let result = hooks.backup(&ns, &dir.join("missing"), || {
+        r.backup(&dir.join("missing"), &ns, &ak, &key, &Default::default())
+    });
+    assert!(result.is_err());
+    let log = read_log();
+    assert!(log.starts_with("pre pre_put laptop\nerror put: "));
+    assert_eq!(log.lines().count(), 2);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,283 @@
+//! Pack indexes.
+//!
+//! Every pack has a small index listing its objects sorted by address, so a
+//! client can find any object with one ranged read of the right pack
+//! instead of scanning pack contents. Indexes hold no plaintext derived
+//! data beyond keyed addresses and object sizes, which the sealed length
+//! of an uncompressed object tells anyway, so they are stored unencrypted
+//! and may be fetched and cached freely. A sha512 checksum guards against corruption,
+//! tampering is caught when the referenced object fails to decrypt.
+//!
+//! Format:
+//!
+//! ```text
+//! "PNBINDEX" u16:format_version [16]:pack_id u64:pack_size u64:created
+//! u32:n n * ([32]:address u8:kind u64:offset u32:length u32:size)
+//! [32]:sha512_prefix_of_everything_above
+//! ```
+//!
+//! Entries are as in the pack's table of contents, see `pack`, and
+//! strictly ascending by address. `created` is the unix time the pack was
+//! stored, which lets `gc` leave recent packs alone.
+
+use super::address::Address;
+use super::datetime::unix_now;
+use super::object::ObjectKind;
+use super::pack::{FinishedPack, PackId, TocEntry};
+use super::wire::{Decoder, Encoder};
+use super::RepoError;
+use std::collections::HashMap;
+use tweetnacl::*;
+
+pub const INDEX_FORMAT_VERSION: u16 = 1;
+const INDEX_MAGIC: &[u8] = b"PNBINDEX";
+const INDEX_ENTRY_SZ: usize = 32 + 1 + 8 + 4 + 4;
+const CHECKSUM_SZ: usize = 32;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PackIndex {
+    pub pack_id: PackId,
+    pub pack_size: u64,
+    pub created: u64,
+    entries: Vec<TocEntry>,
+    fanout: Vec<u32>,
+}
+
+fn checksum(buf: &[u8]) -> [u8; CHECKSUM_SZ] {
+    let mut h = [0; CRYPTO_HASH_BYTES];
+    crypto_hash(&mut h, buf);
+    let mut c = [0; CHECKSUM_SZ];
+    c.copy_from_slice(&h[..CHECKSUM_SZ]);
+    c
+}
+
+impl PackIndex {
+    pub fn new(pack_id: PackId, pack_size: u64, mut entries: Vec<TocEntry>) -> PackIndex {
+        entries.sort_by_key(|e| e.address);
+        entries.dedup_by(|a, b| a.address == b.address);
+        PackIndex::from_sorted(pack_id, pack_size, unix_now(), entries)
+    }
+
+    pub fn from_finished(p: &FinishedPack) -> PackIndex {
+        PackIndex::new(p.id, p.size, p.entries.clone())
+    }
+
+    fn from_sorted(
+        pack_id: PackId,
+        pack_size: u64,
+        created: u64,
+        entries: Vec<TocEntry>,
+    ) -> PackIndex {
+        // fanout[b] is the number of entries with a first address byte < b.
+        let mut fanout = vec![0; 257];
+        for e in entries.iter() {
+            fanout[e.address.bytes[0] as usize + 1] += 1;
+        }
+        for i in 1..fanout.len() {
+            fanout[i] += fanout[i - 1];
+        }
+        PackIndex {
+            pack_id,
+            pack_size,
+            created,
+            entries,
+            fanout,
+        }
+    }
+
+    pub fn entries(&self) -> &[TocEntry] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn lookup(&self, address: &Address) -> Option<&TocEntry> {
+        let b = address.bytes[0] as usize;
+        let bucket = &self.entries[self.fanout[b] as usize..self.fanout[b + 1] as usize];
+        match bucket.binary_search_by(|e| e.address.cmp(address)) {
+            Ok(i) => Some(&bucket[i]),
+            Err(_) => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.fixed(INDEX_MAGIC)
+            .u16(INDEX_FORMAT_VERSION)
+            .fixed(&self.pack_id.bytes)
+            .u64(self.pack_size)
+            .u64(self.created)
+            .u32(self.entries.len() as u32);
+        for ent in self.entries.iter() {
+            ent.encode(&mut e);
+        }
+        let mut buf = e.into_vec();
+        let c = checksum(&buf);
+        buf.extend_from_slice(&c);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<PackIndex, RepoError> {
+        if buf.len() < CHECKSUM_SZ {
+            return Err(RepoError::InvalidDataError);
+        }
+        let (body, c) = buf.split_at(buf.len() - CHECKSUM_SZ);
+        if checksum(body)[..] != c[..] {
+            return Err(RepoError::CorruptOrTamperedDataError);
+        }
+        let mut d = Decoder::new(body);
+        if d.fixed(INDEX_MAGIC.len())? != INDEX_MAGIC {
+            return Err(RepoError::InvalidDataError);
+        }
+        if d.u16()? != INDEX_FORMAT_VERSION {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        let mut pack_id: PackId = Default::default();
+        d.fixed_into(&mut pack_id.bytes)?;
+        let pack_size = d.u64()?;
+        let created = d.u64()?;
+        let n = d.count(INDEX_ENTRY_SZ)?;
+        let mut entries: Vec<TocEntry> = Vec::with_capacity(n);
+        for _ in 0..n {
+            let ent = TocEntry::decode(&mut d)?;
+            if let Some(prev) = entries.last() {
+                if prev.address >= ent.address {
+                    return Err(RepoError::InvalidDataError);
+                }
+            }
+            if ent.offset + ent.length as u64 > pack_size {
+                return Err(RepoError::InvalidDataError);
+            }
+            entries.push(ent);
+        }
+        d.finish()?;
+        Ok(PackIndex::from_sorted(pack_id, pack_size, created, entries))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Location {
+    pub pack_id: PackId,
+    pub kind: ObjectKind,
+    pub offset: u64,
+    pub length: u32,
+    pub size: u32,
+}
+
+// Repository wide address lookup built by merging every pack index.
+#[derive(Default)]
+pub struct RepoIndex {
+    locations: HashMap<Address, Location>,
+}
+
+impl RepoIndex {
+    pub fn new() -> RepoIndex {
+        Default::default()
+    }
+
+    // When an address is present in more than one pack the first pack
+    // added wins, any copy is equally valid.
+    pub fn add_pack(&mut self, idx: &PackIndex) {
+        for ent in idx.entries.iter() {
+            self.locations.entry(ent.address).or_insert(Location {
+                pack_id: idx.pack_id,
+                kind: ent.kind,
+                offset: ent.offset,
+                length: ent.length,
+                size: ent.size,
+            });
+        }
+    }
+
+    pub fn build<'a, I: IntoIterator<Item = &'a PackIndex>>(indexes: I) -> RepoIndex {
+        let mut idx = RepoIndex::new();
+        for i in indexes {
+            idx.add_pack(i);
+        }
+        idx
+    }
+
+    pub fn lookup(&self, address: &Address) -> Option<&Location> {
+        self.locations.get(address)
+    }
+
+    pub fn contains(&self, address: &Address) -> bool {
+        self.locations.contains_key(address)
+    }
+
+    pub fn len(&self) -> usize {
+        self.locations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.locations.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Address, &Location)> {
+        self.locations.iter()
+    }
+}
+
+// Tests --------------------
+
+#[cfg(test)]
+fn test_entries(n: u8, pack_byte: u8) -> Vec<TocEntry> {
+    (0..n)
+        .map(|i| {
+            let mut a: Address = Default::default();
+            a.bytes[0] = i.wrapping_mul(37);
+            a.bytes[1] = pack_byte;
+            TocEntry {
+                address: a,
+                kind: ObjectKind::Chunk,
+                offset: 200 + i as u64 * 10,
+                length: 10,
+                size: 20,
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn test_index_round_trip_and_lookup() {
+    let entries = test_entries(50, 0);
+    let idx = PackIndex::new(PackId::new(), 1000, entries.clone());
+    let idx2 = PackIndex::decode(&idx.encode()).unwrap();
+    assert_eq!(idx, idx2);
+    for e in entries.iter() {
+        assert_eq!(idx2.lookup(&e.address), Some(e));
+    }
+    let missing = Address { bytes: [0xff; 32] };
+    assert!(idx2.lookup(&missing).is_none());
+}
+
+#[test]
+fn test_index_corrupt() {
+    let idx = PackIndex::new(PackId::new(), 1000, test_entries(5, 0));
+    let mut buf = idx.encode();
+    buf[20] ^= 1;
+    match PackIndex::decode(&buf) {
+        Err(RepoError::CorruptOrTamperedDataError) => (),
+        _ => panic!("expected checksum failure"),
+    }
+    let idx = PackIndex::new(PackId::new(), 100, test_entries(5, 0));
+    assert!(PackIndex::decode(&idx.encode()).is_err());
+}
+
+#[test]
+fn test_repo_index() {
+    let a = PackIndex::new(PackId::new(), 1000, test_entries(10, 1));
+    let b = PackIndex::new(PackId::new(), 1000, test_entries(20, 2));
+    let c = PackIndex::new(PackId::new(), 1000, test_entries(20, 2));
+    let idx = RepoIndex::build(vec![&a, &b, &c]);
+    assert_eq!(idx.len(), 30);
+    for e in b.entries() {
+        assert_eq!(idx.lookup(&e.address).unwrap().pack_id, b.pack_id);
+    }
+}
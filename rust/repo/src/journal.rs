@@ -0,0 +1,168 @@
+//! Journals of restores, to resume them.
+//!
+//! A restore with `RestoreOptions::journal` appends a record to the
+//! journal file as each file it writes is complete: contents, metadata,
+//! synced, and read back if verifying. Run again with the same journal,
+//! after a crash, a lost connection or an interrupt, it skips the files
+//! recorded instead of fetching them again, see `restore`. A journal that
+//! is missing is created, and removed once a restore completes without
+//! errors, one with errors is kept so a rerun only retries what failed.
+//!
+//! Format:
+//!
+//! ```text
+//! journal:  "PNBJOURNAL" u16:format_version [32]:snapshot bytes:part
+//!           record...
+//! record:   u8:flags bytes:path
+//! ```
+//!
+//! `part` is what is restored, see `RestoreOptions::path`, empty for the
+//! whole snapshot, and a journal of another snapshot or part is refused.
+//! `path` is the file's path relative to the target. Bit 0 of `flags` says
+//! the file was read back and verified, the other bits are 0, and a
+//! restore verifying redoes the files that were not. Records are appended
+//! without syncing, a record lost to a crash only costs fetching its file
+//! again, and a record cut short is dropped.
+
+use super::address::Address;
+use super::wire::{Decoder, Encoder};
+use super::RepoError;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub const JOURNAL_FORMAT_VERSION: u16 = 1;
+const JOURNAL_MAGIC: &[u8] = b"PNBJOURNAL";
+
+const VERIFIED: u8 = 1;
+
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+    // The journal was there, an earlier run was interrupted.
+    resuming: bool,
+    // Files recorded, and whether they were verified.
+    done: HashMap<Vec<u8>, bool>,
+}
+
+fn header(snapshot: &Address, part: &[u8]) -> Vec<u8> {
+    let mut e = Encoder::new();
+    e.fixed(JOURNAL_MAGIC)
+        .u16(JOURNAL_FORMAT_VERSION)
+        .fixed(&snapshot.bytes)
+        .bytes(part);
+    e.into_vec()
+}
+
+impl Journal {
+    // Open the journal at `path` of restoring `part` of `snapshot`, or
+    // start one.
+    pub fn open(path: &Path, snapshot: &Address, part: &[u8]) -> Result<Journal, RepoError> {
+        let header = header(snapshot, part);
+        let mut buf = Vec::new();
+        let resuming = match File::open(path) {
+            Ok(mut f) => {
+                f.read_to_end(&mut buf)?;
+                true
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                let mut f = OpenOptions::new().write(true).create_new(true).open(path)?;
+                f.write_all(&header)?;
+                f.sync_all()?;
+                buf = header.clone();
+                false
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if !buf.starts_with(&header) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the journal is not of this restore",
+            )
+            .into());
+        }
+        let mut d = Decoder::new(&buf[header.len()..]);
+        let mut done = HashMap::new();
+        let mut end = header.len();
+        while let Ok(flags) = d.u8() {
+            match d.bytes() {
+                Ok(path) => {
+                    done.insert(path.to_vec(), flags & VERIFIED != 0);
+                    end = buf.len() - d.remaining();
+                }
+                Err(_) => break,
+            }
+        }
+        let file = OpenOptions::new().write(true).open(path)?;
+        // Appends go after the last whole record.
+        file.set_len(end as u64)?;
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(Journal {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+            resuming,
+            done,
+        })
+    }
+
+    // Whether an earlier run completed the file at `path`, and verified it
+    // if `verified`.
+    pub fn is_done(&self, path: &[u8], verified: bool) -> bool {
+        self.done.get(path).is_some_and(|v| *v || !verified)
+    }
+
+    pub fn resuming(&self) -> bool {
+        self.resuming
+    }
+
+    pub fn record(&self, path: &[u8], verified: bool) -> io::Result<()> {
+        let mut e = Encoder::new();
+        e.u8(if verified { VERIFIED } else { 0 }).bytes(path);
+        self.file.lock().unwrap().write_all(&e.into_vec())
+    }
+
+    // Remove the journal of a completed restore.
+    pub fn remove(self) -> io::Result<()> {
+        fs::remove_file(&self.path)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_journal() {
+    let dir = super::storage::local::test_dir("journal");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("restore.journal");
+    let snapshot = Address { bytes: [3; 32] };
+    let j = Journal::open(&path, &snapshot, b"").unwrap();
+    assert!(!j.resuming());
+    j.record(b"a/file", true).unwrap();
+    j.record(b"b", false).unwrap();
+    drop(j);
+    // A record cut short by a crash.
+    let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+    f.write_all(&[1, 0, 0, 0, 9, b'c']).unwrap();
+    drop(f);
+
+    let j = Journal::open(&path, &snapshot, b"").unwrap();
+    assert!(j.resuming());
+    assert!(j.is_done(b"a/file", true) && j.is_done(b"b", false));
+    assert!(!j.is_done(b"b", true) && !j.is_done(b"c", false));
+    j.record(b"c", false).unwrap();
+    drop(j);
+    let j = Journal::open(&path, &snapshot, b"").unwrap();
+    assert!(j.is_done(b"c", false));
+
+    for (other, part) in [(Address { bytes: [4; 32] }, &b""[..]), (snapshot, b"a")].iter() {
+        match Journal::open(&path, other, part) {
+            Err(RepoError::IOError(ref e)) if e.kind() == io::ErrorKind::InvalidInput => (),
+            _ => panic!("expected the journal to be refused"),
+        }
+    }
+    j.remove().unwrap();
+    assert!(!path.exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,184 @@
+//! Machine readable output.
+//!
+//! In JSON mode every command writes NDJSON, one object per line, for
+//! scripts and monitoring. Each record has a `type` saying what it
+//! describes. The records of a command's results come first, each module
+//! documents its own, and a single summary record comes last:
+//!
+//! ```text
+//! snapshot  list, see `list`
+//! stats     stats, see `stats`
+//! du        du, see `du`
+//! change    diff and verify, see `diff`
+//! found     find, see `find`
+//! check     fsck, see `fsck`
+//! backup    put, see `backup`
+//! restore   get, see `restore`
+//! existing  get, a path already in the target, see `restore`
+//! verify    verify, see `verify`
+//! problem   config check, what would stop the settings from being used:
+//!           {"type": "problem", "message": s}
+//! error     put, get and verify, a path that could not be stored or restored:
+//!           {"type": "error", "path": s, "message": s}
+//! summary   {"type": "summary", "command": s, "exit_code": n,
+//!            "ok": b, "warnings": n, "errors": n, "failure": s|null}
+//! ```
+//!
+//! `exit_code` is 0 if the command succeeded, 1 if it completed but found
+//! or left errors, `errors` counting them, and 2 if it failed, `failure`
+//! saying why. For put, 1 is a backup that completed with warnings: the
+//! snapshot was committed without the paths it could not read. Paths that
+//! are not utf8 are converted lossily.
+//!
+//! Text output writes paths with `text_path`, one per line whatever their
+//! bytes: backslashes and control characters, a newline or a terminal
+//! escape in a name say, are escaped Rust style, and bytes that are not
+//! utf8 written `\xNN`, so no name can forge a line or an escape
+//! sequence.
+
+use super::tree::SnapshotError;
+use super::RepoError;
+use std::io::{self, Write};
+
+pub fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub fn json_path(path: &[u8]) -> String {
+    json_str(&String::from_utf8_lossy(path))
+}
+
+pub fn text_path(path: &[u8]) -> String {
+    let mut out = String::with_capacity(path.len());
+    for chunk in path.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c.is_control() || c == '\\' {
+                out.extend(c.escape_default());
+            } else {
+                out.push(c);
+            }
+        }
+        for b in chunk.invalid() {
+            out.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    out
+}
+
+pub fn write_errors(w: &mut dyn Write, errors: &[SnapshotError]) -> io::Result<()> {
+    for e in errors.iter() {
+        writeln!(
+            w,
+            "{{\"type\": \"error\", \"path\": {}, \"message\": {}}}",
+            json_path(&e.path),
+            json_str(&e.message)
+        )?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Summary {
+    pub command: String,
+    pub warnings: u64,
+    pub errors: u64,
+    pub failure: Option<String>,
+}
+
+impl Summary {
+    pub fn new(command: &str) -> Summary {
+        Summary {
+            command: command.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn failed(command: &str, err: &RepoError) -> Summary {
+        Summary {
+            command: command.to_string(),
+            failure: Some(err.to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        if self.failure.is_some() {
+            2
+        } else if self.errors != 0 {
+            1
+        } else {
+            0
+        }
+    }
+
+    pub fn write_ndjson(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            w,
+            "{{\"type\": \"summary\", \"command\": {}, \"exit_code\": {}, \"ok\": {}, \
+             \"warnings\": {}, \"errors\": {}, \"failure\": {}}}",
+            json_str(&self.command),
+            self.exit_code(),
+            self.exit_code() == 0,
+            self.warnings,
+            self.errors,
+            self.failure.as_deref().map_or("null".to_string(), json_str)
+        )
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_json() {
+    assert_eq!(json_str("a\"\\\n"), "\"a\\\"\\\\\\u000a\"");
+    assert_eq!(json_path(b"a/\xffb"), "\"a/\u{fffd}b\"");
+    assert_eq!(text_path(b"a/\xffb\xc3"), "a/\\xffb\\xc3");
+    assert_eq!(
+        text_path(b"a\nb\\c\x1b[2J\x7f"),
+        "a\\nb\\\\c\\u{1b}[2J\\u{7f}"
+    );
+    assert_eq!(text_path("caf\u{e9} \"x\"'".as_bytes()), "caf\u{e9} \"x\"'");
+
+    let mut out = Vec::new();
+    write_errors(
+        &mut out,
+        &[SnapshotError {
+            path: b"/src/a".to_vec(),
+            message: "Permission denied".to_string(),
+        }],
+    )
+    .unwrap();
+    let mut summary = Summary::new("put");
+    summary.errors = 1;
+    summary.write_ndjson(&mut out).unwrap();
+    Summary::failed("get", &RepoError::InvalidDataError)
+        .write_ndjson(&mut out)
+        .unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(
+        lines,
+        vec![
+            "{\"type\": \"error\", \"path\": \"/src/a\", \"message\": \"Permission denied\"}",
+            "{\"type\": \"summary\", \"command\": \"put\", \"exit_code\": 1, \"ok\": false, \
+             \"warnings\": 0, \"errors\": 1, \"failure\": null}",
+            &format!(
+                "{{\"type\": \"summary\", \"command\": \"get\", \"exit_code\": 2, \"ok\": false, \
+                 \"warnings\": 0, \"errors\": 0, \"failure\": {}}}",
+                json_str(&RepoError::InvalidDataError.to_string())
+            ),
+        ]
+    );
+    assert_eq!(Summary::new("list").exit_code(), 0);
+}
@@ -0,0 +1,149 @@
+//! Keep lists for server side garbage collection.
+//!
+//! Marking needs the repository key to read trees, which a storage server
+//! must never hold. Instead the client marks, then sends the server a keep
+//! list naming every pack that holds a live object, signed by the owner.
+//! The server deletes the indexed packs that are not listed and were
+//! stored before `cutoff`, see `Repo::apply_keep_list`. Packs stored since
+//! the client last listed the indexes are never older than the cutoff,
+//! which the client sets `GcOptions::grace` before it started.
+//!
+//! A keep list is only accepted within `MAX_KEEP_LIST_AGE` of its
+//! creation, so an old list cannot be replayed once packs it left out
+//! have become live again through deduplication.
+//!
+//! Format (inside the signature envelope, see `signed`):
+//!
+//! ```text
+//! "PNBKEEP" u16:format_version [16]:repo_id u64:created u64:cutoff
+//! u32:n n * [16]:pack_id
+//! ```
+//!
+//! Pack ids are strictly ascending.
+
+use super::manifest::RepoId;
+use super::pack::{PackId, PACK_ID_SZ};
+use super::signed;
+use super::wire::{Decoder, Encoder};
+use super::RepoError;
+use tweetnacl::*;
+
+pub const KEEP_LIST_FORMAT_VERSION: u16 = 1;
+const KEEP_LIST_MAGIC: &[u8] = b"PNBKEEP";
+
+// Seconds a keep list stays valid, and how far its creation may be ahead
+// of the server clock.
+pub const MAX_KEEP_LIST_AGE: u64 = 60 * 60;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct KeepList {
+    pub format_version: u16,
+    pub repo_id: RepoId,
+    // Unix times.
+    pub created: u64,
+    pub cutoff: u64,
+    packs: Vec<PackId>,
+}
+
+impl KeepList {
+    pub fn new(repo_id: RepoId, created: u64, cutoff: u64, mut packs: Vec<PackId>) -> KeepList {
+        packs.sort();
+        packs.dedup();
+        KeepList {
+            format_version: KEEP_LIST_FORMAT_VERSION,
+            repo_id,
+            created,
+            cutoff,
+            packs,
+        }
+    }
+
+    pub fn packs(&self) -> &[PackId] {
+        &self.packs
+    }
+
+    pub fn contains(&self, id: &PackId) -> bool {
+        self.packs.binary_search(id).is_ok()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.fixed(KEEP_LIST_MAGIC)
+            .u16(self.format_version)
+            .fixed(&self.repo_id.bytes)
+            .u64(self.created)
+            .u64(self.cutoff)
+            .u32(self.packs.len() as u32);
+        for id in self.packs.iter() {
+            e.fixed(&id.bytes);
+        }
+        e.into_vec()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<KeepList, RepoError> {
+        let mut d = Decoder::new(buf);
+        if d.fixed(KEEP_LIST_MAGIC.len())? != KEEP_LIST_MAGIC {
+            return Err(RepoError::InvalidDataError);
+        }
+        let format_version = d.u16()?;
+        if format_version != KEEP_LIST_FORMAT_VERSION {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        let mut repo_id: RepoId = Default::default();
+        d.fixed_into(&mut repo_id.bytes)?;
+        let created = d.u64()?;
+        let cutoff = d.u64()?;
+        let n = d.count(PACK_ID_SZ)?;
+        let mut packs: Vec<PackId> = Vec::with_capacity(n);
+        for _ in 0..n {
+            let mut id: PackId = Default::default();
+            d.fixed_into(&mut id.bytes)?;
+            if packs.last().is_some_and(|last| *last >= id) {
+                return Err(RepoError::InvalidDataError);
+            }
+            packs.push(id);
+        }
+        d.finish()?;
+        Ok(KeepList {
+            format_version,
+            repo_id,
+            created,
+            cutoff,
+            packs,
+        })
+    }
+
+    pub fn sign(&self, sk: &CryptoSignSk) -> Vec<u8> {
+        signed::sign(&self.encode(), sk)
+    }
+
+    pub fn open(sm: &[u8], pk: &CryptoSignPk) -> Result<KeepList, RepoError> {
+        KeepList::decode(&signed::open(sm, pk)?)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_keep_list_sign_open() {
+    let (pk, sk) = boxed_crypto_sign_keypair();
+    let (a, b) = (PackId::new(), PackId::new());
+    let l = KeepList::new(RepoId::new(), 100, 50, vec![b, a, b]);
+    assert_eq!(l.packs().len(), 2);
+    assert!(l.contains(&a) && l.contains(&b) && !l.contains(&PackId::new()));
+    assert_eq!(KeepList::open(&l.sign(&sk), &pk).unwrap(), l);
+
+    let (_, other) = boxed_crypto_sign_keypair();
+    match KeepList::open(&l.sign(&other), &pk) {
+        Err(RepoError::SignatureFailedError) => (),
+        _ => panic!("expected signature failure"),
+    }
+    let buf = l.encode();
+    assert!(KeepList::decode(&buf[..buf.len() - 1]).is_err());
+    // Unsorted lists are refused.
+    let mut swapped = buf.clone();
+    let n = buf.len();
+    swapped[n - 32..n - 16].copy_from_slice(&buf[n - 16..]);
+    swapped[n - 16..].copy_from_slice(&buf[n - 32..n - 16]);
+    assert!(KeepList::decode(&swapped).is_err());
+}
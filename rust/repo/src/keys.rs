@@ -0,0 +1,253 @@
+//! Key roles and role key files.
+//!
+//! The owner key can do everything, and every host holding it can read
+//! every file ever backed up. A repository created with a metadata key,
+//! see `RepoConfig::metadata_pk`, splits it into three roles so each host
+//! holds no more than it needs:
+//!
+//! ```text
+//! put       the owner signing key, enough to back up and commit
+//! metadata  the signing key and the metadata box key, which opens trees,
+//!           snapshots and amendments but no chunk
+//! decrypt   the whole owner key, which opens everything
+//! ```
+//!
+//! Chunks are sealed to the owner box key alone and metadata objects to
+//! the metadata key as well, see `pack` and `loose`, so the decrypt key
+//! can stay offline until something is restored. What each command needs:
+//!
+//! ```text
+//! put       backup, stream uploads, commit, amend, rename, retain, prune
+//!           without tags
+//! metadata  list, find, diff, du, prune by tags, gc and gc_remote, fsck
+//!           without reading chunks, restore plans for cold storage
+//! decrypt   restore, cat, mount, verify, repack, copy, migrate, packing
+//!           loose chunks
+//! ```
+//!
+//! A backup with a put key cannot read the last snapshot, so its progress
+//! has no estimated total. It deduplicates all the same.
+//!
+//! Format of a role key file:
+//!
+//! ```text
+//! "PNBKEY" u16:format_version u8:role [32]:sign_pk [64]:sign_sk
+//! [32]:box_pk [32]:box_sk
+//! ```
+//!
+//! `role` is 1 for put, 2 for metadata and 3 for decrypt. The box key is
+//! the metadata key for the metadata role, the owner box key for the
+//! decrypt role and all zero for the put role. Secret keys are written
+//! and read in place, never through a buffer that would outlive them.
+
+use super::wire::{Decoder, Encoder};
+use super::{Repo, RepoError};
+use asymcrypt::Key;
+use std::io::{self, Read, Write};
+use tweetnacl::*;
+
+pub const KEY_FORMAT_VERSION: u16 = 1;
+const KEY_MAGIC: &[u8] = b"PNBKEY";
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum KeyRole {
+    Put,
+    Metadata,
+    Decrypt,
+}
+
+impl KeyRole {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            KeyRole::Put => 1,
+            KeyRole::Metadata => 2,
+            KeyRole::Decrypt => 3,
+        }
+    }
+
+    pub fn from_u8(v: u8) -> Result<KeyRole, RepoError> {
+        match v {
+            1 => Ok(KeyRole::Put),
+            2 => Ok(KeyRole::Metadata),
+            3 => Ok(KeyRole::Decrypt),
+            _ => Err(RepoError::InvalidDataError),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyRole::Put => "put",
+            KeyRole::Metadata => "metadata",
+            KeyRole::Decrypt => "decrypt",
+        }
+    }
+}
+
+// Write the key file of `role` from the owner key. The metadata role also
+// needs the metadata key pair.
+pub fn write_key(
+    w: &mut dyn Write,
+    role: KeyRole,
+    owner: &Key,
+    metadata: Option<(&CryptoBoxPk, &CryptoBoxSk)>,
+) -> Result<(), RepoError> {
+    let zero_sk: CryptoBoxSk = Default::default();
+    let (box_pk, box_sk) = match role {
+        KeyRole::Put => (&[0; 32], &zero_sk.bytes),
+        KeyRole::Metadata => match metadata {
+            Some((pk, sk)) => (&pk.bytes, &sk.bytes),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "a metadata key file needs the metadata key",
+                )
+                .into())
+            }
+        },
+        KeyRole::Decrypt => (&owner.box_pk.bytes, &owner.box_sk.bytes),
+    };
+    let mut e = Encoder::new();
+    e.fixed(KEY_MAGIC).u16(KEY_FORMAT_VERSION).u8(role.to_u8());
+    w.write_all(&e.into_vec())?;
+    w.write_all(&owner.sign_pk.bytes)?;
+    w.write_all(&owner.sign_sk.bytes)?;
+    w.write_all(box_pk)?;
+    w.write_all(box_sk)?;
+    Ok(())
+}
+
+// Read a role key file. The box secret key of a put key is all zero and
+// opens nothing.
+pub fn read_key(r: &mut dyn Read) -> Result<(KeyRole, Box<Key>), RepoError> {
+    let mut header = [0; 9];
+    r.read_exact(&mut header)?;
+    let mut d = Decoder::new(&header);
+    if d.fixed(KEY_MAGIC.len())? != KEY_MAGIC {
+        return Err(RepoError::InvalidDataError);
+    }
+    if d.u16()? != KEY_FORMAT_VERSION {
+        return Err(RepoError::UnsupportedVersionError);
+    }
+    let role = KeyRole::from_u8(d.u8()?)?;
+    let mut k: Box<Key> = Default::default();
+    r.read_exact(&mut k.sign_pk.bytes)?;
+    r.read_exact(&mut k.sign_sk.bytes)?;
+    r.read_exact(&mut k.box_pk.bytes)?;
+    r.read_exact(&mut k.box_sk.bytes)?;
+    Ok((role, k))
+}
+
+impl Repo {
+    // The role `key` has in this repository, judged by its public halves.
+    pub fn key_role(&self, key: &Key) -> Result<KeyRole, RepoError> {
+        if key.sign_pk != self.owner().sign_pk {
+            return Err(RepoError::SignatureFailedError);
+        }
+        if key.box_pk == self.owner().box_pk {
+            Ok(KeyRole::Decrypt)
+        } else if Some(&key.box_pk) == self.config().metadata_pk.as_ref() {
+            Ok(KeyRole::Metadata)
+        } else {
+            Ok(KeyRole::Put)
+        }
+    }
+
+    // Refuse a key whose role does not include what `need` can do.
+    pub fn require_role(&self, key: &Key, need: KeyRole) -> Result<(), RepoError> {
+        if self.key_role(key)? < need {
+            return Err(RepoError::DecryptKeyMismatchError);
+        }
+        Ok(())
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_key_files() {
+    let owner = Key::new();
+    let (mpk, msk) = boxed_crypto_box_keypair();
+    for role in [KeyRole::Put, KeyRole::Metadata, KeyRole::Decrypt].iter() {
+        let mut buf = Vec::new();
+        write_key(&mut buf, *role, &owner, Some((&mpk, &msk))).unwrap();
+        let (read, k) = read_key(&mut &buf[..]).unwrap();
+        assert_eq!(read, *role);
+        assert_eq!(k.sign_pk, owner.sign_pk);
+        assert_eq!(k.sign_sk.bytes[..], owner.sign_sk.bytes[..]);
+        let box_sk = match role {
+            KeyRole::Put => [0; 32],
+            KeyRole::Metadata => msk.bytes,
+            KeyRole::Decrypt => owner.box_sk.bytes,
+        };
+        assert_eq!(k.box_sk.bytes, box_sk);
+        assert!(read_key(&mut &buf[..buf.len() - 1]).is_err());
+    }
+    assert!(write_key(&mut Vec::new(), KeyRole::Metadata, &owner, None).is_err());
+    let mut buf = Vec::new();
+    write_key(&mut buf, KeyRole::Put, &owner, None).unwrap();
+    buf[8] = 4;
+    assert!(read_key(&mut &buf[..]).is_err());
+}
+
+#[test]
+fn test_key_roles() {
+    use super::address::Address;
+    let owner = Key::new();
+    let (mpk, msk) = boxed_crypto_box_keypair();
+    let config = super::config::RepoConfig {
+        metadata_pk: Some((*mpk).clone()),
+        ..Default::default()
+    };
+    let storage = std::sync::Arc::new(super::storage::mem::MemStorage::new());
+    let r = Repo::init(storage, config, &owner).unwrap();
+    let load = |role| {
+        let mut buf = Vec::new();
+        write_key(&mut buf, role, &owner, Some((&mpk, &msk))).unwrap();
+        read_key(&mut &buf[..]).unwrap().1
+    };
+    let (put, metadata) = (load(KeyRole::Put), load(KeyRole::Metadata));
+    assert_eq!(r.key_role(&put).unwrap(), KeyRole::Put);
+    assert_eq!(r.key_role(&metadata).unwrap(), KeyRole::Metadata);
+    assert_eq!(r.key_role(&owner).unwrap(), KeyRole::Decrypt);
+    assert!(r.key_role(&Key::new()).is_err());
+    assert!(r.require_role(&metadata, KeyRole::Put).is_ok());
+    assert!(r.require_role(&put, KeyRole::Metadata).is_err());
+
+    // A put key backs up, chunks and metadata land in separate packs.
+    let chunks: Vec<Address> = (0..3)
+        .map(|i| Address {
+            bytes: [i + 10; 32],
+        })
+        .collect();
+    let head = super::gc::test_commit_tree(&r, &put, 1, &chunks);
+    assert_eq!(r.list_packs().unwrap().len(), 2);
+    assert!(r.fsck(None, &Default::default()).unwrap().is_ok());
+    let index = r.load_index().unwrap();
+    for (key, snapshot, chunk) in [
+        (&put, false, false),
+        (&metadata, true, false),
+        (&owner, true, true),
+    ]
+    .iter()
+    {
+        assert_eq!(
+            r.read_object(&index, &key.box_sk, &head.address).is_ok(),
+            *snapshot
+        );
+        match r.read_object(&index, &key.box_sk, &chunks[0]) {
+            Ok((_, data)) => assert!(*chunk && data == chunks[0].bytes),
+            Err(RepoError::DecryptKeyMismatchError) => assert!(!*chunk),
+            Err(e) => panic!("unexpected error {}", e),
+        }
+    }
+    // The metadata key is enough to collect garbage.
+    r.gc(&metadata.box_sk, &Default::default()).unwrap();
+
+    // So are loose metadata objects.
+    let a = Address { bytes: [3; 32] };
+    r.put_loose(&a, super::object::ObjectKind::Snapshot, b"snap")
+        .unwrap();
+    assert_eq!(r.get_loose(&a, &metadata.box_sk).unwrap().1, b"snap");
+    assert_eq!(r.get_loose(&a, &owner.box_sk).unwrap().1, b"snap");
+    assert!(r.get_loose(&a, &put.box_sk).is_err());
+}
@@ -0,0 +1,774 @@
+extern crate asymcrypt;
+extern crate tweetnacl;
+
+// Walking and restoring trees use Unix file types, modes, owners, device
+// numbers and extended attributes throughout, see `backup`, `restore`,
+// `special` and `xattr`. There is no Windows client yet, see the roadmap:
+// it needs its own walker and restore path, for wide paths, file
+// attributes, alternate data streams, junctions and reading open files
+// from VSS snapshots. Until then other targets fail here rather than deep
+// in the walker.
+#[cfg(not(unix))]
+compile_error!("packnback only supports Unix systems for now");
+
+pub mod acl;
+pub mod address;
+pub mod amend;
+pub mod archive;
+pub mod attestation;
+pub mod audit;
+pub mod backup;
+pub mod bloom;
+pub mod cache;
+pub mod checks;
+pub mod chunker;
+pub mod cold;
+pub mod compress;
+pub mod config;
+pub mod copy;
+pub mod crypto;
+pub mod datetime;
+pub mod diff;
+pub mod du;
+pub mod exclude;
+pub mod find;
+pub mod fsck;
+#[cfg(target_os = "linux")]
+pub mod fuse;
+pub mod gc;
+pub mod hooks;
+pub mod index;
+pub mod journal;
+pub mod json;
+pub mod keeplist;
+pub mod keys;
+pub mod list;
+pub mod lock;
+pub mod loose;
+pub mod manifest;
+pub mod memory;
+pub mod metrics;
+pub mod migrate;
+pub mod mounts;
+pub mod namespace;
+pub mod object;
+pub mod oplog;
+pub mod owners;
+pub mod pack;
+pub mod parity;
+#[cfg(feature = "async")]
+pub mod pipeline;
+pub mod policy;
+pub mod presence;
+pub mod priority;
+pub mod progress;
+pub mod protocol;
+pub mod prune;
+pub mod ratelimit;
+pub mod receipt;
+pub mod repack;
+pub mod restore;
+pub mod retained;
+pub mod revoke;
+pub mod rollback;
+pub mod rotate;
+pub mod scan;
+pub mod scrub;
+pub mod serve;
+pub mod session;
+pub mod settings;
+pub mod signed;
+pub mod snapfs;
+pub mod special;
+pub mod statcache;
+pub mod stats;
+pub mod storage;
+pub mod tar;
+pub mod tenant;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transaction;
+pub mod translog;
+pub mod tree;
+pub mod upload;
+pub mod usage;
+pub mod ustar;
+pub mod verify;
+pub mod wire;
+pub mod xattr;
+
+use asymcrypt::{AsymcryptError, Key, PublicKey};
+use config::RepoConfig;
+use datetime::unix_now;
+use index::{PackIndex, RepoIndex};
+use manifest::{manifest_hash, Manifest};
+use pack::{PackId, PackReader, Packer, PackerOptions, Recipients};
+use policy::Policy;
+use rollback::ManifestTracker;
+use std::error;
+use std::fmt;
+use std::sync::Arc;
+use storage::append_only::AppendOnlyStorage;
+use storage::discard::DiscardStorage;
+use storage::{StorageEngine, StorageObject};
+use tweetnacl::CryptoBoxSk;
+use usage::Quota;
+
+#[derive(Debug)]
+pub enum RepoError {
+    InvalidDataError,
+    UnsupportedVersionError,
+    SignatureFailedError,
+    InvalidNamespaceError,
+    DuplicateWriterError,
+    UnknownWriterError,
+    RepoExistsError,
+    RepoMismatchError,
+    DecryptKeyMismatchError,
+    CorruptOrTamperedDataError,
+    ObjectTooLargeError,
+    UnsupportedOperationError,
+    InvalidKeyError,
+    InvalidRangeError,
+    PermissionDeniedError,
+    RepoLockedError,
+    LockLostError,
+    MissingObjectError,
+    // The quota a put would exceed, see `usage`.
+    QuotaExceededError(Quota),
+    ColdStorageError,
+    RetentionLockedError,
+    RollbackError,
+    RetiredKeyError,
+    // Milliseconds the server asked the client to wait, see `ratelimit`.
+    RateLimitedError(u64),
+    StorageError(String),
+    AsymcryptError(AsymcryptError),
+    IOError(std::io::Error),
+}
+
+impl fmt::Display for RepoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RepoError::InvalidDataError => {
+                write!(f, "The repository data is not in the expected format.")
+            }
+            RepoError::UnsupportedVersionError => {
+                write!(f, "Unsupported repository format version.")
+            }
+            RepoError::SignatureFailedError => write!(f, "The digital signature has failed."),
+            RepoError::InvalidNamespaceError => write!(
+                f,
+                "Namespaces must be 1 to 64 characters of [a-zA-Z0-9._-] not starting with '.'."
+            ),
+            RepoError::DuplicateWriterError => {
+                write!(f, "The writer key or namespace is already in use.")
+            }
+            RepoError::UnknownWriterError => {
+                write!(f, "The key is not an authorized writer of this repository.")
+            }
+            RepoError::RepoExistsError => {
+                write!(
+                    f,
+                    "A repository or other data already exists at the given path."
+                )
+            }
+            RepoError::RepoMismatchError => {
+                write!(
+                    f,
+                    "The repository manifest does not belong to this repository."
+                )
+            }
+            RepoError::DecryptKeyMismatchError => {
+                write!(f, "The given key cannot decrypt the given data.")
+            }
+            RepoError::CorruptOrTamperedDataError => {
+                write!(f, "Decrypting found corrupt or tampered with data.")
+            }
+            RepoError::ObjectTooLargeError => write!(f, "The object is too large to store."),
+            RepoError::UnsupportedOperationError => {
+                write!(f, "The storage backend does not support this operation.")
+            }
+            RepoError::InvalidKeyError => write!(f, "Invalid storage key."),
+            RepoError::InvalidRangeError => {
+                write!(f, "The requested range is outside the stored object.")
+            }
+            RepoError::PermissionDeniedError => {
+                write!(f, "The repository server refused the operation.")
+            }
+            RepoError::RepoLockedError => {
+                write!(f, "The repository is locked by another process.")
+            }
+            RepoError::LockLostError => {
+                write!(f, "The repository lock was removed by another process.")
+            }
+            RepoError::MissingObjectError => {
+                write!(f, "A referenced object is missing from the repository.")
+            }
+            RepoError::QuotaExceededError(ref quota) => {
+                write!(f, "The storage quota is used up, {}.", quota)
+            }
+            RepoError::ColdStorageError => {
+                write!(f, "The data is in cold storage and must be thawed first.")
+            }
+            RepoError::RetentionLockedError => {
+                write!(f, "A snapshot is under a retention lock.")
+            }
+            RepoError::RollbackError => write!(
+                f,
+                "The repository manifest is older than one already seen, \
+                 the storage may be serving an old copy."
+            ),
+            RepoError::RetiredKeyError => write!(
+                f,
+                "The owner key was rotated, the repository must be opened \
+                 with the new one."
+            ),
+            RepoError::RateLimitedError(ms) => write!(
+                f,
+                "The repository server is limiting this client's requests, \
+                 retry in {} ms.",
+                ms
+            ),
+            RepoError::StorageError(ref msg) => write!(f, "Storage error: {}", msg),
+            RepoError::AsymcryptError(ref e) => e.fmt(f),
+            RepoError::IOError(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for RepoError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            RepoError::AsymcryptError(ref e) => Some(e),
+            RepoError::IOError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl RepoError {
+    pub fn is_not_found(&self) -> bool {
+        match *self {
+            RepoError::IOError(ref e) => e.kind() == std::io::ErrorKind::NotFound,
+            _ => false,
+        }
+    }
+
+    // Failures that may well not happen again, such as timeouts, lost
+    // connections and rate limits, see `storage::retry`.
+    pub fn is_transient(&self) -> bool {
+        use std::io::ErrorKind::*;
+        match *self {
+            RepoError::RateLimitedError(_) => true,
+            RepoError::IOError(ref e) => matches!(
+                e.kind(),
+                TimedOut
+                    | Interrupted
+                    | WouldBlock
+                    | ConnectionReset
+                    | ConnectionAborted
+                    | ConnectionRefused
+                    | NotConnected
+                    | BrokenPipe
+                    | UnexpectedEof
+            ),
+            _ => false,
+        }
+    }
+
+    // How long the server asked to wait before trying again.
+    pub fn retry_after(&self) -> Option<std::time::Duration> {
+        match *self {
+            RepoError::RateLimitedError(ms) => Some(std::time::Duration::from_millis(ms)),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for RepoError {
+    fn from(err: std::io::Error) -> RepoError {
+        RepoError::IOError(err)
+    }
+}
+
+impl From<AsymcryptError> for RepoError {
+    fn from(err: AsymcryptError) -> RepoError {
+        RepoError::AsymcryptError(err)
+    }
+}
+
+// Storage layout, keys relative to the repository root.
+pub const CONFIG_FILE: &str = "config";
+pub const MANIFEST_FILE: &str = "manifest";
+pub const POLICY_FILE: &str = "policy";
+pub const ACL_FILE: &str = "acl";
+pub const SCRUB_FILE: &str = "scrub";
+pub const CHECK_FILE: &str = "check";
+pub const REVOCATIONS_FILE: &str = "revocations";
+pub const TRANSLOG_FILE: &str = "translog";
+pub const RETAINED_FILE: &str = "retained";
+pub const KEYS_DIR: &str = "keys";
+pub const OWNER_KEY_FILE: &str = "keys/owner.pub";
+pub const PACKS_DIR: &str = "packs";
+pub const INDEXES_DIR: &str = "indexes";
+pub const SNAPSHOTS_DIR: &str = "snapshots";
+pub const LOCKS_DIR: &str = "locks";
+pub const LOCK_OWNERS_DIR: &str = "lock-owners";
+pub const LOOSE_DIR: &str = "objects";
+pub const PARITY_DIR: &str = "parity";
+pub const USAGE_DIR: &str = "usage";
+pub const REPO_USAGE_FILE: &str = "repo-usage";
+pub const COLD_DIR: &str = "cold";
+pub const ARRIVALS_DIR: &str = "arrivals";
+pub const TENANTS_DIR: &str = "tenants";
+
+pub struct Repo {
+    // `storage` is `raw` behind an append only guard when the policy asks
+    // for one, all normal operations go through `storage`.
+    raw: Arc<dyn StorageEngine>,
+    storage: Arc<dyn StorageEngine>,
+    config: RepoConfig,
+    owner: PublicKey,
+    policy: Policy,
+    // Refuses manifests older than those seen, see `rollback`.
+    tracker: Option<Arc<ManifestTracker>>,
+}
+
+fn read_policy(storage: &dyn StorageEngine, config: &RepoConfig) -> Result<Policy, RepoError> {
+    match storage.get(POLICY_FILE) {
+        Ok(sm) => Policy::open_for(&sm, config),
+        Err(ref e) if e.is_not_found() => Ok(Policy::from_config(config)),
+        Err(e) => Err(e),
+    }
+}
+
+fn guard_storage(storage: &Arc<dyn StorageEngine>, policy: &Policy) -> Arc<dyn StorageEngine> {
+    if policy.append_only {
+        Arc::new(AppendOnlyStorage::new(storage.clone()))
+    } else {
+        storage.clone()
+    }
+}
+
+impl Repo {
+    // Create a new repository owned by `key`. Only the public half of the
+    // key is written into the repository.
+    pub fn init(
+        storage: Arc<dyn StorageEngine>,
+        config: RepoConfig,
+        key: &Key,
+    ) -> Result<Repo, RepoError> {
+        if !storage.list_prefix("")?.is_empty() {
+            return Err(RepoError::RepoExistsError);
+        }
+
+        let owner = key.pub_key();
+        let mut buf = Vec::new();
+        owner.write(&mut buf)?;
+        storage.put(OWNER_KEY_FILE, &buf)?;
+        storage.put(CONFIG_FILE, &config.sign(&key.sign_sk))?;
+
+        let policy = Policy::from_config(&config);
+        let repo = Repo {
+            raw: storage.clone(),
+            storage: guard_storage(&storage, &policy),
+            config,
+            owner,
+            policy,
+            tracker: None,
+        };
+        let manifest = Manifest::new(repo.config.repo_id, repo.config.chunker.clone());
+        repo.commit_manifest(&manifest, key)?;
+        Ok(repo)
+    }
+
+    // Open an existing repository, the config must be signed by `owner`.
+    pub fn open(storage: Arc<dyn StorageEngine>, owner: &PublicKey) -> Result<Repo, RepoError> {
+        let config = RepoConfig::open(&storage.get(CONFIG_FILE)?, &owner.sign_pk)?;
+        let policy = read_policy(&*storage, &config)?;
+        Ok(Repo {
+            storage: guard_storage(&storage, &policy),
+            raw: storage,
+            config,
+            owner: owner.clone(),
+            policy,
+            tracker: None,
+        })
+    }
+
+    pub fn storage(&self) -> &Arc<dyn StorageEngine> {
+        &self.storage
+    }
+
+    // This repository with every write dropped, for dry runs, and the
+    // storage counting what it dropped.
+    pub(crate) fn discarding(&self) -> (Repo, Arc<DiscardStorage>) {
+        let storage = Arc::new(DiscardStorage::new(self.storage.clone()));
+        let repo = Repo {
+            raw: storage.clone(),
+            storage: storage.clone(),
+            config: self.config.clone(),
+            owner: self.owner.clone(),
+            policy: self.policy.clone(),
+            tracker: self.tracker.clone(),
+        };
+        (repo, storage)
+    }
+
+    pub fn config(&self) -> &RepoConfig {
+        &self.config
+    }
+
+    pub fn owner(&self) -> &PublicKey {
+        &self.owner
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    // While append only nothing stored may be deleted or overwritten, only
+    // the manifest and policy may be replaced.
+    pub fn append_only(&self) -> bool {
+        self.policy.append_only
+    }
+
+    // Enable or lift append only mode. Only the maintenance key named in
+    // the config can sign the new policy.
+    pub fn set_append_only(
+        &mut self,
+        append_only: bool,
+        maintenance: &Key,
+    ) -> Result<(), RepoError> {
+        self.replace_policy(|p| p.append_only = append_only, maintenance)
+    }
+
+    // Give each namespace an address key of its own, or share one again,
+    // see `policy`.
+    pub fn set_isolate_namespaces(
+        &mut self,
+        isolate: bool,
+        maintenance: &Key,
+    ) -> Result<(), RepoError> {
+        self.replace_policy(|p| p.isolate_namespaces = isolate, maintenance)
+    }
+
+    fn replace_policy(
+        &mut self,
+        change: impl FnOnce(&mut Policy),
+        maintenance: &Key,
+    ) -> Result<(), RepoError> {
+        let mut policy = read_policy(&*self.raw, &self.config)?;
+        policy.serial += 1;
+        change(&mut policy);
+        let sm = policy.sign(&maintenance.sign_sk);
+        // Refuse to store a policy nobody will accept.
+        Policy::open_for(&sm, &self.config)?;
+        self.raw.put(POLICY_FILE, &sm)?;
+        self.storage = guard_storage(&self.raw, &policy);
+        self.policy = policy;
+        Ok(())
+    }
+
+    pub fn manifest(&self) -> Result<Manifest, RepoError> {
+        self.open_manifest(&self.storage.get(MANIFEST_FILE)?)
+    }
+
+    // Verify a signed manifest as fetched from this repository.
+    pub fn open_manifest(&self, sm: &[u8]) -> Result<Manifest, RepoError> {
+        let m = Manifest::open(sm, &self.owner.sign_pk)?;
+        if m.repo_id != self.config.repo_id {
+            return Err(RepoError::RepoMismatchError);
+        }
+        if let Some(ref tracker) = self.tracker {
+            if let Some(seen) = tracker.seen() {
+                if m.counter > seen.counter {
+                    self.check_translog(seen.log_size, &seen.log_root, &m)?;
+                }
+            }
+            tracker.observe(&m, &manifest_hash(sm))?;
+        }
+        Ok(m)
+    }
+
+    // Storage puts are atomic, so readers see either the old or the new
+    // manifest in full. The counter and previous hash of `m` are set from
+    // the manifest it replaces, which is appended to the log, see
+    // `translog`. Refused while the repository is opened
+    // with an owner key rotated out, see `rotate`.
+    pub fn commit_manifest(&self, m: &Manifest, key: &Key) -> Result<(), RepoError> {
+        if m.repo_id != self.config.repo_id {
+            return Err(RepoError::RepoMismatchError);
+        }
+        let mut m = m.clone();
+        match self.storage.get(MANIFEST_FILE) {
+            Ok(sm) => {
+                let current = self.open_manifest(&sm)?;
+                if current.old_key(&self.owner.box_pk).is_some() {
+                    return Err(RepoError::RetiredKeyError);
+                }
+                current.check_retention(&m, unix_now())?;
+                m.counter = current.counter + 1;
+                m.previous = manifest_hash(&sm);
+                self.append_translog(&mut m, Some((&current, &sm)))?;
+            }
+            Err(ref e) if e.is_not_found() => {
+                m.counter = 1;
+                m.previous = Default::default();
+                self.append_translog(&mut m, None)?;
+            }
+            Err(e) => return Err(e),
+        }
+        let sm = m.sign(&key.sign_sk);
+        self.storage.put(MANIFEST_FILE, &sm)?;
+        if let Some(ref tracker) = self.tracker {
+            tracker.observe(&m, &manifest_hash(&sm))?;
+        }
+        Ok(())
+    }
+
+    // Lock a snapshot until `until`, see `manifest`, listing the packs it
+    // needs first, see `retained`. This only replaces the manifest and the
+    // list, so it works on append only servers too.
+    pub fn retain_snapshot(
+        &self,
+        address: &address::Address,
+        until: u64,
+        key: &Key,
+    ) -> Result<(), RepoError> {
+        let mut m = self.manifest()?;
+        m.retain(address, until)?;
+        let roots = match m.heads.iter().find(|h| h.address == *address) {
+            Some(head) => head.roots(),
+            None => return Err(RepoError::MissingObjectError),
+        };
+        let index = self.load_index()?;
+        let packs = self
+            .mark_from(roots, &key.box_sk, &index)?
+            .iter()
+            .filter_map(|a| index.lookup(a).map(|loc| loc.pack_id))
+            .collect();
+        let mut retained = self.retained()?.unwrap_or_else(|| retained::Retained {
+            repo_id: self.config.repo_id,
+            ..Default::default()
+        });
+        retained.expire(unix_now());
+        retained.retain(address, until, packs);
+        self.storage
+            .put(RETAINED_FILE, &retained.sign(&key.sign_sk))?;
+        self.commit_manifest(&m, key)
+    }
+
+    pub fn packer(&self, opts: PackerOptions) -> Packer {
+        Packer::new(self.storage.clone(), &self.recipients(), opts)
+    }
+
+    // Who new packs and loose objects are sealed to, see `keys`.
+    pub fn recipients(&self) -> Recipients {
+        Recipients {
+            owner: self.owner.box_pk.clone(),
+            metadata: self.config.metadata_pk.clone(),
+        }
+    }
+
+    pub fn open_pack(
+        &self,
+        id: &PackId,
+        sk: &CryptoBoxSk,
+    ) -> Result<PackReader<StorageObject>, RepoError> {
+        let key = format!("{}/{}", PACKS_DIR, id.to_hex());
+        PackReader::open(StorageObject::new(self.storage.clone(), &key), sk)
+    }
+
+    // Read an object from the pack `index` places it in, or stored loose.
+    pub fn read_object(
+        &self,
+        index: &RepoIndex,
+        sk: &CryptoBoxSk,
+        address: &address::Address,
+    ) -> Result<(object::ObjectKind, Vec<u8>), RepoError> {
+        match index.lookup(address) {
+            Some(loc) => {
+                let mut pack = self.open_pack(&loc.pack_id, sk)?;
+                Ok((loc.kind, pack.read(loc.offset, loc.length)?))
+            }
+            None => match self.get_loose(address, sk) {
+                Err(ref e) if e.is_not_found() => Err(RepoError::MissingObjectError),
+                r => r,
+            },
+        }
+    }
+
+    // Delete a pack, its parity and any cold mark, the caller deletes the
+    // index first.
+    pub fn delete_pack(&self, id: &PackId) -> Result<(), RepoError> {
+        self.storage
+            .delete(&format!("{}/{}", PACKS_DIR, id.to_hex()))?;
+        for key in [parity::parity_key(id), cold::cold_key(id)].iter() {
+            match self.storage.delete(key) {
+                Err(ref e) if e.is_not_found() => (),
+                r => r?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn list_packs(&self) -> Result<Vec<PackId>, RepoError> {
+        list_ids(&*self.storage, PACKS_DIR)
+    }
+
+    pub fn write_pack_index(&self, idx: &PackIndex) -> Result<(), RepoError> {
+        let key = format!("{}/{}", INDEXES_DIR, idx.pack_id.to_hex());
+        self.storage.put(&key, &idx.encode())
+    }
+
+    pub fn read_pack_index(&self, id: &PackId) -> Result<PackIndex, RepoError> {
+        let key = format!("{}/{}", INDEXES_DIR, id.to_hex());
+        let idx = PackIndex::decode(&self.storage.get(&key)?)?;
+        if idx.pack_id != *id {
+            return Err(RepoError::InvalidDataError);
+        }
+        Ok(idx)
+    }
+
+    pub fn list_pack_indexes(&self) -> Result<Vec<PackId>, RepoError> {
+        list_ids(&*self.storage, INDEXES_DIR)
+    }
+
+    pub fn load_index(&self) -> Result<RepoIndex, RepoError> {
+        let mut idx = RepoIndex::new();
+        for id in self.list_pack_indexes()? {
+            idx.add_pack(&self.read_pack_index(&id)?);
+        }
+        Ok(idx)
+    }
+}
+
+fn list_ids(storage: &dyn StorageEngine, dir: &str) -> Result<Vec<PackId>, RepoError> {
+    let prefix = format!("{}/", dir);
+    let mut ids = Vec::new();
+    for k in storage.list_prefix(&prefix)? {
+        // Skip anything that is not named by a pack id.
+        if let Ok(id) = PackId::from_hex(&k[prefix.len()..]) {
+            ids.push(id);
+        }
+    }
+    Ok(ids)
+}
+
+// Tests --------------------
+
+#[cfg(test)]
+pub(crate) fn test_repo() -> (Repo, Box<Key>) {
+    let key = Key::new();
+    let storage = Arc::new(storage::mem::MemStorage::new());
+    let r = Repo::init(storage, Default::default(), &key).unwrap();
+    (r, key)
+}
+
+#[test]
+fn test_init_open() {
+    let (r, key) = test_repo();
+    let repo_id = r.config().repo_id;
+    let storage = r.storage().clone();
+
+    let r = Repo::open(storage.clone(), &key.pub_key()).unwrap();
+    assert_eq!(r.config().repo_id, repo_id);
+    assert_eq!(r.manifest().unwrap().heads.len(), 0);
+
+    match Repo::init(storage.clone(), Default::default(), &key) {
+        Err(RepoError::RepoExistsError) => (),
+        _ => panic!("expected exists error"),
+    }
+
+    let other = Key::new();
+    match Repo::open(storage.clone(), &other.pub_key()) {
+        Err(RepoError::SignatureFailedError) => (),
+        _ => panic!("expected signature failure"),
+    }
+
+    let pack_idx = PackIndex::new(PackId::new(), 0, Vec::new());
+    r.write_pack_index(&pack_idx).unwrap();
+    assert_eq!(r.list_pack_indexes().unwrap(), vec![pack_idx.pack_id]);
+    assert_eq!(r.read_pack_index(&pack_idx.pack_id).unwrap(), pack_idx);
+    assert!(r.load_index().unwrap().is_empty());
+
+    storage.delete(CONFIG_FILE).unwrap();
+    assert!(Repo::open(storage, &key.pub_key()).is_err());
+}
+
+#[test]
+fn test_append_only_policy() {
+    let key = Key::new();
+    let maintenance = Key::new();
+    let config = RepoConfig {
+        append_only: true,
+        maintenance_pk: Some(maintenance.sign_pk.clone()),
+        ..Default::default()
+    };
+    let raw = Arc::new(storage::mem::MemStorage::new());
+    let mut r = Repo::init(raw.clone(), config, &key).unwrap();
+    assert!(r.append_only());
+    r.commit_manifest(&r.manifest().unwrap(), &key).unwrap();
+    match r.storage().delete(CONFIG_FILE) {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected delete to be refused"),
+    }
+    match r.storage().put(OWNER_KEY_FILE, b"garbage") {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected overwrite to be refused"),
+    }
+
+    // Neither the owner nor anyone else may lift append only mode.
+    match r.set_append_only(false, &key) {
+        Err(RepoError::SignatureFailedError) => (),
+        _ => panic!("expected the owner key to be refused"),
+    }
+    assert!(r.append_only());
+    assert!(!raw.exists(POLICY_FILE).unwrap());
+
+    r.set_append_only(false, &maintenance).unwrap();
+    assert!(!r.append_only());
+    let r = Repo::open(raw.clone(), &key.pub_key()).unwrap();
+    assert!(!r.append_only());
+    assert_eq!(r.policy().serial, 1);
+    r.storage().delete(OWNER_KEY_FILE).unwrap();
+
+    // Without a maintenance key append only mode is permanent.
+    let raw = Arc::new(storage::mem::MemStorage::new());
+    let config = RepoConfig {
+        append_only: true,
+        ..Default::default()
+    };
+    let mut r = Repo::init(raw.clone(), config, &key).unwrap();
+    match r.set_append_only(false, &maintenance) {
+        Err(RepoError::SignatureFailedError) => (),
+        _ => panic!("expected lifting to be refused"),
+    }
+    raw.put(POLICY_FILE, b"garbage").unwrap();
+    assert!(Repo::open(raw, &key.pub_key()).is_err());
+}
+
+#[test]
+fn test_manifest_commit() {
+    let (r, key) = test_repo();
+    let mut m = r.manifest().unwrap();
+    m.add_head(manifest::SnapshotHead {
+        address: Default::default(),
+        timestamp: 1,
+        namespace: namespace::Namespace::new("test").unwrap(),
+        retain_until: 0,
+        amendment: None,
+    });
+    r.commit_manifest(&m, &key).unwrap();
+    let committed = r.manifest().unwrap();
+    assert_eq!(committed.heads, m.heads);
+    assert_eq!(committed.counter, m.counter + 1);
+
+    m.repo_id = manifest::RepoId::new();
+    match r.commit_manifest(&m, &key) {
+        Err(RepoError::RepoMismatchError) => (),
+        _ => panic!("expected repo mismatch"),
+    }
+}
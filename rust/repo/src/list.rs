@@ -0,0 +1,386 @@
+//! Listing snapshots.
+//!
+//! `Repo::list_snapshots` filters the manifest heads by time, then reads
+//! the snapshot object of each remaining head for its host, tags,
+//! description and sizes, see `tree`, with any amendment of the head
+//! applied, see `amend`. A renamed snapshot is listed, and filtered, under
+//! the namespace it was renamed to. Trees and chunks are never read, so
+//! listing only needs the snapshot objects to be readable. A snapshot
+//! object that cannot be read is still listed, without its details, unless
+//! a host or tag filter needs them.
+//!
+//! Snapshots in the namespace of a writer whose key was revoked, see
+//! `revoke`, are listed all the same, marked `revoked`.
+//!
+//! `write_json` emits a single JSON array:
+//!
+//! ```text
+//! [{"address": hex, "namespace": s, "time": n, "retain_until": n,
+//!   "revoked": b, "host": s, "source": s, "tags": [s, ...], "files": n, "dirs": n,
+//!   "bytes": n, "errors": n, "changed": n, "description": s}, ...]
+//! ```
+//!
+//! `changed` counts the files that kept changing while they were backed
+//! up, see `backup`. Fields from the snapshot object are null if it could
+//! not be read. A source that is not utf8 is converted lossily.
+//! `write_ndjson` emits the same objects one per line, each starting with
+//! `"type": "snapshot"`, see `json`.
+
+use super::datetime::DateTime;
+use super::json::json_str;
+use super::manifest::SnapshotHead;
+use super::namespace::Namespace;
+use super::tree::Snapshot;
+use super::{Repo, RepoError};
+use std::cmp::Ordering;
+use std::io::{self, Write};
+use tweetnacl::CryptoBoxSk;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SortKey {
+    #[default]
+    Time,
+    Namespace,
+    Host,
+    Bytes,
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct ListOptions {
+    pub namespace: Option<Namespace>,
+    pub host: Option<String>,
+    // Snapshots must carry every one of these tags.
+    pub tags: Vec<String>,
+    // Unix times, `after` inclusive and `before` exclusive.
+    pub after: Option<u64>,
+    pub before: Option<u64>,
+    pub sort: SortKey,
+    pub reverse: bool,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SnapshotListing {
+    // The namespace is the one the snapshot was renamed to, if it was.
+    pub head: SnapshotHead,
+    // None if the snapshot object could not be read.
+    pub snapshot: Option<Snapshot>,
+    // Made with a client key since revoked.
+    pub revoked: bool,
+}
+
+impl SnapshotListing {
+    fn host(&self) -> &str {
+        self.snapshot.as_ref().map_or("", |s| &s.host[..])
+    }
+
+    fn bytes(&self) -> u64 {
+        self.snapshot.as_ref().map_or(0, |s| s.bytes)
+    }
+}
+
+fn compare(a: &SnapshotListing, b: &SnapshotListing, key: SortKey) -> Ordering {
+    let by_time = a.head.timestamp.cmp(&b.head.timestamp);
+    match key {
+        SortKey::Time => by_time,
+        SortKey::Namespace => a.head.namespace.cmp(&b.head.namespace).then(by_time),
+        SortKey::Host => a.host().cmp(b.host()).then(by_time),
+        SortKey::Bytes => a.bytes().cmp(&b.bytes()).then(by_time),
+    }
+}
+
+fn write_listing(w: &mut dyn Write, l: &SnapshotListing, open: &str) -> io::Result<()> {
+    // Namespaces and tags never need escaping, see `namespace`, `tree`.
+    write!(
+        w,
+        "{}\"address\": \"{}\", \"namespace\": \"{}\", \"time\": {}, \"retain_until\": {}, \
+         \"revoked\": {}, ",
+        open,
+        l.head.address.to_hex(),
+        l.head.namespace,
+        l.head.timestamp,
+        l.head.retain_until,
+        l.revoked
+    )?;
+    match l.snapshot {
+        Some(ref s) => {
+            let tags: Vec<String> = s.tags.iter().map(|t| format!("\"{}\"", t)).collect();
+            write!(
+                w,
+                "\"host\": {}, \"source\": {}, \"tags\": [{}], \"files\": {}, \
+                 \"dirs\": {}, \"bytes\": {}, \"errors\": {}, \"changed\": {}, \"description\": {}}}",
+                json_str(&s.host),
+                json_str(&String::from_utf8_lossy(&s.source)),
+                tags.join(", "),
+                s.files,
+                s.dirs,
+                s.bytes,
+                s.errors.len(),
+                s.changed.len(),
+                json_str(&s.description)
+            )
+        }
+        None => write!(
+            w,
+            "\"host\": null, \"source\": null, \"tags\": null, \"files\": null, \
+             \"dirs\": null, \"bytes\": null, \"errors\": null, \"changed\": null, \
+             \"description\": null}}"
+        ),
+    }
+}
+
+pub fn write_json(w: &mut dyn Write, listings: &[SnapshotListing]) -> io::Result<()> {
+    write!(w, "[")?;
+    for (i, l) in listings.iter().enumerate() {
+        write_listing(w, l, if i == 0 { "{" } else { ", {" })?;
+    }
+    writeln!(w, "]")
+}
+
+pub fn write_ndjson(w: &mut dyn Write, listings: &[SnapshotListing]) -> io::Result<()> {
+    for l in listings.iter() {
+        write_listing(w, l, "{\"type\": \"snapshot\", ")?;
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+pub fn write_table(w: &mut dyn Write, listings: &[SnapshotListing]) -> io::Result<()> {
+    let mut rows = vec![[
+        "ADDRESS",
+        "TIME",
+        "NAMESPACE",
+        "HOST",
+        "FILES",
+        "BYTES",
+        "TAGS",
+    ]
+    .iter()
+    .map(|c| c.to_string())
+    .collect::<Vec<String>>()];
+    for l in listings.iter() {
+        let mut row = vec![
+            l.head.address.to_hex(),
+            DateTime::from_unix(l.head.timestamp).to_rfc3339(),
+            if l.revoked {
+                format!("{} (revoked)", l.head.namespace)
+            } else {
+                l.head.namespace.to_string()
+            },
+        ];
+        match l.snapshot {
+            Some(ref s) => row.extend_from_slice(&[
+                if s.host.is_empty() {
+                    "-".to_string()
+                } else {
+                    s.host.escape_debug().to_string()
+                },
+                s.files.to_string(),
+                s.bytes.to_string(),
+                s.tags.join(","),
+            ]),
+            None => row.extend_from_slice(&["?", "?", "?", "?"].map(String::from)),
+        }
+        rows.push(row);
+    }
+    let mut widths = vec![0; rows[0].len()];
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = cell.chars().count().max(*width);
+        }
+    }
+    for row in rows.iter() {
+        let mut line = String::new();
+        for (i, (width, cell)) in widths.iter().zip(row.iter()).enumerate() {
+            if i + 1 == row.len() {
+                line.push_str(cell);
+            } else {
+                line.push_str(&format!("{:<w$}  ", cell, w = *width));
+            }
+        }
+        writeln!(w, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+impl Repo {
+    pub fn list_snapshots(
+        &self,
+        sk: &CryptoBoxSk,
+        opts: &ListOptions,
+    ) -> Result<Vec<SnapshotListing>, RepoError> {
+        let index = self.load_index()?;
+        let revoked = self.revoked_namespaces()?;
+        let mut listings = Vec::new();
+        for mut head in self.manifest()?.heads.into_iter() {
+            if opts.after.is_some_and(|t| head.timestamp < t)
+                || opts.before.is_some_and(|t| head.timestamp >= t)
+            {
+                continue;
+            }
+            let read = self
+                .read_amendment(&index, sk, &head)
+                .and_then(|amendment| {
+                    let s = self.read_snapshot(&index, sk, &head.address)?;
+                    Ok(match amendment {
+                        Some(a) => (a.namespace.clone(), s.amended(a)),
+                        None => (None, s),
+                    })
+                });
+            let (renamed, snapshot) = match read {
+                Ok((renamed, s)) => (renamed, Some(s)),
+                // An outage fails the listing, a missing or damaged
+                // object only loses its details.
+                Err(e) if e.is_transient() => return Err(e),
+                Err(_) => (None, None),
+            };
+            // Revocations go by the namespace the snapshot was written in.
+            let revoked = revoked.contains(&head.namespace);
+            if let Some(ns) = renamed {
+                head.namespace = ns;
+            }
+            if opts
+                .namespace
+                .as_ref()
+                .is_some_and(|ns| *ns != head.namespace)
+            {
+                continue;
+            }
+            let wanted = match snapshot {
+                Some(ref s) => {
+                    opts.host.as_ref().is_none_or(|h| *h == s.host)
+                        && opts.tags.iter().all(|t| s.tags.contains(t))
+                }
+                None => opts.host.is_none() && opts.tags.is_empty(),
+            };
+            if wanted {
+                listings.push(SnapshotListing {
+                    head,
+                    snapshot,
+                    revoked,
+                });
+            }
+        }
+        listings.sort_by(|a, b| compare(a, b, opts.sort));
+        if opts.reverse {
+            listings.reverse();
+        }
+        Ok(listings)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_list_snapshots() {
+    use super::address::AddressKey;
+    use super::backup::BackupOptions;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let laptop = Namespace::new("laptop").unwrap();
+    let server = Namespace::new("server").unwrap();
+    let tags = |t: &[&str]| -> Vec<String> { t.iter().map(|t| t.to_string()).collect() };
+    let backup = |name: &str, ns: &Namespace, t: &[&str]| {
+        let opts = BackupOptions {
+            tags: tags(t),
+            ..Default::default()
+        };
+        let mut data: &[u8] = b"dump";
+        r.backup_stream(&mut data, name, ns, &ak, &key, &opts)
+            .unwrap()
+            .0
+    };
+    let a = backup("a", &laptop, &["daily", "home"]);
+    let a = r
+        .amend(
+            &a.address,
+            &tags(&["daily", "home"]),
+            "a \"dump\"",
+            &ak,
+            &key,
+        )
+        .unwrap();
+    let b = backup("bb", &server, &["daily"]);
+    super::gc::test_commit_tree(&r, &key, 3, &[]);
+
+    let all = r.list_snapshots(&key.box_sk, &Default::default()).unwrap();
+    assert_eq!(all.len(), 3);
+    let s = all
+        .iter()
+        .find(|l| l.head == a)
+        .unwrap()
+        .snapshot
+        .clone()
+        .unwrap();
+    assert_eq!(s.tags, vec!["daily", "home"]);
+    assert_eq!((s.source, s.bytes), (b"a".to_vec(), 4));
+    assert_eq!(s.host, super::backup::hostname());
+    assert_eq!(all.iter().filter(|l| l.snapshot.is_none()).count(), 1);
+
+    let list = |opts: ListOptions| -> Vec<SnapshotHead> {
+        let l = r.list_snapshots(&key.box_sk, &opts).unwrap();
+        l.into_iter().map(|l| l.head).collect()
+    };
+    assert_eq!(
+        list(ListOptions {
+            tags: tags(&["daily"]),
+            sort: SortKey::Namespace,
+            reverse: true,
+            ..Default::default()
+        }),
+        vec![b.clone(), a.clone()]
+    );
+    assert_eq!(
+        list(ListOptions {
+            tags: tags(&["daily", "home"]),
+            ..Default::default()
+        }),
+        vec![a.clone()]
+    );
+    assert_eq!(
+        list(ListOptions {
+            namespace: Some(server.clone()),
+            ..Default::default()
+        }),
+        vec![b.clone()]
+    );
+    assert!(list(ListOptions {
+        host: Some("elsewhere".to_string()),
+        ..Default::default()
+    })
+    .is_empty());
+    assert!(list(ListOptions {
+        after: Some(a.timestamp + 3600),
+        ..Default::default()
+    })
+    .is_empty());
+    assert_eq!(
+        list(ListOptions {
+            before: Some(a.timestamp + 3600),
+            ..Default::default()
+        })
+        .len(),
+        3
+    );
+
+    let mut out = Vec::new();
+    write_table(&mut out, &all).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(out.lines().count(), 4);
+    assert!(out.starts_with("ADDRESS "));
+    assert!(out.contains("daily,home"));
+    let mut out = Vec::new();
+    write_json(&mut out, &all).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("\"tags\": [\"daily\", \"home\"], \"files\": 1"));
+    assert!(out.contains("\"host\": null"));
+    assert!(out.contains("\"errors\": 0, \"changed\": 0, \"description\": \"a \\\"dump\\\"\"}"));
+    assert!(out.contains("\"errors\": null, \"changed\": null, \"description\": null}"));
+    assert!(out.ends_with("}]\n"));
+    let mut out = Vec::new();
+    write_ndjson(&mut out, &all).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(out.lines().count(), 3);
+    assert!(out
+        .lines()
+        .all(|l| l.starts_with("{\"type\": \"snapshot\", \"address\": ") && l.ends_with('}')));
+}
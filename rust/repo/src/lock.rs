@@ -0,0 +1,336 @@
+//! Repository locks.
+//!
+//! Backups add data and must never run while garbage collection deletes
+//! it, so backups take shared locks and maintenance takes an exclusive
+//! lock. Storage backends have no compare and swap, so a lock is taken by
+//! first writing a lock object and then listing every other lock. If a
+//! conflicting lock is found our own lock is deleted again and locking
+//! fails. Two processes racing for conflicting locks may both fail, but
+//! they can never both succeed.
+//!
+//! A process that dies leaves its lock behind. Lock holders rewrite their
+//! lock every `HEARTBEAT_INTERVAL` seconds, and a lock whose heartbeat is
+//! older than `STALE_AFTER` seconds is ignored. This assumes client clocks
+//! roughly agree. `Repo::force_unlock` removes every lock when an operator
+//! knows better.
+//!
+//! Locks hold no repository data, so they may be rewritten and deleted
+//! even in append only mode.
+//!
+//! ```text
+//! key:   locks/<hex lock_id>
+//! lock:  "PNBLOCK" u16:format_version [16]:lock_id u8:mode
+//!        u64:created u64:heartbeat
+//! ```
+//!
+//! Times are unix seconds. Modes are 0 for shared and 1 for exclusive.
+
+use super::address::{from_hex, to_hex};
+use super::datetime::unix_now;
+use super::storage::StorageEngine;
+use super::wire::{Decoder, Encoder};
+use super::{Repo, RepoError, LOCKS_DIR, LOCK_OWNERS_DIR};
+use std::sync::Arc;
+use tweetnacl::*;
+
+pub const LOCK_FORMAT_VERSION: u16 = 1;
+const LOCK_MAGIC: &[u8] = b"PNBLOCK";
+
+pub const LOCK_ID_SZ: usize = 16;
+pub const HEARTBEAT_INTERVAL: u64 = 5 * 60;
+pub const STALE_AFTER: u64 = 30 * 60;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct LockId {
+    pub bytes: [u8; LOCK_ID_SZ],
+}
+
+impl LockId {
+    pub fn new() -> LockId {
+        let mut id: LockId = Default::default();
+        random_bytes(&mut id.bytes);
+        id
+    }
+
+    pub fn from_hex(s: &str) -> Result<LockId, RepoError> {
+        let mut id: LockId = Default::default();
+        from_hex(s, &mut id.bytes)?;
+        Ok(id)
+    }
+
+    pub fn to_hex(&self) -> String {
+        to_hex(&self.bytes)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LockInfo {
+    pub format_version: u16,
+    pub id: LockId,
+    pub mode: LockMode,
+    pub created: u64,
+    pub heartbeat: u64,
+}
+
+impl LockInfo {
+    pub fn encode(&self) -> Vec<u8> {
+        let mode = match self.mode {
+            LockMode::Shared => 0,
+            LockMode::Exclusive => 1,
+        };
+        let mut e = Encoder::new();
+        e.fixed(LOCK_MAGIC)
+            .u16(self.format_version)
+            .fixed(&self.id.bytes)
+            .u8(mode)
+            .u64(self.created)
+            .u64(self.heartbeat);
+        e.into_vec()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<LockInfo, RepoError> {
+        let mut d = Decoder::new(buf);
+        if d.fixed(LOCK_MAGIC.len())? != LOCK_MAGIC {
+            return Err(RepoError::InvalidDataError);
+        }
+        let format_version = d.u16()?;
+        if format_version != LOCK_FORMAT_VERSION {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        let mut id: LockId = Default::default();
+        d.fixed_into(&mut id.bytes)?;
+        let mode = match d.u8()? {
+            0 => LockMode::Shared,
+            1 => LockMode::Exclusive,
+            _ => return Err(RepoError::InvalidDataError),
+        };
+        let created = d.u64()?;
+        let heartbeat = d.u64()?;
+        d.finish()?;
+        Ok(LockInfo {
+            format_version,
+            id,
+            mode,
+            created,
+            heartbeat,
+        })
+    }
+
+    pub fn is_stale(&self, now: u64) -> bool {
+        now > self.heartbeat.saturating_add(STALE_AFTER)
+    }
+
+    fn conflicts_with(&self, other: &LockInfo) -> bool {
+        self.mode == LockMode::Exclusive || other.mode == LockMode::Exclusive
+    }
+}
+
+pub fn lock_key(id: &LockId) -> String {
+    format!("{}/{}", LOCKS_DIR, id.to_hex())
+}
+
+pub fn is_lock_key(key: &str) -> bool {
+    key.starts_with(LOCKS_DIR) && key[LOCKS_DIR.len()..].starts_with('/')
+}
+
+// Where `serve` records the client that took the lock `key`, see `acl`.
+pub fn lock_owner_key(key: &str) -> String {
+    format!("{}/{}", LOCK_OWNERS_DIR, &key[LOCKS_DIR.len() + 1..])
+}
+
+pub fn is_lock_owner_key(key: &str) -> bool {
+    key.starts_with(LOCK_OWNERS_DIR) && key[LOCK_OWNERS_DIR.len()..].starts_with('/')
+}
+
+// A held lock, released when dropped.
+pub struct RepoLock {
+    storage: Arc<dyn StorageEngine>,
+    info: LockInfo,
+    released: bool,
+}
+
+impl RepoLock {
+    pub fn info(&self) -> &LockInfo {
+        &self.info
+    }
+
+    // Write a new heartbeat if one is due, long running operations should
+    // call this regularly.
+    pub fn refresh(&mut self) -> Result<(), RepoError> {
+        if unix_now() >= self.info.heartbeat.saturating_add(HEARTBEAT_INTERVAL) {
+            self.heartbeat()?;
+        }
+        Ok(())
+    }
+
+    pub fn heartbeat(&mut self) -> Result<(), RepoError> {
+        let key = lock_key(&self.info.id);
+        // Do not silently take the lock again after `force_unlock`, another
+        // process may have locked the repository in the meantime.
+        if !self.storage.exists(&key)? {
+            return Err(RepoError::LockLostError);
+        }
+        let mut info = self.info.clone();
+        info.heartbeat = unix_now();
+        self.storage.put(&key, &info.encode())?;
+        self.info = info;
+        Ok(())
+    }
+
+    pub fn release(mut self) -> Result<(), RepoError> {
+        self.released = true;
+        self.storage.delete(&lock_key(&self.info.id))
+    }
+}
+
+impl Drop for RepoLock {
+    fn drop(&mut self) {
+        if !self.released {
+            let _ = self.storage.delete(&lock_key(&self.info.id));
+        }
+    }
+}
+
+impl Repo {
+    pub fn lock(&self, mode: LockMode) -> Result<RepoLock, RepoError> {
+        let now = unix_now();
+        let info = LockInfo {
+            format_version: LOCK_FORMAT_VERSION,
+            id: LockId::new(),
+            mode,
+            created: now,
+            heartbeat: now,
+        };
+        self.storage().put(&lock_key(&info.id), &info.encode())?;
+        // From here on dropping the lock on error removes it again.
+        let lock = RepoLock {
+            storage: self.storage().clone(),
+            info,
+            released: false,
+        };
+        for other in self.list_locks()? {
+            if other.id != lock.info.id && !other.is_stale(now) && lock.info.conflicts_with(&other)
+            {
+                return Err(RepoError::RepoLockedError);
+            }
+        }
+        Ok(lock)
+    }
+
+    pub fn list_locks(&self) -> Result<Vec<LockInfo>, RepoError> {
+        let prefix = format!("{}/", LOCKS_DIR);
+        let mut locks = Vec::new();
+        for k in self.storage().list_prefix(&prefix)? {
+            let id = match LockId::from_hex(&k[prefix.len()..]) {
+                Ok(id) => id,
+                Err(_) => continue,
+            };
+            let buf = match self.storage().get(&k) {
+                Ok(buf) => buf,
+                // Released since we listed it.
+                Err(ref e) if e.is_not_found() => continue,
+                Err(e) => return Err(e),
+            };
+            let info = LockInfo::decode(&buf)?;
+            if info.id != id {
+                return Err(RepoError::InvalidDataError);
+            }
+            locks.push(info);
+        }
+        Ok(locks)
+    }
+
+    // Remove every lock, stale or not. Only for operators who know the
+    // lock holders are gone, returns the number of locks removed.
+    pub fn force_unlock(&self) -> Result<usize, RepoError> {
+        let prefix = format!("{}/", LOCKS_DIR);
+        let keys = self.storage().list_prefix(&prefix)?;
+        for k in keys.iter() {
+            match self.storage().delete(k) {
+                Ok(()) => (),
+                Err(ref e) if e.is_not_found() => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(keys.len())
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_lock_modes() {
+    let (r, _) = super::test_repo();
+    let shared1 = r.lock(LockMode::Shared).unwrap();
+    let shared2 = r.lock(LockMode::Shared).unwrap();
+    assert_eq!(r.list_locks().unwrap().len(), 2);
+    match r.lock(LockMode::Exclusive) {
+        Err(RepoError::RepoLockedError) => (),
+        _ => panic!("expected exclusive lock to fail"),
+    }
+    // The failed attempt cleaned up after itself.
+    assert_eq!(r.list_locks().unwrap().len(), 2);
+    drop(shared1);
+    shared2.release().unwrap();
+    assert!(r.list_locks().unwrap().is_empty());
+
+    let exclusive = r.lock(LockMode::Exclusive).unwrap();
+    match r.lock(LockMode::Shared) {
+        Err(RepoError::RepoLockedError) => (),
+        _ => panic!("expected shared lock to fail"),
+    }
+    assert_eq!(r.list_locks().unwrap(), vec![exclusive.info().clone()]);
+}
+
+#[test]
+fn test_lock_stale_and_force_unlock() {
+    let (r, _) = super::test_repo();
+    let now = unix_now();
+    let stale = LockInfo {
+        format_version: LOCK_FORMAT_VERSION,
+        id: LockId::new(),
+        mode: LockMode::Exclusive,
+        created: now - 2 * STALE_AFTER,
+        heartbeat: now - 2 * STALE_AFTER,
+    };
+    assert!(stale.is_stale(now));
+    r.storage()
+        .put(&lock_key(&stale.id), &stale.encode())
+        .unwrap();
+    let mut lock = r.lock(LockMode::Exclusive).unwrap();
+    lock.heartbeat().unwrap();
+    lock.refresh().unwrap();
+
+    assert_eq!(r.force_unlock().unwrap(), 2);
+    assert!(r.list_locks().unwrap().is_empty());
+    match lock.heartbeat() {
+        Err(RepoError::LockLostError) => (),
+        _ => panic!("expected lost lock"),
+    }
+    assert!(r.list_locks().unwrap().is_empty());
+
+    assert!(is_lock_key("locks/00"));
+    assert!(!is_lock_key("locksmith"));
+    assert!(!is_lock_key("packs/00"));
+}
+
+#[test]
+fn test_lock_append_only() {
+    let key = asymcrypt::Key::new();
+    let config = super::config::RepoConfig {
+        append_only: true,
+        ..Default::default()
+    };
+    let storage = Arc::new(super::storage::mem::MemStorage::new());
+    let r = Repo::init(storage, config, &key).unwrap();
+    let mut lock = r.lock(LockMode::Exclusive).unwrap();
+    lock.heartbeat().unwrap();
+    lock.release().unwrap();
+    assert!(r.list_locks().unwrap().is_empty());
+}
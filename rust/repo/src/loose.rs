@@ -0,0 +1,255 @@
+//! Loose objects.
+//!
+//! Small or urgent objects (snapshots, checkpoints) can be written directly
+//! as individual files instead of waiting for a pack to fill up. They are
+//! later moved into packs by the repack step with `Repo::pack_loose_objects`.
+//!
+//! Each loose object is stored at `objects/<hex address>` with the format:
+//!
+//! ```text
+//! "PNBLOOSE" u16:format_version u8:kind [32]:address
+//! [104]:wrapped_object_key [104]:wrapped_object_key_for_metadata
+//! sealed_object
+//! ```
+//!
+//! The object is compressed before it is sealed, as in packs, see
+//! `compress`.
+//!
+//! As in packs, the second wrapped key is there for metadata objects of a
+//! repository with a metadata key, see `keys`, and is all zero otherwise.
+
+use super::address::{Address, ADDRESS_SZ};
+use super::compress::{self, Compressor};
+use super::crypto::{seal, unseal, unwrap_key, wrap_key, SEAL_OVERHEAD, WRAPPED_KEY_SZ};
+use super::index::PackIndex;
+use super::object::ObjectKind;
+use super::pack::{Packer, Recipients};
+use super::wire::{Decoder, Encoder};
+use super::{Repo, RepoError, LOOSE_DIR};
+use tweetnacl::*;
+
+pub const LOOSE_FORMAT_VERSION: u16 = 1;
+const LOOSE_MAGIC: &[u8] = b"PNBLOOSE";
+// Where the object key wrapped to the owner starts.
+pub(crate) const LOOSE_OWNER_KEY_AT: usize = LOOSE_MAGIC.len() + 2 + 1 + ADDRESS_SZ;
+
+pub fn encode_loose(
+    address: &Address,
+    kind: ObjectKind,
+    data: &[u8],
+    to: &Recipients,
+) -> Result<Vec<u8>, RepoError> {
+    let key = CryptoSecretboxKey::new();
+    let metadata = to.metadata.as_ref().filter(|_| to.slot(kind) == 1);
+    let mut e = Encoder::new();
+    e.fixed(LOOSE_MAGIC)
+        .u16(LOOSE_FORMAT_VERSION)
+        .u8(kind.to_u8());
+    address.encode(&mut e);
+    e.fixed(&wrap_key(&key, &to.owner));
+    match metadata {
+        Some(metadata) => e.fixed(&wrap_key(&key, metadata)),
+        None => e.fixed(&[0; WRAPPED_KEY_SZ]),
+    };
+    e.fixed(&seal(&key, &Compressor::new()?.encode(data)?));
+    Ok(e.into_vec())
+}
+
+// The address, kind and wrapped keys of a loose object.
+fn decode_header<'a>(
+    d: &mut Decoder<'a>,
+) -> Result<(Address, ObjectKind, Vec<&'a [u8]>), RepoError> {
+    if d.fixed(LOOSE_MAGIC.len())? != LOOSE_MAGIC {
+        return Err(RepoError::InvalidDataError);
+    }
+    if d.u16()? != LOOSE_FORMAT_VERSION {
+        return Err(RepoError::UnsupportedVersionError);
+    }
+    let kind = ObjectKind::from_u8(d.u8()?)?;
+    let address = Address::decode(d)?;
+    let wrapped = (0..2)
+        .map(|_| d.fixed(WRAPPED_KEY_SZ))
+        .collect::<Result<Vec<_>, _>>()?;
+    let wrapped = wrapped.into_iter().filter(|k| k.iter().any(|&b| b != 0));
+    Ok((address, kind, wrapped.collect()))
+}
+
+pub fn decode_loose(
+    buf: &[u8],
+    sk: &CryptoBoxSk,
+) -> Result<(Address, ObjectKind, Vec<u8>), RepoError> {
+    let mut d = Decoder::new(buf);
+    let (address, kind, wrapped) = decode_header(&mut d)?;
+    let mut key = Err(RepoError::DecryptKeyMismatchError);
+    for w in wrapped {
+        key = unwrap_key(w, sk);
+        if key.is_ok() {
+            break;
+        }
+    }
+    let key = key?;
+    let n = d.remaining();
+    let data = compress::decode(&unseal(&key, d.fixed(n)?)?)?;
+    Ok((address, kind, data))
+}
+
+// The header of a loose object, checking what can be checked without
+// the key.
+pub fn check_loose(buf: &[u8]) -> Result<(Address, ObjectKind), RepoError> {
+    let mut d = Decoder::new(buf);
+    let (address, kind, _) = decode_header(&mut d)?;
+    if d.remaining() <= SEAL_OVERHEAD {
+        return Err(RepoError::InvalidDataError);
+    }
+    Ok((address, kind))
+}
+
+impl Repo {
+    pub(crate) fn loose_key(&self, address: &Address) -> String {
+        format!("{}/{}", LOOSE_DIR, address.to_hex())
+    }
+
+    pub fn put_loose(
+        &self,
+        address: &Address,
+        kind: ObjectKind,
+        data: &[u8],
+    ) -> Result<(), RepoError> {
+        let key = self.loose_key(address);
+        if self.storage().exists(&key)? {
+            return Ok(());
+        }
+        let buf = encode_loose(address, kind, data, &self.recipients())?;
+        self.storage().put(&key, &buf)
+    }
+
+    // Store many small objects with as few round trips as the storage
+    // allows, skipping those already stored.
+    pub fn put_loose_many(
+        &self,
+        objects: &[(Address, ObjectKind, &[u8])],
+    ) -> Result<(), RepoError> {
+        let keys: Vec<String> = objects.iter().map(|o| self.loose_key(&o.0)).collect();
+        let refs: Vec<&str> = keys.iter().map(|k| &k[..]).collect();
+        let exists = self.storage().exists_many(&refs)?;
+        let recipients = self.recipients();
+        let mut new = Vec::new();
+        for (i, (address, kind, data)) in objects.iter().enumerate() {
+            if !exists[i] {
+                let buf = encode_loose(address, *kind, data, &recipients)?;
+                new.push((refs[i], buf));
+            }
+        }
+        let puts: Vec<(&str, &[u8])> = new.iter().map(|(k, buf)| (*k, &buf[..])).collect();
+        self.storage().put_many(&puts)
+    }
+
+    pub fn get_loose(
+        &self,
+        address: &Address,
+        sk: &CryptoBoxSk,
+    ) -> Result<(ObjectKind, Vec<u8>), RepoError> {
+        let buf = self.storage().get(&self.loose_key(address))?;
+        let (stored_address, kind, data) = decode_loose(&buf, sk)?;
+        // The header address is authenticated by nothing but the key, make
+        // sure the two agree so objects cannot be swapped by renaming.
+        if stored_address != *address {
+            return Err(RepoError::CorruptOrTamperedDataError);
+        }
+        Ok((kind, data))
+    }
+
+    pub fn has_loose(&self, address: &Address) -> Result<bool, RepoError> {
+        self.storage().exists(&self.loose_key(address))
+    }
+
+    pub fn list_loose(&self) -> Result<Vec<Address>, RepoError> {
+        let prefix = format!("{}/", LOOSE_DIR);
+        let mut addresses = Vec::new();
+        for k in self.storage().list_prefix(&prefix)? {
+            if let Ok(a) = Address::from_hex(&k[prefix.len()..]) {
+                addresses.push(a);
+            }
+        }
+        Ok(addresses)
+    }
+
+    pub fn delete_loose(&self, address: &Address) -> Result<(), RepoError> {
+        self.storage().delete(&self.loose_key(address))
+    }
+
+    // Move every loose object into packs. Loose objects are only removed
+    // once the packs holding them and their indexes are stored. Refused up
+    // front in append only mode, where the loose objects could never be
+    // removed afterwards.
+    pub fn pack_loose_objects(
+        &self,
+        sk: &CryptoBoxSk,
+        mut packer: Packer,
+    ) -> Result<usize, RepoError> {
+        if self.append_only() {
+            return Err(RepoError::PermissionDeniedError);
+        }
+        let addresses = self.list_loose()?;
+        for a in addresses.iter() {
+            let (kind, data) = self.get_loose(a, sk)?;
+            packer.add(a, kind, &data)?;
+        }
+        for p in packer.finish()? {
+            self.write_pack_index(&PackIndex::from_finished(&p))?;
+        }
+        for a in addresses.iter() {
+            self.delete_loose(a)?;
+        }
+        Ok(addresses.len())
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_loose_round_trip() {
+    let (r, key) = super::test_repo();
+    let a = Address { bytes: [3; 32] };
+    r.put_loose(&a, ObjectKind::Snapshot, b"snap").unwrap();
+    assert!(r.has_loose(&a).unwrap());
+    assert_eq!(r.list_loose().unwrap(), vec![a]);
+    let (kind, data) = r.get_loose(&a, &key.box_sk).unwrap();
+    assert_eq!(kind, ObjectKind::Snapshot);
+    assert_eq!(data, b"snap");
+
+    let b = Address { bytes: [4; 32] };
+    r.storage()
+        .rename(&r.loose_key(&a), &r.loose_key(&b))
+        .unwrap();
+    match r.get_loose(&b, &key.box_sk) {
+        Err(RepoError::CorruptOrTamperedDataError) => (),
+        _ => panic!("expected swapped object to be detected"),
+    }
+
+    let c = Address { bytes: [5; 32] };
+    r.put_loose_many(&[
+        (a, ObjectKind::Tree, &b"ignored"[..]),
+        (c, ObjectKind::Tree, &b"tree"[..]),
+    ])
+    .unwrap();
+    assert!(r.has_loose(&a).unwrap());
+    assert_eq!(r.get_loose(&c, &key.box_sk).unwrap().1, b"tree");
+}
+
+#[test]
+fn test_pack_loose_objects() {
+    let (r, key) = super::test_repo();
+    for i in 0..5 {
+        let a = Address { bytes: [i; 32] };
+        r.put_loose(&a, ObjectKind::Tree, &[i; 100]).unwrap();
+    }
+    let packer = r.packer(Default::default());
+    assert_eq!(r.pack_loose_objects(&key.box_sk, packer).unwrap(), 5);
+    assert!(r.list_loose().unwrap().is_empty());
+    let idx = r.load_index().unwrap();
+    for i in 0..5 {
+        let loc = idx.lookup(&Address { bytes: [i; 32] }).unwrap();
+        assert_eq!(loc.kind, ObjectKind::Tree);
+    }
+}
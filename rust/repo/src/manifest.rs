@@ -0,0 +1,511 @@
+//! The repository manifest.
+//!
+//! The manifest is the single authenticated entry point into a repository.
+//! It is signed by the repository owner and replaced atomically on every
+//! commit, so a reader either sees the old or the new set of snapshot heads,
+//! never a mixture.
+//!
+//! Format (inside the signature envelope, see `signed`):
+//!
+//! ```text
+//! "PNBMANIFEST" u16:format_version [16]:repo_id
+//! u32:chunk_min u32:chunk_avg u32:chunk_max u8:hash_algorithm
+//! u64:counter [32]:previous u64:log_size [32]:log_root
+//! u32:n_writers n_writers * ([32]:sign_pk str:namespace)
+//! u32:n_heads n_heads * ([32]:snapshot_address u64:unix_time str:namespace
+//!                        u64:retain_until bool:amended [32]:amendment)
+//! u32:n_old_keys n_old_keys * ([32]:box_pk bool:retired)
+//! ```
+//!
+//! Strings are encoded as in `wire`, a u32 length followed by utf8 bytes.
+//! The amendment address is only present if `amended` is set, it names
+//! the object replacing the tags and description of the snapshot, see
+//! `tree`.
+//!
+//! Each commit writes a manifest whose `counter` is one more than that of
+//! the manifest it replaces, and whose `previous` is that manifest's hash,
+//! see `manifest_hash`. The first manifest has counter 1 and an all zero
+//! `previous`. A client can then tell a repository rolled back to an
+//! older copy, see `rollback`.
+//!
+//! `log_size` and `log_root` are the tree head of the log of every
+//! manifest replaced so far, see `translog`.
+//!
+//! The old keys are owner box keys replaced by `Repo::rotate_key`, retiring
+//! while objects may still be sealed to them and retired once none are,
+//! see `rotate`.
+//!
+//! A head with `retain_until` in the future is under a retention lock: no
+//! replacement manifest may drop it or shorten its lock until then, see
+//! `Manifest::check_retention`. Clients check this before every commit and
+//! `serve` enforces it. A `retain_until` of 0 means no lock. The lock
+//! covers the snapshot, not its metadata: a locked head may still be
+//! amended.
+
+use super::address::Address;
+use super::namespace::Namespace;
+use super::signed;
+use super::wire::{Decoder, Encoder};
+use super::RepoError;
+use tweetnacl::*;
+
+pub const MANIFEST_FORMAT_VERSION: u16 = 1;
+const MANIFEST_MAGIC: &[u8] = b"PNBMANIFEST";
+
+pub const REPO_ID_SZ: usize = 16;
+pub const MANIFEST_HASH_SZ: usize = 32;
+
+pub type ManifestHash = [u8; MANIFEST_HASH_SZ];
+
+// The hash of a signed manifest as stored, the first 32 bytes of its
+// sha512.
+pub fn manifest_hash(sm: &[u8]) -> ManifestHash {
+    let mut h = [0; CRYPTO_HASH_BYTES];
+    crypto_hash(&mut h, sm);
+    let mut m = [0; MANIFEST_HASH_SZ];
+    m.copy_from_slice(&h[..MANIFEST_HASH_SZ]);
+    m
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct RepoId {
+    pub bytes: [u8; REPO_ID_SZ],
+}
+
+impl RepoId {
+    pub fn new() -> RepoId {
+        let mut id: RepoId = Default::default();
+        random_bytes(&mut id.bytes);
+        id
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HashAlgorithm {
+    HmacSha512_256,
+}
+
+impl HashAlgorithm {
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            HashAlgorithm::HmacSha512_256 => 0,
+        }
+    }
+
+    pub(crate) fn from_u8(v: u8) -> Result<HashAlgorithm, RepoError> {
+        match v {
+            0 => Ok(HashAlgorithm::HmacSha512_256),
+            _ => Err(RepoError::InvalidDataError),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ChunkerParams {
+    pub min_size: u32,
+    pub avg_size: u32,
+    pub max_size: u32,
+}
+
+impl Default for ChunkerParams {
+    fn default() -> ChunkerParams {
+        ChunkerParams {
+            min_size: 256 * 1024,
+            avg_size: 1024 * 1024,
+            max_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+impl ChunkerParams {
+    pub fn is_valid(&self) -> bool {
+        self.min_size > 0
+            && self.min_size <= self.avg_size
+            && self.avg_size <= self.max_size
+            && self.avg_size.is_power_of_two()
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Writer {
+    pub sign_pk: CryptoSignPk,
+    pub namespace: Namespace,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SnapshotHead {
+    pub address: Address,
+    pub timestamp: u64,
+    pub namespace: Namespace,
+    // Unix time until which the head must be kept.
+    pub retain_until: u64,
+    // The newest amendment of the snapshot's metadata.
+    pub amendment: Option<Address>,
+}
+
+impl SnapshotHead {
+    // The objects the head references directly.
+    pub fn roots(&self) -> Vec<Address> {
+        let mut roots = vec![self.address];
+        roots.extend(self.amendment);
+        roots
+    }
+
+    pub fn is_retained(&self, now: u64) -> bool {
+        self.retain_until > now
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct OldKey {
+    pub box_pk: CryptoBoxPk,
+    // No object is sealed to the key any more.
+    pub retired: bool,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Manifest {
+    pub format_version: u16,
+    pub repo_id: RepoId,
+    pub chunker: ChunkerParams,
+    pub hash: HashAlgorithm,
+    // One more with every commit, and the hash of the manifest replaced.
+    pub counter: u64,
+    pub previous: ManifestHash,
+    // The tree head of the log of manifests replaced.
+    pub log_size: u64,
+    pub log_root: ManifestHash,
+    pub writers: Vec<Writer>,
+    pub heads: Vec<SnapshotHead>,
+    pub old_keys: Vec<OldKey>,
+}
+
+impl Manifest {
+    pub fn new(repo_id: RepoId, chunker: ChunkerParams) -> Manifest {
+        Manifest {
+            format_version: MANIFEST_FORMAT_VERSION,
+            repo_id,
+            chunker,
+            hash: HashAlgorithm::HmacSha512_256,
+            counter: 0,
+            previous: [0; MANIFEST_HASH_SZ],
+            log_size: 0,
+            log_root: [0; MANIFEST_HASH_SZ],
+            writers: Vec::new(),
+            heads: Vec::new(),
+            old_keys: Vec::new(),
+        }
+    }
+
+    pub fn add_writer(
+        &mut self,
+        sign_pk: CryptoSignPk,
+        namespace: Namespace,
+    ) -> Result<(), RepoError> {
+        if self
+            .writers
+            .iter()
+            .any(|w| w.sign_pk == sign_pk || w.namespace == namespace)
+        {
+            return Err(RepoError::DuplicateWriterError);
+        }
+        self.writers.push(Writer { sign_pk, namespace });
+        Ok(())
+    }
+
+    pub fn writer_namespace(&self, sign_pk: &CryptoSignPk) -> Result<&Namespace, RepoError> {
+        match self.writers.iter().find(|w| w.sign_pk == *sign_pk) {
+            Some(w) => Ok(&w.namespace),
+            None => Err(RepoError::UnknownWriterError),
+        }
+    }
+
+    pub fn namespace_heads<'a>(
+        &'a self,
+        namespace: &'a Namespace,
+    ) -> impl Iterator<Item = &'a SnapshotHead> + 'a {
+        self.heads.iter().filter(move |h| h.namespace == *namespace)
+    }
+
+    pub fn add_head(&mut self, head: SnapshotHead) {
+        self.heads.push(head);
+    }
+
+    pub fn remove_head(&mut self, address: &Address) -> bool {
+        let n = self.heads.len();
+        self.heads.retain(|h| h.address != *address);
+        n != self.heads.len()
+    }
+
+    // Lock the head at `address` until `until`. Locks are only ever
+    // extended, an earlier `until` leaves the lock as it is.
+    pub fn retain(&mut self, address: &Address, until: u64) -> Result<(), RepoError> {
+        match self.heads.iter_mut().find(|h| h.address == *address) {
+            Some(h) => {
+                h.retain_until = h.retain_until.max(until);
+                Ok(())
+            }
+            None => Err(RepoError::MissingObjectError),
+        }
+    }
+
+    pub fn old_key(&self, box_pk: &CryptoBoxPk) -> Option<&OldKey> {
+        self.old_keys.iter().find(|k| k.box_pk == *box_pk)
+    }
+
+    // Mark `box_pk` as replaced, retired once nothing is sealed to it.
+    // A retired key is never made retiring again.
+    pub fn retire_key(&mut self, box_pk: &CryptoBoxPk, retired: bool) {
+        match self.old_keys.iter_mut().find(|k| k.box_pk == *box_pk) {
+            Some(k) => k.retired |= retired,
+            None => self.old_keys.push(OldKey {
+                box_pk: box_pk.clone(),
+                retired,
+            }),
+        }
+    }
+
+    // Whether any head is under a retention lock at `now`.
+    pub fn is_retained(&self, now: u64) -> bool {
+        self.heads.iter().any(|h| h.is_retained(now))
+    }
+
+    // Check that `new` may replace this manifest at `now`: every head
+    // locked now is still there, unchanged but for a lock at least as long.
+    pub fn check_retention(&self, new: &Manifest, now: u64) -> Result<(), RepoError> {
+        for h in self.heads.iter().filter(|h| h.is_retained(now)) {
+            let kept = new.heads.iter().any(|n| {
+                n.address == h.address
+                    && n.timestamp == h.timestamp
+                    && n.namespace == h.namespace
+                    && n.retain_until >= h.retain_until
+            });
+            if !kept {
+                return Err(RepoError::RetentionLockedError);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.fixed(MANIFEST_MAGIC)
+            .u16(MANIFEST_FORMAT_VERSION)
+            .fixed(&self.repo_id.bytes)
+            .u32(self.chunker.min_size)
+            .u32(self.chunker.avg_size)
+            .u32(self.chunker.max_size)
+            .u8(self.hash.to_u8())
+            .u64(self.counter)
+            .fixed(&self.previous)
+            .u64(self.log_size)
+            .fixed(&self.log_root)
+            .u32(self.writers.len() as u32);
+        for w in self.writers.iter() {
+            e.fixed(&w.sign_pk.bytes);
+            w.namespace.encode(&mut e);
+        }
+        e.u32(self.heads.len() as u32);
+        for h in self.heads.iter() {
+            h.address.encode(&mut e);
+            e.u64(h.timestamp);
+            h.namespace.encode(&mut e);
+            e.u64(h.retain_until).bool(h.amendment.is_some());
+            if let Some(ref a) = h.amendment {
+                a.encode(&mut e);
+            }
+        }
+        e.u32(self.old_keys.len() as u32);
+        for k in self.old_keys.iter() {
+            e.fixed(&k.box_pk.bytes).bool(k.retired);
+        }
+        e.into_vec()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Manifest, RepoError> {
+        let mut d = Decoder::new(buf);
+        if d.fixed(MANIFEST_MAGIC.len())? != MANIFEST_MAGIC {
+            return Err(RepoError::InvalidDataError);
+        }
+        if d.u16()? != MANIFEST_FORMAT_VERSION {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        let mut repo_id: RepoId = Default::default();
+        d.fixed_into(&mut repo_id.bytes)?;
+        let chunker = ChunkerParams {
+            min_size: d.u32()?,
+            avg_size: d.u32()?,
+            max_size: d.u32()?,
+        };
+        if !chunker.is_valid() {
+            return Err(RepoError::InvalidDataError);
+        }
+        let hash = HashAlgorithm::from_u8(d.u8()?)?;
+        let counter = d.u64()?;
+        let mut previous = [0; MANIFEST_HASH_SZ];
+        d.fixed_into(&mut previous)?;
+        let log_size = d.u64()?;
+        let mut log_root = [0; MANIFEST_HASH_SZ];
+        d.fixed_into(&mut log_root)?;
+        let n_writers = d.count(36)?;
+        let mut writers = Vec::with_capacity(n_writers);
+        for _ in 0..n_writers {
+            let mut sign_pk: CryptoSignPk = Default::default();
+            d.fixed_into(&mut sign_pk.bytes)?;
+            writers.push(Writer {
+                sign_pk,
+                namespace: Namespace::decode(&mut d)?,
+            });
+        }
+        let n_heads = d.count(52)?;
+        let mut heads = Vec::with_capacity(n_heads);
+        for _ in 0..n_heads {
+            let mut head = SnapshotHead {
+                address: Address::decode(&mut d)?,
+                timestamp: d.u64()?,
+                namespace: Namespace::decode(&mut d)?,
+                retain_until: d.u64()?,
+                amendment: None,
+            };
+            if d.bool()? {
+                head.amendment = Some(Address::decode(&mut d)?);
+            }
+            heads.push(head);
+        }
+        let mut old_keys = Vec::new();
+        for _ in 0..d.count(33)? {
+            let mut box_pk: CryptoBoxPk = Default::default();
+            d.fixed_into(&mut box_pk.bytes)?;
+            old_keys.push(OldKey {
+                box_pk,
+                retired: d.bool()?,
+            });
+        }
+        d.finish()?;
+        Ok(Manifest {
+            format_version: MANIFEST_FORMAT_VERSION,
+            repo_id,
+            chunker,
+            hash,
+            counter,
+            previous,
+            log_size,
+            log_root,
+            writers,
+            heads,
+            old_keys,
+        })
+    }
+
+    pub fn sign(&self, sk: &CryptoSignSk) -> Vec<u8> {
+        signed::sign(&self.encode(), sk)
+    }
+
+    pub fn open(sm: &[u8], pk: &CryptoSignPk) -> Result<Manifest, RepoError> {
+        Manifest::decode(&signed::open(sm, pk)?)
+    }
+}
+
+// Tests --------------------
+
+#[cfg(test)]
+fn test_manifest() -> Manifest {
+    let (pk, _) = boxed_crypto_sign_keypair();
+    let ns = Namespace::new("laptop").unwrap();
+    let mut m = Manifest::new(RepoId::new(), Default::default());
+    m.add_writer((*pk).clone(), ns.clone()).unwrap();
+    m.add_head(SnapshotHead {
+        address: Address { bytes: [7; 32] },
+        timestamp: 1234,
+        namespace: ns,
+        retain_until: 0,
+        amendment: None,
+    });
+    m
+}
+
+#[test]
+fn test_manifest_sign_open() {
+    let (pk, sk) = boxed_crypto_sign_keypair();
+    let m = test_manifest();
+    let sm = m.sign(&sk);
+    assert_eq!(Manifest::open(&sm, &pk).unwrap(), m);
+
+    let (pk2, _) = boxed_crypto_sign_keypair();
+    match Manifest::open(&sm, &pk2) {
+        Err(RepoError::SignatureFailedError) => (),
+        _ => panic!("expected signature failure"),
+    }
+}
+
+#[test]
+fn test_manifest_writers() {
+    let mut m = test_manifest();
+    let (pk, _) = boxed_crypto_sign_keypair();
+    let ns = Namespace::new("server").unwrap();
+    assert!(m.writer_namespace(&pk).is_err());
+    assert!(m
+        .add_writer((*pk).clone(), Namespace::new("laptop").unwrap())
+        .is_err());
+    m.add_writer((*pk).clone(), ns.clone()).unwrap();
+    assert!(m.add_writer((*pk).clone(), ns.clone()).is_err());
+    assert_eq!(*m.writer_namespace(&pk).unwrap(), ns);
+    assert_eq!(m.namespace_heads(&ns).count(), 0);
+    assert_eq!(
+        m.namespace_heads(&Namespace::new("laptop").unwrap())
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn test_manifest_bad_version() {
+    let mut buf = test_manifest().encode();
+    buf[MANIFEST_MAGIC.len() + 1] = 99;
+    match Manifest::decode(&buf) {
+        Err(RepoError::UnsupportedVersionError) => (),
+        _ => panic!("expected version error"),
+    }
+}
+
+#[test]
+fn test_manifest_retention() {
+    let mut m = test_manifest();
+    let address = m.heads[0].address;
+    assert!(!m.is_retained(0));
+    m.retain(&address, 100).unwrap();
+    m.retain(&address, 50).unwrap();
+    assert_eq!(m.heads[0].retain_until, 100);
+    assert!(m.retain(&Address { bytes: [8; 32] }, 100).is_err());
+    assert!(m.is_retained(99) && !m.is_retained(100));
+
+    // A locked head can be extended but not dropped or shortened.
+    let mut extended = m.clone();
+    extended.retain(&address, 200).unwrap();
+    m.check_retention(&extended, 99).unwrap();
+    let mut shortened = m.clone();
+    shortened.heads[0].retain_until = 99;
+    let mut dropped = m.clone();
+    dropped.remove_head(&address);
+    for new in [shortened, dropped.clone()].iter() {
+        match m.check_retention(new, 99) {
+            Err(RepoError::RetentionLockedError) => (),
+            _ => panic!("expected retention lock"),
+        }
+    }
+    // Expired locks hold nothing.
+    m.check_retention(&dropped, 100).unwrap();
+}
+
+#[test]
+fn test_manifest_old_keys() {
+    let mut m = test_manifest();
+    let (pk, _) = boxed_crypto_box_keypair();
+    assert!(m.old_key(&pk).is_none());
+    m.retire_key(&pk, false);
+    m.retire_key(&pk, true);
+    m.retire_key(&pk, false);
+    assert_eq!(m.old_keys.len(), 1);
+    assert!(m.old_key(&pk).unwrap().retired);
+    assert_eq!(Manifest::decode(&m.encode()).unwrap(), m);
+}
@@ -0,0 +1,154 @@
+//! Fitting a backup into a memory budget.
+//!
+//! A backup's memory is mostly what it holds on purpose, and with
+//! `BackupOptions::max_memory` it sizes those to fit, so a NAS or a small
+//! VPS backs up slower rather than running out of memory. The estimate
+//! is:
+//!
+//! ```text
+//! base     BASE_MEMORY for code, stacks and buffers
+//! index    INDEX_ENTRY_MEMORY per object in the repository, whose index
+//!          is loaded whole to deduplicate against, see `index`
+//! batch    `pending_bytes` of small files and trees waiting to be sealed
+//! chunks   `queue_depth` chunks queued for the workers and as many
+//!          compressed for the sealers, and one per worker and sealer,
+//!          each up to the chunker's `max_size`
+//! zstd     ZSTD_CONTEXT_MEMORY per worker for its compression context,
+//!          see `compress`
+//! packs    one being filled per sealer and two per upload thread, see
+//!          `upload`, each up to `target_size` and one more chunk
+//! ```
+//!
+//! While the estimate is over the budget `fit` gives up, in order, upload
+//! threads, sealers, workers and their queues, half the batch down to
+//! `MIN_PENDING_BYTES`, and half the pack size down to `MIN_PACK_SIZE`.
+//! The index cannot shrink: a budget it does not leave room in fails the
+//! backup before anything is read. Directory listings and the stat cache
+//! are not counted, they are small next to the rest.
+
+use super::backup::BackupOptions;
+use super::compress::ZSTD_CONTEXT_MEMORY;
+use super::manifest::ChunkerParams;
+use super::RepoError;
+use std::io;
+
+pub const BASE_MEMORY: u64 = 16 << 20;
+pub const INDEX_ENTRY_MEMORY: u64 = 80;
+pub const MIN_PENDING_BYTES: u64 = 1 << 20;
+pub const MIN_PACK_SIZE: u64 = 4 << 20;
+
+// What a backup with `opts` holds, but for the base and the index.
+pub fn estimate(opts: &BackupOptions, chunker: &ChunkerParams) -> u64 {
+    let u = &opts.upload;
+    let chunk = chunker.max_size as u64;
+    let packs = (u.sealers + 2 * u.uploads) as u64;
+    opts.pending_bytes
+        + (2 * u.queue_depth + u.workers + u.sealers) as u64 * chunk
+        + u.workers as u64 * ZSTD_CONTEXT_MEMORY
+        + packs * (u.packer.target_size + chunk)
+}
+
+// `opts` cut down to fit `opts.max_memory` with an index of `index_len`
+// objects, see the module documentation.
+pub fn fit(
+    opts: &BackupOptions,
+    chunker: &ChunkerParams,
+    index_len: usize,
+) -> Result<BackupOptions, RepoError> {
+    let mut fitted = opts.clone();
+    let budget = match opts.max_memory {
+        Some(budget) => budget,
+        None => return Ok(fitted),
+    };
+    let fixed = BASE_MEMORY + index_len as u64 * INDEX_ENTRY_MEMORY;
+    loop {
+        let needed = fixed + estimate(&fitted, chunker);
+        if needed <= budget {
+            return Ok(fitted);
+        }
+        let u = &mut fitted.upload;
+        if u.uploads > 1 {
+            u.uploads -= 1;
+        } else if u.sealers > 1 {
+            u.sealers -= 1;
+        } else if u.workers > 1 {
+            u.workers -= 1;
+            u.queue_depth = u.queue_depth.min(2 * u.workers);
+        } else if u.queue_depth > 1 {
+            u.queue_depth = 1;
+        } else if fitted.pending_bytes > MIN_PENDING_BYTES {
+            fitted.pending_bytes = (fitted.pending_bytes / 2).max(MIN_PENDING_BYTES);
+        } else if u.packer.target_size > MIN_PACK_SIZE {
+            u.packer.target_size = (u.packer.target_size / 2).max(MIN_PACK_SIZE);
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "a backup needs at least {} MiB of memory, {} MiB is allowed",
+                    needed.div_ceil(1 << 20),
+                    budget >> 20
+                ),
+            )
+            .into());
+        }
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_fit() {
+    use super::upload::UploadOptions;
+    let chunker: ChunkerParams = Default::default();
+    let opts = BackupOptions {
+        upload: UploadOptions::with_jobs(8),
+        ..Default::default()
+    };
+    let unfitted = fit(&opts, &chunker, 1_000_000).unwrap();
+    assert_eq!(estimate(&unfitted, &chunker), estimate(&opts, &chunker));
+
+    // Threads go first, then the batch, then pack sizes.
+    for (budget, workers, sealers, pending, target) in [
+        (1u64 << 30, 8, 2, 32 << 20, 128 << 20),
+        (512 << 20, 1, 1, 1 << 20, 64 << 20),
+        (256 << 20, 1, 1, 1 << 20, 32 << 20),
+    ]
+    .iter()
+    {
+        let budgeted = BackupOptions {
+            max_memory: Some(*budget),
+            ..opts.clone()
+        };
+        let fitted = fit(&budgeted, &chunker, 1_000_000).unwrap();
+        let u = &fitted.upload;
+        assert_eq!(
+            (
+                u.workers,
+                u.sealers,
+                fitted.pending_bytes,
+                u.packer.target_size
+            ),
+            (*workers, *sealers, *pending, *target),
+            "{}",
+            budget
+        );
+        assert!(BASE_MEMORY + 80_000_000 + estimate(&fitted, &chunker) <= *budget);
+    }
+
+    // The index alone can be too much.
+    let small = BackupOptions {
+        max_memory: Some(96 << 20),
+        ..opts
+    };
+    assert!(fit(&small, &chunker, 0).is_ok());
+    match fit(&small, &chunker, 1_000_000) {
+        Err(RepoError::IOError(e)) => {
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+            assert!(e.to_string().contains("96 MiB is allowed"), "{}", e);
+        }
+        r => panic!(
+            "expected the budget to be too small, got {:?}",
+            r.map(|_| ())
+        ),
+    }
+}
@@ -0,0 +1,479 @@
+//! Metrics of `serve`, for Prometheus.
+//!
+//! A repository host is monitored like any other service. `ServeMetrics`
+//! counts what the sessions sharing it do, see `serve_shared`: sessions
+//! open and served, requests and failed requests by operation and by the
+//! capability they need, see `acl`, bytes received and sent, and the
+//! sweeps gc runs. Prometheus derives rates, requests a second say, from
+//! the counters.
+//!
+//! When rendered the counters are joined by gauges read from the
+//! repository, or from each tenant's, labelled with the tenant, see
+//! `tenant`: the bytes of packs and loose objects stored, each client's
+//! usage, see `usage`, and when scrub, the fsck run piecemeal, last
+//! verified a pack, see `scrub`, or a check the server scheduled last
+//! found nothing wrong, see `checks`. Reading them lists the stored
+//! objects, so metrics are best scraped every minute or so rather than
+//! every second.
+//!
+//! They are exported in the Prometheus text format, either written to a
+//! file for node_exporter's textfile collector, `write_textfile`, or
+//! answered over HTTP by `serve_metrics`, which the process running the
+//! sessions starts on a thread of its own. A server started per
+//! connection only counts its own session, its gauges are still good.
+//!
+//! ```text
+//! packnback_sessions_active                                   gauge
+//! packnback_sessions_total                                    counter
+//! packnback_requests_total{op,capability}                     counter
+//! packnback_request_errors_total{op,capability}               counter
+//! packnback_received_bytes_total                              counter
+//! packnback_sent_bytes_total                                  counter
+//! packnback_gc_running                                        gauge
+//! packnback_gc_runs_total                                     counter
+//! packnback_gc_deleted_packs_total                            counter
+//! packnback_gc_reclaimed_bytes_total                          counter
+//! packnback_gc_last_timestamp_seconds                         gauge
+//! packnback_stored_bytes{tenant}                              gauge
+//! packnback_client_stored_bytes{tenant,client}                gauge
+//! packnback_last_fsck_timestamp_seconds{tenant}               gauge
+//! ```
+//!
+//! Label values are operation, capability, tenant and client names, which
+//! never need escaping.
+
+use super::acl::capability_names;
+use super::checks::read_check;
+use super::datetime::unix_now;
+use super::gc::GcStats;
+use super::storage::StorageEngine;
+use super::tenant::{list_tenants, tenant_storage};
+use super::{Repo, RepoError, LOOSE_DIR, OWNER_KEY_FILE, PACKS_DIR};
+use asymcrypt::PublicKey;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Default)]
+struct RequestCounts {
+    requests: u64,
+    errors: u64,
+}
+
+#[derive(Default)]
+pub struct ServeMetrics {
+    sessions_active: AtomicU64,
+    sessions: AtomicU64,
+    // By operation and the capability it needs.
+    requests: Mutex<BTreeMap<(&'static str, &'static str), RequestCounts>>,
+    received_bytes: AtomicU64,
+    sent_bytes: AtomicU64,
+    gc_running: AtomicU64,
+    gc_runs: AtomicU64,
+    gc_deleted_packs: AtomicU64,
+    gc_reclaimed_bytes: AtomicU64,
+    gc_last: AtomicU64,
+}
+
+// Counts a session or a sweep as running until dropped.
+pub(crate) struct Running<'a>(&'a AtomicU64);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// The capability named for requests needing `caps`, "none" for those any
+// client listed may make.
+fn capability_name(caps: u32) -> &'static str {
+    capability_names(caps).first().copied().unwrap_or("none")
+}
+
+// Append a metric in the text format, with its samples by labels.
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, u64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, v) in samples.iter() {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, v);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, v);
+        }
+    }
+}
+
+fn tenant_label(tenant: Option<&str>) -> String {
+    match tenant {
+        Some(tenant) => format!("tenant=\"{}\"", tenant),
+        None => String::new(),
+    }
+}
+
+fn join_labels(a: &str, b: &str) -> String {
+    match (a.is_empty(), b.is_empty()) {
+        (true, _) => b.to_string(),
+        (_, true) => a.to_string(),
+        _ => format!("{},{}", a, b),
+    }
+}
+
+#[derive(Default)]
+struct RepoGauges {
+    stored: Vec<(String, u64)>,
+    clients: Vec<(String, u64)>,
+    fsck: Vec<(String, u64)>,
+}
+
+impl RepoGauges {
+    // Read the gauges of the repository in `storage`, skipped if it has
+    // none yet.
+    fn read(
+        &mut self,
+        storage: &Arc<dyn StorageEngine>,
+        tenant: Option<&str>,
+    ) -> Result<(), RepoError> {
+        let owner = match storage.get(OWNER_KEY_FILE) {
+            Ok(buf) => PublicKey::read_from(&mut &buf[..])?,
+            Err(ref e) if e.is_not_found() => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let repo = Repo::open(storage.clone(), &owner)?;
+        let label = tenant_label(tenant);
+        let mut stored = 0;
+        for dir in [PACKS_DIR, LOOSE_DIR].iter() {
+            for key in storage.list_prefix(&format!("{}/", dir))?.iter() {
+                stored += storage.size(key)?;
+            }
+        }
+        self.stored.push((label.clone(), stored));
+        for (client, u) in repo.usage()? {
+            let client = format!("client=\"{}\"", client);
+            self.clients
+                .push((join_labels(&label, &client), u.stored_bytes));
+        }
+        let scrubbed = repo.scrub_state()?.last_verified.values().max().copied();
+        let checked = read_check(&**storage)?
+            .filter(|check| check.errors == 0)
+            .map(|check| check.time);
+        if let Some(last) = scrubbed.max(checked) {
+            self.fsck.push((label, last));
+        }
+        Ok(())
+    }
+}
+
+impl ServeMetrics {
+    pub fn new() -> ServeMetrics {
+        Default::default()
+    }
+
+    pub(crate) fn session(&self) -> Running<'_> {
+        self.sessions.fetch_add(1, Ordering::Relaxed);
+        self.sessions_active.fetch_add(1, Ordering::Relaxed);
+        Running(&self.sessions_active)
+    }
+
+    // Count a request `op` needing the capabilities `caps`, received in
+    // `received` bytes and answered in `sent`.
+    pub(crate) fn request(
+        &self,
+        op: &'static str,
+        caps: u32,
+        received: u64,
+        sent: u64,
+        failed: bool,
+    ) {
+        self.received_bytes.fetch_add(received, Ordering::Relaxed);
+        self.sent_bytes.fetch_add(sent, Ordering::Relaxed);
+        let mut requests = self.requests.lock().unwrap();
+        let counts = requests.entry((op, capability_name(caps))).or_default();
+        counts.requests += 1;
+        if failed {
+            counts.errors += 1;
+        }
+    }
+
+    pub(crate) fn gc(&self) -> Running<'_> {
+        self.gc_running.fetch_add(1, Ordering::Relaxed);
+        Running(&self.gc_running)
+    }
+
+    pub(crate) fn gc_done(&self, stats: &GcStats) {
+        self.gc_runs.fetch_add(1, Ordering::Relaxed);
+        self.gc_deleted_packs
+            .fetch_add(stats.deleted_packs.len() as u64, Ordering::Relaxed);
+        self.gc_reclaimed_bytes
+            .fetch_add(stats.reclaimed_bytes, Ordering::Relaxed);
+        self.gc_last.store(unix_now(), Ordering::Relaxed);
+    }
+
+    // The metrics in the Prometheus text format, with the gauges of the
+    // repository in `root`, or of each of its tenants.
+    pub fn render(
+        &self,
+        root: &Arc<dyn StorageEngine>,
+        tenants: bool,
+    ) -> Result<String, RepoError> {
+        let mut gauges = RepoGauges::default();
+        if tenants {
+            for name in list_tenants(&**root)?.iter() {
+                gauges.read(&tenant_storage(root, name)?, Some(name))?;
+            }
+        } else {
+            gauges.read(root, None)?;
+        }
+
+        let load = |v: &AtomicU64| vec![(String::new(), v.load(Ordering::Relaxed))];
+        let requests = self.requests.lock().unwrap().clone();
+        let by_op = |f: fn(&RequestCounts) -> u64| -> Vec<(String, u64)> {
+            requests
+                .iter()
+                .map(|((op, cap), counts)| {
+                    (format!("op=\"{}\",capability=\"{}\"", op, cap), f(counts))
+                })
+                .collect()
+        };
+        let gc_last = match self.gc_last.load(Ordering::Relaxed) {
+            0 => Vec::new(),
+            t => vec![(String::new(), t)],
+        };
+        let metrics = [
+            (
+                "sessions_active",
+                "gauge",
+                "Sessions being served.",
+                load(&self.sessions_active),
+            ),
+            (
+                "sessions_total",
+                "counter",
+                "Sessions started.",
+                load(&self.sessions),
+            ),
+            (
+                "requests_total",
+                "counter",
+                "Requests answered.",
+                by_op(|c| c.requests),
+            ),
+            (
+                "request_errors_total",
+                "counter",
+                "Requests refused or failed.",
+                by_op(|c| c.errors),
+            ),
+            (
+                "received_bytes_total",
+                "counter",
+                "Bytes of requests received.",
+                load(&self.received_bytes),
+            ),
+            (
+                "sent_bytes_total",
+                "counter",
+                "Bytes of responses sent.",
+                load(&self.sent_bytes),
+            ),
+            (
+                "gc_running",
+                "gauge",
+                "Garbage collection sweeps running.",
+                load(&self.gc_running),
+            ),
+            (
+                "gc_runs_total",
+                "counter",
+                "Garbage collection sweeps finished.",
+                load(&self.gc_runs),
+            ),
+            (
+                "gc_deleted_packs_total",
+                "counter",
+                "Packs garbage collection deleted.",
+                load(&self.gc_deleted_packs),
+            ),
+            (
+                "gc_reclaimed_bytes_total",
+                "counter",
+                "Bytes garbage collection reclaimed.",
+                load(&self.gc_reclaimed_bytes),
+            ),
+            (
+                "gc_last_timestamp_seconds",
+                "gauge",
+                "When the last sweep finished.",
+                gc_last,
+            ),
+            (
+                "stored_bytes",
+                "gauge",
+                "Bytes of packs and loose objects stored.",
+                gauges.stored,
+            ),
+            (
+                "client_stored_bytes",
+                "gauge",
+                "Bytes stored by each client.",
+                gauges.clients,
+            ),
+            (
+                "last_fsck_timestamp_seconds",
+                "gauge",
+                "When scrub last verified a pack or a check found no errors.",
+                gauges.fsck,
+            ),
+        ];
+        let mut out = String::new();
+        for (name, kind, help, samples) in metrics.iter() {
+            metric(
+                &mut out,
+                &format!("packnback_{}", name),
+                kind,
+                help,
+                samples,
+            );
+        }
+        Ok(out)
+    }
+
+    // Replace the file at `path`, for node_exporter's textfile collector,
+    // which must never see it half written.
+    pub fn write_textfile(
+        &self,
+        path: &Path,
+        root: &Arc<dyn StorageEngine>,
+        tenants: bool,
+    ) -> Result<(), RepoError> {
+        let text = self.render(root, tenants)?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+// Answer every HTTP request on `listener` with the metrics, until
+// accepting fails. Requests are answered one at a time, a scraper that
+// stalls holds up the others.
+pub fn serve_metrics(
+    listener: TcpListener,
+    metrics: &ServeMetrics,
+    root: &Arc<dyn StorageEngine>,
+    tenants: bool,
+) -> Result<(), RepoError> {
+    for conn in listener.incoming() {
+        let conn = conn?;
+        // One scraper's broken connection is no reason to stop.
+        let _ = (|| -> Result<(), RepoError> {
+            let mut r = BufReader::new(conn.try_clone()?);
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if r.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+                    break;
+                }
+            }
+            let (status, body) = match metrics.render(root, tenants) {
+                Ok(body) => ("200 OK", body),
+                Err(err) => ("500 Internal Server Error", format!("{}\n", err)),
+            };
+            let mut w = io::BufWriter::new(&conn);
+            write!(
+                w,
+                "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )?;
+            w.flush()?;
+            Ok(())
+        })();
+    }
+    Ok(())
+}
+
+// Tests --------------------
+
+#[test]
+fn test_serve_metrics() {
+    use super::protocol::{self, Request};
+    use super::serve::{serve_shared, ServeShared};
+    use std::io::Read;
+    use std::net::TcpStream;
+    let (r, _) = super::test_repo();
+    let storage = r.storage().clone();
+    let shared = Arc::new(ServeShared::new());
+    let mut input = Vec::new();
+    for req in [
+        Request::Put {
+            key: "packs/00",
+            data: b"pack",
+        },
+        Request::Get { key: "packs/00" },
+        Request::Get { key: "packs/01" },
+    ]
+    .iter()
+    {
+        protocol::write_frame(&mut input, &req.encode()).unwrap();
+    }
+    let opts = Default::default();
+    serve_shared(
+        storage.clone(),
+        &opts,
+        &shared,
+        &mut &input[..],
+        &mut Vec::new(),
+    )
+    .unwrap();
+    shared.metrics.gc_done(&GcStats {
+        deleted_packs: vec![Default::default()],
+        reclaimed_bytes: 100,
+        ..Default::default()
+    });
+
+    let text = shared.metrics.render(&storage, false).unwrap();
+    for line in [
+        "packnback_sessions_active 0",
+        "packnback_sessions_total 1",
+        "packnback_requests_total{op=\"put\",capability=\"put-only\"} 1",
+        "packnback_requests_total{op=\"get\",capability=\"fetch-data\"} 2",
+        "packnback_request_errors_total{op=\"get\",capability=\"fetch-data\"} 1",
+        "packnback_gc_deleted_packs_total 1",
+        "packnback_gc_reclaimed_bytes_total 100",
+        "packnback_stored_bytes 4",
+        "# TYPE packnback_last_fsck_timestamp_seconds gauge",
+    ]
+    .iter()
+    {
+        assert!(text.lines().any(|l| l == *line), "{}\n{}", line, text);
+    }
+
+    let dir = super::storage::local::test_dir("metrics");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("packnback.prom");
+    shared
+        .metrics
+        .write_textfile(&path, &storage, false)
+        .unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), text);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let metrics = shared.clone();
+    std::thread::spawn(move || serve_metrics(listener, &metrics.metrics, &storage, false));
+    let mut conn = TcpStream::connect(addr).unwrap();
+    conn.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut resp = String::new();
+    conn.read_to_string(&mut resp).unwrap();
+    assert!(resp.starts_with("HTTP/1.0 200 OK\r\n"), "{}", resp);
+    assert!(resp.ends_with(&text));
+    fs::remove_dir_all(&dir).unwrap();
+}
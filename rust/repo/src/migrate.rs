@@ -0,0 +1,263 @@
+//! Moving a repository to another storage backend.
+//!
+//! `migrate_to` copies everything stored to an empty backend, or to one
+//! holding an interrupted migration of the same repository, which is then
+//! resumed. Packs are checked on arrival by reading them back from the
+//! destination: the table of contents must match the pack index and every
+//! object must decrypt, and with the address key every object must hash
+//! to its address, under the repository's key or that of a namespace in
+//! the manifest, see `policy`. A pack's index is copied only once the
+//! pack checks out, so an indexed pack in the destination is known to be
+//! good and is not copied again.
+//!
+//! The manifest is copied last, and only once every pack it can refer to
+//! is in place, so the destination cannot be used as a repository before
+//! it is complete. Migration holds a shared lock, backups may continue
+//! while it runs. Commits write their indexes before the manifest, so if
+//! the manifest is unchanged after a pass every pack it refers to was
+//! indexed before the pass listed them. Otherwise another pass copies the
+//! new packs. The manifest is copied as signed by the owner, the new
+//! backend serves the same repository.
+//!
+//! A pack's parity is copied with it. Locks are not copied, everything
+//! else, such as the config, keys and policy, is copied byte for byte and
+//! read back on every pass.
+
+use super::address::AddressKey;
+use super::index::PackIndex;
+use super::lock::{is_lock_key, LockMode, RepoLock};
+use super::pack::{PackId, PackReader};
+use super::parity::parity_key;
+use super::storage::{StorageEngine, StorageObject};
+use super::{Repo, RepoError, CONFIG_FILE, INDEXES_DIR, MANIFEST_FILE, PACKS_DIR, PARITY_DIR};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tweetnacl::CryptoBoxSk;
+
+// Passes to make while the source keeps changing before giving up.
+const MAX_PASSES: usize = 5;
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct MigrateStats {
+    pub copied_packs: usize,
+    // Packs already migrated by an earlier, interrupted run.
+    pub skipped_packs: usize,
+    pub copied_objects: usize,
+    pub copied_bytes: u64,
+    pub passes: usize,
+}
+
+fn copy_verbatim(
+    from: &dyn StorageEngine,
+    to: &dyn StorageEngine,
+    key: &str,
+) -> Result<u64, RepoError> {
+    let buf = from.get(key)?;
+    to.put(key, &buf)?;
+    if to.get(key)? != buf {
+        return Err(RepoError::CorruptOrTamperedDataError);
+    }
+    Ok(buf.len() as u64)
+}
+
+impl Repo {
+    pub fn migrate_to(
+        &self,
+        dest: Arc<dyn StorageEngine>,
+        sk: &CryptoBoxSk,
+        address_key: Option<&AddressKey>,
+    ) -> Result<MigrateStats, RepoError> {
+        let mut lock = self.lock(LockMode::Shared)?;
+        let config = self.storage().get(CONFIG_FILE)?;
+        match dest.get(CONFIG_FILE) {
+            Ok(ref existing) if *existing == config => (),
+            Ok(_) => return Err(RepoError::RepoExistsError),
+            Err(ref e) if e.is_not_found() => {
+                if !dest.list_prefix("")?.is_empty() {
+                    return Err(RepoError::RepoExistsError);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+
+        let keys = match address_key {
+            Some(k) => self.address_keys(k)?,
+            None => Vec::new(),
+        };
+        let mut stats: MigrateStats = Default::default();
+        let mut manifest = self.storage().get(MANIFEST_FILE)?;
+        loop {
+            stats.passes += 1;
+            self.migrate_pass(&dest, sk, &keys, &mut lock, &mut stats)?;
+            let current = self.storage().get(MANIFEST_FILE)?;
+            if current == manifest {
+                break;
+            }
+            if stats.passes == MAX_PASSES {
+                return Err(RepoError::RepoLockedError);
+            }
+            manifest = current;
+        }
+        self.open_manifest(&manifest)?;
+        dest.put(MANIFEST_FILE, &manifest)?;
+        if dest.get(MANIFEST_FILE)? != manifest {
+            return Err(RepoError::CorruptOrTamperedDataError);
+        }
+        Ok(stats)
+    }
+
+    fn migrate_pass(
+        &self,
+        dest: &Arc<dyn StorageEngine>,
+        sk: &CryptoBoxSk,
+        address_keys: &[AddressKey],
+        lock: &mut RepoLock,
+        stats: &mut MigrateStats,
+    ) -> Result<(), RepoError> {
+        let packs_prefix = format!("{}/", PACKS_DIR);
+        let indexes_prefix = format!("{}/", INDEXES_DIR);
+        let parity_prefix = format!("{}/", PARITY_DIR);
+        let migrated: HashSet<String> = dest.list_prefix(&indexes_prefix)?.into_iter().collect();
+        for k in self.storage().list_prefix("")? {
+            let special = k == MANIFEST_FILE
+                || k.starts_with(&packs_prefix)
+                || k.starts_with(&indexes_prefix)
+                || k.starts_with(&parity_prefix)
+                || is_lock_key(&k);
+            if !special {
+                stats.copied_bytes += copy_verbatim(&**self.storage(), &**dest, &k)?;
+            }
+        }
+        for id in self.list_pack_indexes()? {
+            let index_key = format!("{}/{}", INDEXES_DIR, id.to_hex());
+            if migrated.contains(&index_key) {
+                stats.skipped_packs += 1;
+                continue;
+            }
+            lock.refresh()?;
+            stats.copied_bytes += self.migrate_pack(dest, &id, sk, address_keys, stats)?;
+            stats.copied_bytes += copy_verbatim(&**self.storage(), &**dest, &index_key)?;
+            stats.copied_packs += 1;
+        }
+        Ok(())
+    }
+
+    fn migrate_pack(
+        &self,
+        dest: &Arc<dyn StorageEngine>,
+        id: &PackId,
+        sk: &CryptoBoxSk,
+        address_keys: &[AddressKey],
+        stats: &mut MigrateStats,
+    ) -> Result<u64, RepoError> {
+        let idx = self.read_pack_index(id)?;
+        let key = format!("{}/{}", PACKS_DIR, id.to_hex());
+        let buf = self.storage().get(&key)?;
+        dest.put(&key, &buf)?;
+        drop(buf);
+
+        // Everything below reads what the destination actually holds.
+        if dest.size(&key)? != idx.pack_size {
+            return Err(RepoError::CorruptOrTamperedDataError);
+        }
+        let mut pack = PackReader::open(StorageObject::new(dest.clone(), &key), sk)?;
+        let toc = pack.read_toc()?;
+        if PackIndex::new(*id, idx.pack_size, toc).entries() != idx.entries() {
+            return Err(RepoError::CorruptOrTamperedDataError);
+        }
+        for ent in idx.entries() {
+            let data = pack.read_entry(ent)?;
+            if !address_keys.is_empty()
+                && !address_keys.iter().any(|k| k.address(&data) == ent.address)
+            {
+                return Err(RepoError::CorruptOrTamperedDataError);
+            }
+            stats.copied_objects += 1;
+        }
+        match copy_verbatim(&**self.storage(), &**dest, &parity_key(id)) {
+            Ok(n) => Ok(idx.pack_size + n),
+            Err(ref e) if e.is_not_found() => Ok(idx.pack_size),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// Tests --------------------
+
+#[cfg(test)]
+fn test_commit_addressed(r: &Repo, key: &asymcrypt::Key, ak: &AddressKey, data: &[&[u8]]) {
+    use super::object::ObjectKind;
+    let mut tx = r.begin(Default::default()).unwrap();
+    let mut e = super::wire::Encoder::new();
+    let chunks: Vec<_> = data.iter().map(|d| ak.address(d)).collect();
+    super::object::encode_refs(&mut e, &chunks);
+    for (a, d) in chunks.iter().zip(data.iter()) {
+        tx.add(a, ObjectKind::Chunk, d).unwrap();
+    }
+    let snapshot = e.into_vec();
+    let address = ak.address(&snapshot);
+    tx.add(&address, ObjectKind::Snapshot, &snapshot).unwrap();
+    let head = super::manifest::SnapshotHead {
+        address,
+        timestamp: 1,
+        namespace: super::namespace::Namespace::new("laptop").unwrap(),
+        retain_until: 0,
+        amendment: None,
+    };
+    tx.commit(head, key).unwrap();
+}
+
+#[test]
+fn test_migrate() {
+    use super::storage::mem::MemStorage;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    test_commit_addressed(&r, &key, &ak, &[b"hello", b"world"]);
+    let opts = super::pack::PackerOptions {
+        parity: Some(Default::default()),
+        ..Default::default()
+    };
+    let mut tx = r.begin(opts).unwrap();
+    tx.add(&ak.address(b"!"), super::object::ObjectKind::Chunk, b"!")
+        .unwrap();
+    let head = r.manifest().unwrap().heads[0].clone();
+    tx.commit(head, &key).unwrap();
+
+    let dest = Arc::new(MemStorage::new());
+    let stats = r.migrate_to(dest.clone(), &key.box_sk, Some(&ak)).unwrap();
+    assert_eq!(stats.copied_packs, 2);
+    assert_eq!(stats.copied_objects, 4);
+    assert_eq!(stats.passes, 1);
+    assert!(r.list_locks().unwrap().is_empty());
+
+    let migrated = Repo::open(dest.clone(), &key.pub_key()).unwrap();
+    assert_eq!(migrated.manifest().unwrap(), r.manifest().unwrap());
+    assert_eq!(migrated.list_packs().unwrap(), r.list_packs().unwrap());
+    assert_eq!(migrated.load_index().unwrap().len(), 4);
+    let opts = super::fsck::FsckOptions {
+        read_data: true,
+        ..Default::default()
+    };
+    assert!(migrated.fsck(Some(&key.box_sk), &opts).unwrap().is_ok());
+    let ids = r.list_packs().unwrap();
+    assert!(ids.iter().any(|id| dest.exists(&parity_key(id)).unwrap()));
+
+    // Running again resumes, there is nothing left to copy.
+    let stats = r.migrate_to(dest.clone(), &key.box_sk, Some(&ak)).unwrap();
+    assert_eq!(stats.copied_packs, 0);
+    assert_eq!(stats.skipped_packs, 2);
+
+    // Objects must hash to their addresses.
+    let other = Arc::new(MemStorage::new());
+    match r.migrate_to(other.clone(), &key.box_sk, Some(&AddressKey::new())) {
+        Err(RepoError::CorruptOrTamperedDataError) => (),
+        _ => panic!("expected address verification to fail"),
+    }
+    assert!(!other.exists(MANIFEST_FILE).unwrap());
+
+    let (unrelated, _) = super::test_repo();
+    match r.migrate_to(unrelated.storage().clone(), &key.box_sk, None) {
+        Err(RepoError::RepoExistsError) => (),
+        _ => panic!("expected a populated destination to be refused"),
+    }
+}
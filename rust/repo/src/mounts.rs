@@ -0,0 +1,125 @@
+//! Mounted filesystems, as seen by a backup.
+//!
+//! `MountTable` lists the mountpoints of the running system and the type
+//! of filesystem mounted on each, from Linux's `/proc/self/mountinfo`.
+//! Elsewhere, or if it cannot be read, the table is empty and mountpoints
+//! are only found by their device, see `backup`.
+//!
+//! Pseudo filesystems, `/proc`, `/sys`, `/dev` and the like, hold nothing
+//! worth restoring and reading them can hang or never end, so a backup
+//! never walks into one.
+
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+const PSEUDO_FILESYSTEMS: &[&str] = &[
+    "autofs",
+    "binfmt_misc",
+    "bpf",
+    "cgroup",
+    "cgroup2",
+    "configfs",
+    "debugfs",
+    "devpts",
+    "devtmpfs",
+    "efivarfs",
+    "fusectl",
+    "hugetlbfs",
+    "mqueue",
+    "nsfs",
+    "proc",
+    "pstore",
+    "rpc_pipefs",
+    "securityfs",
+    "sysfs",
+    "tracefs",
+];
+
+pub fn is_pseudo(fstype: &str) -> bool {
+    PSEUDO_FILESYSTEMS.contains(&fstype)
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct MountTable {
+    // Absolute mountpoint to filesystem type.
+    mounts: HashMap<Vec<u8>, String>,
+}
+
+// Undo the octal escapes of spaces, tabs, newlines and backslashes.
+fn unescape(field: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        let octal = field
+            .get(i + 1..i + 4)
+            .filter(|d| d.iter().all(|b| (b'0'..=b'7').contains(b)));
+        match (field[i], octal) {
+            (b'\\', Some(d)) => {
+                out.push(
+                    d.iter()
+                        .fold(0u8, |v, b| v.wrapping_mul(8).wrapping_add(b - b'0')),
+                );
+                i += 4;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+impl MountTable {
+    pub fn load() -> MountTable {
+        match fs::read("/proc/self/mountinfo") {
+            Ok(text) => MountTable::parse(&text),
+            Err(_) => Default::default(),
+        }
+    }
+
+    // Lines are `id parent major:minor root mountpoint options
+    // [optional fields] - fstype source super_options`. A later mount on
+    // the same mountpoint hides an earlier one.
+    pub fn parse(text: &[u8]) -> MountTable {
+        let mut mounts = HashMap::new();
+        for line in text.split(|b| *b == b'\n') {
+            let fields: Vec<&[u8]> = line.split(|b| *b == b' ').collect();
+            let sep = fields.iter().position(|f| *f == b"-");
+            if let (Some(sep), Some(mountpoint)) = (sep, fields.get(4)) {
+                if let Some(fstype) = fields.get(sep + 1) {
+                    let fstype = String::from_utf8_lossy(fstype).into_owned();
+                    mounts.insert(unescape(mountpoint), fstype);
+                }
+            }
+        }
+        MountTable { mounts }
+    }
+
+    // The type of the filesystem mounted at the absolute `path`, None if
+    // it is not a mountpoint.
+    pub fn fstype(&self, path: &Path) -> Option<&str> {
+        self.mounts.get(path.as_os_str().as_bytes()).map(|t| &t[..])
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_mount_table() {
+    let table = MountTable::parse(
+        b"23 28 0:22 / /proc rw,relatime - proc proc rw\n\
+          30 28 8:1 / /mnt/my\\040disk rw shared:1 - ext4 /dev/sda1 rw\n\
+          31 28 0:40 / /mnt/nfs rw master:2 unbindable - nfs4 host:/export rw\n\
+          32 31 0:41 / /mnt/nfs rw - tmpfs tmpfs rw\n\
+          garbage\n",
+    );
+    assert_eq!(table.fstype(Path::new("/proc")), Some("proc"));
+    assert_eq!(table.fstype(Path::new("/mnt/my disk")), Some("ext4"));
+    assert_eq!(table.fstype(Path::new("/mnt/nfs")), Some("tmpfs"));
+    assert_eq!(table.fstype(Path::new("/mnt")), None);
+    assert!(is_pseudo("sysfs") && !is_pseudo("ext4"));
+    assert_eq!(unescape(b"a\\134b\\011\\0"), b"a\\b\t\\0");
+}
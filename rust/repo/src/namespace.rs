@@ -0,0 +1,173 @@
+//! Per-writer namespaces.
+//!
+//! Every authorized writer key is assigned a namespace in the manifest and
+//! every snapshot it uploads is recorded under that namespace. Chunks are
+//! still deduplicated across the whole repository, namespaces only scope
+//! listings, quotas and garbage collection roots, unless the policy
+//! isolates namespaces, see `policy`.
+
+use super::address::{Address, AddressKey};
+use super::index::RepoIndex;
+use super::wire::{Decoder, Encoder};
+use super::{Repo, RepoError};
+use std::fmt;
+use tweetnacl::CryptoBoxSk;
+
+pub const MAX_NAMESPACE_LEN: usize = 64;
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Namespace {
+    name: String,
+}
+
+impl Namespace {
+    pub fn new(name: &str) -> Result<Namespace, RepoError> {
+        let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+        if name.is_empty()
+            || name.len() > MAX_NAMESPACE_LEN
+            || name.starts_with('.')
+            || !name.chars().all(valid_char)
+        {
+            return Err(RepoError::InvalidNamespaceError);
+        }
+        Ok(Namespace {
+            name: name.to_string(),
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+
+    pub fn encode(&self, e: &mut Encoder) {
+        e.str(&self.name);
+    }
+
+    pub fn decode(d: &mut Decoder) -> Result<Namespace, RepoError> {
+        Namespace::new(d.str()?).map_err(|_| RepoError::InvalidDataError)
+    }
+}
+
+impl fmt::Display for Namespace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl Repo {
+    // The key the snapshots of `namespace` are addressed with, given the
+    // repository's `address_key`: the namespace's own if the policy
+    // isolates namespaces, None for the repository's.
+    pub fn namespace_address_key(
+        &self,
+        address_key: &AddressKey,
+        namespace: &Namespace,
+    ) -> Option<AddressKey> {
+        Some(address_key.for_namespace(namespace)).filter(|_| self.policy().isolate_namespaces)
+    }
+
+    // The key the objects of `snapshot` were addressed with, given the
+    // repository's `address_key`, None for the repository's.
+    pub fn snapshot_address_key(
+        &self,
+        index: &RepoIndex,
+        sk: &CryptoBoxSk,
+        snapshot: &Address,
+        address_key: Option<&AddressKey>,
+    ) -> Result<Option<AddressKey>, RepoError> {
+        let address_key = match address_key {
+            Some(address_key) => address_key,
+            None => return Ok(None),
+        };
+        let key_namespace = self.read_snapshot(index, sk, snapshot)?.key_namespace;
+        if key_namespace.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(
+            address_key.for_namespace(&Namespace::new(&key_namespace)?),
+        ))
+    }
+
+    // Every key objects may be addressed with, given the repository's
+    // `address_key`: it and the own key of each namespace in the manifest.
+    pub fn address_keys(&self, address_key: &AddressKey) -> Result<Vec<AddressKey>, RepoError> {
+        let m = self.manifest()?;
+        let mut namespaces: Vec<&Namespace> = m
+            .writers
+            .iter()
+            .map(|w| &w.namespace)
+            .chain(m.heads.iter().map(|h| &h.namespace))
+            .collect();
+        namespaces.sort();
+        namespaces.dedup();
+        let mut keys = vec![AddressKey {
+            bytes: address_key.bytes,
+        }];
+        keys.extend(
+            namespaces
+                .into_iter()
+                .map(|ns| address_key.for_namespace(ns)),
+        );
+        Ok(keys)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_namespace_validation() {
+    assert!(Namespace::new("laptop-1").is_ok());
+    assert!(Namespace::new("db_host.example").is_ok());
+    assert!(Namespace::new("").is_err());
+    assert!(Namespace::new("..").is_err());
+    assert!(Namespace::new("a/b").is_err());
+    assert!(Namespace::new(&"x".repeat(MAX_NAMESPACE_LEN + 1)).is_err());
+}
+
+#[test]
+fn test_isolate_namespaces() {
+    let key = asymcrypt::Key::new();
+    let maintenance = asymcrypt::Key::new();
+    let config = super::config::RepoConfig {
+        maintenance_pk: Some(maintenance.sign_pk.clone()),
+        ..Default::default()
+    };
+    let storage = std::sync::Arc::new(super::storage::mem::MemStorage::new());
+    let mut r = Repo::init(storage, config, &key).unwrap();
+    let ak = AddressKey::new();
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let laptop = Namespace::new("laptop").unwrap();
+    let desktop = Namespace::new("desktop").unwrap();
+    let backup = |r: &Repo, ns: &Namespace| {
+        let opts = Default::default();
+        r.backup_stream(&mut &data[..], "dump", ns, &ak, &key, &opts)
+            .unwrap()
+    };
+    let cat = |r: &Repo, snapshot: &Address| {
+        let mut out = Vec::new();
+        let path = std::path::Path::new("dump");
+        r.cat(snapshot, path, &mut out, &key.box_sk, Some(&ak))
+            .unwrap();
+        out
+    };
+
+    // Shared by default, the second namespace stores nothing new.
+    backup(&r, &laptop);
+    let (_, stats) = backup(&r, &desktop);
+    assert_eq!(stats.new_bytes, 0);
+
+    r.set_isolate_namespaces(true, &maintenance).unwrap();
+    assert!(r.policy().isolate_namespaces);
+    let (head, stats) = backup(&r, &laptop);
+    assert!(stats.new_bytes > 0);
+    let (other, stats) = backup(&r, &desktop);
+    assert!(stats.new_bytes > 0);
+    assert_eq!(backup(&r, &laptop).1.new_bytes, 0);
+    let index = r.load_index().unwrap();
+    let snapshot = r.read_snapshot(&index, &key.box_sk, &head.address).unwrap();
+    assert_eq!(snapshot.key_namespace, "laptop");
+    for h in [&head, &other].iter() {
+        assert_eq!(cat(&r, &h.address), data);
+    }
+    assert_eq!(r.address_keys(&ak).unwrap().len(), 3);
+}
@@ -0,0 +1,65 @@
+//! Kinds of objects stored in a repository.
+//!
+//! Tree, snapshot and amendment objects begin with the addresses of every
+//! object they reference, so `gc` and integrity checks can walk the
+//! object graph without understanding the rest of their format:
+//!
+//! ```text
+//! u32:n_refs n_refs * [32]:address ...
+//! ```
+
+use super::address::Address;
+use super::wire::{Decoder, Encoder};
+use super::RepoError;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum ObjectKind {
+    Chunk,
+    Tree,
+    Snapshot,
+    Amendment,
+}
+
+impl ObjectKind {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            ObjectKind::Chunk => 0,
+            ObjectKind::Tree => 1,
+            ObjectKind::Snapshot => 2,
+            ObjectKind::Amendment => 3,
+        }
+    }
+
+    pub fn from_u8(v: u8) -> Result<ObjectKind, RepoError> {
+        match v {
+            0 => Ok(ObjectKind::Chunk),
+            1 => Ok(ObjectKind::Tree),
+            2 => Ok(ObjectKind::Snapshot),
+            3 => Ok(ObjectKind::Amendment),
+            _ => Err(RepoError::InvalidDataError),
+        }
+    }
+
+    // Whether objects of this kind start with a reference list.
+    pub fn has_refs(self) -> bool {
+        self != ObjectKind::Chunk
+    }
+}
+
+pub fn encode_refs(e: &mut Encoder, refs: &[Address]) {
+    e.u32(refs.len() as u32);
+    for a in refs.iter() {
+        a.encode(e);
+    }
+}
+
+// Decode the reference list at the start of `buf`, ignoring the rest.
+pub fn decode_refs(buf: &[u8]) -> Result<Vec<Address>, RepoError> {
+    let mut d = Decoder::new(buf);
+    let n = d.count(32)?;
+    let mut refs = Vec::with_capacity(n);
+    for _ in 0..n {
+        refs.push(Address::decode(&mut d)?);
+    }
+    Ok(refs)
+}
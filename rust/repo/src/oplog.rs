@@ -0,0 +1,504 @@
+//! Signed logs of what `serve` was asked to do.
+//!
+//! Started with `ServeOptions::log`, the server appends a record of every
+//! request it answers to a log file of its own, outside the repository:
+//! when, for which client, the operation and key, why it was refused if it
+//! was, and the addresses of the objects it concerned, those asked about
+//! with PRESENT, the entries of an index stored and a loose object stored,
+//! read or deleted. Each request in a batch is a record, challenges and
+//! requests that do not decode are not recorded. A request is recorded
+//! before it is answered, so a client never sees done what the log lacks.
+//!
+//! Each record ends with a chain hash over the one before it and its own
+//! body, so no record can be altered, dropped or slipped in without
+//! breaking every chain hash after it. Every `SEAL_EVERY` records of a
+//! session, and when it ends, the server appends a seal, the chain hash so
+//! far signed by the log key, `ServeOptions::log_key`, which is the
+//! server's and no client's. Rewriting what a seal covers means forging
+//! the signature, only records after the last seal can be cut unnoticed.
+//!
+//! Servers of concurrent sessions append to the same log, each holding an
+//! exclusive lock on the file while it appends. A record cut short by a
+//! crash breaks the chain where it was, and checking the log says so.
+//!
+//! `verify_log` checks a log against the public half of the log key, and
+//! `export_log` writes the operations of a log that checks out as NDJSON,
+//! see `json`, for `packnback log verify` and `packnback log export`:
+//!
+//! ```text
+//! operation  {"type": "operation", "time": s, "client": s|null, "op": s,
+//!             "key": s, "error": s|null, "addresses": [s], "sealed": b}
+//! ```
+//!
+//! `time` is RFC 3339, `addresses` are hex and `sealed` says whether a
+//! seal covers the operation.
+//!
+//! Format:
+//!
+//! ```text
+//! log:     "PNBOPLOG" u16:format_version [32]:sign_pk
+//!          record...
+//! record:  u32:len [len]:body [32]:chain
+//! body:    u8:type=1 u64:unix_time str:client str:op str:key str:error
+//!          u32:n n * [32]:address
+//!        | u8:type=2 u64:unix_time bytes:signed
+//! ```
+//!
+//! Type 1 is an operation. `client` is empty for a session without one,
+//! and for AUTH is the client it claimed to be. `error` is empty if the
+//! request was done. Type 2 is a seal, `signed` in the signature envelope,
+//! see `signed`, is
+//!
+//! ```text
+//! "PNBOPSEAL" [32]:chain u64:unix_time
+//! ```
+//!
+//! with the chain hash of the record before it. A record's `chain` is the
+//! first 32 bytes of the sha512 of the previous record's chain followed by
+//! its body, the first record taking the first 32 bytes of the sha512 of
+//! the header for the previous chain.
+
+use super::address::Address;
+use super::datetime::{unix_now, DateTime};
+use super::json::json_str;
+use super::signed;
+use super::wire::{Decoder, Encoder};
+use super::RepoError;
+use asymcrypt::Key;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use tweetnacl::*;
+
+pub const OPLOG_FORMAT_VERSION: u16 = 1;
+const OPLOG_MAGIC: &[u8] = b"PNBOPLOG";
+const SEAL_MAGIC: &[u8] = b"PNBOPSEAL";
+const HEADER_SZ: usize = 42;
+const CHAIN_SZ: usize = 32;
+
+pub const SEAL_EVERY: usize = 100;
+
+const TYPE_OPERATION: u8 = 1;
+const TYPE_SEAL: u8 = 2;
+
+type Chain = [u8; CHAIN_SZ];
+
+fn digest(buf: &[u8]) -> Chain {
+    let mut h = [0; CRYPTO_HASH_BYTES];
+    crypto_hash(&mut h, buf);
+    let mut c = [0; CHAIN_SZ];
+    c.copy_from_slice(&h[..CHAIN_SZ]);
+    c
+}
+
+fn chain(prev: &Chain, body: &[u8]) -> Chain {
+    let mut buf = Vec::with_capacity(CHAIN_SZ + body.len());
+    buf.extend_from_slice(prev);
+    buf.extend_from_slice(body);
+    digest(&buf)
+}
+
+fn seal_message(chain: &Chain, time: u64) -> Vec<u8> {
+    let mut e = Encoder::new();
+    e.fixed(SEAL_MAGIC).fixed(chain).u64(time);
+    e.into_vec()
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Operation {
+    pub time: u64,
+    pub client: Option<String>,
+    pub op: String,
+    pub key: String,
+    // Why the request was refused or failed, None if it was done.
+    pub error: Option<String>,
+    pub addresses: Vec<Address>,
+}
+
+impl Operation {
+    fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.u8(TYPE_OPERATION)
+            .u64(self.time)
+            .str(self.client.as_deref().unwrap_or(""))
+            .str(&self.op)
+            .str(&self.key)
+            .str(self.error.as_deref().unwrap_or(""))
+            .u32(self.addresses.len() as u32);
+        for a in self.addresses.iter() {
+            a.encode(&mut e);
+        }
+        e.into_vec()
+    }
+
+    fn decode(d: &mut Decoder) -> Result<Operation, RepoError> {
+        let time = d.u64()?;
+        let some = |s: &str| Some(s.to_string()).filter(|s| !s.is_empty());
+        let client = some(d.str()?);
+        let op = d.str()?.to_string();
+        let key = d.str()?.to_string();
+        let error = some(d.str()?);
+        let n = d.count(CHAIN_SZ)?;
+        let mut addresses = Vec::with_capacity(n);
+        for _ in 0..n {
+            addresses.push(Address::decode(d)?);
+        }
+        Ok(Operation {
+            time,
+            client,
+            op,
+            key,
+            error,
+            addresses,
+        })
+    }
+
+    fn write_ndjson(&self, w: &mut dyn Write, sealed: bool) -> io::Result<()> {
+        let addresses: Vec<String> = self
+            .addresses
+            .iter()
+            .map(|a| format!("\"{}\"", a.to_hex()))
+            .collect();
+        writeln!(
+            w,
+            "{{\"type\": \"operation\", \"time\": \"{}\", \"client\": {}, \"op\": {}, \
+             \"key\": {}, \"error\": {}, \"addresses\": [{}], \"sealed\": {}}}",
+            DateTime::from_unix(self.time).to_rfc3339(),
+            self.client.as_deref().map_or("null".to_string(), json_str),
+            json_str(&self.op),
+            json_str(&self.key),
+            self.error.as_deref().map_or("null".to_string(), json_str),
+            addresses.join(", "),
+            sealed
+        )
+    }
+}
+
+enum Record {
+    Operation(Operation),
+    Seal,
+}
+
+// Decode the body of a record following `prev`, checking a seal's
+// signature.
+fn decode_record(body: &[u8], prev: &Chain, pk: &CryptoSignPk) -> Result<Record, RepoError> {
+    let mut d = Decoder::new(body);
+    let record = match d.u8()? {
+        TYPE_OPERATION => Record::Operation(Operation::decode(&mut d)?),
+        TYPE_SEAL => {
+            let time = d.u64()?;
+            if signed::open(d.bytes()?, pk)? != seal_message(prev, time) {
+                return Err(RepoError::SignatureFailedError);
+            }
+            Record::Seal
+        }
+        _ => return Err(RepoError::InvalidDataError),
+    };
+    d.finish()?;
+    Ok(record)
+}
+
+fn encode_header(pk: &CryptoSignPk) -> Vec<u8> {
+    let mut e = Encoder::new();
+    e.fixed(OPLOG_MAGIC)
+        .u16(OPLOG_FORMAT_VERSION)
+        .fixed(&pk.bytes);
+    e.into_vec()
+}
+
+// The log key of the log `r` starts, and the chain the first record
+// follows.
+fn read_header(r: &mut dyn Read) -> Result<(CryptoSignPk, Chain), RepoError> {
+    let mut buf = [0; HEADER_SZ];
+    r.read_exact(&mut buf)?;
+    let mut d = Decoder::new(&buf);
+    if d.fixed(OPLOG_MAGIC.len())? != OPLOG_MAGIC {
+        return Err(RepoError::InvalidDataError);
+    }
+    if d.u16()? != OPLOG_FORMAT_VERSION {
+        return Err(RepoError::UnsupportedVersionError);
+    }
+    let mut pk: CryptoSignPk = Default::default();
+    d.fixed_into(&mut pk.bytes)?;
+    Ok((pk, digest(&buf)))
+}
+
+// The next record's body and chain, None at the end of the log.
+fn read_record(r: &mut dyn Read) -> Result<Option<(Vec<u8>, Chain)>, RepoError> {
+    let mut len = [0; 4];
+    if r.read(&mut len[..1])? == 0 {
+        return Ok(None);
+    }
+    let cut_short = |_| RepoError::CorruptOrTamperedDataError;
+    r.read_exact(&mut len[1..]).map_err(cut_short)?;
+    let len = u32::from_be_bytes(len) as u64;
+    let mut body = Vec::new();
+    if r.take(len).read_to_end(&mut body)? as u64 != len {
+        return Err(RepoError::CorruptOrTamperedDataError);
+    }
+    let mut stored = [0; CHAIN_SZ];
+    r.read_exact(&mut stored).map_err(cut_short)?;
+    Ok(Some((body, stored)))
+}
+
+// The chain the next record appended to `file` follows.
+fn last_chain(file: &mut File) -> Result<Chain, RepoError> {
+    let len = file.seek(SeekFrom::End(0))?;
+    if len <= HEADER_SZ as u64 {
+        file.seek(SeekFrom::Start(0))?;
+        return Ok(read_header(file)?.1);
+    }
+    let mut c = [0; CHAIN_SZ];
+    file.seek(SeekFrom::Start(len - CHAIN_SZ as u64))?;
+    file.read_exact(&mut c)?;
+    Ok(c)
+}
+
+// Append the record whose body `make` builds from the chain it follows,
+// holding the file lock so concurrent servers keep to one chain.
+fn append(file: &mut File, make: impl FnOnce(&Chain) -> Vec<u8>) -> Result<(), RepoError> {
+    file.lock()?;
+    let result = last_chain(file).and_then(|prev| {
+        let body = make(&prev);
+        let mut e = Encoder::new();
+        e.u32(body.len() as u32)
+            .fixed(&body)
+            .fixed(&chain(&prev, &body));
+        file.write_all(&e.into_vec())?;
+        Ok(())
+    });
+    file.unlock()?;
+    result
+}
+
+struct LogState {
+    file: File,
+    // Records this server appended since its last seal.
+    unsealed: usize,
+}
+
+pub struct OpLog {
+    state: Mutex<LogState>,
+    key: Box<Key>,
+}
+
+impl OpLog {
+    // Open the log at `path`, creating it if it is missing. Only `key`
+    // may seal it.
+    pub fn open(path: &Path, key: Box<Key>) -> Result<OpLog, RepoError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        file.lock()?;
+        let result = if file.metadata()?.len() == 0 {
+            file.write_all(&encode_header(&key.sign_pk))
+                .map_err(RepoError::from)
+        } else {
+            file.seek(SeekFrom::Start(0))?;
+            match read_header(&mut file) {
+                Ok((pk, _)) if pk != key.sign_pk => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the log is sealed with another key",
+                )
+                .into()),
+                result => result.map(|_| ()),
+            }
+        };
+        file.unlock()?;
+        result?;
+        Ok(OpLog {
+            state: Mutex::new(LogState { file, unsealed: 0 }),
+            key,
+        })
+    }
+
+    pub fn record(&self, op: &Operation) -> Result<(), RepoError> {
+        let mut state = self.state.lock().unwrap();
+        append(&mut state.file, |_| op.encode())?;
+        state.unsealed += 1;
+        if state.unsealed >= SEAL_EVERY {
+            self.seal_state(&mut state)?;
+        }
+        Ok(())
+    }
+
+    // Seal what this server recorded, and everything before it.
+    pub fn seal(&self) -> Result<(), RepoError> {
+        let mut state = self.state.lock().unwrap();
+        if state.unsealed == 0 {
+            return Ok(());
+        }
+        self.seal_state(&mut state)
+    }
+
+    fn seal_state(&self, state: &mut LogState) -> Result<(), RepoError> {
+        let time = unix_now();
+        append(&mut state.file, |prev| {
+            let mut e = Encoder::new();
+            e.u8(TYPE_SEAL)
+                .u64(time)
+                .bytes(&signed::sign(&seal_message(prev, time), &self.key.sign_sk));
+            e.into_vec()
+        })?;
+        state.unsealed = 0;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct LogReport {
+    pub operations: u64,
+    // Operations a seal covers, the first so many.
+    pub sealed: u64,
+    pub seals: u64,
+}
+
+// Read the log at `path`, checking its chain and seals, passing each
+// record to `each` until it returns false.
+fn read_log(
+    path: &Path,
+    pk: &CryptoSignPk,
+    each: &mut dyn FnMut(Record) -> Result<bool, RepoError>,
+) -> Result<(), RepoError> {
+    let mut r = BufReader::new(File::open(path)?);
+    let (log_pk, mut prev) = read_header(&mut r)?;
+    if log_pk != *pk {
+        return Err(RepoError::SignatureFailedError);
+    }
+    while let Some((body, stored)) = read_record(&mut r)? {
+        let next = chain(&prev, &body);
+        if next != stored {
+            return Err(RepoError::CorruptOrTamperedDataError);
+        }
+        let record = decode_record(&body, &prev, pk)?;
+        prev = next;
+        if !each(record)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Check the log at `path`, sealed by the key whose public half is `pk`.
+pub fn verify_log(path: &Path, pk: &CryptoSignPk) -> Result<LogReport, RepoError> {
+    let mut report = LogReport::default();
+    read_log(path, pk, &mut |record| {
+        match record {
+            Record::Operation(_) => report.operations += 1,
+            Record::Seal => {
+                report.seals += 1;
+                report.sealed = report.operations;
+            }
+        }
+        Ok(true)
+    })?;
+    Ok(report)
+}
+
+// Check the log at `path`, then write its operations to `w`. Records
+// appended meanwhile are left out.
+pub fn export_log(
+    path: &Path,
+    pk: &CryptoSignPk,
+    w: &mut dyn Write,
+) -> Result<LogReport, RepoError> {
+    let report = verify_log(path, pk)?;
+    let mut n = 0;
+    read_log(path, pk, &mut |record| {
+        if let Record::Operation(op) = record {
+            if n == report.operations {
+                return Ok(false);
+            }
+            op.write_ndjson(w, n < report.sealed)?;
+            n += 1;
+        }
+        Ok(true)
+    })?;
+    Ok(report)
+}
+
+// Tests --------------------
+
+#[test]
+fn test_oplog() {
+    let dir = super::storage::local::test_dir("oplog");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("serve.log");
+    let mut key_file = Vec::new();
+    Key::new().write(&mut key_file).unwrap();
+    let load = || Key::read_boxed_from(&mut &key_file[..]).unwrap();
+    let pk = load().sign_pk.clone();
+    let log = OpLog::open(&path, load()).unwrap();
+    let op = |i: u64| Operation {
+        time: 1_000_000_000 + i,
+        client: Some("laptop".to_string()),
+        op: "put".to_string(),
+        key: format!("objects/{}", i),
+        error: None,
+        addresses: vec![Address {
+            bytes: [i as u8; 32],
+        }],
+    };
+    for i in 0..SEAL_EVERY as u64 + 2 {
+        log.record(&op(i)).unwrap();
+    }
+    let report = verify_log(&path, &pk).unwrap();
+    assert_eq!(report.operations, SEAL_EVERY as u64 + 2);
+    assert_eq!((report.sealed, report.seals), (SEAL_EVERY as u64, 1));
+    log.seal().unwrap();
+    log.seal().unwrap();
+    drop(log);
+
+    // Another server appends to the same chain.
+    let log = OpLog::open(&path, load()).unwrap();
+    let refused = Operation {
+        client: None,
+        error: Some("The repository server refused the operation.".to_string()),
+        addresses: Vec::new(),
+        ..op(7)
+    };
+    log.record(&refused).unwrap();
+    drop(log);
+    assert!(OpLog::open(&path, Key::new()).is_err());
+    let report = verify_log(&path, &pk).unwrap();
+    assert_eq!(report.operations, SEAL_EVERY as u64 + 3);
+    assert_eq!((report.sealed, report.seals), (SEAL_EVERY as u64 + 2, 2));
+
+    let mut out = Vec::new();
+    export_log(&path, &pk, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), SEAL_EVERY + 3);
+    assert_eq!(
+        lines[0],
+        format!(
+            "{{\"type\": \"operation\", \"time\": \"2001-09-09T01:46:40Z\", \
+             \"client\": \"laptop\", \"op\": \"put\", \"key\": \"objects/0\", \
+             \"error\": null, \"addresses\": [\"{}\"], \"sealed\": true}}",
+            Address { bytes: [0; 32] }.to_hex()
+        )
+    );
+    assert!(lines[lines.len() - 1].contains("\"client\": null"));
+    assert!(lines[lines.len() - 1].ends_with("\"sealed\": false}"));
+
+    // Only the log key's public half checks it.
+    match verify_log(&path, &Key::new().sign_pk) {
+        Err(RepoError::SignatureFailedError) => (),
+        _ => panic!("expected another key to be refused"),
+    }
+    // Any change breaks the chain, a record cut short too.
+    let good = std::fs::read(&path).unwrap();
+    let mut bad = good.clone();
+    bad[HEADER_SZ + 20] ^= 1;
+    std::fs::write(&path, &bad).unwrap();
+    match verify_log(&path, &pk) {
+        Err(RepoError::CorruptOrTamperedDataError) => (),
+        _ => panic!("expected tampering to be found"),
+    }
+    std::fs::write(&path, &good[..good.len() - 1]).unwrap();
+    assert!(verify_log(&path, &pk).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
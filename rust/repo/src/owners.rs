@@ -0,0 +1,253 @@
+//! File owners across machines.
+//!
+//! Trees store owners and groups as numeric ids, see `tree`, which only
+//! name the same accounts on the machine backed up. A snapshot also
+//! stores the user and group names of the ids its trees use, so a
+//! restore elsewhere can give files to the accounts of the same names.
+//! When a restore sets owners, see `RestoreOptions::owners`, `OwnerMap`
+//! picks the id to set for each one stored, the first of:
+//!
+//! ```text
+//! rule     an explicit rule, `uids` or `gids`, such as 1000:1001
+//! name     unless `numeric`, the local account of the name the
+//!          snapshot has for the id
+//! id       the id as stored
+//! ```
+//!
+//! Without `RestoreOptions::owners` no owner is set, and files belong to
+//! whoever restored them, which is all a restore without root can do.
+//! Names are looked up through the C library, so accounts from LDAP or
+//! any other NSS source count.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+
+const ERANGE: c_int = 34;
+// Largest buffer offered for one account's entry.
+const MAX_ENTRY_BUF: usize = 1 << 20;
+
+mod sys {
+    use std::os::raw::{c_char, c_int, c_uint};
+
+    // The leading fields of struct passwd and struct group, the same on
+    // every Unix, and room for the rest.
+    #[repr(C)]
+    pub struct Passwd {
+        pub pw_name: *mut c_char,
+        pub pw_passwd: *mut c_char,
+        pub pw_uid: c_uint,
+        pub pw_gid: c_uint,
+        pub rest: [usize; 8],
+    }
+
+    #[repr(C)]
+    pub struct Group {
+        pub gr_name: *mut c_char,
+        pub gr_passwd: *mut c_char,
+        pub gr_gid: c_uint,
+        pub rest: [usize; 4],
+    }
+
+    extern "C" {
+        pub fn getpwuid_r(
+            uid: c_uint,
+            pwd: *mut Passwd,
+            buf: *mut c_char,
+            buflen: usize,
+            result: *mut *mut Passwd,
+        ) -> c_int;
+        pub fn getpwnam_r(
+            name: *const c_char,
+            pwd: *mut Passwd,
+            buf: *mut c_char,
+            buflen: usize,
+            result: *mut *mut Passwd,
+        ) -> c_int;
+        pub fn getgrgid_r(
+            gid: c_uint,
+            grp: *mut Group,
+            buf: *mut c_char,
+            buflen: usize,
+            result: *mut *mut Group,
+        ) -> c_int;
+        pub fn getgrnam_r(
+            name: *const c_char,
+            grp: *mut Group,
+            buf: *mut c_char,
+            buflen: usize,
+            result: *mut *mut Group,
+        ) -> c_int;
+    }
+}
+
+// Call one of the getpw*_r or getgr*_r functions, growing the buffer
+// until the entry fits, and take what is needed from the entry found
+// while its strings are still in the buffer.
+fn lookup<T, R>(
+    call: impl Fn(*mut T, *mut c_char, usize, *mut *mut T) -> c_int,
+    take: impl Fn(&T) -> Option<R>,
+) -> Option<R> {
+    // Null pointers and zeros, for the call to fill in.
+    let mut entry: T = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as c_char; 1024];
+    loop {
+        let mut result = ptr::null_mut();
+        match call(&mut entry, buf.as_mut_ptr(), buf.len(), &mut result) {
+            ERANGE if buf.len() < MAX_ENTRY_BUF => buf.resize(buf.len() * 2, 0),
+            0 if !result.is_null() => return take(&entry),
+            _ => return None,
+        }
+    }
+}
+
+fn name(p: *const c_char) -> Option<String> {
+    if p.is_null() {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(p) }.to_str().ok()?;
+    Some(name.to_string()).filter(|n| !n.is_empty())
+}
+
+pub fn user_name(uid: u32) -> Option<String> {
+    lookup(
+        |pwd, buf, len, result| unsafe { sys::getpwuid_r(uid, pwd, buf, len, result) },
+        |pwd: &sys::Passwd| name(pwd.pw_name),
+    )
+}
+
+pub fn group_name(gid: u32) -> Option<String> {
+    lookup(
+        |grp, buf, len, result| unsafe { sys::getgrgid_r(gid, grp, buf, len, result) },
+        |grp: &sys::Group| name(grp.gr_name),
+    )
+}
+
+pub fn user_id(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    lookup(
+        |pwd, buf, len, result| unsafe { sys::getpwnam_r(name.as_ptr(), pwd, buf, len, result) },
+        |pwd: &sys::Passwd| Some(pwd.pw_uid),
+    )
+}
+
+pub fn group_id(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    lookup(
+        |grp, buf, len, result| unsafe { sys::getgrnam_r(name.as_ptr(), grp, buf, len, result) },
+        |grp: &sys::Group| Some(grp.gr_gid),
+    )
+}
+
+// The names of `ids` that have one, ascending by id, for a snapshot.
+pub fn user_names(uids: &BTreeSet<u32>) -> Vec<(u32, String)> {
+    uids.iter()
+        .filter_map(|id| Some((*id, user_name(*id)?)))
+        .collect()
+}
+
+pub fn group_names(gids: &BTreeSet<u32>) -> Vec<(u32, String)> {
+    gids.iter()
+        .filter_map(|id| Some((*id, group_name(*id)?)))
+        .collect()
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct OwnerMap {
+    // Ignore the names stored, as tar's --numeric-owner.
+    pub numeric: bool,
+    // Explicit rules, from the id stored to the id to set.
+    pub uids: BTreeMap<u32, u32>,
+    pub gids: BTreeMap<u32, u32>,
+}
+
+impl OwnerMap {
+    // A rule "from:to", as in "1000:1001".
+    pub fn parse_rule(s: &str) -> Option<(u32, u32)> {
+        let (from, to) = s.split_once(':')?;
+        Some((from.trim().parse().ok()?, to.trim().parse().ok()?))
+    }
+
+    // The ids to set for the owners of a snapshot with the names `users`
+    // and `groups`, see the module documentation.
+    pub fn resolve(&self, users: &[(u32, String)], groups: &[(u32, String)]) -> Owners {
+        let mut owners = Owners {
+            uids: self.uids.iter().map(|(a, b)| (*a, *b)).collect(),
+            gids: self.gids.iter().map(|(a, b)| (*a, *b)).collect(),
+        };
+        if !self.numeric {
+            for (id, name) in users.iter() {
+                if let (false, Some(local)) = (self.uids.contains_key(id), user_id(name)) {
+                    owners.uids.insert(*id, local);
+                }
+            }
+            for (id, name) in groups.iter() {
+                if let (false, Some(local)) = (self.gids.contains_key(id), group_id(name)) {
+                    owners.gids.insert(*id, local);
+                }
+            }
+        }
+        owners
+    }
+}
+
+// The ids resolved for one snapshot, any other id is set as stored.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Owners {
+    uids: HashMap<u32, u32>,
+    gids: HashMap<u32, u32>,
+}
+
+impl Owners {
+    pub fn uid(&self, id: u32) -> u32 {
+        self.uids.get(&id).cloned().unwrap_or(id)
+    }
+
+    pub fn gid(&self, id: u32) -> u32 {
+        self.gids.get(&id).cloned().unwrap_or(id)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_owner_names() {
+    // Every system has root, whatever else it has.
+    assert_eq!(user_name(0).as_deref(), Some("root"));
+    assert_eq!(user_id("root"), Some(0));
+    let root_group = group_name(0).unwrap();
+    assert_eq!(group_id(&root_group), Some(0));
+    assert_eq!(user_id("no-such-user-here"), None);
+    assert_eq!(user_id("nul\0inside"), None);
+    let ids: BTreeSet<u32> = [0, 4_000_000_000].iter().cloned().collect();
+    assert_eq!(user_names(&ids), vec![(0, "root".to_string())]);
+}
+
+#[test]
+fn test_owner_map() {
+    assert_eq!(OwnerMap::parse_rule("1000:1001"), Some((1000, 1001)));
+    for bad in ["1000", "1000:", "a:1", "1000->1001"].iter() {
+        assert_eq!(OwnerMap::parse_rule(bad), None, "{}", bad);
+    }
+    // Stored on another machine, where root was 5 and 7 had no name
+    // there is here.
+    let users = vec![
+        (5, "root".to_string()),
+        (7, "no-such-user-here".to_string()),
+    ];
+    let groups = vec![(5, group_name(0).unwrap())];
+    let by_name = OwnerMap::default().resolve(&users, &groups);
+    assert_eq!((by_name.uid(5), by_name.uid(7), by_name.uid(9)), (0, 7, 9));
+    assert_eq!(by_name.gid(5), 0);
+
+    let mut map = OwnerMap {
+        numeric: true,
+        ..Default::default()
+    };
+    assert_eq!(map.resolve(&users, &groups).uid(5), 5);
+    map.uids.insert(5, 1001);
+    map.numeric = false;
+    let owners = map.resolve(&users, &groups);
+    assert_eq!((owners.uid(5), owners.gid(5)), (1001, 0));
+}
@@ -0,0 +1,750 @@
+//! Packfiles.
+//!
+//! Objects are sealed with a random per-pack key and appended into large
+//! pack blobs so storage backends see a modest number of big objects rather
+//! than millions of tiny ones. The pack key is wrapped to the repository
+//! recipient key and stored in the pack header. A sealed table of contents
+//! at the end of the pack lists every object so a pack can be read without
+//! any other repository state.
+//!
+//! Format:
+//!
+//! ```text
+//! header:  "PNBPACK" u16:format_version [104]:wrapped_pack_key
+//!          [104]:wrapped_pack_key_for_metadata
+//! objects: sealed object, back to back
+//! toc:     sealed(u32:n n * ([32]:address u8:kind u64:offset u32:length
+//!                           u32:size))
+//! trailer: u64:toc_offset u32:toc_length "PNBPEND"
+//! ```
+//!
+//! Offsets are from the start of the pack, lengths are of the sealed bytes
+//! and sizes of the objects before they were compressed, see `compress`
+//! for what is sealed. See `crypto` for the sealed and wrapped key formats.
+//!
+//! In a repository with a metadata key, see `keys`, chunks and metadata
+//! objects go into separate packs. Metadata packs wrap the pack key to the
+//! metadata key as well, the second wrapped key of every other pack is all
+//! zero.
+
+use super::address::{from_hex, to_hex, Address};
+use super::compress::{self, Compressor};
+use super::crypto::{seal, unseal, unwrap_key, wrap_key, SEAL_OVERHEAD, WRAPPED_KEY_SZ};
+use super::object::ObjectKind;
+use super::parity::{parity_key, Parity, ParityOptions};
+use super::storage::StorageEngine;
+use super::wire::{Decoder, Encoder};
+use super::{RepoError, PACKS_DIR};
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tweetnacl::*;
+
+pub const PACK_FORMAT_VERSION: u16 = 1;
+const PACK_MAGIC: &[u8] = b"PNBPACK";
+const PACK_END_MAGIC: &[u8] = b"PNBPEND";
+pub const PACK_HEADER_SZ: usize = 7 + 2 + 2 * WRAPPED_KEY_SZ;
+// Where the pack key wrapped to the owner starts.
+pub(crate) const OWNER_KEY_AT: usize = 7 + 2;
+pub const PACK_TRAILER_SZ: usize = 8 + 4 + 7;
+const TOC_ENTRY_SZ: usize = 32 + 1 + 8 + 4 + 4;
+
+pub const PACK_ID_SZ: usize = 16;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct PackId {
+    pub bytes: [u8; PACK_ID_SZ],
+}
+
+impl PackId {
+    pub fn new() -> PackId {
+        let mut id: PackId = Default::default();
+        random_bytes(&mut id.bytes);
+        id
+    }
+
+    pub fn from_hex(s: &str) -> Result<PackId, RepoError> {
+        let mut id: PackId = Default::default();
+        from_hex(s, &mut id.bytes)?;
+        Ok(id)
+    }
+
+    pub fn to_hex(&self) -> String {
+        to_hex(&self.bytes)
+    }
+}
+
+impl fmt::Display for PackId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TocEntry {
+    pub address: Address,
+    pub kind: ObjectKind,
+    pub offset: u64,
+    pub length: u32,
+    pub size: u32,
+}
+
+impl TocEntry {
+    pub fn encode(&self, e: &mut Encoder) {
+        self.address.encode(e);
+        e.u8(self.kind.to_u8())
+            .u64(self.offset)
+            .u32(self.length)
+            .u32(self.size);
+    }
+
+    pub fn decode(d: &mut Decoder) -> Result<TocEntry, RepoError> {
+        Ok(TocEntry {
+            address: Address::decode(d)?,
+            kind: ObjectKind::from_u8(d.u8()?)?,
+            offset: d.u64()?,
+            length: d.u32()?,
+            size: d.u32()?,
+        })
+    }
+}
+
+// Positional reads, implemented by anything seekable and by storage
+// backends that support ranged gets.
+pub trait RangeRead {
+    fn read_range(&mut self, offset: u64, len: usize) -> Result<Vec<u8>, RepoError>;
+    fn size(&mut self) -> Result<u64, RepoError>;
+}
+
+impl<T: Read + Seek> RangeRead for T {
+    fn read_range(&mut self, offset: u64, len: usize) -> Result<Vec<u8>, RepoError> {
+        let mut buf = vec![0; len];
+        self.seek(SeekFrom::Start(offset))?;
+        self.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn size(&mut self) -> Result<u64, RepoError> {
+        Ok(self.seek(SeekFrom::End(0))?)
+    }
+}
+
+pub struct PackWriter<W: Write> {
+    w: W,
+    key: CryptoSecretboxKey,
+    offset: u64,
+    toc: Vec<TocEntry>,
+    created: Instant,
+    // Set up by the first `add`, see `add_encoded`.
+    compressor: Option<Compressor>,
+}
+
+impl<W: Write> PackWriter<W> {
+    pub fn new(w: W, recipient: &CryptoBoxPk) -> Result<PackWriter<W>, RepoError> {
+        PackWriter::with_metadata_key(w, recipient, None)
+    }
+
+    // A pack `metadata` can open as well as `recipient`, if given.
+    pub fn with_metadata_key(
+        mut w: W,
+        recipient: &CryptoBoxPk,
+        metadata: Option<&CryptoBoxPk>,
+    ) -> Result<PackWriter<W>, RepoError> {
+        let key = CryptoSecretboxKey::new();
+        let mut e = Encoder::new();
+        e.fixed(PACK_MAGIC)
+            .u16(PACK_FORMAT_VERSION)
+            .fixed(&wrap_key(&key, recipient));
+        match metadata {
+            Some(metadata) => e.fixed(&wrap_key(&key, metadata)),
+            None => e.fixed(&[0; WRAPPED_KEY_SZ]),
+        };
+        let header_sz = e.len();
+        w.write_all(&e.into_vec())?;
+        Ok(PackWriter {
+            w,
+            key,
+            offset: header_sz as u64,
+            toc: Vec::new(),
+            created: Instant::now(),
+            compressor: None,
+        })
+    }
+
+    pub fn add(
+        &mut self,
+        address: &Address,
+        kind: ObjectKind,
+        data: &[u8],
+    ) -> Result<(), RepoError> {
+        let compressor = match self.compressor {
+            Some(ref mut c) => c,
+            None => self.compressor.insert(Compressor::new()?),
+        };
+        let encoded = compressor.encode(data)?;
+        self.add_encoded(address, kind, data.len(), &encoded)
+    }
+
+    // Add an object of `size` bytes the caller already compressed, see
+    // `Compressor::encode`.
+    pub fn add_encoded(
+        &mut self,
+        address: &Address,
+        kind: ObjectKind,
+        size: usize,
+        encoded: &[u8],
+    ) -> Result<(), RepoError> {
+        let sealed = seal(&self.key, encoded);
+        if sealed.len() > u32::MAX as usize || size > u32::MAX as usize {
+            return Err(RepoError::ObjectTooLargeError);
+        }
+        self.w.write_all(&sealed)?;
+        self.toc.push(TocEntry {
+            address: *address,
+            kind,
+            offset: self.offset,
+            length: sealed.len() as u32,
+            size: size as u32,
+        });
+        self.offset += sealed.len() as u64;
+        Ok(())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn n_objects(&self) -> usize {
+        self.toc.len()
+    }
+
+    pub fn age(&self) -> Duration {
+        self.created.elapsed()
+    }
+
+    pub fn finish(mut self) -> Result<(W, Vec<TocEntry>, u64), RepoError> {
+        let mut e = Encoder::new();
+        e.u32(self.toc.len() as u32);
+        for ent in self.toc.iter() {
+            ent.encode(&mut e);
+        }
+        let sealed_toc = seal(&self.key, &e.into_vec());
+        self.w.write_all(&sealed_toc)?;
+
+        let mut e = Encoder::new();
+        e.u64(self.offset)
+            .u32(sealed_toc.len() as u32)
+            .fixed(PACK_END_MAGIC);
+        assert!(e.len() == PACK_TRAILER_SZ);
+        self.w.write_all(&e.into_vec())?;
+        self.w.flush()?;
+
+        let size = self.offset + sealed_toc.len() as u64 + PACK_TRAILER_SZ as u64;
+        Ok((self.w, self.toc, size))
+    }
+}
+
+// Check the magic and version of the first PACK_HEADER_SZ bytes of a pack.
+pub(crate) fn check_header(header: &[u8]) -> Result<(), RepoError> {
+    let mut d = Decoder::new(header);
+    if d.fixed(PACK_MAGIC.len())? != PACK_MAGIC {
+        return Err(RepoError::InvalidDataError);
+    }
+    if d.u16()? != PACK_FORMAT_VERSION {
+        return Err(RepoError::UnsupportedVersionError);
+    }
+    d.fixed(2 * WRAPPED_KEY_SZ)?;
+    Ok(())
+}
+
+// The wrapped pack keys, from the header of a pack.
+fn wrapped_keys(header: &[u8]) -> Result<Vec<&[u8]>, RepoError> {
+    check_header(header)?;
+    let mut d = Decoder::new(&header[OWNER_KEY_AT..]);
+    let keys = (0..2)
+        .map(|_| d.fixed(WRAPPED_KEY_SZ))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(keys
+        .into_iter()
+        .filter(|k| k.iter().any(|&b| b != 0))
+        .collect())
+}
+
+fn read_header<R: RangeRead>(r: &mut R) -> Result<Vec<u8>, RepoError> {
+    let header = r.read_range(0, PACK_HEADER_SZ)?;
+    check_header(&header)?;
+    Ok(header)
+}
+
+// The pack key, from the header of a pack, whichever key `sk` unwraps.
+pub(crate) fn open_header(
+    header: &[u8],
+    sk: &CryptoBoxSk,
+) -> Result<CryptoSecretboxKey, RepoError> {
+    let mut result = Err(RepoError::DecryptKeyMismatchError);
+    for wrapped in wrapped_keys(header)? {
+        result = unwrap_key(wrapped, sk);
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
+
+// The offset and length of the sealed table of contents. A trailer that
+// does not describe the pack it ends, whatever its values, is corrupt.
+fn read_trailer<R: RangeRead>(r: &mut R) -> Result<(u64, u32), RepoError> {
+    let size = r.size()?;
+    if size < (PACK_HEADER_SZ + PACK_TRAILER_SZ) as u64 {
+        return Err(RepoError::CorruptOrTamperedDataError);
+    }
+    let trailer = r.read_range(size - PACK_TRAILER_SZ as u64, PACK_TRAILER_SZ)?;
+    let mut d = Decoder::new(&trailer);
+    let toc_offset = d.u64()?;
+    let toc_len = d.u32()?;
+    let end = toc_offset
+        .checked_add(toc_len as u64)
+        .and_then(|n| n.checked_add(PACK_TRAILER_SZ as u64));
+    if d.fixed(PACK_END_MAGIC.len())? != PACK_END_MAGIC
+        || toc_offset < PACK_HEADER_SZ as u64
+        || end != Some(size)
+    {
+        return Err(RepoError::CorruptOrTamperedDataError);
+    }
+    Ok((toc_offset, toc_len))
+}
+
+// Check what can be checked of a pack without its key: the header and
+// trailer, that the sealed table of contents has room for every entry of
+// its index, and that those entries are whole sealed objects between the
+// two. Unless the pack holds duplicates, which its index leaves out, the
+// objects must fill that space exactly.
+pub fn check_envelope<R: RangeRead>(r: &mut R, entries: &[TocEntry]) -> Result<(), RepoError> {
+    let header_sz = read_header(r)?.len();
+    let (toc_offset, toc_len) = read_trailer(r)?;
+    let toc_len = toc_len as usize;
+    if toc_len < SEAL_OVERHEAD + 4 || !(toc_len - SEAL_OVERHEAD - 4).is_multiple_of(TOC_ENTRY_SZ) {
+        return Err(RepoError::InvalidDataError);
+    }
+    let n_toc = (toc_len - SEAL_OVERHEAD - 4) / TOC_ENTRY_SZ;
+    if n_toc < entries.len() {
+        return Err(RepoError::StorageError(format!(
+            "the pack holds {} objects, the index lists {}",
+            n_toc,
+            entries.len()
+        )));
+    }
+    let exact = n_toc == entries.len();
+    let mut spans: Vec<(u64, u64)> = entries
+        .iter()
+        .map(|e| (e.offset, e.offset + e.length as u64))
+        .collect();
+    spans.sort_unstable();
+    let mut end = header_sz as u64;
+    for (start, stop) in spans {
+        if start < end || (exact && start != end) || stop - start <= SEAL_OVERHEAD as u64 {
+            return Err(RepoError::StorageError(format!(
+                "the index has no valid object at offset {}",
+                start
+            )));
+        }
+        end = stop;
+    }
+    if end > toc_offset || (exact && end != toc_offset) {
+        return Err(RepoError::StorageError(
+            "the index does not match the pack layout".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub struct PackReader<R: RangeRead> {
+    r: R,
+    key: CryptoSecretboxKey,
+    header_sz: u64,
+}
+
+impl<R: RangeRead> PackReader<R> {
+    pub fn open(mut r: R, sk: &CryptoBoxSk) -> Result<PackReader<R>, RepoError> {
+        let header = read_header(&mut r)?;
+        let key = open_header(&header, sk)?;
+        Ok(PackReader {
+            r,
+            key,
+            header_sz: header.len() as u64,
+        })
+    }
+
+    pub fn read_toc(&mut self) -> Result<Vec<TocEntry>, RepoError> {
+        let (toc_offset, toc_len) = read_trailer(&mut self.r)?;
+        let toc = unseal(&self.key, &self.r.read_range(toc_offset, toc_len as usize)?)?;
+        let mut d = Decoder::new(&toc);
+        let n = d.count(TOC_ENTRY_SZ)?;
+        let mut entries = Vec::with_capacity(n);
+        for _ in 0..n {
+            let ent = TocEntry::decode(&mut d)?;
+            if ent.offset < self.header_sz || ent.offset + ent.length as u64 > toc_offset {
+                return Err(RepoError::InvalidDataError);
+            }
+            entries.push(ent);
+        }
+        d.finish()?;
+        Ok(entries)
+    }
+
+    // Read a single object with one ranged read, the offset and length
+    // usually come from a pack index rather than the table of contents.
+    pub fn read(&mut self, offset: u64, length: u32) -> Result<Vec<u8>, RepoError> {
+        compress::decode(&unseal(
+            &self.key,
+            &self.r.read_range(offset, length as usize)?,
+        )?)
+    }
+
+    pub fn read_entry(&mut self, ent: &TocEntry) -> Result<Vec<u8>, RepoError> {
+        let data = self.read(ent.offset, ent.length)?;
+        if data.len() != ent.size as usize {
+            return Err(RepoError::CorruptOrTamperedDataError);
+        }
+        Ok(data)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PackerOptions {
+    // Roll over to a new pack once this many bytes are written.
+    pub target_size: u64,
+    // Roll over to a new pack once the current one is this old, so slow
+    // trickles of data still become durable in bounded time.
+    pub max_age: Duration,
+    // Index the packs a transaction stored this often, so one that is
+    // interrupted leaves them for the next to deduplicate against, see
+    // `transaction`. None to index them only on commit.
+    pub checkpoint: Option<Duration>,
+    // Store Reed-Solomon parity alongside every pack, see `parity`.
+    pub parity: Option<ParityOptions>,
+}
+
+impl Default for PackerOptions {
+    fn default() -> PackerOptions {
+        PackerOptions {
+            target_size: 128 * 1024 * 1024,
+            max_age: Duration::from_secs(15 * 60),
+            checkpoint: Some(Duration::from_secs(10 * 60)),
+            parity: None,
+        }
+    }
+}
+
+// The keys packs are sealed to: the owner, and in a repository with a
+// metadata key that one too for packs of metadata objects.
+#[derive(Clone, Debug)]
+pub struct Recipients {
+    pub owner: CryptoBoxPk,
+    pub metadata: Option<CryptoBoxPk>,
+}
+
+// How many packs a packer fills at once, see `Recipients::slot`.
+pub const PACK_SLOTS: usize = 2;
+
+impl Recipients {
+    // Which of the packs being filled takes an object of `kind`, 1 for
+    // metadata objects if they are packed apart, else 0.
+    pub fn slot(&self, kind: ObjectKind) -> usize {
+        match kind {
+            ObjectKind::Chunk => 0,
+            _ if self.metadata.is_some() => 1,
+            _ => 0,
+        }
+    }
+
+    // A new pack for the objects of `slot`.
+    pub fn writer(&self, slot: usize) -> Result<PackWriter<Vec<u8>>, RepoError> {
+        let metadata = self.metadata.as_ref().filter(|_| slot == 1);
+        PackWriter::with_metadata_key(Vec::new(), &self.owner, metadata)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FinishedPack {
+    pub id: PackId,
+    pub size: u64,
+    pub entries: Vec<TocEntry>,
+}
+
+// Writes objects into packs in storage, rolling over to a new pack by size
+// or age. Storage puts are atomic so a pack only becomes visible complete.
+// Chunks and metadata objects fill packs of their own if the recipients
+// have a metadata key.
+pub struct Packer {
+    storage: Arc<dyn StorageEngine>,
+    recipients: Recipients,
+    opts: PackerOptions,
+    current: [Option<(PackId, PackWriter<Vec<u8>>)>; PACK_SLOTS],
+    finished: Vec<FinishedPack>,
+}
+
+impl Packer {
+    pub fn new(
+        storage: Arc<dyn StorageEngine>,
+        recipients: &Recipients,
+        opts: PackerOptions,
+    ) -> Packer {
+        Packer {
+            storage,
+            recipients: recipients.clone(),
+            opts,
+            current: Default::default(),
+            finished: Vec::new(),
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        address: &Address,
+        kind: ObjectKind,
+        data: &[u8],
+    ) -> Result<(), RepoError> {
+        let slot = self.recipients.slot(kind);
+        if self.current[slot].is_none() {
+            let w = self.recipients.writer(slot)?;
+            self.current[slot] = Some((PackId::new(), w));
+        }
+        let should_roll = {
+            let (_, w) = self.current[slot].as_mut().unwrap();
+            w.add(address, kind, data)?;
+            w.size() >= self.opts.target_size || w.age() >= self.opts.max_age
+        };
+        if should_roll {
+            self.flush_slot(slot)?;
+        }
+        Ok(())
+    }
+
+    pub fn flush_if_stale(&mut self) -> Result<(), RepoError> {
+        for slot in 0..PACK_SLOTS {
+            let stale = match self.current[slot] {
+                Some((_, ref w)) => w.age() >= self.opts.max_age,
+                None => false,
+            };
+            if stale {
+                self.flush_slot(slot)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), RepoError> {
+        for slot in 0..PACK_SLOTS {
+            self.flush_slot(slot)?;
+        }
+        Ok(())
+    }
+
+    fn flush_slot(&mut self, slot: usize) -> Result<(), RepoError> {
+        if let Some((id, w)) = self.current[slot].take() {
+            let (buf, entries, size) = w.finish()?;
+            self.storage
+                .put(&format!("{}/{}", PACKS_DIR, id.to_hex()), &buf)?;
+            if let Some(ref opts) = self.opts.parity {
+                let parity = Parity::generate(id, &buf, opts)?;
+                self.storage.put(&parity_key(&id), &parity.encode())?;
+            }
+            self.finished.push(FinishedPack { id, size, entries });
+        }
+        Ok(())
+    }
+
+    pub fn take_finished(&mut self) -> Vec<FinishedPack> {
+        std::mem::take(&mut self.finished)
+    }
+
+    pub fn finish(mut self) -> Result<Vec<FinishedPack>, RepoError> {
+        self.flush()?;
+        Ok(self.take_finished())
+    }
+}
+
+// Tests --------------------
+
+#[cfg(test)]
+fn test_object(i: usize) -> (Address, Vec<u8>) {
+    // Random, so it does not compress.
+    let mut data = vec![0; 1000 + i];
+    random_bytes(&mut data);
+    let mut a: Address = Default::default();
+    a.bytes[0] = i as u8;
+    (a, data)
+}
+
+#[test]
+fn test_pack_round_trip() {
+    let (pk, sk) = boxed_crypto_box_keypair();
+    let mut w = PackWriter::new(Vec::new(), &pk).unwrap();
+    let mut objects: Vec<(Address, Vec<u8>)> = (0..10).map(test_object).collect();
+    // One that compresses.
+    objects.push((Address { bytes: [10; 32] }, vec![10; 100_000]));
+    for (a, data) in objects.iter() {
+        w.add(a, ObjectKind::Chunk, data).unwrap();
+    }
+    let (buf, toc, size) = w.finish().unwrap();
+    assert_eq!(size, buf.len() as u64);
+    assert!(buf.len() < 20_000);
+
+    let mut r = PackReader::open(std::io::Cursor::new(buf), &sk).unwrap();
+    let entries = r.read_toc().unwrap();
+    assert_eq!(entries, toc);
+    for (ent, (a, data)) in entries.iter().zip(objects.iter()) {
+        assert_eq!(ent.address, *a);
+        assert_eq!(ent.size as usize, data.len());
+        assert_eq!(r.read_entry(ent).unwrap(), *data);
+    }
+}
+
+#[test]
+fn test_pack_metadata_key() {
+    use std::io::Cursor;
+    let (pk, sk) = boxed_crypto_box_keypair();
+    let (mpk, msk) = boxed_crypto_box_keypair();
+    let (_, other) = boxed_crypto_box_keypair();
+    let mut w = PackWriter::with_metadata_key(Vec::new(), &pk, Some(&mpk)).unwrap();
+    let (a, data) = test_object(1);
+    w.add(&a, ObjectKind::Tree, &data).unwrap();
+    let (buf, toc, _) = w.finish().unwrap();
+    assert_eq!(toc[0].offset, PACK_HEADER_SZ as u64);
+    check_envelope(&mut Cursor::new(&buf), &toc).unwrap();
+    for sk in [&sk, &msk].iter() {
+        let mut r = PackReader::open(Cursor::new(&buf), sk).unwrap();
+        assert_eq!(r.read_toc().unwrap(), toc);
+        assert_eq!(r.read_entry(&toc[0]).unwrap(), data);
+    }
+    match PackReader::open(Cursor::new(&buf), &other) {
+        Err(RepoError::DecryptKeyMismatchError) => (),
+        _ => panic!("expected key mismatch"),
+    }
+}
+
+#[test]
+fn test_pack_wrong_key_and_tamper() {
+    let (pk, sk) = boxed_crypto_box_keypair();
+    let (_, sk2) = boxed_crypto_box_keypair();
+    let mut w = PackWriter::new(Vec::new(), &pk).unwrap();
+    let (a, data) = test_object(1);
+    w.add(&a, ObjectKind::Chunk, &data).unwrap();
+    let (mut buf, _, _) = w.finish().unwrap();
+    match PackReader::open(std::io::Cursor::new(buf.clone()), &sk2) {
+        Err(RepoError::DecryptKeyMismatchError) => (),
+        _ => panic!("expected key mismatch"),
+    }
+
+    let n = buf.len();
+    buf[n - PACK_TRAILER_SZ - 1] ^= 1;
+    let mut r = PackReader::open(std::io::Cursor::new(buf), &sk).unwrap();
+    match r.read_toc() {
+        Err(RepoError::CorruptOrTamperedDataError) => (),
+        _ => panic!("expected tamper detection"),
+    }
+}
+
+#[test]
+fn test_pack_envelope() {
+    use std::io::Cursor;
+    let (pk, _) = boxed_crypto_box_keypair();
+    let mut w = PackWriter::new(Vec::new(), &pk).unwrap();
+    for i in [1, 2, 2, 3].iter() {
+        let (a, data) = test_object(*i);
+        w.add(&a, ObjectKind::Chunk, &data).unwrap();
+    }
+    let (buf, toc, size) = w.finish().unwrap();
+    let idx = super::index::PackIndex::new(PackId::new(), size, toc.clone());
+    check_envelope(&mut Cursor::new(&buf), &toc).unwrap();
+    check_envelope(&mut Cursor::new(&buf), idx.entries()).unwrap();
+
+    // Entries that overlap, run into the toc or are too many.
+    let mut bad = toc.clone();
+    bad[1].offset -= 1;
+    assert!(check_envelope(&mut Cursor::new(&buf), &bad).is_err());
+    let mut bad = idx.entries().to_vec();
+    bad[2].length += 1;
+    assert!(check_envelope(&mut Cursor::new(&buf), &bad).is_err());
+    bad.push(bad[0].clone());
+    assert!(check_envelope(&mut Cursor::new(&buf), &bad).is_err());
+
+    for at in [0, buf.len() - 1, buf.len() - PACK_TRAILER_SZ].iter() {
+        let mut damaged = buf.clone();
+        damaged[*at] ^= 1;
+        assert!(check_envelope(&mut Cursor::new(&damaged), &toc).is_err());
+    }
+
+    // A hostile trailer whose sum overflows.
+    let mut hostile = buf.clone();
+    let at = buf.len() - PACK_TRAILER_SZ;
+    hostile[at..at + 8].copy_from_slice(&u64::MAX.to_be_bytes());
+    for result in [
+        check_envelope(&mut Cursor::new(&hostile), &toc),
+        read_trailer(&mut Cursor::new(&hostile)).map(|_| ()),
+    ] {
+        match result {
+            Err(RepoError::CorruptOrTamperedDataError) => (),
+            r => panic!("expected a corrupt pack, got {:?}", r),
+        }
+    }
+}
+
+#[test]
+fn test_pack_tampered_object() {
+    let (pk, sk) = boxed_crypto_box_keypair();
+    let mut w = PackWriter::new(Vec::new(), &pk).unwrap();
+    let (a, data) = test_object(1);
+    w.add(&a, ObjectKind::Chunk, &data).unwrap();
+    let (mut buf, toc, _) = w.finish().unwrap();
+    buf[toc[0].offset as usize + 40] ^= 1;
+    let mut r = PackReader::open(std::io::Cursor::new(buf), &sk).unwrap();
+    match r.read_entry(&toc[0]) {
+        Err(RepoError::CorruptOrTamperedDataError) => (),
+        _ => panic!("expected tamper detection"),
+    }
+}
+
+#[test]
+fn test_packer_rollover() {
+    let (r, key) = super::test_repo();
+    let opts = PackerOptions {
+        target_size: 4000,
+        max_age: Duration::from_secs(3600),
+        ..Default::default()
+    };
+    let mut p = r.packer(opts);
+    for i in 0..10 {
+        let (a, data) = test_object(i);
+        p.add(&a, ObjectKind::Chunk, &data).unwrap();
+    }
+    let packs = p.finish().unwrap();
+    assert!(packs.len() > 1);
+    assert_eq!(packs.iter().map(|p| p.entries.len()).sum::<usize>(), 10);
+    assert_eq!(r.list_packs().unwrap().len(), packs.len());
+    for pack in packs.iter() {
+        let mut pr = r.open_pack(&pack.id, &key.box_sk).unwrap();
+        assert_eq!(pr.read_toc().unwrap(), pack.entries);
+        assert_eq!(
+            pr.read_entry(&pack.entries[0]).unwrap().len(),
+            1000 + pack.entries[0].address.bytes[0] as usize
+        );
+    }
+
+    let opts = PackerOptions {
+        target_size: 1 << 30,
+        max_age: Duration::from_secs(0),
+        ..Default::default()
+    };
+    let mut p = r.packer(opts);
+    let (a, data) = test_object(1);
+    p.add(&a, ObjectKind::Chunk, &data).unwrap();
+    p.flush_if_stale().unwrap();
+    assert_eq!(p.take_finished().len(), 1);
+}
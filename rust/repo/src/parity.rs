@@ -0,0 +1,557 @@
+//! Reed-Solomon parity for packs.
+//!
+//! Encryption detects bit rot but cannot undo it. When
+//! `PackerOptions::parity` is set every pack is stored with a parity
+//! object, from which damaged regions of the pack can be rebuilt.
+//!
+//! The pack is split into blocks of `block_size` bytes, the last one
+//! padded with zeros, and blocks are grouped `data_shards` at a time. Each
+//! group gets `parity_shards` parity blocks from a systematic Reed-Solomon
+//! code over GF(2^8) with a Cauchy generator matrix, so any
+//! `parity_shards` damaged blocks of a group can be rebuilt. Storage
+//! overhead is `parity_shards / data_shards`.
+//!
+//! Damaged blocks are found with a checksum per block. The header holding
+//! the checksums has its own checksum, and each parity block is checked
+//! against its own checksum before use, so damage to the parity object
+//! only costs the parity blocks it touches.
+//!
+//! Format, stored at `parity/<hex pack_id>`:
+//!
+//! ```text
+//! header: "PNBPARITY" u16:format_version [16]:pack_id u64:pack_size
+//!         u32:block_size u8:data_shards u8:parity_shards
+//!         u32:n_data n_data * [16]:data_block_checksum
+//!         u32:n_parity n_parity * [16]:parity_block_checksum
+//!         [32]:sha512_prefix_of_the_header_above
+//! body:   n_parity * [block_size]:parity_block
+//! ```
+//!
+//! Parity blocks are ordered by group, then by parity shard. Block
+//! checksums are sha512 prefixes of the block as stored, without padding.
+
+use super::pack::{PackId, PackReader};
+use super::storage::not_found;
+use super::wire::{Decoder, Encoder};
+use super::{Repo, RepoError, PACKS_DIR, PARITY_DIR};
+use std::io::Cursor;
+use tweetnacl::*;
+
+pub const PARITY_FORMAT_VERSION: u16 = 1;
+const PARITY_MAGIC: &[u8] = b"PNBPARITY";
+const SUM_SZ: usize = 16;
+const HEADER_SUM_SZ: usize = 32;
+const MAX_BLOCK_SIZE: u32 = 16 * 1024 * 1024;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ParityOptions {
+    pub block_size: u32,
+    pub data_shards: u8,
+    pub parity_shards: u8,
+}
+
+impl Default for ParityOptions {
+    fn default() -> ParityOptions {
+        ParityOptions {
+            block_size: 64 * 1024,
+            data_shards: 16,
+            parity_shards: 2,
+        }
+    }
+}
+
+impl ParityOptions {
+    pub fn is_valid(&self) -> bool {
+        self.block_size > 0
+            && self.block_size <= MAX_BLOCK_SIZE
+            && self.data_shards > 0
+            && self.parity_shards > 0
+            && self.data_shards as usize + self.parity_shards as usize <= 256
+    }
+}
+
+// GF(2^8) with the polynomial x^8 + x^4 + x^3 + x^2 + 1.
+struct Gf {
+    exp: [u8; 510],
+    log: [u8; 256],
+}
+
+impl Gf {
+    fn new() -> Gf {
+        let mut gf = Gf {
+            exp: [0; 510],
+            log: [0; 256],
+        };
+        let mut x: u16 = 1;
+        for i in 0..255 {
+            gf.exp[i] = x as u8;
+            gf.exp[i + 255] = x as u8;
+            gf.log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= 0x11d;
+            }
+        }
+        gf
+    }
+
+    fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            return 0;
+        }
+        self.exp[self.log[a as usize] as usize + self.log[b as usize] as usize]
+    }
+
+    fn inv(&self, a: u8) -> u8 {
+        self.exp[255 - self.log[a as usize] as usize]
+    }
+
+    // dst ^= c * src
+    fn mul_acc(&self, dst: &mut [u8], src: &[u8], c: u8) {
+        let mut t = [0u8; 256];
+        for (b, v) in t.iter_mut().enumerate() {
+            *v = self.mul(c, b as u8);
+        }
+        for (d, s) in dst.iter_mut().zip(src.iter()) {
+            *d ^= t[*s as usize];
+        }
+    }
+
+    // Generator matrix row of shard `s`, data shards first. Parity rows
+    // form a Cauchy matrix, every square submatrix of which is invertible.
+    fn row(&self, k: usize, s: usize) -> Vec<u8> {
+        if s < k {
+            let mut row = vec![0; k];
+            row[s] = 1;
+            return row;
+        }
+        (0..k).map(|j| self.inv(s as u8 ^ j as u8)).collect()
+    }
+
+    fn invert(&self, mut m: Vec<Vec<u8>>) -> Option<Vec<Vec<u8>>> {
+        let n = m.len();
+        let mut inv: Vec<Vec<u8>> = (0..n)
+            .map(|i| (0..n).map(|j| (i == j) as u8).collect())
+            .collect();
+        for col in 0..n {
+            let pivot = (col..n).find(|&r| m[r][col] != 0)?;
+            m.swap(col, pivot);
+            inv.swap(col, pivot);
+            let p = self.inv(m[col][col]);
+            for j in 0..n {
+                m[col][j] = self.mul(m[col][j], p);
+                inv[col][j] = self.mul(inv[col][j], p);
+            }
+            for r in 0..n {
+                let f = m[r][col];
+                if r != col && f != 0 {
+                    for j in 0..n {
+                        let (a, b) = (m[col][j], inv[col][j]);
+                        m[r][j] ^= self.mul(f, a);
+                        inv[r][j] ^= self.mul(f, b);
+                    }
+                }
+            }
+        }
+        Some(inv)
+    }
+}
+
+fn checksum(buf: &[u8]) -> [u8; SUM_SZ] {
+    let mut h = [0; CRYPTO_HASH_BYTES];
+    crypto_hash(&mut h, buf);
+    let mut c = [0; SUM_SZ];
+    c.copy_from_slice(&h[..SUM_SZ]);
+    c
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Parity {
+    pub pack_id: PackId,
+    pub pack_size: u64,
+    pub opts: ParityOptions,
+    data_sums: Vec<[u8; SUM_SZ]>,
+    parity_sums: Vec<[u8; SUM_SZ]>,
+    parity: Vec<u8>,
+}
+
+impl Parity {
+    pub fn generate(
+        pack_id: PackId,
+        pack: &[u8],
+        opts: &ParityOptions,
+    ) -> Result<Parity, RepoError> {
+        if !opts.is_valid() {
+            return Err(RepoError::InvalidDataError);
+        }
+        let gf = Gf::new();
+        let bs = opts.block_size as usize;
+        let (k, m) = (opts.data_shards as usize, opts.parity_shards as usize);
+        let blocks: Vec<&[u8]> = pack.chunks(bs).collect();
+        let n_groups = blocks.len().div_ceil(k);
+        let mut parity = vec![0; n_groups * m * bs];
+        for (g, group) in blocks.chunks(k).enumerate() {
+            for i in 0..m {
+                let row = gf.row(k, k + i);
+                let p = &mut parity[(g * m + i) * bs..(g * m + i + 1) * bs];
+                for (j, block) in group.iter().enumerate() {
+                    gf.mul_acc(p, block, row[j]);
+                }
+            }
+        }
+        Ok(Parity {
+            pack_id,
+            pack_size: pack.len() as u64,
+            opts: opts.clone(),
+            data_sums: blocks.iter().map(|b| checksum(b)).collect(),
+            parity_sums: parity.chunks(bs).map(checksum).collect(),
+            parity,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.fixed(PARITY_MAGIC)
+            .u16(PARITY_FORMAT_VERSION)
+            .fixed(&self.pack_id.bytes)
+            .u64(self.pack_size)
+            .u32(self.opts.block_size)
+            .u8(self.opts.data_shards)
+            .u8(self.opts.parity_shards)
+            .u32(self.data_sums.len() as u32);
+        for s in self.data_sums.iter() {
+            e.fixed(s);
+        }
+        e.u32(self.parity_sums.len() as u32);
+        for s in self.parity_sums.iter() {
+            e.fixed(s);
+        }
+        let mut buf = e.into_vec();
+        let mut h = [0; CRYPTO_HASH_BYTES];
+        crypto_hash(&mut h, &buf);
+        buf.extend_from_slice(&h[..HEADER_SUM_SZ]);
+        buf.extend_from_slice(&self.parity);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Parity, RepoError> {
+        let mut d = Decoder::new(buf);
+        if d.fixed(PARITY_MAGIC.len())? != PARITY_MAGIC {
+            return Err(RepoError::InvalidDataError);
+        }
+        if d.u16()? != PARITY_FORMAT_VERSION {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        let mut pack_id: PackId = Default::default();
+        d.fixed_into(&mut pack_id.bytes)?;
+        let pack_size = d.u64()?;
+        let opts = ParityOptions {
+            block_size: d.u32()?,
+            data_shards: d.u8()?,
+            parity_shards: d.u8()?,
+        };
+        let sums = |d: &mut Decoder| -> Result<Vec<[u8; SUM_SZ]>, RepoError> {
+            let n = d.count(SUM_SZ)?;
+            let mut sums = vec![[0; SUM_SZ]; n];
+            for s in sums.iter_mut() {
+                d.fixed_into(s)?;
+            }
+            Ok(sums)
+        };
+        let data_sums = sums(&mut d)?;
+        let parity_sums = sums(&mut d)?;
+        let header_len = buf.len() - d.remaining();
+        let mut h = [0; CRYPTO_HASH_BYTES];
+        crypto_hash(&mut h, &buf[..header_len]);
+        if d.fixed(HEADER_SUM_SZ)? != &h[..HEADER_SUM_SZ] {
+            return Err(RepoError::CorruptOrTamperedDataError);
+        }
+
+        if !opts.is_valid() {
+            return Err(RepoError::InvalidDataError);
+        }
+        let bs = opts.block_size as u64;
+        let n_data = pack_size.div_ceil(bs) as usize;
+        let n_parity = n_data.div_ceil(opts.data_shards as usize) * opts.parity_shards as usize;
+        if data_sums.len() != n_data
+            || parity_sums.len() != n_parity
+            || d.remaining() as u64 != n_parity as u64 * bs
+        {
+            return Err(RepoError::InvalidDataError);
+        }
+        Ok(Parity {
+            pack_id,
+            pack_size,
+            opts,
+            data_sums,
+            parity_sums,
+            parity: d.fixed(d.remaining())?.to_vec(),
+        })
+    }
+
+    // Indexes of the pack blocks that fail their checksum.
+    pub fn damaged_blocks(&self, pack: &[u8]) -> Vec<usize> {
+        let bs = self.opts.block_size as usize;
+        (0..self.data_sums.len())
+            .filter(|&b| {
+                let start = b * bs;
+                let end = (start + bs).min(self.pack_size as usize);
+                pack.len() < end || checksum(&pack[start..end]) != self.data_sums[b]
+            })
+            .collect()
+    }
+
+    // Rebuild damaged blocks of `pack` in place, returning how many were
+    // rebuilt. Truncated or overlong packs are restored to their original
+    // size.
+    pub fn repair(&self, pack: &mut Vec<u8>) -> Result<usize, RepoError> {
+        let damaged = self.damaged_blocks(pack);
+        pack.resize(self.pack_size as usize, 0);
+        if damaged.is_empty() {
+            return Ok(0);
+        }
+        let gf = Gf::new();
+        let bs = self.opts.block_size as usize;
+        let (k, m) = (
+            self.opts.data_shards as usize,
+            self.opts.parity_shards as usize,
+        );
+        let block = |pack: &[u8], b: usize| -> Vec<u8> {
+            let mut v = vec![0; bs];
+            if b * bs < pack.len() {
+                let end = (b * bs + bs).min(pack.len());
+                v[..end - b * bs].copy_from_slice(&pack[b * bs..end]);
+            }
+            v
+        };
+
+        for g in 0..self.data_sums.len().div_ceil(k) {
+            let bad: Vec<usize> = damaged
+                .iter()
+                .filter(|&&b| b / k == g)
+                .map(|&b| b % k)
+                .collect();
+            if bad.is_empty() {
+                continue;
+            }
+            // Any k intact shards determine the group.
+            let mut rows = Vec::with_capacity(k);
+            let mut shards = Vec::with_capacity(k);
+            for j in (0..k).filter(|j| !bad.contains(j)) {
+                rows.push(gf.row(k, j));
+                shards.push(block(pack, g * k + j));
+            }
+            for i in 0..m {
+                if rows.len() == k {
+                    break;
+                }
+                let p = g * m + i;
+                let parity = &self.parity[p * bs..(p + 1) * bs];
+                if checksum(parity) == self.parity_sums[p] {
+                    rows.push(gf.row(k, k + i));
+                    shards.push(parity.to_vec());
+                }
+            }
+            if rows.len() < k {
+                return Err(RepoError::CorruptOrTamperedDataError);
+            }
+            let inv = gf
+                .invert(rows)
+                .ok_or(RepoError::CorruptOrTamperedDataError)?;
+            for &j in bad.iter() {
+                let mut rebuilt = vec![0; bs];
+                for (r, shard) in shards.iter().enumerate() {
+                    gf.mul_acc(&mut rebuilt, shard, inv[j][r]);
+                }
+                let b = g * k + j;
+                let end = (b * bs + bs).min(pack.len());
+                pack[b * bs..end].copy_from_slice(&rebuilt[..end - b * bs]);
+                if checksum(&pack[b * bs..end]) != self.data_sums[b] {
+                    return Err(RepoError::CorruptOrTamperedDataError);
+                }
+            }
+        }
+        Ok(damaged.len())
+    }
+}
+
+pub fn parity_key(id: &PackId) -> String {
+    format!("{}/{}", PARITY_DIR, id.to_hex())
+}
+
+impl Repo {
+    // None if the pack was stored without parity.
+    pub fn read_parity(&self, id: &PackId) -> Result<Option<Parity>, RepoError> {
+        let buf = match self.storage().get(&parity_key(id)) {
+            Ok(buf) => buf,
+            Err(ref e) if e.is_not_found() => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let parity = Parity::decode(&buf)?;
+        if parity.pack_id != *id {
+            return Err(RepoError::InvalidDataError);
+        }
+        Ok(Some(parity))
+    }
+
+    // The pack contents with any damage repaired in memory, and how many
+    // blocks needed repairing.
+    pub fn read_pack_repaired(&self, id: &PackId) -> Result<(Vec<u8>, usize), RepoError> {
+        let key = format!("{}/{}", PACKS_DIR, id.to_hex());
+        let parity = match self.read_parity(id)? {
+            Some(parity) => parity,
+            None => return Err(not_found(&parity_key(id))),
+        };
+        let mut pack = match self.storage().get(&key) {
+            Ok(pack) => pack,
+            Err(ref e) if e.is_not_found() => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let n = parity.repair(&mut pack)?;
+        Ok((pack, n))
+    }
+
+    // Like `open_pack`, but reading through any damage the parity can
+    // repair. The whole pack is held in memory.
+    pub fn open_pack_repaired(
+        &self,
+        id: &PackId,
+        sk: &CryptoBoxSk,
+    ) -> Result<PackReader<Cursor<Vec<u8>>>, RepoError> {
+        let (pack, _) = self.read_pack_repaired(id)?;
+        PackReader::open(Cursor::new(pack), sk)
+    }
+
+    // Rewrite a damaged pack from its parity, returning how many blocks
+    // were repaired. Refused in append only mode.
+    pub fn repair_pack(&self, id: &PackId) -> Result<usize, RepoError> {
+        let (pack, n) = self.read_pack_repaired(id)?;
+        if n > 0 {
+            if self.append_only() {
+                return Err(RepoError::PermissionDeniedError);
+            }
+            self.storage()
+                .put(&format!("{}/{}", PACKS_DIR, id.to_hex()), &pack)?;
+        }
+        Ok(n)
+    }
+}
+
+// Tests --------------------
+
+#[cfg(test)]
+fn test_pack_data(n: usize) -> Vec<u8> {
+    (0..n).map(|i| (i * 7 + i / 251) as u8).collect()
+}
+
+#[test]
+fn test_parity_round_trip() {
+    let opts = ParityOptions {
+        block_size: 100,
+        data_shards: 4,
+        parity_shards: 2,
+    };
+    for &n in [0, 1, 100, 399, 400, 1234].iter() {
+        let pack = test_pack_data(n);
+        let p = Parity::generate(PackId::new(), &pack, &opts).unwrap();
+        assert_eq!(Parity::decode(&p.encode()).unwrap(), p);
+        assert!(p.damaged_blocks(&pack).is_empty());
+    }
+    let p = Parity::generate(PackId::new(), &test_pack_data(1234), &opts).unwrap();
+    let mut buf = p.encode();
+    buf[30] ^= 1;
+    match Parity::decode(&buf) {
+        Err(RepoError::CorruptOrTamperedDataError) => (),
+        _ => panic!("expected header checksum failure"),
+    }
+    let bad = ParityOptions {
+        data_shards: 0,
+        ..opts
+    };
+    assert!(Parity::generate(PackId::new(), &[1, 2, 3], &bad).is_err());
+}
+
+#[test]
+fn test_parity_repair() {
+    let opts = ParityOptions {
+        block_size: 100,
+        data_shards: 4,
+        parity_shards: 2,
+    };
+    let pack = test_pack_data(1234);
+    let p = Parity::generate(PackId::new(), &pack, &opts).unwrap();
+
+    // Two damaged blocks per group, including the short last block.
+    let mut damaged = pack.clone();
+    for &i in [5, 150, 420, 777, 1201, 1233].iter() {
+        damaged[i] ^= 0x55;
+    }
+    assert_eq!(p.damaged_blocks(&damaged), vec![0, 1, 4, 7, 12]);
+    assert_eq!(p.repair(&mut damaged).unwrap(), 5);
+    assert_eq!(damaged, pack);
+
+    let mut truncated = pack[..1150].to_vec();
+    assert_eq!(p.repair(&mut truncated).unwrap(), 2);
+    assert_eq!(truncated, pack);
+
+    // Three damaged blocks in one group is beyond repair.
+    let mut damaged = pack.clone();
+    for &i in [5, 150, 250].iter() {
+        damaged[i] ^= 1;
+    }
+    match p.repair(&mut damaged) {
+        Err(RepoError::CorruptOrTamperedDataError) => (),
+        _ => panic!("expected repair to fail"),
+    }
+
+    // Damaged parity blocks are skipped, not trusted.
+    let mut buf = p.encode();
+    let len = buf.len();
+    buf[len - 550] ^= 1;
+    let p = Parity::decode(&buf).unwrap();
+    let mut damaged = pack.clone();
+    damaged[420] ^= 1;
+    assert_eq!(p.repair(&mut damaged).unwrap(), 1);
+    assert_eq!(damaged, pack);
+}
+
+#[test]
+fn test_repair_pack() {
+    use super::pack::PackerOptions;
+    let (r, key) = super::test_repo();
+    let opts = PackerOptions {
+        parity: Some(ParityOptions {
+            block_size: 256,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let mut packer = r.packer(opts);
+    let address = super::address::Address { bytes: [3; 32] };
+    let mut data = vec![0; 5000];
+    tweetnacl::random_bytes(&mut data);
+    packer
+        .add(&address, super::object::ObjectKind::Chunk, &data)
+        .unwrap();
+    let p = packer.finish().unwrap().pop().unwrap();
+    assert!(r.read_parity(&p.id).unwrap().is_some());
+
+    let pack_key = format!("{}/{}", PACKS_DIR, p.id.to_hex());
+    let pack = r.storage().get(&pack_key).unwrap();
+    let mut damaged = pack.clone();
+    damaged[1000] ^= 0xff;
+    r.storage().put(&pack_key, &damaged).unwrap();
+    let ent = p.entries[0].clone();
+    assert!(r
+        .open_pack(&p.id, &key.box_sk)
+        .unwrap()
+        .read_entry(&ent)
+        .is_err());
+    let mut reader = r.open_pack_repaired(&p.id, &key.box_sk).unwrap();
+    assert_eq!(reader.read_entry(&ent).unwrap(), data);
+
+    assert_eq!(r.repair_pack(&p.id).unwrap(), 1);
+    assert_eq!(r.storage().get(&pack_key).unwrap(), pack);
+    assert_eq!(r.repair_pack(&p.id).unwrap(), 0);
+    assert!(r.read_parity(&PackId::new()).unwrap().is_none());
+}
@@ -0,0 +1,488 @@
+//! Concurrent uploads and downloads.
+//!
+//! High latency object stores only reach line rate with many requests in
+//! flight. With the `async` feature packs and objects can be moved through
+//! an `AsyncStorageEngine` on a tokio runtime.
+//!
+//! `AsyncPacker` seals objects like `Packer` but uploads each finished
+//! pack in the background while the next one fills, with at most
+//! `max_in_flight` uploads running. Adding an object waits while that many
+//! are running, so memory stays bounded to about `max_in_flight` packs.
+//! `AsyncTransaction` is a `Transaction` on top of it and orders its writes
+//! the same way, see `transaction`. Packs that were still uploading when a
+//! transaction failed or was dropped are left for `Repo::reclaim_orphans`.
+//!
+//! `Repo::read_objects_async` fetches a batch of objects, as a restore
+//! does, with one ranged get per object and at most `max_in_flight` gets
+//! running.
+//!
+//! Locks, indexes and the manifest are few and small and still go through
+//! the repository's blocking engine. The async engine passed in must reach
+//! the same storage.
+
+use super::address::Address;
+use super::compress;
+use super::crypto::unseal;
+use super::index::{PackIndex, RepoIndex};
+use super::lock::{LockMode, RepoLock};
+use super::manifest::SnapshotHead;
+use super::object::ObjectKind;
+use super::pack::{
+    open_header, FinishedPack, PackId, PackWriter, PackerOptions, Recipients, PACK_HEADER_SZ,
+    PACK_SLOTS,
+};
+use super::parity::{parity_key, Parity};
+use super::storage::aio::AsyncStorageEngine;
+use super::{Repo, RepoError, PACKS_DIR};
+use asymcrypt::Key;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::{JoinError, JoinSet};
+use tweetnacl::CryptoBoxSk;
+
+fn join_error(e: JoinError) -> RepoError {
+    RepoError::StorageError(format!("transfer task failed: {}", e))
+}
+
+pub struct AsyncPacker {
+    storage: Arc<dyn AsyncStorageEngine>,
+    recipients: Recipients,
+    opts: PackerOptions,
+    slots: Arc<Semaphore>,
+    current: [Option<(PackId, PackWriter<Vec<u8>>)>; PACK_SLOTS],
+    uploads: JoinSet<Result<FinishedPack, RepoError>>,
+    finished: Vec<FinishedPack>,
+}
+
+impl AsyncPacker {
+    pub fn new(
+        storage: Arc<dyn AsyncStorageEngine>,
+        recipients: &Recipients,
+        opts: PackerOptions,
+        max_in_flight: usize,
+    ) -> AsyncPacker {
+        AsyncPacker {
+            storage,
+            recipients: recipients.clone(),
+            opts,
+            slots: Arc::new(Semaphore::new(max_in_flight.max(1))),
+            current: Default::default(),
+            uploads: JoinSet::new(),
+            finished: Vec::new(),
+        }
+    }
+
+    pub async fn add(
+        &mut self,
+        address: &Address,
+        kind: ObjectKind,
+        data: &[u8],
+    ) -> Result<(), RepoError> {
+        let slot = self.recipients.slot(kind);
+        if self.current[slot].is_none() {
+            let w = self.recipients.writer(slot)?;
+            self.current[slot] = Some((PackId::new(), w));
+        }
+        let should_roll = {
+            let (_, w) = self.current[slot].as_mut().unwrap();
+            w.add(address, kind, data)?;
+            w.size() >= self.opts.target_size || w.age() >= self.opts.max_age
+        };
+        if should_roll {
+            self.flush_slot(slot).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn flush_if_stale(&mut self) -> Result<(), RepoError> {
+        for slot in 0..PACK_SLOTS {
+            let stale = match self.current[slot] {
+                Some((_, ref w)) => w.age() >= self.opts.max_age,
+                None => false,
+            };
+            if stale {
+                self.flush_slot(slot).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Start uploading the current packs.
+    pub async fn flush(&mut self) -> Result<(), RepoError> {
+        for slot in 0..PACK_SLOTS {
+            self.flush_slot(slot).await?;
+        }
+        Ok(())
+    }
+
+    // Start uploading the current pack of `slot`, waiting for a free
+    // upload slot first.
+    async fn flush_slot(&mut self, slot: usize) -> Result<(), RepoError> {
+        let (id, w) = match self.current[slot].take() {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+        let (buf, entries, size) = w.finish()?;
+        let permit = match self.slots.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => unreachable!("the semaphore is never closed"),
+        };
+        let storage = self.storage.clone();
+        let parity = self.opts.parity.clone();
+        self.uploads.spawn(async move {
+            let _permit = permit;
+            let parity = match parity {
+                Some(ref opts) => Some(Parity::generate(id, &buf, opts)?.encode()),
+                None => None,
+            };
+            storage
+                .put(&format!("{}/{}", PACKS_DIR, id.to_hex()), buf)
+                .await?;
+            if let Some(parity) = parity {
+                storage.put(&parity_key(&id), parity).await?;
+            }
+            Ok(FinishedPack { id, size, entries })
+        });
+        self.collect()
+    }
+
+    // Gather finished uploads without waiting, returning the first error.
+    fn collect(&mut self) -> Result<(), RepoError> {
+        while let Some(r) = self.uploads.try_join_next() {
+            self.finished.push(r.map_err(join_error)??);
+        }
+        Ok(())
+    }
+
+    // Wait for every upload started so far.
+    pub async fn wait(&mut self) -> Result<(), RepoError> {
+        let mut result = Ok(());
+        while let Some(r) = self.uploads.join_next().await {
+            match r.map_err(join_error).and_then(|r| r) {
+                Ok(p) => self.finished.push(p),
+                Err(e) => {
+                    if result.is_ok() {
+                        result = Err(e)
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    // Packs whose upload has completed.
+    pub fn take_finished(&mut self) -> Vec<FinishedPack> {
+        std::mem::take(&mut self.finished)
+    }
+
+    pub async fn finish(mut self) -> Result<Vec<FinishedPack>, RepoError> {
+        self.flush().await?;
+        self.wait().await?;
+        Ok(self.take_finished())
+    }
+}
+
+pub struct AsyncTransaction<'a> {
+    repo: &'a Repo,
+    lock: RepoLock,
+    packer: AsyncPacker,
+    // Stored packs that have no index yet.
+    unindexed: Vec<PackIndex>,
+    done: bool,
+}
+
+impl<'a> AsyncTransaction<'a> {
+    pub async fn add(
+        &mut self,
+        address: &Address,
+        kind: ObjectKind,
+        data: &[u8],
+    ) -> Result<(), RepoError> {
+        self.packer.add(address, kind, data).await?;
+        self.collect_finished();
+        Ok(())
+    }
+
+    pub async fn refresh(&mut self) -> Result<(), RepoError> {
+        self.lock.refresh()?;
+        self.packer.flush_if_stale().await?;
+        self.collect_finished();
+        Ok(())
+    }
+
+    fn collect_finished(&mut self) {
+        for p in self.packer.take_finished() {
+            self.unindexed.push(PackIndex::from_finished(&p));
+        }
+    }
+
+    pub async fn commit(mut self, head: SnapshotHead, key: &Key) -> Result<(), RepoError> {
+        self.packer.flush().await?;
+        let uploaded = self.packer.wait().await;
+        self.collect_finished();
+        if let Err(err) = uploaded.and_then(|_| self.repo.write_indexes(&mut self.unindexed)) {
+            let _ = self.rollback();
+            return Err(err);
+        }
+        self.done = true;
+        self.repo.publish_head(head, key)
+    }
+
+    // Discard the transaction, deleting any packs it stored.
+    pub async fn abort(mut self) -> Result<(), RepoError> {
+        let _ = self.packer.wait().await;
+        self.rollback()
+    }
+
+    fn rollback(&mut self) -> Result<(), RepoError> {
+        self.done = true;
+        self.collect_finished();
+        self.repo.delete_unindexed(&mut self.unindexed)
+    }
+}
+
+impl<'a> Drop for AsyncTransaction<'a> {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.rollback();
+        }
+    }
+}
+
+impl Repo {
+    pub fn begin_async(
+        &self,
+        storage: Arc<dyn AsyncStorageEngine>,
+        opts: PackerOptions,
+        max_in_flight: usize,
+    ) -> Result<AsyncTransaction<'_>, RepoError> {
+        let lock = self.lock(LockMode::Shared)?;
+        Ok(AsyncTransaction {
+            repo: self,
+            lock,
+            packer: AsyncPacker::new(storage, &self.recipients(), opts, max_in_flight),
+            unindexed: Vec::new(),
+            done: false,
+        })
+    }
+
+    // Read `addresses` in order. Pack keys are unwrapped once per pack.
+    pub async fn read_objects_async(
+        &self,
+        storage: Arc<dyn AsyncStorageEngine>,
+        sk: &CryptoBoxSk,
+        index: &RepoIndex,
+        addresses: &[Address],
+        max_in_flight: usize,
+    ) -> Result<Vec<Vec<u8>>, RepoError> {
+        let slots = Arc::new(Semaphore::new(max_in_flight.max(1)));
+        let mut locations = Vec::with_capacity(addresses.len());
+        let mut headers = JoinSet::new();
+        let mut keys = HashMap::new();
+        for address in addresses {
+            let loc = match index.lookup(address) {
+                Some(loc) => *loc,
+                None => return Err(RepoError::MissingObjectError),
+            };
+            if keys.insert(loc.pack_id, None).is_none() {
+                let storage = storage.clone();
+                let slots = slots.clone();
+                headers.spawn(async move {
+                    let _permit = slots.acquire().await;
+                    let key = format!("{}/{}", PACKS_DIR, loc.pack_id.to_hex());
+                    let header = storage.get_range(&key, 0, PACK_HEADER_SZ).await;
+                    (loc.pack_id, header)
+                });
+            }
+            locations.push(loc);
+        }
+        while let Some(r) = headers.join_next().await {
+            let (id, header) = r.map_err(join_error)?;
+            keys.insert(id, Some(Arc::new(open_header(&header?, sk)?)));
+        }
+
+        let mut reads = JoinSet::new();
+        let mut objects = vec![Vec::new(); addresses.len()];
+        for (i, loc) in locations.into_iter().enumerate() {
+            let permit = match slots.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => unreachable!("the semaphore is never closed"),
+            };
+            let storage = storage.clone();
+            let pack_key = keys[&loc.pack_id].clone().unwrap();
+            reads.spawn(async move {
+                let _permit = permit;
+                let key = format!("{}/{}", PACKS_DIR, loc.pack_id.to_hex());
+                let sealed = storage
+                    .get_range(&key, loc.offset, loc.length as usize)
+                    .await?;
+                let data = compress::decode(&unseal(&pack_key, &sealed)?)?;
+                Ok::<_, RepoError>((i, data))
+            });
+            while let Some(r) = reads.try_join_next() {
+                let (i, data) = r.map_err(join_error)??;
+                objects[i] = data;
+            }
+        }
+        while let Some(r) = reads.join_next().await {
+            let (i, data) = r.map_err(join_error)??;
+            objects[i] = data;
+        }
+        Ok(objects)
+    }
+}
+
+// Tests --------------------
+
+// Counts the most `get_range` calls ever running at once.
+#[cfg(test)]
+struct TestSlowStorage {
+    inner: super::storage::aio::Blocking,
+    running: std::sync::atomic::AtomicUsize,
+    most: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl AsyncStorageEngine for TestSlowStorage {
+    fn put<'a>(
+        &'a self,
+        key: &'a str,
+        data: Vec<u8>,
+    ) -> super::storage::aio::BoxFuture<'a, Result<(), RepoError>> {
+        self.inner.put(key, data)
+    }
+
+    fn get<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> super::storage::aio::BoxFuture<'a, Result<Vec<u8>, RepoError>> {
+        self.inner.get(key)
+    }
+
+    fn get_range<'a>(
+        &'a self,
+        key: &'a str,
+        offset: u64,
+        len: usize,
+    ) -> super::storage::aio::BoxFuture<'a, Result<Vec<u8>, RepoError>> {
+        use std::sync::atomic::Ordering;
+        Box::pin(async move {
+            let n = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most.fetch_max(n, Ordering::SeqCst);
+            let r = self.inner.get_range(key, offset, len).await;
+            tokio::task::spawn_blocking(|| std::thread::sleep(std::time::Duration::from_millis(5)))
+                .await
+                .unwrap();
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            r
+        })
+    }
+
+    fn size<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> super::storage::aio::BoxFuture<'a, Result<u64, RepoError>> {
+        self.inner.size(key)
+    }
+
+    fn list_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> super::storage::aio::BoxFuture<'a, Result<Vec<String>, RepoError>> {
+        self.inner.list_prefix(prefix)
+    }
+
+    fn delete<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> super::storage::aio::BoxFuture<'a, Result<(), RepoError>> {
+        self.inner.delete(key)
+    }
+
+    fn capabilities(&self) -> super::storage::Capabilities {
+        self.inner.capabilities()
+    }
+}
+
+#[test]
+fn test_async_transaction() {
+    use super::storage::aio::Blocking;
+    use std::sync::atomic::Ordering;
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (r, key) = super::test_repo();
+    let storage = Arc::new(TestSlowStorage {
+        inner: Blocking::new(r.storage().clone()),
+        running: Default::default(),
+        most: Default::default(),
+    });
+    let opts = PackerOptions {
+        target_size: 1000,
+        parity: Some(Default::default()),
+        ..Default::default()
+    };
+    let objects: Vec<(Address, Vec<u8>)> = (0..50u8)
+        .map(|i| {
+            let mut data = vec![0; 300];
+            tweetnacl::random_bytes(&mut data);
+            (Address { bytes: [i; 32] }, data)
+        })
+        .collect();
+
+    let head = SnapshotHead {
+        address: objects[0].0,
+        timestamp: 1,
+        namespace: super::namespace::Namespace::new("laptop").unwrap(),
+        retain_until: 0,
+        amendment: None,
+    };
+    rt.block_on(async {
+        let mut tx = r.begin_async(storage.clone(), opts.clone(), 4).unwrap();
+        for (a, data) in objects.iter() {
+            tx.add(a, ObjectKind::Chunk, data).await.unwrap();
+        }
+        tx.commit(head.clone(), &key).await.unwrap();
+    });
+    assert_eq!(r.manifest().unwrap().heads, vec![head]);
+    assert!(r.list_locks().unwrap().is_empty());
+    assert!(r.list_packs().unwrap().len() > 10);
+    let fsck = super::fsck::FsckOptions {
+        read_data: true,
+        ..Default::default()
+    };
+    assert!(r.fsck(Some(&key.box_sk), &fsck).unwrap().is_ok());
+
+    let index = r.load_index().unwrap();
+    let addresses: Vec<Address> = objects.iter().rev().map(|(a, _)| *a).collect();
+    let read = rt
+        .block_on(r.read_objects_async(storage.clone(), &key.box_sk, &index, &addresses, 8))
+        .unwrap();
+    for (data, (_, expected)) in read.iter().zip(objects.iter().rev()) {
+        assert_eq!(data, expected);
+    }
+    let most = storage.most.load(Ordering::SeqCst);
+    assert!(most > 1 && most <= 8);
+
+    match rt.block_on(r.read_objects_async(
+        storage.clone(),
+        &key.box_sk,
+        &index,
+        &[Address { bytes: [99; 32] }],
+        8,
+    )) {
+        Err(RepoError::MissingObjectError) => (),
+        _ => panic!("expected an unknown object to be refused"),
+    }
+
+    // Aborting deletes every uploaded pack.
+    let packs = r.list_packs().unwrap().len();
+    rt.block_on(async {
+        let mut tx = r.begin_async(storage.clone(), opts, 4).unwrap();
+        for (a, data) in objects.iter() {
+            tx.add(a, ObjectKind::Chunk, data).await.unwrap();
+        }
+        tx.abort().await.unwrap();
+    });
+    assert_eq!(r.list_packs().unwrap().len(), packs);
+    assert!(r.list_locks().unwrap().is_empty());
+}
@@ -0,0 +1,142 @@
+//! The repository policy file.
+//!
+//! The config fixes whether a repository starts out append only. The
+//! policy overrides that choice later, but it must be signed by the
+//! maintenance key named in the config, never by the owner or a writer
+//! key. Backup clients only hold writer or owner keys, so a compromised
+//! client cannot lift append only mode and then destroy old snapshots.
+//!
+//! Every policy carries a serial that must increase with each
+//! replacement, which stops an old policy that lifted append only mode
+//! being replayed after it was reinstated.
+//!
+//! Writers in different namespaces, see `namespace`, share one address key
+//! and so deduplicate against each other: a chunk another namespace stored
+//! first costs nothing, and stays as long as anyone refers to it. With
+//! `isolate_namespaces` each namespace addresses the snapshots made from
+//! then on with a key of its own, see `Repo::namespace_address_key`, and
+//! shares no chunks with the others, so what a namespace stores is its own
+//! to be counted against its quota and freed when it is pruned.
+//!
+//! Format (inside the signature envelope, see `signed`):
+//!
+//! ```text
+//! "PNBPOLICY" u16:format_version [16]:repo_id u64:serial bool:append_only
+//! bool:isolate_namespaces
+//! ```
+
+use super::config::RepoConfig;
+use super::manifest::RepoId;
+use super::signed;
+use super::wire::{Decoder, Encoder};
+use super::RepoError;
+use tweetnacl::*;
+
+pub const POLICY_FORMAT_VERSION: u16 = 1;
+const POLICY_MAGIC: &[u8] = b"PNBPOLICY";
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Policy {
+    pub format_version: u16,
+    pub repo_id: RepoId,
+    pub serial: u64,
+    pub append_only: bool,
+    pub isolate_namespaces: bool,
+}
+
+impl Policy {
+    // The policy in force before any policy file is written.
+    pub fn from_config(config: &RepoConfig) -> Policy {
+        Policy {
+            format_version: POLICY_FORMAT_VERSION,
+            repo_id: config.repo_id,
+            serial: 0,
+            append_only: config.append_only,
+            isolate_namespaces: false,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.fixed(POLICY_MAGIC)
+            .u16(self.format_version)
+            .fixed(&self.repo_id.bytes)
+            .u64(self.serial)
+            .bool(self.append_only)
+            .bool(self.isolate_namespaces);
+        e.into_vec()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Policy, RepoError> {
+        let mut d = Decoder::new(buf);
+        if d.fixed(POLICY_MAGIC.len())? != POLICY_MAGIC {
+            return Err(RepoError::InvalidDataError);
+        }
+        let format_version = d.u16()?;
+        if format_version != POLICY_FORMAT_VERSION {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        let mut repo_id: RepoId = Default::default();
+        d.fixed_into(&mut repo_id.bytes)?;
+        let serial = d.u64()?;
+        let append_only = d.bool()?;
+        let isolate_namespaces = d.bool()?;
+        d.finish()?;
+        Ok(Policy {
+            format_version,
+            repo_id,
+            serial,
+            append_only,
+            isolate_namespaces,
+        })
+    }
+
+    pub fn sign(&self, sk: &CryptoSignSk) -> Vec<u8> {
+        signed::sign(&self.encode(), sk)
+    }
+
+    pub fn open(sm: &[u8], pk: &CryptoSignPk) -> Result<Policy, RepoError> {
+        Policy::decode(&signed::open(sm, pk)?)
+    }
+
+    // Open a stored policy for the repository described by `config`.
+    pub fn open_for(sm: &[u8], config: &RepoConfig) -> Result<Policy, RepoError> {
+        let pk = match config.maintenance_pk {
+            Some(ref pk) => pk,
+            None => return Err(RepoError::SignatureFailedError),
+        };
+        let p = Policy::open(sm, pk)?;
+        if p.repo_id != config.repo_id {
+            return Err(RepoError::RepoMismatchError);
+        }
+        Ok(p)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_policy_sign_open() {
+    let (pk, sk) = boxed_crypto_sign_keypair();
+    let mut config: RepoConfig = Default::default();
+    let mut p = Policy::from_config(&config);
+    p.serial = 3;
+    assert_eq!(Policy::open(&p.sign(&sk), &pk).unwrap(), p);
+    let isolated = Policy {
+        isolate_namespaces: true,
+        ..p.clone()
+    };
+    assert_eq!(Policy::open(&isolated.sign(&sk), &pk).unwrap(), isolated);
+
+    match Policy::open_for(&p.sign(&sk), &config) {
+        Err(RepoError::SignatureFailedError) => (),
+        _ => panic!("expected signature failure without a maintenance key"),
+    }
+    config.maintenance_pk = Some((*pk).clone());
+    assert_eq!(Policy::open_for(&p.sign(&sk), &config).unwrap(), p);
+    p.repo_id = RepoId::new();
+    match Policy::open_for(&p.sign(&sk), &config) {
+        Err(RepoError::RepoMismatchError) => (),
+        _ => panic!("expected repo mismatch"),
+    }
+}
@@ -0,0 +1,96 @@
+//! Asking the server which objects it already has.
+//!
+//! Before uploading, a client wants to skip chunks the repository already
+//! holds. Object addresses are keyed hashes, see `address`, so a server
+//! without the address key learns nothing about the plaintext from them,
+//! it only sees the addresses it would store anyway. `objects_present`
+//! sends them in batches of up to `MAX_PRESENCE_QUERY` and the server
+//! answers each with a bitmap, instead of the client fetching every pack
+//! index first.
+//!
+//! ```text
+//! bitmap: bit i, counting from the low bit of the first byte, is set if
+//!         address i is in a pack index or stored loose
+//! ```
+//!
+//! Storage that cannot answer, anything but `serve`, is queried through
+//! the indexes and loose objects instead.
+
+use super::address::Address;
+use super::{Repo, RepoError};
+
+// Addresses per query, a bitmap of 8KiB.
+pub const MAX_PRESENCE_QUERY: usize = 64 * 1024;
+
+pub fn encode_bitmap(bits: &[bool]) -> Vec<u8> {
+    let mut buf = vec![0; bits.len().div_ceil(8)];
+    for (i, _) in bits.iter().enumerate().filter(|(_, b)| **b) {
+        buf[i / 8] |= 1 << (i % 8);
+    }
+    buf
+}
+
+// Trailing bits past `n` must be clear.
+pub fn decode_bitmap(buf: &[u8], n: usize) -> Result<Vec<bool>, RepoError> {
+    if buf.len() != n.div_ceil(8) {
+        return Err(RepoError::InvalidDataError);
+    }
+    let bits: Vec<bool> = (0..buf.len() * 8)
+        .map(|i| buf[i / 8] & (1 << (i % 8)) != 0)
+        .collect();
+    if bits[n..].contains(&true) {
+        return Err(RepoError::InvalidDataError);
+    }
+    Ok(bits[..n].to_vec())
+}
+
+impl Repo {
+    // Whether each of `addresses` is stored, asking the server if it can.
+    pub fn objects_present(&self, addresses: &[Address]) -> Result<Vec<bool>, RepoError> {
+        match self.storage().objects_present(addresses) {
+            Err(RepoError::UnsupportedOperationError) => self.find_objects(addresses),
+            r => r,
+        }
+    }
+
+    // Look `addresses` up in the pack indexes, then among loose objects.
+    pub(crate) fn find_objects(&self, addresses: &[Address]) -> Result<Vec<bool>, RepoError> {
+        let index = self.load_index()?;
+        let mut present: Vec<bool> = addresses.iter().map(|a| index.contains(a)).collect();
+        let unindexed: Vec<usize> = (0..addresses.len()).filter(|i| !present[*i]).collect();
+        let keys: Vec<String> = unindexed
+            .iter()
+            .map(|i| self.loose_key(&addresses[*i]))
+            .collect();
+        let keys: Vec<&str> = keys.iter().map(|k| &k[..]).collect();
+        for (i, exists) in unindexed.iter().zip(self.storage().exists_many(&keys)?) {
+            present[*i] = exists;
+        }
+        Ok(present)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_bitmap() {
+    let bits: Vec<bool> = (0..11).map(|i| i % 3 == 0).collect();
+    let buf = encode_bitmap(&bits);
+    assert_eq!(buf, vec![0b0100_1001, 0b0000_0010]);
+    assert_eq!(decode_bitmap(&buf, 11).unwrap(), bits);
+    assert!(decode_bitmap(&buf, 17).is_err());
+    assert!(decode_bitmap(&buf, 8).is_err());
+    assert!(decode_bitmap(&[0x80], 7).is_err());
+    assert!(decode_bitmap(&[], 0).unwrap().is_empty());
+}
+
+#[test]
+fn test_find_objects() {
+    use super::object::ObjectKind;
+    let (r, key) = super::test_repo();
+    let a = |b: u8| Address { bytes: [b; 32] };
+    super::gc::test_commit_tree(&r, &key, 1, &[a(10)]);
+    r.put_loose(&a(20), ObjectKind::Chunk, b"loose").unwrap();
+    let present = r.objects_present(&[a(10), a(20), a(30)]).unwrap();
+    assert_eq!(present, vec![true, true, false]);
+}
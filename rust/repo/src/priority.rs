@@ -0,0 +1,249 @@
+//! Running backups in the background.
+//!
+//! A scheduled backup on a desktop should leave the machine usable. The
+//! `[priority]` table of the settings, see `settings`, lowers what the
+//! process asks of it:
+//!
+//! ```text
+//! nice   the CPU nice value, -20 to 19, 19 yielding to everything
+//! io     the I/O scheduling class on Linux, see ioprio_set(2):
+//!        "idle", only using a disk no one else is, "best-effort" or
+//!        "realtime", each optionally with a level, 0 to 7, 0 first,
+//!        as in "best-effort:7"
+//! low    a low priority run: nice 19 and the idle class unless set
+//!        otherwise, and one worker thread with little read ahead
+//! ```
+//!
+//! `Priority::apply` sets the nice value and class. On Linux both belong
+//! to a thread and are inherited by the threads it starts, so it must be
+//! called before a backup or restore starts any. Raising a priority, a
+//! negative nice value or the realtime class, needs root.
+//! `Priority::limit_backup` and `Priority::limit_restore` cap the worker
+//! threads and queues of a low priority run, a backup then reads one
+//! chunk ahead and lists directories as it walks them.
+
+use super::backup::BackupOptions;
+use super::restore::RestoreOptions;
+use std::io;
+
+const PRIO_PROCESS: i32 = 0;
+#[cfg(target_os = "linux")]
+const IOPRIO_WHO_PROCESS: i64 = 1;
+
+mod sys {
+    use std::os::raw::{c_int, c_long, c_uint};
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    pub const SYS_IOPRIO_SET: c_long = 251;
+    #[cfg(all(target_os = "linux", target_arch = "x86"))]
+    pub const SYS_IOPRIO_SET: c_long = 289;
+    #[cfg(all(target_os = "linux", target_arch = "arm"))]
+    pub const SYS_IOPRIO_SET: c_long = 314;
+    #[cfg(all(
+        target_os = "linux",
+        any(
+            target_arch = "aarch64",
+            target_arch = "riscv64",
+            target_arch = "loongarch64"
+        )
+    ))]
+    pub const SYS_IOPRIO_SET: c_long = 30;
+
+    extern "C" {
+        pub fn setpriority(which: c_int, who: c_uint, prio: c_int) -> c_int;
+        #[cfg(test)]
+        pub fn getpriority(which: c_int, who: c_uint) -> c_int;
+        #[cfg(target_os = "linux")]
+        pub fn syscall(num: c_long, ...) -> c_long;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IoClass {
+    Realtime(u8),
+    BestEffort(u8),
+    Idle,
+}
+
+impl IoClass {
+    // "idle", "best-effort" or "realtime", the last two with an optional
+    // ":level", 4 if none as for the kernel.
+    pub fn parse(s: &str) -> Option<IoClass> {
+        let (name, level) = match s.split_once(':') {
+            Some((name, level)) => (name, Some(level.parse().ok().filter(|l| *l < 8)?)),
+            None => (s, None),
+        };
+        match (name, level) {
+            ("realtime", level) => Some(IoClass::Realtime(level.unwrap_or(4))),
+            ("best-effort", level) => Some(IoClass::BestEffort(level.unwrap_or(4))),
+            ("idle", None) => Some(IoClass::Idle),
+            _ => None,
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn ioprio(self) -> i64 {
+        match self {
+            IoClass::Realtime(level) => (1 << 13) | level as i64,
+            IoClass::BestEffort(level) => (2 << 13) | level as i64,
+            IoClass::Idle => 3 << 13,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Priority {
+    pub nice: Option<i32>,
+    pub io: Option<IoClass>,
+    pub low: bool,
+}
+
+impl Priority {
+    // The nice value to set, if any.
+    pub fn nice(&self) -> Option<i32> {
+        self.nice.or(if self.low { Some(19) } else { None })
+    }
+
+    // The I/O class to set, if any.
+    pub fn io_class(&self) -> Option<IoClass> {
+        self.io
+            .or(if self.low { Some(IoClass::Idle) } else { None })
+    }
+
+    // Set the nice value and I/O class of the calling thread, and so of
+    // the threads it starts from now on.
+    pub fn apply(&self) -> io::Result<()> {
+        if let Some(nice) = self.nice() {
+            if !(-20..=19).contains(&nice) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("nice value {} is not from -20 to 19", nice),
+                ));
+            }
+            if unsafe { sys::setpriority(PRIO_PROCESS, 0, nice) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(class) = self.io_class() {
+            set_io_class(class)?;
+        }
+        Ok(())
+    }
+
+    pub fn limit_backup(&self, opts: &mut BackupOptions) {
+        if self.low {
+            opts.upload.workers = 1;
+            opts.upload.sealers = 1;
+            opts.upload.uploads = 1;
+            opts.upload.queue_depth = 1;
+            opts.scan_threads = 0;
+        }
+    }
+
+    pub fn limit_restore(&self, opts: &mut RestoreOptions) {
+        if self.low {
+            opts.workers = 1;
+        }
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86_64",
+        target_arch = "x86",
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "riscv64",
+        target_arch = "loongarch64"
+    )
+))]
+fn set_io_class(class: IoClass) -> io::Result<()> {
+    // ioprio_set(2) has no C library wrapper.
+    if unsafe {
+        sys::syscall(
+            sys::SYS_IOPRIO_SET,
+            IOPRIO_WHO_PROCESS,
+            0i64,
+            class.ioprio(),
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86_64",
+        target_arch = "x86",
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "riscv64",
+        target_arch = "loongarch64"
+    )
+)))]
+fn set_io_class(_class: IoClass) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "I/O scheduling classes are only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+fn current_nice() -> i32 {
+    unsafe { sys::getpriority(PRIO_PROCESS, 0) }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_priority() {
+    assert_eq!(IoClass::parse("idle"), Some(IoClass::Idle));
+    assert_eq!(IoClass::parse("best-effort"), Some(IoClass::BestEffort(4)));
+    assert_eq!(IoClass::parse("realtime:0"), Some(IoClass::Realtime(0)));
+    for bad in ["idle:3", "best-effort:8", "best-effort:", "low", ""].iter() {
+        assert_eq!(IoClass::parse(bad), None, "{}", bad);
+    }
+
+    let low = Priority {
+        low: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        (low.nice(), low.io_class()),
+        (Some(19), Some(IoClass::Idle))
+    );
+    let mut opts = BackupOptions::default();
+    low.limit_backup(&mut opts);
+    assert_eq!(
+        (opts.upload.workers, opts.upload.sealers, opts.scan_threads),
+        (1, 1, 0)
+    );
+    let mut opts = RestoreOptions::default();
+    Priority::default().limit_restore(&mut opts);
+    assert_eq!(opts.workers, 8);
+    low.limit_restore(&mut opts);
+    assert_eq!(opts.workers, 1);
+
+    // In a thread of its own, so the tests after run as before.
+    std::thread::spawn(move || {
+        let p = Priority {
+            nice: Some(10),
+            ..low
+        };
+        p.apply().unwrap();
+        assert_eq!(current_nice(), 10);
+        let child = std::thread::spawn(current_nice).join().unwrap();
+        assert_eq!(child, 10);
+        let bad = Priority {
+            nice: Some(20),
+            ..Default::default()
+        };
+        assert!(bad.apply().is_err());
+    })
+    .join()
+    .unwrap();
+}
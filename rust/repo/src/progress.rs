@@ -0,0 +1,296 @@
+//! Progress of long running operations.
+//!
+//! A `Progress` is shared between an operation and whoever reports on it.
+//! Its counters are atomics, any thread may update them. A backup counts
+//! the files and directories walked, the bytes of the files read or taken
+//! from the stat cache, and the bytes of the packs put, what is left after
+//! deduplication and sealing. `Progress::state` reads the counters along
+//! with the rate and, once the bytes to read are known, the time left.
+//!
+//! A `Reporter` thread hands the state to a function every interval and
+//! once more when it is dropped, with `done` set. `write_terminal` keeps a
+//! one line summary on a terminal, `write_json` emits one JSON event per
+//! line for wrappers:
+//!
+//! ```text
+//! {"files": n, "dirs": n, "bytes_read": n, "bytes_total": n|null,
+//!  "bytes_uploaded": n, "elapsed_secs": x, "eta_secs": n|null,
+//!  "done": b}
+//! ```
+//!
+//! The total is an estimate, for a backup the size of the last snapshot of
+//! the same source, so there is no time left once more than it was read.
+
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub struct Progress {
+    started: Instant,
+    files: AtomicU64,
+    dirs: AtomicU64,
+    bytes_read: AtomicU64,
+    // 0 while unknown.
+    bytes_total: AtomicU64,
+    bytes_uploaded: AtomicU64,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ProgressState {
+    pub files: u64,
+    pub dirs: u64,
+    pub bytes_read: u64,
+    pub bytes_total: Option<u64>,
+    pub bytes_uploaded: u64,
+    pub elapsed: Duration,
+    pub done: bool,
+}
+
+impl Default for Progress {
+    fn default() -> Progress {
+        Progress::new()
+    }
+}
+
+impl Progress {
+    pub fn new() -> Progress {
+        Progress {
+            started: Instant::now(),
+            files: AtomicU64::new(0),
+            dirs: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            bytes_total: AtomicU64::new(0),
+            bytes_uploaded: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set_walked(&self, files: u64, dirs: u64, bytes_read: u64) {
+        self.files.store(files, Ordering::Relaxed);
+        self.dirs.store(dirs, Ordering::Relaxed);
+        self.bytes_read.store(bytes_read, Ordering::Relaxed);
+    }
+
+    pub fn add_read(&self, n: u64) {
+        self.bytes_read.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn add_uploaded(&self, n: u64) {
+        self.bytes_uploaded.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn total(&self) -> Option<u64> {
+        Some(self.bytes_total.load(Ordering::Relaxed)).filter(|n| *n != 0)
+    }
+
+    pub fn set_total(&self, n: u64) {
+        self.bytes_total.store(n, Ordering::Relaxed);
+    }
+
+    pub fn state(&self) -> ProgressState {
+        ProgressState {
+            files: self.files.load(Ordering::Relaxed),
+            dirs: self.dirs.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_total: self.total(),
+            bytes_uploaded: self.bytes_uploaded.load(Ordering::Relaxed),
+            elapsed: self.started.elapsed(),
+            done: false,
+        }
+    }
+}
+
+impl ProgressState {
+    // Bytes read per second.
+    pub fn rate(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.bytes_read as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    pub fn eta(&self) -> Option<Duration> {
+        let total = self.bytes_total?;
+        let rate = self.rate();
+        if self.done || self.bytes_read > total || rate <= 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs(
+            ((total - self.bytes_read) as f64 / rate).ceil() as u64,
+        ))
+    }
+}
+
+pub fn human_bytes(n: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if n < 1024 {
+        return format!("{} B", n);
+    }
+    let mut v = n as f64 / 1024.0;
+    let mut unit = 0;
+    while v >= 1024.0 && unit + 1 < UNITS.len() {
+        v /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", v, UNITS[unit])
+}
+
+fn human_duration(d: Duration) -> String {
+    let s = d.as_secs();
+    if s >= 3600 {
+        format!("{}h{:02}m", s / 3600, s / 60 % 60)
+    } else if s >= 60 {
+        format!("{}m{:02}s", s / 60, s % 60)
+    } else {
+        format!("{}s", s)
+    }
+}
+
+pub fn render(state: &ProgressState) -> String {
+    let mut line = format!(
+        "{} files, {} dirs, {}",
+        state.files,
+        state.dirs,
+        human_bytes(state.bytes_read)
+    );
+    if let Some(total) = state.bytes_total {
+        line.push_str(&format!(" of ~{}", human_bytes(total)));
+    }
+    line.push_str(&format!(
+        " read, {} uploaded, {}/s",
+        human_bytes(state.bytes_uploaded),
+        human_bytes(state.rate() as u64)
+    ));
+    if state.done {
+        line.push_str(&format!(", done in {}", human_duration(state.elapsed)));
+    } else if let Some(eta) = state.eta() {
+        line.push_str(&format!(", {} left", human_duration(eta)));
+    }
+    line
+}
+
+// Overwrite the current terminal line, ending it once done.
+pub fn write_terminal(w: &mut dyn Write, state: &ProgressState) -> io::Result<()> {
+    write!(w, "\r{}\x1b[K", render(state))?;
+    if state.done {
+        writeln!(w)?;
+    }
+    w.flush()
+}
+
+pub fn write_json(w: &mut dyn Write, state: &ProgressState) -> io::Result<()> {
+    let opt = |v: Option<u64>| v.map_or("null".to_string(), |v| v.to_string());
+    writeln!(
+        w,
+        "{{\"files\": {}, \"dirs\": {}, \"bytes_read\": {}, \"bytes_total\": {}, \
+         \"bytes_uploaded\": {}, \"elapsed_secs\": {:.3}, \"eta_secs\": {}, \"done\": {}}}",
+        state.files,
+        state.dirs,
+        state.bytes_read,
+        opt(state.bytes_total),
+        state.bytes_uploaded,
+        state.elapsed.as_secs_f64(),
+        opt(state.eta().map(|d| d.as_secs())),
+        state.done
+    )?;
+    w.flush()
+}
+
+pub type ReportFn = Box<dyn FnMut(&ProgressState) + Send>;
+
+pub struct Reporter {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Reporter {
+    pub fn start(progress: Arc<Progress>, interval: Duration, mut report: ReportFn) -> Reporter {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let (ref stopped, ref cv) = *stop;
+                let mut stopped = stopped.lock().unwrap();
+                loop {
+                    stopped = cv.wait_timeout(stopped, interval).unwrap().0;
+                    let mut state = progress.state();
+                    state.done = *stopped;
+                    report(&state);
+                    if state.done {
+                        return;
+                    }
+                }
+            })
+        };
+        Reporter {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Reporter {
+    fn drop(&mut self) {
+        *self.stop.0.lock().unwrap() = true;
+        self.stop.1.notify_all();
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_progress() {
+    let p = Progress::new();
+    p.set_walked(3, 1, 1000);
+    p.add_read(24);
+    p.add_uploaded(600);
+    let mut state = p.state();
+    assert_eq!((state.files, state.dirs, state.bytes_read), (3, 1, 1024));
+    assert_eq!((state.bytes_total, state.eta()), (None, None));
+
+    state.elapsed = Duration::from_secs(2);
+    state.bytes_total = Some(10 * 1024);
+    assert_eq!(state.eta(), Some(Duration::from_secs(18)));
+    assert_eq!(
+        render(&state),
+        "3 files, 1 dirs, 1.0 KiB of ~10.0 KiB read, 600 B uploaded, 512 B/s, 18s left"
+    );
+    let mut json = Vec::new();
+    write_json(&mut json, &state).unwrap();
+    assert_eq!(
+        String::from_utf8(json).unwrap(),
+        "{\"files\": 3, \"dirs\": 1, \"bytes_read\": 1024, \"bytes_total\": 10240, \
+         \"bytes_uploaded\": 600, \"elapsed_secs\": 2.000, \"eta_secs\": 18, \"done\": false}\n"
+    );
+    state.bytes_read = 20 * 1024;
+    assert_eq!(state.eta(), None);
+    assert_eq!(human_duration(Duration::from_secs(3725)), "1h02m");
+    assert_eq!(human_bytes(3 << 30), "3.0 GiB");
+
+    // The reporter ends with a done state.
+    let p = Arc::new(Progress::new());
+    let states = Arc::new(Mutex::new(Vec::new()));
+    let reporter = {
+        let states = states.clone();
+        Reporter::start(
+            p.clone(),
+            Duration::from_millis(10),
+            Box::new(move |s| states.lock().unwrap().push(s.clone())),
+        )
+    };
+    p.set_walked(1, 1, 1);
+    thread::sleep(Duration::from_millis(50));
+    drop(reporter);
+    let states = states.lock().unwrap();
+    assert!(states.len() >= 2);
+    assert!(states[..states.len() - 1].iter().all(|s| !s.done));
+    assert!(states.last().unwrap().done);
+    assert_eq!(states.last().unwrap().files, 1);
+}
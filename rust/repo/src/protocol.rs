@@ -0,0 +1,828 @@
+//! The remote repository protocol.
+//!
+//! Spoken over any reliable byte stream, normally the stdin and stdout of
+//! `ssh host packnback serve <dir>`. Giving clients this protocol instead
+//! of raw file access lets the server decide what a client may do, most
+//! importantly refusing to delete or overwrite existing data.
+//!
+//! A client starts each connection with HELLO, giving the newest protocol
+//! version it speaks and the optional features it has, and the server
+//! answers with the version the session speaks, the older of the two, and
+//! the features both have. Unknown feature bits are ignored, so a feature
+//! can be added to either end first. A server from before HELLO refuses it
+//! as unsupported, and the client then tries what it needs and falls back
+//! when refused, as it always did. Servers still serve clients that never
+//! say hello.
+//!
+//! The client sends one request frame and reads the response before
+//! sending the next. Over a slow link that costs a round trip per request,
+//! so many small requests, such as checking which objects exist or storing
+//! small ones, can be sent as one BATCH instead. Each request in a batch
+//! carries an id chosen by the client. The server answers the batch itself,
+//! then each request in a frame of its own tagged with its id, in whatever
+//! order they complete. Requests in a batch may run concurrently and must
+//! not depend on each other, and a batch may not contain another.
+//!
+//! A server started to authenticate clients, see `ServeOptions`, refuses
+//! every request until the client has signed a fresh challenge with a key
+//! the access list names for it, see `acl`. The client asks for a random
+//! CHALLENGE, signs `auth_message` of it and its client name, and sends
+//! the signed message with AUTH. Each challenge is good for one AUTH, and
+//! the session is then the client's. Neither may be in a batch.
+//!
+//! A server hosting many repositories, see `tenant`, refuses everything
+//! else until the client selects one with TENANT, before it authenticates,
+//! and the session then stays with that repository and its access list.
+//!
+//! A client that may lose its connection asks for a SESSION token, and
+//! starts the next connection with RESUME instead of selecting its tenant
+//! and authenticating again. RESUME names the batch the client had in
+//! flight, counted from 1 within the session, and the server answers with
+//! the batches it started and, if the last is that batch, the responses to
+//! it so far, see `session`.
+//!
+//! ```text
+//! frame:    u32:len [len]:payload
+//!
+//! request:  u8:op ...
+//!   PUT          str:key bytes:data
+//!   GET          str:key
+//!   GET_RANGE    str:key u64:offset u32:len
+//!   SIZE         str:key
+//!   LIST         str:prefix
+//!   DELETE       str:key
+//!   CAPABILITIES
+//!   THAW         str:key
+//!   EXISTS       str:key
+//!   BATCH        u32:n n * (u32:id bytes:request)
+//!   GC           bytes:keep_list
+//!   PRESENT      u32:n n * [32]:address
+//!   CHALLENGE
+//!   AUTH         str:client bytes:signed
+//!   CONSISTENCY  u64:old_size u64:new_size
+//!   TENANT       str:name
+//!   QUOTA
+//!   SESSION
+//!   RESUME       [32]:token u64:batch
+//!   HELLO        hello
+//!   CHECK_STATUS
+//!
+//! response: u8:status ...
+//!   OK           result, by request:
+//!                  PUT, DELETE      nothing
+//!                  GET, GET_RANGE   bytes:data
+//!                  SIZE             u64:size
+//!                  LIST             u32:n n * str:key
+//!                  CAPABILITIES     bool:atomic_rename bool:range_reads bool:delete
+//!                  THAW             bool:pending u64:seconds_left
+//!                  EXISTS           bool:exists
+//!                  BATCH            u32:n, then n frames of
+//!                                   u32:id response
+//!                  GC               u32:n n * [16]:pack_id u64:reclaimed_bytes
+//!                  PRESENT          bytes:bitmap, see `presence`
+//!                  CHALLENGE        [32]:challenge
+//!                  AUTH             nothing
+//!                  CONSISTENCY      u32:n n * [32]:hash, see `translog`
+//!                  TENANT           nothing
+//!                  QUOTA            u32:n n * quota
+//!                  SESSION          [32]:token
+//!                  RESUME           u64:batches u32:n n * (u32:id bytes:response)
+//!                  HELLO            hello
+//!                  CHECK_STATUS     check_status
+//!   ERR          u8:error str:message, and for RATE_LIMITED
+//!                u64:retry_after_ms, for QUOTA_EXCEEDED quota
+//!
+//! quota:    u8:scope u64:quota u64:stored_bytes
+//!   scope        0 client, 1 repository, see `usage`
+//!
+//! hello:    u32:version u64:features
+//!   version      1, this document, 0 is never sent
+//!   features     bit 0 BATCH, bit 1 PRESENT, bit 2 SESSION and RESUME,
+//!                bit 3 CHECK_STATUS
+//!
+//! check_status: u64:every bool:checked [u64:time u32:items u32:warnings u32:errors]
+//!   every        seconds between the server's checks, 0 for none, see `checks`
+//!   checked      whether the last check follows
+//! ```
+//!
+//! The message signed for AUTH, in the signature envelope, see `signed`:
+//!
+//! ```text
+//! "PNBAUTH" [32]:challenge str:client
+//! ```
+//!
+//! A server limiting its clients, see `ratelimit`, answers requests over a
+//! client's limits with RATE_LIMITED, much like HTTP's 429, and how long
+//! to wait before sending them again.
+//!
+//! Frames larger than `MAX_FRAME_SZ` are a protocol error and end the
+//! session, the peer cannot be trusted to resynchronize.
+
+use super::address::{Address, ADDRESS_SZ};
+use super::checks::{CheckResult, CheckStatus};
+use super::gc::GcStats;
+use super::manifest::MANIFEST_HASH_SZ;
+use super::pack::{PackId, PACK_ID_SZ};
+use super::session::{Resumed, SessionToken, TOKEN_SZ};
+use super::storage::{not_found, Capabilities, ThawState};
+use super::translog::LogHash;
+use super::usage::{Quota, QuotaScope};
+use super::wire::{Decoder, Encoder};
+use super::RepoError;
+use std::io::{self, Read, Write};
+
+pub const MAX_FRAME_SZ: usize = 1024 * 1024 * 1024;
+pub const CHALLENGE_SZ: usize = 32;
+const AUTH_MAGIC: &[u8] = b"PNBAUTH";
+
+const OP_PUT: u8 = 0;
+const OP_GET: u8 = 1;
+const OP_GET_RANGE: u8 = 2;
+const OP_SIZE: u8 = 3;
+const OP_LIST: u8 = 4;
+const OP_DELETE: u8 = 5;
+const OP_CAPABILITIES: u8 = 6;
+const OP_THAW: u8 = 7;
+const OP_EXISTS: u8 = 8;
+const OP_BATCH: u8 = 9;
+const OP_GC: u8 = 10;
+const OP_PRESENT: u8 = 11;
+const OP_CHALLENGE: u8 = 12;
+const OP_AUTH: u8 = 13;
+const OP_CONSISTENCY: u8 = 14;
+const OP_TENANT: u8 = 15;
+const OP_QUOTA: u8 = 16;
+const OP_SESSION: u8 = 17;
+const OP_RESUME: u8 = 18;
+const OP_HELLO: u8 = 19;
+const OP_CHECK_STATUS: u8 = 20;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+pub const FEATURE_BATCH: u64 = 1 << 0;
+pub const FEATURE_PRESENCE: u64 = 1 << 1;
+pub const FEATURE_SESSIONS: u64 = 1 << 2;
+pub const FEATURE_CHECKS: u64 = 1 << 3;
+// The features this implementation has.
+pub const FEATURES: u64 = FEATURE_BATCH | FEATURE_PRESENCE | FEATURE_SESSIONS | FEATURE_CHECKS;
+// Those a server from before HELLO is tried for.
+pub const LEGACY_FEATURES: u64 = FEATURE_BATCH | FEATURE_PRESENCE;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Hello {
+    pub version: u32,
+    pub features: u64,
+}
+
+impl Hello {
+    // What this implementation says.
+    pub fn ours() -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            features: FEATURES,
+        }
+    }
+
+    // What a server from before HELLO is taken to have said.
+    pub fn legacy() -> Hello {
+        Hello {
+            version: 0,
+            features: LEGACY_FEATURES,
+        }
+    }
+
+    pub fn has(&self, feature: u64) -> bool {
+        self.features & feature != 0
+    }
+
+    // The server's answer to the client's hello.
+    pub fn negotiate(&self) -> Result<Hello, RepoError> {
+        if self.version == 0 {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        Ok(Hello {
+            version: self.version.min(PROTOCOL_VERSION),
+            features: self.features & FEATURES,
+        })
+    }
+}
+
+const STATUS_OK: u8 = 0;
+const STATUS_ERR: u8 = 1;
+
+const ERR_OTHER: u8 = 0;
+const ERR_NOT_FOUND: u8 = 1;
+const ERR_INVALID_KEY: u8 = 2;
+const ERR_INVALID_RANGE: u8 = 3;
+const ERR_UNSUPPORTED: u8 = 4;
+const ERR_PERMISSION_DENIED: u8 = 5;
+const ERR_INVALID_DATA: u8 = 6;
+const ERR_QUOTA_EXCEEDED: u8 = 7;
+const ERR_COLD_STORAGE: u8 = 8;
+const ERR_RETENTION_LOCKED: u8 = 9;
+const ERR_RATE_LIMITED: u8 = 10;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Request<'a> {
+    Put { key: &'a str, data: &'a [u8] },
+    Get { key: &'a str },
+    GetRange { key: &'a str, offset: u64, len: u32 },
+    Size { key: &'a str },
+    List { prefix: &'a str },
+    Delete { key: &'a str },
+    Capabilities,
+    Thaw { key: &'a str },
+    Exists { key: &'a str },
+    // Encoded requests by id.
+    Batch(Vec<(u32, &'a [u8])>),
+    // A signed keep list, see `keeplist`.
+    Gc { keep_list: &'a [u8] },
+    // Which of these objects are stored.
+    Present(Vec<Address>),
+    Challenge,
+    // `auth_message` signed by the client's key.
+    Auth { client: &'a str, signed: &'a [u8] },
+    // A consistency proof between two sizes of the manifest log.
+    Consistency { old: u64, new: u64 },
+    // The repository the session is for, see `tenant`.
+    Tenant { name: &'a str },
+    // The quotas that apply to the session.
+    Quota,
+    // A token to resume the session with, see `session`.
+    Session,
+    // Resume a session with the batch in flight, 0 for none.
+    Resume { token: SessionToken, batch: u64 },
+    Hello(Hello),
+    CheckStatus,
+}
+
+impl<'a> Request<'a> {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        match *self {
+            Request::Put { key, data } => e.u8(OP_PUT).str(key).bytes(data),
+            Request::Get { key } => e.u8(OP_GET).str(key),
+            Request::GetRange { key, offset, len } => {
+                e.u8(OP_GET_RANGE).str(key).u64(offset).u32(len)
+            }
+            Request::Size { key } => e.u8(OP_SIZE).str(key),
+            Request::List { prefix } => e.u8(OP_LIST).str(prefix),
+            Request::Delete { key } => e.u8(OP_DELETE).str(key),
+            Request::Capabilities => e.u8(OP_CAPABILITIES),
+            Request::Thaw { key } => e.u8(OP_THAW).str(key),
+            Request::Exists { key } => e.u8(OP_EXISTS).str(key),
+            Request::Batch(ref reqs) => {
+                e.u8(OP_BATCH).u32(reqs.len() as u32);
+                for (id, req) in reqs.iter() {
+                    e.u32(*id).bytes(req);
+                }
+                &mut e
+            }
+            Request::Gc { keep_list } => e.u8(OP_GC).bytes(keep_list),
+            Request::Present(ref addresses) => {
+                e.u8(OP_PRESENT).u32(addresses.len() as u32);
+                for a in addresses.iter() {
+                    a.encode(&mut e);
+                }
+                &mut e
+            }
+            Request::Challenge => e.u8(OP_CHALLENGE),
+            Request::Auth { client, signed } => e.u8(OP_AUTH).str(client).bytes(signed),
+            Request::Consistency { old, new } => e.u8(OP_CONSISTENCY).u64(old).u64(new),
+            Request::Tenant { name } => e.u8(OP_TENANT).str(name),
+            Request::Quota => e.u8(OP_QUOTA),
+            Request::Session => e.u8(OP_SESSION),
+            Request::Resume { ref token, batch } => e.u8(OP_RESUME).fixed(token).u64(batch),
+            Request::Hello(ref hello) => {
+                encode_hello(e.u8(OP_HELLO), hello);
+                &mut e
+            }
+            Request::CheckStatus => e.u8(OP_CHECK_STATUS),
+        };
+        e.into_vec()
+    }
+
+    pub fn decode(buf: &'a [u8]) -> Result<Request<'a>, RepoError> {
+        let mut d = Decoder::new(buf);
+        let req = match d.u8()? {
+            OP_PUT => Request::Put {
+                key: d.str()?,
+                data: d.bytes()?,
+            },
+            OP_GET => Request::Get { key: d.str()? },
+            OP_GET_RANGE => Request::GetRange {
+                key: d.str()?,
+                offset: d.u64()?,
+                len: d.u32()?,
+            },
+            OP_SIZE => Request::Size { key: d.str()? },
+            OP_LIST => Request::List { prefix: d.str()? },
+            OP_DELETE => Request::Delete { key: d.str()? },
+            OP_CAPABILITIES => Request::Capabilities,
+            OP_THAW => Request::Thaw { key: d.str()? },
+            OP_EXISTS => Request::Exists { key: d.str()? },
+            OP_BATCH => {
+                let n = d.count(8)?;
+                let mut reqs = Vec::with_capacity(n);
+                for _ in 0..n {
+                    reqs.push((d.u32()?, d.bytes()?));
+                }
+                Request::Batch(reqs)
+            }
+            OP_GC => Request::Gc {
+                keep_list: d.bytes()?,
+            },
+            OP_PRESENT => {
+                let n = d.count(ADDRESS_SZ)?;
+                let mut addresses = Vec::with_capacity(n);
+                for _ in 0..n {
+                    addresses.push(Address::decode(&mut d)?);
+                }
+                Request::Present(addresses)
+            }
+            OP_CHALLENGE => Request::Challenge,
+            OP_AUTH => Request::Auth {
+                client: d.str()?,
+                signed: d.bytes()?,
+            },
+            OP_CONSISTENCY => Request::Consistency {
+                old: d.u64()?,
+                new: d.u64()?,
+            },
+            OP_TENANT => Request::Tenant { name: d.str()? },
+            OP_QUOTA => Request::Quota,
+            OP_SESSION => Request::Session,
+            OP_RESUME => {
+                let mut token = [0; TOKEN_SZ];
+                d.fixed_into(&mut token)?;
+                Request::Resume {
+                    token,
+                    batch: d.u64()?,
+                }
+            }
+            OP_HELLO => Request::Hello(decode_hello(&mut d)?),
+            OP_CHECK_STATUS => Request::CheckStatus,
+            _ => return Err(RepoError::UnsupportedOperationError),
+        };
+        d.finish()?;
+        Ok(req)
+    }
+
+    // The key named in error messages.
+    pub fn key(&self) -> &'a str {
+        match *self {
+            Request::Put { key, .. }
+            | Request::Get { key }
+            | Request::GetRange { key, .. }
+            | Request::Size { key }
+            | Request::Delete { key }
+            | Request::Thaw { key }
+            | Request::Exists { key } => key,
+            Request::List { prefix } => prefix,
+            Request::Tenant { name } => name,
+            Request::Capabilities
+            | Request::Batch(_)
+            | Request::Gc { .. }
+            | Request::Present(_)
+            | Request::Challenge
+            | Request::Auth { .. }
+            | Request::Consistency { .. }
+            | Request::Quota
+            | Request::Session
+            | Request::Resume { .. }
+            | Request::Hello(_)
+            | Request::CheckStatus => "",
+        }
+    }
+
+    // The operation, as named in logs.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Request::Put { .. } => "put",
+            Request::Get { .. } => "get",
+            Request::GetRange { .. } => "get-range",
+            Request::Size { .. } => "size",
+            Request::List { .. } => "list",
+            Request::Delete { .. } => "delete",
+            Request::Capabilities => "capabilities",
+            Request::Thaw { .. } => "thaw",
+            Request::Exists { .. } => "exists",
+            Request::Batch(_) => "batch",
+            Request::Gc { .. } => "gc",
+            Request::Present(_) => "present",
+            Request::Challenge => "challenge",
+            Request::Auth { .. } => "auth",
+            Request::Consistency { .. } => "consistency",
+            Request::Tenant { .. } => "tenant",
+            Request::Quota => "quota",
+            Request::Session => "session",
+            Request::Resume { .. } => "resume",
+            Request::Hello(_) => "hello",
+            Request::CheckStatus => "check-status",
+        }
+    }
+}
+
+// What a client signs to authenticate as `client`, see AUTH.
+pub fn auth_message(challenge: &[u8; CHALLENGE_SZ], client: &str) -> Vec<u8> {
+    let mut e = Encoder::new();
+    e.fixed(AUTH_MAGIC).fixed(challenge).str(client);
+    e.into_vec()
+}
+
+// A successful response, the caller appends the result.
+pub fn ok_response() -> Encoder {
+    let mut e = Encoder::new();
+    e.u8(STATUS_OK);
+    e
+}
+
+pub fn err_response(err: &RepoError) -> Vec<u8> {
+    let code = match *err {
+        _ if err.is_not_found() => ERR_NOT_FOUND,
+        RepoError::InvalidKeyError => ERR_INVALID_KEY,
+        RepoError::InvalidRangeError => ERR_INVALID_RANGE,
+        RepoError::UnsupportedOperationError => ERR_UNSUPPORTED,
+        RepoError::PermissionDeniedError => ERR_PERMISSION_DENIED,
+        RepoError::InvalidDataError => ERR_INVALID_DATA,
+        RepoError::QuotaExceededError(_) => ERR_QUOTA_EXCEEDED,
+        RepoError::ColdStorageError => ERR_COLD_STORAGE,
+        RepoError::RetentionLockedError => ERR_RETENTION_LOCKED,
+        RepoError::RateLimitedError(_) => ERR_RATE_LIMITED,
+        _ => ERR_OTHER,
+    };
+    let mut e = Encoder::new();
+    e.u8(STATUS_ERR).u8(code).str(&err.to_string());
+    match *err {
+        RepoError::RateLimitedError(ms) => {
+            e.u64(ms);
+        }
+        RepoError::QuotaExceededError(ref quota) => encode_quota(&mut e, quota),
+        _ => (),
+    }
+    e.into_vec()
+}
+
+// Check the response status, returning a decoder positioned at the result.
+pub fn open_response<'a>(buf: &'a [u8], key: &str) -> Result<Decoder<'a>, RepoError> {
+    let mut d = Decoder::new(buf);
+    match d.u8()? {
+        STATUS_OK => Ok(d),
+        STATUS_ERR => {
+            let code = d.u8()?;
+            let msg = d.str()?;
+            Err(match code {
+                ERR_NOT_FOUND => not_found(key),
+                ERR_INVALID_KEY => RepoError::InvalidKeyError,
+                ERR_INVALID_RANGE => RepoError::InvalidRangeError,
+                ERR_UNSUPPORTED => RepoError::UnsupportedOperationError,
+                ERR_PERMISSION_DENIED => RepoError::PermissionDeniedError,
+                ERR_INVALID_DATA => RepoError::InvalidDataError,
+                ERR_QUOTA_EXCEEDED => RepoError::QuotaExceededError(decode_quota(&mut d)?),
+                ERR_COLD_STORAGE => RepoError::ColdStorageError,
+                ERR_RETENTION_LOCKED => RepoError::RetentionLockedError,
+                ERR_RATE_LIMITED => RepoError::RateLimitedError(d.u64()?),
+                _ => RepoError::StorageError(format!("remote: {}", msg)),
+            })
+        }
+        _ => Err(RepoError::InvalidDataError),
+    }
+}
+
+// Tag a response with the id of its request in a batch.
+pub fn tag_response(id: u32, resp: &[u8]) -> Vec<u8> {
+    let mut e = Encoder::new();
+    e.u32(id).fixed(resp);
+    e.into_vec()
+}
+
+pub fn untag_response(buf: &[u8]) -> Result<(u32, &[u8]), RepoError> {
+    let mut d = Decoder::new(buf);
+    let id = d.u32()?;
+    let n = d.remaining();
+    Ok((id, d.fixed(n)?))
+}
+
+pub fn encode_hello(e: &mut Encoder, hello: &Hello) {
+    e.u32(hello.version).u64(hello.features);
+}
+
+pub fn decode_hello(d: &mut Decoder) -> Result<Hello, RepoError> {
+    Ok(Hello {
+        version: d.u32()?,
+        features: d.u64()?,
+    })
+}
+
+pub fn encode_check_status(e: &mut Encoder, status: &CheckStatus) {
+    e.u64(status.every.unwrap_or(0)).bool(status.last.is_some());
+    if let Some(last) = status.last {
+        e.u64(last.time)
+            .u32(last.items)
+            .u32(last.warnings)
+            .u32(last.errors);
+    }
+}
+
+pub fn decode_check_status(d: &mut Decoder) -> Result<CheckStatus, RepoError> {
+    let every = Some(d.u64()?).filter(|every| *every != 0);
+    let last = match d.bool()? {
+        true => Some(CheckResult {
+            time: d.u64()?,
+            items: d.u32()?,
+            warnings: d.u32()?,
+            errors: d.u32()?,
+        }),
+        false => None,
+    };
+    Ok(CheckStatus { every, last })
+}
+
+pub fn encode_capabilities(e: &mut Encoder, caps: &Capabilities) {
+    e.bool(caps.atomic_rename)
+        .bool(caps.range_reads)
+        .bool(caps.delete);
+}
+
+pub fn decode_capabilities(d: &mut Decoder) -> Result<Capabilities, RepoError> {
+    Ok(Capabilities {
+        atomic_rename: d.bool()?,
+        range_reads: d.bool()?,
+        delete: d.bool()?,
+    })
+}
+
+pub fn encode_thaw_state(e: &mut Encoder, state: ThawState) {
+    match state {
+        ThawState::Ready => e.bool(false).u64(0),
+        ThawState::Pending(secs) => e.bool(true).u64(secs),
+    };
+}
+
+pub fn decode_thaw_state(d: &mut Decoder) -> Result<ThawState, RepoError> {
+    let pending = d.bool()?;
+    let secs = d.u64()?;
+    Ok(if pending {
+        ThawState::Pending(secs)
+    } else {
+        ThawState::Ready
+    })
+}
+
+pub fn encode_gc_stats(e: &mut Encoder, stats: &GcStats) {
+    e.u32(stats.deleted_packs.len() as u32);
+    for id in stats.deleted_packs.iter() {
+        e.fixed(&id.bytes);
+    }
+    e.u64(stats.reclaimed_bytes);
+}
+
+pub fn decode_gc_stats(d: &mut Decoder) -> Result<GcStats, RepoError> {
+    let n = d.count(PACK_ID_SZ)?;
+    let mut deleted_packs = Vec::with_capacity(n);
+    for _ in 0..n {
+        let mut id: PackId = Default::default();
+        d.fixed_into(&mut id.bytes)?;
+        deleted_packs.push(id);
+    }
+    Ok(GcStats {
+        deleted_packs,
+        reclaimed_bytes: d.u64()?,
+        ..Default::default()
+    })
+}
+
+const QUOTA_SZ: usize = 17;
+
+pub fn encode_quota(e: &mut Encoder, quota: &Quota) {
+    let scope = match quota.scope {
+        QuotaScope::Client => 0,
+        QuotaScope::Repository => 1,
+    };
+    e.u8(scope).u64(quota.quota).u64(quota.stored_bytes);
+}
+
+pub fn decode_quota(d: &mut Decoder) -> Result<Quota, RepoError> {
+    let scope = match d.u8()? {
+        0 => QuotaScope::Client,
+        1 => QuotaScope::Repository,
+        _ => return Err(RepoError::InvalidDataError),
+    };
+    Ok(Quota {
+        scope,
+        quota: d.u64()?,
+        stored_bytes: d.u64()?,
+    })
+}
+
+pub fn encode_quotas(e: &mut Encoder, quotas: &[Quota]) {
+    e.u32(quotas.len() as u32);
+    for q in quotas.iter() {
+        encode_quota(e, q);
+    }
+}
+
+pub fn decode_quotas(d: &mut Decoder) -> Result<Vec<Quota>, RepoError> {
+    let n = d.count(QUOTA_SZ)?;
+    (0..n).map(|_| decode_quota(d)).collect()
+}
+
+pub fn encode_resumed(e: &mut Encoder, resumed: &Resumed) {
+    e.u64(resumed.batches).u32(resumed.answered.len() as u32);
+    for (id, resp) in resumed.answered.iter() {
+        e.u32(*id).bytes(resp);
+    }
+}
+
+pub fn decode_resumed(d: &mut Decoder) -> Result<Resumed, RepoError> {
+    let batches = d.u64()?;
+    let n = d.count(8)?;
+    let mut answered = Vec::with_capacity(n);
+    for _ in 0..n {
+        answered.push((d.u32()?, d.bytes()?.to_vec()));
+    }
+    Ok(Resumed { batches, answered })
+}
+
+pub fn encode_consistency_proof(e: &mut Encoder, proof: &[LogHash]) {
+    e.u32(proof.len() as u32);
+    for h in proof.iter() {
+        e.fixed(h);
+    }
+}
+
+pub fn decode_consistency_proof(d: &mut Decoder) -> Result<Vec<LogHash>, RepoError> {
+    let n = d.count(MANIFEST_HASH_SZ)?;
+    let mut proof = Vec::with_capacity(n);
+    for _ in 0..n {
+        let mut h = [0; MANIFEST_HASH_SZ];
+        d.fixed_into(&mut h)?;
+        proof.push(h);
+    }
+    Ok(proof)
+}
+
+pub fn write_frame(w: &mut dyn Write, payload: &[u8]) -> Result<(), RepoError> {
+    if payload.len() > MAX_FRAME_SZ {
+        return Err(RepoError::ObjectTooLargeError);
+    }
+    w.write_all(&(payload.len() as u32).to_be_bytes())?;
+    w.write_all(payload)?;
+    w.flush()?;
+    Ok(())
+}
+
+// Returns None if the stream ended cleanly between frames.
+pub fn read_frame(r: &mut dyn Read) -> Result<Option<Vec<u8>>, RepoError> {
+    let mut hdr = [0; 4];
+    let mut n = 0;
+    while n < hdr.len() {
+        match r.read(&mut hdr[n..]) {
+            Ok(0) if n == 0 => return Ok(None),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(m) => n += m,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let len = u32::from_be_bytes(hdr) as usize;
+    if len > MAX_FRAME_SZ {
+        return Err(RepoError::InvalidDataError);
+    }
+    let mut buf = vec![0; len];
+    r.read_exact(&mut buf)?;
+    Ok(Some(buf))
+}
+
+// Tests --------------------
+
+#[test]
+fn test_request_round_trip() {
+    let reqs = [
+        Request::Put {
+            key: "packs/ab",
+            data: b"data",
+        },
+        Request::Get { key: "config" },
+        Request::GetRange {
+            key: "packs/ab",
+            offset: 7,
+            len: 9,
+        },
+        Request::Size { key: "manifest" },
+        Request::List { prefix: "indexes/" },
+        Request::Delete { key: "objects/cd" },
+        Request::Capabilities,
+        Request::Exists { key: "objects/cd" },
+        Request::Batch(vec![(1, &b"\x06"[..]), (7, &b""[..])]),
+        Request::Gc {
+            keep_list: b"signed",
+        },
+        Request::Present(vec![Address { bytes: [1; 32] }, Address { bytes: [2; 32] }]),
+        Request::Challenge,
+        Request::Auth {
+            client: "laptop",
+            signed: b"signed",
+        },
+        Request::Consistency { old: 2, new: 5 },
+        Request::Tenant { name: "design" },
+        Request::Quota,
+        Request::Session,
+        Request::Resume {
+            token: [3; TOKEN_SZ],
+            batch: 2,
+        },
+        Request::Hello(Hello::ours()),
+        Request::CheckStatus,
+    ];
+    for req in reqs.iter() {
+        let buf = req.encode();
+        assert_eq!(Request::decode(&buf).unwrap(), *req);
+        assert!(Request::decode(&buf[..buf.len() - 1]).is_err());
+    }
+    let resp = tag_response(3, &err_response(&RepoError::InvalidKeyError));
+    let (id, resp) = untag_response(&resp).unwrap();
+    assert_eq!(id, 3);
+    match open_response(resp, "") {
+        Err(RepoError::InvalidKeyError) => (),
+        _ => panic!("expected invalid key"),
+    }
+    let quota = crate::usage::Quota {
+        scope: QuotaScope::Repository,
+        quota: 100,
+        stored_bytes: 90,
+    };
+    match open_response(&err_response(&RepoError::QuotaExceededError(quota)), "") {
+        Err(RepoError::QuotaExceededError(q)) if q == quota => (),
+        _ => panic!("expected the quota to be kept"),
+    }
+    let mut e = Encoder::new();
+    encode_resumed(
+        &mut e,
+        &Resumed {
+            batches: 2,
+            answered: vec![(1, ok_response().into_vec())],
+        },
+    );
+    let resumed = decode_resumed(&mut Decoder::new(&e.into_vec())).unwrap();
+    assert_eq!(resumed.batches, 2);
+    assert_eq!(resumed.answered, vec![(1, vec![STATUS_OK])]);
+    match open_response(&err_response(&RepoError::RateLimitedError(250)), "") {
+        Err(RepoError::RateLimitedError(250)) => (),
+        _ => panic!("expected the wait to be kept"),
+    }
+    // Both ends speak the older version, with the features both have.
+    let newer = Hello {
+        version: PROTOCOL_VERSION + 1,
+        features: FEATURE_BATCH | 1 << 40,
+    };
+    assert_eq!(
+        newer.negotiate().unwrap(),
+        Hello {
+            version: PROTOCOL_VERSION,
+            features: FEATURE_BATCH,
+        }
+    );
+    assert!(Hello::legacy().negotiate().is_err());
+    match Request::decode(&[99]) {
+        Err(RepoError::UnsupportedOperationError) => (),
+        _ => panic!("expected unsupported operation"),
+    }
+}
+
+#[test]
+fn test_error_round_trip() {
+    let resp = err_response(&not_found("packs/ab"));
+    assert!(open_response(&resp, "packs/ab")
+        .err()
+        .unwrap()
+        .is_not_found());
+    let resp = err_response(&RepoError::PermissionDeniedError);
+    match open_response(&resp, "x") {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected permission denied"),
+    }
+    let resp = err_response(&RepoError::StorageError("disk full".to_string()));
+    match open_response(&resp, "x") {
+        Err(RepoError::StorageError(ref msg)) => assert!(msg.contains("disk full")),
+        _ => panic!("expected storage error"),
+    }
+}
+
+#[test]
+fn test_frames() {
+    let mut buf = Vec::new();
+    write_frame(&mut buf, b"hello").unwrap();
+    write_frame(&mut buf, b"").unwrap();
+    let mut r = &buf[..];
+    assert_eq!(read_frame(&mut r).unwrap().unwrap(), b"hello");
+    assert_eq!(read_frame(&mut r).unwrap().unwrap(), b"");
+    assert!(read_frame(&mut r).unwrap().is_none());
+
+    let mut r = &buf[..6];
+    assert!(read_frame(&mut r).is_err());
+    let mut r = &buf[..2];
+    assert!(read_frame(&mut r).is_err());
+    let mut r = &[0xff, 0xff, 0xff, 0xff][..];
+    assert!(read_frame(&mut r).is_err());
+}
@@ -0,0 +1,367 @@
+//! Forgetting snapshots by retention policy.
+//!
+//! `Repo::prune` removes every snapshot head its `KeepPolicy` does not
+//! keep from the manifest. Nothing else is deleted, the space is reclaimed
+//! by a later `gc`.
+//!
+//! A policy applies to each namespace on its own, newest snapshot first:
+//!
+//! - `last` keeps the newest n snapshots.
+//! - `daily`, `weekly` and `monthly` keep the newest snapshot of each of
+//!   the n most recent UTC days, weeks starting on Monday or months that
+//!   have one.
+//! - `within` keeps every snapshot taken less than that many seconds ago.
+//!
+//! A snapshot any rule keeps is kept, and so is one under a retention
+//! lock, see `manifest`. A policy without any rule would forget every
+//! snapshot and is refused.
+//!
+//! `Repo::prune_tagged` applies the policy only to the snapshots carrying
+//! every one of the given tags, with any amendment applied, see `amend`,
+//! and keeps all others. A snapshot object that cannot be read carries no
+//! tags.
+
+use super::datetime::{unix_now, DateTime};
+use super::keys::KeyRole;
+use super::manifest::SnapshotHead;
+use super::transaction::MAX_COMMIT_ATTEMPTS;
+use super::{Repo, RepoError};
+use asymcrypt::Key;
+use std::collections::BTreeMap;
+use std::io;
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct KeepPolicy {
+    pub last: usize,
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+    // Seconds.
+    pub within: u64,
+}
+
+impl KeepPolicy {
+    pub fn is_empty(&self) -> bool {
+        *self == Default::default()
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct PruneStats {
+    pub kept: Vec<SnapshotHead>,
+    pub forgotten: Vec<SnapshotHead>,
+}
+
+// A duration such as 36h, 30d, 2w or 1y, in seconds. Days are 24 hours
+// and years 365 days.
+pub fn parse_duration(s: &str) -> Option<u64> {
+    let unit = match s.chars().last()? {
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        'y' => 365 * 24 * 60 * 60,
+        _ => return None,
+    };
+    let n = &s[..s.len() - 1];
+    if n.is_empty() || !n.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    n.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+fn day(t: u64) -> u64 {
+    t / 86400
+}
+
+// The unix epoch was a Thursday.
+fn week(t: u64) -> u64 {
+    (day(t) + 3) / 7
+}
+
+fn month(t: u64) -> u64 {
+    let d = DateTime::from_unix(t);
+    d.year as u64 * 12 + d.month as u64
+}
+
+// Keep the newest of `order` in each of the first `n` periods.
+fn keep_periods(
+    heads: &[SnapshotHead],
+    order: &[usize],
+    n: usize,
+    period: fn(u64) -> u64,
+    keep: &mut [bool],
+) {
+    let mut last = None;
+    let mut kept = 0;
+    for i in order.iter() {
+        let p = period(heads[*i].timestamp);
+        if last != Some(p) && kept < n {
+            keep[*i] = true;
+            kept += 1;
+        }
+        last = Some(p);
+    }
+}
+
+// Which of `heads` the policy keeps at time `now`.
+pub fn select(heads: &[SnapshotHead], policy: &KeepPolicy, now: u64) -> Vec<bool> {
+    let mut keep: Vec<bool> = heads.iter().map(|h| h.is_retained(now)).collect();
+    let mut namespaces = BTreeMap::new();
+    for (i, h) in heads.iter().enumerate() {
+        namespaces
+            .entry(&h.namespace)
+            .or_insert_with(Vec::new)
+            .push(i);
+    }
+    for order in namespaces.values_mut() {
+        order.sort_by(|a, b| heads[*b].timestamp.cmp(&heads[*a].timestamp));
+        for i in order.iter().take(policy.last) {
+            keep[*i] = true;
+        }
+        keep_periods(heads, order, policy.daily, day, &mut keep);
+        keep_periods(heads, order, policy.weekly, week, &mut keep);
+        keep_periods(heads, order, policy.monthly, month, &mut keep);
+        for i in order.iter() {
+            if heads[*i].timestamp.saturating_add(policy.within) > now {
+                keep[*i] = true;
+            }
+        }
+    }
+    keep
+}
+
+impl Repo {
+    // Which of `heads` carry every one of `tags`.
+    fn tagged(
+        &self,
+        heads: &[SnapshotHead],
+        tags: &[String],
+        key: &Key,
+    ) -> Result<Vec<bool>, RepoError> {
+        if tags.is_empty() {
+            return Ok(vec![true; heads.len()]);
+        }
+        // Without it no snapshot would seem tagged, see `keys`.
+        self.require_role(key, KeyRole::Metadata)?;
+        let index = self.load_index()?;
+        let mut tagged = Vec::with_capacity(heads.len());
+        for h in heads.iter() {
+            tagged.push(match self.read_head_snapshot(&index, &key.box_sk, h) {
+                Ok(s) => tags.iter().all(|t| s.tags.contains(t)),
+                Err(e) if e.is_transient() => return Err(e),
+                Err(_) => false,
+            });
+        }
+        Ok(tagged)
+    }
+
+    // Forget the snapshots `policy` does not keep, or with `dry_run` only
+    // report them.
+    pub fn prune(
+        &self,
+        policy: &KeepPolicy,
+        dry_run: bool,
+        key: &Key,
+    ) -> Result<PruneStats, RepoError> {
+        self.prune_tagged(policy, &[], dry_run, key)
+    }
+
+    // Like `prune`, for only the snapshots carrying every one of `tags`.
+    pub fn prune_tagged(
+        &self,
+        policy: &KeepPolicy,
+        tags: &[String],
+        dry_run: bool,
+        key: &Key,
+    ) -> Result<PruneStats, RepoError> {
+        if policy.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a keep policy without rules would forget every snapshot",
+            )
+            .into());
+        }
+        // Like `publish_head`, retry if a commit replaced the manifest
+        // in between.
+        for _ in 0..MAX_COMMIT_ATTEMPTS {
+            let mut m = self.manifest()?;
+            let tagged = self.tagged(&m.heads, tags, key)?;
+            let candidates: Vec<SnapshotHead> = m
+                .heads
+                .iter()
+                .zip(tagged.iter())
+                .filter(|(_, t)| **t)
+                .map(|(h, _)| h.clone())
+                .collect();
+            let mut selected = select(&candidates, policy, unix_now()).into_iter();
+            let keep = tagged.iter().map(|t| !t || selected.next() == Some(true));
+            let mut stats: PruneStats = Default::default();
+            for (h, keep) in m.heads.iter().zip(keep) {
+                if keep {
+                    stats.kept.push(h.clone());
+                } else {
+                    stats.forgotten.push(h.clone());
+                }
+            }
+            if dry_run || stats.forgotten.is_empty() {
+                return Ok(stats);
+            }
+            for h in stats.forgotten.iter() {
+                m.remove_head(&h.address);
+            }
+            self.commit_manifest(&m, key)?;
+            let heads = self.manifest()?.heads;
+            if !stats.forgotten.iter().any(|h| heads.contains(h))
+                && stats.kept.iter().all(|h| heads.contains(h))
+            {
+                return Ok(stats);
+            }
+        }
+        Err(RepoError::StorageError(
+            "the manifest kept changing while pruning".to_string(),
+        ))
+    }
+}
+
+// Tests --------------------
+
+#[cfg(test)]
+fn test_heads(times: &[u64]) -> Vec<SnapshotHead> {
+    times
+        .iter()
+        .enumerate()
+        .map(|(i, t)| SnapshotHead {
+            address: super::address::Address {
+                bytes: [i as u8 + 1; 32],
+            },
+            timestamp: *t,
+            namespace: super::namespace::Namespace::new("laptop").unwrap(),
+            retain_until: 0,
+            amendment: None,
+        })
+        .collect()
+}
+
+#[test]
+fn test_select() {
+    const DAY: u64 = 86400;
+    // Thursday 1970-01-01 to Sunday 1970-01-04, Monday 1970-01-05 and
+    // 1970-02-01.
+    let times = [3600, DAY + 3600, DAY + 7200, 3 * DAY, 4 * DAY, 31 * DAY];
+    let heads = test_heads(&times);
+    let now = 40 * DAY;
+    let policy = |f: &dyn Fn(&mut KeepPolicy)| {
+        let mut p: KeepPolicy = Default::default();
+        f(&mut p);
+        select(&heads, &p, now)
+    };
+    let f = false;
+    let t = true;
+    assert_eq!(policy(&|p| p.last = 2), vec![f, f, f, f, t, t]);
+    assert_eq!(policy(&|p| p.daily = 4), vec![f, f, t, t, t, t]);
+    assert_eq!(policy(&|p| p.weekly = 3), vec![f, f, f, t, t, t]);
+    assert_eq!(policy(&|p| p.monthly = 5), vec![f, f, f, f, t, t]);
+    assert_eq!(policy(&|p| p.within = 10 * DAY), vec![f, f, f, f, f, t]);
+    assert_eq!(
+        policy(&|p| {
+            p.last = 1;
+            p.within = 37 * DAY;
+        }),
+        vec![f, f, f, f, t, t]
+    );
+
+    // Namespaces are pruned separately and locks always win.
+    let mut heads = heads.clone();
+    heads[0].namespace = super::namespace::Namespace::new("server").unwrap();
+    heads[1].retain_until = now + 1;
+    let keep = select(
+        &heads,
+        &KeepPolicy {
+            last: 1,
+            ..Default::default()
+        },
+        now,
+    );
+    assert_eq!(keep, vec![t, t, f, f, f, t]);
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("30d"), Some(30 * 86400));
+    assert_eq!(parse_duration("36h"), Some(36 * 3600));
+    assert_eq!(parse_duration("2w"), Some(14 * 86400));
+    assert_eq!(parse_duration("1y"), Some(365 * 86400));
+    for bad in ["", "d", "30", "-1d", "1.5d", "30x", "99999999999999999999y"].iter() {
+        assert_eq!(parse_duration(bad), None, "{}", bad);
+    }
+}
+
+#[test]
+fn test_prune() {
+    let (r, key) = super::test_repo();
+    let now = unix_now();
+    let heads = test_heads(&[now - 3 * 86400, now - 2 * 86400, now - 86400, now]);
+    let mut m = r.manifest().unwrap();
+    for h in heads.iter() {
+        m.add_head(h.clone());
+    }
+    r.commit_manifest(&m, &key).unwrap();
+
+    match r.prune(&Default::default(), false, &key) {
+        Err(RepoError::IOError(ref e)) if e.kind() == io::ErrorKind::InvalidInput => (),
+        _ => panic!("expected an empty policy to be refused"),
+    }
+    let policy = KeepPolicy {
+        last: 1,
+        within: 36 * 3600,
+        ..Default::default()
+    };
+    let stats = r.prune(&policy, true, &key).unwrap();
+    assert_eq!(stats.forgotten, heads[..2].to_vec());
+    assert_eq!(r.manifest().unwrap().heads.len(), 4);
+    let stats = r.prune(&policy, false, &key).unwrap();
+    assert_eq!(stats.kept, heads[2..].to_vec());
+    assert_eq!(r.manifest().unwrap().heads, heads[2..].to_vec());
+}
+
+#[test]
+fn test_prune_tagged() {
+    use super::address::AddressKey;
+    use super::backup::BackupOptions;
+    use super::namespace::Namespace;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let ns = Namespace::new("laptop").unwrap();
+    let backup = |name: &str, tags: &[&str]| {
+        let opts = BackupOptions {
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            ..Default::default()
+        };
+        let mut data: &[u8] = b"dump";
+        r.backup_stream(&mut data, name, &ns, &ak, &key, &opts)
+            .unwrap()
+            .0
+    };
+    let a = backup("a", &["daily"]);
+    let b = backup("b", &["manual"]);
+    let c = backup("c", &["daily"]);
+    let policy = KeepPolicy {
+        last: 1,
+        ..Default::default()
+    };
+    let daily = vec!["daily".to_string()];
+    let stats = r.prune_tagged(&policy, &daily, false, &key).unwrap();
+    assert_eq!(stats.forgotten.len(), 1);
+    assert!(stats.kept.contains(&b));
+    assert_eq!(r.manifest().unwrap().heads.len(), 2);
+    // Which daily snapshot is newest depends on the clock, the two may
+    // share a second.
+    assert!(stats.forgotten == vec![a.clone()] || stats.forgotten == vec![c.clone()]);
+
+    // Amended tags count.
+    let kept = stats.kept.iter().find(|h| **h != b).unwrap().clone();
+    r.amend(&kept.address, &[], "", &ak, &key).unwrap();
+    let stats = r.prune_tagged(&policy, &daily, false, &key).unwrap();
+    assert!(stats.forgotten.is_empty());
+    assert_eq!(stats.kept.len(), 2);
+}
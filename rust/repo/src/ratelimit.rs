@@ -0,0 +1,166 @@
+//! Per client rate limits in `serve`.
+//!
+//! One machine retrying in a tight loop, or uploading as fast as its link
+//! allows, should not starve the other clients of a shared repository
+//! host. `ServeOptions::rate` limits how many requests a second, and how
+//! many bytes a second put and returned together, each client may use,
+//! and `ServeOptions::client_rates` sets other limits for named clients,
+//! the ones the access list gives keys to, see `acl`. Sessions without a
+//! client share the limits of the empty name.
+//!
+//! Each limit is a token bucket holding a second's worth. A request is
+//! refused with `RateLimitedError` while its client has no request left in
+//! the bucket, or owes bytes, and the error tells the client how long to
+//! wait, see `protocol`. A request larger than a second's worth of bytes is
+//! let through and borrows from the following seconds, so every request
+//! is eventually served. Refused requests cost nothing.
+//!
+//! The buckets live in a `RateLimiter`, one per `serve` unless the caller
+//! shares one between the sessions it runs, see `serve_shared`. A
+//! server started per connection, as `packnback serve` from ssh is, limits
+//! each session on its own, and how many sessions a key may open at once
+//! is up to sshd.
+//!
+//! `RetryStorage` waits as long as it is told and tries again, without
+//! counting the refusal as a failed attempt, see `storage::retry`.
+
+use super::RepoError;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct RateLimits {
+    // Requests a second.
+    pub requests: Option<u64>,
+    // Bytes a second, put and returned together.
+    pub bandwidth: Option<u64>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64, now: Instant) -> Bucket {
+        Bucket {
+            tokens: rate as f64,
+            last: now,
+        }
+    }
+
+    // Add what `rate` gave since the last refill, up to a second's worth.
+    fn refill(&mut self, rate: u64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        self.last = now;
+    }
+
+    // How long until the bucket holds `need`.
+    fn wait(&self, rate: u64, need: f64) -> Duration {
+        Duration::from_secs_f64(((need - self.tokens) / rate as f64).max(0.0))
+    }
+}
+
+#[derive(Default)]
+struct ClientBuckets {
+    requests: Option<Bucket>,
+    bandwidth: Option<Bucket>,
+}
+
+#[derive(Default)]
+pub struct RateLimiter {
+    clients: Mutex<BTreeMap<String, ClientBuckets>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        Default::default()
+    }
+
+    // Take a request putting `bytes` from the buckets of `client`, or
+    // refuse it with the time to wait.
+    pub fn admit(&self, client: &str, limits: &RateLimits, bytes: u64) -> Result<(), RepoError> {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        let buckets = clients.entry(client.to_string()).or_default();
+        let mut wait = Duration::ZERO;
+        if let Some(rate) = limits.requests {
+            let b = buckets
+                .requests
+                .get_or_insert_with(|| Bucket::new(rate, now));
+            b.refill(rate, now);
+            wait = wait.max(b.wait(rate, 1.0));
+        }
+        if let Some(rate) = limits.bandwidth {
+            let b = buckets
+                .bandwidth
+                .get_or_insert_with(|| Bucket::new(rate, now));
+            b.refill(rate, now);
+            wait = wait.max(b.wait(rate, 0.0));
+        }
+        if wait > Duration::ZERO {
+            // Rounded up, a client waiting exactly this long is let in.
+            return Err(RepoError::RateLimitedError(wait.as_millis() as u64 + 1));
+        }
+        if let Some(ref mut b) = buckets.requests {
+            b.tokens -= 1.0;
+        }
+        if let Some(ref mut b) = buckets.bandwidth {
+            b.tokens -= bytes as f64;
+        }
+        Ok(())
+    }
+
+    // Charge `client` for `bytes` returned by a request it was admitted
+    // for, the next requests wait for them.
+    pub fn charge(&self, client: &str, limits: &RateLimits, bytes: u64) {
+        if limits.bandwidth.is_none() {
+            return;
+        }
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(ref mut b) = clients.entry(client.to_string()).or_default().bandwidth {
+            b.tokens -= bytes as f64;
+        }
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_rate_limiter() {
+    let limiter = RateLimiter::new();
+    let limits = RateLimits {
+        requests: Some(2),
+        bandwidth: Some(1000),
+    };
+    limiter.admit("laptop", &limits, 10).unwrap();
+    limiter.admit("laptop", &limits, 10).unwrap();
+    let wait = match limiter.admit("laptop", &limits, 10) {
+        Err(RepoError::RateLimitedError(ms)) => ms,
+        _ => panic!("expected the third request to be refused"),
+    };
+    assert!(wait > 0 && wait <= 501, "{}", wait);
+    // Other clients have buckets of their own, and no limits no buckets.
+    limiter.admit("desktop", &limits, 10).unwrap();
+    for _ in 0..10 {
+        limiter.admit("laptop", &Default::default(), 0).unwrap();
+    }
+
+    // Large requests borrow, and the next one waits for the bytes.
+    let bytes = RateLimits {
+        requests: None,
+        bandwidth: Some(1000),
+    };
+    limiter.admit("server", &bytes, 1500).unwrap();
+    let wait = match limiter.admit("server", &bytes, 0) {
+        Err(RepoError::RateLimitedError(ms)) => ms,
+        _ => panic!("expected the request to wait for the borrowed bytes"),
+    };
+    assert!(wait > 400 && wait <= 501, "{}", wait);
+    std::thread::sleep(Duration::from_millis(wait));
+    limiter.admit("server", &bytes, 0).unwrap();
+    limiter.charge("server", &bytes, 2000);
+    assert!(limiter.admit("server", &bytes, 0).is_err());
+}
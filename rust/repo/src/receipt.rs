@@ -0,0 +1,170 @@
+//! Signed receipts of restores.
+//!
+//! A restore with `RestoreOptions::verify` reads every file back once it
+//! is written and checks it against the chunk addresses of the snapshot,
+//! see `restore`. A receipt records the outcome, signed with the owner
+//! key, for whoever needs to know later that a restore landed intact: it
+//! names the repository, the snapshot and the target, counts the files
+//! restored and verified, those of the earlier runs of a resumed restore
+//! included, see `journal`, and lists every path that was not restored or
+//! did not match.
+//!
+//! Format (inside the signature envelope, see `signed`):
+//!
+//! ```text
+//! "PNBRECEIPT" u16:format_version [16]:repo_id [32]:snapshot u64:unix_time
+//! bytes:target bytes:path u64:files u64:verified u64:bytes
+//! u32:n_failed n_failed * bytes:path
+//! ```
+//!
+//! `target` is the directory restored to, `path` the part of the snapshot
+//! restored, see `RestoreOptions::path`, empty for all of it. `verified`
+//! is 0 for a restore that did not read files back, a receipt only
+//! vouches for what it counts.
+
+use super::address::Address;
+use super::datetime::unix_now;
+use super::manifest::RepoId;
+use super::restore::{RestoreOptions, RestoreStats};
+use super::signed;
+use super::wire::{Decoder, Encoder};
+use super::RepoError;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use tweetnacl::*;
+
+pub const RECEIPT_FORMAT_VERSION: u16 = 1;
+const RECEIPT_MAGIC: &[u8] = b"PNBRECEIPT";
+
+// Smallest encoded path.
+const MIN_PATH_SZ: usize = 4;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RestoreReceipt {
+    pub repo_id: RepoId,
+    pub snapshot: Address,
+    pub time: u64,
+    pub target: Vec<u8>,
+    pub path: Vec<u8>,
+    pub files: u64,
+    pub verified: u64,
+    pub bytes: u64,
+    // Paths not restored intact, sorted.
+    pub failed: Vec<Vec<u8>>,
+}
+
+impl RestoreReceipt {
+    // The receipt of restoring `snapshot` to `to`.
+    pub fn new(
+        repo_id: RepoId,
+        snapshot: &Address,
+        to: &Path,
+        opts: &RestoreOptions,
+        stats: &RestoreStats,
+    ) -> RestoreReceipt {
+        RestoreReceipt {
+            repo_id,
+            snapshot: *snapshot,
+            time: unix_now(),
+            target: to.as_os_str().as_bytes().to_vec(),
+            path: opts
+                .path
+                .as_ref()
+                .map_or(Vec::new(), |p| p.as_os_str().as_bytes().to_vec()),
+            files: stats.files + stats.resumed,
+            verified: stats.verified,
+            bytes: stats.bytes,
+            failed: stats.errors.iter().map(|e| e.path.clone()).collect(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.fixed(RECEIPT_MAGIC)
+            .u16(RECEIPT_FORMAT_VERSION)
+            .fixed(&self.repo_id.bytes)
+            .fixed(&self.snapshot.bytes)
+            .u64(self.time)
+            .bytes(&self.target)
+            .bytes(&self.path)
+            .u64(self.files)
+            .u64(self.verified)
+            .u64(self.bytes)
+            .u32(self.failed.len() as u32);
+        for path in self.failed.iter() {
+            e.bytes(path);
+        }
+        e.into_vec()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<RestoreReceipt, RepoError> {
+        let mut d = Decoder::new(buf);
+        if d.fixed(RECEIPT_MAGIC.len())? != RECEIPT_MAGIC {
+            return Err(RepoError::InvalidDataError);
+        }
+        if d.u16()? != RECEIPT_FORMAT_VERSION {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        let mut repo_id: RepoId = Default::default();
+        d.fixed_into(&mut repo_id.bytes)?;
+        let mut snapshot: Address = Default::default();
+        d.fixed_into(&mut snapshot.bytes)?;
+        let time = d.u64()?;
+        let target = d.bytes()?.to_vec();
+        let path = d.bytes()?.to_vec();
+        let files = d.u64()?;
+        let verified = d.u64()?;
+        let bytes = d.u64()?;
+        let n = d.count(MIN_PATH_SZ)?;
+        let mut failed = Vec::with_capacity(n);
+        for _ in 0..n {
+            failed.push(d.bytes()?.to_vec());
+        }
+        d.finish()?;
+        Ok(RestoreReceipt {
+            repo_id,
+            snapshot,
+            time,
+            target,
+            path,
+            files,
+            verified,
+            bytes,
+            failed,
+        })
+    }
+
+    pub fn sign(&self, sk: &CryptoSignSk) -> Vec<u8> {
+        signed::sign(&self.encode(), sk)
+    }
+
+    pub fn open(sm: &[u8], pk: &CryptoSignPk) -> Result<RestoreReceipt, RepoError> {
+        RestoreReceipt::decode(&signed::open(sm, pk)?)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_receipt_sign_open() {
+    let (pk, sk) = boxed_crypto_sign_keypair();
+    let r = RestoreReceipt {
+        repo_id: RepoId::new(),
+        snapshot: Address { bytes: [7; 32] },
+        time: 1_600_000_000,
+        target: b"/mnt/restore".to_vec(),
+        path: b"home".to_vec(),
+        files: 10,
+        verified: 9,
+        bytes: 12345,
+        failed: vec![b"/mnt/restore/home/bad".to_vec()],
+    };
+    let sm = r.sign(&sk);
+    assert_eq!(RestoreReceipt::open(&sm, &pk).unwrap(), r);
+    assert!(RestoreReceipt::decode(&r.encode()[..r.encode().len() - 1]).is_err());
+    let (pk2, _) = boxed_crypto_sign_keypair();
+    match RestoreReceipt::open(&sm, &pk2) {
+        Err(RepoError::SignatureFailedError) => (),
+        _ => panic!("expected the signature to fail"),
+    }
+}
@@ -0,0 +1,178 @@
+//! Pack compaction.
+//!
+//! `gc` only deletes packs with no live objects at all, so after pruning
+//! most space is held by packs mixing live and dead objects. `repack`
+//! copies the live objects out of packs whose live ratio is below a
+//! threshold into new packs, then deletes the old ones.
+//!
+//! Unlike `gc` this decrypts live objects of every kind, it needs the
+//! repository box key. The new packs and their indexes are stored before
+//! any old index is deleted, so every live object stays reachable
+//! throughout. An interrupted repack leaves some objects in two packs,
+//! and an object only counts as live in the pack the repository index
+//! resolves it to, so the next repack collects the extra copies.
+//!
+//! Like `gc`, repack holds an exclusive lock and leaves packs younger than
+//! the grace period alone.
+
+use super::datetime::unix_now;
+use super::index::{PackIndex, RepoIndex};
+use super::lock::LockMode;
+use super::pack::{PackId, PackerOptions};
+use super::{Repo, RepoError, INDEXES_DIR};
+use std::time::Duration;
+use tweetnacl::CryptoBoxSk;
+
+#[derive(Clone, Debug)]
+pub struct RepackOptions {
+    // Rewrite packs with less than this percentage of live bytes.
+    pub min_live_percent: u64,
+    pub grace: Duration,
+    pub packer: PackerOptions,
+    // Report which packs would be rewritten without rewriting them.
+    pub dry_run: bool,
+}
+
+impl Default for RepackOptions {
+    fn default() -> RepackOptions {
+        RepackOptions {
+            min_live_percent: 50,
+            grace: Duration::from_secs(24 * 60 * 60),
+            packer: Default::default(),
+            dry_run: false,
+        }
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct RepackStats {
+    pub repacked_packs: Vec<PackId>,
+    pub new_packs: Vec<PackId>,
+    pub copied_objects: usize,
+    pub reclaimed_bytes: u64,
+}
+
+impl Repo {
+    pub fn repack(&self, sk: &CryptoBoxSk, opts: &RepackOptions) -> Result<RepackStats, RepoError> {
+        if self.append_only() {
+            return Err(RepoError::PermissionDeniedError);
+        }
+        let mut lock = self.lock(LockMode::Exclusive)?;
+
+        let mut indexes: Vec<PackIndex> = Vec::new();
+        for id in self.list_pack_indexes()? {
+            indexes.push(self.read_pack_index(&id)?);
+        }
+        let index = RepoIndex::build(indexes.iter());
+        let live = self.mark(sk, &index)?;
+
+        let now = unix_now();
+        let mut stats: RepackStats = Default::default();
+        let mut packer = self.packer(opts.packer.clone());
+        let mut old: Vec<&PackIndex> = Vec::new();
+        for idx in indexes.iter() {
+            if idx.created.saturating_add(opts.grace.as_secs()) > now {
+                continue;
+            }
+            let keep: Vec<_> = idx
+                .entries()
+                .iter()
+                .filter(|e| {
+                    live.contains(&e.address)
+                        && index.lookup(&e.address).map(|l| l.pack_id) == Some(idx.pack_id)
+                })
+                .collect();
+            let live_bytes: u64 = keep.iter().map(|e| e.length as u64).sum();
+            let total_bytes: u64 = idx.entries().iter().map(|e| e.length as u64).sum();
+            if !idx.is_empty() && live_bytes * 100 >= total_bytes * opts.min_live_percent {
+                continue;
+            }
+            if !opts.dry_run && !keep.is_empty() {
+                let mut pack = self.open_pack(&idx.pack_id, sk)?;
+                for e in keep.iter() {
+                    packer.add(&e.address, e.kind, &pack.read_entry(e)?)?;
+                    lock.refresh()?;
+                }
+            }
+            stats.copied_objects += keep.len();
+            old.push(idx);
+        }
+        if opts.dry_run {
+            stats.repacked_packs = old.iter().map(|idx| idx.pack_id).collect();
+            return Ok(stats);
+        }
+
+        let mut new_size = 0;
+        for p in packer.finish()? {
+            self.write_pack_index(&PackIndex::from_finished(&p))?;
+            new_size += p.size;
+            stats.new_packs.push(p.id);
+        }
+        let mut old_size = 0;
+        for idx in old {
+            let id = idx.pack_id.to_hex();
+            self.storage().delete(&format!("{}/{}", INDEXES_DIR, id))?;
+            self.delete_pack(&idx.pack_id)?;
+            old_size += idx.pack_size;
+            stats.repacked_packs.push(idx.pack_id);
+        }
+        stats.reclaimed_bytes = old_size.saturating_sub(new_size);
+        Ok(stats)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_repack() {
+    use super::address::Address;
+    use super::gc::{test_age_indexes, test_commit_tree};
+    let (r, key) = super::test_repo();
+    let chunk = |b: u8| Address { bytes: [b; 32] };
+    let shared = chunk(100);
+    let head1 = test_commit_tree(&r, &key, 1, &[shared, chunk(101)]);
+    test_commit_tree(&r, &key, 2, &[shared, chunk(102)]);
+    let mut m = r.manifest().unwrap();
+    m.remove_head(&head1.address);
+    r.commit_manifest(&m, &key).unwrap();
+
+    let opts = RepackOptions {
+        min_live_percent: 100,
+        ..Default::default()
+    };
+    // Recent packs are left alone.
+    assert_eq!(r.repack(&key.box_sk, &opts).unwrap(), Default::default());
+    test_age_indexes(&r);
+
+    let dry_run = RepackOptions {
+        dry_run: true,
+        ..opts.clone()
+    };
+    let planned = r.repack(&key.box_sk, &dry_run).unwrap();
+    assert!(!planned.repacked_packs.is_empty());
+    assert!(planned.new_packs.is_empty());
+    assert_eq!(r.list_packs().unwrap().len(), 2);
+
+    let stats = r.repack(&key.box_sk, &opts).unwrap();
+    assert_eq!(stats.repacked_packs, planned.repacked_packs);
+    // The shared chunk is only copied if its surviving copy was repacked.
+    assert!(stats.new_packs.len() <= 1);
+    assert!(stats.reclaimed_bytes > 0);
+
+    // Exactly the live objects remain, each stored once.
+    let index = r.load_index().unwrap();
+    let live = r.mark(&key.box_sk, &index).unwrap();
+    assert_eq!(live.len(), 4);
+    let stored: usize = r
+        .list_pack_indexes()
+        .unwrap()
+        .iter()
+        .map(|id| r.read_pack_index(id).unwrap().len())
+        .sum();
+    assert_eq!(stored, 4);
+    assert!(r.list_locks().unwrap().is_empty());
+
+    test_age_indexes(&r);
+    let stats = r.repack(&key.box_sk, &opts).unwrap();
+    assert!(stats.repacked_packs.is_empty());
+}
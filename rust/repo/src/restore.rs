@@ -0,0 +1,2039 @@
+//! Restoring a snapshot to a directory.
+//!
+//! `Repo::restore` works in three passes over the snapshot's trees, see
+//! `tree`:
+//!
+//! 1. Trees are read and the directories, symlinks, devices, fifos and
+//!    sockets they list created, which yields the list of files to
+//!    restore.
+//! 2. `RestoreOptions::workers` threads each take files from that list,
+//!    fetch and decrypt their chunks in order and write them out. A
+//!    worker keeps its last pack open, chunks of a file usually share one.
+//! 3. File metadata is set as each file is finished. The hard links to
+//!    a file already written are made next, then the metadata of
+//!    devices, fifos and sockets is set, and directory metadata last and
+//!    deepest first, so a read only directory can still be filled.
+//!
+//! Files sharing a `link` in the snapshot, see `tree`, are restored as
+//! hard links to the first of them restored, which is written in full. If
+//! that fails so do its links. A tar stream holds each of them in full.
+//!
+//! Without `RestoreOptions::overwrite` the target must be empty or not
+//! exist, and every file is created anew, so nothing already on disk is
+//! overwritten or followed. With it a restore goes into a directory in
+//! use, and the policy decides about each path already there:
+//!
+//! ```text
+//! never       keep what is there
+//! always      replace it
+//! if-changed  replace it unless it is of the same kind as in the
+//!             snapshot and, for a file, of the same size and
+//!             modification time, for a symlink, of the same target,
+//!             for a device, of the same numbers
+//! ```
+//!
+//! Directories already there are restored into rather than replaced,
+//! and their metadata set unless the policy is never. Anything else in
+//! the way is removed before the new file is fetched, or with
+//! `RestoreOptions::backup_existing` renamed with `.orig` added to its
+//! name, or `.orig.1` and on if that is taken. A directory in the way of
+//! anything else is only ever renamed. A symlink in the way is replaced
+//! or kept like a file, never followed. Each path found in the way is
+//! listed in the stats with what was done about it. Tree names are
+//! checked when decoded, a restore cannot write outside its target.
+//! Names are created byte for byte as stored, see `tree`, and a snapshot
+//! of names from another system is refused.
+//!
+//! Symlinks are restored as they were, pointing anywhere. With
+//! `RestoreOptions::contain_symlinks`, for a snapshot from a machine not
+//! trusted say, those that could lead outside the target are left out
+//! and recorded: absolute ones, and relative ones climbing with `..`
+//! past the target or after any other name, which might be a symlink
+//! itself.
+//!
+//! Anything that cannot be restored, a missing chunk, a damaged tree, a
+//! file that cannot be written, is skipped and listed in the returned
+//! stats, the rest of the snapshot is restored regardless. With the
+//! address key every object is also checked against its address, which
+//! catches objects swapped by someone holding the owner public key.
+//!
+//! `RestoreOptions::verify`, which needs the address key too, checks what
+//! reached the disk as well: each file is synced once written, dropped
+//! from the page cache where the system allows, read back and its chunks
+//! addressed against those in the tree. A file that does not match is
+//! removed and listed like any other that could not be restored. The
+//! stats count the files verified, and `receipt` turns them into a signed
+//! record of the restore.
+//!
+//! With `RestoreOptions::journal` a restore records each file it
+//! completes, syncing it first, and when run again after an interruption
+//! skips those instead of fetching them again, see `journal`. Whatever
+//! else the first run left in the target is handled as by the if-changed
+//! policy, unless another is set: directories are restored into, links
+//! and files it finished are kept, files cut short replaced. Hard links
+//! are made again to a file an earlier run restored.
+//! Ownership is only restored with `RestoreOptions::owners`, which
+//! normally needs root, as does making devices, see `special`. Owners
+//! go to the local accounts of the names the snapshot stores, or as
+//! `RestoreOptions::owner_map` says otherwise, see `owners`.
+//! `RestoreOptions::skip_specials` leaves out devices, fifos and sockets.
+//! Extended attributes and POSIX ACLs are restored less those
+//! `RestoreOptions::xattrs` skips, see `xattr`, after the owner, which
+//! would clear file capabilities, and before the mode, which could leave
+//! a file unwritable. A tar stream holds none.
+//!
+//! Modification times are restored to the nanosecond, and access times
+//! if the snapshot has them, see `backup`. A directory's times are set
+//! after everything in it is restored, which would change them otherwise.
+//! Birth times cannot be set on Linux, they are kept in the snapshot only.
+//!
+//! `RestoreOptions::path` restores one file or directory of the snapshot
+//! instead, into the target under its own name. It is found by reading
+//! only the trees along the path, and objects are read by range, so only
+//! the packs holding what is restored are fetched from.
+//!
+//! `Repo::cat` writes out the contents of one file, `Repo::restore_tar`
+//! writes what `restore` would restore as a tar stream instead, see
+//! `tar`, for piping to other tools or to a machine without packnback.
+//! Headers are written before the data is read, so a file that cannot be
+//! read in full is zero filled in the stream and listed in the stats.
+//!
+//! `RestoreStats::write_ndjson` emits a record of the restore, one per
+//! path found in the way and one per path not restored, see `json`:
+//!
+//! ```text
+//! {"type": "restore", "files": n, "dirs": n, "symlinks": n, "specials": n,
+//!  "bytes": n, "errors": n, "fetched_bytes": n, "plan_seconds": x,
+//!  "write_seconds": x, "metadata_seconds": x, "verified": n,
+//!  "resumed": n}
+//! {"type": "existing", "path": s, "decision": "kept"|"replaced"|"renamed",
+//!  "renamed_to": s|null}
+//! ```
+//!
+//! `fetched_bytes` are the sealed objects read from storage, trees
+//! included, against the `bytes` of files written, and the time is split
+//! by pass. `write_report` prints the same for people. A tar stream is
+//! written in one pass, all of it counted as writing.
+
+use super::address::{Address, AddressKey};
+use super::index::RepoIndex;
+use super::journal::Journal;
+use super::json::{json_path, text_path, write_errors, Summary};
+use super::object::ObjectKind;
+use super::owners::{OwnerMap, Owners};
+use super::pack::{PackId, PackReader};
+use super::progress::human_bytes;
+use super::special;
+use super::storage::StorageObject;
+use super::tar;
+use super::tree::{EntryKind, Snapshot, SnapshotError, Tree, TreeEntry, NAMES_UNIX};
+use super::ustar;
+use super::xattr::{self, XattrOptions};
+use super::{Repo, RepoError};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File, FileTimes, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{
+    fchown, lchown, symlink, FileExt, FileTypeExt, MetadataExt, PermissionsExt,
+};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tweetnacl::CryptoBoxSk;
+
+#[cfg(target_os = "linux")]
+const POSIX_FADV_DONTNEED: i32 = 4;
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::os::raw::c_int;
+
+    extern "C" {
+        pub fn posix_fadvise(fd: c_int, offset: i64, len: i64, advice: c_int) -> c_int;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RestoreOptions {
+    // Threads fetching and writing files.
+    pub workers: usize,
+    // Restore file owners and groups.
+    pub owners: bool,
+    // Which ids to give them, see `owners`.
+    pub owner_map: OwnerMap,
+    // Which extended attributes to restore.
+    pub xattrs: XattrOptions,
+    // Leave out devices, fifos and sockets.
+    pub skip_specials: bool,
+    // Leave out symlinks that could lead outside the target.
+    pub contain_symlinks: bool,
+    // Only restore this file or directory, relative to the snapshot root.
+    pub path: Option<PathBuf>,
+    // What to do about paths already in the target, which must be empty
+    // if None.
+    pub overwrite: Option<Overwrite>,
+    // Rename what would be replaced instead of removing it.
+    pub backup_existing: bool,
+    // Read each file back once written and check its chunks.
+    pub verify: bool,
+    // The journal to resume from and record to, see `journal`.
+    pub journal: Option<PathBuf>,
+}
+
+impl Default for RestoreOptions {
+    fn default() -> RestoreOptions {
+        RestoreOptions {
+            workers: 8,
+            owners: false,
+            owner_map: Default::default(),
+            xattrs: Default::default(),
+            skip_specials: false,
+            contain_symlinks: false,
+            path: None,
+            overwrite: None,
+            backup_existing: false,
+            verify: false,
+            journal: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Overwrite {
+    Never,
+    Always,
+    IfChanged,
+}
+
+impl Overwrite {
+    pub fn parse(s: &str) -> Option<Overwrite> {
+        match s {
+            "never" => Some(Overwrite::Never),
+            "always" => Some(Overwrite::Always),
+            "if-changed" => Some(Overwrite::IfChanged),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Decision {
+    Kept,
+    Replaced,
+    // With the path it was renamed to.
+    Renamed(Vec<u8>),
+}
+
+impl Decision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Decision::Kept => "kept",
+            Decision::Replaced => "replaced",
+            Decision::Renamed(_) => "renamed",
+        }
+    }
+}
+
+// A path that was already in the target.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Existing {
+    pub path: Vec<u8>,
+    pub decision: Decision,
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct RestoreStats {
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    // Devices, fifos and sockets.
+    pub specials: u64,
+    pub bytes: u64,
+    // What could not be restored, sorted by path.
+    pub errors: Vec<SnapshotError>,
+    // Paths already in the target, sorted by path.
+    pub existing: Vec<Existing>,
+    // Files read back and found to match, see `RestoreOptions::verify`,
+    // and files an earlier run restored, see `journal`.
+    pub verified: u64,
+    pub resumed: u64,
+    // Bytes of objects read from storage.
+    pub fetched_bytes: u64,
+    // Reading the trees and creating all but files, writing files, and
+    // setting what metadata is left.
+    pub plan_time: Duration,
+    pub write_time: Duration,
+    pub metadata_time: Duration,
+}
+
+impl RestoreStats {
+    pub fn write_ndjson(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            w,
+            "{{\"type\": \"restore\", \"files\": {}, \"dirs\": {}, \"symlinks\": {}, \
+             \"specials\": {}, \"bytes\": {}, \"errors\": {}, \"fetched_bytes\": {}, \
+             \"plan_seconds\": {:.3}, \"write_seconds\": {:.3}, \"metadata_seconds\": {:.3}, \
+             \"verified\": {}, \"resumed\": {}}}",
+            self.files,
+            self.dirs,
+            self.symlinks,
+            self.specials,
+            self.bytes,
+            self.errors.len(),
+            self.fetched_bytes,
+            self.plan_time.as_secs_f64(),
+            self.write_time.as_secs_f64(),
+            self.metadata_time.as_secs_f64(),
+            self.verified,
+            self.resumed
+        )?;
+        for e in self.existing.iter() {
+            let renamed_to = match e.decision {
+                Decision::Renamed(ref to) => json_path(to),
+                _ => "null".to_string(),
+            };
+            writeln!(
+                w,
+                "{{\"type\": \"existing\", \"path\": {}, \"decision\": \"{}\", \"renamed_to\": {}}}",
+                json_path(&e.path),
+                e.decision.as_str(),
+                renamed_to
+            )?;
+        }
+        write_errors(w, &self.errors)
+    }
+
+    // Each path already in the target and what was done about it.
+    pub fn write_existing(&self, w: &mut dyn Write) -> io::Result<()> {
+        for e in self.existing.iter() {
+            match e.decision {
+                Decision::Renamed(ref to) => {
+                    writeln!(w, "renamed   {} -> {}", text_path(&e.path), text_path(to))?
+                }
+                ref d => writeln!(w, "{:<9} {}", d.as_str(), text_path(&e.path))?,
+            }
+        }
+        Ok(())
+    }
+
+    // The summary printed after a restore, see the module documentation.
+    pub fn write_report(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            w,
+            "files   {} restored, {} dirs, {} symlinks, {} special, {} errors",
+            self.files,
+            self.dirs,
+            self.symlinks,
+            self.specials,
+            self.errors.len()
+        )?;
+        writeln!(
+            w,
+            "data    {} written, {} fetched",
+            human_bytes(self.bytes),
+            human_bytes(self.fetched_bytes)
+        )?;
+        writeln!(
+            w,
+            "time    {:.1}s planning, {:.1}s writing files, {:.1}s setting metadata",
+            self.plan_time.as_secs_f64(),
+            self.write_time.as_secs_f64(),
+            self.metadata_time.as_secs_f64()
+        )?;
+        if self.verified > 0 {
+            writeln!(w, "verify  {} files read back intact", self.verified)?;
+        }
+        if self.resumed > 0 {
+            writeln!(
+                w,
+                "resume  {} files restored by an earlier run",
+                self.resumed
+            )?;
+        }
+        if self.existing.is_empty() {
+            return Ok(());
+        }
+        let count =
+            |d: fn(&Decision) -> bool| self.existing.iter().filter(|e| d(&e.decision)).count();
+        writeln!(
+            w,
+            "existing {} kept, {} replaced, {} renamed",
+            count(|d| *d == Decision::Kept),
+            count(|d| *d == Decision::Replaced),
+            count(|d| matches!(d, Decision::Renamed(_)))
+        )
+    }
+
+    pub fn summary(&self) -> Summary {
+        Summary {
+            errors: self.errors.len() as u64,
+            ..Summary::new("get")
+        }
+    }
+}
+
+// Everything found by walking the trees.
+#[derive(Default)]
+struct Plan {
+    files: Vec<(PathBuf, TreeEntry)>,
+    // The path of the file restored for each link id, and the links to
+    // make to it.
+    links: HashMap<u64, PathBuf>,
+    hard_links: Vec<(PathBuf, PathBuf)>,
+    // Symlinks with extended attributes to set.
+    symlinks: Vec<(PathBuf, TreeEntry)>,
+    // Devices, fifos and sockets.
+    specials: Vec<(PathBuf, TreeEntry)>,
+    // The restore target.
+    root: PathBuf,
+    // Ids to set for those stored.
+    owners: Owners,
+    journal: Option<Journal>,
+    // Deepest first.
+    dirs: Vec<(PathBuf, TreeEntry)>,
+    stats: RestoreStats,
+}
+
+// Whether to restore an entry where something already is.
+enum Room {
+    Free,
+    // A directory to restore into.
+    Merge,
+    Kept,
+}
+
+impl Plan {
+    // The path of `path` in the target, as journaled.
+    fn relative<'p>(&self, path: &'p Path) -> &'p [u8] {
+        path.strip_prefix(&self.root)
+            .unwrap_or(path)
+            .as_os_str()
+            .as_bytes()
+    }
+
+    // Whether an earlier run completed the file at `path`.
+    fn resumed(&self, path: &Path, verified: bool) -> bool {
+        self.journal
+            .as_ref()
+            .is_some_and(|j| j.is_done(self.relative(path), verified))
+    }
+}
+
+// What to restore, see `RestoreOptions::path`.
+enum Selected {
+    Root(Address),
+    Entry(TreeEntry),
+}
+
+fn record(errors: &mut Vec<SnapshotError>, path: &Path, message: &str) {
+    errors.push(SnapshotError {
+        path: path.as_os_str().as_bytes().to_vec(),
+        message: message.to_string(),
+    });
+}
+
+fn set_metadata(f: &File, path: &Path, ent: &TreeEntry, opts: &RestoreOptions) -> io::Result<()> {
+    // Changing the owner clears setuid bits, so it goes first.
+    if opts.owners {
+        fchown(f, Some(ent.uid), Some(ent.gid))?;
+    }
+    xattr::write(path, &ent.xattrs, &opts.xattrs)?;
+    f.set_permissions(fs::Permissions::from_mode(ent.mode))?;
+    let time = |(secs, nsec)| UNIX_EPOCH + Duration::new(secs, nsec);
+    let mut times = FileTimes::new().set_modified(time((ent.mtime, ent.mtime_nsec)));
+    if let Some(atime) = ent.atime {
+        times = times.set_accessed(time(atime));
+    }
+    f.set_times(times)
+}
+
+// Like `set_metadata`, for a device, fifo or socket, which is not opened.
+fn set_special_metadata(path: &Path, ent: &TreeEntry, opts: &RestoreOptions) -> io::Result<()> {
+    if opts.owners {
+        lchown(path, Some(ent.uid), Some(ent.gid))?;
+    }
+    xattr::write(path, &ent.xattrs, &opts.xattrs)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(ent.mode))?;
+    special::set_times(path, ent)
+}
+
+// Whether a symlink to `target`, `depth` directories below the restore
+// target, could lead outside it, see the module documentation.
+fn escapes(target: &[u8], mut depth: usize) -> bool {
+    if target.starts_with(b"/") {
+        return true;
+    }
+    let mut climbing = true;
+    for name in target.split(|b| *b == b'/') {
+        match name {
+            b"" | b"." => (),
+            b".." if climbing && depth > 0 => depth -= 1,
+            b".." => return true,
+            _ => climbing = false,
+        }
+    }
+    false
+}
+
+// Whether `meta`, of what is at `path`, already matches `ent`, see the
+// module documentation.
+fn unchanged(ent: &TreeEntry, path: &Path, meta: &fs::Metadata) -> bool {
+    let t = meta.file_type();
+    match ent.kind {
+        EntryKind::File => {
+            t.is_file()
+                && meta.len() == ent.size
+                && (meta.mtime(), meta.mtime_nsec()) == (ent.mtime as i64, ent.mtime_nsec as i64)
+        }
+        EntryKind::Dir => false,
+        EntryKind::Symlink => {
+            t.is_symlink()
+                && fs::read_link(path).is_ok_and(|l| l.as_os_str().as_bytes() == ent.target)
+        }
+        EntryKind::BlockDevice => {
+            t.is_block_device() && special::device_numbers(meta.rdev()) == ent.device
+        }
+        EntryKind::CharDevice => {
+            t.is_char_device() && special::device_numbers(meta.rdev()) == ent.device
+        }
+        EntryKind::Fifo => t.is_fifo(),
+        EntryKind::Socket => t.is_socket(),
+    }
+}
+
+// The first of `path.orig`, `path.orig.1` and on that is free.
+fn orig_path(path: &Path) -> io::Result<PathBuf> {
+    let mut i = 0;
+    loop {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(".orig");
+        if i > 0 {
+            name.push(format!(".{}", i));
+        }
+        let orig = path.with_file_name(name);
+        match fs::symlink_metadata(&orig) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(orig),
+            Err(e) => return Err(e),
+            Ok(_) => i += 1,
+        }
+    }
+}
+
+// Sync `f`, written with the chunks of `ent`, `lengths` long, and read it
+// back from the disk to check them, see `RestoreOptions::verify`.
+fn check_written(
+    f: &File,
+    ent: &TreeEntry,
+    lengths: &[usize],
+    ak: &AddressKey,
+) -> Result<(), RepoError> {
+    f.sync_all()?;
+    drop_cache(f);
+    let mut buf = Vec::new();
+    let mut offset = 0;
+    for (address, len) in ent.refs.iter().zip(lengths.iter()) {
+        buf.resize(*len, 0);
+        f.read_exact_at(&mut buf, offset)?;
+        if ak.address(&buf) != *address {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the file read back does not match the snapshot",
+            )
+            .into());
+        }
+        offset += *len as u64;
+    }
+    if f.metadata()?.len() != offset {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the file read back is not the size in the snapshot",
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// Have the next read of `f` come from the disk, not the page cache.
+#[cfg(target_os = "linux")]
+fn drop_cache(f: &File) {
+    // Only advice, a file still cached is read all the same.
+    unsafe { sys::posix_fadvise(f.as_raw_fd(), 0, 0, POSIX_FADV_DONTNEED) };
+}
+
+#[cfg(not(target_os = "linux"))]
+fn drop_cache(_f: &File) {}
+
+// The restore target must be empty unless a policy says what to do about
+// what is in it, it is created if missing.
+fn prepare_target(to: &Path, opts: &RestoreOptions) -> Result<(), RepoError> {
+    fs::create_dir_all(to)?;
+    if opts.overwrite.is_none() && fs::read_dir(to)?.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "the restore target is not empty",
+        )
+        .into());
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    repo: &'a Repo,
+    index: &'a RepoIndex,
+    sk: &'a CryptoBoxSk,
+    address_key: Option<&'a AddressKey>,
+    // Bytes of sealed objects read from storage.
+    fetched: AtomicU64,
+}
+
+impl<'a> Reader<'a> {
+    fn read(
+        &self,
+        address: &Address,
+        kind: ObjectKind,
+        pack: &mut Option<(PackId, PackReader<StorageObject>)>,
+    ) -> Result<Vec<u8>, RepoError> {
+        let (stored_kind, data) = match self.index.lookup(address) {
+            Some(loc) => {
+                if pack.as_ref().map(|(id, _)| *id) != Some(loc.pack_id) {
+                    *pack = Some((loc.pack_id, self.repo.open_pack(&loc.pack_id, self.sk)?));
+                }
+                let (_, reader) = pack.as_mut().unwrap();
+                let data = reader.read(loc.offset, loc.length)?;
+                self.fetched.fetch_add(loc.length as u64, Ordering::Relaxed);
+                (loc.kind, data)
+            }
+            None => {
+                let (kind, data) = self.repo.read_object(self.index, self.sk, address)?;
+                self.fetched.fetch_add(data.len() as u64, Ordering::Relaxed);
+                (kind, data)
+            }
+        };
+        if stored_kind != kind {
+            return Err(RepoError::InvalidDataError);
+        }
+        if let Some(ak) = self.address_key {
+            if ak.address(&data) != *address {
+                return Err(RepoError::CorruptOrTamperedDataError);
+            }
+        }
+        Ok(data)
+    }
+
+    fn plan_dir(&self, tree: &Address, path: &Path, opts: &RestoreOptions, plan: &mut Plan) {
+        let tree = match self
+            .read(tree, ObjectKind::Tree, &mut None)
+            .and_then(|buf| Tree::decode(&buf))
+        {
+            Ok(tree) => tree,
+            Err(err) => return record(&mut plan.stats.errors, path, &err.to_string()),
+        };
+        for ent in tree.entries {
+            let p = path.join(OsStr::from_bytes(&ent.name));
+            self.plan_entry(ent, p, opts, plan);
+        }
+    }
+
+    fn plan_entry(
+        &self,
+        mut ent: TreeEntry,
+        path: PathBuf,
+        opts: &RestoreOptions,
+        plan: &mut Plan,
+    ) {
+        ent.uid = plan.owners.uid(ent.uid);
+        ent.gid = plan.owners.gid(ent.gid);
+        match ent.kind {
+            EntryKind::File | EntryKind::Dir => (),
+            EntryKind::Symlink if opts.contain_symlinks && self.escapes(&ent, &path, plan) => {
+                let message = "symlink leads outside the restore target";
+                return record(&mut plan.stats.errors, &path, message);
+            }
+            EntryKind::Symlink => (),
+            _ if opts.skip_specials => return,
+            _ => (),
+        }
+        if ent.kind == EntryKind::File && plan.resumed(&path, opts.verify) {
+            plan.stats.resumed += 1;
+            if ent.link != 0 {
+                plan.links.entry(ent.link).or_insert(path);
+            }
+            return;
+        }
+        let merge = match self.make_room(&ent, &path, opts, plan) {
+            Ok(Room::Free) => false,
+            Ok(Room::Merge) => true,
+            Ok(Room::Kept) => return,
+            Err(err) => return record(&mut plan.stats.errors, &path, &err.to_string()),
+        };
+        match ent.kind {
+            EntryKind::File if ent.link != 0 => match plan.links.get(&ent.link) {
+                Some(first) => plan.hard_links.push((path, first.clone())),
+                None => {
+                    plan.links.insert(ent.link, path.clone());
+                    plan.files.push((path, ent));
+                }
+            },
+            EntryKind::File => plan.files.push((path, ent)),
+            EntryKind::Dir => {
+                if !merge {
+                    if let Err(err) = fs::create_dir(&path) {
+                        return record(&mut plan.stats.errors, &path, &err.to_string());
+                    }
+                }
+                self.plan_dir(&ent.refs[0], &path, opts, plan);
+                plan.stats.dirs += 1;
+                if !merge || opts.overwrite != Some(Overwrite::Never) {
+                    plan.dirs.push((path, ent));
+                }
+            }
+            EntryKind::Symlink => match symlink(OsStr::from_bytes(&ent.target), &path) {
+                Ok(()) if !ent.xattrs.is_empty() => {
+                    plan.stats.symlinks += 1;
+                    plan.symlinks.push((path, ent));
+                }
+                Ok(()) => plan.stats.symlinks += 1,
+                Err(err) => record(&mut plan.stats.errors, &path, &err.to_string()),
+            },
+            _ => match special::make(&path, &ent) {
+                Ok(()) => {
+                    plan.stats.specials += 1;
+                    plan.specials.push((path, ent));
+                }
+                Err(err) => record(&mut plan.stats.errors, &path, &err.to_string()),
+            },
+        }
+    }
+
+    // Apply the overwrite policy to whatever is at `path`, recording what
+    // was done about it.
+    fn make_room(
+        &self,
+        ent: &TreeEntry,
+        path: &Path,
+        opts: &RestoreOptions,
+        plan: &mut Plan,
+    ) -> io::Result<Room> {
+        let policy = match opts.overwrite {
+            Some(policy) => policy,
+            None => return Ok(Room::Free),
+        };
+        let meta = match fs::symlink_metadata(path) {
+            Ok(meta) => meta,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Room::Free),
+            Err(e) => return Err(e),
+        };
+        if ent.kind == EntryKind::Dir && meta.is_dir() {
+            return Ok(Room::Merge);
+        }
+        let decision = if policy == Overwrite::Never
+            || (policy == Overwrite::IfChanged && unchanged(ent, path, &meta))
+        {
+            Decision::Kept
+        } else if opts.backup_existing {
+            let orig = orig_path(path)?;
+            fs::rename(path, &orig)?;
+            Decision::Renamed(orig.into_os_string().into_vec())
+        } else if meta.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "a directory is in the way",
+            ));
+        } else {
+            fs::remove_file(path)?;
+            Decision::Replaced
+        };
+        let room = match decision {
+            Decision::Kept => Room::Kept,
+            _ => Room::Free,
+        };
+        plan.stats.existing.push(Existing {
+            path: path.as_os_str().as_bytes().to_vec(),
+            decision,
+        });
+        Ok(room)
+    }
+
+    fn escapes(&self, ent: &TreeEntry, path: &Path, plan: &Plan) -> bool {
+        let depth = path
+            .strip_prefix(&plan.root)
+            .map_or(0, |p| p.components().count());
+        escapes(&ent.target, depth.saturating_sub(1))
+    }
+
+    // Find the entry at `path` below the tree `root`.
+    fn lookup(&self, root: &Address, path: &Path) -> Result<TreeEntry, RepoError> {
+        let not_found = || {
+            RepoError::IOError(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no such path in the snapshot",
+            ))
+        };
+        let mut found: Option<TreeEntry> = None;
+        for c in path.components() {
+            let name = match c {
+                Component::Normal(name) => name.as_bytes(),
+                Component::CurDir => continue,
+                _ => return Err(not_found()),
+            };
+            let tree = match found {
+                None => *root,
+                Some(ref ent) if ent.kind == EntryKind::Dir => ent.refs[0],
+                Some(_) => return Err(not_found()),
+            };
+            let tree = Tree::decode(&self.read(&tree, ObjectKind::Tree, &mut None)?)?;
+            let i = tree
+                .entries
+                .binary_search_by(|ent| ent.name[..].cmp(name))
+                .map_err(|_| not_found())?;
+            found = Some(tree.entries[i].clone());
+        }
+        found.ok_or_else(not_found)
+    }
+
+    fn file(
+        &self,
+        path: &Path,
+        ent: &TreeEntry,
+        opts: &RestoreOptions,
+        pack: &mut Option<(PackId, PackReader<StorageObject>)>,
+    ) -> Result<u64, RepoError> {
+        let mut f = OpenOptions::new()
+            .read(opts.verify)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        let lengths = self.copy(ent, &mut f, &mut 0, pack)?;
+        if let (true, Some(ak)) = (opts.verify, self.address_key) {
+            check_written(&f, ent, &lengths, ak)?;
+        }
+        set_metadata(&f, path, ent, opts)?;
+        if opts.journal.is_some() {
+            // Nothing is journaled before it is on disk.
+            f.sync_all()?;
+        }
+        Ok(ent.size)
+    }
+
+    // Write the contents of the file `ent` to `w`, counting bytes in `n`,
+    // and return the length of each chunk. No more than `ent.size` bytes
+    // are ever written.
+    fn copy(
+        &self,
+        ent: &TreeEntry,
+        w: &mut dyn Write,
+        n: &mut u64,
+        pack: &mut Option<(PackId, PackReader<StorageObject>)>,
+    ) -> Result<Vec<usize>, RepoError> {
+        let mut lengths = Vec::with_capacity(ent.refs.len());
+        for address in ent.refs.iter() {
+            let data = self.read(address, ObjectKind::Chunk, pack)?;
+            if data.len() as u64 > ent.size - *n {
+                return Err(RepoError::InvalidDataError);
+            }
+            w.write_all(&data)?;
+            *n += data.len() as u64;
+            lengths.push(data.len());
+        }
+        if *n != ent.size {
+            return Err(RepoError::InvalidDataError);
+        }
+        Ok(lengths)
+    }
+
+    // The snapshot and the entry at `path` in it, or all of it.
+    fn select(
+        &self,
+        snapshot: &Address,
+        path: Option<&Path>,
+    ) -> Result<(Snapshot, Selected), RepoError> {
+        let snapshot = Snapshot::decode(&self.read(snapshot, ObjectKind::Snapshot, &mut None)?)?;
+        if snapshot.name_encoding != NAMES_UNIX {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the snapshot's file names are not those of a Unix system",
+            )
+            .into());
+        }
+        let selected = match path {
+            Some(path) => Selected::Entry(self.lookup(&snapshot.root, path)?),
+            None => Selected::Root(snapshot.root),
+        };
+        Ok((snapshot, selected))
+    }
+
+    // Write `ent` and everything below it to a tar stream. Only errors
+    // writing the stream are returned, others are recorded.
+    fn tar_entry(
+        &self,
+        ent: &TreeEntry,
+        path: &[u8],
+        skip_specials: bool,
+        w: &mut dyn Write,
+        pack: &mut Option<(PackId, PackReader<StorageObject>)>,
+        stats: &mut RestoreStats,
+    ) -> io::Result<()> {
+        match ent.kind {
+            EntryKind::File => {
+                tar::write_header(w, path, ent)?;
+                let mut n = 0;
+                let mut failed = None;
+                for address in ent.refs.iter() {
+                    match self.read(address, ObjectKind::Chunk, pack) {
+                        Ok(ref data) if data.len() as u64 <= ent.size - n => {
+                            w.write_all(data)?;
+                            n += data.len() as u64;
+                        }
+                        Ok(_) => failed = Some(RepoError::InvalidDataError),
+                        Err(err) => failed = Some(err),
+                    }
+                    if failed.is_some() {
+                        break;
+                    }
+                }
+                if failed.is_none() && n != ent.size {
+                    failed = Some(RepoError::InvalidDataError);
+                }
+                match failed {
+                    Some(err) => record(
+                        &mut stats.errors,
+                        Path::new(OsStr::from_bytes(path)),
+                        &err.to_string(),
+                    ),
+                    None => {
+                        stats.files += 1;
+                        stats.bytes += n;
+                    }
+                }
+                ustar::write_zeros(w, ent.size - n)?;
+                ustar::write_padding(w, ent.size)
+            }
+            EntryKind::Dir => {
+                let mut dir = path.to_vec();
+                dir.push(b'/');
+                tar::write_header(w, &dir, ent)?;
+                stats.dirs += 1;
+                self.tar_dir(&ent.refs[0], &dir, skip_specials, w, pack, stats)
+            }
+            EntryKind::Symlink => {
+                stats.symlinks += 1;
+                tar::write_header(w, path, ent)
+            }
+            EntryKind::Socket => Ok(()),
+            _ if skip_specials => Ok(()),
+            _ => {
+                stats.specials += 1;
+                tar::write_header(w, path, ent)
+            }
+        }
+    }
+
+    // `prefix` is empty or ends in `/`.
+    fn tar_dir(
+        &self,
+        tree: &Address,
+        prefix: &[u8],
+        skip_specials: bool,
+        w: &mut dyn Write,
+        pack: &mut Option<(PackId, PackReader<StorageObject>)>,
+        stats: &mut RestoreStats,
+    ) -> io::Result<()> {
+        let tree = match self
+            .read(tree, ObjectKind::Tree, &mut None)
+            .and_then(|buf| Tree::decode(&buf))
+        {
+            Ok(tree) => tree,
+            Err(err) => {
+                let path = Path::new(OsStr::from_bytes(prefix));
+                record(&mut stats.errors, path, &err.to_string());
+                return Ok(());
+            }
+        };
+        for ent in tree.entries.iter() {
+            let path = [prefix, &ent.name].concat();
+            self.tar_entry(ent, &path, skip_specials, w, pack, stats)?;
+        }
+        Ok(())
+    }
+}
+
+impl Repo {
+    // Restore `snapshot` into the directory `to`, which must be empty.
+    pub fn restore(
+        &self,
+        snapshot: &Address,
+        to: &Path,
+        sk: &CryptoBoxSk,
+        address_key: Option<&AddressKey>,
+        opts: &RestoreOptions,
+    ) -> Result<RestoreStats, RepoError> {
+        let started = Instant::now();
+        if opts.verify && address_key.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "verifying a restore needs the address key",
+            )
+            .into());
+        }
+        let index = self.load_index()?;
+        let own_key = self.snapshot_address_key(&index, sk, snapshot, address_key)?;
+        let address_key = own_key.as_ref().or(address_key);
+        let reader = Reader {
+            repo: self,
+            index: &index,
+            sk,
+            address_key,
+            fetched: AtomicU64::new(0),
+        };
+        let address = snapshot;
+        let (snapshot, selected) = reader.select(address, opts.path.as_deref())?;
+        let journal = match opts.journal {
+            Some(ref p) => {
+                let part = opts
+                    .path
+                    .as_ref()
+                    .map_or(&b""[..], |p| p.as_os_str().as_bytes());
+                Some(Journal::open(p, address, part)?)
+            }
+            None => None,
+        };
+        let resumed;
+        let opts = match journal {
+            Some(ref j) if j.resuming() && opts.overwrite.is_none() => {
+                resumed = RestoreOptions {
+                    overwrite: Some(Overwrite::IfChanged),
+                    ..opts.clone()
+                };
+                &resumed
+            }
+            _ => opts,
+        };
+        prepare_target(to, opts)?;
+
+        let mut plan = Plan {
+            root: to.to_path_buf(),
+            owners: opts.owner_map.resolve(&snapshot.users, &snapshot.groups),
+            journal,
+            ..Default::default()
+        };
+        match selected {
+            Selected::Entry(ent) => {
+                let p = to.join(OsStr::from_bytes(&ent.name));
+                reader.plan_entry(ent, p, opts, &mut plan);
+            }
+            Selected::Root(root) => reader.plan_dir(&root, to, opts, &mut plan),
+        }
+        let plan_time = started.elapsed();
+
+        let next = AtomicUsize::new(0);
+        let done = Mutex::new((0, 0, Vec::new()));
+        thread::scope(|s| {
+            for _ in 0..opts.workers.clamp(1, plan.files.len().max(1)) {
+                let (reader, plan, next, done) = (&reader, &plan, &next, &done);
+                s.spawn(move || {
+                    let mut pack = None;
+                    while let Some((path, ent)) =
+                        plan.files.get(next.fetch_add(1, Ordering::SeqCst))
+                    {
+                        let result = reader.file(path, ent, opts, &mut pack);
+                        if let (Ok(_), Some(j)) = (&result, &plan.journal) {
+                            // Failing to journal only costs a refetch.
+                            let _ = j.record(plan.relative(path), opts.verify);
+                        }
+                        let mut done = done.lock().unwrap();
+                        match result {
+                            Ok(n) => {
+                                done.0 += 1;
+                                done.1 += n;
+                            }
+                            Err(err) => {
+                                // Leave no partial file behind.
+                                let _ = fs::remove_file(path);
+                                record(&mut done.2, path, &err.to_string());
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        let (files, bytes, errors) = done.into_inner().unwrap();
+        let mut stats = plan.stats;
+        stats.files = files;
+        stats.verified = if opts.verify {
+            files + stats.resumed
+        } else {
+            0
+        };
+        stats.bytes = bytes;
+        stats.errors.extend(errors);
+        stats.fetched_bytes = reader.fetched.load(Ordering::Relaxed);
+        stats.plan_time = plan_time;
+        stats.write_time = started.elapsed() - plan_time;
+
+        for (path, first) in plan.hard_links.iter() {
+            match fs::hard_link(first, path) {
+                Ok(()) => stats.files += 1,
+                Err(err) => record(&mut stats.errors, path, &err.to_string()),
+            }
+        }
+        for (path, ent) in plan.symlinks.iter() {
+            if let Err(err) = xattr::write(path, &ent.xattrs, &opts.xattrs) {
+                record(&mut stats.errors, path, &err.to_string());
+            }
+        }
+        for (path, ent) in plan.specials.iter() {
+            if let Err(err) = set_special_metadata(path, ent, opts) {
+                record(&mut stats.errors, path, &err.to_string());
+            }
+        }
+        for (path, ent) in plan.dirs.iter() {
+            let result = File::open(path).and_then(|f| set_metadata(&f, path, ent, opts));
+            if let Err(err) = result {
+                record(&mut stats.errors, path, &err.to_string());
+            }
+        }
+        stats.metadata_time = started.elapsed() - plan_time - stats.write_time;
+        stats.errors.sort_by(|a, b| a.path.cmp(&b.path));
+        stats.existing.sort_by(|a, b| a.path.cmp(&b.path));
+        if let (true, Some(j)) = (stats.errors.is_empty(), plan.journal) {
+            // A journal left behind would only be resumed from again.
+            let _ = j.remove();
+        }
+        Ok(stats)
+    }
+
+    // Write the contents of the file at `path` in `snapshot` to `w`.
+    pub fn cat(
+        &self,
+        snapshot: &Address,
+        path: &Path,
+        w: &mut dyn Write,
+        sk: &CryptoBoxSk,
+        address_key: Option<&AddressKey>,
+    ) -> Result<u64, RepoError> {
+        let index = self.load_index()?;
+        let own_key = self.snapshot_address_key(&index, sk, snapshot, address_key)?;
+        let address_key = own_key.as_ref().or(address_key);
+        let reader = Reader {
+            repo: self,
+            index: &index,
+            sk,
+            address_key,
+            fetched: AtomicU64::new(0),
+        };
+        let ent = match reader.select(snapshot, Some(path))?.1 {
+            Selected::Entry(ent) if ent.kind == EntryKind::File => ent,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "not a file in the snapshot",
+                )
+                .into())
+            }
+        };
+        reader.copy(&ent, w, &mut 0, &mut None)?;
+        Ok(ent.size)
+    }
+
+    // Write what `restore` would restore to `w` as a tar stream. Paths are
+    // relative, `RestoreOptions::path` and `skip_specials` are the only
+    // options used, and sockets are left out.
+    pub fn restore_tar(
+        &self,
+        snapshot: &Address,
+        w: &mut dyn Write,
+        sk: &CryptoBoxSk,
+        address_key: Option<&AddressKey>,
+        opts: &RestoreOptions,
+    ) -> Result<RestoreStats, RepoError> {
+        let started = Instant::now();
+        let index = self.load_index()?;
+        let own_key = self.snapshot_address_key(&index, sk, snapshot, address_key)?;
+        let address_key = own_key.as_ref().or(address_key);
+        let reader = Reader {
+            repo: self,
+            index: &index,
+            sk,
+            address_key,
+            fetched: AtomicU64::new(0),
+        };
+        let mut stats: RestoreStats = Default::default();
+        let mut pack = None;
+        let skip = opts.skip_specials;
+        match reader.select(snapshot, opts.path.as_deref())?.1 {
+            Selected::Entry(ent) => {
+                reader.tar_entry(&ent, &ent.name, skip, w, &mut pack, &mut stats)?
+            }
+            Selected::Root(root) => reader.tar_dir(&root, b"", skip, w, &mut pack, &mut stats)?,
+        }
+        ustar::write_end(w)?;
+        stats.fetched_bytes = reader.fetched.load(Ordering::Relaxed);
+        stats.write_time = started.elapsed();
+        stats.errors.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(stats)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_restore() {
+    use super::namespace::Namespace;
+    use std::os::unix::fs::MetadataExt;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let dir = super::storage::local::test_dir("restore-src");
+    let to = super::storage::local::test_dir("restore-dst");
+    let big = super::chunker::test_data(3 << 20, 3);
+    fs::create_dir_all(dir.join("sub/empty")).unwrap();
+    fs::write(dir.join("small"), b"hello").unwrap();
+    fs::write(dir.join("empty"), b"").unwrap();
+    fs::write(dir.join("sub/big"), &big).unwrap();
+    symlink("../small", dir.join("sub/link")).unwrap();
+    fs::set_permissions(dir.join("small"), fs::Permissions::from_mode(0o640)).unwrap();
+    fs::set_permissions(dir.join("sub"), fs::Permissions::from_mode(0o555)).unwrap();
+    let ns = Namespace::new("laptop").unwrap();
+    let (head, _) = r.backup(&dir, &ns, &ak, &key, &Default::default()).unwrap();
+
+    let opts = RestoreOptions {
+        workers: 3,
+        ..Default::default()
+    };
+    let stats = r
+        .restore(&head.address, &to, &key.box_sk, Some(&ak), &opts)
+        .unwrap();
+    assert!(stats.errors.is_empty());
+    assert_eq!((stats.files, stats.dirs, stats.symlinks), (3, 2, 1));
+    assert_eq!(stats.bytes, 5 + big.len() as u64);
+    // Sealing adds a little to every chunk and the trees are read too.
+    assert!(stats.fetched_bytes > stats.bytes);
+    assert!(stats.fetched_bytes < stats.bytes + 4096);
+    let mut report = Vec::new();
+    stats.write_report(&mut report).unwrap();
+    let report = String::from_utf8(report).unwrap();
+    assert!(report.starts_with("files   3 restored, 2 dirs, 1 symlinks, 0 special, 0 errors\n"));
+    assert_eq!(fs::read(to.join("small")).unwrap(), b"hello");
+    assert_eq!(fs::read(to.join("sub/big")).unwrap(), big);
+    assert_eq!(
+        fs::read_link(to.join("sub/link")).unwrap(),
+        Path::new("../small")
+    );
+    assert!(to.join("sub/empty").is_dir());
+    let (a, b) = (
+        fs::metadata(dir.join("small")).unwrap(),
+        fs::metadata(to.join("small")).unwrap(),
+    );
+    assert_eq!(b.mode() & 0o7777, 0o640);
+    assert_eq!((a.mtime(), a.mtime_nsec()), (b.mtime(), b.mtime_nsec()));
+    assert_eq!(fs::metadata(to.join("sub")).unwrap().mode() & 0o7777, 0o555);
+
+    // A target that is not empty is refused.
+    match r.restore(&head.address, &to, &key.box_sk, Some(&ak), &opts) {
+        Err(RepoError::IOError(ref e)) if e.kind() == io::ErrorKind::AlreadyExists => (),
+        _ => panic!("expected the target to be refused"),
+    }
+
+    // A damaged chunk costs its file and nothing else.
+    let to2 = super::storage::local::test_dir("restore-dst2");
+    let index = r.load_index().unwrap();
+    let loc = index.lookup(&ak.address(b"hello")).unwrap();
+    let pack_key = format!("{}/{}", super::PACKS_DIR, loc.pack_id.to_hex());
+    let mut buf = r.storage().get(&pack_key).unwrap();
+    buf[loc.offset as usize + 30] ^= 1;
+    r.storage().put(&pack_key, &buf).unwrap();
+    let stats = r
+        .restore(&head.address, &to2, &key.box_sk, Some(&ak), &opts)
+        .unwrap();
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.errors.len(), 1);
+    assert_eq!(
+        stats.errors[0].path,
+        to2.join("small").as_os_str().as_bytes()
+    );
+    assert!(!to2.join("small").exists());
+    let mut out = Vec::new();
+    stats.write_ndjson(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("{\"type\": \"restore\", \"files\": 2, "));
+    assert!(lines[1].starts_with("{\"type\": \"error\", \"path\": "));
+    assert_eq!(stats.summary().exit_code(), 1);
+
+    for d in [&dir, &to, &to2].iter() {
+        let _ = fs::set_permissions(d.join("sub"), fs::Permissions::from_mode(0o755));
+        fs::remove_dir_all(d).unwrap();
+    }
+}
+
+#[test]
+fn test_restore_hard_links() {
+    use super::namespace::Namespace;
+    use std::os::unix::fs::MetadataExt;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let dir = super::storage::local::test_dir("restore-links-src");
+    let to = super::storage::local::test_dir("restore-links-dst");
+    fs::create_dir_all(dir.join("a")).unwrap();
+    fs::create_dir_all(dir.join("b")).unwrap();
+    fs::write(dir.join("a/mail"), b"message").unwrap();
+    fs::hard_link(dir.join("a/mail"), dir.join("b/mail")).unwrap();
+    fs::hard_link(dir.join("a/mail"), dir.join("b/again")).unwrap();
+    fs::write(dir.join("a/copy"), b"message").unwrap();
+    let ns = Namespace::new("laptop").unwrap();
+    let (head, stats) = r.backup(&dir, &ns, &ak, &key, &Default::default()).unwrap();
+    assert_eq!((stats.files, stats.new_chunks), (4, 1));
+
+    let stats = r
+        .restore(
+            &head.address,
+            &to,
+            &key.box_sk,
+            Some(&ak),
+            &Default::default(),
+        )
+        .unwrap();
+    assert!(stats.errors.is_empty());
+    assert_eq!((stats.files, stats.bytes), (4, 14));
+    let ino = |p: &str| fs::metadata(to.join(p)).unwrap().ino();
+    assert_eq!(ino("a/mail"), ino("b/mail"));
+    assert_eq!(ino("a/mail"), ino("b/again"));
+    assert_ne!(ino("a/mail"), ino("a/copy"));
+    assert_eq!(fs::metadata(to.join("b/mail")).unwrap().nlink(), 3);
+    assert_eq!(fs::read(to.join("b/again")).unwrap(), b"message");
+
+    // Link ids are inode numbers, so an unchanged tree keeps its address.
+    let index = r.load_index().unwrap();
+    let root = r
+        .read_snapshot(&index, &key.box_sk, &head.address)
+        .unwrap()
+        .root;
+    let (again, _) = r.backup(&dir, &ns, &ak, &key, &Default::default()).unwrap();
+    let index = r.load_index().unwrap();
+    let s = r
+        .read_snapshot(&index, &key.box_sk, &again.address)
+        .unwrap();
+    assert_eq!(s.root, root);
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&to).unwrap();
+}
+
+#[test]
+fn test_restore_specials() {
+    use super::backup::BackupOptions;
+    use super::namespace::Namespace;
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    use std::os::unix::net::UnixListener;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let dir = super::storage::local::test_dir("restore-specials-src");
+    let to = super::storage::local::test_dir("restore-specials-dst");
+    let tr = super::storage::local::test_dir("restore-specials-skip");
+    fs::create_dir_all(&dir).unwrap();
+    let fifo = TreeEntry {
+        name: b"fifo".to_vec(),
+        kind: EntryKind::Fifo,
+        mode: 0o640,
+        uid: 0,
+        gid: 0,
+        mtime: 0,
+        mtime_nsec: 0,
+        size: 0,
+        refs: Vec::new(),
+        target: Vec::new(),
+        link: 0,
+        xattrs: Vec::new(),
+        device: (0, 0),
+        atime: None,
+        birthtime: None,
+    };
+    special::make(&dir.join("fifo"), &fifo).unwrap();
+    fs::set_permissions(dir.join("fifo"), fs::Permissions::from_mode(0o640)).unwrap();
+    let _listener = UnixListener::bind(dir.join("socket")).unwrap();
+    // Only root can make a device to back up.
+    let null = TreeEntry {
+        name: b"null".to_vec(),
+        kind: EntryKind::CharDevice,
+        device: (1, 3),
+        ..fifo.clone()
+    };
+    let root = special::make(&dir.join("null"), &null).is_ok();
+    let ns = Namespace::new("laptop").unwrap();
+    let (head, stats) = r.backup(&dir, &ns, &ak, &key, &Default::default()).unwrap();
+    assert_eq!(stats.specials, 2 + root as u64);
+
+    let stats = r
+        .restore(
+            &head.address,
+            &to,
+            &key.box_sk,
+            Some(&ak),
+            &Default::default(),
+        )
+        .unwrap();
+    assert!(stats.errors.is_empty(), "{:?}", stats.errors);
+    assert_eq!(stats.specials, 2 + root as u64);
+    let meta = |p: &Path| fs::symlink_metadata(p).unwrap();
+    assert!(meta(&to.join("fifo")).file_type().is_fifo());
+    assert!(meta(&to.join("socket")).file_type().is_socket());
+    let (a, b) = (meta(&dir.join("fifo")), meta(&to.join("fifo")));
+    assert_eq!(b.mode() & 0o7777, 0o640);
+    assert_eq!((a.mtime(), a.mtime_nsec()), (b.mtime(), b.mtime_nsec()));
+    if root {
+        assert!(meta(&to.join("null")).file_type().is_char_device());
+        assert_eq!(
+            special::device_numbers(meta(&to.join("null")).rdev()),
+            (1, 3)
+        );
+    }
+
+    // Left out of a backup, or of a restore, when asked to.
+    let opts = RestoreOptions {
+        skip_specials: true,
+        ..Default::default()
+    };
+    let stats = r
+        .restore(&head.address, &tr, &key.box_sk, Some(&ak), &opts)
+        .unwrap();
+    assert_eq!(stats.specials, 0);
+    assert_eq!(fs::read_dir(&tr).unwrap().count(), 0);
+    let opts = BackupOptions {
+        skip_specials: true,
+        ..Default::default()
+    };
+    let (_, stats) = r.backup(&dir, &ns, &ak, &key, &opts).unwrap();
+    assert_eq!((stats.specials, stats.skipped), (0, 2 + root as u64));
+
+    // A tar stream holds the fifo but no socket.
+    let mut out = Vec::new();
+    let stats = r
+        .restore_tar(
+            &head.address,
+            &mut out,
+            &key.box_sk,
+            Some(&ak),
+            &Default::default(),
+        )
+        .unwrap();
+    assert_eq!(stats.specials, 1 + root as u64);
+    let entries = tar::test_read_tar(&out);
+    assert_eq!(entries[0], (b"fifo".to_vec(), b'6', Vec::new()));
+    assert_eq!(entries.len(), 1 + root as usize);
+    for d in [&dir, &to, &tr].iter() {
+        fs::remove_dir_all(d).unwrap();
+    }
+}
+
+#[test]
+fn test_restore_times() {
+    use super::backup::BackupOptions;
+    use super::namespace::Namespace;
+    use std::os::unix::fs::MetadataExt;
+    use std::time::SystemTime;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let dir = super::storage::local::test_dir("restore-times-src");
+    let to = super::storage::local::test_dir("restore-times-dst");
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/file"), b"data").unwrap();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let mtime = UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789);
+    let atime = UNIX_EPOCH + Duration::new(now - 60, 987_654_321);
+    let times = FileTimes::new().set_modified(mtime).set_accessed(atime);
+    File::open(dir.join("sub/file"))
+        .unwrap()
+        .set_times(times)
+        .unwrap();
+    File::open(dir.join("sub"))
+        .unwrap()
+        .set_times(times)
+        .unwrap();
+    let ns = Namespace::new("laptop").unwrap();
+    let opts = BackupOptions {
+        atime: true,
+        birthtime: true,
+        ..Default::default()
+    };
+    let (head, _) = r.backup(&dir, &ns, &ak, &key, &opts).unwrap();
+    let index = r.load_index().unwrap();
+    let root = r
+        .read_snapshot(&index, &key.box_sk, &head.address)
+        .unwrap()
+        .root;
+    let sub = &r.read_tree(&index, &key.box_sk, &root).unwrap().entries[0];
+    assert_eq!(sub.atime, Some((now - 60, 987_654_321)));
+    let created = fs::metadata(dir.join("sub")).unwrap().created().is_ok();
+    assert_eq!(sub.birthtime.is_some(), created);
+    // Not stored unless asked for.
+    let (plain, _) = r.backup(&dir, &ns, &ak, &key, &Default::default()).unwrap();
+    let index = r.load_index().unwrap();
+    let root = r
+        .read_snapshot(&index, &key.box_sk, &plain.address)
+        .unwrap()
+        .root;
+    let sub = &r.read_tree(&index, &key.box_sk, &root).unwrap().entries[0];
+    assert_eq!((sub.atime, sub.birthtime), (None, None));
+
+    let stats = r
+        .restore(
+            &head.address,
+            &to,
+            &key.box_sk,
+            Some(&ak),
+            &Default::default(),
+        )
+        .unwrap();
+    assert!(stats.errors.is_empty());
+    // Directories too, though their contents were written after them.
+    for p in ["sub", "sub/file"].iter() {
+        let meta = fs::metadata(to.join(p)).unwrap();
+        assert_eq!(
+            (meta.mtime(), meta.mtime_nsec()),
+            (1_600_000_000, 123_456_789)
+        );
+        assert_eq!(
+            (meta.atime() as u64, meta.atime_nsec()),
+            (now - 60, 987_654_321)
+        );
+    }
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&to).unwrap();
+}
+
+#[test]
+fn test_restore_xattrs() {
+    use super::namespace::Namespace;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let dir = super::storage::local::test_dir("restore-xattrs-src");
+    let to = super::storage::local::test_dir("restore-xattrs-dst");
+    fs::create_dir_all(dir.join("d")).unwrap();
+    fs::write(dir.join("d/file"), b"data").unwrap();
+    if !xattr::test_supported(&dir.join("d/file")) {
+        return fs::remove_dir_all(&dir).unwrap();
+    }
+    let attrs = vec![
+        (b"user.kept".to_vec(), b"yes".to_vec()),
+        (b"user.local".to_vec(), b"here only".to_vec()),
+    ];
+    xattr::write(&dir.join("d/file"), &attrs, &Default::default()).unwrap();
+    xattr::write(&dir.join("d"), &attrs[..1], &Default::default()).unwrap();
+    let ns = Namespace::new("laptop").unwrap();
+    let (head, _) = r.backup(&dir, &ns, &ak, &key, &Default::default()).unwrap();
+
+    let opts = RestoreOptions {
+        xattrs: XattrOptions::skipping(&["user.local".to_string()]),
+        ..Default::default()
+    };
+    let stats = r
+        .restore(&head.address, &to, &key.box_sk, Some(&ak), &opts)
+        .unwrap();
+    assert!(stats.errors.is_empty());
+    let read = |p: &str| xattr::read(&to.join(p), false, &Default::default()).unwrap();
+    assert_eq!(
+        read("d/file"),
+        vec![
+            (b"user.kept".to_vec(), b"yes".to_vec()),
+            (b"user.probe".to_vec(), Vec::new()),
+        ]
+    );
+    assert_eq!(read("d"), attrs[..1].to_vec());
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&to).unwrap();
+}
+
+#[test]
+fn test_restore_path() {
+    use super::namespace::Namespace;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let dir = super::storage::local::test_dir("restore-path-src");
+    fs::create_dir_all(dir.join("a/b")).unwrap();
+    fs::write(dir.join("a/b/lost"), b"found").unwrap();
+    fs::write(dir.join("a/other"), b"other").unwrap();
+    fs::write(dir.join("top"), b"top").unwrap();
+    let ns = Namespace::new("laptop").unwrap();
+    let (head, _) = r.backup(&dir, &ns, &ak, &key, &Default::default()).unwrap();
+    let restore = |path: &str, to: &Path| {
+        let opts = RestoreOptions {
+            path: Some(PathBuf::from(path)),
+            ..Default::default()
+        };
+        r.restore(&head.address, to, &key.box_sk, Some(&ak), &opts)
+    };
+
+    // A single file lands in the target under its own name.
+    let to = super::storage::local::test_dir("restore-path-file");
+    let stats = restore("a/./b/lost", &to).unwrap();
+    assert_eq!((stats.files, stats.dirs), (1, 0));
+    assert_eq!(fs::read(to.join("lost")).unwrap(), b"found");
+    assert_eq!(fs::read_dir(&to).unwrap().count(), 1);
+
+    // So does a subtree, with everything below it.
+    let to2 = super::storage::local::test_dir("restore-path-dir");
+    let stats = restore("a", &to2).unwrap();
+    assert_eq!((stats.files, stats.dirs), (2, 2));
+    assert_eq!(fs::read(to2.join("a/b/lost")).unwrap(), b"found");
+    assert!(!to2.join("top").exists());
+
+    let to3 = super::storage::local::test_dir("restore-path-none");
+    for path in ["nope", "a/nope", "top/x", "../a", "/a", ""].iter() {
+        match restore(path, &to3) {
+            Err(ref e) if e.is_not_found() => (),
+            _ => panic!("expected {:?} not to be found", path),
+        }
+    }
+    // Nothing is created for a path that is not found.
+    assert!(!to3.exists());
+
+    for d in [&dir, &to, &to2].iter() {
+        fs::remove_dir_all(d).unwrap();
+    }
+}
+
+#[test]
+fn test_restore_verify() {
+    use super::namespace::Namespace;
+    use super::receipt::RestoreReceipt;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let dir = super::storage::local::test_dir("restore-verify-src");
+    let to = super::storage::local::test_dir("restore-verify-dst");
+    let big = super::chunker::test_data(3 << 20, 7);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("big"), &big).unwrap();
+    fs::write(dir.join("small"), b"hello").unwrap();
+    let ns = Namespace::new("laptop").unwrap();
+    let (head, _) = r.backup(&dir, &ns, &ak, &key, &Default::default()).unwrap();
+    let opts = RestoreOptions {
+        verify: true,
+        ..Default::default()
+    };
+    match r.restore(&head.address, &to, &key.box_sk, None, &opts) {
+        Err(RepoError::IOError(ref e)) if e.kind() == io::ErrorKind::InvalidInput => (),
+        _ => panic!("expected verifying without the address key to be refused"),
+    }
+    let stats = r
+        .restore(&head.address, &to, &key.box_sk, Some(&ak), &opts)
+        .unwrap();
+    assert!(stats.errors.is_empty());
+    assert_eq!((stats.files, stats.verified), (2, 2));
+    let mut report = Vec::new();
+    stats.write_report(&mut report).unwrap();
+    let report = String::from_utf8(report).unwrap();
+    assert!(report.ends_with("verify  2 files read back intact\n"));
+
+    let receipt = RestoreReceipt::new(r.config().repo_id, &head.address, &to, &opts, &stats);
+    let signed = receipt.sign(&key.sign_sk);
+    let opened = RestoreReceipt::open(&signed, &key.sign_pk).unwrap();
+    assert_eq!(opened, receipt);
+    assert_eq!(
+        (opened.files, opened.verified, opened.bytes),
+        (2, 2, big.len() as u64 + 5)
+    );
+    assert!(opened.failed.is_empty() && opened.path.is_empty());
+
+    // A file that changed on its way to the disk is caught.
+    let ent = TreeEntry {
+        name: b"small".to_vec(),
+        kind: EntryKind::File,
+        mode: 0o644,
+        uid: 0,
+        gid: 0,
+        mtime: 0,
+        mtime_nsec: 0,
+        size: 5,
+        refs: vec![ak.address(b"hello")],
+        target: Vec::new(),
+        link: 0,
+        xattrs: Vec::new(),
+        device: (0, 0),
+        atime: None,
+        birthtime: None,
+    };
+    let f = File::open(to.join("small")).unwrap();
+    assert!(check_written(&f, &ent, &[5], &ak).is_ok());
+    fs::write(to.join("small"), b"jello").unwrap();
+    assert!(check_written(&f, &ent, &[5], &ak).is_err());
+    fs::write(to.join("small"), b"hello!").unwrap();
+    assert!(check_written(&f, &ent, &[5], &ak).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&to).unwrap();
+}
+
+#[test]
+fn test_restore_owners() {
+    use super::namespace::Namespace;
+    use super::owners;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let dir = super::storage::local::test_dir("restore-owners-src");
+    let to = super::storage::local::test_dir("restore-owners-dst");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("file"), b"mine").unwrap();
+    let meta = fs::metadata(dir.join("file")).unwrap();
+    let (uid, gid) = (meta.uid(), meta.gid());
+    let ns = Namespace::new("laptop").unwrap();
+    let (head, _) = r.backup(&dir, &ns, &ak, &key, &Default::default()).unwrap();
+    let index = r.load_index().unwrap();
+    let snapshot = r.read_snapshot(&index, &key.box_sk, &head.address).unwrap();
+    let named = |id, name: Option<String>| name.map(|n| vec![(id, n)]).unwrap_or_default();
+    assert_eq!(snapshot.users, named(uid, owners::user_name(uid)));
+    assert_eq!(snapshot.groups, named(gid, owners::group_name(gid)));
+
+    // Only root can give files away.
+    let mut opts = RestoreOptions {
+        owners: true,
+        ..Default::default()
+    };
+    opts.owner_map.uids.insert(uid, 4321);
+    opts.owner_map.gids.insert(gid, 4321);
+    let stats = r
+        .restore(&head.address, &to, &key.box_sk, Some(&ak), &opts)
+        .unwrap();
+    if uid == 0 {
+        assert!(stats.errors.is_empty());
+        let meta = fs::metadata(to.join("file")).unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (4321, 4321));
+    } else {
+        assert_eq!(stats.errors.len(), 1);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&to).unwrap();
+}
+
+#[test]
+fn test_restore_overwrite() {
+    use super::namespace::Namespace;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let dir = super::storage::local::test_dir("restore-overwrite-src");
+    let to = super::storage::local::test_dir("restore-overwrite-dst");
+    fs::create_dir_all(dir.join("d")).unwrap();
+    fs::write(dir.join("same"), b"same").unwrap();
+    fs::write(dir.join("changed"), b"snapshot").unwrap();
+    fs::write(dir.join("d/file"), b"file").unwrap();
+    fs::write(dir.join("dir"), b"not a dir").unwrap();
+    symlink("same", dir.join("link")).unwrap();
+    let ns = Namespace::new("laptop").unwrap();
+    let (head, _) = r.backup(&dir, &ns, &ak, &key, &Default::default()).unwrap();
+    r.restore(
+        &head.address,
+        &to,
+        &key.box_sk,
+        Some(&ak),
+        &Default::default(),
+    )
+    .unwrap();
+    // The target has moved on since.
+    fs::write(to.join("changed"), b"local edit").unwrap();
+    fs::remove_file(to.join("d/file")).unwrap();
+    fs::write(to.join("d/local"), b"local").unwrap();
+    fs::remove_file(to.join("dir")).unwrap();
+    fs::create_dir(to.join("dir")).unwrap();
+    fs::remove_file(to.join("link")).unwrap();
+    symlink("/etc", to.join("link")).unwrap();
+
+    let restore = |overwrite, backup_existing| {
+        let opts = RestoreOptions {
+            overwrite: Some(overwrite),
+            backup_existing,
+            ..Default::default()
+        };
+        r.restore(&head.address, &to, &key.box_sk, Some(&ak), &opts)
+            .unwrap()
+    };
+    let decisions = |stats: &RestoreStats| -> Vec<(String, Decision)> {
+        let name = |p: &[u8]| {
+            let p = Path::new(OsStr::from_bytes(p)).strip_prefix(&to).unwrap();
+            p.to_str().unwrap().to_string()
+        };
+        stats
+            .existing
+            .iter()
+            .map(|e| (name(&e.path), e.decision.clone()))
+            .collect()
+    };
+
+    // Only what is missing is restored, the local file is left alone.
+    let stats = restore(Overwrite::Never, true);
+    assert!(stats.errors.is_empty());
+    assert_eq!((stats.files, stats.dirs), (1, 1));
+    let kept: Vec<String> = decisions(&stats).into_iter().map(|(p, _)| p).collect();
+    assert_eq!(kept, vec!["changed", "dir", "link", "same"]);
+    assert_eq!(fs::read(to.join("d/file")).unwrap(), b"file");
+    assert_eq!(fs::read(to.join("d/local")).unwrap(), b"local");
+    assert_eq!(fs::read(to.join("changed")).unwrap(), b"local edit");
+
+    // Unchanged files are kept, a directory is never removed.
+    let stats = restore(Overwrite::IfChanged, false);
+    assert_eq!(
+        decisions(&stats),
+        vec![
+            ("changed".to_string(), Decision::Replaced),
+            ("d/file".to_string(), Decision::Kept),
+            ("link".to_string(), Decision::Replaced),
+            ("same".to_string(), Decision::Kept),
+        ]
+    );
+    assert_eq!(stats.errors.len(), 1);
+    assert_eq!(stats.errors[0].path, to.join("dir").as_os_str().as_bytes());
+    assert_eq!(fs::read(to.join("changed")).unwrap(), b"snapshot");
+    assert_eq!(fs::read_link(to.join("link")).unwrap(), Path::new("same"));
+
+    // What is replaced can be kept aside.
+    fs::write(to.join("same.orig"), b"taken").unwrap();
+    let stats = restore(Overwrite::Always, true);
+    assert!(stats.errors.is_empty());
+    assert_eq!(decisions(&stats).len(), 5);
+    let renamed = |p: &str| Decision::Renamed(to.join(p).into_os_string().into_vec());
+    assert_eq!(
+        decisions(&stats)[2],
+        ("dir".to_string(), renamed("dir.orig"))
+    );
+    assert_eq!(
+        decisions(&stats)[4],
+        ("same".to_string(), renamed("same.orig.1"))
+    );
+    assert_eq!(fs::read(to.join("dir")).unwrap(), b"not a dir");
+    assert!(to.join("dir.orig").is_dir());
+    assert_eq!(fs::read(to.join("changed.orig")).unwrap(), b"snapshot");
+    assert_eq!(fs::read(to.join("same.orig")).unwrap(), b"taken");
+
+    let mut out = Vec::new();
+    stats.write_ndjson(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(out.lines().count(), 6);
+    assert!(out.lines().nth(1).unwrap().starts_with(&format!(
+        "{{\"type\": \"existing\", \"path\": {}, \"decision\": \"renamed\", \"renamed_to\": ",
+        json_path(to.join("changed").as_os_str().as_bytes())
+    )));
+    let mut out = Vec::new();
+    stats.write_report(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.ends_with("existing 0 kept, 0 replaced, 5 renamed\n"));
+    let mut out = Vec::new();
+    stats.write_existing(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with(&format!(
+        "renamed   {0}/changed -> {0}/changed.orig\n",
+        to.display()
+    )));
+    assert_eq!(Overwrite::parse("if-changed"), Some(Overwrite::IfChanged));
+    assert_eq!(Overwrite::parse("sometimes"), None);
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&to).unwrap();
+}
+
+#[test]
+fn test_cat_and_tar() {
+    use super::namespace::Namespace;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let dir = super::storage::local::test_dir("restore-tar-src");
+    let big = super::chunker::test_data(3 << 20, 5);
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/big"), &big).unwrap();
+    fs::write(dir.join("small"), b"hello").unwrap();
+    symlink("sub/big", dir.join("link")).unwrap();
+    let ns = Namespace::new("laptop").unwrap();
+    let (head, _) = r.backup(&dir, &ns, &ak, &key, &Default::default()).unwrap();
+
+    let mut out = Vec::new();
+    let n = r
+        .cat(
+            &head.address,
+            Path::new("sub/big"),
+            &mut out,
+            &key.box_sk,
+            Some(&ak),
+        )
+        .unwrap();
+    assert_eq!(n, big.len() as u64);
+    assert_eq!(out, big);
+    for path in ["sub", "link"].iter() {
+        match r.cat(&head.address, Path::new(path), &mut out, &key.box_sk, None) {
+            Err(RepoError::IOError(ref e)) if e.kind() == io::ErrorKind::InvalidInput => (),
+            _ => panic!("expected {} to be refused", path),
+        }
+    }
+
+    let mut out = Vec::new();
+    let stats = r
+        .restore_tar(
+            &head.address,
+            &mut out,
+            &key.box_sk,
+            Some(&ak),
+            &Default::default(),
+        )
+        .unwrap();
+    assert!(stats.errors.is_empty());
+    assert_eq!((stats.files, stats.dirs, stats.symlinks), (2, 1, 1));
+    assert_eq!(
+        tar::test_read_tar(&out),
+        vec![
+            (b"link".to_vec(), b'2', b"sub/big".to_vec()),
+            (b"small".to_vec(), b'0', b"hello".to_vec()),
+            (b"sub/".to_vec(), b'5', Vec::new()),
+            (b"sub/big".to_vec(), b'0', big.clone()),
+        ]
+    );
+
+    // A damaged chunk leaves its file zero filled and the stream intact.
+    let index = r.load_index().unwrap();
+    let loc = index.lookup(&ak.address(b"hello")).unwrap();
+    let pack_key = format!("{}/{}", super::PACKS_DIR, loc.pack_id.to_hex());
+    let mut buf = r.storage().get(&pack_key).unwrap();
+    buf[loc.offset as usize + 30] ^= 1;
+    r.storage().put(&pack_key, &buf).unwrap();
+    let opts = RestoreOptions {
+        path: Some(PathBuf::from("small")),
+        ..Default::default()
+    };
+    let mut out = Vec::new();
+    let stats = r
+        .restore_tar(&head.address, &mut out, &key.box_sk, Some(&ak), &opts)
+        .unwrap();
+    assert_eq!((stats.files, stats.errors.len()), (0, 1));
+    assert_eq!(
+        tar::test_read_tar(&out),
+        vec![(b"small".to_vec(), b'0', vec![0; 5])]
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_restore_hostile_paths() {
+    use super::namespace::Namespace;
+    use std::os::unix::ffi::OsStrExt;
+    assert!(escapes(b"/etc/passwd", 3));
+    assert!(escapes(b"../../x", 1));
+    assert!(escapes(b"a/../x", 1));
+    assert!(!escapes(b"a/./b/", 0));
+    assert!(!escapes(b"../x", 1));
+    assert!(!escapes(b"./../../x", 2));
+
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let dir = super::storage::local::test_dir("restore-hostile-src");
+    let to = super::storage::local::test_dir("restore-hostile-dst");
+    let tr = super::storage::local::test_dir("restore-hostile-contained");
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    let odd = OsStr::from_bytes(b"new\nline\xff\x1b[2J");
+    fs::write(dir.join(odd), b"odd").unwrap();
+    symlink("/etc/passwd", dir.join("abs")).unwrap();
+    symlink("a/b", dir.join("in")).unwrap();
+    symlink("../in", dir.join("sub/up")).unwrap();
+    symlink("../../x", dir.join("sub/out")).unwrap();
+    symlink("a/../../x", dir.join("sub/mid")).unwrap();
+    let ns = Namespace::new("laptop").unwrap();
+    let (head, _) = r.backup(&dir, &ns, &ak, &key, &Default::default()).unwrap();
+
+    // Names come back byte for byte, and symlinks as they were.
+    let stats = r
+        .restore(
+            &head.address,
+            &to,
+            &key.box_sk,
+            Some(&ak),
+            &Default::default(),
+        )
+        .unwrap();
+    assert!(stats.errors.is_empty(), "{:?}", stats.errors);
+    assert_eq!(fs::read(to.join(odd)).unwrap(), b"odd");
+    assert_eq!(stats.symlinks, 5);
+
+    let opts = RestoreOptions {
+        contain_symlinks: true,
+        ..Default::default()
+    };
+    let stats = r
+        .restore(&head.address, &tr, &key.box_sk, Some(&ak), &opts)
+        .unwrap();
+    assert_eq!(stats.symlinks, 2);
+    let errors: Vec<&[u8]> = stats.errors.iter().map(|e| &e.path[..]).collect();
+    let expected = [tr.join("abs"), tr.join("sub/mid"), tr.join("sub/out")];
+    let expected: Vec<&[u8]> = expected.iter().map(|p| p.as_os_str().as_bytes()).collect();
+    assert_eq!(errors, expected);
+    assert!(fs::symlink_metadata(tr.join("sub/out")).is_err());
+    assert_eq!(
+        fs::read_link(tr.join("sub/up")).unwrap(),
+        Path::new("../in")
+    );
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&to).unwrap();
+    fs::remove_dir_all(&tr).unwrap();
+}
+
+#[test]
+fn test_restore_resume() {
+    use super::namespace::Namespace;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let dir = super::storage::local::test_dir("restore-resume-src");
+    let to = super::storage::local::test_dir("restore-resume-dst");
+    let journal = super::storage::local::test_dir("restore-resume-journal");
+    let big = super::chunker::test_data(3 << 20, 8);
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/big"), &big).unwrap();
+    fs::write(dir.join("small"), b"hello").unwrap();
+    let ns = Namespace::new("laptop").unwrap();
+    let (head, _) = r.backup(&dir, &ns, &ak, &key, &Default::default()).unwrap();
+
+    // Interrupted by a damaged chunk, the journal keeps what was done.
+    let index = r.load_index().unwrap();
+    let loc = index.lookup(&ak.address(b"hello")).unwrap();
+    let pack_key = format!("{}/{}", super::PACKS_DIR, loc.pack_id.to_hex());
+    let good = r.storage().get(&pack_key).unwrap();
+    let mut bad = good.clone();
+    bad[loc.offset as usize + 30] ^= 1;
+    r.storage().put(&pack_key, &bad).unwrap();
+    let opts = RestoreOptions {
+        journal: Some(journal.clone()),
+        ..Default::default()
+    };
+    let stats = r
+        .restore(&head.address, &to, &key.box_sk, Some(&ak), &opts)
+        .unwrap();
+    assert_eq!((stats.files, stats.errors.len(), stats.resumed), (1, 1, 0));
+    let j = Journal::open(&journal, &head.address, b"").unwrap();
+    assert!(j.is_done(b"sub/big", false) && !j.is_done(b"small", false));
+    drop(j);
+
+    // A restore of another part does not take it.
+    let part = RestoreOptions {
+        path: Some(PathBuf::from("sub")),
+        ..opts.clone()
+    };
+    match r.restore(&head.address, &to, &key.box_sk, Some(&ak), &part) {
+        Err(RepoError::IOError(ref e)) if e.kind() == io::ErrorKind::InvalidInput => (),
+        _ => panic!("expected the journal to be refused"),
+    }
+
+    r.storage().put(&pack_key, &good).unwrap();
+    let stats = r
+        .restore(&head.address, &to, &key.box_sk, Some(&ak), &opts)
+        .unwrap();
+    assert!(stats.errors.is_empty());
+    assert_eq!((stats.files, stats.resumed), (1, 1));
+    assert!(stats.existing.is_empty());
+    let mut report = Vec::new();
+    stats.write_report(&mut report).unwrap();
+    let report = String::from_utf8(report).unwrap();
+    assert!(report.ends_with("resume  1 files restored by an earlier run\n"));
+    assert_eq!(fs::read(to.join("small")).unwrap(), b"hello");
+    assert_eq!(fs::read(to.join("sub/big")).unwrap(), big);
+    assert!(!journal.exists());
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&to).unwrap();
+}
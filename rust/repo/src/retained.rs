@@ -0,0 +1,248 @@
+//! Packs held by retention locks.
+//!
+//! A retention lock covers a snapshot, see `manifest`, but a server
+//! holding no keys cannot tell which encrypted packs the snapshot needs.
+//! `Repo::retain_snapshot` marks the snapshot as `gc` does and, before it
+//! commits the lock, stores the packs holding its objects at `retained`
+//! with the lock's expiry, signed by the owner. Until the lock expires
+//! `serve` refuses to delete or overwrite those packs, their indexes and
+//! parity, and a sweep passes over them, while everything else may be
+//! deleted as usual. A head locked in the manifest without an entry
+//! lasting as long as its lock makes the whole repository append only, as
+//! nothing is known of what it needs.
+//!
+//! A replacement must keep every entry that has not expired, with no
+//! earlier expiry and every pack still listed, and `serve` accepts no
+//! other, so holders of the owner key cannot lift the protection while a
+//! lock holds. Expired entries may be dropped.
+//!
+//! Format (inside the signature envelope, see `signed`):
+//!
+//! ```text
+//! "PNBRETAINED" u16:format_version [16]:repo_id
+//! u32:n n * ([32]:snapshot u64:retain_until u32:n_packs n_packs * [16]:pack_id)
+//! ```
+//!
+//! Entries are sorted by snapshot address, pack ids are strictly
+//! ascending.
+
+use super::address::Address;
+use super::manifest::{Manifest, RepoId};
+use super::pack::{PackId, PACK_ID_SZ};
+use super::signed;
+use super::wire::{Decoder, Encoder};
+use super::{Repo, RepoError, RETAINED_FILE};
+use tweetnacl::*;
+
+pub const RETAINED_FORMAT_VERSION: u16 = 1;
+const RETAINED_MAGIC: &[u8] = b"PNBRETAINED";
+
+// Smallest encoded entry, without packs.
+const MIN_ENTRY_SZ: usize = 44;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RetainedSnapshot {
+    pub snapshot: Address,
+    pub retain_until: u64,
+    // Sorted.
+    pub packs: Vec<PackId>,
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Retained {
+    pub repo_id: RepoId,
+    // Sorted by snapshot.
+    pub entries: Vec<RetainedSnapshot>,
+}
+
+impl Retained {
+    pub fn entry(&self, snapshot: &Address) -> Option<&RetainedSnapshot> {
+        self.entries.iter().find(|e| e.snapshot == *snapshot)
+    }
+
+    // Hold `packs` for `snapshot` until `until`, keeping what was held
+    // already.
+    pub fn retain(&mut self, snapshot: &Address, until: u64, packs: Vec<PackId>) {
+        let entry = match self.entries.iter_mut().find(|e| e.snapshot == *snapshot) {
+            Some(entry) => entry,
+            None => {
+                self.entries.push(RetainedSnapshot {
+                    snapshot: *snapshot,
+                    retain_until: 0,
+                    packs: Vec::new(),
+                });
+                self.entries.last_mut().unwrap()
+            }
+        };
+        entry.retain_until = entry.retain_until.max(until);
+        entry.packs.extend(packs);
+        entry.packs.sort();
+        entry.packs.dedup();
+        self.entries.sort_by_key(|e| e.snapshot);
+    }
+
+    // Drop the entries expired at `now`.
+    pub fn expire(&mut self, now: u64) {
+        self.entries.retain(|e| e.retain_until > now);
+    }
+
+    // Whether a lock holds pack `id` at `now`.
+    pub fn holds(&self, id: &PackId, now: u64) -> bool {
+        self.entries
+            .iter()
+            .any(|e| e.retain_until > now && e.packs.binary_search(id).is_ok())
+    }
+
+    // Whether every head of `m` locked at `now` has an entry lasting as
+    // long as its lock.
+    pub fn covers(&self, m: &Manifest, now: u64) -> bool {
+        m.heads.iter().filter(|h| h.is_retained(now)).all(|h| {
+            self.entry(&h.address)
+                .is_some_and(|e| e.retain_until >= h.retain_until)
+        })
+    }
+
+    // Check that `new` may replace these entries at `now`: nothing that
+    // has not expired shortened or dropped.
+    pub fn check_replacement(&self, new: &Retained, now: u64) -> Result<(), RepoError> {
+        for e in self.entries.iter().filter(|e| e.retain_until > now) {
+            let kept = new.entry(&e.snapshot).is_some_and(|n| {
+                n.retain_until >= e.retain_until
+                    && e.packs.iter().all(|id| n.packs.binary_search(id).is_ok())
+            });
+            if !kept {
+                return Err(RepoError::RetentionLockedError);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.fixed(RETAINED_MAGIC)
+            .u16(RETAINED_FORMAT_VERSION)
+            .fixed(&self.repo_id.bytes)
+            .u32(self.entries.len() as u32);
+        for entry in self.entries.iter() {
+            e.fixed(&entry.snapshot.bytes)
+                .u64(entry.retain_until)
+                .u32(entry.packs.len() as u32);
+            for id in entry.packs.iter() {
+                e.fixed(&id.bytes);
+            }
+        }
+        e.into_vec()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Retained, RepoError> {
+        let mut d = Decoder::new(buf);
+        if d.fixed(RETAINED_MAGIC.len())? != RETAINED_MAGIC {
+            return Err(RepoError::InvalidDataError);
+        }
+        if d.u16()? != RETAINED_FORMAT_VERSION {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        let mut repo_id: RepoId = Default::default();
+        d.fixed_into(&mut repo_id.bytes)?;
+        let n = d.count(MIN_ENTRY_SZ)?;
+        let mut entries: Vec<RetainedSnapshot> = Vec::with_capacity(n);
+        for _ in 0..n {
+            let snapshot = Address::decode(&mut d)?;
+            if entries.last().is_some_and(|last| last.snapshot >= snapshot) {
+                return Err(RepoError::InvalidDataError);
+            }
+            let retain_until = d.u64()?;
+            let n_packs = d.count(PACK_ID_SZ)?;
+            let mut packs: Vec<PackId> = Vec::with_capacity(n_packs);
+            for _ in 0..n_packs {
+                let mut id: PackId = Default::default();
+                d.fixed_into(&mut id.bytes)?;
+                if packs.last().is_some_and(|last| *last >= id) {
+                    return Err(RepoError::InvalidDataError);
+                }
+                packs.push(id);
+            }
+            entries.push(RetainedSnapshot {
+                snapshot,
+                retain_until,
+                packs,
+            });
+        }
+        d.finish()?;
+        Ok(Retained { repo_id, entries })
+    }
+
+    pub fn sign(&self, sk: &CryptoSignSk) -> Vec<u8> {
+        signed::sign(&self.encode(), sk)
+    }
+
+    pub fn open(sm: &[u8], pk: &CryptoSignPk) -> Result<Retained, RepoError> {
+        Retained::decode(&signed::open(sm, pk)?)
+    }
+}
+
+impl Repo {
+    // Verify signed retained packs as fetched from this repository.
+    pub fn open_retained(&self, sm: &[u8]) -> Result<Retained, RepoError> {
+        let retained = Retained::open(sm, &self.owner().sign_pk)?;
+        if retained.repo_id != self.config().repo_id {
+            return Err(RepoError::RepoMismatchError);
+        }
+        Ok(retained)
+    }
+
+    // The stored retained packs, None if nothing was ever locked.
+    pub fn retained(&self) -> Result<Option<Retained>, RepoError> {
+        match self.storage().get(RETAINED_FILE) {
+            Ok(sm) => Ok(Some(self.open_retained(&sm)?)),
+            Err(ref e) if e.is_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_retained() {
+    let (pk, sk) = boxed_crypto_sign_keypair();
+    let (a, b, c) = (PackId::new(), PackId::new(), PackId::new());
+    let s1 = Address { bytes: [1; 32] };
+    let s2 = Address { bytes: [2; 32] };
+    let mut r = Retained {
+        repo_id: RepoId::new(),
+        ..Default::default()
+    };
+    r.retain(&s2, 200, vec![b, a]);
+    r.retain(&s1, 100, vec![c]);
+    r.retain(&s2, 150, vec![c]);
+    assert_eq!(r.entries[0].snapshot, s1);
+    assert_eq!(r.entry(&s2).unwrap().retain_until, 200);
+    assert_eq!(r.entry(&s2).unwrap().packs.len(), 3);
+    assert_eq!(Retained::open(&r.sign(&sk), &pk).unwrap(), r);
+    assert!(r.holds(&a, 150) && !r.holds(&a, 200) && !r.holds(&PackId::new(), 0));
+
+    // Nothing held may be dropped or shortened until it expires.
+    let mut dropped = r.clone();
+    dropped.entries.remove(0);
+    let mut shortened = r.clone();
+    shortened.entries[1].retain_until = 199;
+    let mut fewer = r.clone();
+    fewer.entries[1].packs.pop();
+    for new in [dropped.clone(), shortened, fewer].iter() {
+        match r.check_replacement(new, 50) {
+            Err(RepoError::RetentionLockedError) => (),
+            _ => panic!("expected the replacement to be refused"),
+        }
+    }
+    r.check_replacement(&dropped, 100).unwrap();
+    let mut expired = r.clone();
+    expired.expire(100);
+    assert_eq!(expired, dropped);
+
+    let buf = r.encode();
+    assert!(Retained::decode(&buf[..buf.len() - 1]).is_err());
+    let mut unsorted = r.clone();
+    unsorted.entries.reverse();
+    assert!(Retained::decode(&unsorted.encode()).is_err());
+}
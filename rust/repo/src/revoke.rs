@@ -0,0 +1,282 @@
+//! Revoking client keys.
+//!
+//! A client that authenticates to `serve` does so with the key the access
+//! list gives it, see `acl`, and registers that key in the manifest as the
+//! writer of its namespace, see `manifest` and `Repo::register_writer`.
+//! When a host is lost the holder of the maintenance key publishes a
+//! revocation of its key with `Repo::revoke_key`. From then on `serve` refuses every put, delete and
+//! sweep of a session whose client has that key, whichever client of the
+//! repository it is, without changing the access list or any other client.
+//! Locks may still be taken, and whatever the access list allows may still
+//! be read.
+//!
+//! Snapshots the key made stay where they are, nothing is deleted by a
+//! revocation. `Repo::list_snapshots` marks those in the namespace of a
+//! revoked writer, see `list`, so they can be looked at before they are
+//! trusted.
+//!
+//! Like the policy and the access list the revocations are signed by the
+//! maintenance key named in the config, not the owner key every writer
+//! holds, so a writer cannot revoke the others. They are stored whole at
+//! `revocations`, which, like the manifest, may be replaced in append only
+//! mode. A replacement must have a higher serial and keep every revocation
+//! of the one it replaces, and `serve` accepts no other, so a revoked key
+//! cannot be reinstated by anyone, the maintenance key included.
+//!
+//! Format (inside the signature envelope, see `signed`):
+//!
+//! ```text
+//! "PNBREVOKE" u16:format_version [16]:repo_id u64:serial
+//! u32:n n * ([32]:sign_pk u64:unix_time str:reason)
+//! ```
+
+use super::config::RepoConfig;
+use super::datetime::unix_now;
+use super::manifest::RepoId;
+use super::namespace::Namespace;
+use super::signed;
+use super::wire::{Decoder, Encoder};
+use super::{Repo, RepoError, REVOCATIONS_FILE};
+use asymcrypt::Key;
+use tweetnacl::*;
+
+pub const REVOCATIONS_FORMAT_VERSION: u16 = 1;
+const REVOCATIONS_MAGIC: &[u8] = b"PNBREVOKE";
+
+// Smallest encoded revocation, with an empty reason.
+const MIN_REVOCATION_SZ: usize = 44;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Revocation {
+    pub sign_pk: CryptoSignPk,
+    pub time: u64,
+    pub reason: String,
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Revocations {
+    pub repo_id: RepoId,
+    pub serial: u64,
+    pub revoked: Vec<Revocation>,
+}
+
+impl Revocations {
+    pub fn revocation(&self, sign_pk: &CryptoSignPk) -> Option<&Revocation> {
+        self.revoked.iter().find(|r| r.sign_pk == *sign_pk)
+    }
+
+    pub fn is_revoked(&self, sign_pk: &CryptoSignPk) -> bool {
+        self.revocation(sign_pk).is_some()
+    }
+
+    // Check that `new` may replace these revocations: a higher serial and
+    // nothing revoked dropped.
+    pub fn check_replacement(&self, new: &Revocations) -> Result<(), RepoError> {
+        if new.serial <= self.serial || self.revoked.iter().any(|r| !new.is_revoked(&r.sign_pk)) {
+            return Err(RepoError::PermissionDeniedError);
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.fixed(REVOCATIONS_MAGIC)
+            .u16(REVOCATIONS_FORMAT_VERSION)
+            .fixed(&self.repo_id.bytes)
+            .u64(self.serial)
+            .u32(self.revoked.len() as u32);
+        for r in self.revoked.iter() {
+            e.fixed(&r.sign_pk.bytes).u64(r.time).str(&r.reason);
+        }
+        e.into_vec()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Revocations, RepoError> {
+        let mut d = Decoder::new(buf);
+        if d.fixed(REVOCATIONS_MAGIC.len())? != REVOCATIONS_MAGIC {
+            return Err(RepoError::InvalidDataError);
+        }
+        if d.u16()? != REVOCATIONS_FORMAT_VERSION {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        let mut repo_id: RepoId = Default::default();
+        d.fixed_into(&mut repo_id.bytes)?;
+        let serial = d.u64()?;
+        let n = d.count(MIN_REVOCATION_SZ)?;
+        let mut revoked = Vec::with_capacity(n);
+        for _ in 0..n {
+            let mut sign_pk: CryptoSignPk = Default::default();
+            d.fixed_into(&mut sign_pk.bytes)?;
+            revoked.push(Revocation {
+                sign_pk,
+                time: d.u64()?,
+                reason: d.str()?.to_string(),
+            });
+        }
+        d.finish()?;
+        Ok(Revocations {
+            repo_id,
+            serial,
+            revoked,
+        })
+    }
+
+    pub fn sign(&self, sk: &CryptoSignSk) -> Vec<u8> {
+        signed::sign(&self.encode(), sk)
+    }
+
+    pub fn open(sm: &[u8], pk: &CryptoSignPk) -> Result<Revocations, RepoError> {
+        Revocations::decode(&signed::open(sm, pk)?)
+    }
+
+    // Open stored revocations for the repository described by `config`.
+    pub fn open_for(sm: &[u8], config: &RepoConfig) -> Result<Revocations, RepoError> {
+        let pk = match config.maintenance_pk {
+            Some(ref pk) => pk,
+            None => return Err(RepoError::SignatureFailedError),
+        };
+        let revocations = Revocations::open(sm, pk)?;
+        if revocations.repo_id != config.repo_id {
+            return Err(RepoError::RepoMismatchError);
+        }
+        Ok(revocations)
+    }
+}
+
+impl Repo {
+    // Verify signed revocations as fetched from this repository.
+    pub fn open_revocations(&self, sm: &[u8]) -> Result<Revocations, RepoError> {
+        Revocations::open_for(sm, self.config())
+    }
+
+    // The stored revocations, None if no key was ever revoked.
+    pub fn revocations(&self) -> Result<Option<Revocations>, RepoError> {
+        match self.storage().get(REVOCATIONS_FILE) {
+            Ok(sm) => Ok(Some(self.open_revocations(&sm)?)),
+            Err(ref e) if e.is_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Revoke the client key `sign_pk`, see `revoke`. Revoking a key twice
+    // keeps the first revocation. Only the maintenance key named in the
+    // config can sign revocations.
+    pub fn revoke_key(
+        &self,
+        sign_pk: &CryptoSignPk,
+        reason: &str,
+        maintenance: &Key,
+    ) -> Result<Revocations, RepoError> {
+        let mut revocations = self.revocations()?.unwrap_or_else(|| Revocations {
+            repo_id: self.config().repo_id,
+            ..Default::default()
+        });
+        if revocations.is_revoked(sign_pk) {
+            return Ok(revocations);
+        }
+        revocations.serial += 1;
+        revocations.revoked.push(Revocation {
+            sign_pk: sign_pk.clone(),
+            time: unix_now(),
+            reason: reason.to_string(),
+        });
+        let sm = revocations.sign(&maintenance.sign_sk);
+        // Refuse to store revocations nobody will accept.
+        self.open_revocations(&sm)?;
+        self.storage().put(REVOCATIONS_FILE, &sm)?;
+        Ok(revocations)
+    }
+
+    // Record in the manifest that snapshots in `namespace` are made by
+    // the client with key `sign_pk`.
+    pub fn register_writer(
+        &self,
+        sign_pk: &CryptoSignPk,
+        namespace: &Namespace,
+        key: &Key,
+    ) -> Result<(), RepoError> {
+        let mut m = self.manifest()?;
+        m.add_writer(sign_pk.clone(), namespace.clone())?;
+        self.commit_manifest(&m, key)
+    }
+
+    // The namespaces of writers whose key is revoked.
+    pub fn revoked_namespaces(&self) -> Result<Vec<Namespace>, RepoError> {
+        let revocations = match self.revocations()? {
+            Some(revocations) => revocations,
+            None => return Ok(Vec::new()),
+        };
+        Ok(self
+            .manifest()?
+            .writers
+            .into_iter()
+            .filter(|w| revocations.is_revoked(&w.sign_pk))
+            .map(|w| w.namespace)
+            .collect())
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_revocations() {
+    let key = Key::new();
+    let maintenance = Key::new();
+    let config = RepoConfig {
+        maintenance_pk: Some(maintenance.sign_pk.clone()),
+        ..Default::default()
+    };
+    let storage = std::sync::Arc::new(super::storage::mem::MemStorage::new());
+    let r = Repo::init(storage, config, &key).unwrap();
+    assert_eq!(r.revocations().unwrap(), None);
+    let (pk, _) = boxed_crypto_sign_keypair();
+    let (other, _) = boxed_crypto_sign_keypair();
+    let laptop = Namespace::new("laptop").unwrap();
+    r.register_writer(&pk, &laptop, &key).unwrap();
+    assert!(r.revoked_namespaces().unwrap().is_empty());
+
+    // Writers hold the owner key, it cannot revoke anything.
+    assert!(r.revoke_key(&pk, "stolen", &key).is_err());
+    assert_eq!(r.revocations().unwrap(), None);
+    let first = r.revoke_key(&pk, "stolen", &maintenance).unwrap();
+    assert_eq!(r.revoke_key(&pk, "again", &maintenance).unwrap(), first);
+    let second = r.revoke_key(&other, "", &maintenance).unwrap();
+    assert_eq!(second.serial, 2);
+    assert_eq!(r.revocations().unwrap().unwrap(), second);
+    assert_eq!(second.revocation(&pk).unwrap().reason, "stolen");
+    assert_eq!(r.revoked_namespaces().unwrap(), vec![laptop]);
+
+    // Revocations only ever grow.
+    first.check_replacement(&second).unwrap();
+    for new in [
+        first.clone(),
+        Revocations {
+            serial: 3,
+            ..first.clone()
+        },
+    ]
+    .iter()
+    {
+        match second.check_replacement(new) {
+            Err(RepoError::PermissionDeniedError) => (),
+            _ => panic!("expected the replacement to be refused"),
+        }
+    }
+    match r.open_revocations(&second.sign(&key.sign_sk)) {
+        Err(RepoError::SignatureFailedError) => (),
+        _ => panic!("expected revocations signed by the owner key to be refused"),
+    }
+    let sm = second.sign(&maintenance.sign_sk);
+    let (elsewhere, _) = super::test_repo();
+    match elsewhere.open_revocations(&sm) {
+        Err(RepoError::SignatureFailedError) | Err(RepoError::RepoMismatchError) => (),
+        _ => panic!("expected another repository's revocations to be refused"),
+    }
+    assert!(Revocations::decode(&second.encode()[..60]).is_err());
+
+    // Snapshots of the revoked key are kept, and listed as such.
+    super::gc::test_commit_tree(&r, &key, 1, &[]);
+    let listed = r.list_snapshots(&key.box_sk, &Default::default()).unwrap();
+    assert_eq!(listed.len(), 1);
+    assert!(listed[0].revoked);
+}
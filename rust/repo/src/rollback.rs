@@ -0,0 +1,229 @@
+//! Refusing a repository rolled back to an older copy.
+//!
+//! Every manifest in an old copy of a repository is validly signed, so a
+//! storage provider, or anyone in between, can serve one and hide every
+//! snapshot since without breaking a signature. Manifests carry a counter
+//! that each commit increases and the hash of the manifest they replace,
+//! see `manifest`, and a client given a state file, `Repo::track_manifests`,
+//! remembers there the newest manifest it has seen of the repository. A
+//! manifest is refused with `RollbackError` if it
+//!
+//! - has a lower counter than the one remembered,
+//! - has the same counter but is another manifest, or
+//! - has the next counter but does not name the remembered one as the
+//!   manifest it replaced, the history forked.
+//!
+//! The tree head of the newest manifest is remembered too, and a newer
+//! manifest is refused unless its log is consistent with it, see
+//! `translog`, so no manifest in between can have been left out or
+//! replaced either.
+//!
+//! A newer manifest is remembered as soon as it is read or committed. A
+//! rollback to a copy the client never saw the end of goes unnoticed, so
+//! the state file should live as long as the client uses the repository,
+//! next to its index cache say, and not on the storage it protects.
+//!
+//! Format:
+//!
+//! ```text
+//! "PNBSEEN" u16:format_version [16]:repo_id u64:counter [32]:manifest_hash
+//! u64:log_size [32]:log_root
+//! ```
+//!
+//! The file is replaced whole, through a temporary file renamed over it.
+
+use super::manifest::{Manifest, ManifestHash, RepoId, MANIFEST_HASH_SZ};
+use super::wire::{Decoder, Encoder};
+use super::{Repo, RepoError};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub const SEEN_FORMAT_VERSION: u16 = 1;
+const SEEN_MAGIC: &[u8] = b"PNBSEEN";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Seen {
+    pub counter: u64,
+    pub hash: ManifestHash,
+    // The tree head of the manifest, see `translog`.
+    pub log_size: u64,
+    pub log_root: ManifestHash,
+}
+
+fn encode_seen(repo_id: &RepoId, seen: &Seen) -> Vec<u8> {
+    let mut e = Encoder::new();
+    e.fixed(SEEN_MAGIC)
+        .u16(SEEN_FORMAT_VERSION)
+        .fixed(&repo_id.bytes)
+        .u64(seen.counter)
+        .fixed(&seen.hash)
+        .u64(seen.log_size)
+        .fixed(&seen.log_root);
+    e.into_vec()
+}
+
+fn decode_seen(buf: &[u8]) -> Result<(RepoId, Seen), RepoError> {
+    let mut d = Decoder::new(buf);
+    if d.fixed(SEEN_MAGIC.len())? != SEEN_MAGIC {
+        return Err(RepoError::InvalidDataError);
+    }
+    if d.u16()? != SEEN_FORMAT_VERSION {
+        return Err(RepoError::UnsupportedVersionError);
+    }
+    let mut repo_id: RepoId = Default::default();
+    d.fixed_into(&mut repo_id.bytes)?;
+    let mut seen = Seen {
+        counter: d.u64()?,
+        hash: [0; MANIFEST_HASH_SZ],
+        log_size: 0,
+        log_root: [0; MANIFEST_HASH_SZ],
+    };
+    d.fixed_into(&mut seen.hash)?;
+    seen.log_size = d.u64()?;
+    d.fixed_into(&mut seen.log_root)?;
+    d.finish()?;
+    Ok((repo_id, seen))
+}
+
+pub struct ManifestTracker {
+    path: PathBuf,
+    repo_id: RepoId,
+    seen: Mutex<Option<Seen>>,
+}
+
+impl ManifestTracker {
+    // Track the manifests of `repo_id` in the state file at `path`, which
+    // is created once a manifest is seen.
+    pub fn open(path: &Path, repo_id: &RepoId) -> Result<ManifestTracker, RepoError> {
+        let seen = match fs::read(path) {
+            Ok(buf) => {
+                let (id, seen) = decode_seen(&buf)?;
+                if id != *repo_id {
+                    return Err(RepoError::RepoMismatchError);
+                }
+                Some(seen)
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(ManifestTracker {
+            path: path.to_path_buf(),
+            repo_id: *repo_id,
+            seen: Mutex::new(seen),
+        })
+    }
+
+    // The newest manifest seen, None before any.
+    pub fn seen(&self) -> Option<Seen> {
+        *self.seen.lock().unwrap()
+    }
+
+    // Refuse `m`, whose signed form hashes to `hash`, if it is older than
+    // the newest manifest seen, and remember it if it is newer.
+    pub fn observe(&self, m: &Manifest, hash: &ManifestHash) -> Result<(), RepoError> {
+        let mut seen = self.seen.lock().unwrap();
+        if let Some(ref s) = *seen {
+            if m.counter < s.counter
+                || (m.counter == s.counter && *hash != s.hash)
+                || (m.counter == s.counter + 1 && m.previous != s.hash)
+            {
+                return Err(RepoError::RollbackError);
+            }
+            if m.counter == s.counter {
+                return Ok(());
+            }
+        }
+        let next = Seen {
+            counter: m.counter,
+            hash: *hash,
+            log_size: m.log_size,
+            log_root: m.log_root,
+        };
+        self.write(&next)?;
+        *seen = Some(next);
+        Ok(())
+    }
+
+    fn write(&self, seen: &Seen) -> Result<(), RepoError> {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        let tmp = self.path.with_file_name(name);
+        let mut f = File::create(&tmp)?;
+        f.write_all(&encode_seen(&self.repo_id, seen))?;
+        f.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+impl Repo {
+    // From now on refuse manifests older than the newest one recorded in
+    // the state file at `path`, see `rollback`, starting with the current
+    // one.
+    pub fn track_manifests(&mut self, path: &Path) -> Result<(), RepoError> {
+        self.tracker = Some(Arc::new(ManifestTracker::open(
+            path,
+            &self.config().repo_id,
+        )?));
+        match self.manifest() {
+            Err(ref e) if e.is_not_found() => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_rollback() {
+    use super::address::Address;
+    let dir = super::storage::local::test_dir("rollback");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("seen");
+    let (mut r, key) = super::test_repo();
+    r.track_manifests(&path).unwrap();
+    let first = r.storage().get(super::MANIFEST_FILE).unwrap();
+    assert_eq!(r.manifest().unwrap().counter, 1);
+
+    let chunk = Address { bytes: [1; 32] };
+    super::gc::test_commit_tree(&r, &key, 1, &[chunk]);
+    let m = r.manifest().unwrap();
+    assert_eq!(m.counter, 2);
+    assert_eq!(m.previous, super::manifest::manifest_hash(&first));
+    let tracker = ManifestTracker::open(&path, &r.config().repo_id).unwrap();
+    assert_eq!(tracker.seen().unwrap().counter, 2);
+
+    // The storage serves the old manifest again, every signature valid.
+    let second = r.storage().get(super::MANIFEST_FILE).unwrap();
+    r.storage().put(super::MANIFEST_FILE, &first).unwrap();
+    match r.manifest() {
+        Err(RepoError::RollbackError) => (),
+        _ => panic!("expected the rollback to be refused"),
+    }
+    // A client that never saw the newer one cannot tell.
+    let mut other = Repo::open(r.storage().clone(), r.owner()).unwrap();
+    other.track_manifests(&dir.join("other")).unwrap();
+
+    // Nor is a fork with the same counter accepted.
+    let mut forked = m.clone();
+    forked.heads.clear();
+    r.storage()
+        .put(super::MANIFEST_FILE, &forked.sign(&key.sign_sk))
+        .unwrap();
+    match r.manifest() {
+        Err(RepoError::RollbackError) => (),
+        _ => panic!("expected the fork to be refused"),
+    }
+    r.storage().put(super::MANIFEST_FILE, &second).unwrap();
+    r.manifest().unwrap();
+
+    // A state file is of one repository.
+    let (elsewhere, _) = super::test_repo();
+    match ManifestTracker::open(&path, &elsewhere.config().repo_id) {
+        Err(RepoError::RepoMismatchError) => (),
+        _ => panic!("expected another repository's state to be refused"),
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,274 @@
+//! Rotating the owner box key.
+//!
+//! Every pack and loose object is sealed with a random data key, which is
+//! stored wrapped to the owner box key, see `pack` and `loose`. A leaked or
+//! aging box key is replaced by `Repo::rotate_key` without sealing anything
+//! again: each data key is unwrapped with the old key and wrapped to the
+//! new one in place. The wrapped key has the same size, so pack offsets,
+//! indexes and object addresses stay as they are, and readers holding
+//! either key see a complete pack throughout. The parity of a re-wrapped
+//! pack is generated again, see `parity`. The signing key is what clients
+//! know the repository by and is kept, `rotated_key` makes the new key.
+//!
+//! Rotation goes in steps, each safe to interrupt and run again:
+//!
+//! - `keys/owner.pub` is replaced with the new public key, and the old box
+//!   key is listed in the manifest as retiring, see `manifest`. From then
+//!   on a host still sealing to the old key cannot commit,
+//!   `RetiredKeyError`, and has to read the new public key.
+//! - Every pack and loose object whose owner copy of the data key is still
+//!   wrapped to the old key is re-wrapped, passes are made until one finds
+//!   nothing left to do, so objects stored by such hosts before they
+//!   noticed are caught too.
+//! - Once no object depends on the old key it is marked retired, and the
+//!   old secret key can be destroyed.
+//!
+//! Packs in cold storage cannot be read and are left for a later run once
+//! thawed, see `cold`, the old key then stays retiring. The copies wrapped
+//! to the metadata key, see `keys`, are left alone. Each pack is read and
+//! put whole, so rotation costs a download and an upload of the repository,
+//! and it is refused in append only mode, where nothing may be overwritten.
+
+use super::crypto::{unwrap_key, wrap_key, WRAPPED_KEY_SZ};
+use super::lock::LockMode;
+use super::loose::{check_loose, LOOSE_OWNER_KEY_AT};
+use super::pack::{check_header, PackId, OWNER_KEY_AT, PACK_HEADER_SZ};
+use super::parity::{parity_key, Parity};
+use super::{Repo, RepoError, OWNER_KEY_FILE, PACKS_DIR};
+use asymcrypt::Key;
+use std::io;
+use tweetnacl::*;
+
+// Passes to make while objects sealed to the old key keep appearing.
+const MAX_PASSES: usize = 5;
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct RotateStats {
+    pub packs: usize,
+    pub loose: usize,
+    // Objects found wrapped to the new key, by an interrupted run.
+    pub already: usize,
+    // Packs of the last pass skipped as cold.
+    pub cold: usize,
+    // Objects of the current pass not yet looked at.
+    pub remaining: usize,
+    pub passes: usize,
+    pub retired: bool,
+}
+
+// A new owner key with the signing key of `old` and a fresh box key.
+pub fn rotated_key(old: &Key) -> Box<Key> {
+    let mut k: Box<Key> = Default::default();
+    k.sign_pk = old.sign_pk.clone();
+    k.sign_sk.bytes = old.sign_sk.bytes;
+    crypto_box_keypair(&mut k.box_pk, &mut k.box_sk);
+    k
+}
+
+// Wrap the owner copy of the data key at `at` in `buf` to `new` instead of
+// `old`. False if it is wrapped to `new` already.
+fn rewrap(buf: &mut [u8], at: usize, old: &Key, new: &Key) -> Result<bool, RepoError> {
+    let wrapped = match buf.get_mut(at..at + WRAPPED_KEY_SZ) {
+        Some(wrapped) => wrapped,
+        None => return Err(RepoError::InvalidDataError),
+    };
+    if unwrap_key(wrapped, &new.box_sk).is_ok() {
+        return Ok(false);
+    }
+    let key = unwrap_key(wrapped, &old.box_sk)?;
+    wrapped.copy_from_slice(&wrap_key(&key, &new.box_pk));
+    Ok(true)
+}
+
+impl Repo {
+    // Move every object from the owner box key `old` to `new`, which must
+    // share its signing key, calling `report` after each. Resumes a rotation
+    // that was interrupted, and from then on the repository is the new
+    // key's, see `rotate`.
+    pub fn rotate_key(
+        &mut self,
+        old: &Key,
+        new: &Key,
+        report: &mut dyn FnMut(&RotateStats),
+    ) -> Result<RotateStats, RepoError> {
+        if old.sign_pk != self.owner().sign_pk || new.sign_pk != old.sign_pk {
+            return Err(RepoError::SignatureFailedError);
+        }
+        if old.box_pk == new.box_pk {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the new key has the box key of the old one",
+            )
+            .into());
+        }
+        if self.owner().box_pk != old.box_pk && self.owner().box_pk != new.box_pk {
+            return Err(RepoError::DecryptKeyMismatchError);
+        }
+        if self.append_only() {
+            return Err(RepoError::PermissionDeniedError);
+        }
+        let mut lock = self.lock(LockMode::Shared)?;
+        let mut m = self.manifest()?;
+        if m.old_key(&new.box_pk).is_some() {
+            return Err(RepoError::RetiredKeyError);
+        }
+
+        let owner = new.pub_key();
+        let mut buf = Vec::new();
+        owner.write(&mut buf)?;
+        if self.storage().get(OWNER_KEY_FILE)? != buf {
+            self.storage().put(OWNER_KEY_FILE, &buf)?;
+        }
+        self.owner = owner;
+        if m.old_key(&old.box_pk).is_none() {
+            m.retire_key(&old.box_pk, false);
+            self.commit_manifest(&m, new)?;
+        }
+
+        let mut stats: RotateStats = Default::default();
+        loop {
+            stats.passes += 1;
+            stats.cold = 0;
+            let before = stats.packs + stats.loose;
+            let packs = self.list_packs()?;
+            let loose = self.list_loose()?;
+            stats.remaining = packs.len() + loose.len();
+            for id in packs.iter() {
+                lock.refresh()?;
+                match self.rotate_pack(id, old, new) {
+                    Ok(true) => stats.packs += 1,
+                    Ok(false) => stats.already += 1,
+                    Err(RepoError::ColdStorageError) => stats.cold += 1,
+                    Err(e) => return Err(e),
+                }
+                stats.remaining -= 1;
+                report(&stats);
+            }
+            for a in loose.iter() {
+                lock.refresh()?;
+                let key = self.loose_key(a);
+                let mut buf = self.storage().get(&key)?;
+                check_loose(&buf)?;
+                if rewrap(&mut buf, LOOSE_OWNER_KEY_AT, old, new)? {
+                    self.storage().put(&key, &buf)?;
+                    stats.loose += 1;
+                } else {
+                    stats.already += 1;
+                }
+                stats.remaining -= 1;
+                report(&stats);
+            }
+            if stats.packs + stats.loose == before {
+                break;
+            }
+            if stats.passes == MAX_PASSES {
+                return Err(RepoError::RepoLockedError);
+            }
+        }
+
+        if stats.cold == 0 {
+            let mut m = self.manifest()?;
+            if !m.old_key(&old.box_pk).is_some_and(|k| k.retired) {
+                m.retire_key(&old.box_pk, true);
+                self.commit_manifest(&m, new)?;
+            }
+            stats.retired = true;
+        }
+        lock.release()?;
+        Ok(stats)
+    }
+
+    // Re-wrap one pack, false if there was nothing to do.
+    fn rotate_pack(&self, id: &PackId, old: &Key, new: &Key) -> Result<bool, RepoError> {
+        let key = format!("{}/{}", PACKS_DIR, id.to_hex());
+        let mut header = self.storage().get_range(&key, 0, PACK_HEADER_SZ)?;
+        check_header(&header)?;
+        if !rewrap(&mut header, OWNER_KEY_AT, old, new)? {
+            return Ok(false);
+        }
+        let mut pack = self.storage().get(&key)?;
+        rewrap(&mut pack, OWNER_KEY_AT, old, new)?;
+        self.storage().put(&key, &pack)?;
+        if let Some(parity) = self.read_parity(id)? {
+            let parity = Parity::generate(*id, &pack, &parity.opts)?;
+            self.storage().put(&parity_key(id), &parity.encode())?;
+        }
+        Ok(true)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_rotate_key() {
+    use super::address::Address;
+    use super::object::ObjectKind;
+    use super::pack::PackerOptions;
+    use super::parity::ParityOptions;
+    let (mut r, key) = super::test_repo();
+    let chunks: Vec<Address> = (0..3).map(|i| Address { bytes: [i; 32] }).collect();
+    let head = super::gc::test_commit_tree(&r, &key, 1, &chunks);
+    let opts = PackerOptions {
+        parity: Some(ParityOptions::default()),
+        ..Default::default()
+    };
+    let mut packer = r.packer(opts);
+    packer
+        .add(&Address { bytes: [9; 32] }, ObjectKind::Chunk, b"nine")
+        .unwrap();
+    for p in packer.finish().unwrap() {
+        r.write_pack_index(&super::index::PackIndex::from_finished(&p))
+            .unwrap();
+    }
+    let a = Address { bytes: [5; 32] };
+    r.put_loose(&a, ObjectKind::Snapshot, b"snap").unwrap();
+    let n_packs = r.list_packs().unwrap().len();
+
+    let new = rotated_key(&key);
+    assert_eq!(new.sign_pk, key.sign_pk);
+    let mut reports = 0;
+    let stats = r.rotate_key(&key, &new, &mut |_| reports += 1).unwrap();
+    assert_eq!((stats.packs, stats.loose), (n_packs, 1));
+    assert_eq!(reports, 2 * (n_packs + 1));
+    assert!(stats.retired && stats.remaining == 0);
+    assert!(r.manifest().unwrap().old_key(&key.box_pk).unwrap().retired);
+
+    // Everything opens with the new key alone, parity included.
+    let reopened = Repo::open(r.storage().clone(), &new.pub_key()).unwrap();
+    assert_eq!(reopened.owner().box_pk, new.box_pk);
+    let index = reopened.load_index().unwrap();
+    for address in chunks.iter().chain([head.address].iter()) {
+        reopened.read_object(&index, &new.box_sk, address).unwrap();
+        assert!(r.read_object(&index, &key.box_sk, address).is_err());
+    }
+    assert_eq!(reopened.get_loose(&a, &new.box_sk).unwrap().1, b"snap");
+    let fsck = reopened
+        .fsck(Some(&new.box_sk), &Default::default())
+        .unwrap();
+    assert!(fsck.is_ok());
+    let with_parity = r
+        .list_packs()
+        .unwrap()
+        .into_iter()
+        .filter(|id| r.read_parity(id).unwrap().is_some())
+        .collect::<Vec<_>>();
+    assert_eq!(with_parity.len(), 1);
+    assert_eq!(r.read_pack_repaired(&with_parity[0]).unwrap().1, 0);
+
+    // Running again finds nothing to do.
+    let stats = r.rotate_key(&key, &new, &mut |_| ()).unwrap();
+    assert_eq!((stats.packs, stats.already), (0, n_packs + 1));
+
+    // A host still holding the old public key cannot commit.
+    let stale = Repo::open(r.storage().clone(), &key.pub_key()).unwrap();
+    match stale.commit_manifest(&stale.manifest().unwrap(), &key) {
+        Err(RepoError::RetiredKeyError) => (),
+        _ => panic!("expected the old key to be refused"),
+    }
+    // Nor can a key be rotated back to one retired.
+    let mut again = reopened;
+    match again.rotate_key(&new, &key, &mut |_| ()) {
+        Err(RepoError::RetiredKeyError) => (),
+        _ => panic!("expected the retired key to be refused"),
+    }
+}
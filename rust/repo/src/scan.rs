@@ -0,0 +1,167 @@
+//! Parallel directory scanning.
+//!
+//! On trees of many small files a backup spends most of its time waiting
+//! on `readdir` and `lstat`. A `Scanner` runs threads that list
+//! directories and stat their entries ahead of the walk in `backup`: as
+//! the walk enters a directory it submits every subdirectory it will walk
+//! into, and by the time it gets to each one its listing is usually ready.
+//! The walk itself stays depth first and in name order, so trees come out
+//! the same however many threads scan.
+//!
+//! A listing the walk needs before a thread has started on it is scanned
+//! in the walking thread. Listings wait in memory until the walk takes
+//! them, at most those of one directory's subdirectories plus the
+//! directories above it.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::OsString;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+// The entries of a directory in name order, each with its `lstat`.
+pub type Listing = Vec<(OsString, io::Result<Metadata>)>;
+
+pub fn scan_dir(path: &Path) -> io::Result<Listing> {
+    let mut names = Vec::new();
+    for ent in fs::read_dir(path)? {
+        names.push(ent?.file_name());
+    }
+    names.sort();
+    Ok(names
+        .into_iter()
+        .map(|name| {
+            let meta = fs::symlink_metadata(path.join(&name));
+            (name, meta)
+        })
+        .collect())
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<PathBuf>,
+    scanning: HashSet<PathBuf>,
+    done: HashMap<PathBuf, io::Result<Listing>>,
+    stop: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    // Signalled when work is queued and when a listing is done.
+    queued: Condvar,
+    done: Condvar,
+}
+
+pub struct Scanner {
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+fn scan_loop(shared: &Shared) {
+    let mut state = shared.state.lock().unwrap();
+    loop {
+        if state.stop {
+            return;
+        }
+        let path = match state.queue.pop_front() {
+            Some(path) => path,
+            None => {
+                state = shared.queued.wait(state).unwrap();
+                continue;
+            }
+        };
+        state.scanning.insert(path.clone());
+        drop(state);
+        let listing = scan_dir(&path);
+        state = shared.state.lock().unwrap();
+        state.scanning.remove(&path);
+        state.done.insert(path, listing);
+        shared.done.notify_all();
+    }
+}
+
+impl Scanner {
+    // Without threads every directory is scanned when it is taken.
+    pub fn new(threads: usize) -> Scanner {
+        let shared: Arc<Shared> = Default::default();
+        let threads = (0..threads)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || scan_loop(&shared))
+            })
+            .collect();
+        Scanner { shared, threads }
+    }
+
+    // Scan `path` ahead of a `take`, which every submitted path must get.
+    pub fn submit(&self, path: PathBuf) {
+        if self.threads.is_empty() {
+            return;
+        }
+        self.shared.state.lock().unwrap().queue.push_back(path);
+        self.shared.queued.notify_one();
+    }
+
+    pub fn take(&self, path: &Path) -> io::Result<Listing> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(listing) = state.done.remove(path) {
+                return listing;
+            }
+            if !state.scanning.contains(path) {
+                // Not submitted or not started, quicker to scan it here.
+                state.queue.retain(|p| p != path);
+                drop(state);
+                return scan_dir(path);
+            }
+            state = self.shared.done.wait(state).unwrap();
+        }
+    }
+}
+
+impl Drop for Scanner {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().stop = true;
+        self.shared.queued.notify_all();
+        for t in self.threads.drain(..) {
+            let _ = t.join();
+        }
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_scanner() {
+    let dir = super::storage::local::test_dir("scan");
+    for i in 0..20 {
+        let sub = dir.join(format!("d{:02}", i));
+        fs::create_dir_all(&sub).unwrap();
+        for j in 0..i {
+            fs::write(sub.join(format!("f{}", j)), b"x").unwrap();
+        }
+    }
+    let scanner = Scanner::new(4);
+    let top = scanner.take(&dir).unwrap();
+    assert_eq!(top.len(), 20);
+    for (name, _) in top.iter() {
+        scanner.submit(dir.join(name));
+    }
+    for (i, (name, meta)) in top.iter().enumerate() {
+        assert!(meta.as_ref().unwrap().is_dir());
+        let listing = scanner.take(&dir.join(name)).unwrap();
+        let names: Vec<OsString> = listing.into_iter().map(|(n, _)| n).collect();
+        let mut want: Vec<OsString> = (0..i).map(|j| format!("f{}", j).into()).collect();
+        want.sort();
+        assert_eq!(names, want);
+    }
+    assert!(scanner.shared.state.lock().unwrap().done.is_empty());
+    match Scanner::new(0).take(&dir.join("missing")) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        _ => panic!("expected a missing directory to fail"),
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
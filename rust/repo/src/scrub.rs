@@ -0,0 +1,206 @@
+//! Incremental verification of stored packs.
+//!
+//! Reading back a whole repository is slow and expensive, so `scrub`
+//! verifies a fraction of the packs per run. Each run picks the packs
+//! verified longest ago, never verified packs first, so repeated runs
+//! cycle through the whole repository.
+//!
+//! Pack indexes never change once written, so the time each pack was last
+//! verified is kept in a separate scrub state object, which is replaced
+//! after every run, even in append only mode. It holds nothing but
+//! scheduling hints, losing or forging it only changes the order in which
+//! packs are verified.
+//!
+//! A pack is verified by checking its size against its index, its parity
+//! blocks if it has parity, and with the repository key its table of
+//! contents and the decryption of every object in it. Packs that fail
+//! keep their old timestamp so the next run checks them again.
+//!
+//! ```text
+//! "PNBSCRUB" u16:format_version u32:n n * ([16]:pack_id u64:last_verified)
+//! ```
+
+use super::datetime::unix_now;
+use super::fsck::{FsckOptions, FsckReport, Verdict};
+use super::lock::LockMode;
+use super::pack::PackId;
+use super::wire::{Decoder, Encoder};
+use super::{Repo, RepoError, PACKS_DIR, SCRUB_FILE};
+use std::collections::{BTreeMap, HashSet};
+use tweetnacl::CryptoBoxSk;
+
+pub const SCRUB_FORMAT_VERSION: u16 = 1;
+const SCRUB_MAGIC: &[u8] = b"PNBSCRUB";
+
+#[derive(Clone, Debug)]
+pub struct ScrubOptions {
+    // Verify this percentage of the packs, at least one.
+    pub percent: u32,
+    // Rewrite packs that their parity can repair.
+    pub repair: bool,
+}
+
+impl Default for ScrubOptions {
+    fn default() -> ScrubOptions {
+        ScrubOptions {
+            percent: 10,
+            repair: false,
+        }
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct ScrubState {
+    pub last_verified: BTreeMap<PackId, u64>,
+}
+
+impl ScrubState {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.fixed(SCRUB_MAGIC)
+            .u16(SCRUB_FORMAT_VERSION)
+            .u32(self.last_verified.len() as u32);
+        for (id, t) in self.last_verified.iter() {
+            e.fixed(&id.bytes).u64(*t);
+        }
+        e.into_vec()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<ScrubState, RepoError> {
+        let mut d = Decoder::new(buf);
+        if d.fixed(SCRUB_MAGIC.len())? != SCRUB_MAGIC {
+            return Err(RepoError::InvalidDataError);
+        }
+        if d.u16()? != SCRUB_FORMAT_VERSION {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        let n = d.count(24)?;
+        let mut state: ScrubState = Default::default();
+        for _ in 0..n {
+            let mut id: PackId = Default::default();
+            d.fixed_into(&mut id.bytes)?;
+            state.last_verified.insert(id, d.u64()?);
+        }
+        d.finish()?;
+        Ok(state)
+    }
+}
+
+impl Repo {
+    // A missing or unreadable state starts the cycle over.
+    pub fn scrub_state(&self) -> Result<ScrubState, RepoError> {
+        match self.storage().get(SCRUB_FILE) {
+            Ok(buf) => Ok(ScrubState::decode(&buf).unwrap_or_default()),
+            Err(ref e) if e.is_not_found() => Ok(Default::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn scrub(
+        &self,
+        sk: Option<&CryptoBoxSk>,
+        opts: &ScrubOptions,
+    ) -> Result<FsckReport, RepoError> {
+        let mut lock = self.lock(LockMode::Shared)?;
+        let mut state = self.scrub_state()?;
+        let ids = self.list_pack_indexes()?;
+        let existing: HashSet<PackId> = ids.iter().cloned().collect();
+        state.last_verified.retain(|id, _| existing.contains(id));
+
+        let mut order: Vec<(u64, PackId)> = ids
+            .iter()
+            .map(|id| (state.last_verified.get(id).cloned().unwrap_or(0), *id))
+            .collect();
+        order.sort();
+        let n = (ids.len() * opts.percent as usize).div_ceil(100).max(1);
+        let fsck_opts = FsckOptions {
+            read_data: true,
+            repair: opts.repair,
+        };
+
+        let mut report: FsckReport = Default::default();
+        for (_, id) in order.into_iter().take(n) {
+            lock.refresh()?;
+            let errors = report.count(Verdict::Error);
+            let subject = format!("{}/{}", PACKS_DIR, id.to_hex());
+            self.fsck_parity(&mut report, &id, &fsck_opts);
+            let result = self.read_pack_index(&id).and_then(|idx| {
+                self.fsck_pack(&idx, sk)?;
+                if let Some(sk) = sk {
+                    let mut pack = self.open_pack(&id, sk)?;
+                    for ent in idx.entries() {
+                        pack.read_entry(ent)?;
+                    }
+                }
+                Ok(())
+            });
+            report.check(&subject, result);
+            if report.count(Verdict::Error) == errors {
+                state.last_verified.insert(id, unix_now());
+            }
+        }
+        self.storage().put(SCRUB_FILE, &state.encode())?;
+        Ok(report)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_scrub_state_round_trip() {
+    let mut state: ScrubState = Default::default();
+    state.last_verified.insert(PackId::new(), 7);
+    state.last_verified.insert(PackId::new(), 9);
+    assert_eq!(ScrubState::decode(&state.encode()).unwrap(), state);
+    assert!(ScrubState::decode(&state.encode()[..20]).is_err());
+}
+
+#[test]
+fn test_scrub_cycles() {
+    use super::address::Address;
+    use super::gc::test_commit_tree;
+    let (r, key) = super::test_repo();
+    for i in 1..5 {
+        test_commit_tree(&r, &key, i, &[Address { bytes: [i; 32] }]);
+    }
+    let opts = ScrubOptions {
+        percent: 50,
+        ..Default::default()
+    };
+    let first = r.scrub(Some(&key.box_sk), &opts).unwrap();
+    assert!(first.is_ok());
+    assert_eq!(first.entries.len(), 2);
+    assert_eq!(r.scrub_state().unwrap().last_verified.len(), 2);
+    let second = r.scrub(Some(&key.box_sk), &opts).unwrap();
+    assert_eq!(r.scrub_state().unwrap().last_verified.len(), 4);
+    let checked: HashSet<_> = first
+        .entries
+        .iter()
+        .chain(second.entries.iter())
+        .map(|e| e.subject.clone())
+        .collect();
+    assert_eq!(checked.len(), 4);
+
+    // Damaged packs are reported and stay first in line.
+    let id = r.list_packs().unwrap()[0];
+    let pack_key = format!("{}/{}", PACKS_DIR, id.to_hex());
+    let mut pack = r.storage().get(&pack_key).unwrap();
+    let n = pack.len();
+    pack[n / 2] ^= 1;
+    r.storage().put(&pack_key, &pack).unwrap();
+    let mut state = r.scrub_state().unwrap();
+    state.last_verified.insert(id, 0);
+    r.storage().put(SCRUB_FILE, &state.encode()).unwrap();
+    let opts = ScrubOptions {
+        percent: 1,
+        ..Default::default()
+    };
+    for _ in 0..2 {
+        let report = r.scrub(Some(&key.box_sk), &opts).unwrap();
+        assert_eq!(report.entries.len(), 1);
+        assert_eq!(report.entries[0].subject, pack_key);
+        assert_eq!(report.entries[0].verdict, Verdict::Error);
+    }
+    // Without the key only the size is checked.
+    assert!(r.scrub(None, &opts).unwrap().is_ok());
+}
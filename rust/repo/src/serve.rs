@@ -0,0 +1,2357 @@
+//! Server side of the remote protocol, see `protocol`.
+//!
+//! In append only mode nothing stored can be deleted or overwritten,
+//! except locks and the scrub state, which hold no data, and the manifest,
+//! which every commit replaces. A replacement manifest, append only or
+//! not, is only accepted if it is signed by the repository owner key,
+//! names this repository and follows the stored one, with the next
+//! counter and its hash as `previous`, see `manifest`. So a client can
+//! never swap in garbage, a manifest from another repository or an old
+//! one replayed. The config is never replaced, and the owner key only by
+//! one of the same signing key, see `rotate`.
+//!
+//! A repository is append only if its `policy` says so, or if the server
+//! is started with `ServeOptions::append_only`, which no client can lift.
+//! While a snapshot in the manifest is under a retention lock the packs it
+//! needs, their indexes and parity cannot be deleted or overwritten, and
+//! sweeps pass over them. The server cannot tell which encrypted packs
+//! those are, so the owner lists them, see `retained`, and a lock the
+//! list does not cover makes the whole repository append only until it
+//! expires. Locks are extended by replacing the manifest, which append
+//! only mode allows, and a replacement that drops or shortens a lock is
+//! refused, see `manifest`, as is a list dropping what a lock holds.
+//! A new policy is only accepted if it is signed by the maintenance key
+//! from the config and has a higher serial than the current one, so
+//! clients holding only owner or writer keys cannot lift append only mode.
+//!
+//! Given a client name the server tracks what that client stores and
+//! enforces `ServeOptions::quota`, or the client's own quota from
+//! `ServeOptions::client_quotas`, see `usage`. The name comes from the
+//! transport, typically an ssh forced command per authorized key, or from
+//! the key the client authenticated with, never from the client's say so.
+//! `ServeOptions::repo_quota` and `ServeOptions::tenant_quotas` limit what
+//! the repository, or each tenant's, holds in all, whoever stored it.
+//!
+//! Once the repository has an access list, see `acl`, each request must
+//! be allowed to the client, or it is refused before anything else is
+//! checked. The access list is replaced like the policy, only by a newer
+//! one the maintenance key signed, and only by an admin client. With
+//! `ServeOptions::authenticate` the session has no client, and nothing is
+//! allowed, until it signs a challenge with the key the access list gives
+//! a client, see `protocol`. A name the transport gave is then only
+//! accepted for the same client.
+//!
+//! A client whose key was revoked, see `revoke`, may no longer put,
+//! delete or sweep anything. Revocations are replaced like the manifest,
+//! only by an admin client, and only by newer ones that keep every key
+//! revoked.
+//!
+//! Only an admin client may delete the access list, the policy, the
+//! revocations, the log of manifests, the config or the owner key, what
+//! clients open and trust the repository by. Without the access list a
+//! server started with `ServeOptions::authenticate` allows nothing, rather
+//! than everything.
+//!
+//! The log of manifests, see `translog`, may only be replaced by a log that
+//! extends it, append only mode or not, and the server answers consistency
+//! proofs from it, so clients need not download the log to check one.
+//!
+//! Garbage collection is swept here from a keep list the owner signed,
+//! see `keeplist`, so the server never needs the repository key. Packs it
+//! deletes are not credited to any client's usage.
+//!
+//! With `ServeOptions::rate` and `ServeOptions::client_rates` each client is
+//! held to a number of requests and bytes a second, and told to come back
+//! later past them, see `ratelimit`.
+//!
+//! With `ServeOptions::tenants` the server hosts a repository per tenant,
+//! selected by the client before it authenticates, and each session sees
+//! only its tenant's, see `tenant`. Usage is kept within each tenant's
+//! repository, and rate limits and the log name clients with their tenant.
+//!
+//! What the sessions do is counted for monitoring, see `metrics`. A
+//! session the client asked to keep can be resumed on a new connection,
+//! see `session`. Clients say which protocol version and features they
+//! speak first, see `protocol`.
+//!
+//! With `ServeOptions::delete_grace` the server stamps everything stored
+//! with its arrival time and keeps it for that long whatever clients ask,
+//! see `storage::grace`. A sweep passes over packs too young to delete.
+//!
+//! With `ServeOptions::log` every request answered is recorded in a log
+//! sealed with the server's own key, `ServeOptions::log_key`, see `oplog`.
+//! If a record cannot be appended the session ends.
+//!
+//! With `ServeOptions::check_every` the server checks the structure of
+//! the packs and indexes it holds that often, and tells clients how the
+//! last check went, see `checks`. The status of the checks is the
+//! server's, no client may put or delete it.
+//!
+//! `serve_dir` is what `packnback serve` runs, over stdin and stdout, with
+//! the arguments `ServeOptions::parse_args` takes. Installed as the forced
+//! command of a key in authorized_keys,
+//!
+//! ```text
+//! command="packnback serve --append-only --client laptop /srv/repo",restrict ssh-ed25519 ...
+//! ```
+//!
+//! the directory and the rules are the server's: whatever the client asked
+//! to run, see `RemoteStorage::ssh`, is only in SSH_ORIGINAL_COMMAND and is
+//! ignored, so a stolen client key reaches that one repository on those
+//! terms and nothing else. Where clients cannot be given shell accounts
+//! the server can listen on a port instead, see `tls`.
+//!
+//! Clients on the same machine get the same guarantees from a server
+//! listening on a unix socket, `ServeOptions::socket`, rather than opening
+//! the repository directory themselves, which only the server's user
+//! should be able to write. Who may connect is up to the permissions of
+//! the directory holding the socket, and clients are told apart by
+//! `ServeOptions::authenticate`, see `RemoteStorage::unix`.
+//!
+//! Requests in a batch are served by up to `BATCH_THREADS` threads, so one
+//! slow request does not hold up the answers to the others. Puts and
+//! deletes check the state they are about to change, and still run one at
+//! a time.
+
+use super::acl::{Acl, CAP_ADMIN, CAP_FETCH, CAP_LIST, CAP_PRUNE, CAP_PUT};
+use super::address::Address;
+use super::checks::{check_status, schedule_checks, Checks};
+use super::datetime::unix_now;
+use super::gc::GcStats;
+use super::index::PackIndex;
+use super::lock::{is_lock_key, is_lock_owner_key, lock_owner_key};
+use super::manifest::{manifest_hash, Manifest, MANIFEST_HASH_SZ};
+use super::metrics::ServeMetrics;
+use super::oplog::{OpLog, Operation};
+use super::pack::PackId;
+use super::policy::Policy;
+use super::presence::encode_bitmap;
+use super::protocol::{self, Request, CHALLENGE_SZ};
+use super::prune::parse_duration;
+use super::ratelimit::{RateLimiter, RateLimits};
+use super::session::{Resumable, Sessions};
+use super::settings::parse_size;
+use super::signed;
+use super::storage::grace::{is_arrival_key, GraceStorage};
+use super::storage::local::LocalStorage;
+use super::storage::throttle::parse_rate;
+use super::storage::{check_prefix, not_found, StorageEngine};
+use super::tenant::{check_tenant, tenant_storage};
+use super::translog::TransLog;
+use super::usage::{is_usage_key, usage_key, Quota, QuotaScope, Usage};
+use super::{
+    Repo, RepoError, ACL_FILE, CHECK_FILE, COLD_DIR, CONFIG_FILE, INDEXES_DIR, LOCKS_DIR,
+    LOOSE_DIR, MANIFEST_FILE, OWNER_KEY_FILE, PACKS_DIR, PARITY_DIR, POLICY_FILE, REPO_USAGE_FILE,
+    RETAINED_FILE, REVOCATIONS_FILE, SCRUB_FILE, TRANSLOG_FILE,
+};
+use asymcrypt::{Key, PublicKey};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tweetnacl::random_bytes;
+
+pub const BATCH_THREADS: usize = 8;
+
+// What the sessions a process serves at once share.
+#[derive(Default)]
+pub struct ServeShared {
+    pub limiter: RateLimiter,
+    pub metrics: ServeMetrics,
+    pub sessions: Sessions,
+    pub checks: Checks,
+}
+
+impl ServeShared {
+    pub fn new() -> ServeShared {
+        Default::default()
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct ServeOptions {
+    // Treat the repository as append only whatever its policy says.
+    pub append_only: bool,
+    // The authenticated client, usage is tracked when set.
+    pub client: Option<String>,
+    // Bytes each client may store, only enforced with `client`.
+    pub quota: Option<u64>,
+    // Quotas of particular clients instead, None for no quota.
+    pub client_quotas: BTreeMap<String, Option<u64>>,
+    // Bytes the repository may hold, each tenant's with `tenants`.
+    pub repo_quota: Option<u64>,
+    // Quotas of particular tenants instead, None for no quota.
+    pub tenant_quotas: BTreeMap<String, Option<u64>>,
+    // Refuse requests until the client authenticates, see `acl`.
+    pub authenticate: bool,
+    // Seconds everything stored is kept before it may be deleted or
+    // overwritten, however it was asked for.
+    pub delete_grace: Option<u64>,
+    // The operation log requests are recorded in, see `oplog`.
+    pub log: Option<PathBuf>,
+    // The key file of the key that seals the log, needed with `log`.
+    pub log_key: Option<PathBuf>,
+    // The limits of every client, see `ratelimit`.
+    pub rate: RateLimits,
+    // Limits of particular clients instead.
+    pub client_rates: BTreeMap<String, RateLimits>,
+    // Host a repository per tenant, which the client selects, see `tenant`.
+    pub tenants: bool,
+    // The tenant served, when the transport rather than the client picks it.
+    pub tenant: Option<String>,
+    // Listen on this unix socket rather than serve stdin and stdout.
+    pub socket: Option<PathBuf>,
+    // Seconds between structural checks of each repository, see `checks`.
+    pub check_every: Option<u64>,
+}
+
+pub(crate) fn open_repo(storage: &Arc<dyn StorageEngine>) -> Result<Repo, RepoError> {
+    let owner = PublicKey::read_from(&mut &storage.get(OWNER_KEY_FILE)?[..])?;
+    Repo::open(storage.clone(), &owner)
+}
+
+fn current_manifest(repo: &Repo) -> Result<Option<Manifest>, RepoError> {
+    match repo.manifest() {
+        Ok(m) => Ok(Some(m)),
+        Err(ref e) if e.is_not_found() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Before `Repo::init` has written a config there is nothing to protect.
+fn append_only(storage: &Arc<dyn StorageEngine>, opts: &ServeOptions) -> Result<bool, RepoError> {
+    if opts.append_only {
+        return Ok(true);
+    }
+    if !storage.exists(CONFIG_FILE)? {
+        return Ok(false);
+    }
+    let repo = open_repo(storage)?;
+    if repo.append_only() {
+        return Ok(true);
+    }
+    let retained = repo.retained()?.unwrap_or_default();
+    Ok(current_manifest(&repo)?.is_some_and(|m| !retained.covers(&m, unix_now())))
+}
+
+// Whether `key` is a pack, or its index or parity, that a retention lock
+// holds, see `retained`.
+fn is_held(storage: &Arc<dyn StorageEngine>, key: &str) -> Result<bool, RepoError> {
+    let id = match [PACKS_DIR, INDEXES_DIR, PARITY_DIR]
+        .iter()
+        .find(|dir| in_dir(key, dir))
+    {
+        Some(dir) => match PackId::from_hex(&key[dir.len() + 1..]) {
+            Ok(id) => id,
+            Err(_) => return Ok(false),
+        },
+        None => return Ok(false),
+    };
+    if !storage.exists(RETAINED_FILE)? {
+        return Ok(false);
+    }
+    let retained = open_repo(storage)?.retained()?.unwrap_or_default();
+    Ok(retained.holds(&id, unix_now()))
+}
+
+fn check_manifest(storage: &Arc<dyn StorageEngine>, data: &[u8]) -> Result<(), RepoError> {
+    let repo = open_repo(storage)?;
+    let m = Manifest::open(data, &repo.owner().sign_pk)?;
+    if m.repo_id != repo.config().repo_id {
+        return Err(RepoError::RepoMismatchError);
+    }
+    // Only the manifest following the stored one, see `manifest`, so an
+    // old manifest cannot be replayed over a newer one.
+    match storage.get(MANIFEST_FILE) {
+        Ok(sm) => {
+            let current = repo.open_manifest(&sm)?;
+            if m.counter != current.counter + 1 || m.previous != manifest_hash(&sm) {
+                return Err(RepoError::PermissionDeniedError);
+            }
+            current.check_retention(&m, unix_now())?;
+        }
+        Err(ref e) if e.is_not_found() => {
+            if m.counter != 1 || m.previous != [0; MANIFEST_HASH_SZ] {
+                return Err(RepoError::PermissionDeniedError);
+            }
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
+
+fn check_retained(storage: &Arc<dyn StorageEngine>, data: &[u8]) -> Result<(), RepoError> {
+    let repo = open_repo(storage)?;
+    let new = repo.open_retained(data)?;
+    if let Some(current) = repo.retained()? {
+        current.check_replacement(&new, unix_now())?;
+    }
+    Ok(())
+}
+
+fn check_policy(storage: &Arc<dyn StorageEngine>, data: &[u8]) -> Result<(), RepoError> {
+    let repo = open_repo(storage)?;
+    let p = Policy::open_for(data, repo.config())?;
+    if p.serial <= repo.policy().serial {
+        return Err(RepoError::PermissionDeniedError);
+    }
+    Ok(())
+}
+
+fn check_acl(storage: &Arc<dyn StorageEngine>, data: &[u8]) -> Result<(), RepoError> {
+    let repo = open_repo(storage)?;
+    let acl = Acl::open_for(data, repo.config())?;
+    if acl.serial <= repo.acl()?.map_or(0, |current| current.serial) {
+        return Err(RepoError::PermissionDeniedError);
+    }
+    Ok(())
+}
+
+fn check_revocations(storage: &Arc<dyn StorageEngine>, data: &[u8]) -> Result<(), RepoError> {
+    let repo = open_repo(storage)?;
+    let new = repo.open_revocations(data)?;
+    if let Some(current) = repo.revocations()? {
+        current.check_replacement(&new)?;
+    }
+    Ok(())
+}
+
+// Refuse the writes of a client whose key is revoked, see `revoke`.
+fn check_not_revoked(
+    storage: &Arc<dyn StorageEngine>,
+    opts: &ServeOptions,
+    req: &Request,
+) -> Result<(), RepoError> {
+    let writes = match *req {
+        Request::Put { key, .. } | Request::Delete { key } => !is_lock_key(key),
+        Request::Gc { .. } => true,
+        _ => false,
+    };
+    let client = match opts.client {
+        Some(ref client) if writes => client,
+        _ => return Ok(()),
+    };
+    if !storage.exists(REVOCATIONS_FILE)? {
+        return Ok(());
+    }
+    let repo = open_repo(storage)?;
+    let pk = match repo.acl()? {
+        Some(mut acl) => acl.keys.remove(client),
+        None => None,
+    };
+    match (pk, repo.revocations()?) {
+        (Some(pk), Some(revocations)) if revocations.is_revoked(&pk) => {
+            Err(RepoError::PermissionDeniedError)
+        }
+        _ => Ok(()),
+    }
+}
+
+// A client cannot sign the log, so the server keeps it from being
+// rewritten.
+fn check_translog(storage: &Arc<dyn StorageEngine>, data: &[u8]) -> Result<(), RepoError> {
+    let repo = open_repo(storage)?;
+    let new = TransLog::decode(data)?;
+    if new.repo_id != repo.config().repo_id {
+        return Err(RepoError::RepoMismatchError);
+    }
+    if !new.extends(&repo.translog()?) {
+        return Err(RepoError::PermissionDeniedError);
+    }
+    Ok(())
+}
+
+fn in_dir(key: &str, dir: &str) -> bool {
+    key.starts_with(dir) && key[dir.len()..].starts_with('/')
+}
+
+// Check an AUTH answering `challenge`, returning the client it proves the
+// session is.
+fn authenticate(
+    storage: &Arc<dyn StorageEngine>,
+    opts: &ServeOptions,
+    challenge: Option<[u8; CHALLENGE_SZ]>,
+    client: &str,
+    signed: &[u8],
+) -> Result<String, RepoError> {
+    let challenge = challenge.ok_or(RepoError::PermissionDeniedError)?;
+    if opts.client.as_deref().is_some_and(|c| c != client) {
+        return Err(RepoError::PermissionDeniedError);
+    }
+    let pk = match open_repo(storage)?.acl()? {
+        Some(mut acl) => acl.keys.remove(client),
+        None => None,
+    };
+    let pk = pk.ok_or(RepoError::PermissionDeniedError)?;
+    match signed::open(signed, &pk) {
+        Ok(m) if m == protocol::auth_message(&challenge, client) => Ok(client.to_string()),
+        _ => Err(RepoError::PermissionDeniedError),
+    }
+}
+
+// Keys holding file data, however encrypted.
+fn is_data_key(key: &str) -> bool {
+    [PACKS_DIR, LOOSE_DIR, PARITY_DIR, COLD_DIR]
+        .iter()
+        .any(|dir| in_dir(key, dir))
+}
+
+// What only an admin client may delete, see `acl`. Of these writers may
+// still extend the log, see `check_translog`.
+fn is_admin_key(key: &str) -> bool {
+    [
+        ACL_FILE,
+        POLICY_FILE,
+        REVOCATIONS_FILE,
+        TRANSLOG_FILE,
+        CONFIG_FILE,
+        OWNER_KEY_FILE,
+    ]
+    .contains(&key)
+}
+
+// What every client needs to open the repository.
+fn is_open_key(key: &str) -> bool {
+    [
+        CONFIG_FILE,
+        OWNER_KEY_FILE,
+        POLICY_FILE,
+        ACL_FILE,
+        REVOCATIONS_FILE,
+    ]
+    .contains(&key)
+}
+
+// The capabilities `req` needs, see `acl`, 0 for what every client listed
+// may do.
+fn required(req: &Request) -> u32 {
+    match *req {
+        Request::Put { key, .. } if [POLICY_FILE, ACL_FILE, REVOCATIONS_FILE].contains(&key) => {
+            CAP_ADMIN
+        }
+        Request::Delete { key } if is_admin_key(key) => CAP_ADMIN,
+        // Whose lock it is is checked by `check_lock_owner`.
+        Request::Delete { key } if is_lock_key(key) => 0,
+        Request::Put { .. } => CAP_PUT,
+        Request::Delete { .. } | Request::Gc { .. } => CAP_PRUNE,
+        Request::Get { key } | Request::GetRange { key, .. } if is_open_key(key) => 0,
+        Request::Get { key } | Request::GetRange { key, .. } if is_data_key(key) => CAP_FETCH,
+        Request::Thaw { .. } => CAP_FETCH,
+        Request::List { prefix } if in_dir(prefix, LOCKS_DIR) => 0,
+        Request::Get { .. } | Request::GetRange { .. } | Request::Size { .. } => CAP_LIST,
+        Request::List { .. } | Request::Consistency { .. } => CAP_LIST,
+        Request::Capabilities | Request::Exists { .. } | Request::Present(_) => 0,
+        Request::Quota | Request::CheckStatus => 0,
+        // Each request in a batch is checked, the others are answered by
+        // `serve` itself.
+        Request::Batch(_) | Request::Challenge | Request::Auth { .. } => 0,
+        Request::Tenant { .. } | Request::Session | Request::Resume { .. } => 0,
+        Request::Hello(_) => 0,
+    }
+}
+
+// The access list, before `Repo::init` there is none to read.
+fn read_acl(storage: &Arc<dyn StorageEngine>) -> Result<Option<Acl>, RepoError> {
+    if !storage.exists(ACL_FILE)? {
+        return Ok(None);
+    }
+    let acl = Acl::open_for(&storage.get(ACL_FILE)?, open_repo(storage)?.config())?;
+    Ok(Some(acl))
+}
+
+fn check_allowed(
+    storage: &Arc<dyn StorageEngine>,
+    opts: &ServeOptions,
+    req: &Request,
+) -> Result<(), RepoError> {
+    match read_acl(storage)? {
+        Some(acl) if !acl.allows(opts.client.as_deref(), required(req)) => {
+            Err(RepoError::PermissionDeniedError)
+        }
+        // Clients authenticate with the keys the access list gives them,
+        // without it no session can be trusted.
+        None if opts.authenticate => Err(RepoError::PermissionDeniedError),
+        _ => Ok(()),
+    }
+}
+
+// Whether the session may replace what is stored and the locks of other
+// clients, once there is an access list only with prune.
+fn may_replace(storage: &Arc<dyn StorageEngine>, opts: &ServeOptions) -> Result<bool, RepoError> {
+    Ok(read_acl(storage)?.is_none_or(|acl| acl.allows(opts.client.as_deref(), CAP_PRUNE)))
+}
+
+// Refuse a lock the session did not take, unless it may replace it.
+fn check_lock_owner(
+    storage: &Arc<dyn StorageEngine>,
+    opts: &ServeOptions,
+    key: &str,
+) -> Result<(), RepoError> {
+    if !storage.exists(key)? || may_replace(storage, opts)? {
+        return Ok(());
+    }
+    let owner = match storage.get(&lock_owner_key(key)) {
+        Ok(owner) => Some(owner),
+        Err(ref e) if e.is_not_found() => None,
+        Err(e) => return Err(e),
+    };
+    match (owner, opts.client.as_deref()) {
+        (Some(owner), Some(client)) if owner == client.as_bytes() => Ok(()),
+        _ => Err(RepoError::PermissionDeniedError),
+    }
+}
+
+// A new owner key may only replace the box key, see `rotate`, the
+// signing key is what clients know the repository by.
+fn check_owner_key(storage: &Arc<dyn StorageEngine>, data: &[u8]) -> Result<(), RepoError> {
+    let current = PublicKey::read_from(&mut &storage.get(OWNER_KEY_FILE)?[..])?;
+    let new = PublicKey::read_from(&mut &data[..])?;
+    if new.sign_pk != current.sign_pk {
+        return Err(RepoError::PermissionDeniedError);
+    }
+    Ok(())
+}
+
+fn read_usage(storage: &Arc<dyn StorageEngine>, key: &str) -> Result<Usage, RepoError> {
+    match storage.get(key) {
+        Ok(buf) => Usage::decode(&buf),
+        Err(ref e) if e.is_not_found() => Ok(Default::default()),
+        Err(e) => Err(e),
+    }
+}
+
+fn stored_size(storage: &Arc<dyn StorageEngine>, key: &str) -> Result<Option<u64>, RepoError> {
+    match storage.size(key) {
+        Ok(n) => Ok(Some(n)),
+        Err(ref e) if e.is_not_found() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// The repository's usage record, started from what is stored when there is
+// none yet, see `usage`.
+fn read_repo_usage(storage: &Arc<dyn StorageEngine>) -> Result<Usage, RepoError> {
+    match storage.get(REPO_USAGE_FILE) {
+        Ok(buf) => Usage::decode(&buf),
+        Err(ref e) if e.is_not_found() => {
+            let mut u: Usage = Default::default();
+            for key in storage.list_prefix("")?.iter() {
+                if !is_lock_key(key)
+                    && !is_lock_owner_key(key)
+                    && !is_usage_key(key)
+                    && !is_arrival_key(key)
+                {
+                    u.stored_bytes += storage.size(key)?;
+                    u.objects += 1;
+                }
+            }
+            Ok(u)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Record a put of `new` bytes over `old` ones, or a delete if `new` is
+// None, in `u`, refusing growth past `quota`.
+fn charge(
+    u: &mut Usage,
+    scope: QuotaScope,
+    quota: Option<u64>,
+    old: Option<u64>,
+    new: Option<u64>,
+) -> Result<(), RepoError> {
+    let before = u.stored_bytes;
+    u.stored_bytes = (u.stored_bytes + new.unwrap_or(0)).saturating_sub(old.unwrap_or(0));
+    match (old, new) {
+        (None, Some(_)) => u.objects += 1,
+        (Some(_), None) => u.objects = u.objects.saturating_sub(1),
+        _ => (),
+    }
+    match quota {
+        Some(quota) if u.stored_bytes > quota && new > old => {
+            Err(RepoError::QuotaExceededError(Quota {
+                scope,
+                quota,
+                stored_bytes: before,
+            }))
+        }
+        _ => Ok(()),
+    }
+}
+
+// The usage records a put of `new` bytes to `key`, or a delete if `new` is
+// None, leaves, refusing growth past a quota.
+fn account(
+    storage: &Arc<dyn StorageEngine>,
+    opts: &ServeOptions,
+    key: &str,
+    new: Option<u64>,
+) -> Result<Vec<(String, Usage)>, RepoError> {
+    let mut records = Vec::new();
+    if is_lock_key(key) {
+        return Ok(records);
+    }
+    let old = stored_size(storage, key)?;
+    if let Some(ref client) = opts.client {
+        let ukey = usage_key(client)?;
+        let mut u = read_usage(storage, &ukey)?;
+        charge(&mut u, QuotaScope::Client, opts.client_quota(), old, new)?;
+        records.push((ukey, u));
+    }
+    if let Some(quota) = opts.repo_quota() {
+        let mut u = read_repo_usage(storage)?;
+        charge(&mut u, QuotaScope::Repository, Some(quota), old, new)?;
+        records.push((REPO_USAGE_FILE.to_string(), u));
+    }
+    Ok(records)
+}
+
+// Credit the packs a sweep deleted to the repository's usage, if it is
+// kept, and nobody's else, see `usage`. Their indexes are left counted.
+fn credit_sweep(storage: &Arc<dyn StorageEngine>, stats: &GcStats) -> Result<(), RepoError> {
+    if !storage.exists(REPO_USAGE_FILE)? {
+        return Ok(());
+    }
+    let mut u = read_repo_usage(storage)?;
+    u.stored_bytes = u.stored_bytes.saturating_sub(stats.reclaimed_bytes);
+    u.objects = u.objects.saturating_sub(stats.deleted_packs.len() as u64);
+    storage.put(REPO_USAGE_FILE, &u.encode())
+}
+
+// The quotas that apply to the session, and how much of each is used.
+fn quotas(storage: &Arc<dyn StorageEngine>, opts: &ServeOptions) -> Result<Vec<Quota>, RepoError> {
+    let mut quotas = Vec::new();
+    if let (Some(client), Some(quota)) = (opts.client.as_deref(), opts.client_quota()) {
+        quotas.push(Quota {
+            scope: QuotaScope::Client,
+            quota,
+            stored_bytes: read_usage(storage, &usage_key(client)?)?.stored_bytes,
+        });
+    }
+    if let Some(quota) = opts.repo_quota() {
+        quotas.push(Quota {
+            scope: QuotaScope::Repository,
+            quota,
+            stored_bytes: read_repo_usage(storage)?.stored_bytes,
+        });
+    }
+    Ok(quotas)
+}
+
+fn handle(
+    storage: &Arc<dyn StorageEngine>,
+    opts: &ServeOptions,
+    req: &Request,
+) -> Result<Vec<u8>, RepoError> {
+    check_allowed(storage, opts, req)?;
+    check_not_revoked(storage, opts, req)?;
+    let mut resp = protocol::ok_response();
+    match *req {
+        Request::Put { key, data } => {
+            if is_usage_key(key)
+                || is_arrival_key(key)
+                || is_lock_owner_key(key)
+                || key == CHECK_FILE
+            {
+                return Err(RepoError::PermissionDeniedError);
+            }
+            // The policy and manifest are checked even when append only
+            // mode is off, or anyone could turn it on and replay old
+            // policies, or swap in a manifest the owner never signed.
+            let new_lock = is_lock_key(key) && !storage.exists(key)?;
+            if key == POLICY_FILE {
+                check_policy(storage, data)?;
+            } else if key == ACL_FILE {
+                check_acl(storage, data)?;
+            } else if key == REVOCATIONS_FILE {
+                check_revocations(storage, data)?;
+            } else if key == TRANSLOG_FILE {
+                check_translog(storage, data)?;
+            } else if key == MANIFEST_FILE {
+                check_manifest(storage, data)?;
+            } else if key == RETAINED_FILE {
+                check_retained(storage, data)?;
+            } else if is_lock_key(key) {
+                check_lock_owner(storage, opts, key)?;
+            } else if key != SCRUB_FILE && storage.exists(key)? {
+                let replaceable = match key {
+                    CONFIG_FILE => false,
+                    OWNER_KEY_FILE => {
+                        check_owner_key(storage, data)?;
+                        !append_only(storage, opts)?
+                            && read_acl(storage)?
+                                .is_none_or(|acl| acl.allows(opts.client.as_deref(), CAP_ADMIN))
+                    }
+                    _ => {
+                        !append_only(storage, opts)?
+                            && may_replace(storage, opts)?
+                            && !is_held(storage, key)?
+                    }
+                };
+                if !replaceable {
+                    return Err(RepoError::PermissionDeniedError);
+                }
+            }
+            let usage = account(storage, opts, key, Some(data.len() as u64))?;
+            storage.put(key, data)?;
+            for (ukey, u) in usage.iter() {
+                storage.put(ukey, &u.encode())?;
+            }
+            if let (true, Some(client)) = (new_lock, opts.client.as_deref()) {
+                storage.put(&lock_owner_key(key), client.as_bytes())?;
+            }
+        }
+        Request::Get { key } => {
+            resp.bytes(&storage.get(key)?);
+        }
+        Request::GetRange { key, offset, len } => {
+            resp.bytes(&storage.get_range(key, offset, len as usize)?);
+        }
+        Request::Size { key } => {
+            resp.u64(storage.size(key)?);
+        }
+        Request::List { prefix } => {
+            check_prefix(prefix)?;
+            let keys = storage.list_prefix(prefix)?;
+            resp.u32(keys.len() as u32);
+            for k in keys.iter() {
+                resp.str(k);
+            }
+        }
+        Request::Delete { key } => {
+            if is_usage_key(key)
+                || is_arrival_key(key)
+                || is_lock_owner_key(key)
+                || key == CHECK_FILE
+                || (!is_lock_key(key) && append_only(storage, opts)?)
+                || is_held(storage, key)?
+            {
+                return Err(RepoError::PermissionDeniedError);
+            }
+            if is_lock_key(key) {
+                check_lock_owner(storage, opts, key)?;
+            }
+            let usage = account(storage, opts, key, None)?;
+            storage.delete(key)?;
+            for (ukey, u) in usage.iter() {
+                storage.put(ukey, &u.encode())?;
+            }
+            if is_lock_key(key) {
+                match storage.delete(&lock_owner_key(key)) {
+                    Err(ref e) if e.is_not_found() => (),
+                    result => result?,
+                }
+            }
+        }
+        Request::Capabilities => {
+            let caps = storage.capabilities();
+            let caps = super::storage::Capabilities {
+                atomic_rename: false,
+                range_reads: caps.range_reads,
+                delete: caps.delete && !append_only(storage, opts)?,
+            };
+            protocol::encode_capabilities(&mut resp, &caps);
+        }
+        Request::Thaw { key } => {
+            protocol::encode_thaw_state(&mut resp, storage.thaw(key)?);
+        }
+        Request::Exists { key } => {
+            resp.bool(storage.exists(key)?);
+        }
+        // Only allowed at the top level, see `serve_batch`.
+        Request::Batch(_)
+        | Request::Challenge
+        | Request::Auth { .. }
+        | Request::Tenant { .. }
+        | Request::Session
+        | Request::Resume { .. }
+        | Request::Hello(_) => return Err(RepoError::UnsupportedOperationError),
+        Request::Gc { keep_list } => {
+            if append_only(storage, opts)? {
+                return Err(RepoError::PermissionDeniedError);
+            }
+            let stats = open_repo(storage)?.apply_keep_list(keep_list, unix_now())?;
+            credit_sweep(storage, &stats)?;
+            protocol::encode_gc_stats(&mut resp, &stats);
+        }
+        Request::Present(ref addresses) => {
+            let present = open_repo(storage)?.find_objects(addresses)?;
+            resp.bytes(&encode_bitmap(&present));
+        }
+        Request::Consistency { old, new } => {
+            let proof = open_repo(storage)?.local_translog_proof(old, new)?;
+            protocol::encode_consistency_proof(&mut resp, &proof);
+        }
+        Request::Quota => {
+            protocol::encode_quotas(&mut resp, &quotas(storage, opts)?);
+        }
+        Request::CheckStatus => {
+            protocol::encode_check_status(&mut resp, &check_status(&**storage, opts)?);
+        }
+    }
+    Ok(resp.into_vec())
+}
+
+// A client as the limiter and the log know it, with its tenant, clients of
+// different tenants may have the same name.
+fn qualified(opts: &ServeOptions, client: Option<&str>) -> Option<String> {
+    match opts.tenant {
+        Some(ref tenant) => Some(format!("{}/{}", tenant, client.unwrap_or(""))),
+        None => client.map(str::to_string),
+    }
+}
+
+// Handle `req` within the limits of the session's client, see `ratelimit`.
+fn handle_limited(
+    storage: &Arc<dyn StorageEngine>,
+    opts: &ServeOptions,
+    shared: &ServeShared,
+    req: &Request,
+) -> Result<Vec<u8>, RepoError> {
+    let client = qualified(opts, opts.client.as_deref()).unwrap_or_default();
+    let limits = opts.rate_limits();
+    let put = match *req {
+        Request::Put { data, .. } => data.len() as u64,
+        _ => 0,
+    };
+    shared.limiter.admit(&client, &limits, put)?;
+    let resp = match *req {
+        Request::Gc { .. } => {
+            let _running = shared.metrics.gc();
+            let resp = handle(storage, opts, req)?;
+            let mut d = protocol::open_response(&resp, "")?;
+            shared.metrics.gc_done(&protocol::decode_gc_stats(&mut d)?);
+            resp
+        }
+        _ => handle(storage, opts, req)?,
+    };
+    shared.limiter.charge(&client, &limits, resp.len() as u64);
+    Ok(resp)
+}
+
+pub(crate) fn open_log(path: &Path, opts: &ServeOptions) -> Result<OpLog, RepoError> {
+    let key_path = opts.log_key.as_ref().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "a log needs a key to seal it")
+    })?;
+    let key = Key::read_boxed_from(&mut File::open(key_path)?)?;
+    OpLog::open(path, key)
+}
+
+// The objects `req` concerns, for the log.
+fn addresses(req: &Request) -> Vec<Address> {
+    match *req {
+        Request::Present(ref addresses) => addresses.clone(),
+        Request::Put { key, data } if in_dir(key, INDEXES_DIR) => PackIndex::decode(data)
+            .map(|idx| idx.entries().iter().map(|e| e.address).collect())
+            .unwrap_or_default(),
+        _ if in_dir(req.key(), LOOSE_DIR) => Address::from_hex(&req.key()[LOOSE_DIR.len() + 1..])
+            .into_iter()
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Record `req` of the session `opts`, answered `resp`, if there is a log.
+fn log_request(
+    log: Option<&OpLog>,
+    opts: &ServeOptions,
+    req: &Request,
+    resp: &Result<Vec<u8>, RepoError>,
+) -> Result<(), RepoError> {
+    let log = match log {
+        Some(log) => log,
+        None => return Ok(()),
+    };
+    let client = match *req {
+        Request::Auth { client, .. } => qualified(opts, Some(client)),
+        _ => qualified(opts, opts.client.as_deref()),
+    };
+    log.record(&Operation {
+        time: unix_now(),
+        client,
+        op: req.name().to_string(),
+        key: req.key().to_string(),
+        error: resp.as_ref().err().map(|e| e.to_string()),
+        addresses: addresses(req),
+    })
+}
+
+// Count `req`, received in `received` bytes and answered `resp`.
+fn count_request(
+    metrics: &ServeMetrics,
+    req: &Request,
+    received: usize,
+    resp: &Result<Vec<u8>, RepoError>,
+) {
+    let sent = resp.as_ref().map_or(0, |resp| resp.len());
+    metrics.request(
+        req.name(),
+        required(req),
+        received as u64,
+        sent as u64,
+        resp.is_err(),
+    );
+}
+
+fn serve_batch(
+    storage: &Arc<dyn StorageEngine>,
+    opts: &ServeOptions,
+    log: Option<&OpLog>,
+    shared: &ServeShared,
+    resumable: Option<&Resumable>,
+    reqs: &[(u32, &[u8])],
+    w: &mut dyn Write,
+) -> Result<(), RepoError> {
+    if let Some(r) = resumable {
+        r.start_batch()?;
+    }
+    let served = serve_batch_requests(storage, opts, log, shared, resumable, reqs, w);
+    if let Some(r) = resumable {
+        r.end_batch();
+    }
+    served
+}
+
+fn serve_batch_requests(
+    storage: &Arc<dyn StorageEngine>,
+    opts: &ServeOptions,
+    log: Option<&OpLog>,
+    shared: &ServeShared,
+    resumable: Option<&Resumable>,
+    reqs: &[(u32, &[u8])],
+    w: &mut dyn Write,
+) -> Result<(), RepoError> {
+    let mut header = protocol::ok_response();
+    header.u32(reqs.len() as u32);
+    protocol::write_frame(w, &header.into_vec())?;
+
+    let next = &AtomicUsize::new(0);
+    let writes = &Mutex::new(());
+    let log_failure: &Mutex<Option<RepoError>> = &Mutex::new(None);
+    let (tx, rx) = channel();
+    thread::scope(|s| {
+        for _ in 0..BATCH_THREADS.min(reqs.len()) {
+            let tx = tx.clone();
+            s.spawn(move || {
+                while let Some(&(id, buf)) = reqs.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    let resp = match Request::decode(buf) {
+                        Ok(req) => {
+                            let _write = match req {
+                                Request::Put { .. }
+                                | Request::Delete { .. }
+                                | Request::Gc { .. } => Some(writes.lock().unwrap()),
+                                _ => None,
+                            };
+                            let resp = handle_limited(storage, opts, shared, &req);
+                            count_request(&shared.metrics, &req, buf.len(), &resp);
+                            if let Err(err) = log_request(log, opts, &req, &resp) {
+                                *log_failure.lock().unwrap() = Some(err);
+                                return;
+                            }
+                            resp
+                        }
+                        Err(err) => Err(err),
+                    };
+                    let resp = resp.unwrap_or_else(|err| protocol::err_response(&err));
+                    if let Some(r) = resumable {
+                        r.answered(id, &resp);
+                    }
+                    // The session ended, give up on the rest.
+                    if tx.send(protocol::tag_response(id, &resp)).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        drop(tx);
+        for resp in rx {
+            protocol::write_frame(w, &resp)?;
+        }
+        Ok::<(), RepoError>(())
+    })?;
+    let failure = log_failure.lock().unwrap().take();
+    match failure {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+// The storage sessions are served from, `storage` as the options keep it.
+fn session_storage(storage: Arc<dyn StorageEngine>, opts: &ServeOptions) -> Arc<dyn StorageEngine> {
+    match opts.delete_grace {
+        Some(grace) => Arc::new(GraceStorage::new(storage, grace)),
+        None => storage,
+    }
+}
+
+// The storage of the tenant `name` of `root`, which must have a repository.
+fn open_tenant(
+    root: &Arc<dyn StorageEngine>,
+    opts: &ServeOptions,
+    name: &str,
+) -> Result<Arc<dyn StorageEngine>, RepoError> {
+    let storage = tenant_storage(root, name)?;
+    if !storage.exists(CONFIG_FILE)? {
+        return Err(not_found(name));
+    }
+    Ok(session_storage(storage, opts))
+}
+
+// Answer requests until the client closes the stream. Failed requests are
+// reported to the client, only transport and framing errors end the session.
+pub fn serve(
+    storage: Arc<dyn StorageEngine>,
+    opts: &ServeOptions,
+    r: &mut dyn Read,
+    w: &mut dyn Write,
+) -> Result<(), RepoError> {
+    serve_shared(storage, opts, &ServeShared::new(), r, w)
+}
+
+// `serve`, holding clients to their limits in `shared` and counting what
+// the session does in its metrics, shared by sessions served at once.
+pub fn serve_shared(
+    storage: Arc<dyn StorageEngine>,
+    opts: &ServeOptions,
+    shared: &ServeShared,
+    r: &mut dyn Read,
+    w: &mut dyn Write,
+) -> Result<(), RepoError> {
+    if let Some(ref client) = opts.client {
+        usage_key(client)?;
+    }
+    let _session = shared.metrics.session();
+    let root = storage;
+    let mut storage = match opts.tenant {
+        Some(ref tenant) => open_tenant(&root, opts, tenant)?,
+        None if opts.tenants => root.clone(),
+        None => session_storage(root.clone(), opts),
+    };
+    let log = match opts.log {
+        Some(ref path) => Some(open_log(path, opts)?),
+        None => None,
+    };
+    // The session, its tenant once selected and client once authenticated.
+    let mut session = opts.clone();
+    let mut authenticated = !opts.authenticate;
+    let mut challenge = None;
+    let mut resumable: Option<Resumable> = None;
+    let mut first = true;
+    let mut greeted = false;
+    while let Some(frame) = protocol::read_frame(r)? {
+        let req = match Request::decode(&frame) {
+            Ok(req) => req,
+            Err(err) => {
+                protocol::write_frame(w, &protocol::err_response(&err))?;
+                continue;
+            }
+        };
+        // A connection whose session was resumed elsewhere is done.
+        if let Some(ref r) = resumable {
+            r.check_owner()?;
+        }
+        // Only a new connection can say hello, and resume a session, after
+        // it.
+        let fresh = std::mem::replace(&mut first, false);
+        let resp = match req {
+            Request::Hello(ref hello) if fresh && !greeted => {
+                greeted = true;
+                first = true;
+                hello.negotiate().map(|hello| {
+                    let mut resp = protocol::ok_response();
+                    protocol::encode_hello(&mut resp, &hello);
+                    resp.into_vec()
+                })
+            }
+            Request::Resume { ref token, batch } if fresh => (|| -> Result<Vec<u8>, RepoError> {
+                let (r, tenant, client, resumed) = shared.sessions.resume(token, batch, opts)?;
+                if let Some(ref tenant) = tenant {
+                    storage = open_tenant(&root, opts, tenant)?;
+                }
+                session.tenant = tenant;
+                session.client = client;
+                authenticated = true;
+                resumable = Some(r);
+                let mut resp = protocol::ok_response();
+                protocol::encode_resumed(&mut resp, &resumed);
+                Ok(resp.into_vec())
+            })(),
+            Request::Tenant { name } if opts.tenants && session.tenant.is_none() => {
+                open_tenant(&root, opts, name).map(|tenant| {
+                    storage = tenant;
+                    session.tenant = Some(name.to_string());
+                    protocol::ok_response().into_vec()
+                })
+            }
+            _ if opts.tenants && session.tenant.is_none() => Err(RepoError::PermissionDeniedError),
+            Request::Challenge => {
+                let mut c = [0; CHALLENGE_SZ];
+                random_bytes(&mut c);
+                challenge = Some(c);
+                let mut resp = protocol::ok_response();
+                resp.fixed(&c);
+                protocol::write_frame(w, &resp.into_vec())?;
+                continue;
+            }
+            Request::Auth { client, signed } => {
+                authenticate(&storage, opts, challenge.take(), client, signed).map(|client| {
+                    session.client = Some(client);
+                    authenticated = true;
+                    protocol::ok_response().into_vec()
+                })
+            }
+            _ if !authenticated => Err(RepoError::PermissionDeniedError),
+            Request::Session => {
+                let (token, r) = shared.sessions.open(&session);
+                resumable = Some(r);
+                let mut resp = protocol::ok_response();
+                resp.fixed(&token);
+                Ok(resp.into_vec())
+            }
+            Request::Batch(ref reqs) => {
+                let r = resumable.as_ref();
+                serve_batch(&storage, &session, log.as_ref(), shared, r, reqs, w)?;
+                continue;
+            }
+            ref req => handle_limited(&storage, &session, shared, req),
+        };
+        count_request(&shared.metrics, &req, frame.len(), &resp);
+        log_request(log.as_ref(), &session, &req, &resp)?;
+        let resp = match resp {
+            Ok(resp) => resp,
+            Err(err) => protocol::err_response(&err),
+        };
+        protocol::write_frame(w, &resp)?;
+    }
+    if let Some(log) = log {
+        log.seal()?;
+    }
+    Ok(())
+}
+
+impl ServeOptions {
+    // The arguments of `packnback serve` after "serve",
+    // `[--append-only] [--authenticate] [--client name] [--quota size]
+    // [--client-quota name size]... [--repo-quota size]
+    // [--tenant-quota name size]...
+    // [--delete-grace duration] [--log path --log-key path]
+    // [--max-requests rate] [--max-bandwidth rate]
+    // [--client-limit name requests bandwidth]... [--tenants]
+    // [--tenant name] [--socket path] dir`, with sizes and
+    // rates such as 20g or off and durations such as 30d, returning the
+    // repository directory.
+    pub fn parse_args(args: &[String]) -> Result<(PathBuf, ServeOptions), RepoError> {
+        let usage = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "usage: serve [--append-only] [--authenticate] [--client name] [--quota size] \
+                 [--client-quota name size]... [--repo-quota size] \
+                 [--tenant-quota name size]... \
+                 [--delete-grace duration] [--log path --log-key path] \
+                 [--max-requests rate] [--max-bandwidth rate] \
+                 [--client-limit name requests bandwidth]... [--tenants] \
+                 [--tenant name] [--socket path] [--check-every duration] dir",
+            )
+            .into()
+        };
+        let mut opts = ServeOptions::default();
+        let mut dir = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--append-only" => opts.append_only = true,
+                "--authenticate" => opts.authenticate = true,
+                "--client" => opts.client = Some(args.next().ok_or_else(usage)?.clone()),
+                "--quota" => opts.quota = parse_quota(args.next().ok_or_else(usage)?)?,
+                "--client-quota" => {
+                    let client = args.next().ok_or_else(usage)?;
+                    usage_key(client)?;
+                    let quota = parse_quota(args.next().ok_or_else(usage)?)?;
+                    opts.client_quotas.insert(client.clone(), quota);
+                }
+                "--repo-quota" => opts.repo_quota = parse_quota(args.next().ok_or_else(usage)?)?,
+                "--tenant-quota" => {
+                    let tenant = args.next().ok_or_else(usage)?;
+                    check_tenant(tenant)?;
+                    let quota = parse_quota(args.next().ok_or_else(usage)?)?;
+                    opts.tenant_quotas.insert(tenant.clone(), quota);
+                }
+                "--delete-grace" => {
+                    let grace = parse_duration(args.next().ok_or_else(usage)?);
+                    opts.delete_grace = Some(grace.ok_or_else(usage)?);
+                }
+                "--log" => opts.log = Some(PathBuf::from(args.next().ok_or_else(usage)?)),
+                "--log-key" => opts.log_key = Some(PathBuf::from(args.next().ok_or_else(usage)?)),
+                "--max-requests" => {
+                    opts.rate.requests = parse_rate(args.next().ok_or_else(usage)?)?
+                }
+                "--max-bandwidth" => {
+                    opts.rate.bandwidth = parse_rate(args.next().ok_or_else(usage)?)?
+                }
+                "--client-limit" => {
+                    let client = args.next().ok_or_else(usage)?;
+                    usage_key(client)?;
+                    let limits = RateLimits {
+                        requests: parse_rate(args.next().ok_or_else(usage)?)?,
+                        bandwidth: parse_rate(args.next().ok_or_else(usage)?)?,
+                    };
+                    opts.client_rates.insert(client.clone(), limits);
+                }
+                "--tenants" => opts.tenants = true,
+                "--tenant" => {
+                    let tenant = args.next().ok_or_else(usage)?;
+                    check_tenant(tenant)?;
+                    opts.tenant = Some(tenant.clone());
+                }
+                "--socket" => opts.socket = Some(PathBuf::from(args.next().ok_or_else(usage)?)),
+                "--check-every" => {
+                    let every = parse_duration(args.next().ok_or_else(usage)?);
+                    opts.check_every = Some(every.ok_or_else(usage)?);
+                }
+                _ if arg.starts_with('-') || dir.is_some() => return Err(usage()),
+                _ => dir = Some(PathBuf::from(arg)),
+            }
+        }
+        if let Some(ref client) = opts.client {
+            usage_key(client)?;
+        }
+        if opts.log.is_some() != opts.log_key.is_some() {
+            return Err(usage());
+        }
+        Ok((dir.ok_or_else(usage)?, opts))
+    }
+
+    // The quota of the session's client.
+    fn client_quota(&self) -> Option<u64> {
+        let client = self.client.as_ref()?;
+        self.client_quotas
+            .get(client)
+            .copied()
+            .unwrap_or(self.quota)
+    }
+
+    // The quota of the session's repository.
+    fn repo_quota(&self) -> Option<u64> {
+        self.tenant
+            .as_ref()
+            .and_then(|tenant| self.tenant_quotas.get(tenant))
+            .copied()
+            .unwrap_or(self.repo_quota)
+    }
+
+    // The limits of the session's client.
+    fn rate_limits(&self) -> RateLimits {
+        self.client
+            .as_ref()
+            .and_then(|client| self.client_rates.get(client))
+            .copied()
+            .unwrap_or(self.rate)
+    }
+}
+
+// Serve every connection accepted on `listener`, until accepting fails.
+// Each session has a thread, and all share `shared`, as do the checks
+// scheduled, see `checks`.
+pub fn serve_unix(
+    listener: UnixListener,
+    storage: Arc<dyn StorageEngine>,
+    opts: &ServeOptions,
+    shared: &ServeShared,
+) -> Result<(), RepoError> {
+    thread::scope(|s| {
+        s.spawn(|| schedule_checks(&storage, opts, &shared.checks));
+        let accepted = (|| -> Result<(), RepoError> {
+            for conn in listener.incoming() {
+                let conn = conn?;
+                let storage = storage.clone();
+                // One client's broken connection is no reason to stop.
+                s.spawn(move || -> Result<(), RepoError> {
+                    let mut r = BufReader::new(conn.try_clone()?);
+                    let mut w = BufWriter::new(conn);
+                    serve_shared(storage, opts, shared, &mut r, &mut w)
+                });
+            }
+            Ok(())
+        })();
+        shared.checks.stop();
+        accepted
+    })
+}
+
+// Listen on the unix socket at `path`, replacing the socket of a server
+// no longer running. Anything else there is left alone.
+fn bind_socket(path: &Path) -> Result<UnixListener, RepoError> {
+    match fs::symlink_metadata(path) {
+        Ok(m) if m.file_type().is_socket() => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::from(io::ErrorKind::AddrInUse).into());
+            }
+            fs::remove_file(path)?;
+        }
+        _ => (),
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+// A quota such as 20g, or off for none.
+fn parse_quota(s: &str) -> Result<Option<u64>, RepoError> {
+    if s == "off" {
+        return Ok(None);
+    }
+    match parse_size(s) {
+        Some(size) => Ok(Some(size)),
+        None => {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid size '{}'", s)).into())
+        }
+    }
+}
+
+// Serve the repository in `dir` over stdin and stdout, or to every client
+// of `ServeOptions::socket`.
+pub fn serve_dir(dir: &Path, opts: &ServeOptions) -> Result<(), RepoError> {
+    let storage: Arc<dyn StorageEngine> = Arc::new(LocalStorage::new(dir)?);
+    if let Some(ref path) = opts.socket {
+        return serve_unix(bind_socket(path)?, storage, opts, &ServeShared::new());
+    }
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut r = BufReader::new(stdin.lock());
+    let mut w = BufWriter::new(stdout.lock());
+    serve(storage, opts, &mut r, &mut w)
+}
+
+// Tests --------------------
+
+#[cfg(test)]
+fn test_exchange(
+    storage: Arc<dyn StorageEngine>,
+    opts: &ServeOptions,
+    req: &Request,
+) -> Result<Vec<u8>, RepoError> {
+    let mut input = Vec::new();
+    protocol::write_frame(&mut input, &req.encode()).unwrap();
+    let mut output = Vec::new();
+    serve(storage, opts, &mut &input[..], &mut output).unwrap();
+    let mut r = &output[..];
+    let resp = protocol::read_frame(&mut r).unwrap().unwrap();
+    assert!(protocol::read_frame(&mut r).unwrap().is_none());
+    let mut d = protocol::open_response(&resp, req.key())?;
+    let v = d.fixed(d.remaining())?.to_vec();
+    Ok(v)
+}
+
+// `m` as the manifest to follow the one stored.
+#[cfg(test)]
+fn test_next_manifest(r: &Repo, m: &Manifest) -> Manifest {
+    let sm = r.storage().get(MANIFEST_FILE).unwrap();
+    Manifest {
+        counter: r.manifest().unwrap().counter + 1,
+        previous: manifest_hash(&sm),
+        ..m.clone()
+    }
+}
+
+#[test]
+fn test_serve_append_only() {
+    let (r, key) = crate::test_repo();
+    let storage = r.storage().clone();
+    let opts = ServeOptions {
+        append_only: true,
+        ..Default::default()
+    };
+    let put =
+        |key: &str, data: &[u8]| test_exchange(storage.clone(), &opts, &Request::Put { key, data });
+
+    put("packs/00", b"pack").unwrap();
+    match put("packs/00", b"replaced") {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected overwrite to be refused"),
+    }
+    match put(crate::CONFIG_FILE, b"garbage") {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected config overwrite to be refused"),
+    }
+    match test_exchange(storage.clone(), &opts, &Request::Delete { key: "packs/00" }) {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected delete to be refused"),
+    }
+    assert_eq!(storage.get("packs/00").unwrap(), b"pack");
+    put("locks/00", b"lock").unwrap();
+    put("locks/00", b"heartbeat").unwrap();
+    test_exchange(storage.clone(), &opts, &Request::Delete { key: "locks/00" }).unwrap();
+
+    // Manifests must be signed by the owner and belong to this repository.
+    let first = r.manifest().unwrap();
+    let m = test_next_manifest(&r, &first);
+    put(MANIFEST_FILE, &m.sign(&key.sign_sk)).unwrap();
+    let next = test_next_manifest(&r, &m);
+    let other = asymcrypt::Key::new();
+    assert!(put(MANIFEST_FILE, &next.sign(&other.sign_sk)).is_err());
+    let mut foreign = next.clone();
+    foreign.repo_id = crate::manifest::RepoId::new();
+    assert!(put(MANIFEST_FILE, &foreign.sign(&key.sign_sk)).is_err());
+    assert!(put(MANIFEST_FILE, b"garbage").is_err());
+
+    // And follow the stored one, old manifests cannot be replayed.
+    let mut skipped = next.clone();
+    skipped.counter += 1;
+    let mut forked = next.clone();
+    forked.previous = [1; MANIFEST_HASH_SZ];
+    for old in [first, m.clone(), skipped, forked].iter() {
+        match put(MANIFEST_FILE, &old.sign(&key.sign_sk)) {
+            Err(RepoError::PermissionDeniedError) => (),
+            _ => panic!("expected a manifest out of order to be refused"),
+        }
+    }
+    assert_eq!(r.manifest().unwrap(), m);
+
+    let caps = test_exchange(storage.clone(), &opts, &Request::Capabilities).unwrap();
+    assert_eq!(caps, vec![0, 1, 0]);
+}
+
+#[test]
+fn test_serve_retention() {
+    let (r, key) = crate::test_repo();
+    let storage = r.storage().clone();
+    let opts = Default::default();
+    let put =
+        |key: &str, data: &[u8]| test_exchange(storage.clone(), &opts, &Request::Put { key, data });
+    let delete = |key: &str| test_exchange(storage.clone(), &opts, &Request::Delete { key });
+    let chunk = crate::address::Address { bytes: [1; 32] };
+    let head = crate::gc::test_commit_tree(&r, &key, 1, &[chunk]);
+
+    // Expired locks protect nothing.
+    let mut m = test_next_manifest(&r, &r.manifest().unwrap());
+    m.retain(&head.address, 1).unwrap();
+    put(MANIFEST_FILE, &m.sign(&key.sign_sk)).unwrap();
+    put("packs/00", b"pack").unwrap();
+    delete("packs/00").unwrap();
+
+    let until = unix_now() + 3600;
+    m = test_next_manifest(&r, &m);
+    m.retain(&head.address, until).unwrap();
+    put(MANIFEST_FILE, &m.sign(&key.sign_sk)).unwrap();
+    m = test_next_manifest(&r, &m);
+    let mut dropped = m.clone();
+    dropped.remove_head(&head.address);
+    let mut shortened = m.clone();
+    shortened.heads[0].retain_until = until - 1;
+    for new in [dropped.clone(), shortened].iter() {
+        match put(MANIFEST_FILE, &new.sign(&key.sign_sk)) {
+            Err(RepoError::RetentionLockedError) => (),
+            _ => panic!("expected retention lock"),
+        }
+    }
+    m.retain(&head.address, until + 1).unwrap();
+    put(MANIFEST_FILE, &m.sign(&key.sign_sk)).unwrap();
+    assert_eq!(r.manifest().unwrap(), m);
+
+    // Without a list of the packs it needs a lock holds everything.
+    put("packs/00", b"pack").unwrap();
+    match delete("packs/00") {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected delete to be refused"),
+    }
+    r.retain_snapshot(&head.address, until + 2, &key).unwrap();
+    match r.commit_manifest(&dropped, &key) {
+        Err(RepoError::RetentionLockedError) => (),
+        _ => panic!("expected retention lock"),
+    }
+
+    // With one, what the locked snapshot needs cannot be deleted,
+    // overwritten or dropped from the list while the lock holds.
+    let retained = r.retained().unwrap().unwrap();
+    let id = retained.entry(&head.address).unwrap().packs[0];
+    for dir in [crate::PACKS_DIR, crate::INDEXES_DIR].iter() {
+        let held = format!("{}/{}", dir, id.to_hex());
+        match delete(&held) {
+            Err(RepoError::PermissionDeniedError) => (),
+            _ => panic!("expected delete to be refused"),
+        }
+        match put(&held, b"garbage") {
+            Err(RepoError::PermissionDeniedError) => (),
+            _ => panic!("expected overwrite to be refused"),
+        }
+    }
+    let lifted = crate::retained::Retained {
+        entries: Vec::new(),
+        ..retained
+    };
+    match put(RETAINED_FILE, &lifted.sign(&key.sign_sk)) {
+        Err(RepoError::RetentionLockedError) => (),
+        _ => panic!("expected retention lock"),
+    }
+
+    // Everything else may go, and a sweep passes over what it holds.
+    delete("packs/00").unwrap();
+    crate::gc::test_commit_tree(&r, &key, 2, &[crate::address::Address { bytes: [2; 32] }]);
+    assert_eq!(r.list_packs().unwrap().len(), 2);
+    let now = unix_now() + 60;
+    let list = crate::keeplist::KeepList::new(r.config().repo_id, now, now, Vec::new());
+    test_exchange(
+        storage.clone(),
+        &opts,
+        &Request::Gc {
+            keep_list: &list.sign(&key.sign_sk),
+        },
+    )
+    .unwrap();
+    assert_eq!(r.list_packs().unwrap(), vec![id]);
+}
+
+#[test]
+fn test_serve_policy() {
+    let key = asymcrypt::Key::new();
+    let maintenance = asymcrypt::Key::new();
+    let config = crate::config::RepoConfig {
+        append_only: true,
+        maintenance_pk: Some(maintenance.sign_pk.clone()),
+        ..Default::default()
+    };
+    let storage: Arc<dyn StorageEngine> = Arc::new(crate::storage::mem::MemStorage::new());
+    let r = Repo::init(storage.clone(), config, &key).unwrap();
+    let opts = ServeOptions::default();
+    let req = |req: &Request| test_exchange(storage.clone(), &opts, req);
+    let put_policy = |append_only: bool, serial: u64, sk: &tweetnacl::CryptoSignSk| {
+        let p = Policy {
+            serial,
+            append_only,
+            ..r.policy().clone()
+        };
+        req(&Request::Put {
+            key: POLICY_FILE,
+            data: &p.sign(sk),
+        })
+    };
+
+    match req(&Request::Delete { key: CONFIG_FILE }) {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected delete to be refused"),
+    }
+    assert!(put_policy(false, 1, &key.sign_sk).is_err());
+    assert!(put_policy(false, 0, &maintenance.sign_sk).is_err());
+    assert_eq!(req(&Request::Capabilities).unwrap(), vec![0, 1, 0]);
+
+    put_policy(false, 1, &maintenance.sign_sk).unwrap();
+    assert_eq!(req(&Request::Capabilities).unwrap(), vec![0, 1, 1]);
+    put_policy(true, 2, &maintenance.sign_sk).unwrap();
+    match put_policy(false, 1, &maintenance.sign_sk) {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected an old policy to be refused"),
+    }
+    assert_eq!(req(&Request::Capabilities).unwrap(), vec![0, 1, 0]);
+
+    // The server option wins over any policy.
+    put_policy(false, 3, &maintenance.sign_sk).unwrap();
+    let forced = ServeOptions {
+        append_only: true,
+        ..Default::default()
+    };
+    match test_exchange(
+        storage.clone(),
+        &forced,
+        &Request::Delete { key: CONFIG_FILE },
+    ) {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected delete to be refused"),
+    }
+    req(&Request::Delete { key: CONFIG_FILE }).unwrap();
+}
+
+#[test]
+fn test_serve_requests() {
+    let storage: Arc<dyn StorageEngine> = Arc::new(crate::storage::mem::MemStorage::new());
+    let opts = ServeOptions::default();
+    storage.put("a/b", b"hello").unwrap();
+
+    let get = test_exchange(storage.clone(), &opts, &Request::Get { key: "a/b" }).unwrap();
+    assert_eq!(get, b"\x00\x00\x00\x05hello");
+    let size = test_exchange(storage.clone(), &opts, &Request::Size { key: "a/b" }).unwrap();
+    assert_eq!(size, 5u64.to_be_bytes());
+    assert!(
+        test_exchange(storage.clone(), &opts, &Request::Get { key: "a/c" })
+            .err()
+            .unwrap()
+            .is_not_found()
+    );
+    match test_exchange(storage.clone(), &opts, &Request::List { prefix: "../" }) {
+        Err(RepoError::InvalidKeyError) => (),
+        _ => panic!("expected invalid prefix"),
+    }
+    test_exchange(storage.clone(), &opts, &Request::Delete { key: "a/b" }).unwrap();
+    assert!(!storage.exists("a/b").unwrap());
+
+    // Garbage requests get an error response, not a dropped session.
+    let mut input = Vec::new();
+    protocol::write_frame(&mut input, &[99]).unwrap();
+    protocol::write_frame(&mut input, &Request::Capabilities.encode()).unwrap();
+    let mut output = Vec::new();
+    serve(storage, &opts, &mut &input[..], &mut output).unwrap();
+    let mut r = &output[..];
+    let resp = protocol::read_frame(&mut r).unwrap().unwrap();
+    assert!(protocol::open_response(&resp, "").is_err());
+    let resp = protocol::read_frame(&mut r).unwrap().unwrap();
+    assert!(protocol::open_response(&resp, "").is_ok());
+
+    // A truncated frame ends the session with an error.
+    let mut input = Vec::new();
+    protocol::write_frame(&mut input, &Request::Capabilities.encode()).unwrap();
+    let storage: Arc<dyn StorageEngine> = Arc::new(crate::storage::mem::MemStorage::new());
+    assert!(serve(storage, &opts, &mut &input[..3], &mut Vec::new()).is_err());
+}
+
+#[test]
+fn test_serve_batch() {
+    let storage: Arc<dyn StorageEngine> = Arc::new(crate::storage::mem::MemStorage::new());
+    let opts = ServeOptions::default();
+    storage.put("a", b"hello").unwrap();
+    let reqs: Vec<Vec<u8>> = vec![
+        Request::Put {
+            key: "b",
+            data: b"world",
+        }
+        .encode(),
+        Request::Exists { key: "a" }.encode(),
+        Request::Exists { key: "c" }.encode(),
+        Request::Get { key: "c" }.encode(),
+        Request::Batch(Vec::new()).encode(),
+        vec![99],
+    ];
+    let batch = Request::Batch(
+        reqs.iter()
+            .enumerate()
+            .map(|(i, r)| (100 + i as u32, &r[..]))
+            .collect(),
+    );
+    let mut input = Vec::new();
+    protocol::write_frame(&mut input, &batch.encode()).unwrap();
+    protocol::write_frame(&mut input, &Request::Capabilities.encode()).unwrap();
+    let mut output = Vec::new();
+    serve(storage.clone(), &opts, &mut &input[..], &mut output).unwrap();
+
+    let mut r = &output[..];
+    let header = protocol::read_frame(&mut r).unwrap().unwrap();
+    let mut d = protocol::open_response(&header, "").unwrap();
+    assert_eq!(d.u32().unwrap(), 6);
+    let mut resps = std::collections::BTreeMap::new();
+    for _ in 0..6 {
+        let buf = protocol::read_frame(&mut r).unwrap().unwrap();
+        let (id, resp) = protocol::untag_response(&buf).unwrap();
+        resps.insert(id, resp.to_vec());
+    }
+    let ok = |id: u32| {
+        let mut d = protocol::open_response(&resps[&id], "").unwrap();
+        d.fixed(d.remaining()).unwrap().to_vec()
+    };
+    assert_eq!(ok(100), b"");
+    assert_eq!(ok(101), [1]);
+    assert_eq!(ok(102), [0]);
+    assert!(protocol::open_response(&resps[&103], "c")
+        .err()
+        .unwrap()
+        .is_not_found());
+    for id in [104, 105].iter() {
+        match protocol::open_response(&resps[id], "") {
+            Err(RepoError::UnsupportedOperationError) => (),
+            _ => panic!("expected unsupported operation"),
+        }
+    }
+    assert_eq!(storage.get("b").unwrap(), b"world");
+    // The session carries on after the batch.
+    let resp = protocol::read_frame(&mut r).unwrap().unwrap();
+    assert!(protocol::open_response(&resp, "").is_ok());
+    assert!(protocol::read_frame(&mut r).unwrap().is_none());
+}
+
+#[test]
+fn test_serve_quota() {
+    let (r, _) = crate::test_repo();
+    let storage = r.storage().clone();
+    let opts = ServeOptions {
+        client: Some("laptop".to_string()),
+        quota: Some(10),
+        ..Default::default()
+    };
+    let req = |req: &Request| test_exchange(storage.clone(), &opts, req);
+    let usage = || r.usage().unwrap();
+
+    req(&Request::Put {
+        key: "packs/00",
+        data: b"12345678",
+    })
+    .unwrap();
+    req(&Request::Put {
+        key: "locks/00",
+        data: b"not counted",
+    })
+    .unwrap();
+    match req(&Request::Put {
+        key: "packs/01",
+        data: b"123",
+    }) {
+        Err(RepoError::QuotaExceededError(q)) => {
+            assert_eq!(
+                (q.scope, q.quota, q.stored_bytes),
+                (QuotaScope::Client, 10, 8)
+            )
+        }
+        _ => panic!("expected the quota to be enforced"),
+    }
+    assert!(!storage.exists("packs/01").unwrap());
+    let expected = Usage {
+        stored_bytes: 8,
+        objects: 1,
+    };
+    assert_eq!(usage(), vec![("laptop".to_string(), expected)]);
+
+    // Shrinking and deleting is always allowed and credited.
+    req(&Request::Put {
+        key: "packs/00",
+        data: b"1234",
+    })
+    .unwrap();
+    req(&Request::Put {
+        key: "packs/01",
+        data: b"123456",
+    })
+    .unwrap();
+    req(&Request::Delete { key: "packs/00" }).unwrap();
+    let expected = Usage {
+        stored_bytes: 6,
+        objects: 1,
+    };
+    assert_eq!(usage(), vec![("laptop".to_string(), expected)]);
+
+    let quotas = req(&Request::Quota).unwrap();
+    let mut d = crate::wire::Decoder::new(&quotas);
+    let client = Quota {
+        scope: QuotaScope::Client,
+        quota: 10,
+        stored_bytes: 6,
+    };
+    assert_eq!(protocol::decode_quotas(&mut d).unwrap(), vec![client]);
+
+    // Clients cannot touch usage records.
+    for key in ["usage/laptop", "usage/other", REPO_USAGE_FILE].iter() {
+        match req(&Request::Put { key, data: b"" }) {
+            Err(RepoError::PermissionDeniedError) => (),
+            _ => panic!("expected usage writes to be refused"),
+        }
+    }
+    match req(&Request::Delete {
+        key: "usage/laptop",
+    }) {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected usage deletes to be refused"),
+    }
+
+    let bad = ServeOptions {
+        client: Some("../x".to_string()),
+        ..Default::default()
+    };
+    assert!(serve(storage.clone(), &bad, &mut &b""[..], &mut Vec::new()).is_err());
+
+    // The repository quota counts what is already stored, whoever stored
+    // it, and some clients may have quotas of their own.
+    let stored = read_repo_usage(&storage).unwrap().stored_bytes;
+    let mut opts = ServeOptions {
+        client: Some("nas".to_string()),
+        quota: Some(10),
+        repo_quota: Some(stored + 100),
+        ..Default::default()
+    };
+    opts.client_quotas.insert("nas".to_string(), None);
+    let req = |req: &Request| test_exchange(storage.clone(), &opts, req);
+    req(&Request::Put {
+        key: "packs/02",
+        data: &[0; 60],
+    })
+    .unwrap();
+    match req(&Request::Put {
+        key: "packs/03",
+        data: &[0; 60],
+    }) {
+        Err(RepoError::QuotaExceededError(q)) => {
+            assert_eq!(q.scope, QuotaScope::Repository);
+            assert_eq!(q.headroom(), 40);
+        }
+        _ => panic!("expected the repository quota to be enforced"),
+    }
+    let quotas = req(&Request::Quota).unwrap();
+    let mut d = crate::wire::Decoder::new(&quotas);
+    let repo = Quota {
+        scope: QuotaScope::Repository,
+        quota: stored + 100,
+        stored_bytes: stored + 60,
+    };
+    assert_eq!(protocol::decode_quotas(&mut d).unwrap(), vec![repo]);
+}
+
+#[test]
+fn test_serve_args() {
+    let args = |s: &str| -> Vec<String> { s.split_whitespace().map(String::from).collect() };
+    let (dir, opts) =
+        ServeOptions::parse_args(&args("--append-only --client laptop --quota 2g /srv/repo"))
+            .unwrap();
+    let (_, authed) = ServeOptions::parse_args(&args("--authenticate /srv/repo")).unwrap();
+    assert!(authed.authenticate);
+    assert_eq!(dir, PathBuf::from("/srv/repo"));
+    assert_eq!(
+        opts,
+        ServeOptions {
+            append_only: true,
+            client: Some("laptop".to_string()),
+            quota: Some(2 << 30),
+            authenticate: false,
+            delete_grace: None,
+            log: None,
+            log_key: None,
+            rate: Default::default(),
+            client_rates: Default::default(),
+            client_quotas: Default::default(),
+            repo_quota: None,
+            tenant_quotas: Default::default(),
+            tenants: false,
+            tenant: None,
+            socket: None,
+            check_every: None,
+        }
+    );
+    let (_, local) = ServeOptions::parse_args(&args("--socket /run/pnb.sock /srv/repo")).unwrap();
+    assert_eq!(local.socket, Some(PathBuf::from("/run/pnb.sock")));
+    let (_, hosting) = ServeOptions::parse_args(&args("--tenants /srv/repos")).unwrap();
+    assert!(hosting.tenants);
+    let (_, fixed) = ServeOptions::parse_args(&args("--tenant design /srv/repos")).unwrap();
+    assert_eq!(fixed.tenant.as_deref(), Some("design"));
+    let (_, quotas) = ServeOptions::parse_args(&args(
+        "--client-quota nas off --repo-quota 1024g --tenant-quota design 100g /srv/repos",
+    ))
+    .unwrap();
+    assert_eq!(quotas.client_quotas["nas"], None);
+    assert_eq!(quotas.repo_quota, Some(1 << 40));
+    assert_eq!(quotas.tenant_quotas["design"], Some(100 << 30));
+    let (_, limited) = ServeOptions::parse_args(&args(
+        "--max-requests 100 --max-bandwidth 10m --client-limit nas off 1g /srv/repo",
+    ))
+    .unwrap();
+    assert_eq!(limited.rate.requests, Some(100));
+    assert_eq!(limited.rate.bandwidth, Some(10 << 20));
+    let nas = limited.client_rates["nas"];
+    assert_eq!((nas.requests, nas.bandwidth), (None, Some(1 << 30)));
+    let (_, graced) = ServeOptions::parse_args(&args("--delete-grace 30d /srv/repo")).unwrap();
+    assert_eq!(graced.delete_grace, Some(30 * 24 * 60 * 60));
+    let (_, checked) = ServeOptions::parse_args(&args("--check-every 1w /srv/repo")).unwrap();
+    assert_eq!(checked.check_every, Some(7 * 24 * 60 * 60));
+    let (_, logged) =
+        ServeOptions::parse_args(&args("--log /var/log/pnb --log-key /etc/pnb.key /srv/repo"))
+            .unwrap();
+    assert_eq!(logged.log, Some(PathBuf::from("/var/log/pnb")));
+    assert_eq!(logged.log_key, Some(PathBuf::from("/etc/pnb.key")));
+    let (_, opts) = ServeOptions::parse_args(&args("/srv/repo")).unwrap();
+    assert_eq!(opts, ServeOptions::default());
+    for bad in [
+        "",
+        "--client",
+        "--quota lots /srv/repo",
+        "--repo-quota 0 /srv/repo",
+        "--tenant-quota design 2t /srv/repos",
+        "--delete-grace soon /srv/repo",
+        "--log /var/log/pnb /srv/repo",
+        "--delete-all /srv/repo",
+        "/srv/a /srv/b",
+        "--client ../x /srv/repo",
+        "--client-limit nas 10 /srv/repo",
+        "--max-requests 0 /srv/repo",
+        "--tenant a/b /srv/repos",
+        "--client-quota nas /srv/repo",
+        "--tenant-quota ../x 1g /srv/repos",
+        "--socket",
+        "--check-every often /srv/repo",
+    ]
+    .iter()
+    {
+        assert!(ServeOptions::parse_args(&args(bad)).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn test_serve_delete_grace() {
+    use crate::keeplist::KeepList;
+    use crate::storage::grace::arrival_key;
+    let storage: Arc<dyn StorageEngine> = Arc::new(crate::storage::mem::MemStorage::new());
+    let opts = ServeOptions {
+        delete_grace: Some(3600),
+        ..Default::default()
+    };
+    let req = |req: &Request| test_exchange(storage.clone(), &opts, req);
+    req(&Request::Put {
+        key: "a/b",
+        data: b"hello",
+    })
+    .unwrap();
+    let stamp = arrival_key("a/b");
+    for refused in [
+        Request::Delete { key: "a/b" },
+        Request::Put {
+            key: "a/b",
+            data: b"bye",
+        },
+        Request::Delete { key: &stamp },
+        Request::Put {
+            key: &stamp,
+            data: b"",
+        },
+    ]
+    .iter()
+    {
+        match req(refused) {
+            Err(RepoError::PermissionDeniedError) => (),
+            _ => panic!("expected {:?} to be refused", refused),
+        }
+    }
+    assert!(req(&Request::Get { key: &stamp }).is_ok());
+    // Served without a grace period it can go.
+    test_exchange(
+        storage.clone(),
+        &Default::default(),
+        &Request::Delete { key: "a/b" },
+    )
+    .unwrap();
+
+    // A sweep passes over young packs, whatever the keep list says.
+    let storage: Arc<dyn StorageEngine> = Arc::new(crate::storage::mem::MemStorage::new());
+    let key = asymcrypt::Key::new();
+    let graced = Arc::new(GraceStorage::new(storage.clone(), 3600));
+    let r = Repo::init(graced, Default::default(), &key).unwrap();
+    crate::gc::test_commit_tree(&r, &key, 1, &[crate::address::Address { bytes: [1; 32] }]);
+    let now = unix_now() + 60;
+    let list = KeepList::new(r.config().repo_id, now, now, Vec::new());
+    test_exchange(
+        storage,
+        &opts,
+        &Request::Gc {
+            keep_list: &list.sign(&key.sign_sk),
+        },
+    )
+    .unwrap();
+    assert_eq!(r.list_packs().unwrap().len(), 1);
+}
+
+#[test]
+fn test_serve_acl() {
+    use crate::acl::parse_capabilities;
+    use std::collections::BTreeMap;
+    let key = asymcrypt::Key::new();
+    let maintenance = asymcrypt::Key::new();
+    let config = crate::config::RepoConfig {
+        maintenance_pk: Some(maintenance.sign_pk.clone()),
+        ..Default::default()
+    };
+    let storage: Arc<dyn StorageEngine> = Arc::new(crate::storage::mem::MemStorage::new());
+    let r = Repo::init(storage.clone(), config, &key).unwrap();
+    let mut clients = BTreeMap::new();
+    for (client, caps) in [
+        ("laptop", "put-only,list-metadata"),
+        ("restore", "fetch-data"),
+        ("pruner", "prune"),
+        ("ops", "admin"),
+    ]
+    .iter()
+    {
+        clients.insert(client.to_string(), parse_capabilities(caps).unwrap());
+    }
+    let acl = r.set_acl(clients, BTreeMap::new(), &maintenance).unwrap();
+    let as_client = |client: Option<&str>, req: &Request| {
+        let opts = ServeOptions {
+            client: client.map(String::from),
+            ..Default::default()
+        };
+        test_exchange(storage.clone(), &opts, req)
+    };
+    let allowed = |client: Option<&str>, req: &Request| {
+        !matches!(
+            as_client(client, req),
+            Err(RepoError::PermissionDeniedError)
+        )
+    };
+
+    let laptop = Some("laptop");
+    let restore = Some("restore");
+    let put = Request::Put {
+        key: "packs/00",
+        data: b"pack",
+    };
+    assert!(allowed(laptop, &put));
+    assert!(allowed(laptop, &Request::Get { key: CONFIG_FILE }));
+    assert!(allowed(laptop, &Request::List { prefix: "packs/" }));
+    assert!(allowed(
+        laptop,
+        &Request::Put {
+            key: "locks/00",
+            data: b""
+        }
+    ));
+    assert!(allowed(laptop, &Request::Delete { key: "locks/00" }));
+    assert!(!allowed(laptop, &Request::Get { key: "packs/00" }));
+    assert!(!allowed(laptop, &Request::Delete { key: "packs/00" }));
+    assert!(allowed(restore, &Request::Get { key: "packs/00" }));
+    assert!(!allowed(restore, &Request::Get { key: MANIFEST_FILE }));
+    assert!(!allowed(restore, &put));
+    // Unknown clients and sessions without one get nothing.
+    assert!(!allowed(
+        Some("stranger"),
+        &Request::Get { key: CONFIG_FILE }
+    ));
+    assert!(!allowed(None, &Request::Capabilities));
+
+    // Put-only replaces nothing, not even with append only mode off.
+    assert!(!allowed(laptop, &put));
+    let config = storage.get(CONFIG_FILE).unwrap();
+    let owner = storage.get(OWNER_KEY_FILE).unwrap();
+    for (key, data) in [(CONFIG_FILE, &config), (OWNER_KEY_FILE, &owner)].iter() {
+        let req = Request::Put { key, data };
+        assert!(!allowed(laptop, &req));
+    }
+    // The config is write once, and the manifest always checked.
+    assert!(!allowed(
+        Some("ops"),
+        &Request::Put {
+            key: CONFIG_FILE,
+            data: &config
+        }
+    ));
+    assert!(allowed(
+        Some("ops"),
+        &Request::Put {
+            key: OWNER_KEY_FILE,
+            data: &owner
+        }
+    ));
+    assert!(as_client(
+        Some("ops"),
+        &Request::Put {
+            key: MANIFEST_FILE,
+            data: b"garbage"
+        }
+    )
+    .is_err());
+
+    // Locks are only released by whoever took them, or with prune.
+    let lock = Request::Put {
+        key: "locks/01",
+        data: b"",
+    };
+    assert!(!allowed(restore, &lock));
+    as_client(laptop, &lock).unwrap();
+    assert!(!allowed(
+        laptop,
+        &Request::Put {
+            key: "lock-owners/01",
+            data: b"restore"
+        }
+    ));
+    let unlock = Request::Delete { key: "locks/01" };
+    assert!(!allowed(restore, &unlock));
+    as_client(Some("ops"), &lock).unwrap();
+    as_client(Some("ops"), &unlock).unwrap();
+    assert!(!storage.exists("lock-owners/01").unwrap());
+
+    // Only an admin replaces the access list, and only with a newer one.
+    let newer = Acl {
+        serial: acl.serial + 1,
+        ..acl.clone()
+    };
+    let signed = newer.sign(&maintenance.sign_sk);
+    let req = Request::Put {
+        key: ACL_FILE,
+        data: &signed,
+    };
+    assert!(!allowed(laptop, &req));
+    as_client(Some("ops"), &req).unwrap();
+    match as_client(Some("ops"), &req) {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected a replayed access list to be refused"),
+    }
+    let forged = Acl {
+        serial: acl.serial + 2,
+        ..acl
+    }
+    .sign(&key.sign_sk);
+    assert!(as_client(
+        Some("ops"),
+        &Request::Put {
+            key: ACL_FILE,
+            data: &forged
+        }
+    )
+    .is_err());
+    as_client(Some("ops"), &Request::Delete { key: "packs/00" }).unwrap();
+
+    // Pruning deletes data, not what the repository is opened and trusted
+    // by.
+    for key in [
+        ACL_FILE,
+        POLICY_FILE,
+        REVOCATIONS_FILE,
+        TRANSLOG_FILE,
+        CONFIG_FILE,
+        OWNER_KEY_FILE,
+    ]
+    .iter()
+    {
+        assert!(!allowed(Some("pruner"), &Request::Delete { key }));
+    }
+    assert!(storage.exists(ACL_FILE).unwrap());
+    assert!(allowed(
+        Some("pruner"),
+        &Request::Delete { key: "packs/01" }
+    ));
+
+    // Without the access list an authenticating server allows nothing.
+    as_client(Some("ops"), &Request::Delete { key: ACL_FILE }).unwrap();
+    let opts = ServeOptions {
+        authenticate: true,
+        client: Some("ops".to_string()),
+        ..Default::default()
+    };
+    match handle(&storage, &opts, &Request::Get { key: CONFIG_FILE }) {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected a session without an access list to be refused"),
+    }
+}
+
+#[test]
+fn test_serve_log() {
+    use crate::oplog::{export_log, verify_log};
+    let dir = crate::storage::local::test_dir("serve-log");
+    std::fs::create_dir_all(&dir).unwrap();
+    let key = Key::new();
+    key.write(&mut File::create(dir.join("log.key")).unwrap())
+        .unwrap();
+    let opts = ServeOptions {
+        append_only: true,
+        client: Some("laptop".to_string()),
+        log: Some(dir.join("serve.log")),
+        log_key: Some(dir.join("log.key")),
+        ..Default::default()
+    };
+    let storage: Arc<dyn StorageEngine> = Arc::new(crate::storage::mem::MemStorage::new());
+    let a = Address { bytes: [1; 32] };
+    let loose = format!("{}/{}", LOOSE_DIR, a.to_hex());
+    let put = Request::Put {
+        key: &loose,
+        data: b"object",
+    };
+    test_exchange(storage.clone(), &opts, &put).unwrap();
+    assert!(test_exchange(storage.clone(), &opts, &put).is_err());
+    let present = Request::Present(vec![a]).encode();
+    let batch = Request::Batch(vec![(1, &present[..])]);
+    let mut input = Vec::new();
+    protocol::write_frame(&mut input, &batch.encode()).unwrap();
+    serve(storage, &opts, &mut &input[..], &mut Vec::new()).unwrap();
+
+    let report = verify_log(&dir.join("serve.log"), &key.sign_pk).unwrap();
+    assert_eq!((report.operations, report.sealed, report.seals), (3, 3, 3));
+    let mut out = Vec::new();
+    export_log(&dir.join("serve.log"), &key.sign_pk, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    let hex = format!("[\"{}\"]", a.to_hex());
+    assert!(lines[0].contains("\"client\": \"laptop\", \"op\": \"put\""));
+    assert!(lines[0].contains("\"error\": null") && lines[0].contains(&hex));
+    assert!(lines[1].contains("\"error\": \"The repository server refused"));
+    assert!(lines[2].contains("\"op\": \"present\"") && lines[2].contains(&hex));
+
+    // No log without its key.
+    let keyless = ServeOptions {
+        log_key: None,
+        ..opts
+    };
+    assert!(serve(
+        Arc::new(crate::storage::mem::MemStorage::new()),
+        &keyless,
+        &mut &b""[..],
+        &mut Vec::new()
+    )
+    .is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_serve_checks() {
+    use crate::checks::read_check;
+    use crate::oplog::export_log;
+    let dir = crate::storage::local::test_dir("serve-checks");
+    std::fs::create_dir_all(&dir).unwrap();
+    let key = Key::new();
+    key.write(&mut File::create(dir.join("log.key")).unwrap())
+        .unwrap();
+    let opts = ServeOptions {
+        check_every: Some(24 * 60 * 60),
+        log: Some(dir.join("serve.log")),
+        log_key: Some(dir.join("log.key")),
+        ..Default::default()
+    };
+    let (r, _) = crate::test_repo();
+    let storage = r.storage().clone();
+    let status = |storage: &Arc<dyn StorageEngine>| {
+        let buf = test_exchange(storage.clone(), &opts, &Request::CheckStatus).unwrap();
+        protocol::decode_check_status(&mut crate::wire::Decoder::new(&buf)).unwrap()
+    };
+    assert_eq!(status(&storage).every, Some(24 * 60 * 60));
+    assert!(status(&storage).last.is_none());
+
+    // The scheduler checks at once, the next check is a day away.
+    let shared = ServeShared::new();
+    thread::scope(|s| {
+        let scheduled = s.spawn(|| schedule_checks(&storage, &opts, &shared.checks));
+        while read_check(&*storage).unwrap().is_none() {
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        shared.checks.stop();
+        scheduled.join().unwrap().unwrap();
+    });
+    let last = status(&storage).last.unwrap();
+    assert_eq!(last.errors, 0);
+
+    // Only the server writes the status.
+    let put = Request::Put {
+        key: CHECK_FILE,
+        data: &last.encode(),
+    };
+    for req in [put, Request::Delete { key: CHECK_FILE }].iter() {
+        match test_exchange(storage.clone(), &opts, req) {
+            Err(RepoError::PermissionDeniedError) => (),
+            _ => panic!("expected {} of the check status to be refused", req.name()),
+        }
+    }
+    let mut out = Vec::new();
+    export_log(&dir.join("serve.log"), &key.sign_pk, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let checks: Vec<&str> = out
+        .lines()
+        .filter(|l| l.contains("\"op\": \"check\""))
+        .collect();
+    assert_eq!(checks.len(), 1, "{}", out);
+    assert!(checks[0].contains("\"client\": null, \"op\": \"check\", \"key\": \"check\""));
+    assert!(checks[0].contains("\"error\": null"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_serve_revoked() {
+    use crate::acl::CAP_LIST;
+    use crate::revoke::Revocations;
+    use std::collections::BTreeMap;
+    let key = asymcrypt::Key::new();
+    let maintenance = asymcrypt::Key::new();
+    let config = crate::config::RepoConfig {
+        maintenance_pk: Some(maintenance.sign_pk.clone()),
+        ..Default::default()
+    };
+    let storage: Arc<dyn StorageEngine> = Arc::new(crate::storage::mem::MemStorage::new());
+    let r = Repo::init(storage.clone(), config, &key).unwrap();
+    let (laptop_pk, _) = tweetnacl::boxed_crypto_sign_keypair();
+    let (desktop_pk, _) = tweetnacl::boxed_crypto_sign_keypair();
+    let mut clients = BTreeMap::new();
+    let mut keys = BTreeMap::new();
+    for (client, pk) in [("laptop", &*laptop_pk), ("desktop", &*desktop_pk)] {
+        clients.insert(client.to_string(), CAP_PUT | CAP_LIST);
+        keys.insert(client.to_string(), pk.clone());
+    }
+    clients.insert("ops".to_string(), crate::acl::CAP_ADMIN);
+    r.set_acl(clients, keys, &maintenance).unwrap();
+    let as_client = |client: &str, req: &Request| {
+        let opts = ServeOptions {
+            client: Some(client.to_string()),
+            ..Default::default()
+        };
+        test_exchange(storage.clone(), &opts, req)
+    };
+    let refused = |client: &str, req: &Request| {
+        matches!(
+            as_client(client, req),
+            Err(RepoError::PermissionDeniedError)
+        )
+    };
+    let put = Request::Put {
+        key: "packs/00",
+        data: b"pack",
+    };
+    assert!(!refused("laptop", &put));
+
+    let revocations = r.revoke_key(&laptop_pk, "stolen", &maintenance).unwrap();
+    assert!(refused("laptop", &put));
+    assert!(refused("laptop", &Request::Delete { key: "packs/00" }));
+    assert!(!refused(
+        "desktop",
+        &Request::Put {
+            key: "packs/01",
+            data: b"pack"
+        }
+    ));
+    // The revoked client can still take locks and read.
+    let lock = Request::Put {
+        key: "locks/00",
+        data: b"",
+    };
+    assert!(!refused("laptop", &lock));
+    assert!(!refused("laptop", &Request::Get { key: MANIFEST_FILE }));
+    assert!(!refused(
+        "laptop",
+        &Request::Get {
+            key: REVOCATIONS_FILE
+        }
+    ));
+
+    // Nobody can take a revocation back, an admin or the maintenance key
+    // notwithstanding.
+    let lifted = Revocations {
+        serial: revocations.serial + 1,
+        revoked: Vec::new(),
+        ..revocations.clone()
+    };
+    let signed = lifted.sign(&maintenance.sign_sk);
+    let req = Request::Put {
+        key: REVOCATIONS_FILE,
+        data: &signed,
+    };
+    assert!(refused("ops", &req));
+    let signed = revocations.sign(&maintenance.sign_sk);
+    let req = Request::Put {
+        key: REVOCATIONS_FILE,
+        data: &signed,
+    };
+    assert!(refused("ops", &req));
+    let mut more = revocations.clone();
+    more.serial += 1;
+    more.revoked.push(crate::revoke::Revocation {
+        sign_pk: (*desktop_pk).clone(),
+        time: 0,
+        reason: String::new(),
+    });
+    // The owner key every writer holds signs no revocations.
+    let signed = more.sign(&key.sign_sk);
+    assert!(as_client(
+        "ops",
+        &Request::Put {
+            key: REVOCATIONS_FILE,
+            data: &signed,
+        }
+    )
+    .is_err());
+    let signed = more.sign(&maintenance.sign_sk);
+    let req = Request::Put {
+        key: REVOCATIONS_FILE,
+        data: &signed,
+    };
+    assert!(refused("desktop", &req));
+    as_client("ops", &req).unwrap();
+    assert!(refused("desktop", &put));
+}
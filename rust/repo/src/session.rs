@@ -0,0 +1,233 @@
+//! Sessions of `serve` that outlive their connection.
+//!
+//! Laptops and phones drop their connection all the time, often in the
+//! middle of a batch of puts. Connecting again costs the tenant selection
+//! and the authentication challenge, and sending the batch again would
+//! upload objects the server already stored, which an append only server
+//! then refuses as overwrites.
+//!
+//! A client asks for a SESSION once it has selected its tenant and
+//! authenticated, and the server keeps what the session is, its tenant
+//! and client, under a random token. A new connection starts with RESUME
+//! and the token instead, and is that session at once. The server also
+//! keeps the responses to the session's last batch as each request is
+//! answered, and RESUME returns those of the batch the client had in
+//! flight, so the client only sends the rest, see `RemoteStorage`.
+//!
+//! Batches are numbered within the session, by both ends, so the server
+//! can tell whether the batch the client asks about is the one it last
+//! started or never arrived. Resuming waits for a batch still running on
+//! the old connection, which the server may not know is gone yet, to
+//! finish, and the old connection serves nothing after, batch or not, it
+//! is closed at its next request. Sessions are forgotten after
+//! `SESSION_TTL` without a batch or a connection resuming them, and by a
+//! server that exits.
+//!
+//! A server started per connection, as over ssh, forgets the session with
+//! the connection, and the client falls back to negotiating again. Only
+//! sessions of a server serving many connections, see `serve_tls` and
+//! `serve_unix`, can be resumed.
+
+use super::serve::ServeOptions;
+use super::storage::not_found;
+use super::RepoError;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use tweetnacl::random_bytes;
+
+pub const TOKEN_SZ: usize = 32;
+pub const SESSION_TTL: Duration = Duration::from_secs(10 * 60);
+
+pub type SessionToken = [u8; TOKEN_SZ];
+
+struct SessionState {
+    // Bumped by each connection that resumes the session.
+    owner: u64,
+    running: bool,
+    // Batches started, and the responses to the last one so far by id.
+    batches: u64,
+    answered: Vec<(u32, Vec<u8>)>,
+    last_used: Instant,
+}
+
+struct Session {
+    tenant: Option<String>,
+    client: Option<String>,
+    state: Mutex<SessionState>,
+    idle: Condvar,
+}
+
+// A session, as the connection now serving it holds it.
+pub(crate) struct Resumable {
+    session: Arc<Session>,
+    owner: u64,
+}
+
+// What RESUME tells the client.
+pub struct Resumed {
+    // The batches the session started.
+    pub batches: u64,
+    // The responses to the batch asked about, if it was the last started.
+    pub answered: Vec<(u32, Vec<u8>)>,
+}
+
+#[derive(Default)]
+pub struct Sessions {
+    sessions: Mutex<BTreeMap<SessionToken, Arc<Session>>>,
+}
+
+impl Sessions {
+    pub fn new() -> Sessions {
+        Default::default()
+    }
+
+    // Keep the session `opts` under a new token, forgetting expired ones.
+    pub(crate) fn open(&self, opts: &ServeOptions) -> (SessionToken, Resumable) {
+        let mut token = [0; TOKEN_SZ];
+        random_bytes(&mut token);
+        let session = Arc::new(Session {
+            tenant: opts.tenant.clone(),
+            client: opts.client.clone(),
+            state: Mutex::new(SessionState {
+                owner: 0,
+                running: false,
+                batches: 0,
+                answered: Vec::new(),
+                last_used: Instant::now(),
+            }),
+            idle: Condvar::new(),
+        });
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, s| {
+            let state = s.state.lock().unwrap();
+            state.running || state.last_used.elapsed() < SESSION_TTL
+        });
+        sessions.insert(token, session.clone());
+        (token, Resumable { session, owner: 0 })
+    }
+
+    // Take over the session `token` for a connection of the session `opts`,
+    // returning its tenant and client and the responses to `batch`. A
+    // tenant or client the transport named must be the session's.
+    pub(crate) fn resume(
+        &self,
+        token: &SessionToken,
+        batch: u64,
+        opts: &ServeOptions,
+    ) -> Result<(Resumable, Option<String>, Option<String>, Resumed), RepoError> {
+        let session = match self.sessions.lock().unwrap().get(token) {
+            Some(session) => session.clone(),
+            None => return Err(not_found("session")),
+        };
+        let named = |by_transport: &Option<String>, of_session: &Option<String>| {
+            by_transport.is_none() || by_transport == of_session
+        };
+        if !named(&opts.client, &session.client) || !named(&opts.tenant, &session.tenant) {
+            return Err(RepoError::PermissionDeniedError);
+        }
+        let mut state = session.state.lock().unwrap();
+        if state.last_used.elapsed() >= SESSION_TTL && !state.running {
+            return Err(not_found("session"));
+        }
+        while state.running {
+            state = session.idle.wait(state).unwrap();
+        }
+        state.owner += 1;
+        state.last_used = Instant::now();
+        let resumed = Resumed {
+            batches: state.batches,
+            answered: if batch == state.batches {
+                state.answered.clone()
+            } else {
+                Vec::new()
+            },
+        };
+        let owner = state.owner;
+        drop(state);
+        let (tenant, client) = (session.tenant.clone(), session.client.clone());
+        Ok((Resumable { session, owner }, tenant, client, resumed))
+    }
+}
+
+impl Resumable {
+    // Refuse to serve the session once another connection resumed it.
+    pub(crate) fn check_owner(&self) -> Result<(), RepoError> {
+        let state = self.session.state.lock().unwrap();
+        check_owner(&state, self.owner)
+    }
+
+    // Start a batch, unless another connection resumed the session.
+    pub(crate) fn start_batch(&self) -> Result<(), RepoError> {
+        let mut state = self.session.state.lock().unwrap();
+        check_owner(&state, self.owner)?;
+        state.running = true;
+        state.batches += 1;
+        state.answered.clear();
+        Ok(())
+    }
+
+    pub(crate) fn answered(&self, id: u32, resp: &[u8]) {
+        let mut state = self.session.state.lock().unwrap();
+        state.answered.push((id, resp.to_vec()));
+    }
+
+    pub(crate) fn end_batch(&self) {
+        let mut state = self.session.state.lock().unwrap();
+        state.running = false;
+        state.last_used = Instant::now();
+        self.session.idle.notify_all();
+    }
+}
+
+fn check_owner(state: &SessionState, owner: u64) -> Result<(), RepoError> {
+    if state.owner != owner {
+        return Err(RepoError::StorageError(
+            "the session was resumed on another connection".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Tests --------------------
+
+#[test]
+fn test_sessions() {
+    let sessions = Sessions::new();
+    let opts = ServeOptions {
+        client: Some("laptop".to_string()),
+        ..Default::default()
+    };
+    let (token, first) = sessions.open(&opts);
+    first.start_batch().unwrap();
+    first.answered(3, b"done");
+    first.end_batch();
+
+    // The transport may not name someone else, the session is the client's.
+    let other = ServeOptions {
+        client: Some("desktop".to_string()),
+        ..Default::default()
+    };
+    match sessions.resume(&token, 1, &other) {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected another client to be refused"),
+    }
+    let (second, _, client, resumed) = sessions.resume(&token, 1, &Default::default()).unwrap();
+    assert_eq!(client.as_deref(), Some("laptop"));
+    assert_eq!(resumed.batches, 1);
+    assert_eq!(resumed.answered, vec![(3, b"done".to_vec())]);
+
+    // The old connection serves nothing more, a batch that never arrived
+    // has no responses.
+    assert!(first.check_owner().is_err());
+    assert!(first.start_batch().is_err());
+    second.check_owner().unwrap();
+    let (_, _, _, resumed) = sessions.resume(&token, 2, &Default::default()).unwrap();
+    assert!(resumed.answered.is_empty());
+    assert!(second.start_batch().is_err());
+    assert!(sessions
+        .resume(&[0; TOKEN_SZ], 0, &opts)
+        .err()
+        .unwrap()
+        .is_not_found());
+}
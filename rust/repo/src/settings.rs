@@ -0,0 +1,668 @@
+//! Client settings files.
+//!
+//! Unlike the repository `config`, settings are local to a client and
+//! hold its defaults: where the repository is, where the keys are, and
+//! what backups exclude, how fast they may upload and how long snapshots
+//! are kept. They are read from, in order:
+//!
+//! 1. `$XDG_CONFIG_HOME/packnback/config.toml`, `~/.config` if unset.
+//! 2. `$XDG_CONFIG_HOME/packnback/repos/<name>.toml` for the repository
+//!    named on the command line, if any.
+//!
+//! Either may be missing. A later file overrides an earlier one key by
+//! key, a table such as `[limits]` replaces the whole table. Environment
+//! variables override the files, for systemd units and CI secrets, and
+//! command line flags, gathered into one more `Settings`, override
+//! everything, see `Settings::resolve`:
+//!
+//! ```text
+//! PACKNBACK_REPOSITORY       repository
+//! PACKNBACK_KEY              key
+//! PACKNBACK_ADDRESS_KEY      address_key
+//! PACKNBACK_PASSPHRASE_CMD   passphrase_command
+//! PACKNBACK_UPLOAD_LIMIT     limits.up
+//! PACKNBACK_DOWNLOAD_LIMIT   limits.down
+//! PACKNBACK_MAX_MEMORY       max_memory
+//! ```
+//!
+//! A variable set to the empty string counts as unset. The passphrase is
+//! never stored: `passphrase_command` is run through `sh -c` when it is
+//! needed, and what it prints, less one trailing newline, is the
+//! passphrase.
+//!
+//! Files are TOML, read with the `toml` crate. Settings are strings,
+//! integers, booleans and arrays of strings, in one level of tables:
+//!
+//! ```text
+//! repository = "sftp://backup@nas/srv/packnback"
+//! key = "~/.config/packnback/key"          # paths may start with ~/
+//! address_key = "~/.config/packnback/address-key"
+//! passphrase_command = "pass show packnback"
+//! exclude = ["*.tmp", "/.cache/"]         # rules, see `exclude`
+//! max_memory = "512m"                     # for backups, see `memory`
+//!
+//! [chunker]                               # for new repositories
+//! min_size = 262144                       # missing sizes are defaults
+//! avg_size = 1048576
+//! max_size = 8388608
+//!
+//! [limits]                                # see `storage::throttle`
+//! up = "1m"
+//! down = "off"
+//! schedule = ["mon-fri 09:00-17:00 up=128k"]
+//! utc_offset = 3600
+//!
+//! [retention]                             # see `prune`
+//! last = 7
+//! daily = 14
+//! weekly = 8
+//! monthly = 12
+//! within = "30d"
+//!
+//! [hooks]                                 # see `hooks`
+//! pre_put = "systemctl stop postgresql"
+//! post_put = "systemctl start postgresql"
+//! on_error = "notify-send 'backup failed'"
+//!
+//! [xattrs]                                # attributes not to store or
+//! skip = ["security.selinux"]             # restore in any namespace,
+//! laptop = ["user.xdg.*"]                 # and in laptop, see `xattr`
+//!
+//! [priority]                              # see `priority`
+//! nice = 10
+//! io = "best-effort:7"
+//! low = false
+//! ```
+//!
+//! Unknown keys and values of the wrong type are errors, reported with
+//! their line, so a typo never silently loses a setting.
+//! `Settings::check` finds what parsing cannot, for a `config check`
+//! command.
+
+use super::exclude::{parse_rules, Rule};
+use super::hooks::Hooks;
+use super::manifest::ChunkerParams;
+use super::namespace::Namespace;
+use super::priority::{IoClass, Priority};
+use super::prune::{parse_duration, KeepPolicy};
+use super::storage::throttle::{parse_rate, Limits, ScheduleRule};
+use super::xattr::XattrOptions;
+use super::RepoError;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use toml::de::{DeTable, DeValue};
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Settings {
+    pub repository: Option<String>,
+    pub key: Option<PathBuf>,
+    pub address_key: Option<PathBuf>,
+    pub passphrase_command: Option<String>,
+    pub exclude: Option<Vec<String>>,
+    pub max_memory: Option<u64>,
+    pub chunker: Option<ChunkerParams>,
+    pub limits: Option<Limits>,
+    pub retention: Option<KeepPolicy>,
+    pub hooks: Option<Hooks>,
+    pub xattrs: Option<XattrSettings>,
+    pub priority: Option<Priority>,
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct XattrSettings {
+    // Patterns skipped in every namespace.
+    pub skip: Vec<String>,
+    // And in one namespace.
+    pub namespaces: BTreeMap<Namespace, Vec<String>>,
+}
+
+fn type_name(v: &DeValue) -> &'static str {
+    match v {
+        DeValue::String(_) => "a string",
+        DeValue::Integer(_) => "an integer",
+        DeValue::Float(_) => "a float",
+        DeValue::Boolean(_) => "a boolean",
+        DeValue::Datetime(_) => "a date",
+        DeValue::Array(_) => "an array",
+        DeValue::Table(_) => "a table",
+    }
+}
+
+fn int(v: &DeValue) -> Option<i64> {
+    match v {
+        DeValue::Integer(i) => i64::from_str_radix(i.as_str(), i.radix()).ok(),
+        _ => None,
+    }
+}
+
+fn invalid(line: usize, message: &str) -> RepoError {
+    RepoError::IOError(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("line {}: {}", line, message),
+    ))
+}
+
+// The line of byte `at` in `text`.
+fn line_of(text: &str, at: usize) -> usize {
+    text.as_bytes()[..at.min(text.len())]
+        .iter()
+        .filter(|c| **c == b'\n')
+        .count()
+        + 1
+}
+
+// Keys as `table.key`, each with its value and line, in order of key.
+fn parse_toml(text: &str) -> Result<Vec<(String, DeValue<'_>, usize)>, RepoError> {
+    let doc = DeTable::parse(text).map_err(|e| {
+        let line = e.span().map_or(1, |span| line_of(text, span.start));
+        invalid(line, e.message().trim_end())
+    })?;
+    let mut keys = Vec::new();
+    for (key, value) in doc.into_inner() {
+        let line = line_of(text, key.span().start);
+        match value.into_inner() {
+            DeValue::Table(table) => {
+                for (name, value) in table {
+                    let line = line_of(text, name.span().start);
+                    keys.push((
+                        format!("{}.{}", key.as_ref(), name.as_ref()),
+                        value.into_inner(),
+                        line,
+                    ));
+                }
+            }
+            value => keys.push((key.into_inner().into_owned(), value, line)),
+        }
+    }
+    Ok(keys)
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match path.strip_prefix("~/") {
+        Some(rest) => PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(rest),
+        None => PathBuf::from(path),
+    }
+}
+
+fn config_dir() -> PathBuf {
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => expand_home("~/.config"),
+    }
+    .join("packnback")
+}
+
+// A size such as `512m`, written as rates are.
+pub fn parse_size(s: &str) -> Option<u64> {
+    parse_rate(s).ok().flatten()
+}
+
+// The settings files to read for the repository called `repo`, see the
+// module documentation.
+pub fn default_paths(repo: Option<&str>) -> Vec<PathBuf> {
+    let dir = config_dir();
+    let mut paths = vec![dir.join("config.toml")];
+    if let Some(repo) = repo {
+        paths.push(dir.join("repos").join(format!("{}.toml", repo)));
+    }
+    paths
+}
+
+impl Settings {
+    pub fn parse(text: &str) -> Result<Settings, RepoError> {
+        let mut s: Settings = Default::default();
+        for (key, value, line) in parse_toml(text)? {
+            let wrong = || invalid(line, &format!("'{}' cannot be {}", key, type_name(&value)));
+            let str_value = || match value {
+                DeValue::String(ref v) => Ok(v.to_string()),
+                _ => Err(wrong()),
+            };
+            let int_value = || match int(&value) {
+                Some(v) if v >= 0 => Ok(v as u64),
+                _ => Err(wrong()),
+            };
+            let strs_value = || match value {
+                DeValue::Array(ref items) => items
+                    .iter()
+                    .map(|v| match v.get_ref() {
+                        DeValue::String(s) => Ok(s.to_string()),
+                        _ => Err(wrong()),
+                    })
+                    .collect::<Result<Vec<String>, RepoError>>(),
+                _ => Err(wrong()),
+            };
+            let bad = |what: &str| invalid(line, &format!("invalid {} for '{}'", what, key));
+            let (table, name) = match key.find('.') {
+                Some(i) => (&key[..i], &key[i + 1..]),
+                None => ("", &key[..]),
+            };
+            match (table, name) {
+                ("", "repository") => s.repository = Some(str_value()?),
+                ("", "key") => s.key = Some(expand_home(&str_value()?)),
+                ("", "address_key") => s.address_key = Some(expand_home(&str_value()?)),
+                ("", "passphrase_command") => s.passphrase_command = Some(str_value()?),
+                ("", "exclude") => s.exclude = Some(strs_value()?),
+                ("", "max_memory") => {
+                    s.max_memory = Some(parse_size(&str_value()?).ok_or_else(|| bad("size"))?)
+                }
+                ("chunker", _) => {
+                    let size = u32::try_from(int_value()?).map_err(|_| bad("size"))?;
+                    let c = s.chunker.get_or_insert_with(Default::default);
+                    match name {
+                        "min_size" => c.min_size = size,
+                        "avg_size" => c.avg_size = size,
+                        "max_size" => c.max_size = size,
+                        _ => return Err(invalid(line, &format!("unknown key '{}'", key))),
+                    }
+                }
+                ("limits", _) => {
+                    let l = s.limits.get_or_insert_with(Default::default);
+                    match name {
+                        "up" => l.up = parse_rate(&str_value()?).map_err(|_| bad("rate"))?,
+                        "down" => l.down = parse_rate(&str_value()?).map_err(|_| bad("rate"))?,
+                        "schedule" => {
+                            l.schedule = strs_value()?
+                                .iter()
+                                .map(|r| ScheduleRule::parse(r).map_err(|_| bad("rule")))
+                                .collect::<Result<_, _>>()?;
+                        }
+                        "utc_offset" => l.utc_offset = int(&value).ok_or_else(wrong)?,
+                        _ => return Err(invalid(line, &format!("unknown key '{}'", key))),
+                    }
+                }
+                ("retention", _) => {
+                    let k = s.retention.get_or_insert_with(Default::default);
+                    match name {
+                        "last" => k.last = int_value()? as usize,
+                        "daily" => k.daily = int_value()? as usize,
+                        "weekly" => k.weekly = int_value()? as usize,
+                        "monthly" => k.monthly = int_value()? as usize,
+                        "within" => {
+                            k.within =
+                                parse_duration(&str_value()?).ok_or_else(|| bad("duration"))?
+                        }
+                        _ => return Err(invalid(line, &format!("unknown key '{}'", key))),
+                    }
+                }
+                ("hooks", _) => {
+                    let h = s.hooks.get_or_insert_with(Default::default);
+                    let hook = match name {
+                        "pre_put" => &mut h.pre_put,
+                        "post_put" => &mut h.post_put,
+                        "pre_get" => &mut h.pre_get,
+                        "post_get" => &mut h.post_get,
+                        "on_error" => &mut h.on_error,
+                        _ => return Err(invalid(line, &format!("unknown key '{}'", key))),
+                    };
+                    *hook = Some(str_value()?);
+                }
+                ("xattrs", _) => {
+                    let x = s.xattrs.get_or_insert_with(Default::default);
+                    if name == "skip" {
+                        x.skip = strs_value()?;
+                    } else {
+                        let ns = Namespace::new(name).map_err(|_| bad("namespace"))?;
+                        x.namespaces.insert(ns, strs_value()?);
+                    }
+                }
+                ("priority", _) => {
+                    let p = s.priority.get_or_insert_with(Default::default);
+                    match (name, &value) {
+                        ("nice", _) => match int(&value) {
+                            Some(v) if (-20..=19).contains(&v) => p.nice = Some(v as i32),
+                            Some(_) => return Err(bad("nice value")),
+                            None => return Err(wrong()),
+                        },
+                        ("io", _) => {
+                            p.io = Some(IoClass::parse(&str_value()?).ok_or_else(|| bad("class"))?)
+                        }
+                        ("low", DeValue::Boolean(v)) => p.low = *v,
+                        ("low", _) => return Err(wrong()),
+                        _ => return Err(invalid(line, &format!("unknown key '{}'", key))),
+                    }
+                }
+                _ => return Err(invalid(line, &format!("unknown key '{}'", key))),
+            }
+        }
+        Ok(s)
+    }
+
+    // The settings in the file at `path`, none if it does not exist.
+    pub fn load(path: &Path) -> Result<Settings, RepoError> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Default::default()),
+            Err(e) => return Err(e.into()),
+        };
+        Settings::parse(&text).map_err(|e| match e {
+            RepoError::IOError(e) => RepoError::IOError(io::Error::new(
+                e.kind(),
+                format!("{}: {}", path.display(), e),
+            )),
+            e => e,
+        })
+    }
+
+    // Every file in `paths` merged in order.
+    pub fn load_all(paths: &[PathBuf]) -> Result<Settings, RepoError> {
+        let mut s: Settings = Default::default();
+        for path in paths.iter() {
+            s.merge(Settings::load(path)?);
+        }
+        Ok(s)
+    }
+
+    // Take everything `over` sets.
+    pub fn merge(&mut self, over: Settings) {
+        fn take<T>(to: &mut Option<T>, from: Option<T>) {
+            if from.is_some() {
+                *to = from;
+            }
+        }
+        take(&mut self.repository, over.repository);
+        take(&mut self.key, over.key);
+        take(&mut self.address_key, over.address_key);
+        take(&mut self.passphrase_command, over.passphrase_command);
+        take(&mut self.exclude, over.exclude);
+        take(&mut self.max_memory, over.max_memory);
+        take(&mut self.chunker, over.chunker);
+        take(&mut self.limits, over.limits);
+        take(&mut self.retention, over.retention);
+        take(&mut self.hooks, over.hooks);
+        take(&mut self.xattrs, over.xattrs);
+        take(&mut self.priority, over.priority);
+    }
+
+    // Apply the `PACKNBACK_` variables among `vars`, see the module
+    // documentation. Others are ignored.
+    pub fn apply_env<I>(&mut self, vars: I) -> Result<(), RepoError>
+    where
+        I: IntoIterator<Item = (OsString, OsString)>,
+    {
+        for (name, value) in vars {
+            let name = match name.to_str().and_then(|n| n.strip_prefix("PACKNBACK_")) {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            if value.is_empty() {
+                continue;
+            }
+            let value = value.into_string().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("PACKNBACK_{} is not utf8", name),
+                )
+            })?;
+            let rate = |v: &str| {
+                parse_rate(v).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid rate '{}' in PACKNBACK_{}", v, name),
+                    )
+                })
+            };
+            match &name[..] {
+                "REPOSITORY" => self.repository = Some(value),
+                "KEY" => self.key = Some(expand_home(&value)),
+                "ADDRESS_KEY" => self.address_key = Some(expand_home(&value)),
+                "PASSPHRASE_CMD" => self.passphrase_command = Some(value),
+                "UPLOAD_LIMIT" => {
+                    self.limits.get_or_insert_with(Default::default).up = rate(&value)?;
+                }
+                "DOWNLOAD_LIMIT" => {
+                    self.limits.get_or_insert_with(Default::default).down = rate(&value)?;
+                }
+                "MAX_MEMORY" => {
+                    self.max_memory = Some(parse_size(&value).ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("invalid size '{}' in PACKNBACK_MAX_MEMORY", value),
+                        )
+                    })?);
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    // The settings files for the repository called `repo`, then the
+    // environment, then `flags`.
+    pub fn resolve(repo: Option<&str>, flags: Settings) -> Result<Settings, RepoError> {
+        let mut s = Settings::load_all(&default_paths(repo))?;
+        s.apply_env(env::vars_os())?;
+        s.merge(flags);
+        Ok(s)
+    }
+
+    // Run `passphrase_command` for the passphrase, None if there is none.
+    pub fn passphrase(&self) -> Result<Option<Vec<u8>>, RepoError> {
+        let cmd = match self.passphrase_command {
+            Some(ref cmd) => cmd,
+            None => return Ok(None),
+        };
+        let out = Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output()?;
+        if !out.status.success() {
+            return Err(RepoError::IOError(io::Error::other(format!(
+                "passphrase command failed, {}",
+                out.status
+            ))));
+        }
+        let mut passphrase = out.stdout;
+        if passphrase.last() == Some(&b'\n') {
+            passphrase.pop();
+        }
+        Ok(Some(passphrase))
+    }
+
+    pub fn exclude_rules(&self) -> Vec<Rule> {
+        let lines = self.exclude.as_deref().unwrap_or(&[]).join("\n");
+        parse_rules(lines.as_bytes())
+    }
+
+    // The extended attributes to store and restore in `namespace`.
+    pub fn xattr_options(&self, namespace: &Namespace) -> XattrOptions {
+        let mut skip = Vec::new();
+        if let Some(ref x) = self.xattrs {
+            skip.extend_from_slice(&x.skip);
+            skip.extend_from_slice(x.namespaces.get(namespace).map_or(&[], |v| &v[..]));
+        }
+        XattrOptions::skipping(&skip)
+    }
+
+    // Problems that would stop the settings from being used, empty if
+    // there are none.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        match self.repository {
+            Some(ref r) if r.is_empty() => problems.push("repository is empty".to_string()),
+            Some(_) => (),
+            None => problems.push("no repository is set".to_string()),
+        }
+        for (name, path) in [("key", &self.key), ("address_key", &self.address_key)] {
+            if let Some(path) = path {
+                if let Err(e) = fs::File::open(path) {
+                    problems.push(format!("{} {}: {}", name, path.display(), e));
+                }
+            }
+        }
+        if self.chunker.as_ref().is_some_and(|c| !c.is_valid()) {
+            problems.push(
+                "chunker sizes must grow from min to max, with a power of two average".to_string(),
+            );
+        }
+        if self.retention.as_ref().is_some_and(|k| k.is_empty()) {
+            problems.push("retention keeps nothing".to_string());
+        }
+        problems
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_settings() {
+    let s = Settings::parse(
+        "# defaults\n\
+         repository = \"sftp://backup@nas/srv/packnback\" # the nas\n\
+         key = '/etc/packnback/key'\n\
+         exclude = [\n  \"*.tmp\",\n  \"/.cache/\", # caches\n]\n\
+         max_memory = \"512m\"\n\
+         \n\
+         [chunker]\n\
+         avg_size = 2_097_152\n\
+         [limits]\n\
+         up = \"1m\"\n\
+         schedule = [\"mon-fri 09:00-17:00 up=128k\"]\n\
+         utc_offset = -3600\n\
+         [retention]\n\
+         daily = 7\n\
+         within = \"2w\"\n\
+         [hooks]\n\
+         pre_put = \"sync\"\n\
+         [xattrs]\n\
+         skip = [\"security.selinux\"]\n\
+         laptop = [\"user.*\"]\n\
+         [priority]\n\
+         nice = -5\n\
+         io = \"best-effort:7\"\n",
+    )
+    .unwrap();
+    assert_eq!(
+        s.repository.as_deref(),
+        Some("sftp://backup@nas/srv/packnback")
+    );
+    assert_eq!(s.key, Some(PathBuf::from("/etc/packnback/key")));
+    assert_eq!(s.address_key, None);
+    assert_eq!(s.exclude_rules().len(), 2);
+    assert_eq!(s.max_memory, Some(512 << 20));
+    assert_eq!(s.chunker.as_ref().unwrap().avg_size, 2 << 20);
+    assert_eq!(s.chunker.as_ref().unwrap().min_size, 256 * 1024);
+    let limits = s.limits.as_ref().unwrap();
+    assert_eq!(
+        (limits.up, limits.down, limits.utc_offset),
+        (Some(1 << 20), None, -3600)
+    );
+    assert_eq!(limits.schedule[0].up, Some(128 << 10));
+    assert_eq!(
+        s.retention,
+        Some(KeepPolicy {
+            daily: 7,
+            within: 14 * 24 * 3600,
+            ..Default::default()
+        })
+    );
+
+    assert_eq!(s.hooks.as_ref().unwrap().pre_put.as_deref(), Some("sync"));
+    assert_eq!(s.hooks.as_ref().unwrap().on_error, None);
+    let server_ns = Namespace::new("server").unwrap();
+    let laptop = s.xattr_options(&Namespace::new("laptop").unwrap());
+    let server = s.xattr_options(&server_ns);
+    assert!(!laptop.wanted(b"security.selinux") && !laptop.wanted(b"user.mime"));
+    assert!(!server.wanted(b"security.selinux") && server.wanted(b"user.mime"));
+    assert!(Settings::default()
+        .xattr_options(&server_ns)
+        .wanted(b"security.selinux"));
+    assert_eq!(
+        s.priority,
+        Some(Priority {
+            nice: Some(-5),
+            io: Some(IoClass::BestEffort(7)),
+            low: false,
+        })
+    );
+
+    // Later settings override key by key, tables whole.
+    let mut merged = s.clone();
+    merged
+        .merge(Settings::parse("repository = \"/backups\"\n[limits]\ndown = \"512k\"\n").unwrap());
+    assert_eq!(merged.repository.as_deref(), Some("/backups"));
+    assert_eq!(merged.key, s.key);
+    assert_eq!(merged.limits.as_ref().unwrap().up, None);
+    assert_eq!(merged.limits.as_ref().unwrap().down, Some(512 << 10));
+
+    for (text, line) in [
+        ("repository = 1\n", 1),
+        ("\n\nrepo = \"x\"\n", 3),
+        ("[limits]\nup = \"fast\"\n", 2),
+        ("key = \"a\"\nkey = \"b\"\n", 2),
+        ("exclude = [\"a\", 1]\n", 1),
+        ("repository = \"x\" y\n", 1),
+        ("key = \"open\n", 1),
+        ("[xattrs]\nskip = \"user.*\"\n", 2),
+        ("max_memory = \"off\"\n", 1),
+        ("[priority]\nnice = 20\n", 2),
+        ("[priority]\nio = \"idle:7\"\n", 2),
+        ("[priority]\nlow = 1\n", 2),
+        ("[limits.up]\nrate = 1\n", 1),
+        ("\nmax_memory = 1.5\n", 2),
+    ] {
+        match Settings::parse(text) {
+            Err(RepoError::IOError(ref e)) if e.kind() == io::ErrorKind::InvalidInput => {
+                assert!(
+                    e.to_string().starts_with(&format!("line {}:", line)),
+                    "{}",
+                    e
+                )
+            }
+            r => panic!("expected {:?} to fail, got {:?}", text, r),
+        }
+    }
+
+    let dir = super::storage::local::test_dir("settings");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("config.toml"), "repository = \"/backups\"\n").unwrap();
+    fs::write(dir.join("bad.toml"), "[chunker]\navg_size = 3000\n").unwrap();
+    let loaded = Settings::load_all(&[dir.join("config.toml"), dir.join("missing.toml")]).unwrap();
+    assert_eq!(loaded.repository.as_deref(), Some("/backups"));
+    let bad = Settings::load(&dir.join("bad.toml")).unwrap();
+    assert_eq!(bad.check().len(), 2);
+    let key = dir.join("key");
+    fs::write(&key, b"key").unwrap();
+    let ok = Settings {
+        repository: Some("/backups".to_string()),
+        key: Some(key),
+        ..Default::default()
+    };
+    assert!(ok.check().is_empty());
+    assert!(default_paths(Some("nas"))[1].ends_with("packnback/repos/nas.toml"));
+
+    // The environment overrides single keys, even within tables.
+    let mut env = s.clone();
+    let vars = [
+        ("PACKNBACK_REPOSITORY", "/env"),
+        ("PACKNBACK_KEY", ""),
+        ("PACKNBACK_DOWNLOAD_LIMIT", "2m"),
+        ("PACKNBACK_MAX_MEMORY", "1g"),
+        ("PACKNBACK_PASSPHRASE_CMD", "printf 'secret\\n'"),
+        ("PACKNBACK_UNKNOWN", "x"),
+        ("HOME", "/root"),
+    ];
+    env.apply_env(vars.iter().map(|(n, v)| (n.into(), v.into())))
+        .unwrap();
+    assert_eq!(env.repository.as_deref(), Some("/env"));
+    assert_eq!(env.key, s.key);
+    let limits = env.limits.as_ref().unwrap();
+    assert_eq!((limits.up, limits.down), (Some(1 << 20), Some(2 << 20)));
+    assert_eq!(limits.schedule.len(), 1);
+    assert_eq!(env.max_memory, Some(1 << 30));
+    assert_eq!(env.passphrase().unwrap(), Some(b"secret".to_vec()));
+    assert!(env
+        .apply_env(vec![("PACKNBACK_UPLOAD_LIMIT".into(), "fast".into())])
+        .is_err());
+    env.passphrase_command = Some("exit 3".to_string());
+    assert!(env.passphrase().is_err());
+    assert_eq!(s.passphrase().unwrap(), None);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,39 @@
+//! Attached ed25519 signatures over encoded objects.
+//!
+//! A signed object is the nacl crypto_sign output: a 64 byte signature
+//! followed by the encoded object.
+
+use super::RepoError;
+use tweetnacl::*;
+
+pub fn sign(m: &[u8], sk: &CryptoSignSk) -> Vec<u8> {
+    let mut sm = vec![0; m.len() + CRYPTO_SIGN_BYTES];
+    let n = crypto_sign(&mut sm, m, sk);
+    sm.truncate(n);
+    sm
+}
+
+pub fn open(sm: &[u8], pk: &CryptoSignPk) -> Result<Vec<u8>, RepoError> {
+    let mut m = vec![0; sm.len()];
+    match crypto_sign_open(&mut m, sm, pk) {
+        Some(n) => {
+            m.truncate(n);
+            Ok(m)
+        }
+        None => Err(RepoError::SignatureFailedError),
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_sign_open() {
+    let (pk, sk) = boxed_crypto_sign_keypair();
+    let mut sm = sign(b"hello", &sk);
+    assert_eq!(open(&sm, &pk).unwrap(), b"hello");
+    let last = sm.len() - 1;
+    sm[last] ^= 1;
+    assert!(open(&sm, &pk).is_err());
+    let (pk2, _) = boxed_crypto_sign_keypair();
+    assert!(open(&sign(b"hello", &sk), &pk2).is_err());
+}
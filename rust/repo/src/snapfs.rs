@@ -0,0 +1,423 @@
+//! A read only filesystem view of every snapshot.
+//!
+//! `SnapshotFs` holds what `fuse` serves to the kernel, but knows nothing
+//! of FUSE itself. Its root holds a directory per namespace, each holding
+//! a directory per snapshot named by its UTC time, see `datetime`, with
+//! `-2`, `-3` and so on appended if several share a second. Below that
+//! are the snapshot's trees, see `tree`.
+//!
+//! Every node has an inode number, handed out as nodes are first listed
+//! or looked up and kept until the filesystem is dropped. Trees are read
+//! when a directory is first listed. File data is fetched a chunk at a
+//! time as it is read: chunk offsets come from the sizes in the pack
+//! indexes, so a read only fetches the chunks it overlaps. Recently read
+//! chunks are kept in a least recently used cache of `cache_bytes`.
+
+use super::address::Address;
+use super::datetime::DateTime;
+use super::index::RepoIndex;
+use super::object::ObjectKind;
+use super::pack::{PackId, PackReader};
+use super::storage::StorageObject;
+use super::tree::{EntryKind, TreeEntry};
+use super::{Repo, RepoError};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::Arc;
+use tweetnacl::CryptoBoxSk;
+
+pub const ROOT_INO: u64 = 1;
+pub const DEFAULT_CACHE_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FileAttr {
+    pub ino: u64,
+    pub kind: EntryKind,
+    pub size: u64,
+    // Permission bits only.
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u64,
+    pub mtime_nsec: u32,
+    // The modification time unless the snapshot has one.
+    pub atime: (u64, u32),
+    // The major and minor numbers of a device.
+    pub rdev: (u32, u32),
+}
+
+struct Node {
+    parent: u64,
+    entry: TreeEntry,
+    // A snapshot directory's snapshot, read when it is first listed.
+    snapshot: Option<Address>,
+    children: Option<Vec<u64>>,
+    // Chunk start offsets of a file, then its size.
+    offsets: Option<Vec<u64>>,
+}
+
+// Chunks by address, evicting the least recently used.
+struct ChunkCache {
+    max_bytes: usize,
+    bytes: usize,
+    tick: u64,
+    chunks: HashMap<Address, (u64, Arc<Vec<u8>>)>,
+    by_use: BTreeMap<u64, Address>,
+}
+
+impl ChunkCache {
+    fn get(&mut self, address: &Address) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+        let (used, data) = self.chunks.get_mut(address)?;
+        self.by_use.remove(used);
+        *used = self.tick;
+        self.by_use.insert(self.tick, *address);
+        Some(data.clone())
+    }
+
+    fn insert(&mut self, address: Address, data: Arc<Vec<u8>>) {
+        self.tick += 1;
+        self.bytes += data.len();
+        if let Some((used, old)) = self.chunks.insert(address, (self.tick, data)) {
+            self.by_use.remove(&used);
+            self.bytes -= old.len();
+        }
+        self.by_use.insert(self.tick, address);
+        while self.bytes > self.max_bytes {
+            let (_, oldest) = self.by_use.pop_first().unwrap();
+            let (_, data) = self.chunks.remove(&oldest).unwrap();
+            self.bytes -= data.len();
+        }
+    }
+}
+
+fn dir_entry(name: &[u8], mtime: u64) -> TreeEntry {
+    TreeEntry {
+        name: name.to_vec(),
+        kind: EntryKind::Dir,
+        mode: 0o555,
+        uid: 0,
+        gid: 0,
+        mtime,
+        mtime_nsec: 0,
+        size: 0,
+        refs: Vec::new(),
+        target: Vec::new(),
+        link: 0,
+        xattrs: Vec::new(),
+        device: (0, 0),
+        atime: None,
+        birthtime: None,
+    }
+}
+
+fn error(kind: io::ErrorKind) -> RepoError {
+    RepoError::IOError(io::Error::from(kind))
+}
+
+pub struct SnapshotFs<'a> {
+    repo: &'a Repo,
+    index: RepoIndex,
+    sk: &'a CryptoBoxSk,
+    // Inode i is nodes[i - 1].
+    nodes: Vec<Node>,
+    cache: ChunkCache,
+    pack: Option<(PackId, PackReader<StorageObject>)>,
+}
+
+impl<'a> SnapshotFs<'a> {
+    // Every snapshot in the manifest as it is now.
+    pub fn new(
+        repo: &'a Repo,
+        sk: &'a CryptoBoxSk,
+        cache_bytes: usize,
+    ) -> Result<SnapshotFs<'a>, RepoError> {
+        let mut fs = SnapshotFs {
+            repo,
+            index: repo.load_index()?,
+            sk,
+            nodes: Vec::new(),
+            cache: ChunkCache {
+                max_bytes: cache_bytes,
+                bytes: 0,
+                tick: 0,
+                chunks: HashMap::new(),
+                by_use: BTreeMap::new(),
+            },
+            pack: None,
+        };
+        let mut heads = repo.manifest()?.heads;
+        heads.sort_by(|a, b| (&a.namespace, a.timestamp).cmp(&(&b.namespace, b.timestamp)));
+        let mtime = heads.iter().map(|h| h.timestamp).max().unwrap_or(0);
+        fs.add(ROOT_INO, dir_entry(b"", mtime), None);
+        fs.nodes[0].children = Some(Vec::new());
+        let mut namespaces: BTreeMap<String, u64> = BTreeMap::new();
+        let mut names: HashMap<(u64, String), u32> = HashMap::new();
+        for h in heads.iter() {
+            let ns = h.namespace.to_string();
+            let ns_ino = match namespaces.get(&ns) {
+                Some(ino) => *ino,
+                None => {
+                    let ino = fs.add(ROOT_INO, dir_entry(ns.as_bytes(), mtime), None);
+                    fs.nodes[ino as usize - 1].children = Some(Vec::new());
+                    namespaces.insert(ns, ino);
+                    ino
+                }
+            };
+            let time = DateTime::from_unix(h.timestamp).to_rfc3339();
+            let n = names.entry((ns_ino, time.clone())).or_insert(0);
+            *n += 1;
+            let name = match *n {
+                1 => time,
+                n => format!("{}-{}", time, n),
+            };
+            fs.add(
+                ns_ino,
+                dir_entry(name.as_bytes(), h.timestamp),
+                Some(h.address),
+            );
+        }
+        Ok(fs)
+    }
+
+    fn add(&mut self, parent: u64, entry: TreeEntry, snapshot: Option<Address>) -> u64 {
+        self.nodes.push(Node {
+            parent,
+            entry,
+            snapshot,
+            children: None,
+            offsets: None,
+        });
+        let ino = self.nodes.len() as u64;
+        if ino != ROOT_INO {
+            if let Some(ref mut children) = self.node_mut(parent).children {
+                children.push(ino);
+            }
+        }
+        ino
+    }
+
+    fn node(&self, ino: u64) -> Result<&Node, RepoError> {
+        match ino.checked_sub(1).and_then(|i| self.nodes.get(i as usize)) {
+            Some(node) => Ok(node),
+            None => Err(error(io::ErrorKind::NotFound)),
+        }
+    }
+
+    fn node_mut(&mut self, ino: u64) -> &mut Node {
+        &mut self.nodes[ino as usize - 1]
+    }
+
+    pub fn parent(&self, ino: u64) -> Result<u64, RepoError> {
+        Ok(self.node(ino)?.parent)
+    }
+
+    pub fn attr(&self, ino: u64) -> Result<FileAttr, RepoError> {
+        let ent = &self.node(ino)?.entry;
+        let (size, nlink) = match ent.kind {
+            EntryKind::File => (ent.size, 1),
+            EntryKind::Dir => (0, 2),
+            EntryKind::Symlink => (ent.target.len() as u64, 1),
+            _ => (0, 1),
+        };
+        Ok(FileAttr {
+            ino,
+            kind: ent.kind,
+            size,
+            mode: ent.mode & 0o7777,
+            nlink,
+            uid: ent.uid,
+            gid: ent.gid,
+            mtime: ent.mtime,
+            mtime_nsec: ent.mtime_nsec,
+            atime: ent.atime.unwrap_or((ent.mtime, ent.mtime_nsec)),
+            rdev: ent.device,
+        })
+    }
+
+    // The inodes in a directory, in name order.
+    pub fn children(&mut self, ino: u64) -> Result<Vec<u64>, RepoError> {
+        let node = self.node(ino)?;
+        if let Some(ref children) = node.children {
+            return Ok(children.clone());
+        }
+        if node.entry.kind != EntryKind::Dir {
+            return Err(error(io::ErrorKind::NotADirectory));
+        }
+        let tree = match node.snapshot {
+            Some(ref address) => self.repo.read_snapshot(&self.index, self.sk, address)?.root,
+            None => node.entry.refs[0],
+        };
+        let tree = self.repo.read_tree(&self.index, self.sk, &tree)?;
+        self.node_mut(ino).children = Some(Vec::new());
+        for ent in tree.entries {
+            self.add(ino, ent, None);
+        }
+        Ok(self.node(ino)?.children.clone().unwrap())
+    }
+
+    pub fn lookup(&mut self, parent: u64, name: &[u8]) -> Result<u64, RepoError> {
+        for ino in self.children(parent)? {
+            if self.node(ino)?.entry.name == name {
+                return Ok(ino);
+            }
+        }
+        Err(error(io::ErrorKind::NotFound))
+    }
+
+    pub fn name(&self, ino: u64) -> Result<&[u8], RepoError> {
+        Ok(&self.node(ino)?.entry.name)
+    }
+
+    pub fn readlink(&self, ino: u64) -> Result<Vec<u8>, RepoError> {
+        let ent = &self.node(ino)?.entry;
+        match ent.kind {
+            EntryKind::Symlink => Ok(ent.target.clone()),
+            _ => Err(error(io::ErrorKind::InvalidInput)),
+        }
+    }
+
+    fn fetch(&mut self, address: &Address) -> Result<Arc<Vec<u8>>, RepoError> {
+        if let Some(data) = self.cache.get(address) {
+            return Ok(data);
+        }
+        let data = match self.index.lookup(address) {
+            Some(loc) if loc.kind == ObjectKind::Chunk => {
+                if self.pack.as_ref().map(|(id, _)| *id) != Some(loc.pack_id) {
+                    self.pack = Some((loc.pack_id, self.repo.open_pack(&loc.pack_id, self.sk)?));
+                }
+                let (_, reader) = self.pack.as_mut().unwrap();
+                reader.read(loc.offset, loc.length)?
+            }
+            Some(_) => return Err(RepoError::InvalidDataError),
+            None => match self.repo.read_object(&self.index, self.sk, address)? {
+                (ObjectKind::Chunk, data) => data,
+                _ => return Err(RepoError::InvalidDataError),
+            },
+        };
+        let data = Arc::new(data);
+        self.cache.insert(*address, data.clone());
+        Ok(data)
+    }
+
+    fn offsets(&mut self, ino: u64) -> Result<Vec<u64>, RepoError> {
+        let node = self.node(ino)?;
+        if let Some(ref offsets) = node.offsets {
+            return Ok(offsets.clone());
+        }
+        match node.entry.kind {
+            EntryKind::File => (),
+            EntryKind::Dir => return Err(error(io::ErrorKind::IsADirectory)),
+            _ => return Err(error(io::ErrorKind::InvalidInput)),
+        }
+        let (refs, size) = (node.entry.refs.clone(), node.entry.size);
+        let mut offsets = vec![0];
+        for address in refs.iter() {
+            let len = match self.index.lookup(address) {
+                Some(loc) => loc.size as u64,
+                None => self.fetch(address)?.len() as u64,
+            };
+            offsets.push(offsets.last().unwrap() + len);
+        }
+        if *offsets.last().unwrap() != size {
+            return Err(RepoError::InvalidDataError);
+        }
+        self.node_mut(ino).offsets = Some(offsets.clone());
+        Ok(offsets)
+    }
+
+    // Up to `size` bytes of a file from `offset`, fewer only at its end.
+    pub fn read(&mut self, ino: u64, offset: u64, size: usize) -> Result<Vec<u8>, RepoError> {
+        let offsets = self.offsets(ino)?;
+        let refs = self.node(ino)?.entry.refs.clone();
+        let end = (offset + size as u64).min(*offsets.last().unwrap());
+        let mut out = Vec::with_capacity(end.saturating_sub(offset) as usize);
+        let mut i = offsets.partition_point(|o| *o <= offset).saturating_sub(1);
+        while i < refs.len() && offsets[i] < end {
+            let data = self.fetch(&refs[i])?;
+            if data.len() as u64 != offsets[i + 1] - offsets[i] {
+                return Err(RepoError::InvalidDataError);
+            }
+            let from = offset.saturating_sub(offsets[i]) as usize;
+            let to = (end - offsets[i]).min(data.len() as u64) as usize;
+            out.extend_from_slice(&data[from..to]);
+            i += 1;
+        }
+        Ok(out)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_snapshot_fs() {
+    use super::address::AddressKey;
+    use super::namespace::Namespace;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let dir = super::storage::local::test_dir("snapfs");
+    let big = super::chunker::test_data(5 << 20, 7);
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/big"), &big).unwrap();
+    symlink("sub/big", dir.join("link")).unwrap();
+    let ns = Namespace::new("laptop").unwrap();
+    let (head, _) = r.backup(&dir, &ns, &ak, &key, &Default::default()).unwrap();
+    let mut m = r.manifest().unwrap();
+    let mut twin = head.clone();
+    twin.address.bytes[0] ^= 1;
+    m.add_head(twin);
+    r.commit_manifest(&m, &key).unwrap();
+
+    let mut snapfs = SnapshotFs::new(&r, &key.box_sk, 1 << 20).unwrap();
+    let laptop = snapfs.lookup(ROOT_INO, b"laptop").unwrap();
+    assert_eq!(snapfs.children(ROOT_INO).unwrap(), vec![laptop]);
+    let time = DateTime::from_unix(head.timestamp).to_rfc3339();
+    let names: Vec<Vec<u8>> = snapfs
+        .children(laptop)
+        .unwrap()
+        .iter()
+        .map(|ino| snapfs.name(*ino).unwrap().to_vec())
+        .collect();
+    assert!(names.contains(&time.as_bytes().to_vec()));
+    assert!(names.contains(&format!("{}-2", time).into_bytes()));
+
+    let snap = snapfs.lookup(laptop, time.as_bytes()).unwrap();
+    let sub = snapfs.lookup(snap, b"sub").unwrap();
+    assert_eq!(snapfs.parent(sub).unwrap(), snap);
+    let file = snapfs.lookup(sub, b"big").unwrap();
+    let attr = snapfs.attr(file).unwrap();
+    assert_eq!((attr.kind, attr.size), (EntryKind::File, big.len() as u64));
+    let link = snapfs.lookup(snap, b"link").unwrap();
+    assert_eq!(snapfs.readlink(link).unwrap(), b"sub/big");
+
+    // Reads spanning chunks, past the end and at the end.
+    for (offset, size) in [
+        (0, 100),
+        (1 << 20, 3 << 20),
+        (5 << 20, 10),
+        ((5 << 20) - 3, 10),
+    ]
+    .iter()
+    {
+        let got = snapfs.read(file, *offset as u64, *size).unwrap();
+        let end = (offset + size).min(big.len());
+        assert_eq!(got, &big[*offset..end]);
+    }
+    assert!(snapfs.cache.bytes <= 1 << 20);
+    assert_eq!(snapfs.cache.chunks.len(), snapfs.cache.by_use.len());
+
+    for err in [
+        snapfs.lookup(snap, b"nope"),
+        snapfs.lookup(file, b"x"),
+        snapfs.read(sub, 0, 1).map(|_| 0),
+        snapfs.lookup(9999, b"x"),
+    ]
+    .iter()
+    {
+        assert!(matches!(err, Err(RepoError::IOError(_))));
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
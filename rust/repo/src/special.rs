@@ -0,0 +1,156 @@
+//! Device nodes, fifos and sockets.
+//!
+//! Backups store them as entries of their own kinds, devices with their
+//! major and minor numbers, see `tree`, and restores make them again with
+//! `mknod`, so a restored system still has its `/dev` entries and named
+//! pipes. Numbers are stored split rather than as the `dev_t` of the
+//! machine backed up, whose encoding differs between systems, and each
+//! system's own `major`, `minor` and `makedev` convert them.
+//!
+//! Making a device needs root, or `CAP_MKNOD`, so a restore by anyone
+//! else records each device it cannot make, like any file it cannot
+//! write. A socket restored is only a name, nothing listens on it.
+//! `BackupOptions::skip_specials` and `RestoreOptions::skip_specials`
+//! leave them all out.
+
+use super::tree::{EntryKind, TreeEntry};
+use std::ffi::CString;
+use std::fs::FileType;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+
+// The kind of a device, fifo or socket, None for anything else.
+pub fn kind(ft: &FileType) -> Option<EntryKind> {
+    if ft.is_block_device() {
+        Some(EntryKind::BlockDevice)
+    } else if ft.is_char_device() {
+        Some(EntryKind::CharDevice)
+    } else if ft.is_fifo() {
+        Some(EntryKind::Fifo)
+    } else if ft.is_socket() {
+        Some(EntryKind::Socket)
+    } else {
+        None
+    }
+}
+
+// The major and minor numbers of a `dev_t`, as `MetadataExt::rdev` gives
+// it.
+pub fn device_numbers(rdev: u64) -> (u32, u32) {
+    let dev = rdev as libc::dev_t;
+    (libc::major(dev) as u32, libc::minor(dev) as u32)
+}
+
+fn make_dev((major, minor): (u32, u32)) -> libc::dev_t {
+    libc::makedev(major as _, minor as _)
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))
+}
+
+// Make the device, fifo or socket `ent` at `path`, which must not exist.
+// The mode is left to the umask.
+pub fn make(path: &Path, ent: &TreeEntry) -> io::Result<()> {
+    let file_type = match ent.kind {
+        EntryKind::BlockDevice => 0o060000,
+        EntryKind::CharDevice => 0o020000,
+        EntryKind::Fifo => 0o010000,
+        EntryKind::Socket => 0o140000,
+        _ => return Err(io::Error::from(io::ErrorKind::InvalidInput)),
+    };
+    let p = c_path(path)?;
+    let mode = (file_type | (ent.mode & 0o777)) as libc::mode_t;
+    if unsafe { libc::mknod(p.as_ptr(), mode, make_dev(ent.device)) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Set the modification time of `path` to that of `ent`, and its access
+// time if `ent` has one, without opening it or following a symlink.
+pub fn set_times(path: &Path, ent: &TreeEntry) -> io::Result<()> {
+    let p = c_path(path)?;
+    // Zeroed first, some targets pad the struct.
+    let timespec = |secs: u64, nsec| {
+        let mut t: libc::timespec = unsafe { std::mem::zeroed() };
+        t.tv_sec = secs as libc::time_t;
+        t.tv_nsec = nsec;
+        t
+    };
+    let ts = |(secs, nsec): (u64, u32)| timespec(secs, nsec as _);
+    let omit = timespec(0, libc::UTIME_OMIT);
+    let times = [ent.atime.map_or(omit, ts), ts((ent.mtime, ent.mtime_nsec))];
+    let (dirfd, flags) = (libc::AT_FDCWD, libc::AT_SYMLINK_NOFOLLOW);
+    if unsafe { libc::utimensat(dirfd, p.as_ptr(), times.as_ptr(), flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Tests --------------------
+
+#[test]
+fn test_special() {
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    assert_eq!(device_numbers(make_dev((8, 1))), (8, 1));
+    assert_eq!(device_numbers(make_dev((259, 70000))), (259, 70000));
+    // /dev/null on Linux.
+    if cfg!(target_os = "linux") {
+        assert_eq!(make_dev((1, 3)), 0x103);
+    }
+
+    let dir = super::storage::local::test_dir("special");
+    fs::create_dir_all(&dir).unwrap();
+    let ent = |kind: EntryKind, device: (u32, u32)| TreeEntry {
+        name: b"x".to_vec(),
+        kind,
+        mode: 0o640,
+        uid: 0,
+        gid: 0,
+        mtime: 1_600_000_000,
+        mtime_nsec: 5,
+        size: 0,
+        refs: Vec::new(),
+        target: Vec::new(),
+        link: 0,
+        xattrs: Vec::new(),
+        device,
+        atime: None,
+        birthtime: None,
+    };
+    make(&dir.join("fifo"), &ent(EntryKind::Fifo, (0, 0))).unwrap();
+    make(&dir.join("socket"), &ent(EntryKind::Socket, (0, 0))).unwrap();
+    let timed = TreeEntry {
+        atime: Some((1_500_000_000, 6)),
+        ..ent(EntryKind::Fifo, (0, 0))
+    };
+    set_times(&dir.join("fifo"), &timed).unwrap();
+    let meta = fs::symlink_metadata(dir.join("fifo")).unwrap();
+    assert_eq!(kind(&meta.file_type()), Some(EntryKind::Fifo));
+    assert_eq!((meta.mtime(), meta.mtime_nsec()), (1_600_000_000, 5));
+    assert_eq!((meta.atime(), meta.atime_nsec()), (1_500_000_000, 6));
+    // Without one the access time is left alone.
+    set_times(&dir.join("fifo"), &ent(EntryKind::Fifo, (0, 0))).unwrap();
+    let meta = fs::symlink_metadata(dir.join("fifo")).unwrap();
+    assert_eq!(meta.atime(), 1_500_000_000);
+    let meta = fs::symlink_metadata(dir.join("socket")).unwrap();
+    assert_eq!(kind(&meta.file_type()), Some(EntryKind::Socket));
+    assert!(make(&dir.join("fifo"), &ent(EntryKind::Fifo, (0, 0))).is_err());
+    assert!(make(&dir.join("file"), &ent(EntryKind::File, (0, 0))).is_err());
+    // Only root makes devices.
+    match make(&dir.join("null"), &ent(EntryKind::CharDevice, (1, 3))) {
+        Ok(()) => {
+            let meta = fs::symlink_metadata(dir.join("null")).unwrap();
+            assert_eq!(kind(&meta.file_type()), Some(EntryKind::CharDevice));
+            assert_eq!(device_numbers(meta.rdev()), (1, 3));
+        }
+        Err(err) => assert_eq!(err.kind(), io::ErrorKind::PermissionDenied),
+    }
+    assert_eq!(kind(&fs::metadata(&dir).unwrap().file_type()), None);
+    fs::remove_dir_all(&dir).unwrap();
+}
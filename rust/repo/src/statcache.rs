@@ -0,0 +1,268 @@
+//! Client side cache of file metadata, for fast incremental backups.
+//!
+//! Reading every file each run dominates the time of a backup where
+//! little changed. After a backup commits, the stat cache records each
+//! file it stored with the metadata it had and its chunks. The next backup
+//! of the same directory into the same namespace takes the chunks of a
+//! file from the cache instead of reading it, as long as its size, mtime,
+//! ctime and inode are all unchanged and every chunk is still in the
+//! repository. ctime changes with any write, even one that restores the
+//! mtime, and a new file reusing an inode gets a new ctime.
+//!
+//! A file whose ctime is not older than the start of the backup, to the
+//! second, is not cached: it could change again within the same tick of
+//! the clock without its times changing. `BackupOptions::force_rescan`
+//! reads every file regardless and rewrites the cache.
+//!
+//! Layout below `<cache root>/<hex repo id>/`, the same as `cache`:
+//!
+//! ```text
+//! stat/<hex id>  u16:format_version [32]:key_check u32:n n * file
+//!                [32]:checksum
+//! file:          bytes:path u64:size u64:mtime u32:mtime_nsec u64:ctime
+//!                u32:ctime_nsec u64:ino u32:n_refs n_refs * [32]:address
+//! ```
+//!
+//! `id` is the sha512 prefix of the namespace, a NUL and the source path,
+//! so each backed up directory has its own cache. `key_check` is the
+//! address of a fixed string under the address key, a cache written with
+//! another key is ignored. Paths are relative to the source. `checksum` is
+//! the sha512 prefix of everything before it. A cache that fails any check
+//! is treated as empty, losing it only costs a full read.
+
+use super::address::{to_hex, Address, AddressKey};
+use super::manifest::RepoId;
+use super::namespace::Namespace;
+use super::storage::local::{LocalStorage, SyncPolicy};
+use super::storage::StorageEngine;
+use super::wire::{Decoder, Encoder};
+use super::RepoError;
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use tweetnacl::*;
+
+pub const STAT_CACHE_FORMAT_VERSION: u16 = 1;
+
+const STAT_DIR: &str = "stat";
+const CHECKSUM_SZ: usize = 32;
+const KEY_CHECK: &[u8] = b"packnback stat cache";
+const MIN_FILE_SZ: usize = 4 + 8 + 8 + 4 + 8 + 4 + 8 + 4;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CachedFile {
+    pub size: u64,
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+    pub ctime: i64,
+    pub ctime_nsec: u32,
+    pub ino: u64,
+    pub refs: Vec<Address>,
+}
+
+impl CachedFile {
+    pub fn new(meta: &Metadata, refs: Vec<Address>) -> CachedFile {
+        CachedFile {
+            size: meta.len(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec() as u32,
+            ctime: meta.ctime(),
+            ctime_nsec: meta.ctime_nsec() as u32,
+            ino: meta.ino(),
+            refs,
+        }
+    }
+
+    fn matches(&self, meta: &Metadata) -> bool {
+        let now = CachedFile::new(meta, Vec::new());
+        (
+            self.size,
+            self.mtime,
+            self.mtime_nsec,
+            self.ctime,
+            self.ctime_nsec,
+            self.ino,
+        ) == (
+            now.size,
+            now.mtime,
+            now.mtime_nsec,
+            now.ctime,
+            now.ctime_nsec,
+            now.ino,
+        )
+    }
+}
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_SZ] {
+    let mut h = [0; CRYPTO_HASH_BYTES];
+    crypto_hash(&mut h, data);
+    let mut c = [0; CHECKSUM_SZ];
+    c.copy_from_slice(&h[..CHECKSUM_SZ]);
+    c
+}
+
+fn cache_key(namespace: &Namespace, source: &[u8]) -> String {
+    let id = checksum(&[namespace.as_str().as_bytes(), b"\0", source].concat());
+    format!("{}/{}", STAT_DIR, to_hex(&id))
+}
+
+#[derive(Default)]
+pub struct StatCache {
+    files: HashMap<Vec<u8>, CachedFile>,
+}
+
+impl StatCache {
+    pub fn get(&self, path: &[u8], meta: &Metadata) -> Option<&CachedFile> {
+        self.files.get(path).filter(|f| f.matches(meta))
+    }
+
+    pub fn insert(&mut self, path: Vec<u8>, file: CachedFile) {
+        self.files.insert(path, file);
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn encode(&self, address_key: &AddressKey) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.u16(STAT_CACHE_FORMAT_VERSION)
+            .fixed(&address_key.address(KEY_CHECK).bytes)
+            .u32(self.files.len() as u32);
+        for (path, f) in self.files.iter() {
+            e.bytes(path)
+                .u64(f.size)
+                .u64(f.mtime as u64)
+                .u32(f.mtime_nsec)
+                .u64(f.ctime as u64)
+                .u32(f.ctime_nsec)
+                .u64(f.ino)
+                .u32(f.refs.len() as u32);
+            for address in f.refs.iter() {
+                address.encode(&mut e);
+            }
+        }
+        let mut buf = e.into_vec();
+        let sum = checksum(&buf);
+        buf.extend_from_slice(&sum);
+        buf
+    }
+
+    pub fn decode(buf: &[u8], address_key: &AddressKey) -> Result<StatCache, RepoError> {
+        if buf.len() < CHECKSUM_SZ {
+            return Err(RepoError::InvalidDataError);
+        }
+        let (body, sum) = buf.split_at(buf.len() - CHECKSUM_SZ);
+        if checksum(body)[..] != sum[..] {
+            return Err(RepoError::InvalidDataError);
+        }
+        let mut d = Decoder::new(body);
+        if d.u16()? != STAT_CACHE_FORMAT_VERSION {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        if d.fixed(32)? != &address_key.address(KEY_CHECK).bytes[..] {
+            return Err(RepoError::InvalidDataError);
+        }
+        let n = d.count(MIN_FILE_SZ)?;
+        let mut files = HashMap::with_capacity(n);
+        for _ in 0..n {
+            let path = d.bytes()?.to_vec();
+            let mut f = CachedFile {
+                size: d.u64()?,
+                mtime: d.u64()? as i64,
+                mtime_nsec: d.u32()?,
+                ctime: d.u64()? as i64,
+                ctime_nsec: d.u32()?,
+                ino: d.u64()?,
+                refs: Vec::new(),
+            };
+            let n_refs = d.count(32)?;
+            for _ in 0..n_refs {
+                f.refs.push(Address::decode(&mut d)?);
+            }
+            files.insert(path, f);
+        }
+        d.finish()?;
+        Ok(StatCache { files })
+    }
+
+    // The cache for backups of `source` into `namespace`, empty if there
+    // is none or it cannot be used.
+    pub fn load(
+        root: &Path,
+        repo_id: &RepoId,
+        namespace: &Namespace,
+        source: &[u8],
+        address_key: &AddressKey,
+    ) -> StatCache {
+        let storage = match open_storage(root, repo_id) {
+            Ok(storage) => storage,
+            Err(_) => return Default::default(),
+        };
+        match storage.get(&cache_key(namespace, source)) {
+            Ok(buf) => StatCache::decode(&buf, address_key).unwrap_or_default(),
+            Err(_) => Default::default(),
+        }
+    }
+
+    pub fn save(
+        &self,
+        root: &Path,
+        repo_id: &RepoId,
+        namespace: &Namespace,
+        source: &[u8],
+        address_key: &AddressKey,
+    ) -> Result<(), RepoError> {
+        let storage = open_storage(root, repo_id)?;
+        storage.put(&cache_key(namespace, source), &self.encode(address_key))
+    }
+}
+
+// Never synced, losing the cache to a crash only costs a full read.
+fn open_storage(root: &Path, repo_id: &RepoId) -> Result<LocalStorage, RepoError> {
+    LocalStorage::with_sync_policy(&root.join(to_hex(&repo_id.bytes)), SyncPolicy::Never)
+}
+
+// Tests --------------------
+
+#[test]
+fn test_stat_cache() {
+    let root = super::storage::local::test_dir("stat-cache");
+    std::fs::create_dir_all(&root).unwrap();
+    let file = root.join("file");
+    std::fs::write(&file, b"data").unwrap();
+    let meta = std::fs::metadata(&file).unwrap();
+    let ak = AddressKey::new();
+    let repo_id = RepoId::new();
+    let ns = Namespace::new("laptop").unwrap();
+    let refs = vec![Address { bytes: [7; 32] }];
+
+    let mut cache: StatCache = Default::default();
+    cache.insert(b"a/file".to_vec(), CachedFile::new(&meta, refs.clone()));
+    cache.save(&root, &repo_id, &ns, b"/src", &ak).unwrap();
+    cache.save(&root, &repo_id, &ns, b"/src", &ak).unwrap();
+    let loaded = StatCache::load(&root, &repo_id, &ns, b"/src", &ak);
+    assert_eq!(loaded.get(b"a/file", &meta).unwrap().refs, refs);
+    assert!(loaded.get(b"b/file", &meta).is_none());
+
+    // Any change to the file misses.
+    std::fs::write(&file, b"more data").unwrap();
+    assert!(loaded
+        .get(b"a/file", &std::fs::metadata(&file).unwrap())
+        .is_none());
+
+    // Other sources, namespaces and keys have their own caches.
+    assert!(StatCache::load(&root, &repo_id, &ns, b"/other", &ak).is_empty());
+    let server = Namespace::new("server").unwrap();
+    assert!(StatCache::load(&root, &repo_id, &server, b"/src", &ak).is_empty());
+    assert!(StatCache::load(&root, &repo_id, &ns, b"/src", &AddressKey::new()).is_empty());
+    let mut buf = cache.encode(&ak);
+    buf[10] ^= 1;
+    assert!(StatCache::decode(&buf, &ak).is_err());
+    std::fs::remove_dir_all(&root).unwrap();
+}
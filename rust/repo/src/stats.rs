@@ -0,0 +1,398 @@
+//! Repository statistics.
+//!
+//! Sizes are computed from the pack indexes and the reference lists of
+//! tree and snapshot objects, chunks are never read. Walking the trees
+//! needs the repository box key.
+//!
+//! - `stored_bytes` is the size of every pack, `parity_bytes` the size of
+//!   their parity.
+//! - `unique_bytes` is the plaintext size of every object reachable from
+//!   the manifest, counted once, `compressed_bytes` what those came to
+//!   once compressed, see `compress`, and `sealed_bytes` once sealed.
+//! - `logical_bytes` is what the snapshots would take without any
+//!   deduplication, every reference counted as often as it occurs, summed
+//!   over all snapshots.
+//! - A snapshot's own `unique_bytes` are the plaintext bytes no other
+//!   snapshot references, about what forgetting it would free.
+//! - A pack's `live_bytes` are the sealed bytes of the reachable objects
+//!   the repository index resolves to it, see `repack`.
+//! - `usage` lists the per client records kept by `serve`, see `usage`.
+//!
+//! The compression ratio is `unique_bytes` over `compressed_bytes`, 1 for
+//! objects that were stored as they are, sealing overhead left out.
+//!
+//! `write_json` emits a single JSON object:
+//!
+//! ```text
+//! {"stored_bytes": n, "parity_bytes": n, "unique_bytes": n,
+//!  "logical_bytes": n, "compressed_bytes": n, "sealed_bytes": n,
+//!  "objects": n,
+//!  "dedup_ratio": x, "compression_ratio": x, "pack_utilization": x,
+//!  "snapshots": [{"address": hex, "namespace": s, "timestamp": n,
+//!                 "logical_bytes": n, "unique_bytes": n}, ...],
+//!  "packs": [{"id": hex, "size": n, "live_bytes": n}, ...],
+//!  "usage": [{"client": s, "stored_bytes": n, "objects": n}, ...]}
+//! ```
+//!
+//! `write_ndjson` emits it on one line with `"type": "stats"` first, see
+//! `json`.
+
+use super::address::Address;
+use super::crypto::SEAL_OVERHEAD;
+use super::index::{PackIndex, RepoIndex};
+use super::lock::LockMode;
+use super::manifest::SnapshotHead;
+use super::object::decode_refs;
+use super::pack::PackId;
+use super::parity::parity_key;
+use super::usage::Usage;
+use super::{Repo, RepoError};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use tweetnacl::CryptoBoxSk;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SnapshotStats {
+    pub head: SnapshotHead,
+    pub logical_bytes: u64,
+    pub unique_bytes: u64,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PackStats {
+    pub id: PackId,
+    pub size: u64,
+    pub live_bytes: u64,
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct RepoStats {
+    pub stored_bytes: u64,
+    pub parity_bytes: u64,
+    pub unique_bytes: u64,
+    pub logical_bytes: u64,
+    // Compressed and sealed size of every reachable object, counted once.
+    pub compressed_bytes: u64,
+    pub sealed_bytes: u64,
+    pub objects: usize,
+    pub snapshots: Vec<SnapshotStats>,
+    pub packs: Vec<PackStats>,
+    pub usage: Vec<(String, Usage)>,
+}
+
+// Ratios of an empty repository are 1.
+fn ratio(a: u64, b: u64) -> f64 {
+    if b == 0 {
+        1.0
+    } else {
+        a as f64 / b as f64
+    }
+}
+
+impl RepoStats {
+    pub fn dedup_ratio(&self) -> f64 {
+        ratio(self.logical_bytes, self.unique_bytes)
+    }
+
+    pub fn compression_ratio(&self) -> f64 {
+        ratio(self.unique_bytes, self.compressed_bytes)
+    }
+
+    // Share of stored pack bytes holding live objects.
+    pub fn pack_utilization(&self) -> f64 {
+        let live: u64 = self.packs.iter().map(|p| p.live_bytes).sum();
+        ratio(live, self.stored_bytes)
+    }
+
+    pub fn write_json(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.write_object(w, "{")
+    }
+
+    // The same object, with `"type": "stats"` first, see `json`.
+    pub fn write_ndjson(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.write_object(w, "{\"type\": \"stats\", ")
+    }
+
+    fn write_object(&self, w: &mut dyn Write, open: &str) -> io::Result<()> {
+        write!(
+            w,
+            "{}\"stored_bytes\": {}, \"parity_bytes\": {}, \"unique_bytes\": {}, \
+             \"logical_bytes\": {}, \"compressed_bytes\": {}, \"sealed_bytes\": {}, \
+             \"objects\": {}, \
+             \"dedup_ratio\": {:.4}, \"compression_ratio\": {:.4}, \
+             \"pack_utilization\": {:.4}, \"snapshots\": [",
+            open,
+            self.stored_bytes,
+            self.parity_bytes,
+            self.unique_bytes,
+            self.logical_bytes,
+            self.compressed_bytes,
+            self.sealed_bytes,
+            self.objects,
+            self.dedup_ratio(),
+            self.compression_ratio(),
+            self.pack_utilization()
+        )?;
+        for (i, s) in self.snapshots.iter().enumerate() {
+            // Namespaces never need escaping, see `namespace`.
+            write!(
+                w,
+                "{}{{\"address\": \"{}\", \"namespace\": \"{}\", \"timestamp\": {}, \
+                 \"logical_bytes\": {}, \"unique_bytes\": {}}}",
+                if i == 0 { "" } else { ", " },
+                s.head.address.to_hex(),
+                s.head.namespace,
+                s.head.timestamp,
+                s.logical_bytes,
+                s.unique_bytes
+            )?;
+        }
+        write!(w, "], \"packs\": [")?;
+        for (i, p) in self.packs.iter().enumerate() {
+            write!(
+                w,
+                "{}{{\"id\": \"{}\", \"size\": {}, \"live_bytes\": {}}}",
+                if i == 0 { "" } else { ", " },
+                p.id.to_hex(),
+                p.size,
+                p.live_bytes
+            )?;
+        }
+        write!(w, "], \"usage\": [")?;
+        // Client names never need escaping either.
+        for (i, (client, u)) in self.usage.iter().enumerate() {
+            write!(
+                w,
+                "{}{{\"client\": \"{}\", \"stored_bytes\": {}, \"objects\": {}}}",
+                if i == 0 { "" } else { ", " },
+                client,
+                u.stored_bytes,
+                u.objects
+            )?;
+        }
+        writeln!(w, "]}}")
+    }
+}
+
+// The object graph below the manifest heads, each object's references in
+// order and with repeats.
+pub(crate) struct Graph<'a> {
+    index: &'a RepoIndex,
+    refs: HashMap<Address, Vec<Address>>,
+    // Every object below the heads.
+    pub(crate) live: HashSet<Address>,
+}
+
+impl<'a> Graph<'a> {
+    // Read the reference lists below `heads`, never chunks.
+    pub(crate) fn load(
+        repo: &Repo,
+        index: &'a RepoIndex,
+        sk: &CryptoBoxSk,
+        heads: &[SnapshotHead],
+    ) -> Result<Graph<'a>, RepoError> {
+        let mut graph = Graph {
+            index,
+            refs: HashMap::new(),
+            live: HashSet::new(),
+        };
+        let mut todo: Vec<Address> = heads.iter().flat_map(|h| h.roots()).collect();
+        while let Some(address) = todo.pop() {
+            if !graph.live.insert(address) {
+                continue;
+            }
+            let loc = match index.lookup(&address) {
+                Some(loc) => *loc,
+                None => return Err(RepoError::MissingObjectError),
+            };
+            if loc.kind.has_refs() {
+                let mut pack = repo.open_pack(&loc.pack_id, sk)?;
+                let refs = decode_refs(&pack.read(loc.offset, loc.length)?)?;
+                todo.extend_from_slice(&refs);
+                graph.refs.insert(address, refs);
+            }
+        }
+        Ok(graph)
+    }
+
+    pub(crate) fn plain_size(&self, address: &Address) -> u64 {
+        self.index
+            .lookup(address)
+            .map(|l| l.size as u64)
+            .unwrap_or(0)
+    }
+
+    fn refs(&self, address: &Address) -> &[Address] {
+        self.refs.get(address).map(|r| &r[..]).unwrap_or(&[])
+    }
+
+    pub(crate) fn reachable(&self, from: &Address) -> HashSet<Address> {
+        let mut seen = HashSet::new();
+        let mut todo = vec![*from];
+        while let Some(a) = todo.pop() {
+            if seen.insert(a) {
+                todo.extend_from_slice(self.refs(&a));
+            }
+        }
+        seen
+    }
+
+    // Plaintext size of everything below `from` with every reference
+    // counted, memoized so shared subtrees are only walked once. Corrupt
+    // data could form a cycle, which is cut where it closes.
+    fn logical_size(&self, from: &Address, memo: &mut HashMap<Address, u64>) -> u64 {
+        let mut visiting = HashSet::new();
+        let mut stack = vec![(*from, false)];
+        while let Some((a, expanded)) = stack.pop() {
+            if memo.contains_key(&a) {
+                continue;
+            }
+            if expanded {
+                let below: u64 = self
+                    .refs(&a)
+                    .iter()
+                    .map(|r| memo.get(r).cloned().unwrap_or(0))
+                    .sum();
+                memo.insert(a, self.plain_size(&a) + below);
+            } else if visiting.insert(a) {
+                stack.push((a, true));
+                stack.extend(self.refs(&a).iter().map(|r| (*r, false)));
+            }
+        }
+        memo[from]
+    }
+}
+
+impl Repo {
+    pub fn stats(&self, sk: &CryptoBoxSk) -> Result<RepoStats, RepoError> {
+        let _lock = self.lock(LockMode::Shared)?;
+        let mut indexes: Vec<PackIndex> = Vec::new();
+        for id in self.list_pack_indexes()? {
+            indexes.push(self.read_pack_index(&id)?);
+        }
+        let index = RepoIndex::build(indexes.iter());
+        let heads = self.manifest()?.heads;
+
+        let graph = Graph::load(self, &index, sk, &heads)?;
+        let live = &graph.live;
+
+        let mut stats = RepoStats {
+            objects: live.len(),
+            usage: self.usage()?,
+            ..Default::default()
+        };
+        for a in live.iter() {
+            stats.unique_bytes += graph.plain_size(a);
+            if let Some(l) = index.lookup(a) {
+                // Sealed, the codec byte comes on top of the compressed body.
+                let sealed = l.length as u64;
+                stats.compressed_bytes += sealed.saturating_sub(SEAL_OVERHEAD as u64 + 1);
+                stats.sealed_bytes += sealed;
+            }
+        }
+
+        // Count the snapshots reaching each object, then credit each
+        // snapshot with the objects only it reaches.
+        let mut reached_by: HashMap<Address, u32> = HashMap::new();
+        for h in heads.iter() {
+            for a in graph.reachable(&h.address) {
+                *reached_by.entry(a).or_insert(0) += 1;
+            }
+        }
+        let mut memo = HashMap::new();
+        for h in heads.into_iter() {
+            let unique_bytes = graph
+                .reachable(&h.address)
+                .iter()
+                .filter(|a| reached_by[a] == 1)
+                .map(|a| graph.plain_size(a))
+                .sum();
+            let logical_bytes = graph.logical_size(&h.address, &mut memo);
+            stats.logical_bytes += logical_bytes;
+            stats.snapshots.push(SnapshotStats {
+                head: h,
+                logical_bytes,
+                unique_bytes,
+            });
+        }
+
+        for idx in indexes.iter() {
+            let live_bytes = idx
+                .entries()
+                .iter()
+                .filter(|e| {
+                    live.contains(&e.address)
+                        && index.lookup(&e.address).map(|l| l.pack_id) == Some(idx.pack_id)
+                })
+                .map(|e| e.length as u64)
+                .sum();
+            stats.stored_bytes += idx.pack_size;
+            match self.storage().size(&parity_key(&idx.pack_id)) {
+                Ok(n) => stats.parity_bytes += n,
+                Err(ref e) if e.is_not_found() => (),
+                Err(e) => return Err(e),
+            }
+            stats.packs.push(PackStats {
+                id: idx.pack_id,
+                size: idx.pack_size,
+                live_bytes,
+            });
+        }
+        Ok(stats)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_stats() {
+    use super::gc::test_commit_tree;
+    let (r, key) = super::test_repo();
+    let empty = r.stats(&key.box_sk).unwrap();
+    assert_eq!(empty, Default::default());
+    assert_eq!(empty.dedup_ratio(), 1.0);
+
+    let chunk = |b: u8| Address { bytes: [b; 32] };
+    let shared = chunk(100);
+    test_commit_tree(&r, &key, 1, &[shared, shared, chunk(101)]);
+    test_commit_tree(&r, &key, 2, &[shared, chunk(102)]);
+    let stats = r.stats(&key.box_sk).unwrap();
+    assert_eq!(stats.objects, 7);
+    assert_eq!(stats.packs.len(), 2);
+    assert_eq!(stats.snapshots.len(), 2);
+    assert!(stats.parity_bytes == 0 && stats.stored_bytes > stats.sealed_bytes);
+
+    // Chunks hold their own 32 byte address, see `test_commit_tree`.
+    let s1 = &stats.snapshots[0];
+    let s2 = &stats.snapshots[1];
+    assert_eq!(s1.logical_bytes - s1.unique_bytes, 2 * 32);
+    assert_eq!(s2.logical_bytes - s2.unique_bytes, 32);
+    assert_eq!(stats.logical_bytes, s1.logical_bytes + s2.logical_bytes);
+    assert_eq!(stats.unique_bytes, s1.unique_bytes + s2.unique_bytes + 32);
+    assert!(stats.dedup_ratio() > 1.0);
+    // Chunks repeat a single byte, so compress well.
+    assert_eq!(
+        stats.compressed_bytes + 7 * (SEAL_OVERHEAD as u64 + 1),
+        stats.sealed_bytes
+    );
+    assert!(stats.compressed_bytes < stats.unique_bytes);
+    assert!(stats.compression_ratio() > 1.0);
+    assert_eq!(
+        stats.pack_utilization(),
+        ratio(stats.sealed_bytes, stats.stored_bytes)
+    );
+
+    let mut json = Vec::new();
+    stats.write_json(&mut json).unwrap();
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.starts_with("{\"stored_bytes\": "));
+    assert!(json.contains(&format!("\"address\": \"{}\"", s1.head.address.to_hex())));
+    assert!(json.contains("\"namespace\": \"laptop\""));
+    assert!(json.ends_with("\"usage\": []}\n"));
+    let mut ndjson = Vec::new();
+    stats.write_ndjson(&mut ndjson).unwrap();
+    assert_eq!(
+        String::from_utf8(ndjson).unwrap(),
+        format!("{{\"type\": \"stats\", {}", &json[1..])
+    );
+}
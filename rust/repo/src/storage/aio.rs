@@ -0,0 +1,166 @@
+//! Asynchronous storage.
+//!
+//! `AsyncStorageEngine` is `StorageEngine` for use from a tokio runtime, so
+//! transfers can keep hundreds of requests in flight without an OS thread
+//! for each, see `pipeline`. The semantics of every operation are those of
+//! the blocking trait.
+//!
+//! `Blocking` serves any `StorageEngine` asynchronously by running its
+//! calls on the runtime's blocking thread pool. Each request in flight
+//! still holds a thread there, it lets backends without native
+//! asynchronous I/O take part in the pipelines until they have it. Memory
+//! storage is served natively.
+
+use super::mem::MemStorage;
+use super::{Capabilities, StorageEngine};
+use crate::RepoError;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+pub trait AsyncStorageEngine: Send + Sync {
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, Result<(), RepoError>>;
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Vec<u8>, RepoError>>;
+
+    fn get_range<'a>(
+        &'a self,
+        key: &'a str,
+        offset: u64,
+        len: usize,
+    ) -> BoxFuture<'a, Result<Vec<u8>, RepoError>>;
+
+    fn size<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<u64, RepoError>>;
+
+    // All keys starting with `prefix`, sorted.
+    fn list_prefix<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<String>, RepoError>>;
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), RepoError>>;
+
+    fn capabilities(&self) -> Capabilities;
+}
+
+pub struct Blocking {
+    inner: Arc<dyn StorageEngine>,
+}
+
+impl Blocking {
+    pub fn new(inner: Arc<dyn StorageEngine>) -> Blocking {
+        Blocking { inner }
+    }
+
+    fn run<T, F>(&self, f: F) -> BoxFuture<'static, Result<T, RepoError>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn StorageEngine) -> Result<T, RepoError> + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        let task = tokio::task::spawn_blocking(move || f(&*inner));
+        Box::pin(async move {
+            match task.await {
+                Ok(r) => r,
+                Err(e) => Err(RepoError::StorageError(e.to_string())),
+            }
+        })
+    }
+}
+
+impl AsyncStorageEngine for Blocking {
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, Result<(), RepoError>> {
+        let key = key.to_string();
+        self.run(move |s| s.put(&key, &data))
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Vec<u8>, RepoError>> {
+        let key = key.to_string();
+        self.run(move |s| s.get(&key))
+    }
+
+    fn get_range<'a>(
+        &'a self,
+        key: &'a str,
+        offset: u64,
+        len: usize,
+    ) -> BoxFuture<'a, Result<Vec<u8>, RepoError>> {
+        let key = key.to_string();
+        self.run(move |s| s.get_range(&key, offset, len))
+    }
+
+    fn size<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<u64, RepoError>> {
+        let key = key.to_string();
+        self.run(move |s| s.size(&key))
+    }
+
+    fn list_prefix<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<String>, RepoError>> {
+        let prefix = prefix.to_string();
+        self.run(move |s| s.list_prefix(&prefix))
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), RepoError>> {
+        let key = key.to_string();
+        self.run(move |s| s.delete(&key))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+}
+
+// Memory operations never block for long.
+impl AsyncStorageEngine for MemStorage {
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, Result<(), RepoError>> {
+        Box::pin(async move { StorageEngine::put(self, key, &data) })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Vec<u8>, RepoError>> {
+        Box::pin(async move { StorageEngine::get(self, key) })
+    }
+
+    fn get_range<'a>(
+        &'a self,
+        key: &'a str,
+        offset: u64,
+        len: usize,
+    ) -> BoxFuture<'a, Result<Vec<u8>, RepoError>> {
+        Box::pin(async move { StorageEngine::get_range(self, key, offset, len) })
+    }
+
+    fn size<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<u64, RepoError>> {
+        Box::pin(async move { StorageEngine::size(self, key) })
+    }
+
+    fn list_prefix<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<String>, RepoError>> {
+        Box::pin(async move { StorageEngine::list_prefix(self, prefix) })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), RepoError>> {
+        Box::pin(async move { StorageEngine::delete(self, key) })
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        StorageEngine::capabilities(self)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_blocking() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let mem = Arc::new(MemStorage::new());
+    let engines: Vec<Arc<dyn AsyncStorageEngine>> =
+        vec![Arc::new(Blocking::new(mem.clone())), mem.clone()];
+    for s in engines {
+        rt.block_on(async {
+            s.put("a/b", b"hello".to_vec()).await.unwrap();
+            assert_eq!(s.get("a/b").await.unwrap(), b"hello");
+            assert_eq!(s.get_range("a/b", 1, 3).await.unwrap(), b"ell");
+            assert_eq!(s.size("a/b").await.unwrap(), 5);
+            assert_eq!(s.list_prefix("a/").await.unwrap(), vec!["a/b"]);
+            s.delete("a/b").await.unwrap();
+            assert!(s.get("a/b").await.unwrap_err().is_not_found());
+        });
+    }
+}
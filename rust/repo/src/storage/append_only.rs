@@ -0,0 +1,163 @@
+//! Client side enforcement of append only repositories.
+//!
+//! Wraps another engine and refuses to delete, rename or overwrite
+//! anything except the manifest, policy, access list, revocations, the
+//! manifest log and the list of packs retention locks hold, which are
+//! replaced rather than added to, and locks and the scrub state, which
+//! hold no data. This only protects against client bugs, a compromised
+//! client can simply skip the wrapper. Real protection needs a server
+//! that enforces the same rules, see `serve`.
+
+use super::{Capabilities, StorageEngine, ThawState};
+use crate::address::Address;
+use crate::checks::CheckStatus;
+use crate::gc::GcStats;
+use crate::lock::is_lock_key;
+use crate::translog::LogHash;
+use crate::usage::Quota;
+use crate::{
+    RepoError, ACL_FILE, MANIFEST_FILE, POLICY_FILE, RETAINED_FILE, REVOCATIONS_FILE, SCRUB_FILE,
+    TRANSLOG_FILE,
+};
+use std::sync::Arc;
+
+pub struct AppendOnlyStorage {
+    inner: Arc<dyn StorageEngine>,
+}
+
+impl AppendOnlyStorage {
+    pub fn new(inner: Arc<dyn StorageEngine>) -> AppendOnlyStorage {
+        AppendOnlyStorage { inner }
+    }
+}
+
+pub(crate) fn replaceable(key: &str) -> bool {
+    key == MANIFEST_FILE
+        || key == POLICY_FILE
+        || key == ACL_FILE
+        || key == SCRUB_FILE
+        || key == REVOCATIONS_FILE
+        || key == TRANSLOG_FILE
+        || key == RETAINED_FILE
+        || is_lock_key(key)
+}
+
+impl StorageEngine for AppendOnlyStorage {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), RepoError> {
+        if !replaceable(key) && self.inner.exists(key)? {
+            return Err(RepoError::PermissionDeniedError);
+        }
+        self.inner.put(key, data)
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, RepoError> {
+        self.inner.get(key)
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: usize) -> Result<Vec<u8>, RepoError> {
+        self.inner.get_range(key, offset, len)
+    }
+
+    fn size(&self, key: &str) -> Result<u64, RepoError> {
+        self.inner.size(key)
+    }
+
+    fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, RepoError> {
+        self.inner.list_prefix(prefix)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RepoError> {
+        if !is_lock_key(key) {
+            return Err(RepoError::PermissionDeniedError);
+        }
+        self.inner.delete(key)
+    }
+
+    fn rename(&self, _from: &str, _to: &str) -> Result<(), RepoError> {
+        Err(RepoError::PermissionDeniedError)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            atomic_rename: false,
+            delete: false,
+            ..self.inner.capabilities()
+        }
+    }
+
+    fn thaw(&self, key: &str) -> Result<ThawState, RepoError> {
+        self.inner.thaw(key)
+    }
+
+    fn exists_many(&self, keys: &[&str]) -> Result<Vec<bool>, RepoError> {
+        self.inner.exists_many(keys)
+    }
+
+    // Nothing is stored if any object would overwrite another.
+    fn put_many(&self, objects: &[(&str, &[u8])]) -> Result<(), RepoError> {
+        let keys: Vec<&str> = objects
+            .iter()
+            .map(|(key, _)| *key)
+            .filter(|key| !replaceable(key))
+            .collect();
+        if self.inner.exists_many(&keys)?.contains(&true) {
+            return Err(RepoError::PermissionDeniedError);
+        }
+        self.inner.put_many(objects)
+    }
+
+    fn collect_garbage(&self, _keep_list: &[u8]) -> Result<GcStats, RepoError> {
+        Err(RepoError::PermissionDeniedError)
+    }
+
+    fn objects_present(&self, addresses: &[Address]) -> Result<Vec<bool>, RepoError> {
+        self.inner.objects_present(addresses)
+    }
+
+    fn consistency_proof(&self, old: u64, new: u64) -> Result<Vec<LogHash>, RepoError> {
+        self.inner.consistency_proof(old, new)
+    }
+
+    fn quotas(&self) -> Result<Vec<Quota>, RepoError> {
+        self.inner.quotas()
+    }
+
+    fn check_status(&self) -> Result<CheckStatus, RepoError> {
+        self.inner.check_status()
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_append_only_storage() {
+    let inner = Arc::new(super::mem::MemStorage::new());
+    let s = AppendOnlyStorage::new(inner.clone());
+    s.put("packs/00", b"pack").unwrap();
+    match s.put("packs/00", b"replaced") {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected overwrite to be refused"),
+    }
+    match s.put_many(&[("packs/01", &b"new"[..]), ("packs/00", &b"replaced"[..])]) {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected overwrite to be refused"),
+    }
+    assert!(!inner.exists("packs/01").unwrap());
+    match s.delete("packs/00") {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected delete to be refused"),
+    }
+    match s.rename("packs/00", "packs/01") {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected rename to be refused"),
+    }
+    assert_eq!(s.get("packs/00").unwrap(), b"pack");
+    s.put(MANIFEST_FILE, b"1").unwrap();
+    s.put(MANIFEST_FILE, b"2").unwrap();
+    assert_eq!(s.get(MANIFEST_FILE).unwrap(), b"2");
+    s.put("locks/00", b"1").unwrap();
+    s.put("locks/00", b"2").unwrap();
+    s.delete("locks/00").unwrap();
+    assert!(!s.capabilities().delete);
+    assert!(s.capabilities().range_reads);
+}
@@ -0,0 +1,536 @@
+//! Backblaze B2 storage using the native API, enabled with the `b2` feature.
+//!
+//! Values larger than `large_file_threshold` are sent with the large file
+//! API in parts of `part_size`, anything smaller with a single upload. B2
+//! expects clients to handle its failures in specific ways, which this
+//! backend does:
+//!
+//! - An expired authorization token (401) is renewed with
+//!   b2_authorize_account and the request repeated.
+//! - Upload URLs may stop accepting uploads at any time. On a 5xx, 408 or
+//!   connection failure the URL is discarded and a fresh one fetched.
+//! - 429 and 503 are retried with exponential backoff.
+//!
+//! B2 keeps old versions of overwritten files, deleting a key removes
+//! every version so the space is actually freed.
+
+use super::{check_key, check_prefix, not_found, uri_encode, Capabilities, StorageEngine};
+use crate::RepoError;
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+use std::io::Read;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+const AUTHORIZE_URL: &str = "https://api.backblazeb2.com/b2api/v2/b2_authorize_account";
+
+#[derive(Clone)]
+pub struct B2Config {
+    pub key_id: String,
+    pub application_key: String,
+    pub bucket: String,
+    // Prepended to every key, allows several repositories per bucket.
+    pub prefix: String,
+    pub large_file_threshold: usize,
+    pub part_size: usize,
+    pub max_attempts: u32,
+    pub timeout: Duration,
+}
+
+impl B2Config {
+    // Credentials default to the variables the b2 command line tool uses.
+    pub fn new(bucket: &str) -> B2Config {
+        let env = |k: &str| std::env::var(k).unwrap_or_default();
+        B2Config {
+            key_id: env("B2_APPLICATION_KEY_ID"),
+            application_key: env("B2_APPLICATION_KEY"),
+            bucket: bucket.to_string(),
+            prefix: String::new(),
+            large_file_threshold: 200 * 1024 * 1024,
+            part_size: 100 * 1024 * 1024,
+            max_attempts: 5,
+            timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Auth {
+    token: String,
+    api_url: String,
+    download_url: String,
+    bucket_id: String,
+}
+
+#[derive(Clone)]
+struct UploadUrl {
+    url: String,
+    token: String,
+}
+
+pub struct B2Storage {
+    cfg: B2Config,
+    agent: ureq::Agent,
+    auth: Mutex<Option<Auth>>,
+    upload_url: Mutex<Option<UploadUrl>>,
+}
+
+enum Failure {
+    // HTTP status, B2 error code and message.
+    Status(u16, String, String),
+    Transport(String),
+    Fatal(RepoError),
+}
+
+impl From<ureq::Error> for Failure {
+    fn from(err: ureq::Error) -> Failure {
+        match err {
+            ureq::Error::Status(status, resp) => {
+                let body = resp.into_string().unwrap_or_default();
+                let (code, msg) = parse_error(&body);
+                Failure::Status(status, code, msg)
+            }
+            ureq::Error::Transport(t) => Failure::Transport(t.to_string()),
+        }
+    }
+}
+
+impl From<RepoError> for Failure {
+    fn from(err: RepoError) -> Failure {
+        Failure::Fatal(err)
+    }
+}
+
+impl From<std::io::Error> for Failure {
+    fn from(err: std::io::Error) -> Failure {
+        Failure::Transport(err.to_string())
+    }
+}
+
+fn parse_error(body: &str) -> (String, String) {
+    match serde_json::from_str::<Value>(body) {
+        Ok(v) => (
+            v["code"].as_str().unwrap_or_default().to_string(),
+            v["message"].as_str().unwrap_or_default().to_string(),
+        ),
+        Err(_) => (String::new(), body.to_string()),
+    }
+}
+
+fn json_str(v: &Value, field: &str) -> Result<String, Failure> {
+    match v[field].as_str() {
+        Some(s) => Ok(s.to_string()),
+        None => Err(Failure::Fatal(RepoError::StorageError(format!(
+            "b2 response missing {}",
+            field
+        )))),
+    }
+}
+
+fn read_json(resp: ureq::Response) -> Result<Value, Failure> {
+    let body = resp.into_string()?;
+    serde_json::from_str(&body).map_err(|_| {
+        Failure::Fatal(RepoError::StorageError(format!(
+            "b2 bad response: {}",
+            body
+        )))
+    })
+}
+
+fn sha1_hex(data: &[u8]) -> String {
+    crate::address::to_hex(&Sha1::digest(data))
+}
+
+impl B2Storage {
+    // Does not contact B2, the account is authorized by the first request.
+    pub fn new(cfg: B2Config) -> Result<B2Storage, RepoError> {
+        if cfg.part_size < 5 * 1024 * 1024 || cfg.large_file_threshold < cfg.part_size {
+            return Err(RepoError::StorageError(
+                "b2 parts must be at least 5MB and below the large file threshold".to_string(),
+            ));
+        }
+        let agent = ureq::AgentBuilder::new().timeout(cfg.timeout).build();
+        Ok(B2Storage {
+            cfg,
+            agent,
+            auth: Mutex::new(None),
+            upload_url: Mutex::new(None),
+        })
+    }
+
+    fn name(&self, key: &str) -> String {
+        format!("{}{}", self.cfg.prefix, key)
+    }
+
+    fn authorize(&self) -> Result<Auth, Failure> {
+        let mut cached = self.auth.lock().unwrap();
+        if let Some(ref auth) = *cached {
+            return Ok(auth.clone());
+        }
+        let basic =
+            base64_encode(format!("{}:{}", self.cfg.key_id, self.cfg.application_key).as_bytes());
+        let v = read_json(
+            self.agent
+                .get(AUTHORIZE_URL)
+                .set("authorization", &format!("Basic {}", basic))
+                .call()?,
+        )?;
+        let mut auth = Auth {
+            token: json_str(&v, "authorizationToken")?,
+            api_url: json_str(&v, "apiUrl")?,
+            download_url: json_str(&v, "downloadUrl")?,
+            bucket_id: String::new(),
+        };
+        // Keys restricted to a bucket can not list buckets, but say which
+        // bucket they are restricted to.
+        auth.bucket_id = match (
+            v["allowed"]["bucketName"].as_str(),
+            v["allowed"]["bucketId"].as_str(),
+        ) {
+            (Some(name), Some(id)) if name == self.cfg.bucket => id.to_string(),
+            _ => {
+                let req = json!({
+                    "accountId": json_str(&v, "accountId")?,
+                    "bucketName": self.cfg.bucket,
+                });
+                let buckets = self.api(&auth, "b2_list_buckets", &req)?;
+                match buckets["buckets"][0]["bucketId"].as_str() {
+                    Some(id) => id.to_string(),
+                    None => return Err(not_found(&self.cfg.bucket).into()),
+                }
+            }
+        };
+        *cached = Some(auth.clone());
+        Ok(auth)
+    }
+
+    fn api(&self, auth: &Auth, name: &str, req: &Value) -> Result<Value, Failure> {
+        let resp = self
+            .agent
+            .post(&format!("{}/b2api/v2/{}", auth.api_url, name))
+            .set("authorization", &auth.token)
+            .send_string(&req.to_string())?;
+        read_json(resp)
+    }
+
+    // Run `f` until it succeeds, handling the failures B2 documents as
+    // recoverable. `key` names the value in errors.
+    fn retry<T, F>(&self, key: &str, f: F) -> Result<T, RepoError>
+    where
+        F: Fn(&Auth) -> Result<T, Failure>,
+    {
+        let mut delay = Duration::from_millis(500);
+        let mut attempt = 1;
+        loop {
+            let err = match self.authorize().and_then(|auth| f(&auth)) {
+                Ok(v) => return Ok(v),
+                Err(Failure::Fatal(err)) => return Err(err),
+                Err(Failure::Status(404, _, _)) => return Err(not_found(key)),
+                Err(Failure::Status(416, _, _)) => return Err(RepoError::InvalidRangeError),
+                Err(Failure::Status(401, ref code, ref msg)) => {
+                    if code != "expired_auth_token" && code != "bad_auth_token" {
+                        return Err(RepoError::StorageError(format!("b2 {}: {}", key, msg)));
+                    }
+                    self.auth.lock().unwrap().take();
+                    format!("b2 {}: {}", key, msg)
+                }
+                Err(Failure::Status(status, _, msg)) => {
+                    if status != 408 && status != 429 && status < 500 {
+                        return Err(RepoError::StorageError(format!(
+                            "b2 {}: {} {}",
+                            key, status, msg
+                        )));
+                    }
+                    format!("b2 {}: {} {}", key, status, msg)
+                }
+                Err(Failure::Transport(msg)) => format!("b2 {}: {}", key, msg),
+            };
+            // Whatever went wrong, the upload URL may be the cause.
+            self.upload_url.lock().unwrap().take();
+            if attempt >= self.cfg.max_attempts {
+                return Err(RepoError::StorageError(err));
+            }
+            thread::sleep(delay);
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+
+    fn get_upload_url(&self, auth: &Auth) -> Result<UploadUrl, Failure> {
+        if let Some(ref u) = *self.upload_url.lock().unwrap() {
+            return Ok(u.clone());
+        }
+        let v = self.api(
+            auth,
+            "b2_get_upload_url",
+            &json!({ "bucketId": auth.bucket_id }),
+        )?;
+        let u = UploadUrl {
+            url: json_str(&v, "uploadUrl")?,
+            token: json_str(&v, "authorizationToken")?,
+        };
+        *self.upload_url.lock().unwrap() = Some(u.clone());
+        Ok(u)
+    }
+
+    fn download_url(&self, auth: &Auth, key: &str) -> String {
+        format!(
+            "{}/file/{}/{}",
+            auth.download_url,
+            uri_encode(&self.cfg.bucket, true),
+            uri_encode(&self.name(key), false)
+        )
+    }
+
+    fn put_large(&self, key: &str, data: &[u8]) -> Result<(), RepoError> {
+        let file_id = self.retry(key, |auth| {
+            let req = json!({
+                "bucketId": auth.bucket_id,
+                "fileName": self.name(key),
+                "contentType": "application/octet-stream",
+            });
+            json_str(&self.api(auth, "b2_start_large_file", &req)?, "fileId")
+        })?;
+
+        let result = self.upload_parts(key, &file_id, data);
+        if result.is_err() {
+            let _ = self.retry(key, |auth| {
+                self.api(auth, "b2_cancel_large_file", &json!({ "fileId": file_id }))
+            });
+        }
+        result
+    }
+
+    fn upload_parts(&self, key: &str, file_id: &str, data: &[u8]) -> Result<(), RepoError> {
+        let mut sha1s = Vec::new();
+        for (i, part) in data.chunks(self.cfg.part_size).enumerate() {
+            let sha1 = sha1_hex(part);
+            self.retry(key, |auth| {
+                // Part upload URLs are cheap, take a fresh one per attempt.
+                let v = self.api(
+                    auth,
+                    "b2_get_upload_part_url",
+                    &json!({ "fileId": file_id }),
+                )?;
+                self.agent
+                    .post(&json_str(&v, "uploadUrl")?)
+                    .set("authorization", &json_str(&v, "authorizationToken")?)
+                    .set("x-bz-part-number", &(i + 1).to_string())
+                    .set("x-bz-content-sha1", &sha1)
+                    .send_bytes(part)?;
+                Ok(())
+            })?;
+            sha1s.push(sha1);
+        }
+        self.retry(key, |auth| {
+            let req = json!({ "fileId": file_id, "partSha1Array": sha1s });
+            self.api(auth, "b2_finish_large_file", &req)
+        })?;
+        Ok(())
+    }
+}
+
+// B2 only needs this for basic authorization, not worth a dependency.
+fn base64_encode(data: &[u8]) -> String {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for c in data.chunks(3) {
+        let b = [c[0], *c.get(1).unwrap_or(&0), *c.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= c.len() {
+                out.push(CHARS[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+impl StorageEngine for B2Storage {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), RepoError> {
+        check_key(key)?;
+        if data.len() > self.cfg.large_file_threshold {
+            return self.put_large(key, data);
+        }
+        let sha1 = sha1_hex(data);
+        self.retry(key, |auth| {
+            let u = self.get_upload_url(auth)?;
+            self.agent
+                .post(&u.url)
+                .set("authorization", &u.token)
+                .set("x-bz-file-name", &uri_encode(&self.name(key), false))
+                .set("content-type", "application/octet-stream")
+                .set("x-bz-content-sha1", &sha1)
+                .send_bytes(data)?;
+            Ok(())
+        })
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, RepoError> {
+        check_key(key)?;
+        self.retry(key, |auth| {
+            let resp = self
+                .agent
+                .get(&self.download_url(auth, key))
+                .set("authorization", &auth.token)
+                .call()?;
+            let mut buf = Vec::new();
+            resp.into_reader().read_to_end(&mut buf)?;
+            Ok(buf)
+        })
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: usize) -> Result<Vec<u8>, RepoError> {
+        check_key(key)?;
+        if len == 0 {
+            return if offset <= self.size(key)? {
+                Ok(Vec::new())
+            } else {
+                Err(RepoError::InvalidRangeError)
+            };
+        }
+        let range = format!("bytes={}-{}", offset, offset + len as u64 - 1);
+        let buf = self.retry(key, |auth| {
+            let resp = self
+                .agent
+                .get(&self.download_url(auth, key))
+                .set("authorization", &auth.token)
+                .set("range", &range)
+                .call()?;
+            let mut buf = Vec::new();
+            resp.into_reader().read_to_end(&mut buf)?;
+            Ok(buf)
+        })?;
+        if buf.len() != len {
+            return Err(RepoError::InvalidRangeError);
+        }
+        Ok(buf)
+    }
+
+    fn size(&self, key: &str) -> Result<u64, RepoError> {
+        check_key(key)?;
+        self.retry(key, |auth| {
+            let resp = self
+                .agent
+                .head(&self.download_url(auth, key))
+                .set("authorization", &auth.token)
+                .call()?;
+            match resp.header("content-length").map(|v| v.parse()) {
+                Some(Ok(n)) => Ok(n),
+                _ => Err(Failure::Fatal(RepoError::StorageError(
+                    "b2 response missing content-length".to_string(),
+                ))),
+            }
+        })
+    }
+
+    fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, RepoError> {
+        check_prefix(prefix)?;
+        let mut keys = Vec::new();
+        let mut start: Value = Value::Null;
+        loop {
+            let v = self.retry(prefix, |auth| {
+                let req = json!({
+                    "bucketId": auth.bucket_id,
+                    "prefix": self.name(prefix),
+                    "startFileName": start,
+                    "maxFileCount": 1000,
+                });
+                self.api(auth, "b2_list_file_names", &req)
+            })?;
+            for f in v["files"].as_array().into_iter().flatten() {
+                let name = f["fileName"].as_str().unwrap_or_default();
+                if let Some(k) = name.strip_prefix(&self.cfg.prefix) {
+                    if check_key(k).is_ok() {
+                        keys.push(k.to_string());
+                    }
+                }
+            }
+            start = v["nextFileName"].clone();
+            if start.is_null() {
+                break;
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RepoError> {
+        check_key(key)?;
+        let name = self.name(key);
+        let versions = self.retry(key, |auth| {
+            let req = json!({
+                "bucketId": auth.bucket_id,
+                "startFileName": name,
+                "prefix": name,
+                "maxFileCount": 1000,
+            });
+            self.api(auth, "b2_list_file_versions", &req)
+        })?;
+        let ids: Vec<String> = versions["files"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|f| f["fileName"].as_str() == Some(&name))
+            .filter_map(|f| f["fileId"].as_str().map(|id| id.to_string()))
+            .collect();
+        if ids.is_empty() {
+            return Err(not_found(key));
+        }
+        for id in ids.iter() {
+            self.retry(key, |auth| {
+                let req = json!({ "fileName": name, "fileId": id });
+                self.api(auth, "b2_delete_file_version", &req)
+            })?;
+        }
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            atomic_rename: false,
+            range_reads: true,
+            delete: true,
+        }
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_base64() {
+    assert_eq!(base64_encode(b""), "");
+    assert_eq!(base64_encode(b"f"), "Zg==");
+    assert_eq!(base64_encode(b"fo"), "Zm8=");
+    assert_eq!(base64_encode(b"foo"), "Zm9v");
+    assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+}
+
+#[test]
+fn test_b2_helpers() {
+    assert_eq!(
+        parse_error(r#"{"status":401,"code":"expired_auth_token","message":"expired"}"#),
+        ("expired_auth_token".to_string(), "expired".to_string())
+    );
+    assert_eq!(parse_error("oops"), (String::new(), "oops".to_string()));
+    assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+
+    let mut cfg = B2Config::new("backups");
+    cfg.prefix = "laptop/".to_string();
+    let s = B2Storage::new(cfg.clone()).unwrap();
+    let auth = Auth {
+        token: String::new(),
+        api_url: String::new(),
+        download_url: "https://f000.backblazeb2.com".to_string(),
+        bucket_id: String::new(),
+    };
+    assert_eq!(
+        s.download_url(&auth, "packs/ab"),
+        "https://f000.backblazeb2.com/file/backups/laptop/packs/ab"
+    );
+    cfg.part_size = 1024;
+    assert!(B2Storage::new(cfg).is_err());
+}
@@ -0,0 +1,111 @@
+//! Storage for dry runs.
+//!
+//! Wraps another engine, passing reads through and dropping every write,
+//! so an operation can run against a real repository and leave it as it
+//! was. Puts succeed without storing anything and are counted, deletes and
+//! renames do nothing. Whatever the operation writes cannot be read back,
+//! so it must not depend on that, a commit say.
+
+use super::{Capabilities, StorageEngine, ThawState};
+use crate::address::Address;
+use crate::checks::CheckStatus;
+use crate::translog::LogHash;
+use crate::usage::Quota;
+use crate::RepoError;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+pub struct DiscardStorage {
+    inner: Arc<dyn StorageEngine>,
+    put_bytes: AtomicU64,
+}
+
+impl DiscardStorage {
+    pub fn new(inner: Arc<dyn StorageEngine>) -> DiscardStorage {
+        DiscardStorage {
+            inner,
+            put_bytes: AtomicU64::new(0),
+        }
+    }
+
+    // Bytes that would have been put.
+    pub fn put_bytes(&self) -> u64 {
+        self.put_bytes.load(Ordering::Relaxed)
+    }
+}
+
+impl StorageEngine for DiscardStorage {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), RepoError> {
+        super::check_key(key)?;
+        self.put_bytes
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, RepoError> {
+        self.inner.get(key)
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: usize) -> Result<Vec<u8>, RepoError> {
+        self.inner.get_range(key, offset, len)
+    }
+
+    fn size(&self, key: &str) -> Result<u64, RepoError> {
+        self.inner.size(key)
+    }
+
+    fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, RepoError> {
+        self.inner.list_prefix(prefix)
+    }
+
+    fn delete(&self, _key: &str) -> Result<(), RepoError> {
+        Ok(())
+    }
+
+    fn rename(&self, _from: &str, _to: &str) -> Result<(), RepoError> {
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn thaw(&self, key: &str) -> Result<ThawState, RepoError> {
+        self.inner.thaw(key)
+    }
+
+    fn exists_many(&self, keys: &[&str]) -> Result<Vec<bool>, RepoError> {
+        self.inner.exists_many(keys)
+    }
+
+    fn objects_present(&self, addresses: &[Address]) -> Result<Vec<bool>, RepoError> {
+        self.inner.objects_present(addresses)
+    }
+
+    fn consistency_proof(&self, old: u64, new: u64) -> Result<Vec<LogHash>, RepoError> {
+        self.inner.consistency_proof(old, new)
+    }
+
+    fn quotas(&self) -> Result<Vec<Quota>, RepoError> {
+        self.inner.quotas()
+    }
+
+    fn check_status(&self) -> Result<CheckStatus, RepoError> {
+        self.inner.check_status()
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_discard_storage() {
+    let inner = Arc::new(super::mem::MemStorage::new());
+    inner.put("packs/00", b"kept").unwrap();
+    let s = DiscardStorage::new(inner.clone());
+    s.put("packs/01", b"dropped").unwrap();
+    s.delete("packs/00").unwrap();
+    assert_eq!(s.put_bytes(), 7);
+    assert_eq!(s.get("packs/00").unwrap(), b"kept");
+    assert_eq!(inner.list_prefix("").unwrap(), vec!["packs/00".to_string()]);
+    assert!(s.put("../escape", b"").is_err());
+}
@@ -0,0 +1,472 @@
+//! Google Cloud Storage using the JSON API, enabled with the `gcs` feature.
+//!
+//! Requests are authorized with an OAuth2 access token. Either give one
+//! directly (for example from `gcloud auth print-access-token`) or leave
+//! it unset on a Compute Engine, Cloud Run or GKE host and tokens are
+//! fetched, and renewed before they expire, from the metadata server.
+//!
+//! Values larger than `chunk_size` use a resumable upload session. After
+//! a failed chunk the session is asked how much it has persisted and the
+//! upload carries on from there. 408, 429 and 5xx responses are retried
+//! with exponential backoff as Google recommends, a 401 renews the token.
+
+use super::{check_key, check_prefix, not_found, uri_encode, Capabilities, StorageEngine};
+use crate::RepoError;
+use serde_json::Value;
+use std::io::Read;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const METADATA_TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+
+// Every chunk but the last must be a multiple of this.
+const CHUNK_ALIGN: usize = 256 * 1024;
+
+#[derive(Clone)]
+pub struct GcsConfig {
+    // Only changed to point at an emulator.
+    pub endpoint: String,
+    pub bucket: String,
+    // Prepended to every key, allows several repositories per bucket.
+    pub prefix: String,
+    // A fixed access token, None fetches tokens from the metadata server.
+    pub token: Option<String>,
+    pub chunk_size: usize,
+    pub max_attempts: u32,
+    pub timeout: Duration,
+}
+
+impl GcsConfig {
+    pub fn new(bucket: &str) -> GcsConfig {
+        GcsConfig {
+            endpoint: "https://storage.googleapis.com".to_string(),
+            bucket: bucket.to_string(),
+            prefix: String::new(),
+            token: std::env::var("GOOGLE_OAUTH_ACCESS_TOKEN").ok(),
+            chunk_size: 32 * 1024 * 1024,
+            max_attempts: 5,
+            timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+struct Token {
+    token: String,
+    expires: Option<Instant>,
+}
+
+pub struct GcsStorage {
+    cfg: GcsConfig,
+    agent: ureq::Agent,
+    token: Mutex<Option<Token>>,
+}
+
+enum Failure {
+    Status(u16, String),
+    Transport(String),
+    Fatal(RepoError),
+}
+
+impl From<ureq::Error> for Failure {
+    fn from(err: ureq::Error) -> Failure {
+        match err {
+            ureq::Error::Status(status, resp) => {
+                Failure::Status(status, resp.into_string().unwrap_or_default())
+            }
+            ureq::Error::Transport(t) => Failure::Transport(t.to_string()),
+        }
+    }
+}
+
+impl From<std::io::Error> for Failure {
+    fn from(err: std::io::Error) -> Failure {
+        Failure::Transport(err.to_string())
+    }
+}
+
+fn bad_response(what: &str) -> Failure {
+    Failure::Fatal(RepoError::StorageError(format!(
+        "gcs bad response: {}",
+        what
+    )))
+}
+
+fn read_json(resp: ureq::Response) -> Result<Value, Failure> {
+    let body = resp.into_string()?;
+    serde_json::from_str(&body).map_err(|_| bad_response(&body))
+}
+
+// The number of bytes a resumable session has persisted, from the Range
+// header of a 308 response. No header means nothing was persisted.
+fn persisted(range: Option<&str>) -> Result<usize, Failure> {
+    match range {
+        None => Ok(0),
+        Some(r) => match r.strip_prefix("bytes=0-").map(|end| end.parse::<usize>()) {
+            Some(Ok(end)) => Ok(end + 1),
+            _ => Err(bad_response(r)),
+        },
+    }
+}
+
+impl GcsStorage {
+    pub fn new(cfg: GcsConfig) -> Result<GcsStorage, RepoError> {
+        if cfg.chunk_size == 0 || !cfg.chunk_size.is_multiple_of(CHUNK_ALIGN) {
+            return Err(RepoError::StorageError(
+                "gcs chunk size must be a multiple of 256KiB".to_string(),
+            ));
+        }
+        // Resumable sessions answer incomplete uploads with 308, which
+        // must not be followed.
+        let agent = ureq::AgentBuilder::new()
+            .timeout(cfg.timeout)
+            .redirects(0)
+            .build();
+        Ok(GcsStorage {
+            cfg,
+            agent,
+            token: Mutex::new(None),
+        })
+    }
+
+    fn object_url(&self, key: &str) -> String {
+        format!(
+            "{}/storage/v1/b/{}/o/{}",
+            self.cfg.endpoint,
+            uri_encode(&self.cfg.bucket, true),
+            uri_encode(&format!("{}{}", self.cfg.prefix, key), true)
+        )
+    }
+
+    fn upload_url(&self, key: &str, upload_type: &str) -> String {
+        format!(
+            "{}/upload/storage/v1/b/{}/o?uploadType={}&name={}",
+            self.cfg.endpoint,
+            uri_encode(&self.cfg.bucket, true),
+            upload_type,
+            uri_encode(&format!("{}{}", self.cfg.prefix, key), true)
+        )
+    }
+
+    fn bearer(&self) -> Result<String, Failure> {
+        if let Some(ref token) = self.cfg.token {
+            return Ok(format!("Bearer {}", token));
+        }
+        let mut cached = self.token.lock().unwrap();
+        if let Some(ref t) = *cached {
+            if t.expires.is_none_or(|e| Instant::now() < e) {
+                return Ok(format!("Bearer {}", t.token));
+            }
+        }
+        let v = read_json(
+            self.agent
+                .get(METADATA_TOKEN_URL)
+                .set("metadata-flavor", "Google")
+                .call()?,
+        )?;
+        let token = match v["access_token"].as_str() {
+            Some(t) => t.to_string(),
+            None => return Err(bad_response("metadata token")),
+        };
+        // Renew a minute early so requests never race the expiry.
+        let expires = v["expires_in"]
+            .as_u64()
+            .map(|s| Instant::now() + Duration::from_secs(s.saturating_sub(60)));
+        *cached = Some(Token {
+            token: token.clone(),
+            expires,
+        });
+        Ok(format!("Bearer {}", token))
+    }
+
+    fn retry<T, F>(&self, key: &str, f: F) -> Result<T, RepoError>
+    where
+        F: Fn(&str) -> Result<T, Failure>,
+    {
+        let mut delay = Duration::from_millis(500);
+        let mut attempt = 1;
+        loop {
+            let err = match self.bearer().and_then(|auth| f(&auth)) {
+                Ok(v) => return Ok(v),
+                Err(Failure::Fatal(err)) => return Err(err),
+                Err(Failure::Status(404, _)) => return Err(not_found(key)),
+                Err(Failure::Status(416, _)) => return Err(RepoError::InvalidRangeError),
+                Err(Failure::Status(401, body)) if self.cfg.token.is_none() => {
+                    self.token.lock().unwrap().take();
+                    format!("gcs {}: 401 {}", key, body)
+                }
+                Err(Failure::Status(status, body)) => {
+                    let msg = format!("gcs {}: {} {}", key, status, body);
+                    if status != 408 && status != 429 && status < 500 {
+                        return Err(RepoError::StorageError(msg));
+                    }
+                    msg
+                }
+                Err(Failure::Transport(msg)) => format!("gcs {}: {}", key, msg),
+            };
+            if attempt >= self.cfg.max_attempts {
+                return Err(RepoError::StorageError(err));
+            }
+            thread::sleep(delay);
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+
+    fn put_resumable(&self, key: &str, data: &[u8]) -> Result<(), RepoError> {
+        let session = self.retry(key, |auth| {
+            let resp = self
+                .agent
+                .post(&self.upload_url(key, "resumable"))
+                .set("authorization", auth)
+                .set("x-upload-content-length", &data.len().to_string())
+                .send_bytes(&[])?;
+            match resp.header("location") {
+                Some(l) => Ok(l.to_string()),
+                None => Err(bad_response("missing session location")),
+            }
+        })?;
+
+        let total = data.len();
+        let mut offset = 0;
+        let mut attempt = 1;
+        loop {
+            let end = total.min(offset + self.cfg.chunk_size);
+            let range = format!("bytes {}-{}/{}", offset, end - 1, total);
+            let result = self.bearer().and_then(|auth| {
+                Ok(self
+                    .agent
+                    .put(&session)
+                    .set("authorization", &auth)
+                    .set("content-range", &range)
+                    .send_bytes(&data[offset..end])?)
+            });
+            match result {
+                Ok(resp) if resp.status() == 308 => {
+                    offset = persisted(resp.header("range")).map_err(|_| {
+                        RepoError::StorageError("gcs bad resumable response".to_string())
+                    })?;
+                    attempt = 1;
+                    continue;
+                }
+                Ok(_) => return Ok(()),
+                Err(Failure::Fatal(err)) => return Err(err),
+                // The session expired or was cancelled.
+                Err(Failure::Status(404, _)) | Err(Failure::Status(410, _)) => {
+                    return Err(RepoError::StorageError(format!(
+                        "gcs {}: upload session lost",
+                        key
+                    )))
+                }
+                Err(Failure::Status(status, body))
+                    if status != 401 && status != 408 && status != 429 && status < 500 =>
+                {
+                    return Err(RepoError::StorageError(format!(
+                        "gcs {}: {} {}",
+                        key, status, body
+                    )))
+                }
+                Err(_) if attempt < self.cfg.max_attempts => {
+                    self.token.lock().unwrap().take();
+                }
+                Err(_) => {
+                    return Err(RepoError::StorageError(format!(
+                        "gcs {}: upload failed after {} attempts",
+                        key, attempt
+                    )))
+                }
+            }
+            thread::sleep(Duration::from_millis(500) * 2u32.pow(attempt - 1));
+            attempt += 1;
+            // Ask the session where to resume.
+            let status = self.retry(key, |auth| {
+                let resp = self
+                    .agent
+                    .put(&session)
+                    .set("authorization", auth)
+                    .set("content-range", &format!("bytes */{}", total))
+                    .send_bytes(&[])?;
+                if resp.status() == 308 {
+                    persisted(resp.header("range")).map(Some)
+                } else {
+                    Ok(None)
+                }
+            })?;
+            match status {
+                Some(n) => offset = n,
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+impl StorageEngine for GcsStorage {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), RepoError> {
+        check_key(key)?;
+        if data.len() > self.cfg.chunk_size {
+            return self.put_resumable(key, data);
+        }
+        self.retry(key, |auth| {
+            self.agent
+                .post(&self.upload_url(key, "media"))
+                .set("authorization", auth)
+                .set("content-type", "application/octet-stream")
+                .send_bytes(data)?;
+            Ok(())
+        })
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, RepoError> {
+        check_key(key)?;
+        self.retry(key, |auth| {
+            let resp = self
+                .agent
+                .get(&format!("{}?alt=media", self.object_url(key)))
+                .set("authorization", auth)
+                .call()?;
+            let mut buf = Vec::new();
+            resp.into_reader().read_to_end(&mut buf)?;
+            Ok(buf)
+        })
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: usize) -> Result<Vec<u8>, RepoError> {
+        check_key(key)?;
+        if len == 0 {
+            return if offset <= self.size(key)? {
+                Ok(Vec::new())
+            } else {
+                Err(RepoError::InvalidRangeError)
+            };
+        }
+        let range = format!("bytes={}-{}", offset, offset + len as u64 - 1);
+        let buf = self.retry(key, |auth| {
+            let resp = self
+                .agent
+                .get(&format!("{}?alt=media", self.object_url(key)))
+                .set("authorization", auth)
+                .set("range", &range)
+                .call()?;
+            let mut buf = Vec::new();
+            resp.into_reader().read_to_end(&mut buf)?;
+            Ok(buf)
+        })?;
+        if buf.len() != len {
+            return Err(RepoError::InvalidRangeError);
+        }
+        Ok(buf)
+    }
+
+    fn size(&self, key: &str) -> Result<u64, RepoError> {
+        check_key(key)?;
+        self.retry(key, |auth| {
+            let v = read_json(
+                self.agent
+                    .get(&format!("{}?fields=size", self.object_url(key)))
+                    .set("authorization", auth)
+                    .call()?,
+            )?;
+            // int64 fields are sent as JSON strings.
+            match v["size"].as_str().map(|s| s.parse()) {
+                Some(Ok(n)) => Ok(n),
+                _ => Err(bad_response("missing size")),
+            }
+        })
+    }
+
+    fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, RepoError> {
+        check_prefix(prefix)?;
+        let mut keys = Vec::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut url = format!(
+                "{}/storage/v1/b/{}/o?fields=items(name),nextPageToken&prefix={}",
+                self.cfg.endpoint,
+                uri_encode(&self.cfg.bucket, true),
+                uri_encode(&format!("{}{}", self.cfg.prefix, prefix), true)
+            );
+            if let Some(ref t) = page_token {
+                url.push_str(&format!("&pageToken={}", uri_encode(t, true)));
+            }
+            let v = self.retry(prefix, |auth| {
+                read_json(self.agent.get(&url).set("authorization", auth).call()?)
+            })?;
+            keys.extend(list_page_keys(&v, &self.cfg.prefix));
+            page_token = v["nextPageToken"].as_str().map(|t| t.to_string());
+            if page_token.is_none() {
+                break;
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RepoError> {
+        check_key(key)?;
+        self.retry(key, |auth| {
+            self.agent
+                .delete(&self.object_url(key))
+                .set("authorization", auth)
+                .call()?;
+            Ok(())
+        })
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            atomic_rename: false,
+            range_reads: true,
+            delete: true,
+        }
+    }
+}
+
+fn list_page_keys(v: &Value, prefix: &str) -> Vec<String> {
+    v["items"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|item| item["name"].as_str())
+        .filter_map(|name| name.strip_prefix(prefix))
+        .filter(|k| check_key(k).is_ok())
+        .map(|k| k.to_string())
+        .collect()
+}
+
+// Tests --------------------
+
+#[test]
+fn test_persisted() {
+    assert_eq!(persisted(None).ok(), Some(0));
+    assert_eq!(persisted(Some("bytes=0-262143")).ok(), Some(262144));
+    assert!(persisted(Some("bytes=5-9")).is_err());
+}
+
+#[test]
+fn test_gcs_urls() {
+    let mut cfg = GcsConfig::new("backups");
+    cfg.prefix = "laptop/".to_string();
+    let s = GcsStorage::new(cfg.clone()).unwrap();
+    assert_eq!(
+        s.object_url("packs/ab"),
+        "https://storage.googleapis.com/storage/v1/b/backups/o/laptop%2Fpacks%2Fab"
+    );
+    assert_eq!(
+        s.upload_url("config", "media"),
+        "https://storage.googleapis.com/upload/storage/v1/b/backups/o?uploadType=media&name=laptop%2Fconfig"
+    );
+    cfg.chunk_size = 1000;
+    assert!(GcsStorage::new(cfg).is_err());
+}
+
+#[test]
+fn test_list_page_keys() {
+    let v: Value = serde_json::from_str(
+        r#"{"items":[{"name":"laptop/packs/ab"},{"name":"other/x"},{"name":"laptop/../x"}],
+            "nextPageToken":"t"}"#,
+    )
+    .unwrap();
+    assert_eq!(list_page_keys(&v, "laptop/"), vec!["packs/ab"]);
+    assert!(list_page_keys(&serde_json::json!({}), "").is_empty());
+}
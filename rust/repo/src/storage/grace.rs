@@ -0,0 +1,263 @@
+//! Server side delete grace periods.
+//!
+//! Wraps another engine, stamps every object stored with the time it
+//! arrived and refuses to delete, rename or overwrite an object that
+//! arrived less than the grace period ago. `serve` runs behind it when
+//! started with `ServeOptions::delete_grace`, so whatever a client asks,
+//! and whatever keep list it signs for `gc`, data stays for at least that
+//! long. A stolen key that may prune can only destroy what was already old
+//! when it was stolen, and a backup taken since is safe.
+//!
+//! Locks, their owners and usage records are not stamped, nor are the
+//! files append only mode lets be replaced checked before they are
+//! overwritten. An object without a stamp, stored before the server
+//! enforced a grace period, is taken to be old.
+//!
+//! The stamp is written before the object, so an object that made it into
+//! storage always has one, even if the server stopped in between. A stamp
+//! a failed put left behind protects nothing, only an object stored is
+//! ever refused a replacement.
+//!
+//! Stamps are stored at `arrivals/<key>`, clients may read but never
+//! write or delete them:
+//!
+//! ```text
+//! "PNBARRIVAL" u16:format_version u64:arrival_unix_time
+//! ```
+
+use super::append_only::replaceable;
+use super::{Capabilities, StorageEngine, ThawState};
+use crate::address::Address;
+use crate::checks::CheckStatus;
+use crate::datetime::unix_now;
+use crate::lock::{is_lock_key, is_lock_owner_key};
+use crate::translog::LogHash;
+use crate::usage::{is_usage_key, Quota};
+use crate::wire::{Decoder, Encoder};
+use crate::{RepoError, ARRIVALS_DIR};
+use std::sync::Arc;
+
+pub const ARRIVAL_FORMAT_VERSION: u16 = 1;
+const ARRIVAL_MAGIC: &[u8] = b"PNBARRIVAL";
+
+pub fn arrival_key(key: &str) -> String {
+    format!("{}/{}", ARRIVALS_DIR, key)
+}
+
+pub fn is_arrival_key(key: &str) -> bool {
+    key.starts_with(ARRIVALS_DIR) && key[ARRIVALS_DIR.len()..].starts_with('/')
+}
+
+fn encode_arrival(time: u64) -> Vec<u8> {
+    let mut e = Encoder::new();
+    e.fixed(ARRIVAL_MAGIC).u16(ARRIVAL_FORMAT_VERSION).u64(time);
+    e.into_vec()
+}
+
+fn decode_arrival(buf: &[u8]) -> Result<u64, RepoError> {
+    let mut d = Decoder::new(buf);
+    if d.fixed(ARRIVAL_MAGIC.len())? != ARRIVAL_MAGIC {
+        return Err(RepoError::InvalidDataError);
+    }
+    if d.u16()? != ARRIVAL_FORMAT_VERSION {
+        return Err(RepoError::UnsupportedVersionError);
+    }
+    let time = d.u64()?;
+    d.finish()?;
+    Ok(time)
+}
+
+fn stamped(key: &str) -> bool {
+    !is_lock_key(key) && !is_lock_owner_key(key) && !is_usage_key(key) && !is_arrival_key(key)
+}
+
+pub struct GraceStorage {
+    inner: Arc<dyn StorageEngine>,
+    // Seconds an object must have been stored before it may go.
+    grace: u64,
+}
+
+impl GraceStorage {
+    pub fn new(inner: Arc<dyn StorageEngine>, grace: u64) -> GraceStorage {
+        GraceStorage { inner, grace }
+    }
+
+    // When `key` arrived, None if it has no stamp.
+    pub fn arrival(&self, key: &str) -> Result<Option<u64>, RepoError> {
+        match self.inner.get(&arrival_key(key)) {
+            Ok(buf) => Ok(Some(decode_arrival(&buf)?)),
+            Err(ref e) if e.is_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Refuse to remove `key` while it is within the grace period.
+    fn check_old(&self, key: &str) -> Result<(), RepoError> {
+        if !stamped(key) {
+            return Ok(());
+        }
+        match self.arrival(key)? {
+            Some(time) if unix_now() < time.saturating_add(self.grace) => {
+                Err(RepoError::PermissionDeniedError)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Refuse to overwrite `key` while it is within the grace period.
+    fn check_overwrite(&self, key: &str) -> Result<(), RepoError> {
+        if replaceable(key) || !self.inner.exists(key)? {
+            return Ok(());
+        }
+        self.check_old(key)
+    }
+
+    fn stamp(&self, key: &str) -> Result<(), RepoError> {
+        if !stamped(key) {
+            return Ok(());
+        }
+        self.inner
+            .put(&arrival_key(key), &encode_arrival(unix_now()))
+    }
+
+    fn unstamp(&self, key: &str) -> Result<(), RepoError> {
+        if !stamped(key) {
+            return Ok(());
+        }
+        match self.inner.delete(&arrival_key(key)) {
+            Err(ref e) if e.is_not_found() => Ok(()),
+            result => result,
+        }
+    }
+}
+
+impl StorageEngine for GraceStorage {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), RepoError> {
+        self.check_overwrite(key)?;
+        self.stamp(key)?;
+        self.inner.put(key, data)
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, RepoError> {
+        self.inner.get(key)
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: usize) -> Result<Vec<u8>, RepoError> {
+        self.inner.get_range(key, offset, len)
+    }
+
+    fn size(&self, key: &str) -> Result<u64, RepoError> {
+        self.inner.size(key)
+    }
+
+    fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, RepoError> {
+        self.inner.list_prefix(prefix)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RepoError> {
+        self.check_old(key)?;
+        self.inner.delete(key)?;
+        self.unstamp(key)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), RepoError> {
+        self.check_old(from)?;
+        self.check_overwrite(to)?;
+        self.stamp(to)?;
+        self.inner.rename(from, to)?;
+        self.unstamp(from)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn thaw(&self, key: &str) -> Result<ThawState, RepoError> {
+        self.inner.thaw(key)
+    }
+
+    fn exists_many(&self, keys: &[&str]) -> Result<Vec<bool>, RepoError> {
+        self.inner.exists_many(keys)
+    }
+
+    // Nothing is stored if any object would overwrite one too young.
+    fn put_many(&self, objects: &[(&str, &[u8])]) -> Result<(), RepoError> {
+        for (key, _) in objects.iter() {
+            self.check_overwrite(key)?;
+        }
+        let stamp = encode_arrival(unix_now());
+        let keys: Vec<String> = objects
+            .iter()
+            .filter(|(key, _)| stamped(key))
+            .map(|(key, _)| arrival_key(key))
+            .collect();
+        let stamps: Vec<(&str, &[u8])> = keys.iter().map(|k| (&k[..], &stamp[..])).collect();
+        self.inner.put_many(&stamps)?;
+        self.inner.put_many(objects)
+    }
+
+    fn objects_present(&self, addresses: &[Address]) -> Result<Vec<bool>, RepoError> {
+        self.inner.objects_present(addresses)
+    }
+
+    fn consistency_proof(&self, old: u64, new: u64) -> Result<Vec<LogHash>, RepoError> {
+        self.inner.consistency_proof(old, new)
+    }
+
+    fn quotas(&self) -> Result<Vec<Quota>, RepoError> {
+        self.inner.quotas()
+    }
+
+    fn check_status(&self) -> Result<CheckStatus, RepoError> {
+        self.inner.check_status()
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_grace_storage() {
+    let inner = Arc::new(super::mem::MemStorage::new());
+    let s = GraceStorage::new(inner.clone(), 3600);
+    s.put("packs/00", b"pack").unwrap();
+    assert!(s.arrival("packs/00").unwrap().unwrap() >= unix_now() - 1);
+    for result in [
+        s.delete("packs/00"),
+        s.put("packs/00", b"replaced"),
+        s.put_many(&[("packs/01", &b"new"[..]), ("packs/00", &b"replaced"[..])]),
+        s.rename("packs/00", "packs/02"),
+    ]
+    .iter()
+    {
+        match result {
+            Err(RepoError::PermissionDeniedError) => (),
+            _ => panic!("expected a young object to be kept"),
+        }
+    }
+    assert_eq!(s.get("packs/00").unwrap(), b"pack");
+    assert!(!inner.exists("packs/01").unwrap());
+
+    // Old enough, or stored before the grace period was enforced.
+    inner
+        .put(&arrival_key("packs/00"), &encode_arrival(unix_now() - 3600))
+        .unwrap();
+    s.delete("packs/00").unwrap();
+    assert!(!inner.exists(&arrival_key("packs/00")).unwrap());
+    inner.put("packs/03", b"unstamped").unwrap();
+    s.delete("packs/03").unwrap();
+
+    // A stamp whose object never arrived does not block storing it.
+    inner
+        .put(&arrival_key("packs/04"), &encode_arrival(unix_now()))
+        .unwrap();
+    s.put("packs/04", b"retried").unwrap();
+    assert!(s.delete("packs/04").is_err());
+
+    // Replaced files, locks and usage records are left alone.
+    s.put(crate::MANIFEST_FILE, b"1").unwrap();
+    s.put(crate::MANIFEST_FILE, b"2").unwrap();
+    s.put("locks/00", b"1").unwrap();
+    s.delete("locks/00").unwrap();
+    assert_eq!(s.arrival("locks/00").unwrap(), None);
+    assert!(decode_arrival(&encode_arrival(7)[..12]).is_err());
+}
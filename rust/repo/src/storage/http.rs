@@ -0,0 +1,486 @@
+//! Plain HTTP(S) storage, enabled with the `http` feature.
+//!
+//! Any server or reverse proxy implementing the few endpoints below can
+//! hold a repository. `<url>` is the configured repository URL, keys are
+//! appended verbatim, they only ever contain URL safe characters.
+//!
+//! ```text
+//! GET    <url>/<key>                        200 value, 206 with a Range header
+//! HEAD   <url>/<key>                        200 with Content-Length
+//! PUT    <url>/<key>                        store the body as the value
+//! DELETE <url>/<key>                        404 if missing
+//! GET    <url>/?list&prefix=<prefix>        200 keys, one per line
+//!
+//! POST   <url>/<key>?uploads                200 body is a new upload id
+//! PUT    <url>/<key>?upload=<id>&offset=<n> append the body, 409 unless n
+//!                                           is the number of bytes received
+//! HEAD   <url>/<key>?upload=<id>            200 with Upload-Offset: <n>
+//! POST   <url>/<key>?upload=<id>&commit     atomically publish the value
+//! DELETE <url>/<key>?upload=<id>            abandon the upload
+//! ```
+//!
+//! Every request carries `Authorization: Bearer <token>` when a token is
+//! configured. Values larger than `chunk_size` use a resumable upload: after
+//! a failed chunk the client asks how much the server received and carries
+//! on from there, so a flaky link never resends a whole pack. PUT and the
+//! upload commit must both replace the value atomically.
+
+use super::{check_key, check_prefix, not_found, Capabilities, StorageEngine};
+use crate::RepoError;
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Clone)]
+pub struct HttpConfig {
+    // For example "https://backup.example/repos/laptop".
+    pub url: String,
+    pub token: Option<String>,
+    pub chunk_size: usize,
+    pub max_attempts: u32,
+    pub timeout: Duration,
+}
+
+impl HttpConfig {
+    pub fn new(url: &str) -> HttpConfig {
+        HttpConfig {
+            url: url.trim_end_matches('/').to_string(),
+            token: None,
+            chunk_size: 8 * 1024 * 1024,
+            max_attempts: 5,
+            timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+pub struct HttpStorage {
+    cfg: HttpConfig,
+    agent: ureq::Agent,
+}
+
+fn is_transient(err: &ureq::Error) -> bool {
+    match *err {
+        ureq::Error::Status(status, _) => status == 429 || status >= 500,
+        ureq::Error::Transport(_) => true,
+    }
+}
+
+// Upload ids end up in query strings, only accept ones that need no escaping.
+fn check_upload_id(id: &str) -> Result<(), RepoError> {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if !id.is_empty() && id.len() <= 256 && id.chars().all(valid_char) {
+        Ok(())
+    } else {
+        Err(RepoError::StorageError(format!(
+            "http server sent an invalid upload id {:?}",
+            id
+        )))
+    }
+}
+
+impl HttpStorage {
+    pub fn new(cfg: HttpConfig) -> Result<HttpStorage, RepoError> {
+        if !cfg.url.starts_with("http://") && !cfg.url.starts_with("https://") {
+            return Err(RepoError::StorageError("invalid http url".to_string()));
+        }
+        if cfg.chunk_size == 0 {
+            return Err(RepoError::StorageError(
+                "http chunk size must not be zero".to_string(),
+            ));
+        }
+        let agent = ureq::AgentBuilder::new().timeout(cfg.timeout).build();
+        Ok(HttpStorage { cfg, agent })
+    }
+
+    fn url(&self, key: &str, query: &str) -> String {
+        if query.is_empty() {
+            format!("{}/{}", self.cfg.url, key)
+        } else {
+            format!("{}/{}?{}", self.cfg.url, key, query)
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn send_once(
+        &self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, String)],
+        body: &[u8],
+    ) -> Result<ureq::Response, ureq::Error> {
+        let mut r = self.agent.request(method, url);
+        if let Some(ref token) = self.cfg.token {
+            r = r.set("authorization", &format!("Bearer {}", token));
+        }
+        for (k, v) in headers.iter() {
+            r = r.set(k, v);
+        }
+        r.send_bytes(body)
+    }
+
+    fn error(&self, method: &str, key: &str, err: ureq::Error) -> RepoError {
+        match err {
+            ureq::Error::Status(404, _) => not_found(key),
+            ureq::Error::Status(416, _) => RepoError::InvalidRangeError,
+            ureq::Error::Status(status, resp) => RepoError::StorageError(format!(
+                "http {} {}: {} {}",
+                method,
+                key,
+                status,
+                resp.into_string().unwrap_or_default()
+            )),
+            ureq::Error::Transport(t) => {
+                RepoError::StorageError(format!("http {} {}: {}", method, key, t))
+            }
+        }
+    }
+
+    fn backoff(&self, attempt: u32) {
+        thread::sleep(Duration::from_millis(200) * 2u32.pow(attempt.min(10) - 1));
+    }
+
+    // Retry transient failures, repeating any of these requests is harmless.
+    fn send(
+        &self,
+        method: &str,
+        key: &str,
+        query: &str,
+        headers: &[(&str, String)],
+        body: &[u8],
+    ) -> Result<ureq::Response, RepoError> {
+        let url = self.url(key, query);
+        let mut attempt = 1;
+        loop {
+            match self.send_once(method, &url, headers, body) {
+                Ok(resp) => return Ok(resp),
+                Err(err) => {
+                    if !is_transient(&err) || attempt >= self.cfg.max_attempts {
+                        return Err(self.error(method, key, err));
+                    }
+                }
+            }
+            self.backoff(attempt);
+            attempt += 1;
+        }
+    }
+
+    fn read_body(resp: ureq::Response) -> Result<Vec<u8>, RepoError> {
+        let mut buf = Vec::new();
+        resp.into_reader().read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn upload_offset(&self, key: &str, id: &str) -> Result<usize, RepoError> {
+        let resp = self.send("HEAD", key, &format!("upload={}", id), &[], &[])?;
+        match resp.header("upload-offset").map(|v| v.parse()) {
+            Some(Ok(n)) => Ok(n),
+            _ => Err(RepoError::StorageError(
+                "http response missing upload-offset".to_string(),
+            )),
+        }
+    }
+
+    fn put_resumable(&self, key: &str, data: &[u8]) -> Result<(), RepoError> {
+        let resp = self.send("POST", key, "uploads", &[], &[])?;
+        let id = String::from_utf8_lossy(&HttpStorage::read_body(resp)?)
+            .trim()
+            .to_string();
+        check_upload_id(&id)?;
+
+        let result = self.upload_chunks(key, &id, data);
+        if result.is_err() {
+            let _ = self.send_once(
+                "DELETE",
+                &self.url(key, &format!("upload={}", id)),
+                &[],
+                &[],
+            );
+        }
+        result
+    }
+
+    fn upload_chunks(&self, key: &str, id: &str, data: &[u8]) -> Result<(), RepoError> {
+        let mut offset = 0;
+        let mut attempt = 1;
+        while offset < data.len() {
+            let end = data.len().min(offset + self.cfg.chunk_size);
+            let url = self.url(key, &format!("upload={}&offset={}", id, offset));
+            match self.send_once("PUT", &url, &[], &data[offset..end]) {
+                Ok(_) => {
+                    offset = end;
+                    attempt = 1;
+                    continue;
+                }
+                Err(err) => {
+                    // A conflict means our idea of the offset is stale,
+                    // which a resync fixes.
+                    let conflict = matches!(err, ureq::Error::Status(409, _));
+                    if (!conflict && !is_transient(&err)) || attempt >= self.cfg.max_attempts {
+                        return Err(self.error("PUT", key, err));
+                    }
+                }
+            }
+            self.backoff(attempt);
+            attempt += 1;
+            offset = self.upload_offset(key, id)?;
+            if offset > data.len() {
+                return Err(RepoError::StorageError(
+                    "http server received more than was sent".to_string(),
+                ));
+            }
+        }
+        let headers = [("upload-length", data.len().to_string())];
+        self.send("POST", key, &format!("upload={}&commit", id), &headers, &[])?;
+        Ok(())
+    }
+}
+
+impl StorageEngine for HttpStorage {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), RepoError> {
+        check_key(key)?;
+        if data.len() > self.cfg.chunk_size {
+            return self.put_resumable(key, data);
+        }
+        self.send("PUT", key, "", &[], data)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, RepoError> {
+        check_key(key)?;
+        HttpStorage::read_body(self.send("GET", key, "", &[], &[])?)
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: usize) -> Result<Vec<u8>, RepoError> {
+        check_key(key)?;
+        if len == 0 {
+            return if offset <= self.size(key)? {
+                Ok(Vec::new())
+            } else {
+                Err(RepoError::InvalidRangeError)
+            };
+        }
+        let range = format!("bytes={}-{}", offset, offset + len as u64 - 1);
+        let resp = self.send("GET", key, "", &[("range", range)], &[])?;
+        // A server ignoring the range sends the whole value with a 200.
+        if resp.status() != 206 {
+            return Err(RepoError::StorageError(format!(
+                "http server does not support range requests for {}",
+                key
+            )));
+        }
+        let buf = HttpStorage::read_body(resp)?;
+        if buf.len() != len {
+            return Err(RepoError::InvalidRangeError);
+        }
+        Ok(buf)
+    }
+
+    fn size(&self, key: &str) -> Result<u64, RepoError> {
+        check_key(key)?;
+        let resp = self.send("HEAD", key, "", &[], &[])?;
+        match resp.header("content-length").map(|v| v.parse()) {
+            Some(Ok(n)) => Ok(n),
+            _ => Err(RepoError::StorageError(
+                "http response missing content-length".to_string(),
+            )),
+        }
+    }
+
+    fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, RepoError> {
+        check_prefix(prefix)?;
+        let query = format!("list&prefix={}", prefix);
+        let body = HttpStorage::read_body(self.send("GET", "", &query, &[], &[])?)?;
+        let mut keys: Vec<String> = String::from_utf8_lossy(&body)
+            .lines()
+            .filter(|k| k.starts_with(prefix) && check_key(k).is_ok())
+            .map(|k| k.to_string())
+            .collect();
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RepoError> {
+        check_key(key)?;
+        self.send("DELETE", key, "", &[], &[])?;
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            atomic_rename: false,
+            range_reads: true,
+            delete: true,
+        }
+    }
+}
+
+// Tests --------------------
+
+// A minimal single threaded implementation of the endpoints above, backed
+// by MemStorage. `fail_chunks` makes that many chunk uploads store only half
+// their body and then fail, to exercise resumption.
+#[cfg(test)]
+fn test_server(token: &str, fail_chunks: usize) -> String {
+    use super::mem::MemStorage;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/repo", listener.local_addr().unwrap());
+    let auth = format!("Bearer {}", token);
+    thread::spawn(move || {
+        let storage = MemStorage::new();
+        let mut uploads: HashMap<String, Vec<u8>> = HashMap::new();
+        let mut fail_chunks = fail_chunks;
+        for conn in listener.incoming() {
+            let mut conn = conn.unwrap();
+            let mut r = BufReader::new(conn.try_clone().unwrap());
+            let mut line = String::new();
+            if r.read_line(&mut line).unwrap() == 0 {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let (method, target) = (parts[0].to_string(), parts[1].to_string());
+            let mut headers = HashMap::new();
+            loop {
+                let mut h = String::new();
+                r.read_line(&mut h).unwrap();
+                let h = h.trim_end();
+                if h.is_empty() {
+                    break;
+                }
+                let (k, v) = h.split_at(h.find(':').unwrap());
+                headers.insert(k.to_ascii_lowercase(), v[1..].trim().to_string());
+            }
+            let n = headers
+                .get("content-length")
+                .map(|v| v.parse().unwrap())
+                .unwrap_or(0);
+            let mut body = vec![0; n];
+            r.read_exact(&mut body).unwrap();
+
+            let path = target.split('?').next().unwrap();
+            let key = &path["/repo/".len()..];
+            let query: HashMap<&str, &str> = match target.find('?') {
+                Some(i) => target[i + 1..]
+                    .split('&')
+                    .map(|kv| match kv.find('=') {
+                        Some(j) => (&kv[..j], &kv[j + 1..]),
+                        None => (kv, ""),
+                    })
+                    .collect(),
+                None => HashMap::new(),
+            };
+            let upload = query.get("upload").map(|u| u.to_string());
+            let (status, extra, resp): (u16, String, Vec<u8>) =
+                if headers.get("authorization") != Some(&auth) {
+                    (401, String::new(), Vec::new())
+                } else if query.contains_key("list") {
+                    let keys = storage.list_prefix(query["prefix"]).unwrap();
+                    (200, String::new(), keys.join("\n").into_bytes())
+                } else if query.contains_key("uploads") {
+                    let id = format!("up{}", uploads.len());
+                    uploads.insert(id.clone(), Vec::new());
+                    (200, String::new(), id.into_bytes())
+                } else if let Some(id) = upload {
+                    let buf = uploads.get_mut(&id).unwrap();
+                    match method.as_str() {
+                        "PUT" if query["offset"] != buf.len().to_string() => {
+                            (409, String::new(), Vec::new())
+                        }
+                        "PUT" if fail_chunks > 0 => {
+                            fail_chunks -= 1;
+                            buf.extend_from_slice(&body[..body.len() / 2]);
+                            (500, String::new(), Vec::new())
+                        }
+                        "PUT" => {
+                            buf.extend_from_slice(&body);
+                            (200, String::new(), Vec::new())
+                        }
+                        "HEAD" => (200, format!("Upload-Offset: {}\r\n", buf.len()), Vec::new()),
+                        "POST" => {
+                            storage.put(key, buf).unwrap();
+                            uploads.remove(&id);
+                            (200, String::new(), Vec::new())
+                        }
+                        _ => {
+                            uploads.remove(&id);
+                            (200, String::new(), Vec::new())
+                        }
+                    }
+                } else {
+                    match method.as_str() {
+                        "PUT" => {
+                            storage.put(key, &body).unwrap();
+                            (200, String::new(), Vec::new())
+                        }
+                        "DELETE" => match storage.delete(key) {
+                            Ok(()) => (200, String::new(), Vec::new()),
+                            Err(_) => (404, String::new(), Vec::new()),
+                        },
+                        _ => match (storage.get(key), headers.get("range")) {
+                            (Err(_), _) => (404, String::new(), Vec::new()),
+                            (Ok(v), Some(range)) => {
+                                let range = &range["bytes=".len()..];
+                                let i = range.find('-').unwrap();
+                                let start: usize = range[..i].parse().unwrap();
+                                let end: usize = range[i + 1..].parse().unwrap();
+                                if end >= v.len() {
+                                    (416, String::new(), Vec::new())
+                                } else {
+                                    (206, String::new(), v[start..end + 1].to_vec())
+                                }
+                            }
+                            (Ok(v), None) => (200, String::new(), v),
+                        },
+                    }
+                };
+            // HEAD answers carry the length of the body a GET would send.
+            let len = resp.len();
+            let body = if method == "HEAD" { &[][..] } else { &resp[..] };
+            let _ = write!(
+                conn,
+                "HTTP/1.1 {} X\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+                status, len, extra
+            );
+            let _ = conn.write_all(body);
+        }
+    });
+    url
+}
+
+#[test]
+fn test_http_storage() {
+    let mut cfg = HttpConfig::new(&test_server("secret", 0));
+    cfg.token = Some("secret".to_string());
+    let s = HttpStorage::new(cfg).unwrap();
+    super::test_storage_engine(&s);
+}
+
+#[test]
+fn test_http_resumable() {
+    let mut cfg = HttpConfig::new(&test_server("secret", 2));
+    cfg.token = Some("secret".to_string());
+    cfg.chunk_size = 10;
+    let s = HttpStorage::new(cfg).unwrap();
+    let data: Vec<u8> = (0..100).collect();
+    s.put("packs/big", &data).unwrap();
+    assert_eq!(s.get("packs/big").unwrap(), data);
+    assert_eq!(s.get_range("packs/big", 95, 5).unwrap(), &data[95..]);
+}
+
+#[test]
+fn test_http_auth() {
+    let mut cfg = HttpConfig::new(&test_server("secret", 0));
+    cfg.token = Some("wrong".to_string());
+    let s = HttpStorage::new(cfg).unwrap();
+    match s.put("config", b"x") {
+        Err(RepoError::StorageError(ref msg)) => assert!(msg.contains("401")),
+        _ => panic!("expected auth failure"),
+    }
+    assert!(HttpStorage::new(HttpConfig::new("ftp://example")).is_err());
+    assert!(check_upload_id("up0").is_ok());
+    assert!(check_upload_id("a&b").is_err());
+}
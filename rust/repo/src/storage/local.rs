@@ -0,0 +1,237 @@
+//! Local directory storage.
+//!
+//! Values are written to a temporary file in the destination directory and
+//! renamed into place, so a crash leaves either the old value, the new value
+//! or a stray temporary file, never a truncated value. Temporary files start
+//! with '.' which no valid key may, so they are never listed.
+//!
+//! Keys map to paths below the root with each ASCII uppercase letter `X`
+//! written as `^x`. Keys differing only in case therefore stay distinct on
+//! case insensitive filesystems, and a repository copied between
+//! filesystems keeps working.
+
+use super::{
+    check_key, check_prefix, escape_key, not_found, unescape_key, Capabilities, StorageEngine,
+};
+use crate::RepoError;
+use std::fs;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tweetnacl::random_bytes;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SyncPolicy {
+    // fsync every written file and the directory entry pointing at it.
+    Always,
+    // fsync written files but not directories, faster on some filesystems
+    // at the risk of losing recent renames on power loss.
+    FileOnly,
+    // Leave everything to the operating system.
+    Never,
+}
+
+pub struct LocalStorage {
+    root: PathBuf,
+    sync: SyncPolicy,
+}
+
+impl LocalStorage {
+    pub fn new(root: &Path) -> Result<LocalStorage, RepoError> {
+        LocalStorage::with_sync_policy(root, SyncPolicy::Always)
+    }
+
+    pub fn with_sync_policy(root: &Path, sync: SyncPolicy) -> Result<LocalStorage, RepoError> {
+        fs::create_dir_all(root)?;
+        Ok(LocalStorage {
+            root: root.to_path_buf(),
+            sync,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, RepoError> {
+        check_key(key)?;
+        Ok(self.root.join(escape_key(key)))
+    }
+
+    fn sync_dir(&self, dir: &Path) -> Result<(), RepoError> {
+        if self.sync == SyncPolicy::Always {
+            fs::File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn list_dir(&self, dir: &Path, out: &mut Vec<String>) -> Result<(), RepoError> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for ent in entries {
+            let ent = ent?;
+            let name = ent.file_name();
+            let name = match name.to_str() {
+                Some(name) if !name.starts_with('.') => name,
+                _ => continue,
+            };
+            if ent.file_type()?.is_dir() {
+                self.list_dir(&dir.join(name), out)?;
+            } else {
+                let rel = dir.join(name);
+                let rel = rel.strip_prefix(&self.root).unwrap();
+                let parts: Vec<_> = rel.iter().map(|p| p.to_string_lossy()).collect();
+                out.push(unescape_key(&parts.join("/")));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn map_not_found(err: std::io::Error, key: &str) -> RepoError {
+    if err.kind() == ErrorKind::NotFound {
+        not_found(key)
+    } else {
+        err.into()
+    }
+}
+
+impl StorageEngine for LocalStorage {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), RepoError> {
+        let path = self.path(key)?;
+        let dir = path.parent().unwrap();
+        fs::create_dir_all(dir)?;
+
+        let mut rand = [0; 8];
+        random_bytes(&mut rand);
+        let tmp_name = format!(
+            ".{}.{}.tmp",
+            path.file_name().unwrap().to_string_lossy(),
+            crate::address::to_hex(&rand)
+        );
+        let tmp_path = dir.join(tmp_name);
+
+        let result = (|| {
+            let mut f = fs::File::create(&tmp_path)?;
+            f.write_all(data)?;
+            if self.sync != SyncPolicy::Never {
+                f.sync_all()?;
+            }
+            fs::rename(&tmp_path, &path)?;
+            self.sync_dir(dir)
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        result
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, RepoError> {
+        fs::read(self.path(key)?).map_err(|e| map_not_found(e, key))
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: usize) -> Result<Vec<u8>, RepoError> {
+        let mut f = fs::File::open(self.path(key)?).map_err(|e| map_not_found(e, key))?;
+        let size = f.metadata()?.len();
+        if offset > size || size - offset < len as u64 {
+            return Err(RepoError::InvalidRangeError);
+        }
+        let mut buf = vec![0; len];
+        f.seek(SeekFrom::Start(offset))?;
+        f.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn size(&self, key: &str) -> Result<u64, RepoError> {
+        let md = fs::metadata(self.path(key)?).map_err(|e| map_not_found(e, key))?;
+        Ok(md.len())
+    }
+
+    fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, RepoError> {
+        check_prefix(prefix)?;
+        // Only walk the deepest directory that can contain matches.
+        let dir = match prefix.rfind('/') {
+            Some(i) => self.root.join(escape_key(&prefix[..i])),
+            None => self.root.clone(),
+        };
+        let mut keys = Vec::new();
+        self.list_dir(&dir, &mut keys)?;
+        keys.retain(|k| k.starts_with(prefix));
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RepoError> {
+        let path = self.path(key)?;
+        fs::remove_file(&path).map_err(|e| map_not_found(e, key))?;
+        self.sync_dir(path.parent().unwrap())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), RepoError> {
+        let from_path = self.path(from)?;
+        let to_path = self.path(to)?;
+        let to_dir = to_path.parent().unwrap();
+        fs::create_dir_all(to_dir)?;
+        fs::rename(&from_path, &to_path).map_err(|e| map_not_found(e, from))?;
+        self.sync_dir(to_dir)?;
+        self.sync_dir(from_path.parent().unwrap())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            atomic_rename: true,
+            range_reads: true,
+            delete: true,
+        }
+    }
+}
+
+// Tests --------------------
+
+#[cfg(test)]
+pub(crate) fn test_dir(name: &str) -> PathBuf {
+    let d = std::env::temp_dir().join(format!("pnb-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&d);
+    d
+}
+
+#[test]
+fn test_local_storage() {
+    let dir = test_dir("local-storage");
+    for sync in [SyncPolicy::Always, SyncPolicy::FileOnly, SyncPolicy::Never].iter() {
+        let s = LocalStorage::with_sync_policy(&dir, *sync).unwrap();
+        super::test_storage_engine(&s);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[test]
+fn test_local_storage_case() {
+    let dir = test_dir("local-storage-case");
+    let s = LocalStorage::new(&dir).unwrap();
+    s.put("ns/Laptop", b"upper").unwrap();
+    s.put("ns/laptop", b"lower").unwrap();
+    assert_eq!(s.get("ns/Laptop").unwrap(), b"upper");
+    assert_eq!(s.get("ns/laptop").unwrap(), b"lower");
+    assert_eq!(
+        s.list_prefix("ns/").unwrap(),
+        vec!["ns/Laptop", "ns/laptop"]
+    );
+    assert!(dir.join("ns/^laptop").exists());
+    assert!(s.put("../escape", b"").is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_local_repo() {
+    let dir = test_dir("local-repo");
+    let key = asymcrypt::Key::new();
+    let storage = std::sync::Arc::new(LocalStorage::new(&dir).unwrap());
+    crate::Repo::init(storage.clone(), Default::default(), &key).unwrap();
+    let r = crate::Repo::open(storage, &key.pub_key()).unwrap();
+    assert!(r.manifest().unwrap().heads.is_empty());
+    assert!(dir.join(crate::CONFIG_FILE).exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,100 @@
+//! In memory storage, used for tests.
+
+use super::{check_key, not_found, Capabilities, StorageEngine};
+use crate::RepoError;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+#[derive(Default)]
+pub struct MemStorage {
+    objects: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemStorage {
+    pub fn new() -> MemStorage {
+        Default::default()
+    }
+}
+
+impl StorageEngine for MemStorage {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), RepoError> {
+        check_key(key)?;
+        let mut objects = self.objects.lock().unwrap();
+        objects.insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, RepoError> {
+        let objects = self.objects.lock().unwrap();
+        match objects.get(key) {
+            Some(v) => Ok(v.clone()),
+            None => Err(not_found(key)),
+        }
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: usize) -> Result<Vec<u8>, RepoError> {
+        let objects = self.objects.lock().unwrap();
+        match objects.get(key) {
+            Some(v) => {
+                let start = offset as usize;
+                if offset > v.len() as u64 || v.len() - start < len {
+                    return Err(RepoError::InvalidRangeError);
+                }
+                Ok(v[start..start + len].to_vec())
+            }
+            None => Err(not_found(key)),
+        }
+    }
+
+    fn size(&self, key: &str) -> Result<u64, RepoError> {
+        let objects = self.objects.lock().unwrap();
+        match objects.get(key) {
+            Some(v) => Ok(v.len() as u64),
+            None => Err(not_found(key)),
+        }
+    }
+
+    fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, RepoError> {
+        let objects = self.objects.lock().unwrap();
+        Ok(objects
+            .range(prefix.to_string()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, _)| k.clone())
+            .collect())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RepoError> {
+        let mut objects = self.objects.lock().unwrap();
+        match objects.remove(key) {
+            Some(_) => Ok(()),
+            None => Err(not_found(key)),
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), RepoError> {
+        check_key(to)?;
+        let mut objects = self.objects.lock().unwrap();
+        match objects.remove(from) {
+            Some(v) => {
+                objects.insert(to.to_string(), v);
+                Ok(())
+            }
+            None => Err(not_found(from)),
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            atomic_rename: true,
+            range_reads: true,
+            delete: true,
+        }
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_mem_storage() {
+    super::test_storage_engine(&MemStorage::new());
+}
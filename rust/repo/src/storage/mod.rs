@@ -0,0 +1,299 @@
+//! Storage backends.
+//!
+//! The repository layer only ever talks to storage through the
+//! `StorageEngine` trait. Keys are '/' separated relative paths such as
+//! `packs/<hex id>`, values are opaque byte strings.
+//!
+//! Every backend must make `put` atomic: a concurrent or later reader sees
+//! either the complete new value or no value at all, never a partial one.
+//! Missing keys are reported as an `io::ErrorKind::NotFound` error.
+//!
+//! Backends with archival storage classes report reads of values that
+//! must be thawed first as `ColdStorageError`, promptly rather than
+//! after a timeout, and start thawing them on `thaw`.
+
+use super::address::Address;
+use super::checks::CheckStatus;
+use super::gc::GcStats;
+use super::pack::RangeRead;
+use super::translog::LogHash;
+use super::usage::Quota;
+use super::RepoError;
+use std::io;
+use std::sync::Arc;
+
+#[cfg(feature = "async")]
+pub mod aio;
+pub mod append_only;
+#[cfg(feature = "b2")]
+pub mod b2;
+pub mod discard;
+#[cfg(feature = "gcs")]
+pub mod gcs;
+pub mod grace;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod local;
+pub mod mem;
+pub mod prefix;
+pub mod remote;
+pub mod retry;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(feature = "sftp")]
+pub mod sftp;
+pub mod throttle;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Capabilities {
+    // `rename` is supported and atomic.
+    pub atomic_rename: bool,
+    // `get_range` is served without transferring the whole value.
+    pub range_reads: bool,
+    // `delete` is permitted, false for append only transports.
+    pub delete: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ThawState {
+    Ready,
+    // Readable in about this many seconds.
+    Pending(u64),
+}
+
+pub trait StorageEngine: Send + Sync {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), RepoError>;
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, RepoError>;
+
+    fn get_range(&self, key: &str, offset: u64, len: usize) -> Result<Vec<u8>, RepoError>;
+
+    fn size(&self, key: &str) -> Result<u64, RepoError>;
+
+    // All keys starting with `prefix`, sorted.
+    fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, RepoError>;
+
+    fn delete(&self, key: &str) -> Result<(), RepoError>;
+
+    fn rename(&self, _from: &str, _to: &str) -> Result<(), RepoError> {
+        Err(RepoError::UnsupportedOperationError)
+    }
+
+    fn capabilities(&self) -> Capabilities;
+
+    // Start making an archived value readable. Values that are readable
+    // already, and everything on backends without archival storage, are
+    // ready.
+    fn thaw(&self, key: &str) -> Result<ThawState, RepoError> {
+        self.size(key)?;
+        Ok(ThawState::Ready)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RepoError> {
+        match self.size(key) {
+            Ok(_) => Ok(true),
+            Err(ref e) if e.is_not_found() => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Whether each of `keys` exists. Engines with a high round trip time
+    // answer these in one go.
+    fn exists_many(&self, keys: &[&str]) -> Result<Vec<bool>, RepoError> {
+        keys.iter().map(|k| self.exists(k)).collect()
+    }
+
+    // Put every object, stopping at the first error. Which of the others
+    // were stored is then unknown.
+    fn put_many(&self, objects: &[(&str, &[u8])]) -> Result<(), RepoError> {
+        for (key, data) in objects.iter() {
+            self.put(key, data)?;
+        }
+        Ok(())
+    }
+
+    // Have the server delete what a signed keep list leaves out, see
+    // `keeplist`. Only storage reached through `serve` can do this.
+    fn collect_garbage(&self, _keep_list: &[u8]) -> Result<GcStats, RepoError> {
+        Err(RepoError::UnsupportedOperationError)
+    }
+
+    // Whether each object is indexed or stored loose, see `presence`.
+    fn objects_present(&self, _addresses: &[Address]) -> Result<Vec<bool>, RepoError> {
+        Err(RepoError::UnsupportedOperationError)
+    }
+
+    // A proof that the first `old` leaves of the manifest log are a prefix
+    // of the first `new`, see `translog`.
+    fn consistency_proof(&self, _old: u64, _new: u64) -> Result<Vec<LogHash>, RepoError> {
+        Err(RepoError::UnsupportedOperationError)
+    }
+
+    // The quotas the server holds this client to, see `usage`.
+    fn quotas(&self) -> Result<Vec<Quota>, RepoError> {
+        Err(RepoError::UnsupportedOperationError)
+    }
+
+    // How the server's structural checks of the repository went, see
+    // `checks`.
+    fn check_status(&self) -> Result<CheckStatus, RepoError> {
+        Err(RepoError::UnsupportedOperationError)
+    }
+}
+
+pub fn not_found(key: &str) -> RepoError {
+    RepoError::IOError(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found", key),
+    ))
+}
+
+// Validate keys before they reach a backend, so a forged key from a remote
+// peer or corrupt data can never escape the repository root.
+pub fn check_key(key: &str) -> Result<(), RepoError> {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+    let valid = !key.is_empty()
+        && key.len() <= 1024
+        && key
+            .split('/')
+            .all(|p| !p.is_empty() && !p.starts_with('.') && p.chars().all(valid_char));
+    if valid {
+        Ok(())
+    } else {
+        Err(RepoError::InvalidKeyError)
+    }
+}
+
+// A listing prefix is valid if some valid key starts with it.
+pub fn check_prefix(prefix: &str) -> Result<(), RepoError> {
+    check_key(&format!("{}x", prefix))
+}
+
+// Percent encode everything but RFC 3986 unreserved characters.
+#[cfg(any(feature = "s3", feature = "b2", feature = "gcs"))]
+pub(crate) fn uri_encode(s: &str, encode_slash: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            b'/' if !encode_slash => out.push('/'),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+// Keys as written to filesystem like backends, each ASCII uppercase letter
+// `X` becomes `^x` so case insensitive filesystems keep keys distinct.
+pub(crate) fn escape_key(key: &str) -> String {
+    let mut s = String::with_capacity(key.len());
+    for c in key.chars() {
+        if c.is_ascii_uppercase() {
+            s.push('^');
+            s.push(c.to_ascii_lowercase());
+        } else {
+            s.push(c);
+        }
+    }
+    s
+}
+
+pub(crate) fn unescape_key(name: &str) -> String {
+    let mut s = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '^' => {
+                if let Some(c) = chars.next() {
+                    s.push(c.to_ascii_uppercase());
+                }
+            }
+            c => s.push(c),
+        }
+    }
+    s
+}
+
+// A single stored value, readable with ranged gets.
+pub struct StorageObject {
+    storage: Arc<dyn StorageEngine>,
+    key: String,
+}
+
+impl StorageObject {
+    pub fn new(storage: Arc<dyn StorageEngine>, key: &str) -> StorageObject {
+        StorageObject {
+            storage,
+            key: key.to_string(),
+        }
+    }
+}
+
+impl RangeRead for StorageObject {
+    fn read_range(&mut self, offset: u64, len: usize) -> Result<Vec<u8>, RepoError> {
+        self.storage.get_range(&self.key, offset, len)
+    }
+
+    fn size(&mut self) -> Result<u64, RepoError> {
+        self.storage.size(&self.key)
+    }
+}
+
+// Shared behavioural checks, run against every backend by its own tests.
+#[cfg(test)]
+pub(crate) fn test_storage_engine(s: &dyn StorageEngine) {
+    s.put("a/b", b"hello").unwrap();
+    s.put("a/c", b"world").unwrap();
+    s.put("b", b"!").unwrap();
+    assert_eq!(s.get("a/b").unwrap(), b"hello");
+    assert_eq!(s.get_range("a/b", 1, 3).unwrap(), b"ell");
+    assert!(s.get_range("a/b", 3, 3).is_err());
+    assert_eq!(s.size("a/c").unwrap(), 5);
+    assert_eq!(s.list_prefix("a/").unwrap(), vec!["a/b", "a/c"]);
+    assert_eq!(s.list_prefix("").unwrap(), vec!["a/b", "a/c", "b"]);
+    assert!(s.list_prefix("nothing/").unwrap().is_empty());
+
+    s.put("a/b", b"replaced").unwrap();
+    assert_eq!(s.get("a/b").unwrap(), b"replaced");
+
+    assert!(s.exists("b").unwrap());
+    s.delete("b").unwrap();
+    assert!(!s.exists("b").unwrap());
+    assert!(s.get("b").unwrap_err().is_not_found());
+    assert!(s.delete("b").unwrap_err().is_not_found());
+
+    s.put_many(&[("m/1", &b"one"[..]), ("m/2", &b"two"[..])])
+        .unwrap();
+    assert_eq!(s.get("m/2").unwrap(), b"two");
+    assert_eq!(
+        s.exists_many(&["m/1", "m/3", "a/b"]).unwrap(),
+        vec![true, false, true]
+    );
+    assert!(s.exists_many(&[]).unwrap().is_empty());
+
+    if s.capabilities().atomic_rename {
+        s.rename("a/c", "d").unwrap();
+        assert_eq!(s.get("d").unwrap(), b"world");
+        assert!(!s.exists("a/c").unwrap());
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_check_key() {
+    assert!(check_key("packs/0123abcd").is_ok());
+    assert!(check_key("keys/owner.pub").is_ok());
+    assert!(check_key("").is_err());
+    assert!(check_key("/etc/passwd").is_err());
+    assert!(check_key("packs/../config").is_err());
+    assert!(check_key("packs//x").is_err());
+    assert!(check_key("packs/.hidden").is_err());
+    assert!(check_prefix("").is_ok());
+    assert!(check_prefix("packs/").is_ok());
+    assert!(check_prefix("packs/01").is_ok());
+    assert!(check_prefix("../").is_err());
+    assert!(check_prefix("/").is_err());
+}
@@ -0,0 +1,102 @@
+//! Storage under a directory of another engine.
+//!
+//! Wraps another engine, storing each key under `prefix/`, so several
+//! repositories can share one, see `tenant`. Keys and prefixes are checked
+//! before the prefix is added, so nothing outside the directory can be
+//! named, and listed keys have it stripped again.
+//!
+//! The operations an engine may answer for the whole repository, garbage
+//! collection and finding objects, are not passed on, the inner engine
+//! would answer them for its own root.
+
+use super::{Capabilities, StorageEngine, ThawState};
+use crate::RepoError;
+use std::sync::Arc;
+
+pub struct PrefixStorage {
+    inner: Arc<dyn StorageEngine>,
+    prefix: String,
+}
+
+impl PrefixStorage {
+    pub fn new(inner: Arc<dyn StorageEngine>, prefix: &str) -> Result<PrefixStorage, RepoError> {
+        super::check_key(prefix)?;
+        Ok(PrefixStorage {
+            inner,
+            prefix: format!("{}/", prefix),
+        })
+    }
+
+    fn key(&self, key: &str) -> Result<String, RepoError> {
+        super::check_key(key)?;
+        Ok(format!("{}{}", self.prefix, key))
+    }
+}
+
+impl StorageEngine for PrefixStorage {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), RepoError> {
+        self.inner.put(&self.key(key)?, data)
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, RepoError> {
+        self.inner.get(&self.key(key)?)
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: usize) -> Result<Vec<u8>, RepoError> {
+        self.inner.get_range(&self.key(key)?, offset, len)
+    }
+
+    fn size(&self, key: &str) -> Result<u64, RepoError> {
+        self.inner.size(&self.key(key)?)
+    }
+
+    fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, RepoError> {
+        super::check_prefix(prefix)?;
+        let keys = self
+            .inner
+            .list_prefix(&format!("{}{}", self.prefix, prefix))?;
+        Ok(keys
+            .into_iter()
+            .filter_map(|k| k.strip_prefix(&self.prefix).map(str::to_string))
+            .collect())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RepoError> {
+        self.inner.delete(&self.key(key)?)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), RepoError> {
+        self.inner.rename(&self.key(from)?, &self.key(to)?)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn thaw(&self, key: &str) -> Result<ThawState, RepoError> {
+        self.inner.thaw(&self.key(key)?)
+    }
+
+    fn exists_many(&self, keys: &[&str]) -> Result<Vec<bool>, RepoError> {
+        let keys = keys
+            .iter()
+            .map(|k| self.key(k))
+            .collect::<Result<Vec<_>, _>>()?;
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        self.inner.exists_many(&keys)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_prefix_storage() {
+    let inner = Arc::new(super::mem::MemStorage::new());
+    inner.put("tenants/other/a/b", b"other").unwrap();
+    let s = PrefixStorage::new(inner.clone(), "tenants/design").unwrap();
+    super::test_storage_engine(&s);
+    assert_eq!(inner.get("tenants/design/a/b").unwrap(), b"replaced");
+    assert_eq!(inner.get("tenants/other/a/b").unwrap(), b"other");
+    assert!(s.get("../other/a/b").is_err());
+    assert!(PrefixStorage::new(inner, "../escape").is_err());
+}
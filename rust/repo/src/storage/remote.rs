@@ -0,0 +1,1268 @@
+//! Storage reached through the remote protocol, see `protocol`.
+//!
+//! Usually the far end is `packnback serve` started over ssh, which means
+//! the server, not this client, decides what may be deleted or
+//! overwritten. It can also be reached over TLS, see `tls`, and a server on
+//! the same machine over a unix socket, see `RemoteStorage::unix`. Any byte
+//! stream works, which the tests use to talk to an in process server.
+//!
+//! Requests are sequential, but `exists_many` and `put_many` send their
+//! requests in batches of up to `BATCH_MAX_REQUESTS` and `BATCH_MAX_BYTES`
+//! to save round trips. Servers from before batching refuse the batch, the
+//! requests are then sent one at a time.
+//!
+//! Each connection starts with a hello, see `protocol`, and the features
+//! the server does not have are not asked for, see `RemoteStorage::hello`.
+//!
+//! If a request fails part way through sending or receiving a frame the
+//! stream can no longer be trusted, so the connection is marked broken. A
+//! storage started with `spawn` or `ssh` runs the command again for the
+//! next request, one started with `connect` opens a new connection, on
+//! any other stream every later request fails. The failed
+//! request itself is not repeated here, see `retry`.
+//!
+//! A server that authenticates clients, see `serve`, is answered by
+//! `RemoteStorage::authenticate`. The storage keeps its own copy of the
+//! signing key to authenticate each connection it runs the command again
+//! for, wiped when the storage is dropped. A server hosting many
+//! repositories is told which one with `RemoteStorage::select_tenant`
+//! first, see `tenant`, on every connection too. After
+//! `RemoteStorage::open_session` new connections resume the session
+//! instead, and a batch cut off is finished without sending again what the
+//! server already answered, see `session`.
+
+use super::{check_key, check_prefix, Capabilities, StorageEngine, ThawState};
+use crate::address::Address;
+use crate::checks::CheckStatus;
+use crate::gc::GcStats;
+use crate::presence::{decode_bitmap, MAX_PRESENCE_QUERY};
+use crate::protocol::{self, Hello, Request, CHALLENGE_SZ};
+use crate::session::{SessionToken, TOKEN_SZ};
+use crate::signed;
+use crate::translog::LogHash;
+use crate::usage::Quota;
+use crate::wire::Decoder;
+use crate::RepoError;
+use std::ffi::OsString;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+use tweetnacl::CryptoSignSk;
+
+pub const BATCH_MAX_REQUESTS: usize = 4096;
+pub const BATCH_MAX_BYTES: usize = 16 * 1024 * 1024;
+pub const RESUME_ATTEMPTS: usize = 3;
+
+struct Conn {
+    r: Box<dyn Read + Send>,
+    w: Box<dyn Write + Send>,
+    child: Option<Child>,
+    broken: bool,
+    // The responses to the batch in flight, by id, if the connection
+    // resumed a session the batch was sent in.
+    resumed: Option<Vec<(u32, Vec<u8>)>>,
+    // What the server answered our hello with, once it has.
+    hello: Option<Hello>,
+}
+
+impl Conn {
+    fn new(r: Box<dyn Read + Send>, w: Box<dyn Write + Send>, child: Option<Child>) -> Conn {
+        Conn {
+            r,
+            w,
+            child,
+            broken: false,
+            resumed: None,
+            hello: None,
+        }
+    }
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        // Closing our end of the stream tells the server to exit, one that
+        // is stuck part way through a frame is killed.
+        self.w = Box::new(io::sink());
+        self.r = Box::new(io::empty());
+        if let Some(ref mut child) = self.child {
+            if self.broken {
+                let _ = child.kill();
+            }
+            let _ = child.wait();
+        }
+    }
+}
+
+// What is needed to run a `Command` again.
+struct Respawn {
+    program: OsString,
+    args: Vec<OsString>,
+    envs: Vec<(OsString, Option<OsString>)>,
+    dir: Option<PathBuf>,
+}
+
+impl Respawn {
+    fn new(cmd: &Command) -> Respawn {
+        Respawn {
+            program: cmd.get_program().to_owned(),
+            args: cmd.get_args().map(|a| a.to_owned()).collect(),
+            envs: cmd
+                .get_envs()
+                .map(|(k, v)| (k.to_owned(), v.map(|v| v.to_owned())))
+                .collect(),
+            dir: cmd.get_current_dir().map(|d| d.to_owned()),
+        }
+    }
+
+    fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.args(&self.args);
+        for (k, v) in self.envs.iter() {
+            match v {
+                Some(v) => cmd.env(k, v),
+                None => cmd.env_remove(k),
+            };
+        }
+        if let Some(ref dir) = self.dir {
+            cmd.current_dir(dir);
+        }
+        cmd
+    }
+}
+
+// Opens a new connection in place of a broken one.
+type Reconnect = Box<dyn Fn() -> Result<Conn, RepoError> + Send + Sync>;
+
+pub struct RemoteStorage {
+    conn: Mutex<Option<Conn>>,
+    reconnect: Option<Reconnect>,
+    // The client and key to authenticate new connections with.
+    auth: Option<(String, Box<CryptoSignSk>)>,
+    // The tenant to select on new connections, before authenticating.
+    tenant: Option<String>,
+    // The session new connections resume instead, if the server keeps one.
+    session: Mutex<Option<ClientSession>>,
+}
+
+// A session the server keeps for us, see `session`.
+struct ClientSession {
+    token: SessionToken,
+    // Batches sent in the session, and whether the last is unanswered.
+    batches: u64,
+    in_flight: bool,
+}
+
+// Quote for the remote shell that ssh runs commands with.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+// Sign the server's challenge as `client`, see `protocol`, returning the
+// server's reason for refusing it, if it did, apart from transport errors.
+fn authenticate_conn(
+    conn: &mut Conn,
+    client: &str,
+    sk: &CryptoSignSk,
+) -> Result<Result<(), RepoError>, RepoError> {
+    protocol::write_frame(&mut conn.w, &Request::Challenge.encode())?;
+    let resp = read_response(conn)?;
+    let mut challenge = [0; CHALLENGE_SZ];
+    let opened = protocol::open_response(&resp, "").and_then(|mut d| {
+        d.fixed_into(&mut challenge)?;
+        d.finish()
+    });
+    if let Err(err) = opened {
+        return Ok(Err(err));
+    }
+    let signed = signed::sign(&protocol::auth_message(&challenge, client), sk);
+    let req = Request::Auth {
+        client,
+        signed: &signed,
+    };
+    protocol::write_frame(&mut conn.w, &req.encode())?;
+    let resp = read_response(conn)?;
+    Ok(protocol::open_response(&resp, "").and_then(|d| d.finish()))
+}
+
+// Say which protocol version and features we speak, keeping what the
+// server speaks of them. Servers from before HELLO refuse it.
+fn hello_conn(conn: &mut Conn) -> Result<Hello, RepoError> {
+    protocol::write_frame(&mut conn.w, &Request::Hello(Hello::ours()).encode())?;
+    let resp = read_response(conn)?;
+    let answered = protocol::open_response(&resp, "").and_then(|mut d| {
+        let hello = protocol::decode_hello(&mut d)?;
+        d.finish()?;
+        Ok(hello)
+    });
+    let hello = match answered {
+        Ok(hello) if hello.version == 0 || hello.version > protocol::PROTOCOL_VERSION => {
+            return Err(RepoError::InvalidDataError)
+        }
+        Ok(hello) => Hello {
+            version: hello.version,
+            features: hello.features & protocol::FEATURES,
+        },
+        Err(RepoError::UnsupportedOperationError) => Hello::legacy(),
+        Err(err) => return Err(err),
+    };
+    conn.hello = Some(hello);
+    Ok(hello)
+}
+
+// Select the tenant `name`, returning the server's reason for refusing it
+// apart from transport errors, as `authenticate_conn` does.
+fn select_tenant_conn(conn: &mut Conn, name: &str) -> Result<Result<(), RepoError>, RepoError> {
+    let req = Request::Tenant { name };
+    protocol::write_frame(&mut conn.w, &req.encode())?;
+    let resp = read_response(conn)?;
+    Ok(protocol::open_response(&resp, name).and_then(|d| d.finish()))
+}
+
+// Ask for a session token, returning the server's reason for refusing it
+// apart from transport errors, as `authenticate_conn` does.
+fn open_session_conn(conn: &mut Conn) -> Result<Result<ClientSession, RepoError>, RepoError> {
+    protocol::write_frame(&mut conn.w, &Request::Session.encode())?;
+    let resp = read_response(conn)?;
+    let mut token = [0; TOKEN_SZ];
+    let opened = protocol::open_response(&resp, "").and_then(|mut d| {
+        d.fixed_into(&mut token)?;
+        d.finish()
+    });
+    Ok(opened.map(|_| ClientSession {
+        token,
+        batches: 0,
+        in_flight: false,
+    }))
+}
+
+// Resume `session` on a new connection, keeping the responses to the batch
+// in flight, false if the server no longer has it.
+fn resume_conn(conn: &mut Conn, session: &mut ClientSession) -> Result<bool, RepoError> {
+    let batch = if session.in_flight {
+        session.batches
+    } else {
+        0
+    };
+    let req = Request::Resume {
+        token: session.token,
+        batch,
+    };
+    protocol::write_frame(&mut conn.w, &req.encode())?;
+    let resp = read_response(conn)?;
+    let resumed = protocol::open_response(&resp, "").and_then(|mut d| {
+        let resumed = protocol::decode_resumed(&mut d)?;
+        d.finish()?;
+        Ok(resumed)
+    });
+    let resumed = match resumed {
+        Ok(resumed) => resumed,
+        Err(_) => return Ok(false),
+    };
+    if session.in_flight {
+        // A batch the server never started has no responses yet.
+        conn.resumed = Some(if resumed.batches == batch {
+            resumed.answered
+        } else {
+            Vec::new()
+        });
+    }
+    session.batches = resumed.batches;
+    session.in_flight = false;
+    Ok(true)
+}
+
+fn spawn_conn(cmd: &mut Command) -> Result<Conn, RepoError> {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    let r = Box::new(BufReader::new(child.stdout.take().unwrap()));
+    let w = Box::new(BufWriter::new(child.stdin.take().unwrap()));
+    Ok(Conn::new(r, w, Some(child)))
+}
+
+impl RemoteStorage {
+    pub fn new(r: Box<dyn Read + Send>, w: Box<dyn Write + Send>) -> RemoteStorage {
+        RemoteStorage {
+            conn: Mutex::new(Some(Conn::new(r, w, None))),
+            reconnect: None,
+            auth: None,
+            tenant: None,
+            session: Mutex::new(None),
+        }
+    }
+
+    // Speak the protocol over the streams `connect` opens, calling it again
+    // for the next request whenever a connection breaks.
+    pub fn connect<F>(connect: F) -> Result<RemoteStorage, RepoError>
+    where
+        F: Fn() -> Result<(Box<dyn Read + Send>, Box<dyn Write + Send>), RepoError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let open = move || {
+            let (r, w) = connect()?;
+            Ok(Conn::new(r, w, None))
+        };
+        Ok(RemoteStorage {
+            conn: Mutex::new(Some(open()?)),
+            reconnect: Some(Box::new(open)),
+            auth: None,
+            tenant: None,
+            session: Mutex::new(None),
+        })
+    }
+
+    // Run `cmd` and speak the protocol over its stdin and stdout, its
+    // stderr is left connected to ours for diagnostics.
+    pub fn spawn(cmd: &mut Command) -> Result<RemoteStorage, RepoError> {
+        let conn = spawn_conn(cmd)?;
+        let respawn = Respawn::new(cmd);
+        Ok(RemoteStorage {
+            conn: Mutex::new(Some(conn)),
+            reconnect: Some(Box::new(move || spawn_conn(&mut respawn.command()))),
+            auth: None,
+            tenant: None,
+            session: Mutex::new(None),
+        })
+    }
+
+    // Connect to a server listening on the unix socket at `path`, see
+    // `ServeOptions::socket`.
+    pub fn unix(path: &Path) -> Result<RemoteStorage, RepoError> {
+        let path = path.to_owned();
+        RemoteStorage::connect(move || {
+            let conn = UnixStream::connect(&path)?;
+            Ok((
+                Box::new(BufReader::new(conn.try_clone()?)),
+                Box::new(BufWriter::new(conn)),
+            ))
+        })
+    }
+
+    // Equivalent to `ssh host packnback serve path`.
+    pub fn ssh(host: &str, path: &str) -> Result<RemoteStorage, RepoError> {
+        RemoteStorage::spawn(
+            Command::new("ssh")
+                .arg("--")
+                .arg(host)
+                .arg("packnback")
+                .arg("serve")
+                .arg(shell_quote(path)),
+        )
+    }
+
+    // Authenticate as `client` with its key from the access list, see
+    // `acl`, now and on every connection after.
+    pub fn authenticate(&mut self, client: &str, sk: &CryptoSignSk) -> Result<(), RepoError> {
+        self.exchange(|conn| authenticate_conn(conn, client, sk))??;
+        let copy = Box::new(CryptoSignSk { bytes: sk.bytes });
+        self.auth = Some((client.to_string(), copy));
+        Ok(())
+    }
+
+    // Select the repository `name` of a server hosting tenants, now and on
+    // every connection after. Must come before `authenticate`.
+    pub fn select_tenant(&mut self, name: &str) -> Result<(), RepoError> {
+        self.exchange(|conn| select_tenant_conn(conn, name))??;
+        self.tenant = Some(name.to_string());
+        Ok(())
+    }
+
+    // Ask the server to keep this session, so new connections resume it
+    // rather than select the tenant and authenticate again, and batches cut
+    // off are finished without sending what the server already answered,
+    // see `session`. Must come after both.
+    pub fn open_session(&mut self) -> Result<(), RepoError> {
+        if !self.hello()?.has(protocol::FEATURE_SESSIONS) {
+            return Err(RepoError::UnsupportedOperationError);
+        }
+        let session = self.exchange(open_session_conn)??;
+        *self.session.get_mut().unwrap() = Some(session);
+        Ok(())
+    }
+
+    // The protocol version and features the server speaks with us, see
+    // `protocol`, those of a server from before HELLO are assumed.
+    pub fn hello(&self) -> Result<Hello, RepoError> {
+        self.exchange(|conn| Ok(conn.hello.unwrap()))
+    }
+
+    // Run an exchange of frames, the connection is broken if it fails.
+    fn exchange<T, F>(&self, f: F) -> Result<T, RepoError>
+    where
+        F: FnOnce(&mut Conn) -> Result<T, RepoError>,
+    {
+        let mut guard = self.conn.lock().unwrap();
+        let usable = matches!(*guard, Some(ref conn) if !conn.broken);
+        if !usable {
+            match self.reconnect {
+                Some(ref reconnect) => {
+                    guard.take();
+                    let mut conn = reconnect()?;
+                    hello_conn(&mut conn)?;
+                    let mut session = self.session.lock().unwrap();
+                    let resumed = match *session {
+                        Some(ref mut session) => resume_conn(&mut conn, session)?,
+                        None => false,
+                    };
+                    if !resumed {
+                        if let Some(ref name) = self.tenant {
+                            select_tenant_conn(&mut conn, name)??;
+                        }
+                        if let Some((ref client, ref sk)) = self.auth {
+                            authenticate_conn(&mut conn, client, sk)??;
+                        }
+                        if session.is_some() {
+                            *session = open_session_conn(&mut conn)?.ok();
+                        }
+                    }
+                    drop(session);
+                    *guard = Some(conn);
+                }
+                None => {
+                    return Err(RepoError::StorageError(
+                        "remote connection lost".to_string(),
+                    ))
+                }
+            }
+        }
+        let conn = guard.as_mut().unwrap();
+        conn.broken = true;
+        if conn.hello.is_none() {
+            hello_conn(conn)?;
+        }
+        let v = f(conn)?;
+        conn.broken = false;
+        Ok(v)
+    }
+
+    fn call<T, F>(&self, req: &Request, f: F) -> Result<T, RepoError>
+    where
+        F: FnOnce(&mut Decoder) -> Result<T, RepoError>,
+    {
+        let resp = self.exchange(|conn| {
+            protocol::write_frame(&mut conn.w, &req.encode())?;
+            read_response(conn)
+        })?;
+        let mut d = protocol::open_response(&resp, req.key())?;
+        let v = f(&mut d)?;
+        d.finish()?;
+        Ok(v)
+    }
+
+    // Send requests as batches, returning each result in order. Requests
+    // must not depend on each other, see `protocol`.
+    pub fn call_many<T, F>(
+        &self,
+        reqs: &[Request],
+        f: F,
+    ) -> Result<Vec<Result<T, RepoError>>, RepoError>
+    where
+        F: Fn(&mut Decoder) -> Result<T, RepoError>,
+    {
+        let decode = |req: &Request, resp: &[u8]| {
+            let mut d = protocol::open_response(resp, req.key())?;
+            let v = f(&mut d)?;
+            d.finish()?;
+            Ok(v)
+        };
+        let mut results = Vec::with_capacity(reqs.len());
+        if !self.hello()?.has(protocol::FEATURE_BATCH) {
+            for req in reqs.iter() {
+                results.push(self.call(req, &f));
+            }
+            return Ok(results);
+        }
+        let mut start = 0;
+        while start < reqs.len() {
+            let mut encoded = Vec::new();
+            let mut bytes = 0;
+            for req in reqs[start..].iter() {
+                let buf = req.encode();
+                bytes += buf.len() + 8;
+                if !encoded.is_empty()
+                    && (encoded.len() == BATCH_MAX_REQUESTS || bytes > BATCH_MAX_BYTES)
+                {
+                    break;
+                }
+                encoded.push(buf);
+            }
+            let batch = &reqs[start..start + encoded.len()];
+            match self.send_batch(&encoded)? {
+                Ok(resps) => {
+                    for (req, resp) in batch.iter().zip(resps.iter()) {
+                        results.push(decode(req, resp));
+                    }
+                }
+                Err(RepoError::UnsupportedOperationError) => {
+                    for req in batch.iter() {
+                        results.push(self.call(req, &f));
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+            start += batch.len();
+        }
+        Ok(results)
+    }
+
+    // The responses to one batch in request order, or the server's reason
+    // for refusing it. In a session a batch cut off is resumed on a new
+    // connection, up to `RESUME_ATTEMPTS` times, and only the requests the
+    // server has not answered are sent again.
+    fn send_batch(
+        &self,
+        encoded: &[Vec<u8>],
+    ) -> Result<Result<Vec<Vec<u8>>, RepoError>, RepoError> {
+        let mut resps: Vec<Option<Vec<u8>>> = vec![None; encoded.len()];
+        let mut attempts = 0;
+        let result = loop {
+            let reqs: Vec<(u32, &[u8])> = resps
+                .iter()
+                .zip(encoded.iter())
+                .enumerate()
+                .filter(|(_, (resp, _))| resp.is_none())
+                .map(|(i, (_, buf))| (i as u32, &buf[..]))
+                .collect();
+            let err = match self.exchange(|conn| self.send_batch_conn(conn, reqs, &mut resps)) {
+                Ok(refused) => break Ok(refused),
+                Err(err) => err,
+            };
+            attempts += 1;
+            let resumable = self.session.lock().unwrap().is_some();
+            if !resumable || attempts > RESUME_ATTEMPTS {
+                break Err(err);
+            }
+            match self.exchange(|conn| Ok(conn.resumed.take())) {
+                Ok(Some(answered)) => {
+                    for (id, resp) in answered {
+                        if let Some(slot @ None) = resps.get_mut(id as usize) {
+                            *slot = Some(resp);
+                        }
+                    }
+                    if resps.iter().all(|resp| resp.is_some()) {
+                        break Ok(None);
+                    }
+                }
+                _ => break Err(err),
+            }
+        };
+        if let Some(ref mut session) = *self.session.lock().unwrap() {
+            session.in_flight = false;
+        }
+        Ok(match result? {
+            Some(err) => Err(err),
+            None => Ok(resps.into_iter().flatten().collect()),
+        })
+    }
+
+    // Send the encoded requests `reqs` by id as a batch, filling in their
+    // responses, or return the server's reason for refusing it.
+    fn send_batch_conn(
+        &self,
+        conn: &mut Conn,
+        reqs: Vec<(u32, &[u8])>,
+        resps: &mut [Option<Vec<u8>>],
+    ) -> Result<Option<RepoError>, RepoError> {
+        let n = reqs.len();
+        let batch = Request::Batch(reqs);
+        if let Some(ref mut session) = *self.session.lock().unwrap() {
+            session.batches += 1;
+            session.in_flight = true;
+        }
+        protocol::write_frame(&mut conn.w, &batch.encode())?;
+        let header = read_response(conn)?;
+        let count = match protocol::open_response(&header, "") {
+            Ok(mut d) => {
+                let count = d.u32()? as usize;
+                d.finish()?;
+                count
+            }
+            Err(err) => return Ok(Some(err)),
+        };
+        if count != n {
+            return Err(RepoError::InvalidDataError);
+        }
+        for _ in 0..n {
+            let buf = read_response(conn)?;
+            let (id, resp) = protocol::untag_response(&buf)?;
+            match resps.get_mut(id as usize) {
+                Some(slot @ None) => *slot = Some(resp.to_vec()),
+                _ => return Err(RepoError::InvalidDataError),
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn read_response(conn: &mut Conn) -> Result<Vec<u8>, RepoError> {
+    match protocol::read_frame(&mut conn.r)? {
+        Some(resp) => Ok(resp),
+        None => Err(RepoError::IOError(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "remote closed the connection",
+        ))),
+    }
+}
+
+impl StorageEngine for RemoteStorage {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), RepoError> {
+        check_key(key)?;
+        self.call(&Request::Put { key, data }, |_| Ok(()))
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, RepoError> {
+        check_key(key)?;
+        self.call(&Request::Get { key }, |d| Ok(d.bytes()?.to_vec()))
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: usize) -> Result<Vec<u8>, RepoError> {
+        check_key(key)?;
+        if len > protocol::MAX_FRAME_SZ {
+            return Err(RepoError::InvalidRangeError);
+        }
+        let req = Request::GetRange {
+            key,
+            offset,
+            len: len as u32,
+        };
+        let buf = self.call(&req, |d| Ok(d.bytes()?.to_vec()))?;
+        if buf.len() != len {
+            return Err(RepoError::InvalidDataError);
+        }
+        Ok(buf)
+    }
+
+    fn size(&self, key: &str) -> Result<u64, RepoError> {
+        check_key(key)?;
+        self.call(&Request::Size { key }, |d| d.u64())
+    }
+
+    fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, RepoError> {
+        check_prefix(prefix)?;
+        let mut keys = self.call(&Request::List { prefix }, |d| {
+            let n = d.count(4)?;
+            let mut keys = Vec::with_capacity(n);
+            for _ in 0..n {
+                keys.push(d.str()?.to_string());
+            }
+            Ok(keys)
+        })?;
+        // Do not trust the server to filter or validate.
+        keys.retain(|k| k.starts_with(prefix) && check_key(k).is_ok());
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RepoError> {
+        check_key(key)?;
+        self.call(&Request::Delete { key }, |_| Ok(()))
+    }
+
+    fn exists_many(&self, keys: &[&str]) -> Result<Vec<bool>, RepoError> {
+        let mut reqs = Vec::with_capacity(keys.len());
+        for key in keys.iter() {
+            check_key(key)?;
+            reqs.push(Request::Exists { key });
+        }
+        let mut exists = Vec::with_capacity(keys.len());
+        for (req, r) in reqs.iter().zip(self.call_many(&reqs, |d| d.bool())?) {
+            exists.push(match r {
+                Ok(exists) => exists,
+                // Servers from before EXISTS.
+                Err(RepoError::UnsupportedOperationError) => self.exists(req.key())?,
+                Err(err) => return Err(err),
+            });
+        }
+        Ok(exists)
+    }
+
+    fn put_many(&self, objects: &[(&str, &[u8])]) -> Result<(), RepoError> {
+        let mut reqs = Vec::with_capacity(objects.len());
+        for (key, data) in objects.iter() {
+            check_key(key)?;
+            reqs.push(Request::Put { key, data });
+        }
+        for r in self.call_many(&reqs, |_| Ok(()))? {
+            r?;
+        }
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        // A broken connection can do nothing at all.
+        self.call(&Request::Capabilities, protocol::decode_capabilities)
+            .unwrap_or_default()
+    }
+
+    fn thaw(&self, key: &str) -> Result<ThawState, RepoError> {
+        check_key(key)?;
+        self.call(&Request::Thaw { key }, protocol::decode_thaw_state)
+    }
+
+    fn collect_garbage(&self, keep_list: &[u8]) -> Result<GcStats, RepoError> {
+        self.call(&Request::Gc { keep_list }, protocol::decode_gc_stats)
+    }
+
+    fn objects_present(&self, addresses: &[Address]) -> Result<Vec<bool>, RepoError> {
+        if !self.hello()?.has(protocol::FEATURE_PRESENCE) {
+            return Err(RepoError::UnsupportedOperationError);
+        }
+        let mut present = Vec::with_capacity(addresses.len());
+        for chunk in addresses.chunks(MAX_PRESENCE_QUERY) {
+            let req = Request::Present(chunk.to_vec());
+            present.extend(self.call(&req, |d| decode_bitmap(d.bytes()?, chunk.len()))?);
+        }
+        Ok(present)
+    }
+
+    fn consistency_proof(&self, old: u64, new: u64) -> Result<Vec<LogHash>, RepoError> {
+        self.call(
+            &Request::Consistency { old, new },
+            protocol::decode_consistency_proof,
+        )
+    }
+
+    fn quotas(&self) -> Result<Vec<Quota>, RepoError> {
+        self.call(&Request::Quota, protocol::decode_quotas)
+    }
+
+    fn check_status(&self) -> Result<CheckStatus, RepoError> {
+        if !self.hello()?.has(protocol::FEATURE_CHECKS) {
+            return Err(RepoError::UnsupportedOperationError);
+        }
+        self.call(&Request::CheckStatus, protocol::decode_check_status)
+    }
+}
+
+// Tests --------------------
+
+#[cfg(test)]
+fn test_remote(
+    storage: std::sync::Arc<dyn StorageEngine>,
+    opts: crate::serve::ServeOptions,
+) -> (RemoteStorage, std::thread::JoinHandle<()>) {
+    use std::os::unix::net::UnixStream;
+    let (client, server) = UnixStream::pair().unwrap();
+    let handle = std::thread::spawn(move || {
+        let mut r = BufReader::new(server.try_clone().unwrap());
+        let mut w = BufWriter::new(server);
+        crate::serve::serve(storage, &opts, &mut r, &mut w).unwrap();
+    });
+    let r = Box::new(BufReader::new(client.try_clone().unwrap()));
+    let w = Box::new(BufWriter::new(client));
+    (RemoteStorage::new(r, w), handle)
+}
+
+#[test]
+fn test_remote_storage() {
+    let storage = std::sync::Arc::new(super::mem::MemStorage::new());
+    let (s, handle) = test_remote(storage, Default::default());
+    super::test_storage_engine(&s);
+    assert!(s.capabilities().delete);
+
+    // More than fits in one batch.
+    let keys: Vec<String> = (0..BATCH_MAX_REQUESTS + 10)
+        .map(|i| format!("k/{}", i))
+        .collect();
+    let objects: Vec<(&str, &[u8])> = keys
+        .iter()
+        .step_by(2)
+        .map(|k| (&k[..], k.as_bytes()))
+        .collect();
+    s.put_many(&objects).unwrap();
+    let refs: Vec<&str> = keys.iter().map(|k| &k[..]).collect();
+    let exists = s.exists_many(&refs).unwrap();
+    assert_eq!(exists.len(), keys.len());
+    assert!(exists.iter().enumerate().all(|(i, e)| *e == (i % 2 == 0)));
+    assert_eq!(s.get("k/4096").unwrap(), b"k/4096");
+    match s.exists_many(&["k/0", "../x"]) {
+        Err(RepoError::InvalidKeyError) => (),
+        _ => panic!("expected invalid key"),
+    }
+    drop(s);
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_remote_repo() {
+    let key = asymcrypt::Key::new();
+    let storage = std::sync::Arc::new(super::mem::MemStorage::new());
+    let opts = crate::serve::ServeOptions {
+        append_only: true,
+        ..Default::default()
+    };
+    let (s, handle) = test_remote(storage, opts);
+    let s = std::sync::Arc::new(s);
+    let r = crate::Repo::init(s.clone(), Default::default(), &key).unwrap();
+    let mut m = r.manifest().unwrap();
+    m.add_head(crate::manifest::SnapshotHead {
+        address: Default::default(),
+        timestamp: 1,
+        namespace: crate::namespace::Namespace::new("laptop").unwrap(),
+        retain_until: 0,
+        amendment: None,
+    });
+    r.commit_manifest(&m, &key).unwrap();
+    assert_eq!(r.manifest().unwrap().heads, m.heads);
+    assert!(!s.capabilities().delete);
+    match s.delete(crate::CONFIG_FILE) {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected delete to be refused"),
+    }
+    match s.collect_garbage(b"") {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected gc to be refused"),
+    }
+    drop(r);
+    drop(s);
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_remote_gc() {
+    let key = asymcrypt::Key::new();
+    let storage = std::sync::Arc::new(super::mem::MemStorage::new());
+    let (s, handle) = test_remote(storage, Default::default());
+    let r = crate::Repo::init(std::sync::Arc::new(s), Default::default(), &key).unwrap();
+    let chunk = |b: u8| crate::address::Address { bytes: [b; 32] };
+    let head = crate::gc::test_commit_tree(&r, &key, 1, &[chunk(1)]);
+    crate::gc::test_commit_tree(&r, &key, 2, &[chunk(2)]);
+    let mut m = r.manifest().unwrap();
+    m.remove_head(&head.address);
+    r.commit_manifest(&m, &key).unwrap();
+    crate::gc::test_age_indexes(&r);
+
+    let opts = crate::gc::GcOptions {
+        dry_run: true,
+        ..Default::default()
+    };
+    let planned = r.gc_remote(&key.box_sk, &key, &opts).unwrap();
+    assert_eq!(planned.deleted_packs.len(), 1);
+    assert_eq!(r.list_packs().unwrap().len(), 2);
+    let stats = r.gc_remote(&key.box_sk, &key, &Default::default()).unwrap();
+    assert_eq!(stats, planned);
+    assert_eq!(r.list_packs().unwrap().len(), 1);
+    // Only servers sweep.
+    match crate::storage::mem::MemStorage::new().collect_garbage(b"") {
+        Err(RepoError::UnsupportedOperationError) => (),
+        _ => panic!("expected gc to be unsupported"),
+    }
+    drop(r);
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_remote_present() {
+    let key = asymcrypt::Key::new();
+    let storage = std::sync::Arc::new(super::mem::MemStorage::new());
+    let (s, handle) = test_remote(storage, Default::default());
+    let r = crate::Repo::init(std::sync::Arc::new(s), Default::default(), &key).unwrap();
+    let chunk = Address { bytes: [7; 32] };
+    crate::gc::test_commit_tree(&r, &key, 1, &[chunk]);
+    // More than one query's worth.
+    let mut addresses = vec![Address { bytes: [8; 32] }; MAX_PRESENCE_QUERY];
+    addresses.push(chunk);
+    let present = r.storage().objects_present(&addresses).unwrap();
+    assert_eq!(present.len(), addresses.len());
+    assert_eq!(present.iter().filter(|p| **p).count(), 1);
+    assert!(present[MAX_PRESENCE_QUERY]);
+    drop(r);
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_remote_translog() {
+    let key = asymcrypt::Key::new();
+    let storage = std::sync::Arc::new(super::mem::MemStorage::new());
+    let (s, handle) = test_remote(storage, Default::default());
+    let mut r = crate::Repo::init(std::sync::Arc::new(s), Default::default(), &key).unwrap();
+    let dir = super::local::test_dir("remote-translog");
+    std::fs::create_dir_all(&dir).unwrap();
+    r.track_manifests(&dir.join("seen")).unwrap();
+    for i in 1..5 {
+        crate::gc::test_commit_tree(&r, &key, i, &[Address { bytes: [i; 32] }]);
+    }
+    // Proofs come from the server and check out.
+    let proof = r.storage().consistency_proof(1, 4).unwrap();
+    assert_eq!(proof, r.local_translog_proof(1, 4).unwrap());
+    let m = r.manifest().unwrap();
+    assert_eq!(m.log_size, 4);
+    assert!(r.storage().consistency_proof(2, 9).is_err());
+
+    // The server refuses a log that drops or rewrites what it held.
+    let mut log = r.translog().unwrap();
+    log.leaves.pop();
+    match r.storage().put(crate::TRANSLOG_FILE, &log.encode()) {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected the shorter log to be refused"),
+    }
+    drop(r);
+    handle.join().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_remote_rate_limited() {
+    use super::retry::{RetryPolicy, RetryStorage};
+    use crate::ratelimit::RateLimits;
+    let storage = std::sync::Arc::new(super::mem::MemStorage::new());
+    storage.put("config", b"x").unwrap();
+    let opts = crate::serve::ServeOptions {
+        client: Some("laptop".to_string()),
+        rate: RateLimits {
+            requests: Some(20),
+            bandwidth: None,
+        },
+        ..Default::default()
+    };
+    let (s, handle) = test_remote(storage, opts);
+    let s = std::sync::Arc::new(s);
+    let refused = (0..40)
+        .filter(|_| matches!(s.get("config"), Err(RepoError::RateLimitedError(_))))
+        .count();
+    assert!(refused >= 15, "{}", refused);
+    // Told how long to wait, the client backs off and is served.
+    let retry = RetryStorage::new(
+        s.clone(),
+        RetryPolicy {
+            max_attempts: 2,
+            ..Default::default()
+        },
+    );
+    for _ in 0..30 {
+        assert_eq!(retry.get("config").unwrap(), b"x");
+    }
+    drop(retry);
+    drop(s);
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_remote_broken() {
+    let server = Vec::new();
+    let s = RemoteStorage::new(Box::new(&b"\x00\x00"[..]), Box::new(server));
+    assert!(s.get("config").is_err());
+    match s.get("config") {
+        Err(RepoError::StorageError(_)) => (),
+        _ => panic!("expected broken connection"),
+    }
+    assert_eq!(s.capabilities(), Default::default());
+    assert_eq!(shell_quote("it's"), "'it'\\''s'");
+}
+
+#[test]
+fn test_remote_hello() {
+    use crate::protocol::{FEATURES, FEATURE_BATCH, LEGACY_FEATURES, PROTOCOL_VERSION};
+    let storage = std::sync::Arc::new(super::mem::MemStorage::new());
+    let (s, handle) = test_remote(storage, Default::default());
+    let hello = s.hello().unwrap();
+    assert_eq!(
+        (hello.version, hello.features),
+        (PROTOCOL_VERSION, FEATURES)
+    );
+    assert_eq!(s.check_status().unwrap(), Default::default());
+    drop(s);
+    handle.join().unwrap();
+
+    // A server from before HELLO refuses it, and sessions are not asked for.
+    let mut refused = Vec::new();
+    protocol::write_frame(
+        &mut refused,
+        &protocol::err_response(&RepoError::UnsupportedOperationError),
+    )
+    .unwrap();
+    let mut s = RemoteStorage::new(Box::new(io::Cursor::new(refused)), Box::new(Vec::new()));
+    let hello = s.hello().unwrap();
+    assert_eq!(hello, Hello::legacy());
+    assert_eq!(hello.features, LEGACY_FEATURES);
+    assert!(hello.has(FEATURE_BATCH));
+    match s.open_session() {
+        Err(RepoError::UnsupportedOperationError) => (),
+        _ => panic!("expected sessions to be unsupported"),
+    }
+    match s.check_status() {
+        Err(RepoError::UnsupportedOperationError) => (),
+        _ => panic!("expected checks to be unsupported"),
+    }
+}
+
+#[test]
+fn test_remote_respawn() {
+    // A server that exits at once, each request runs it again.
+    let log = super::local::test_dir("remote-respawn");
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg("echo started >> \"$LOG\"")
+        .env("LOG", &log);
+    let s = RemoteStorage::spawn(&mut cmd).unwrap();
+    for _ in 0..2 {
+        assert!(s.get("config").unwrap_err().is_transient());
+    }
+    drop(s);
+    let started = std::fs::read_to_string(&log).unwrap();
+    assert_eq!(started.lines().count(), 2);
+    std::fs::remove_file(&log).unwrap();
+}
+
+#[test]
+fn test_remote_unix() {
+    use crate::serve::{serve_unix, ServeOptions, ServeShared};
+    use std::os::unix::net::UnixListener;
+    let dir = super::local::test_dir("remote-unix");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("sock");
+    let listener = UnixListener::bind(&path).unwrap();
+    let storage = std::sync::Arc::new(super::mem::MemStorage::new());
+    let opts = ServeOptions {
+        append_only: true,
+        ..Default::default()
+    };
+    let serving = storage.clone();
+    std::thread::spawn(move || serve_unix(listener, serving, &opts, &ServeShared::new()));
+
+    // Each client has a session of its own, held to the server's rules.
+    let a = RemoteStorage::unix(&path).unwrap();
+    let b = RemoteStorage::unix(&path).unwrap();
+    a.put("packs/00", b"pack").unwrap();
+    assert_eq!(b.get("packs/00").unwrap(), b"pack");
+    match b.delete("packs/00") {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected delete to be refused"),
+    }
+    assert_eq!(storage.get("packs/00").unwrap(), b"pack");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_remote_resume() {
+    use crate::serve::{serve_unix, ServeOptions, ServeShared};
+    use crate::tenant::tenant_storage;
+    use std::os::unix::net::UnixListener;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    // Fails the next read once cut, as a dropped connection does.
+    struct Cut(UnixStream, Arc<AtomicBool>);
+    impl Read for Cut {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.1.swap(false, Ordering::SeqCst) {
+                return Err(io::ErrorKind::ConnectionReset.into());
+            }
+            self.0.read(buf)
+        }
+    }
+
+    let dir = super::local::test_dir("remote-resume");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("sock");
+    let listener = UnixListener::bind(&path).unwrap();
+    let root: Arc<dyn StorageEngine> = Arc::new(super::mem::MemStorage::new());
+    let storage = tenant_storage(&root, "design").unwrap();
+    crate::Repo::init(storage.clone(), Default::default(), &asymcrypt::Key::new()).unwrap();
+    let opts = ServeOptions {
+        append_only: true,
+        tenants: true,
+        ..Default::default()
+    };
+    let shared = Arc::new(ServeShared::new());
+    let (serving, sessions) = (root.clone(), shared.clone());
+    std::thread::spawn(move || serve_unix(listener, serving, &opts, &sessions));
+
+    let cut = Arc::new(AtomicBool::new(false));
+    let cutter = cut.clone();
+    let mut s = RemoteStorage::connect(move || {
+        let conn = UnixStream::connect(&path)?;
+        Ok((
+            Box::new(BufReader::new(Cut(conn.try_clone()?, cutter.clone()))),
+            Box::new(BufWriter::new(conn)),
+        ))
+    })
+    .unwrap();
+    s.select_tenant("design").unwrap();
+    s.open_session().unwrap();
+
+    // The batch is cut off, resumed, and finished without putting anything
+    // twice, which the append only server would refuse.
+    let keys: Vec<String> = (0..100).map(|i| format!("packs/{:02}", i)).collect();
+    let objects: Vec<(&str, &[u8])> = keys.iter().map(|k| (&k[..], k.as_bytes())).collect();
+    cut.store(true, Ordering::SeqCst);
+    s.put_many(&objects).unwrap();
+    for k in keys.iter() {
+        assert_eq!(storage.get(k).unwrap(), k.as_bytes());
+    }
+    let metrics = shared.metrics.render(&root, true).unwrap();
+    for line in [
+        "packnback_requests_total{op=\"tenant\",capability=\"none\"} 1",
+        "packnback_requests_total{op=\"resume\",capability=\"none\"} 1",
+        "packnback_requests_total{op=\"put\",capability=\"put-only\"} 100",
+        "packnback_request_errors_total{op=\"put\",capability=\"put-only\"} 0",
+    ]
+    .iter()
+    {
+        assert!(metrics.lines().any(|l| l == *line), "{}\n{}", line, metrics);
+    }
+
+    // The next connection resumes the session too.
+    cut.store(true, Ordering::SeqCst);
+    assert!(s.get("packs/00").is_err());
+    assert_eq!(s.get("packs/00").unwrap(), b"packs/00");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_remote_authenticate() {
+    use crate::acl::CAP_ADMIN;
+    use std::collections::BTreeMap;
+    let key = asymcrypt::Key::new();
+    let maintenance = asymcrypt::Key::new();
+    let (laptop_pk, laptop_sk) = tweetnacl::boxed_crypto_sign_keypair();
+    let config = crate::config::RepoConfig {
+        maintenance_pk: Some(maintenance.sign_pk.clone()),
+        ..Default::default()
+    };
+    let storage = std::sync::Arc::new(super::mem::MemStorage::new());
+    let r = crate::Repo::init(storage.clone(), config, &key).unwrap();
+    let mut clients = BTreeMap::new();
+    clients.insert("laptop".to_string(), CAP_ADMIN);
+    let mut keys = BTreeMap::new();
+    keys.insert("laptop".to_string(), (*laptop_pk).clone());
+    r.set_acl(clients, keys, &maintenance).unwrap();
+    let opts = crate::serve::ServeOptions {
+        authenticate: true,
+        ..Default::default()
+    };
+    let (mut s, handle) = test_remote(storage, opts);
+    match s.get(crate::CONFIG_FILE) {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected a request before authenticating to be refused"),
+    }
+    match s.authenticate("laptop", &key.sign_sk) {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected the wrong key to be refused"),
+    }
+    assert!(s.authenticate("stranger", &laptop_sk).is_err());
+    // An answer without a challenge.
+    let signed = signed::sign(
+        &protocol::auth_message(&[0; CHALLENGE_SZ], "laptop"),
+        &laptop_sk,
+    );
+    let replay = Request::Auth {
+        client: "laptop",
+        signed: &signed,
+    };
+    match s.call(&replay, |_| Ok(())) {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected an unchallenged answer to be refused"),
+    }
+    s.authenticate("laptop", &laptop_sk).unwrap();
+    assert!(s.get(crate::CONFIG_FILE).is_ok());
+    drop(s);
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_remote_tenants() {
+    use crate::acl::CAP_ADMIN;
+    use crate::tenant::{list_tenants, tenant_storage};
+    use std::collections::BTreeMap;
+    let root: std::sync::Arc<dyn StorageEngine> =
+        std::sync::Arc::new(super::mem::MemStorage::new());
+    let maintenance = asymcrypt::Key::new();
+    // Each tenant gives its own key to a client named laptop.
+    let mut laptop_sks = Vec::new();
+    for name in ["design", "accounts"].iter() {
+        let config = crate::config::RepoConfig {
+            maintenance_pk: Some(maintenance.sign_pk.clone()),
+            ..Default::default()
+        };
+        let storage = tenant_storage(&root, name).unwrap();
+        let r = crate::Repo::init(storage, config, &asymcrypt::Key::new()).unwrap();
+        let (laptop_pk, laptop_sk) = tweetnacl::boxed_crypto_sign_keypair();
+        let mut clients = BTreeMap::new();
+        clients.insert("laptop".to_string(), CAP_ADMIN);
+        let mut keys = BTreeMap::new();
+        keys.insert("laptop".to_string(), (*laptop_pk).clone());
+        r.set_acl(clients, keys, &maintenance).unwrap();
+        laptop_sks.push(laptop_sk);
+    }
+    assert_eq!(list_tenants(&*root).unwrap(), vec!["accounts", "design"]);
+    let opts = crate::serve::ServeOptions {
+        authenticate: true,
+        tenants: true,
+        ..Default::default()
+    };
+
+    let (mut s, handle) = test_remote(root.clone(), opts.clone());
+    match s.authenticate("laptop", &laptop_sks[0]) {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected authenticating before selecting a tenant to be refused"),
+    }
+    assert!(s.select_tenant("marketing").unwrap_err().is_not_found());
+    assert!(s.select_tenant("../design").is_err());
+    s.select_tenant("design").unwrap();
+    assert!(s.select_tenant("accounts").is_err());
+    // The other tenant's key for the same client name is no good.
+    assert!(s.authenticate("laptop", &laptop_sks[1]).is_err());
+    s.authenticate("laptop", &laptop_sks[0]).unwrap();
+    s.put("packs/00", b"pack").unwrap();
+    assert_eq!(s.list_prefix("packs/").unwrap(), vec!["packs/00"]);
+    drop(s);
+    handle.join().unwrap();
+    assert_eq!(root.get("tenants/design/packs/00").unwrap(), b"pack");
+
+    let (mut s, handle) = test_remote(root.clone(), opts);
+    s.select_tenant("accounts").unwrap();
+    s.authenticate("laptop", &laptop_sks[1]).unwrap();
+    assert!(s.get("packs/00").unwrap_err().is_not_found());
+    drop(s);
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_remote_quotas() {
+    use crate::namespace::Namespace;
+    use crate::usage::QuotaScope;
+    let key = asymcrypt::Key::new();
+    let ak = crate::address::AddressKey::new();
+    let ns = Namespace::new("laptop").unwrap();
+    let storage = std::sync::Arc::new(super::mem::MemStorage::new());
+    let opts = crate::serve::ServeOptions {
+        client: Some("laptop".to_string()),
+        quota: Some(1 << 20),
+        ..Default::default()
+    };
+    let (s, handle) = test_remote(storage, opts);
+    let r = crate::Repo::init(std::sync::Arc::new(s), Default::default(), &key).unwrap();
+    let data = vec![1u8; 1000];
+    let (_, stats) = r
+        .backup_stream(&mut &data[..], "dump", &ns, &ak, &key, &Default::default())
+        .unwrap();
+    assert_eq!(stats.quotas.len(), 1);
+    let q = stats.quotas[0];
+    assert_eq!((q.scope, q.quota), (QuotaScope::Client, 1 << 20));
+    assert!(q.stored_bytes > 1000 && q.headroom() < (1 << 20) - 1000);
+    let mut report = Vec::new();
+    stats.write_report(&mut report).unwrap();
+    let report = String::from_utf8(report).unwrap();
+    assert!(
+        report.lines().last().unwrap().starts_with("quota   "),
+        "{}",
+        report
+    );
+    assert_eq!(r.quotas().unwrap(), stats.quotas);
+
+    let mut data = vec![0; 2_000_000];
+    tweetnacl::random_bytes(&mut data);
+    match r.backup_stream(&mut &data[..], "big", &ns, &ak, &key, &Default::default()) {
+        Err(RepoError::QuotaExceededError(q)) => assert_eq!(q.scope, QuotaScope::Client),
+        Err(err) => panic!("expected the quota to be exceeded, got {}", err),
+        Ok(_) => panic!("expected the quota to be exceeded"),
+    }
+    drop(r);
+    handle.join().unwrap();
+}
@@ -0,0 +1,311 @@
+//! Retrying transient storage failures.
+//!
+//! Wraps another engine and repeats operations that failed with a
+//! transient error, see `RepoError::is_transient`, so a dropped connection
+//! or a timeout part way through a long backup costs a few seconds instead
+//! of the backup. Between attempts it waits with exponential backoff, each
+//! wait chosen at random between half and all of the current delay so
+//! clients that failed together do not retry together.
+//!
+//! Every operation is safe to repeat, but a failed attempt may still have
+//! taken effect. A repeated delete or rename that finds its work already
+//! done succeeds, and a repeated put refused by an append only server
+//! succeeds if the value it finds is the one being put.
+//!
+//! A server limiting the client's requests, see `ratelimit`, says how long
+//! to wait. The wrapper waits that long instead, and the refused attempt
+//! is not counted, it did nothing.
+//!
+//! The HTTP backends also retry failed requests themselves. This wrapper
+//! is mostly for the others, in particular the remote protocol over ssh,
+//! which reconnects on the next request when spawned with a command.
+
+use super::{Capabilities, StorageEngine, ThawState};
+use crate::address::Address;
+use crate::checks::CheckStatus;
+use crate::gc::GcStats;
+use crate::translog::LogHash;
+use crate::usage::Quota;
+use crate::RepoError;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    // Including the first, 1 never retries.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 8,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // The wait after failed attempt `attempt`, counting from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let max = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        let mut r = [0u8; 4];
+        tweetnacl::random_bytes(&mut r);
+        let frac = u32::from_le_bytes(r) as f64 / u32::MAX as f64;
+        max.mul_f64(0.5 + frac / 2.0)
+    }
+}
+
+pub struct RetryStorage {
+    inner: Arc<dyn StorageEngine>,
+    policy: RetryPolicy,
+}
+
+impl RetryStorage {
+    pub fn new(inner: Arc<dyn StorageEngine>, policy: RetryPolicy) -> RetryStorage {
+        RetryStorage { inner, policy }
+    }
+
+    // How long to wait after failed attempt `attempt` failed with `err`.
+    fn wait(&self, err: &RepoError, attempt: u32) -> Duration {
+        err.retry_after()
+            .unwrap_or_else(|| self.policy.delay(attempt))
+    }
+
+    // Run `f` until it succeeds, fails permanently or runs out of
+    // attempts. `f` is told whether an earlier attempt failed.
+    fn retry<T, F>(&self, f: F) -> Result<T, RepoError>
+    where
+        F: Fn(&dyn StorageEngine, bool) -> Result<T, RepoError>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f(&*self.inner, attempt > 1) {
+                Err(ref e) if e.is_transient() && attempt < self.policy.max_attempts => {
+                    thread::sleep(self.wait(e, attempt));
+                    if e.retry_after().is_none() {
+                        attempt += 1;
+                    }
+                }
+                r => return r,
+            }
+        }
+    }
+}
+
+impl StorageEngine for RetryStorage {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), RepoError> {
+        self.retry(|s, retried| match s.put(key, data) {
+            Err(RepoError::PermissionDeniedError) if retried => match s.get(key) {
+                Ok(ref stored) if stored == data => Ok(()),
+                _ => Err(RepoError::PermissionDeniedError),
+            },
+            r => r,
+        })
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, RepoError> {
+        self.retry(|s, _| s.get(key))
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: usize) -> Result<Vec<u8>, RepoError> {
+        self.retry(|s, _| s.get_range(key, offset, len))
+    }
+
+    fn size(&self, key: &str) -> Result<u64, RepoError> {
+        self.retry(|s, _| s.size(key))
+    }
+
+    fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, RepoError> {
+        self.retry(|s, _| s.list_prefix(prefix))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RepoError> {
+        self.retry(|s, retried| match s.delete(key) {
+            Err(ref e) if retried && e.is_not_found() => Ok(()),
+            r => r,
+        })
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), RepoError> {
+        self.retry(|s, retried| match s.rename(from, to) {
+            Err(ref e) if retried && e.is_not_found() && s.exists(to)? => Ok(()),
+            r => r,
+        })
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn thaw(&self, key: &str) -> Result<ThawState, RepoError> {
+        self.retry(|s, _| s.thaw(key))
+    }
+
+    // A failed batch is retried one request at a time, a batch can fail
+    // part way through and repeating all of it may never succeed.
+    fn exists_many(&self, keys: &[&str]) -> Result<Vec<bool>, RepoError> {
+        match self.inner.exists_many(keys) {
+            Err(ref e) if e.is_transient() && self.policy.max_attempts > 1 => {
+                thread::sleep(self.wait(e, 1));
+                keys.iter().map(|k| self.exists(k)).collect()
+            }
+            r => r,
+        }
+    }
+
+    // Puts are retried with the checks above.
+    fn put_many(&self, objects: &[(&str, &[u8])]) -> Result<(), RepoError> {
+        match self.inner.put_many(objects) {
+            Err(ref e) if e.is_transient() && self.policy.max_attempts > 1 => {
+                thread::sleep(self.wait(e, 1));
+                for (key, data) in objects.iter() {
+                    self.put(key, data)?;
+                }
+                Ok(())
+            }
+            r => r,
+        }
+    }
+
+    // Sweeping again with the same list deletes nothing more.
+    fn collect_garbage(&self, keep_list: &[u8]) -> Result<GcStats, RepoError> {
+        self.retry(|s, _| s.collect_garbage(keep_list))
+    }
+
+    fn objects_present(&self, addresses: &[Address]) -> Result<Vec<bool>, RepoError> {
+        self.retry(|s, _| s.objects_present(addresses))
+    }
+
+    fn consistency_proof(&self, old: u64, new: u64) -> Result<Vec<LogHash>, RepoError> {
+        self.retry(|s, _| s.consistency_proof(old, new))
+    }
+
+    fn quotas(&self) -> Result<Vec<Quota>, RepoError> {
+        self.retry(|s, _| s.quotas())
+    }
+
+    fn check_status(&self) -> Result<CheckStatus, RepoError> {
+        self.retry(|s, _| s.check_status())
+    }
+}
+
+// Tests --------------------
+
+// Fails every other call with a dropped connection, after doing the work
+// for puts and deletes as if only the response had been lost.
+#[cfg(test)]
+struct TestFlakyStorage {
+    inner: super::mem::MemStorage,
+    calls: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl TestFlakyStorage {
+    fn flake(&self) -> Result<(), RepoError> {
+        let n = self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        if n.is_multiple_of(2) {
+            return Err(RepoError::IOError(std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "connection reset",
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl StorageEngine for TestFlakyStorage {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), RepoError> {
+        if self.inner.exists(key)? && key.starts_with("immutable/") {
+            return Err(RepoError::PermissionDeniedError);
+        }
+        self.inner.put(key, data)?;
+        self.flake()
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, RepoError> {
+        self.flake()?;
+        self.inner.get(key)
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: usize) -> Result<Vec<u8>, RepoError> {
+        self.flake()?;
+        self.inner.get_range(key, offset, len)
+    }
+
+    fn size(&self, key: &str) -> Result<u64, RepoError> {
+        self.flake()?;
+        self.inner.size(key)
+    }
+
+    fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, RepoError> {
+        self.flake()?;
+        self.inner.list_prefix(prefix)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RepoError> {
+        self.inner.delete(key)?;
+        self.flake()
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), RepoError> {
+        self.inner.rename(from, to)?;
+        self.flake()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+}
+
+#[test]
+fn test_retry_storage() {
+    use std::sync::atomic::Ordering;
+    let flaky = Arc::new(TestFlakyStorage {
+        inner: super::mem::MemStorage::new(),
+        calls: Default::default(),
+    });
+    let policy = RetryPolicy {
+        base_delay: Duration::from_millis(1),
+        ..Default::default()
+    };
+    let s = RetryStorage::new(flaky.clone(), policy.clone());
+    super::test_storage_engine(&s);
+    // Every other put is only refused when repeated.
+    for key in ["immutable/a", "immutable/b"].iter() {
+        s.put(key, b"x").unwrap();
+    }
+    match s.put("immutable/a", b"x") {
+        Err(RepoError::PermissionDeniedError) => (),
+        _ => panic!("expected an overwrite to be refused"),
+    }
+    s.put("c", b"!").unwrap();
+    s.rename("c", "d").unwrap();
+    assert_eq!(s.get("d").unwrap(), b"!");
+
+    // Permanent errors are not retried.
+    let calls = flaky.calls.load(Ordering::SeqCst);
+    assert!(s.delete("missing").unwrap_err().is_not_found());
+    assert_eq!(flaky.calls.load(Ordering::SeqCst), calls);
+
+    let once = RetryStorage::new(
+        flaky.clone(),
+        RetryPolicy {
+            max_attempts: 1,
+            ..policy
+        },
+    );
+    assert!((0..2).any(|_| once.get("d").is_err()));
+
+    let p: RetryPolicy = Default::default();
+    assert!(p.delay(1) >= p.base_delay / 2 && p.delay(1) <= p.base_delay);
+    assert!(p.delay(40) <= p.max_delay && p.delay(40) >= p.max_delay / 2);
+}
@@ -0,0 +1,442 @@
+//! SFTP storage, enabled with the `sftp` feature.
+//!
+//! Lets any machine reachable over SSH with some spare disk hold a
+//! repository. Keys map to paths below `root` exactly as in `local`,
+//! including the `^x` escaping of uppercase letters.
+//!
+//! A single SSH session is opened lazily and reused for every request. If a
+//! request fails and the session no longer answers keepalives it is
+//! replaced and the request retried, errors reported by a live server (a
+//! missing file, a permission problem) are returned immediately.
+//!
+//! Values are written to a '.' prefixed temporary file and renamed into
+//! place. Servers speaking SFTP version 3, OpenSSH included, refuse to
+//! rename over an existing file, so replacing a value removes the old one
+//! first and a reader may briefly see no value, but never a partial one.
+//!
+//! libssh2 keeps many write requests in flight for a single large write
+//! instead of waiting for each acknowledgement, so data is handed over in
+//! `write_window` sized blocks to keep the link busy with bounded memory.
+
+use super::{
+    check_key, check_prefix, escape_key, not_found, unescape_key, Capabilities, StorageEngine,
+};
+use crate::RepoError;
+use ssh2::{CheckResult, ErrorCode, KnownHostFileKind, OpenFlags, OpenType, Session, Sftp};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use tweetnacl::random_bytes;
+
+// SSH_FX_NO_SUCH_FILE and SSH_FX_NO_SUCH_PATH.
+const FX_NO_SUCH_FILE: i32 = 2;
+const FX_NO_SUCH_PATH: i32 = 10;
+
+#[derive(Clone)]
+pub enum SftpAuth {
+    // Identities offered by a running ssh-agent.
+    Agent,
+    KeyFile {
+        private_key: PathBuf,
+        passphrase: Option<String>,
+    },
+    Password(String),
+}
+
+#[derive(Clone)]
+pub struct SftpConfig {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    // Repository directory on the server, relative paths start at the
+    // login directory.
+    pub root: String,
+    pub auth: SftpAuth,
+    // The server host key must already be listed here, connections to
+    // unknown or changed hosts are refused.
+    pub known_hosts: PathBuf,
+    pub write_window: usize,
+    // Ask the server to flush written files with the fsync@openssh.com
+    // extension, skipped on servers without it.
+    pub fsync: bool,
+    pub max_attempts: u32,
+    pub timeout: Duration,
+}
+
+impl SftpConfig {
+    pub fn new(host: &str, user: &str, root: &str) -> SftpConfig {
+        let home = std::env::var_os("HOME").unwrap_or_default();
+        SftpConfig {
+            host: host.to_string(),
+            port: 22,
+            user: user.to_string(),
+            root: root.to_string(),
+            auth: SftpAuth::Agent,
+            known_hosts: Path::new(&home).join(".ssh").join("known_hosts"),
+            write_window: 1024 * 1024,
+            fsync: true,
+            max_attempts: 3,
+            timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+struct Conn {
+    session: Session,
+    sftp: Sftp,
+}
+
+pub struct SftpStorage {
+    cfg: SftpConfig,
+    root: String,
+    conn: Mutex<Option<Conn>>,
+}
+
+fn ssh_err(err: ssh2::Error) -> RepoError {
+    RepoError::StorageError(format!("sftp: {}", err))
+}
+
+fn is_not_found(err: &ssh2::Error) -> bool {
+    matches!(
+        err.code(),
+        ErrorCode::SFTP(FX_NO_SUCH_FILE) | ErrorCode::SFTP(FX_NO_SUCH_PATH)
+    )
+}
+
+fn map_not_found(err: ssh2::Error, key: &str) -> RepoError {
+    if is_not_found(&err) {
+        not_found(key)
+    } else {
+        ssh_err(err)
+    }
+}
+
+fn join(dir: &str, name: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), name)
+}
+
+impl SftpStorage {
+    // Does not connect, the session is opened by the first request.
+    pub fn new(cfg: SftpConfig) -> Result<SftpStorage, RepoError> {
+        if cfg.host.is_empty() || cfg.user.is_empty() || cfg.write_window == 0 {
+            return Err(RepoError::StorageError(
+                "sftp needs a host, a user and a non zero write window".to_string(),
+            ));
+        }
+        let root = if cfg.root.is_empty() {
+            ".".to_string()
+        } else {
+            cfg.root.clone()
+        };
+        Ok(SftpStorage {
+            cfg,
+            root,
+            conn: Mutex::new(None),
+        })
+    }
+
+    fn path(&self, key: &str) -> Result<String, RepoError> {
+        check_key(key)?;
+        Ok(join(&self.root, &escape_key(key)))
+    }
+
+    fn connect(&self) -> Result<Conn, RepoError> {
+        let tcp = TcpStream::connect((self.cfg.host.as_str(), self.cfg.port))?;
+        let mut session = Session::new().map_err(ssh_err)?;
+        session.set_tcp_stream(tcp);
+        session.set_timeout(self.cfg.timeout.as_millis() as u32);
+        session.handshake().map_err(ssh_err)?;
+        self.check_host_key(&session)?;
+
+        let user = &self.cfg.user;
+        let auth = match self.cfg.auth {
+            SftpAuth::Agent => session.userauth_agent(user),
+            SftpAuth::KeyFile {
+                ref private_key,
+                ref passphrase,
+            } => session.userauth_pubkey_file(user, None, private_key, passphrase.as_deref()),
+            SftpAuth::Password(ref password) => session.userauth_password(user, password),
+        };
+        if auth.is_err() || !session.authenticated() {
+            return Err(RepoError::StorageError(format!(
+                "sftp authentication failed for {}@{}",
+                user, self.cfg.host
+            )));
+        }
+        let sftp = session.sftp().map_err(ssh_err)?;
+        Ok(Conn { session, sftp })
+    }
+
+    fn check_host_key(&self, session: &Session) -> Result<(), RepoError> {
+        let key = match session.host_key() {
+            Some((key, _)) => key,
+            None => {
+                return Err(RepoError::StorageError(
+                    "sftp server sent no host key".to_string(),
+                ))
+            }
+        };
+        let mut known_hosts = session.known_hosts().map_err(ssh_err)?;
+        known_hosts
+            .read_file(&self.cfg.known_hosts, KnownHostFileKind::OpenSSH)
+            .map_err(ssh_err)?;
+        let msg = match known_hosts.check_port(&self.cfg.host, self.cfg.port, key) {
+            CheckResult::Match => return Ok(()),
+            CheckResult::NotFound => "is not in",
+            CheckResult::Mismatch => "does not match the one in",
+            CheckResult::Failure => "could not be checked against",
+        };
+        Err(RepoError::StorageError(format!(
+            "sftp host key for {} {} {}",
+            self.cfg.host,
+            msg,
+            self.cfg.known_hosts.display()
+        )))
+    }
+
+    fn with_sftp<T, F>(&self, f: F) -> Result<T, RepoError>
+    where
+        F: Fn(&Sftp) -> Result<T, RepoError>,
+    {
+        let mut conn = self.conn.lock().unwrap();
+        let mut delay = Duration::from_millis(200);
+        let mut attempt = 1;
+        loop {
+            if conn.is_none() {
+                *conn = Some(self.connect()?);
+            }
+            let c = conn.as_ref().unwrap();
+            let err = match f(&c.sftp) {
+                Ok(v) => return Ok(v),
+                Err(err) => err,
+            };
+            if c.session.keepalive_send().is_ok() || attempt >= self.cfg.max_attempts {
+                return Err(err);
+            }
+            *conn = None;
+            thread::sleep(delay);
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+
+    // Create the root and every directory leading to `key`.
+    fn create_dirs(&self, sftp: &Sftp, key: &str) -> Result<(), RepoError> {
+        let mut dir = self.root.clone();
+        let mut dirs = vec![dir.clone()];
+        let parts: Vec<&str> = key.split('/').collect();
+        for p in parts[..parts.len() - 1].iter() {
+            dir = join(&dir, &escape_key(p));
+            dirs.push(dir.clone());
+        }
+        for d in dirs.iter() {
+            if let Err(err) = sftp.mkdir(Path::new(d), 0o755) {
+                match sftp.stat(Path::new(d)) {
+                    Ok(st) if st.is_dir() => (),
+                    _ => return Err(ssh_err(err)),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_file(&self, sftp: &Sftp, key: &str, path: &str, data: &[u8]) -> Result<(), RepoError> {
+        let open = || {
+            sftp.open_mode(
+                Path::new(path),
+                OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                0o644,
+                OpenType::File,
+            )
+        };
+        let mut f = match open() {
+            Ok(f) => f,
+            Err(ref err) if is_not_found(err) => {
+                self.create_dirs(sftp, key)?;
+                open().map_err(ssh_err)?
+            }
+            Err(err) => return Err(ssh_err(err)),
+        };
+        for block in data.chunks(self.cfg.write_window) {
+            f.write_all(block)?;
+        }
+        if self.cfg.fsync {
+            let _ = f.fsync();
+        }
+        f.close().map_err(ssh_err)
+    }
+
+    fn replace(&self, sftp: &Sftp, from: &str, to: &str) -> Result<(), RepoError> {
+        let (from, to) = (Path::new(from), Path::new(to));
+        match sftp.rename(from, to, None) {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                if sftp.stat(to).is_err() {
+                    return Err(ssh_err(err));
+                }
+                sftp.unlink(to).map_err(ssh_err)?;
+                sftp.rename(from, to, None).map_err(ssh_err)
+            }
+        }
+    }
+
+    fn list_dir(
+        &self,
+        sftp: &Sftp,
+        dir: &str,
+        rel: &str,
+        out: &mut Vec<String>,
+    ) -> Result<(), RepoError> {
+        let entries = match sftp.readdir(Path::new(dir)) {
+            Ok(entries) => entries,
+            Err(ref err) if is_not_found(err) => return Ok(()),
+            Err(err) => return Err(ssh_err(err)),
+        };
+        for (path, st) in entries {
+            let name = match path.file_name().and_then(|n| n.to_str()) {
+                Some(name) if !name.starts_with('.') => name.to_string(),
+                _ => continue,
+            };
+            let child = if rel.is_empty() {
+                name.clone()
+            } else {
+                format!("{}/{}", rel, name)
+            };
+            if st.is_dir() {
+                self.list_dir(sftp, &join(dir, &name), &child, out)?;
+            } else {
+                out.push(unescape_key(&child));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageEngine for SftpStorage {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), RepoError> {
+        let path = self.path(key)?;
+        let (dir, name) = path.split_at(path.rfind('/').unwrap() + 1);
+        let mut rand = [0; 8];
+        random_bytes(&mut rand);
+        let tmp_path = format!("{}.{}.{}.tmp", dir, name, crate::address::to_hex(&rand));
+
+        self.with_sftp(|sftp| {
+            let result = self
+                .write_file(sftp, key, &tmp_path, data)
+                .and_then(|_| self.replace(sftp, &tmp_path, &path));
+            if result.is_err() {
+                let _ = sftp.unlink(Path::new(&tmp_path));
+            }
+            result
+        })
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, RepoError> {
+        let path = self.path(key)?;
+        self.with_sftp(|sftp| {
+            let mut f = sftp
+                .open(Path::new(&path))
+                .map_err(|e| map_not_found(e, key))?;
+            let size = f.stat().map_err(ssh_err)?.size.unwrap_or(0);
+            let mut buf = Vec::with_capacity(size as usize);
+            f.read_to_end(&mut buf)?;
+            Ok(buf)
+        })
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: usize) -> Result<Vec<u8>, RepoError> {
+        let path = self.path(key)?;
+        self.with_sftp(|sftp| {
+            let mut f = sftp
+                .open(Path::new(&path))
+                .map_err(|e| map_not_found(e, key))?;
+            let size = f.stat().map_err(ssh_err)?.size.unwrap_or(0);
+            if offset > size || size - offset < len as u64 {
+                return Err(RepoError::InvalidRangeError);
+            }
+            let mut buf = vec![0; len];
+            f.seek(SeekFrom::Start(offset))?;
+            f.read_exact(&mut buf)?;
+            Ok(buf)
+        })
+    }
+
+    fn size(&self, key: &str) -> Result<u64, RepoError> {
+        let path = self.path(key)?;
+        self.with_sftp(|sftp| {
+            let st = sftp
+                .stat(Path::new(&path))
+                .map_err(|e| map_not_found(e, key))?;
+            match st.size {
+                Some(size) => Ok(size),
+                None => Err(RepoError::StorageError(
+                    "sftp server did not report a size".to_string(),
+                )),
+            }
+        })
+    }
+
+    fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, RepoError> {
+        check_prefix(prefix)?;
+        // Only walk the deepest directory that can contain matches.
+        let (dir, rel) = match prefix.rfind('/') {
+            Some(i) => (
+                join(&self.root, &escape_key(&prefix[..i])),
+                escape_key(&prefix[..i]),
+            ),
+            None => (self.root.clone(), String::new()),
+        };
+        let mut keys = self.with_sftp(|sftp| {
+            let mut keys = Vec::new();
+            self.list_dir(sftp, &dir, &rel, &mut keys)?;
+            Ok(keys)
+        })?;
+        keys.retain(|k| k.starts_with(prefix));
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RepoError> {
+        let path = self.path(key)?;
+        self.with_sftp(|sftp| {
+            sftp.unlink(Path::new(&path))
+                .map_err(|e| map_not_found(e, key))
+        })
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            atomic_rename: false,
+            range_reads: true,
+            delete: true,
+        }
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_sftp_paths() {
+    let s = SftpStorage::new(SftpConfig::new("backup.example", "me", "/srv/pnb/")).unwrap();
+    assert_eq!(s.path("packs/Ab01").unwrap(), "/srv/pnb/packs/^ab01");
+    assert!(s.path("../escape").is_err());
+
+    let s = SftpStorage::new(SftpConfig::new("backup.example", "me", "")).unwrap();
+    assert_eq!(s.path("config").unwrap(), "./config");
+
+    assert!(SftpStorage::new(SftpConfig::new("", "me", "repo")).is_err());
+}
+
+#[test]
+fn test_sftp_connect_refused() {
+    // Nothing listens on port 1, connecting must fail cleanly and every
+    // request must report it rather than panic.
+    let mut cfg = SftpConfig::new("127.0.0.1", "me", "repo");
+    cfg.port = 1;
+    let s = SftpStorage::new(cfg).unwrap();
+    assert!(s.get("config").is_err());
+    assert!(s.list_prefix("").is_err());
+}
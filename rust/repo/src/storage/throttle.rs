@@ -0,0 +1,398 @@
+//! Bandwidth limits.
+//!
+//! Wraps another engine and holds transfers to a rate in bytes per second,
+//! with a token bucket for each direction. Uploads count the bytes put,
+//! downloads the bytes returned by gets. Every `ThrottledStorage` built
+//! from the same `Throttle` shares its buckets, so one limit covers all
+//! connections of a process. A bucket holds a second's worth of bytes, a
+//! transfer larger than that waits for the bytes it borrowed.
+//!
+//! Limits may change over the week, for instance to stay off a home uplink
+//! during working hours. The first schedule rule matching the current time
+//! replaces the default limits, written one rule per line as:
+//!
+//! ```text
+//! <days> <HH:MM>-<HH:MM> [up=<rate>] [down=<rate>]
+//! ```
+//!
+//! Days are `*` or a comma separated list of `mon` to `sun` and ranges of
+//! them such as `mon-fri`. A range whose end is before its start runs past
+//! midnight, into the next day. Rates are bytes per second with an optional
+//! `k`, `m` or `g` binary suffix, or `off` for no limit. Times are UTC
+//! shifted by `Limits::utc_offset`, there is no time zone database.
+
+use super::{Capabilities, StorageEngine, ThawState};
+use crate::address::Address;
+use crate::checks::CheckStatus;
+use crate::datetime::unix_now;
+use crate::gc::GcStats;
+use crate::translog::LogHash;
+use crate::usage::Quota;
+use crate::RepoError;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ScheduleRule {
+    // Monday first.
+    pub days: [bool; 7],
+    // Minutes after midnight, end exclusive.
+    pub start: u32,
+    pub end: u32,
+    pub up: Option<u64>,
+    pub down: Option<u64>,
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Limits {
+    pub up: Option<u64>,
+    pub down: Option<u64>,
+    pub schedule: Vec<ScheduleRule>,
+    // Seconds added to UTC to give the schedule's local time.
+    pub utc_offset: i64,
+}
+
+fn invalid(what: &str, s: &str) -> RepoError {
+    RepoError::StorageError(format!("invalid {} '{}'", what, s))
+}
+
+// Bytes per second, such as `512k`, or `off`.
+pub fn parse_rate(s: &str) -> Result<Option<u64>, RepoError> {
+    if s == "off" {
+        return Ok(None);
+    }
+    let (digits, mul) = match s.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('k') => (&s[..s.len() - 1], 1 << 10),
+        Some('m') => (&s[..s.len() - 1], 1 << 20),
+        Some('g') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    match digits.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(Some(n.saturating_mul(mul))),
+        _ => Err(invalid("rate", s)),
+    }
+}
+
+fn parse_time(s: &str) -> Result<u32, RepoError> {
+    let mut parts = s.splitn(2, ':');
+    let h = parts.next().and_then(|h| h.parse::<u32>().ok());
+    let m = parts.next().and_then(|m| m.parse::<u32>().ok());
+    match (h, m) {
+        (Some(h), Some(m)) if (h < 24 && m < 60) || (h == 24 && m == 0) => Ok(h * 60 + m),
+        _ => Err(invalid("time", s)),
+    }
+}
+
+fn parse_days(s: &str) -> Result<[bool; 7], RepoError> {
+    if s == "*" {
+        return Ok([true; 7]);
+    }
+    let day = |d: &str| {
+        DAY_NAMES
+            .iter()
+            .position(|n| *n == d)
+            .ok_or_else(|| invalid("day", d))
+    };
+    let mut days = [false; 7];
+    for part in s.split(',') {
+        let (from, to) = match part.find('-') {
+            Some(i) => (day(&part[..i])?, day(&part[i + 1..])?),
+            None => (day(part)?, day(part)?),
+        };
+        let mut d = from;
+        loop {
+            days[d] = true;
+            if d == to {
+                break;
+            }
+            d = (d + 1) % 7;
+        }
+    }
+    Ok(days)
+}
+
+impl ScheduleRule {
+    pub fn parse(line: &str) -> Result<ScheduleRule, RepoError> {
+        let mut words = line.split_whitespace();
+        let days = parse_days(words.next().ok_or_else(|| invalid("rule", line))?)?;
+        let span = words.next().ok_or_else(|| invalid("rule", line))?;
+        let dash = span.find('-').ok_or_else(|| invalid("rule", line))?;
+        let mut rule = ScheduleRule {
+            days,
+            start: parse_time(&span[..dash])?,
+            end: parse_time(&span[dash + 1..])?,
+            up: None,
+            down: None,
+        };
+        for w in words {
+            if let Some(rate) = w.strip_prefix("up=") {
+                rule.up = parse_rate(rate)?;
+            } else if let Some(rate) = w.strip_prefix("down=") {
+                rule.down = parse_rate(rate)?;
+            } else {
+                return Err(invalid("rule", line));
+            }
+        }
+        Ok(rule)
+    }
+
+    // Whether the rule covers `minute` of day `day`, Monday being 0.
+    fn matches(&self, day: usize, minute: u32) -> bool {
+        if self.start <= self.end {
+            self.days[day] && minute >= self.start && minute < self.end
+        } else {
+            (self.days[day] && minute >= self.start)
+                || (self.days[(day + 6) % 7] && minute < self.end)
+        }
+    }
+}
+
+impl Limits {
+    // Rules one per line, blank lines and lines starting with '#' are
+    // skipped.
+    pub fn parse_schedule(text: &str) -> Result<Vec<ScheduleRule>, RepoError> {
+        text.lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(ScheduleRule::parse)
+            .collect()
+    }
+
+    // The upload and download limits in force at unix time `t`.
+    pub fn at(&self, t: u64) -> (Option<u64>, Option<u64>) {
+        let local = (t as i64).saturating_add(self.utc_offset).max(0) as u64;
+        let days = local / 86400;
+        // The epoch was a Thursday.
+        let day = ((days + 3) % 7) as usize;
+        let minute = ((local % 86400) / 60) as u32;
+        match self.schedule.iter().find(|r| r.matches(day, minute)) {
+            Some(r) => (r.up, r.down),
+            None => (self.up, self.down),
+        }
+    }
+}
+
+struct Bucket {
+    // Negative while transfers are waiting for borrowed bytes.
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new() -> Bucket {
+        Bucket {
+            // Full, whatever the rate turns out to be.
+            tokens: f64::INFINITY,
+            last: Instant::now(),
+        }
+    }
+
+    // Take `n` bytes at `rate`, returning how long to wait for them.
+    fn take(&mut self, n: usize, rate: u64) -> Duration {
+        let now = Instant::now();
+        let rate = rate as f64;
+        let refill = now.duration_since(self.last).as_secs_f64() * rate;
+        self.tokens = (self.tokens + refill).min(rate) - n as f64;
+        self.last = now;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+pub struct Throttle {
+    limits: Limits,
+    up: Mutex<Bucket>,
+    down: Mutex<Bucket>,
+}
+
+impl Throttle {
+    pub fn new(limits: Limits) -> Throttle {
+        Throttle {
+            limits,
+            up: Mutex::new(Bucket::new()),
+            down: Mutex::new(Bucket::new()),
+        }
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    fn wait(&self, bucket: &Mutex<Bucket>, rate: Option<u64>, n: usize) {
+        if let Some(rate) = rate {
+            let delay = bucket.lock().unwrap().take(n, rate);
+            thread::sleep(delay);
+        }
+    }
+
+    pub fn upload(&self, n: usize) {
+        self.wait(&self.up, self.limits.at(unix_now()).0, n);
+    }
+
+    pub fn download(&self, n: usize) {
+        self.wait(&self.down, self.limits.at(unix_now()).1, n);
+    }
+}
+
+pub struct ThrottledStorage {
+    inner: Arc<dyn StorageEngine>,
+    throttle: Arc<Throttle>,
+}
+
+impl ThrottledStorage {
+    pub fn new(inner: Arc<dyn StorageEngine>, throttle: Arc<Throttle>) -> ThrottledStorage {
+        ThrottledStorage { inner, throttle }
+    }
+}
+
+impl StorageEngine for ThrottledStorage {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), RepoError> {
+        self.throttle.upload(data.len());
+        self.inner.put(key, data)
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, RepoError> {
+        let buf = self.inner.get(key)?;
+        self.throttle.download(buf.len());
+        Ok(buf)
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: usize) -> Result<Vec<u8>, RepoError> {
+        let buf = self.inner.get_range(key, offset, len)?;
+        self.throttle.download(buf.len());
+        Ok(buf)
+    }
+
+    fn size(&self, key: &str) -> Result<u64, RepoError> {
+        self.inner.size(key)
+    }
+
+    fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, RepoError> {
+        self.inner.list_prefix(prefix)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RepoError> {
+        self.inner.delete(key)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), RepoError> {
+        self.inner.rename(from, to)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn thaw(&self, key: &str) -> Result<ThawState, RepoError> {
+        self.inner.thaw(key)
+    }
+
+    fn exists_many(&self, keys: &[&str]) -> Result<Vec<bool>, RepoError> {
+        self.inner.exists_many(keys)
+    }
+
+    fn put_many(&self, objects: &[(&str, &[u8])]) -> Result<(), RepoError> {
+        self.throttle
+            .upload(objects.iter().map(|(_, data)| data.len()).sum());
+        self.inner.put_many(objects)
+    }
+
+    fn collect_garbage(&self, keep_list: &[u8]) -> Result<GcStats, RepoError> {
+        self.inner.collect_garbage(keep_list)
+    }
+
+    fn objects_present(&self, addresses: &[Address]) -> Result<Vec<bool>, RepoError> {
+        self.inner.objects_present(addresses)
+    }
+
+    fn consistency_proof(&self, old: u64, new: u64) -> Result<Vec<LogHash>, RepoError> {
+        self.inner.consistency_proof(old, new)
+    }
+
+    fn quotas(&self) -> Result<Vec<Quota>, RepoError> {
+        self.inner.quotas()
+    }
+
+    fn check_status(&self) -> Result<CheckStatus, RepoError> {
+        self.inner.check_status()
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_schedule() {
+    assert_eq!(parse_rate("512k").unwrap(), Some(512 * 1024));
+    assert_eq!(parse_rate("2M").unwrap(), Some(2 << 20));
+    assert_eq!(parse_rate("100").unwrap(), Some(100));
+    assert_eq!(parse_rate("off").unwrap(), None);
+    for bad in ["", "0", "k", "1x", "-1"].iter() {
+        assert!(parse_rate(bad).is_err());
+    }
+
+    let limits = Limits {
+        up: Some(1000),
+        down: None,
+        schedule: Limits::parse_schedule(
+            "# Working hours.\n\
+             mon-fri 09:00-17:30 up=10k down=1m\n\
+             \n\
+             fri,sat 22:00-06:00 up=off\n",
+        )
+        .unwrap(),
+        utc_offset: 3600,
+    };
+    assert_eq!(
+        limits.schedule[0].days,
+        [true, true, true, true, true, false, false]
+    );
+    // 1970-01-05 was a Monday.
+    let monday = 4 * 86400 - 3600;
+    assert_eq!(
+        limits.at(monday + 9 * 3600),
+        (Some(10 << 10), Some(1 << 20))
+    );
+    assert_eq!(limits.at(monday + 17 * 3600 + 1800), (Some(1000), None));
+    assert_eq!(limits.at(monday + 8 * 3600), (Some(1000), None));
+    // Saturday night runs into Sunday morning.
+    assert_eq!(limits.at(monday + 6 * 86400 + 3600), (None, None));
+    assert_eq!(limits.at(monday + 6 * 86400 + 7 * 3600), (Some(1000), None));
+    assert_eq!(limits.at(monday + 23 * 3600), (Some(1000), None));
+
+    for bad in [
+        "mon",
+        "xyz 09:00-10:00",
+        "* 25:00-26:00",
+        "* 09:00-10:00 left=1",
+    ]
+    .iter()
+    {
+        assert!(ScheduleRule::parse(bad).is_err());
+    }
+}
+
+#[test]
+fn test_throttled_storage() {
+    let throttle = Arc::new(Throttle::new(Limits {
+        up: Some(100_000),
+        ..Default::default()
+    }));
+    let s = ThrottledStorage::new(Arc::new(super::mem::MemStorage::new()), throttle.clone());
+    super::test_storage_engine(&s);
+
+    // The first second's worth goes out at once, the buckets are shared so
+    // a second engine then has to wait.
+    let other = ThrottledStorage::new(Arc::new(super::mem::MemStorage::new()), throttle);
+    let start = Instant::now();
+    s.put("a", &[0; 90_000]).unwrap();
+    assert!(start.elapsed() < Duration::from_millis(300));
+    other.put("a", &[0; 50_000]).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(350));
+    assert_eq!(s.get("a").unwrap().len(), 90_000);
+}
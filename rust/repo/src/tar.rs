@@ -0,0 +1,610 @@
+//! Writing and reading tar streams.
+//!
+//! `Repo::restore_tar` writes a snapshot as a POSIX.1-2001 (pax) tar
+//! stream, which any tar can unpack. Each entry is a 512 byte ustar header,
+//! see `ustar`, followed by its data, padded to a multiple of 512 bytes.
+//! Values that do not fit the header, paths and link targets over 100
+//! bytes, sizes and times of 8GiB or more, large ids, times with
+//! nanoseconds and access times, are carried in a pax extended header of
+//! records just before it:
+//!
+//! ```text
+//! record: "<length> <key>=<value>\n"
+//! ```
+//!
+//! `length` is the decimal byte length of the whole record. Devices and
+//! fifos have entries of their own, devices with their major and minor
+//! numbers in the ustar header. Tar has no entry for a socket, so a
+//! stream holds none. The stream ends with two zero blocks.
+//!
+//! `TarReader` reads what common tars write, for `Repo::backup_tar`: v7,
+//! ustar with its path prefix, GNU long names and links and base-256
+//! numbers, and pax extended and global headers. Only the pax keys
+//! `path`, `linkpath`, `size`, `uid`, `gid`, `mtime` and `atime` are
+//! used. A stream may end with one zero block or none, as long as it ends
+//! between entries.
+
+use super::tree::{EntryKind, TreeEntry};
+use super::ustar::{
+    checksum_ok, field, finish_header, numeric, octal_max, padding, put, put_octal, write_padding,
+    BLOCK_SZ, DEVMAJOR, DEVMINOR, GID, LINKNAME, MAGIC, MODE, MTIME, NAME, PREFIX, SIZE, TYPEFLAG,
+    UID,
+};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+
+// Bounds the memory a pax header or GNU long name can take.
+pub const MAX_EXTENSION_SZ: u64 = 1024 * 1024;
+
+const PAX_NAME: &[u8] = b"././@PaxHeader";
+
+// Without `ent`, as for a pax header, owner and time are zero.
+fn header(
+    path: &[u8],
+    typeflag: u8,
+    size: u64,
+    link: &[u8],
+    ent: Option<&TreeEntry>,
+) -> [u8; BLOCK_SZ] {
+    let mut block = [0; BLOCK_SZ];
+    put(&mut block, NAME, path);
+    let (mode, uid, gid, mtime) = ent.map_or((0o644, 0, 0, 0), |ent| {
+        (ent.mode & 0o7777, ent.uid, ent.gid, ent.mtime)
+    });
+    put_octal(&mut block, MODE, mode as u64);
+    put_octal(&mut block, UID, uid as u64);
+    put_octal(&mut block, GID, gid as u64);
+    put_octal(&mut block, SIZE, size);
+    put_octal(&mut block, MTIME, mtime);
+    if let Some(ent) = ent {
+        put_octal(&mut block, DEVMAJOR, ent.device.0 as u64);
+        put_octal(&mut block, DEVMINOR, ent.device.1 as u64);
+    }
+    block[TYPEFLAG] = typeflag;
+    put(&mut block, LINKNAME, link);
+    finish_header(&mut block);
+    block
+}
+
+fn pax_record(buf: &mut Vec<u8>, key: &str, value: &[u8]) {
+    // The length counts its own digits.
+    let base = key.len() + value.len() + 3;
+    let mut n = base;
+    while base + n.to_string().len() != n {
+        n = base + n.to_string().len();
+    }
+    buf.extend_from_slice(format!("{} {}=", n, key).as_bytes());
+    buf.extend_from_slice(value);
+    buf.push(b'\n');
+}
+
+// Write the header of `ent`, stored under `path`. Directory paths should
+// end in `/`. A file's `size` bytes of data must follow, then padding.
+// Sockets cannot be written.
+pub fn write_header(w: &mut dyn Write, path: &[u8], ent: &TreeEntry) -> io::Result<()> {
+    let (typeflag, size, link) = match ent.kind {
+        EntryKind::File => (b'0', ent.size, &[][..]),
+        EntryKind::Dir => (b'5', 0, &[][..]),
+        EntryKind::Symlink => (b'2', 0, &ent.target[..]),
+        EntryKind::CharDevice => (b'3', 0, &[][..]),
+        EntryKind::BlockDevice => (b'4', 0, &[][..]),
+        EntryKind::Fifo => (b'6', 0, &[][..]),
+        EntryKind::Socket => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a socket has no tar entry",
+            ))
+        }
+    };
+    let mut pax = Vec::new();
+    if path.len() > NAME.1 {
+        pax_record(&mut pax, "path", path);
+    }
+    if link.len() > LINKNAME.1 {
+        pax_record(&mut pax, "linkpath", link);
+    }
+    if ent.mtime_nsec != 0 {
+        let mtime = format!("{}.{:09}", ent.mtime, ent.mtime_nsec);
+        pax_record(&mut pax, "mtime", mtime.as_bytes());
+    } else if ent.mtime > octal_max(MTIME) {
+        pax_record(&mut pax, "mtime", ent.mtime.to_string().as_bytes());
+    }
+    if let Some((secs, nsec)) = ent.atime {
+        pax_record(
+            &mut pax,
+            "atime",
+            format!("{}.{:09}", secs, nsec).as_bytes(),
+        );
+    }
+    for (key, v, field) in [
+        ("size", size, SIZE),
+        ("uid", ent.uid as u64, UID),
+        ("gid", ent.gid as u64, GID),
+    ]
+    .iter()
+    {
+        if *v > octal_max(*field) {
+            pax_record(&mut pax, key, v.to_string().as_bytes());
+        }
+    }
+    if !pax.is_empty() {
+        w.write_all(&header(PAX_NAME, b'x', pax.len() as u64, b"", None))?;
+        w.write_all(&pax)?;
+        write_padding(w, pax.len() as u64)?;
+    }
+    w.write_all(&header(path, typeflag, size, link, Some(ent)))
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Header {
+    pub path: Vec<u8>,
+    pub typeflag: u8,
+    pub size: u64,
+    // Permission bits only.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u64,
+    pub mtime_nsec: u32,
+    pub link: Vec<u8>,
+    // The major and minor numbers of a device.
+    pub device: (u32, u32),
+    // Seconds and nanoseconds, if the archive has it.
+    pub atime: Option<(u64, u32)>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn parse_pax(mut data: &[u8]) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut records = Vec::new();
+    while !data.is_empty() && data[0] != 0 {
+        let sp = data.iter().position(|b| *b == b' ');
+        let n = sp.and_then(|sp| std::str::from_utf8(&data[..sp]).ok()?.parse::<usize>().ok());
+        let (sp, n) = match (sp, n) {
+            (Some(sp), Some(n)) if n > sp + 1 && n <= data.len() && data[n - 1] == b'\n' => (sp, n),
+            _ => return Err(invalid("bad pax header record")),
+        };
+        let record = &data[sp + 1..n - 1];
+        let eq = match record.iter().position(|b| *b == b'=') {
+            Some(eq) => eq,
+            None => return Err(invalid("bad pax header record")),
+        };
+        let key = String::from_utf8_lossy(&record[..eq]).into_owned();
+        records.push((key, record[eq + 1..].to_vec()));
+        data = &data[n..];
+    }
+    Ok(records)
+}
+
+fn pax_number(value: &[u8]) -> io::Result<u64> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| invalid("bad number in pax header"))
+}
+
+fn device_number(v: u64) -> io::Result<u32> {
+    u32::try_from(v).map_err(|_| invalid("oversized device number in tar header"))
+}
+
+fn id(v: u64) -> io::Result<u32> {
+    if v > u32::MAX as u64 {
+        return Err(invalid("oversized uid or gid in tar header"));
+    }
+    Ok(v as u32)
+}
+
+// Seconds with an optional fraction, times before 1970 are clamped.
+fn pax_time(value: &[u8]) -> io::Result<(u64, u32)> {
+    let bad = || invalid("bad time in pax header");
+    let v = std::str::from_utf8(value).map_err(|_| bad())?;
+    let (secs, frac) = match v.find('.') {
+        Some(dot) => (&v[..dot], &v[dot + 1..]),
+        None => (v, ""),
+    };
+    if !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(bad());
+    }
+    let secs: i64 = secs.parse().map_err(|_| bad())?;
+    if secs < 0 {
+        return Ok((0, 0));
+    }
+    let nsec = format!("{:0<9}", &frac[..frac.len().min(9)]);
+    Ok((secs as u64, nsec.parse().map_err(|_| bad())?))
+}
+
+// Reads the entries of a tar stream in turn. After `next_header` returns an
+// entry, reading the `TarReader` yields its data.
+pub struct TarReader<R: Read> {
+    inner: R,
+    // Unread data and padding of the current entry.
+    remaining: u64,
+    padding: u64,
+    // From pax global headers, for every later entry.
+    globals: Vec<(String, Vec<u8>)>,
+    done: bool,
+}
+
+impl<R: Read> TarReader<R> {
+    pub fn new(inner: R) -> TarReader<R> {
+        TarReader {
+            inner,
+            remaining: 0,
+            padding: 0,
+            globals: Vec::new(),
+            done: false,
+        }
+    }
+
+    fn skip(&mut self, n: u64) -> io::Result<()> {
+        let skipped = io::copy(&mut (&mut self.inner).take(n), &mut io::sink())?;
+        if skipped != n {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    // The next header block, None at the end of the stream.
+    fn block(&mut self) -> io::Result<Option<[u8; BLOCK_SZ]>> {
+        let mut block = [0; BLOCK_SZ];
+        let mut n = 0;
+        while n < BLOCK_SZ {
+            match self.inner.read(&mut block[n..]) {
+                Ok(0) if n == 0 => return Ok(None),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(k) => n += k,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        if block.iter().all(|b| *b == 0) {
+            return Ok(None);
+        }
+        if !checksum_ok(&block) {
+            return Err(invalid("bad tar header checksum"));
+        }
+        Ok(Some(block))
+    }
+
+    fn extension(&mut self, size: u64) -> io::Result<Vec<u8>> {
+        if size > MAX_EXTENSION_SZ {
+            return Err(invalid("oversized tar extension header"));
+        }
+        let mut data = vec![0; size as usize];
+        self.inner.read_exact(&mut data)?;
+        self.skip(padding(size))?;
+        Ok(data)
+    }
+
+    // The header of the next entry, skipping whatever is left of the
+    // current one, or None at the end of the stream.
+    pub fn next_header(&mut self) -> io::Result<Option<Header>> {
+        self.skip(self.remaining + self.padding)?;
+        self.remaining = 0;
+        self.padding = 0;
+        let mut pax = self.globals.clone();
+        let (mut long_name, mut long_link) = (None, None);
+        let block = loop {
+            if self.done {
+                return Ok(None);
+            }
+            let block = match self.block()? {
+                Some(block) => block,
+                None => {
+                    self.done = true;
+                    continue;
+                }
+            };
+            let size = numeric(&block, SIZE)?;
+            match block[TYPEFLAG] {
+                b'x' => pax.extend(parse_pax(&self.extension(size)?)?),
+                b'g' => {
+                    let records = parse_pax(&self.extension(size)?)?;
+                    self.globals.extend(records.iter().cloned());
+                    pax.extend(records);
+                }
+                b'L' => {
+                    long_name = Some(field(&self.extension(size)?, (0, size as usize)).to_vec())
+                }
+                b'K' => {
+                    long_link = Some(field(&self.extension(size)?, (0, size as usize)).to_vec())
+                }
+                _ => break block,
+            }
+        };
+        let mut path = field(&block, NAME).to_vec();
+        let prefix = field(&block, PREFIX);
+        if &block[MAGIC.0..MAGIC.0 + 6] == b"ustar\0" && !prefix.is_empty() {
+            path = [prefix, b"/", &path].concat();
+        }
+        let mut h = Header {
+            path: long_name.unwrap_or(path),
+            typeflag: block[TYPEFLAG],
+            size: numeric(&block, SIZE)?,
+            mode: (numeric(&block, MODE)? & 0o7777) as u32,
+            uid: id(numeric(&block, UID)?)?,
+            gid: id(numeric(&block, GID)?)?,
+            mtime: numeric(&block, MTIME)?,
+            mtime_nsec: 0,
+            link: long_link.unwrap_or_else(|| field(&block, LINKNAME).to_vec()),
+            device: (0, 0),
+            atime: None,
+        };
+        if let b'3' | b'4' = h.typeflag {
+            h.device = (
+                device_number(numeric(&block, DEVMAJOR)?)?,
+                device_number(numeric(&block, DEVMINOR)?)?,
+            );
+        }
+        for (key, value) in pax {
+            match &key[..] {
+                "path" => h.path = value,
+                "linkpath" => h.link = value,
+                "size" => h.size = pax_number(&value)?,
+                "uid" => h.uid = id(pax_number(&value)?)?,
+                "gid" => h.gid = id(pax_number(&value)?)?,
+                "mtime" => (h.mtime, h.mtime_nsec) = pax_time(&value)?,
+                "atime" => h.atime = Some(pax_time(&value)?),
+                _ => (),
+            }
+        }
+        self.remaining = h.size;
+        self.padding = padding(h.size);
+        Ok(Some(h))
+    }
+}
+
+impl<R: Read> Read for TarReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let n = buf
+            .len()
+            .min(self.remaining.min(usize::MAX as u64) as usize);
+        let n = self.inner.read(&mut buf[..n])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+// Tests --------------------
+
+// A header for any `typeflag`, owned by root, mode 644 and modified at 0.
+#[cfg(test)]
+pub(crate) fn test_header(path: &[u8], typeflag: u8, size: u64, link: &[u8]) -> [u8; BLOCK_SZ] {
+    header(path, typeflag, size, link, None)
+}
+
+// The entries of a tar stream as (path, typeflag, data or link target).
+#[cfg(test)]
+pub(crate) fn test_read_tar(buf: &[u8]) -> Vec<(Vec<u8>, u8, Vec<u8>)> {
+    use super::ustar::CHKSUM;
+    let field = |block: &[u8], f: (usize, usize)| -> Vec<u8> {
+        block[f.0..f.0 + f.1]
+            .iter()
+            .cloned()
+            .take_while(|b| *b != 0)
+            .collect()
+    };
+    let octal = |block: &[u8], f: (usize, usize)| {
+        u64::from_str_radix(std::str::from_utf8(&field(block, f)).unwrap(), 8).unwrap()
+    };
+    let mut entries = Vec::new();
+    let mut pax: Vec<(String, Vec<u8>)> = Vec::new();
+    let mut off = 0;
+    while buf[off..off + BLOCK_SZ].iter().any(|b| *b != 0) {
+        let block = &buf[off..off + BLOCK_SZ];
+        let mut sum_block = block.to_vec();
+        sum_block[CHKSUM.0..CHKSUM.0 + CHKSUM.1].copy_from_slice(b"        ");
+        let sum: u64 = sum_block.iter().map(|b| *b as u64).sum();
+        assert_eq!(octal(block, (CHKSUM.0, 6)), sum);
+        assert_eq!(&block[MAGIC.0..MAGIC.0 + MAGIC.1], b"ustar\x0000");
+        let mut path = field(block, NAME);
+        let mut link = field(block, LINKNAME);
+        let mut size = octal(block, SIZE);
+        for (key, value) in pax.drain(..) {
+            match &key[..] {
+                "path" => path = value,
+                "linkpath" => link = value,
+                "size" => size = std::str::from_utf8(&value).unwrap().parse().unwrap(),
+                _ => (),
+            }
+        }
+        let data = buf[off + BLOCK_SZ..off + BLOCK_SZ + size as usize].to_vec();
+        off += BLOCK_SZ + (size as usize).div_ceil(BLOCK_SZ) * BLOCK_SZ;
+        match block[TYPEFLAG] {
+            b'x' => {
+                let mut rest = &data[..];
+                while !rest.is_empty() {
+                    let sp = rest.iter().position(|b| *b == b' ').unwrap();
+                    let n: usize = std::str::from_utf8(&rest[..sp]).unwrap().parse().unwrap();
+                    let record = &rest[sp + 1..n - 1];
+                    let eq = record.iter().position(|b| *b == b'=').unwrap();
+                    let key = String::from_utf8(record[..eq].to_vec()).unwrap();
+                    pax.push((key, record[eq + 1..].to_vec()));
+                    rest = &rest[n..];
+                }
+            }
+            b'2' => entries.push((path, b'2', link)),
+            t => entries.push((path, t, data)),
+        }
+    }
+    assert!(buf[off..].len() >= 2 * BLOCK_SZ && buf[off..].iter().all(|b| *b == 0));
+    entries
+}
+
+#[test]
+fn test_tar_headers() {
+    use super::ustar::write_end;
+    let ent = |kind, size, target: &[u8]| TreeEntry {
+        name: b"x".to_vec(),
+        kind,
+        mode: 0o100644,
+        uid: 1000,
+        gid: 100,
+        mtime: 1_600_000_000,
+        mtime_nsec: 0,
+        size,
+        refs: Vec::new(),
+        target: target.to_vec(),
+        link: 0,
+        xattrs: Vec::new(),
+        device: (0, 0),
+        atime: None,
+        birthtime: None,
+    };
+    let long = vec![b'a'; 150];
+    let mut buf = Vec::new();
+    write_header(&mut buf, b"dir/", &ent(EntryKind::Dir, 0, b"")).unwrap();
+    write_header(&mut buf, b"dir/file", &ent(EntryKind::File, 3, b"")).unwrap();
+    buf.extend_from_slice(b"abc");
+    write_padding(&mut buf, 3).unwrap();
+    write_header(&mut buf, &long, &ent(EntryKind::Symlink, 0, &long)).unwrap();
+    write_header(&mut buf, b"fifo", &ent(EntryKind::Fifo, 0, b"")).unwrap();
+    assert!(write_header(&mut buf, b"socket", &ent(EntryKind::Socket, 0, b"")).is_err());
+    write_end(&mut buf).unwrap();
+    assert_eq!(buf.len() % BLOCK_SZ, 0);
+    assert_eq!(
+        test_read_tar(&buf),
+        vec![
+            (b"dir/".to_vec(), b'5', Vec::new()),
+            (b"dir/file".to_vec(), b'0', b"abc".to_vec()),
+            (long.clone(), b'2', long.clone()),
+            (b"fifo".to_vec(), b'6', Vec::new()),
+        ]
+    );
+
+    // Record lengths count their own digits, across a change in width.
+    for n in 90..110 {
+        let mut rec = Vec::new();
+        pax_record(&mut rec, "path", &vec![b'p'; n]);
+        let sp = rec.iter().position(|b| *b == b' ').unwrap();
+        let len: usize = std::str::from_utf8(&rec[..sp]).unwrap().parse().unwrap();
+        assert_eq!(len, rec.len());
+    }
+}
+
+#[test]
+fn test_tar_reader() {
+    use super::ustar::{write_zeros, CHKSUM};
+    let resum = |block: &mut [u8; BLOCK_SZ]| {
+        put(block, CHKSUM, b"        ");
+        let sum: u32 = block.iter().map(|b| *b as u32).sum();
+        put(block, CHKSUM, format!("{:06o}\0 ", sum).as_bytes());
+    };
+    let ent = TreeEntry {
+        name: b"d".to_vec(),
+        kind: EntryKind::Dir,
+        mode: 0o750,
+        uid: 1000,
+        gid: 100,
+        mtime: 1_600_000_000,
+        mtime_nsec: 0,
+        size: 0,
+        refs: Vec::new(),
+        target: Vec::new(),
+        link: 0,
+        xattrs: Vec::new(),
+        device: (0, 0),
+        atime: None,
+        birthtime: None,
+    };
+    let long = vec![b'n'; 300];
+    let mut buf = Vec::new();
+    write_header(&mut buf, b"d/", &ent).unwrap();
+    let null = TreeEntry {
+        kind: EntryKind::CharDevice,
+        device: (1, 3),
+        mtime_nsec: 7,
+        atime: Some((5, 600)),
+        ..ent.clone()
+    };
+    write_header(&mut buf, b"null", &null).unwrap();
+    // A GNU long name.
+    buf.extend_from_slice(&test_header(b"././@LongLink", b'L', 301, b""));
+    buf.extend_from_slice(&long);
+    buf.push(0);
+    write_padding(&mut buf, 301).unwrap();
+    buf.extend_from_slice(&test_header(b"cut", b'0', 3, b""));
+    buf.extend_from_slice(b"abc");
+    write_padding(&mut buf, 3).unwrap();
+    // A pax global header, then an extended one overriding it.
+    let mut pax = Vec::new();
+    pax_record(&mut pax, "uid", b"7");
+    pax_record(&mut pax, "mtime", b"5.5");
+    buf.extend_from_slice(&test_header(PAX_NAME, b'g', pax.len() as u64, b""));
+    buf.extend_from_slice(&pax);
+    write_padding(&mut buf, pax.len() as u64).unwrap();
+    let mut pax = Vec::new();
+    pax_record(&mut pax, "mtime", b"1600000000.25");
+    pax_record(&mut pax, "path", b"p/x");
+    pax_record(&mut pax, "comment", b"ignored");
+    buf.extend_from_slice(&test_header(PAX_NAME, b'x', pax.len() as u64, b""));
+    buf.extend_from_slice(&pax);
+    write_padding(&mut buf, pax.len() as u64).unwrap();
+    buf.extend_from_slice(&test_header(b"ignored", b'2', 0, b"target"));
+    // A ustar prefix and a base-256 size.
+    let mut block = test_header(b"file", b'0', 0, b"");
+    put(&mut block, PREFIX, b"pre/fix");
+    block[SIZE.0..SIZE.0 + SIZE.1].copy_from_slice(&[0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4]);
+    resum(&mut block);
+    buf.extend_from_slice(&block);
+    buf.extend_from_slice(b"data");
+    write_padding(&mut buf, 4).unwrap();
+    // One zero block is enough.
+    write_zeros(&mut buf, BLOCK_SZ as u64).unwrap();
+
+    let mut tar = TarReader::new(&buf[..]);
+    let d = tar.next_header().unwrap().unwrap();
+    assert_eq!((&d.path[..], d.typeflag, d.mode), (&b"d/"[..], b'5', 0o750));
+    assert_eq!((d.uid, d.gid, d.mtime), (1000, 100, 1_600_000_000));
+    let null = tar.next_header().unwrap().unwrap();
+    assert_eq!((null.typeflag, null.device), (b'3', (1, 3)));
+    // Nanoseconds and access times survive.
+    assert_eq!((null.mtime, null.mtime_nsec), (1_600_000_000, 7));
+    assert_eq!(null.atime, Some((5, 600)));
+    // Left unread, so skipped.
+    let cut = tar.next_header().unwrap().unwrap();
+    assert_eq!((cut.path, cut.size), (long, 3));
+    let link = tar.next_header().unwrap().unwrap();
+    assert_eq!(
+        (&link.path[..], &link.link[..]),
+        (&b"p/x"[..], &b"target"[..])
+    );
+    assert_eq!(
+        (link.uid, link.mtime, link.mtime_nsec),
+        (7, 1_600_000_000, 250_000_000)
+    );
+    let file = tar.next_header().unwrap().unwrap();
+    assert_eq!((&file.path[..], file.size), (&b"pre/fix/file"[..], 4));
+    assert_eq!((file.uid, file.mtime, file.mtime_nsec), (7, 5, 500_000_000));
+    let mut data = Vec::new();
+    tar.read_to_end(&mut data).unwrap();
+    assert_eq!(data, b"data");
+    assert!(tar.next_header().unwrap().is_none());
+    assert!(tar.next_header().unwrap().is_none());
+
+    // Damaged and truncated archives are errors, a missing end is not.
+    let mut tar = TarReader::new(&buf[..BLOCK_SZ]);
+    tar.next_header().unwrap().unwrap();
+    assert!(tar.next_header().unwrap().is_none());
+    let mut bad = buf.clone();
+    bad[0] = b'e';
+    match TarReader::new(&bad[..]).next_header() {
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidData => (),
+        _ => panic!("expected a bad checksum to fail"),
+    }
+    let end = buf.len() - 2 * BLOCK_SZ + 2;
+    let mut tar = TarReader::new(&buf[..end]);
+    while tar.next_header().unwrap().unwrap().path != b"pre/fix/file" {}
+    match tar.read_to_end(&mut Vec::new()) {
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => (),
+        _ => panic!("expected a truncated entry to fail"),
+    }
+}
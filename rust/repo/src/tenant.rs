@@ -0,0 +1,58 @@
+//! Tenants, repositories one server hosts side by side.
+//!
+//! A small team can run one `serve` for all its machines and still keep
+//! them apart. Started with `ServeOptions::tenants`, the server hosts a
+//! repository for each tenant under `tenants/<name>/` of its storage, each
+//! with its own config, keys, manifest, access list, usage and quotas. The
+//! repositories share nothing, not even chunks, as chunk addresses are
+//! keyed per repository, see `address`.
+//!
+//! A client selects its tenant with TENANT before it authenticates, see
+//! `protocol`, and the session is then served as if that repository were
+//! the only one, with its access list deciding who the client is and what
+//! it may do. Nothing else is answered until a tenant is selected, and the
+//! selection cannot be changed, a client wanting another tenant starts
+//! another session.
+//!
+//! Tenants are created on the server, with `Repo::init` on the storage
+//! `tenant_storage` returns, clients cannot select one without a config.
+//!
+//! Machines sharing one repository can still be kept from sharing chunks,
+//! see `Policy::isolate_namespaces`.
+
+use super::storage::prefix::PrefixStorage;
+use super::storage::StorageEngine;
+use super::{RepoError, CONFIG_FILE, TENANTS_DIR};
+use std::sync::Arc;
+
+// Names are a single key component, so one tenant cannot name another's
+// keys.
+pub fn check_tenant(name: &str) -> Result<(), RepoError> {
+    if name.contains('/') {
+        return Err(RepoError::InvalidKeyError);
+    }
+    super::storage::check_key(name)
+}
+
+// The storage of tenant `name` within `root`.
+pub fn tenant_storage(
+    root: &Arc<dyn StorageEngine>,
+    name: &str,
+) -> Result<Arc<dyn StorageEngine>, RepoError> {
+    check_tenant(name)?;
+    let prefix = format!("{}/{}", TENANTS_DIR, name);
+    Ok(Arc::new(PrefixStorage::new(root.clone(), &prefix)?))
+}
+
+// The tenants in `root` with a repository.
+pub fn list_tenants(root: &dyn StorageEngine) -> Result<Vec<String>, RepoError> {
+    let dir = format!("{}/", TENANTS_DIR);
+    let mut names = Vec::new();
+    for key in root.list_prefix(&dir)?.iter() {
+        let rest = &key[dir.len()..];
+        if let Some((name, CONFIG_FILE)) = rest.split_once('/') {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
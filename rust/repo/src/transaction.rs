@@ -0,0 +1,362 @@
+//! Backup transactions.
+//!
+//! Readers only find objects through pack indexes, and only find snapshots
+//! through the manifest. A transaction relies on that by ordering its
+//! writes so that nothing it adds is reachable until it is complete:
+//!
+//! 1. Objects are written into packs. A pack without an index is
+//!    invisible, nothing can reference an object it cannot find.
+//! 2. On commit the indexes of every pack are written. The objects become
+//!    visible for deduplication but no snapshot references them yet.
+//! 3. The manifest is replaced with one naming the new snapshot, a single
+//!    atomic put that publishes the whole backup.
+//!
+//! A transaction that fails or is dropped before step 2 deletes its packs
+//! again. Once an index is written a concurrent writer may already
+//! deduplicate against its objects, so an interrupted commit leaves its
+//! packs and indexes to `gc`. A crash before step 2 leaves packs without
+//! indexes, which `Repo::reclaim_orphans` deletes.
+//!
+//! Long transactions checkpoint: every `PackerOptions::checkpoint` they
+//! run step 2 early for the packs stored so far. A backup killed by a
+//! reboot or a lost connection then leaves its checkpointed objects
+//! indexed, and the next backup of the same source finds them in the index
+//! and uploads only what came after the last checkpoint, including the
+//! trees of directories it finished. Checkpointed packs outlive a rollback
+//! like those of an interrupted commit, nothing references them until a
+//! later backup does, and `gc` deletes them once they are older than its
+//! grace period.
+//!
+//! Transactions hold a shared lock for their whole life, see `lock`.
+//! Storage offers no compare and swap, so two transactions committing at
+//! the same moment can race on the manifest. The manifest is read
+//! immediately before it is replaced and checked again afterwards, and a
+//! lost head is added again, which narrows the window but cannot close
+//! it.
+
+use super::address::Address;
+use super::index::PackIndex;
+use super::lock::{LockMode, RepoLock};
+use super::manifest::SnapshotHead;
+use super::object::ObjectKind;
+use super::pack::{FinishedPack, PackId, Packer, PackerOptions};
+use super::{Repo, RepoError};
+use asymcrypt::Key;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+pub const MAX_COMMIT_ATTEMPTS: usize = 3;
+
+pub struct Transaction<'a> {
+    repo: &'a Repo,
+    lock: RepoLock,
+    packer: Packer,
+    // Stored packs that have no index yet.
+    unindexed: Vec<PackIndex>,
+    // Every object added, so callers can skip adding one again.
+    pub(crate) added: HashSet<Address>,
+    // Bytes of the packs stored.
+    stored: u64,
+    checkpoint: Option<Duration>,
+    checkpointed: Instant,
+    done: bool,
+}
+
+impl<'a> Transaction<'a> {
+    pub fn add(
+        &mut self,
+        address: &Address,
+        kind: ObjectKind,
+        data: &[u8],
+    ) -> Result<(), RepoError> {
+        self.packer.add(address, kind, data)?;
+        self.added.insert(*address);
+        self.collect_finished();
+        Ok(())
+    }
+
+    // Whether an object was already added to this transaction.
+    pub fn contains(&self, address: &Address) -> bool {
+        self.added.contains(address)
+    }
+
+    // Keep the lock alive, make slow trickles of data durable and
+    // checkpoint when due, long running backups should call this
+    // regularly.
+    pub fn refresh(&mut self) -> Result<(), RepoError> {
+        self.lock.refresh()?;
+        self.packer.flush_if_stale()?;
+        self.collect_finished();
+        if self
+            .checkpoint
+            .is_some_and(|every| self.checkpointed.elapsed() >= every)
+        {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    // Index every object stored so far, see the module comment.
+    pub fn checkpoint(&mut self) -> Result<(), RepoError> {
+        self.packer.flush()?;
+        self.collect_finished();
+        self.repo.write_indexes(&mut self.unindexed)?;
+        self.checkpointed = Instant::now();
+        Ok(())
+    }
+
+    fn collect_finished(&mut self) {
+        let finished = self.packer.take_finished();
+        self.add_finished(finished);
+    }
+
+    // Take on packs stored outside the packer, see `upload`.
+    pub(crate) fn add_finished(&mut self, packs: Vec<FinishedPack>) {
+        for p in packs {
+            self.stored += p.size;
+            self.unindexed.push(PackIndex::from_finished(&p));
+        }
+    }
+
+    // Store the pack being filled, so `stored_bytes` counts everything
+    // added so far.
+    pub fn flush(&mut self) -> Result<(), RepoError> {
+        self.packer.flush()?;
+        self.collect_finished();
+        Ok(())
+    }
+
+    // Bytes of the packs stored so far, parity and indexes aside.
+    pub fn stored_bytes(&self) -> u64 {
+        self.stored
+    }
+
+    pub fn repo(&self) -> &'a Repo {
+        self.repo
+    }
+
+    // Publish every object added so far along with `head`, which must
+    // name a snapshot object added to this transaction.
+    pub fn commit(self, head: SnapshotHead, key: &Key) -> Result<(), RepoError> {
+        let repo = self.repo;
+        self.commit_objects()?;
+        repo.publish_head(head, key)
+    }
+
+    // Make every object added so far visible without publishing a head,
+    // for callers that change the manifest themselves, see `amend`.
+    pub fn commit_objects(mut self) -> Result<(), RepoError> {
+        self.packer.flush()?;
+        self.collect_finished();
+        if let Err(err) = self.repo.write_indexes(&mut self.unindexed) {
+            let _ = self.rollback();
+            return Err(err);
+        }
+        self.done = true;
+        Ok(())
+    }
+
+    // Discard the transaction, deleting any packs it stored.
+    pub fn abort(mut self) -> Result<(), RepoError> {
+        self.rollback()
+    }
+
+    fn rollback(&mut self) -> Result<(), RepoError> {
+        self.done = true;
+        self.collect_finished();
+        self.repo.delete_unindexed(&mut self.unindexed)
+    }
+}
+
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.rollback();
+        }
+    }
+}
+
+impl Repo {
+    pub fn begin(&self, opts: PackerOptions) -> Result<Transaction<'_>, RepoError> {
+        let lock = self.lock(LockMode::Shared)?;
+        Ok(Transaction {
+            repo: self,
+            lock,
+            checkpoint: opts.checkpoint,
+            packer: self.packer(opts),
+            unindexed: Vec::new(),
+            added: HashSet::new(),
+            stored: 0,
+            checkpointed: Instant::now(),
+            done: false,
+        })
+    }
+
+    // Write the indexes of stored packs, removing each from `unindexed`
+    // once written.
+    pub(crate) fn write_indexes(&self, unindexed: &mut Vec<PackIndex>) -> Result<(), RepoError> {
+        while let Some(idx) = unindexed.pop() {
+            // On failure this index may or may not have been stored, so
+            // only the packs still waiting for theirs are safe to delete.
+            self.write_pack_index(&idx)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn delete_unindexed(&self, unindexed: &mut Vec<PackIndex>) -> Result<(), RepoError> {
+        if !self.storage().capabilities().delete {
+            // Left for `reclaim_orphans` once deletes are allowed.
+            return Ok(());
+        }
+        for idx in unindexed.drain(..) {
+            self.delete_pack(&idx.pack_id)?;
+        }
+        Ok(())
+    }
+
+    // Add `head` to the manifest once its objects are indexed.
+    pub(crate) fn publish_head(&self, head: SnapshotHead, key: &Key) -> Result<(), RepoError> {
+        for _ in 0..MAX_COMMIT_ATTEMPTS {
+            let mut m = self.manifest()?;
+            if !m.heads.contains(&head) {
+                m.add_head(head.clone());
+                self.commit_manifest(&m, key)?;
+            }
+            if self.manifest()?.heads.contains(&head) {
+                return Ok(());
+            }
+        }
+        Err(RepoError::StorageError(
+            "the manifest kept changing while committing".to_string(),
+        ))
+    }
+
+    // Delete packs left without an index by transactions that crashed
+    // before committing. Takes an exclusive lock, so no transaction can be
+    // in flight, and returns the deleted packs.
+    pub fn reclaim_orphans(&self) -> Result<Vec<PackId>, RepoError> {
+        let _lock = self.lock(LockMode::Exclusive)?;
+        let indexed: HashSet<PackId> = self.list_pack_indexes()?.into_iter().collect();
+        let mut orphans = Vec::new();
+        for id in self.list_packs()? {
+            if !indexed.contains(&id) {
+                self.delete_pack(&id)?;
+                orphans.push(id);
+            }
+        }
+        Ok(orphans)
+    }
+}
+
+// Tests --------------------
+
+#[cfg(test)]
+fn test_head(tx: &mut Transaction, i: u8) -> SnapshotHead {
+    let mut address: Address = Default::default();
+    address.bytes[0] = i;
+    tx.add(&address, ObjectKind::Snapshot, &[i; 100]).unwrap();
+    SnapshotHead {
+        address,
+        timestamp: i as u64,
+        namespace: super::namespace::Namespace::new("laptop").unwrap(),
+        retain_until: 0,
+        amendment: None,
+    }
+}
+
+#[test]
+fn test_transaction_commit() {
+    let (r, key) = super::test_repo();
+    let mut tx = r.begin(Default::default()).unwrap();
+    let head = test_head(&mut tx, 1);
+    // Nothing is visible before the commit.
+    assert!(r.list_pack_indexes().unwrap().is_empty());
+    assert_eq!(r.list_locks().unwrap().len(), 1);
+    tx.commit(head.clone(), &key).unwrap();
+    assert_eq!(r.manifest().unwrap().heads, vec![head.clone()]);
+    assert!(r.load_index().unwrap().contains(&head.address));
+    assert!(r.list_locks().unwrap().is_empty());
+
+    // Committing the same head twice does not duplicate it.
+    let mut tx = r.begin(Default::default()).unwrap();
+    let head = test_head(&mut tx, 1);
+    tx.commit(head, &key).unwrap();
+    assert_eq!(r.manifest().unwrap().heads.len(), 1);
+}
+
+#[test]
+fn test_transaction_rollback() {
+    let (r, _) = super::test_repo();
+    let opts = PackerOptions {
+        target_size: 1,
+        ..Default::default()
+    };
+    let mut tx = r.begin(opts.clone()).unwrap();
+    test_head(&mut tx, 1);
+    assert_eq!(r.list_packs().unwrap().len(), 1);
+    tx.abort().unwrap();
+    assert!(r.list_packs().unwrap().is_empty());
+
+    let mut tx = r.begin(opts.clone()).unwrap();
+    test_head(&mut tx, 2);
+    drop(tx);
+    assert!(r.list_packs().unwrap().is_empty());
+    assert!(r.list_locks().unwrap().is_empty());
+    assert!(r.manifest().unwrap().heads.is_empty());
+
+    // A crash leaves the pack and the lock behind.
+    let mut tx = r.begin(opts).unwrap();
+    test_head(&mut tx, 3);
+    std::mem::forget(tx);
+    match r.reclaim_orphans() {
+        Err(RepoError::RepoLockedError) => (),
+        _ => panic!("expected the crashed transaction to hold its lock"),
+    }
+    r.force_unlock().unwrap();
+    let committed = PackIndex::new(PackId::new(), 0, Vec::new());
+    r.write_pack_index(&committed).unwrap();
+    assert_eq!(r.reclaim_orphans().unwrap().len(), 1);
+    assert!(r.list_packs().unwrap().is_empty());
+    assert!(r.list_locks().unwrap().is_empty());
+}
+
+#[test]
+fn test_transaction_checkpoint() {
+    use super::gc::GcOptions;
+    let (r, key) = super::test_repo();
+    let opts = PackerOptions {
+        checkpoint: Some(Duration::from_secs(0)),
+        ..Default::default()
+    };
+    let mut tx = r.begin(opts).unwrap();
+    let head = test_head(&mut tx, 1);
+    tx.refresh().unwrap();
+    let lost = test_head(&mut tx, 2);
+    drop(tx);
+    // The checkpointed object survives the rollback, indexed.
+    let index = r.load_index().unwrap();
+    assert!(index.contains(&head.address));
+    assert!(!index.contains(&lost.address));
+    assert_eq!(r.list_packs().unwrap().len(), 1);
+
+    // gc keeps it for the next backup until its grace period is over.
+    let stats = r.gc(&key.box_sk, &Default::default()).unwrap();
+    assert!(stats.deleted_packs.is_empty());
+    let grace = GcOptions {
+        grace: Duration::from_secs(0),
+        ..Default::default()
+    };
+    let stats = r.gc(&key.box_sk, &grace).unwrap();
+    assert_eq!(stats.deleted_packs.len(), 1);
+    assert!(r.list_packs().unwrap().is_empty());
+}
+
+#[test]
+fn test_transaction_locked_out() {
+    let (r, _) = super::test_repo();
+    let _gc = r.lock(LockMode::Exclusive).unwrap();
+    match r.begin(Default::default()) {
+        Err(RepoError::RepoLockedError) => (),
+        _ => panic!("expected the transaction to be locked out"),
+    };
+}
@@ -0,0 +1,363 @@
+//! A Merkle log of manifests.
+//!
+//! Every commit appends the hash of the manifest it replaces, see
+//! `manifest_hash`, to a log kept at `translog`, and the new manifest
+//! carries the size and root of the Merkle tree over the whole log, so the
+//! owner signs a tree head with every commit. A client that remembers the
+//! tree head of the newest manifest it saw, see `rollback`, asks for a
+//! consistency proof when it sees a newer one, and refuses the newer
+//! manifest unless the proof shows the old tree is a prefix of the new.
+//! Unlike counters, this checks every manifest in between, not just the
+//! last: a storage provider cannot leave out or swap any manifest of the
+//! history a client already saw without also forging the owner's
+//! signature.
+//!
+//! The tree is that of RFC 6962, with the first 32 bytes of sha512 for
+//! the hash. A leaf is hashed as `H(0x00 || manifest_hash)`, an inner node
+//! as `H(0x01 || left || right)`, and the empty tree as `H("")`. Proofs
+//! are computed by `serve` from the log, see `protocol`, or locally from
+//! storage that cannot.
+//!
+//! Format of the log, which is replaced whole, through `serve` only by a
+//! log that extends it:
+//!
+//! ```text
+//! "PNBTLOG" u16:format_version [16]:repo_id u64:n n * [32]:manifest_hash
+//! ```
+//!
+//! The first manifest has an empty tree, the log is written from the
+//! commit that replaces it.
+
+use super::manifest::{manifest_hash, Manifest, ManifestHash, RepoId, MANIFEST_HASH_SZ};
+use super::wire::{Decoder, Encoder};
+use super::{Repo, RepoError, TRANSLOG_FILE};
+use tweetnacl::*;
+
+pub const TRANSLOG_FORMAT_VERSION: u16 = 1;
+const TRANSLOG_MAGIC: &[u8] = b"PNBTLOG";
+
+pub type LogHash = [u8; MANIFEST_HASH_SZ];
+
+fn hash(parts: &[&[u8]]) -> LogHash {
+    let mut h = [0; CRYPTO_HASH_BYTES];
+    crypto_hash(&mut h, &parts.concat());
+    let mut c = [0; MANIFEST_HASH_SZ];
+    c.copy_from_slice(&h[..MANIFEST_HASH_SZ]);
+    c
+}
+
+fn leaf_hash(leaf: &ManifestHash) -> LogHash {
+    hash(&[&[0], leaf])
+}
+
+fn node_hash(left: &LogHash, right: &LogHash) -> LogHash {
+    hash(&[&[1], left, right])
+}
+
+// The largest power of two less than `n`, which is at least 2.
+fn split(n: usize) -> usize {
+    let mut k = 1;
+    while k * 2 < n {
+        k *= 2;
+    }
+    k
+}
+
+pub fn tree_root(leaves: &[ManifestHash]) -> LogHash {
+    match leaves.len() {
+        0 => hash(&[]),
+        1 => leaf_hash(&leaves[0]),
+        n => {
+            let k = split(n);
+            node_hash(&tree_root(&leaves[..k]), &tree_root(&leaves[k..]))
+        }
+    }
+}
+
+fn subproof(m: usize, leaves: &[ManifestHash], whole: bool, proof: &mut Vec<LogHash>) {
+    let n = leaves.len();
+    if m == n {
+        if !whole {
+            proof.push(tree_root(leaves));
+        }
+        return;
+    }
+    let k = split(n);
+    if m <= k {
+        subproof(m, &leaves[..k], whole, proof);
+        proof.push(tree_root(&leaves[k..]));
+    } else {
+        subproof(m - k, &leaves[k..], false, proof);
+        proof.push(tree_root(&leaves[..k]));
+    }
+}
+
+// The proof that the tree of the first `m` leaves is a prefix of the tree
+// of all of them.
+pub fn consistency_proof(m: usize, leaves: &[ManifestHash]) -> Result<Vec<LogHash>, RepoError> {
+    if m > leaves.len() {
+        return Err(RepoError::InvalidRangeError);
+    }
+    let mut proof = Vec::new();
+    if m > 0 {
+        subproof(m, leaves, true, &mut proof);
+    }
+    Ok(proof)
+}
+
+// Whether `proof` shows that the tree of size `m` and root `old` is a
+// prefix of the tree of size `n` and root `new`.
+pub fn verify_consistency(m: u64, n: u64, old: &LogHash, new: &LogHash, proof: &[LogHash]) -> bool {
+    if m > n {
+        return false;
+    }
+    if m == n {
+        return proof.is_empty() && old == new;
+    }
+    if m == 0 {
+        return proof.is_empty();
+    }
+    let mut proof = proof.to_vec();
+    if m.is_power_of_two() {
+        proof.insert(0, *old);
+    }
+    if proof.is_empty() {
+        return false;
+    }
+    let (mut f, mut s) = (m - 1, n - 1);
+    while f & 1 == 1 {
+        f >>= 1;
+        s >>= 1;
+    }
+    let (mut fr, mut sr) = (proof[0], proof[0]);
+    for c in proof[1..].iter() {
+        if s == 0 {
+            return false;
+        }
+        if f & 1 == 1 || f == s {
+            fr = node_hash(c, &fr);
+            sr = node_hash(c, &sr);
+            while f & 1 == 0 && f != 0 {
+                f >>= 1;
+                s >>= 1;
+            }
+        } else {
+            sr = node_hash(&sr, c);
+        }
+        f >>= 1;
+        s >>= 1;
+    }
+    fr == *old && sr == *new && s == 0
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TransLog {
+    pub repo_id: RepoId,
+    pub leaves: Vec<ManifestHash>,
+}
+
+impl TransLog {
+    // Whether this log starts with every leaf of `other`.
+    pub fn extends(&self, other: &TransLog) -> bool {
+        self.repo_id == other.repo_id && self.leaves.starts_with(&other.leaves)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.fixed(TRANSLOG_MAGIC)
+            .u16(TRANSLOG_FORMAT_VERSION)
+            .fixed(&self.repo_id.bytes)
+            .u64(self.leaves.len() as u64);
+        for leaf in self.leaves.iter() {
+            e.fixed(leaf);
+        }
+        e.into_vec()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<TransLog, RepoError> {
+        let mut d = Decoder::new(buf);
+        if d.fixed(TRANSLOG_MAGIC.len())? != TRANSLOG_MAGIC {
+            return Err(RepoError::InvalidDataError);
+        }
+        if d.u16()? != TRANSLOG_FORMAT_VERSION {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        let mut repo_id: RepoId = Default::default();
+        d.fixed_into(&mut repo_id.bytes)?;
+        let n = d.u64()?;
+        if n > (d.remaining() / MANIFEST_HASH_SZ) as u64 {
+            return Err(RepoError::InvalidDataError);
+        }
+        let mut leaves = vec![[0; MANIFEST_HASH_SZ]; n as usize];
+        for leaf in leaves.iter_mut() {
+            d.fixed_into(leaf)?;
+        }
+        d.finish()?;
+        Ok(TransLog { repo_id, leaves })
+    }
+}
+
+impl Repo {
+    // The stored log, empty if no commit has written one yet.
+    pub fn translog(&self) -> Result<TransLog, RepoError> {
+        let log = match self.storage().get(TRANSLOG_FILE) {
+            Ok(buf) => TransLog::decode(&buf)?,
+            Err(ref e) if e.is_not_found() => TransLog {
+                repo_id: self.config().repo_id,
+                leaves: Vec::new(),
+            },
+            Err(e) => return Err(e),
+        };
+        if log.repo_id != self.config().repo_id {
+            return Err(RepoError::RepoMismatchError);
+        }
+        Ok(log)
+    }
+
+    // Set the tree head of `m`, which replaces the manifest `current`
+    // signed as `sm`, and append `sm` to the log.
+    pub(crate) fn append_translog(
+        &self,
+        m: &mut Manifest,
+        current: Option<(&Manifest, &[u8])>,
+    ) -> Result<(), RepoError> {
+        let (current, sm) = match current {
+            Some(current) => current,
+            None => {
+                m.log_size = 0;
+                m.log_root = tree_root(&[]);
+                return Ok(());
+            }
+        };
+        let stored = self.translog()?;
+        let size = current.log_size as usize;
+        if stored.leaves.len() < size || tree_root(&stored.leaves[..size]) != current.log_root {
+            return Err(RepoError::CorruptOrTamperedDataError);
+        }
+        let mut log = stored.clone();
+        log.leaves.truncate(size);
+        log.leaves.push(manifest_hash(sm));
+        // An interrupted commit may have appended the same leaf already.
+        if !stored.extends(&log) {
+            self.storage().put(TRANSLOG_FILE, &log.encode())?;
+        }
+        m.log_size = log.leaves.len() as u64;
+        m.log_root = tree_root(&log.leaves);
+        Ok(())
+    }
+
+    // A proof that the log of `old` leaves is a prefix of that of `new`,
+    // from the server if it can.
+    pub fn translog_proof(&self, old: u64, new: u64) -> Result<Vec<LogHash>, RepoError> {
+        match self.storage().consistency_proof(old, new) {
+            Err(RepoError::UnsupportedOperationError) => self.local_translog_proof(old, new),
+            r => r,
+        }
+    }
+
+    pub(crate) fn local_translog_proof(
+        &self,
+        old: u64,
+        new: u64,
+    ) -> Result<Vec<LogHash>, RepoError> {
+        let log = self.translog()?;
+        if new > log.leaves.len() as u64 || old > new {
+            return Err(RepoError::InvalidRangeError);
+        }
+        consistency_proof(old as usize, &log.leaves[..new as usize])
+    }
+
+    // Refuse `m` unless its tree extends the one of size `size` and root
+    // `root`, the tree head of a manifest seen before.
+    pub fn check_translog(&self, size: u64, root: &LogHash, m: &Manifest) -> Result<(), RepoError> {
+        if size == 0 {
+            return Ok(());
+        }
+        let proof = if m.log_size > size {
+            self.translog_proof(size, m.log_size)?
+        } else {
+            Vec::new()
+        };
+        if !verify_consistency(size, m.log_size, root, &m.log_root, &proof) {
+            return Err(RepoError::RollbackError);
+        }
+        Ok(())
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_consistency_proofs() {
+    let leaves: Vec<ManifestHash> = (0..20).map(|i| [i; MANIFEST_HASH_SZ]).collect();
+    assert_eq!(tree_root(&[]), hash(&[]));
+    for n in 0..leaves.len() {
+        let new = tree_root(&leaves[..n]);
+        for m in 0..=n {
+            let old = tree_root(&leaves[..m]);
+            let proof = consistency_proof(m, &leaves[..n]).unwrap();
+            assert!(verify_consistency(m as u64, n as u64, &old, &new, &proof));
+            if m > 0 && m < n {
+                // Any other history fails.
+                let mut other = leaves[..m].to_vec();
+                other[m - 1][0] ^= 1;
+                let other = tree_root(&other);
+                assert!(!verify_consistency(
+                    m as u64, n as u64, &other, &new, &proof
+                ));
+                for i in 0..proof.len() {
+                    let mut bad = proof.clone();
+                    bad[i][1] ^= 1;
+                    assert!(!verify_consistency(m as u64, n as u64, &old, &new, &bad));
+                }
+                let longer = tree_root(&leaves[..n + 1]);
+                assert!(!verify_consistency(
+                    m as u64,
+                    n as u64 + 1,
+                    &old,
+                    &longer,
+                    &proof
+                ));
+            }
+        }
+    }
+    assert!(consistency_proof(3, &leaves[..2]).is_err());
+}
+
+#[test]
+fn test_translog() {
+    use super::address::Address;
+    let (r, key) = super::test_repo();
+    assert_eq!(r.manifest().unwrap().log_size, 0);
+    let mut sms = vec![r.storage().get(super::MANIFEST_FILE).unwrap()];
+    for i in 1..4 {
+        super::gc::test_commit_tree(&r, &key, i, &[Address { bytes: [i; 32] }]);
+        sms.push(r.storage().get(super::MANIFEST_FILE).unwrap());
+    }
+    let m = r.manifest().unwrap();
+    let log = r.translog().unwrap();
+    let leaves: Vec<ManifestHash> = sms[..3].iter().map(|sm| manifest_hash(sm)).collect();
+    assert_eq!(log.leaves, leaves);
+    assert_eq!((m.log_size, m.log_root), (3, tree_root(&leaves)));
+    let first = Manifest::open(&sms[1], &key.sign_pk).unwrap();
+    r.check_translog(first.log_size, &first.log_root, &m)
+        .unwrap();
+    let buf = log.encode();
+    assert_eq!(TransLog::decode(&buf).unwrap(), log);
+    assert!(TransLog::decode(&buf[..buf.len() - 1]).is_err());
+
+    // A log rewritten under a manifest is noticed by the next commit.
+    let mut forged = log.clone();
+    forged.leaves[1][0] ^= 1;
+    r.storage()
+        .put(super::TRANSLOG_FILE, &forged.encode())
+        .unwrap();
+    match r.commit_manifest(&m, &key) {
+        Err(RepoError::CorruptOrTamperedDataError) => (),
+        _ => panic!("expected the forged log to be noticed"),
+    }
+    match r.check_translog(first.log_size, &first.log_root, &m) {
+        Err(RepoError::RollbackError) => (),
+        _ => panic!("expected the proof to fail"),
+    }
+}
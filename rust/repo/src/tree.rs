@@ -0,0 +1,836 @@
+//! Tree and snapshot objects.
+//!
+//! A tree object lists one directory. Every entry's data is referenced by
+//! address: the chunks of a file, in order, or the tree of a
+//! subdirectory. The addresses of all entries are concatenated in the
+//! leading reference list, see `object`, and each entry says how many of
+//! them are its own.
+//!
+//! ```text
+//! tree:     u32:n_refs n_refs * [32]:address
+//!           u16:format_version u32:n n * entry
+//! entry:    bytes:name u8:kind u32:mode u32:uid u32:gid
+//!           u64:mtime u32:mtime_nsec u64:size u32:n_refs bytes:target
+//!           u64:link u32:n_xattrs n_xattrs * (bytes:name bytes:value)
+//!           u32:major u32:minor u8:times
+//!           [u64:atime u32:atime_nsec] [u64:birthtime u32:birthtime_nsec]
+//! kind:     0 file, 1 directory, 2 symlink, 3 block device,
+//!           4 character device, 5 fifo, 6 socket
+//! ```
+//!
+//! Entries are strictly ascending by name. Names are raw file name bytes,
+//! never empty, `.` or `..` and without `/` or NUL, so a restore cannot be
+//! led outside its target directory. `mode` holds the permission bits,
+//! `size` the bytes stored for a file, `target` the target of a symlink.
+//! A directory has exactly one reference, a symlink none. Devices, fifos
+//! and sockets have neither references nor target, and only devices have
+//! `major` and `minor` numbers, 0 for every other kind.
+//!
+//! `link` is 0 unless a file is one of several hard links to the same
+//! inode, when every entry of the snapshot for that inode, in any tree,
+//! holds the same nonzero `link` and the same data. A backup uses the
+//! inode number where it is unique within the snapshot, so unchanged
+//! trees keep their address from one backup to the next.
+//!
+//! `xattrs` are the extended attributes of the entry, POSIX ACLs among
+//! them, strictly ascending by name, see `xattr`. Names are never empty
+//! and hold no NUL.
+//!
+//! Times are seconds and nanoseconds since 1970. Bit 0 of `times` says an
+//! access time follows and bit 1 a birth time, the other bits are 0.
+//! Backups only store them when asked to, see `backup`: access times
+//! change whenever a file is read, and with them the tree's address.
+//!
+//! A snapshot object names the root tree of one backup:
+//!
+//! ```text
+//! snapshot: u32:1 [32]:root_tree
+//!           u16:format_version u64:unix_time bytes:source str:host
+//!           u32:n_tags n_tags * str:tag
+//!           u64:files u64:dirs u64:bytes
+//!           u32:n_errors n_errors * (bytes:path str:message)
+//!           u32:n_mounts n_mounts * bytes:path
+//!           str:description u8:name_encoding
+//!           u32:n_changed n_changed * bytes:path
+//!           u32:n_users n_users * (u32:uid str:name)
+//!           u32:n_groups n_groups * (u32:gid str:name)
+//!           str:key_namespace
+//! ```
+//!
+//! `source` is the path that was backed up, `host` the name of the
+//! machine it was backed up on. Tags are 1 to 64 characters of
+//! `[a-zA-Z0-9._:=@+-]`, sorted and unique, plain names such as `daily`
+//! or labels such as `env=prod`. `errors` lists what could not be backed
+//! up, such as unreadable files, and `mounts` the mountpoints stored as
+//! empty directories because the filesystem mounted on them was not
+//! backed up, see `backup`. `changed` lists the files that kept changing
+//! while they were read, stored as last read, which may mix versions of
+//! the file. The description is free text of at most
+//! `MAX_DESCRIPTION_LEN` bytes.
+//!
+//! `users` and `groups` name the owner and group ids of the snapshot's
+//! entries on the machine backed up, strictly ascending by id, so a
+//! restore elsewhere can map them to local accounts, see `owners`. Ids
+//! without a name are left out, and names are never empty.
+//!
+//! `name_encoding` says what the names in the snapshot's trees and its
+//! paths are, as they are stored as raw bytes and never converted:
+//! `NAMES_UNIX`, 1, for the bytes of Unix file names, in whatever
+//! encoding their system used, and none of them need be utf8. Other
+//! values are left for other systems, a restore refuses names it cannot
+//! create as they are.
+//!
+//! `key_namespace` is empty if the snapshot's objects are addressed with
+//! the repository's address key, otherwise the namespace whose own key
+//! addressed them, see `policy`.
+//!
+//! An amendment object replaces the tags and description of a snapshot
+//! after the fact, leaving the snapshot object and its address as they
+//! are, and may rename its namespace. The manifest head of the snapshot
+//! names its amendment, see `manifest` and `amend`:
+//!
+//! ```text
+//! amendment: u32:1 [32]:snapshot
+//!            u16:format_version u64:unix_time
+//!            u32:n_tags n_tags * str:tag str:description
+//!            bool:renamed [str:namespace]
+//! ```
+//!
+//! `namespace` is only present if `renamed` is set. Version 1 amendments
+//! end after the description and rename nothing.
+
+use super::address::Address;
+use super::index::RepoIndex;
+use super::manifest::SnapshotHead;
+use super::namespace::Namespace;
+use super::object::{decode_refs, encode_refs, ObjectKind};
+use super::wire::{Decoder, Encoder};
+use super::{Repo, RepoError};
+use std::io;
+use tweetnacl::CryptoBoxSk;
+
+pub const TREE_FORMAT_VERSION: u16 = 1;
+pub const SNAPSHOT_FORMAT_VERSION: u16 = 1;
+pub const AMENDMENT_FORMAT_VERSION: u16 = 2;
+
+pub const NAMES_UNIX: u8 = 1;
+
+pub const MAX_TAG_LEN: usize = 64;
+pub const MAX_DESCRIPTION_LEN: usize = 4096;
+
+// Smallest encoded entry, tag, snapshot error, path, attribute and owner
+// name.
+const MIN_ENTRY_SZ: usize = 4 + 1 + 4 + 4 + 4 + 8 + 4 + 8 + 4 + 4;
+const MIN_TAG_SZ: usize = 5;
+const MIN_ERROR_SZ: usize = 8;
+const MIN_PATH_SZ: usize = 4;
+const MIN_XATTR_SZ: usize = 8;
+const MIN_NAME_SZ: usize = 9;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    BlockDevice,
+    CharDevice,
+    Fifo,
+    Socket,
+}
+
+impl EntryKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EntryKind::File => "file",
+            EntryKind::Dir => "dir",
+            EntryKind::Symlink => "symlink",
+            EntryKind::BlockDevice => "block-device",
+            EntryKind::CharDevice => "char-device",
+            EntryKind::Fifo => "fifo",
+            EntryKind::Socket => "socket",
+        }
+    }
+
+    // Devices, fifos and sockets.
+    pub fn is_special(self) -> bool {
+        self.to_u8() >= 3
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            EntryKind::File => 0,
+            EntryKind::Dir => 1,
+            EntryKind::Symlink => 2,
+            EntryKind::BlockDevice => 3,
+            EntryKind::CharDevice => 4,
+            EntryKind::Fifo => 5,
+            EntryKind::Socket => 6,
+        }
+    }
+
+    fn from_u8(v: u8) -> Result<EntryKind, RepoError> {
+        match v {
+            0 => Ok(EntryKind::File),
+            1 => Ok(EntryKind::Dir),
+            2 => Ok(EntryKind::Symlink),
+            3 => Ok(EntryKind::BlockDevice),
+            4 => Ok(EntryKind::CharDevice),
+            5 => Ok(EntryKind::Fifo),
+            6 => Ok(EntryKind::Socket),
+            _ => Err(RepoError::InvalidDataError),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TreeEntry {
+    pub name: Vec<u8>,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u64,
+    pub mtime_nsec: u32,
+    pub size: u64,
+    // The chunks of a file or the tree of a directory.
+    pub refs: Vec<Address>,
+    pub target: Vec<u8>,
+    // Shared by the hard links to one file, 0 for none.
+    pub link: u64,
+    // Extended attributes as names and values.
+    pub xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+    // The major and minor numbers of a device.
+    pub device: (u32, u32),
+    // Seconds and nanoseconds, if stored.
+    pub atime: Option<(u64, u32)>,
+    pub birthtime: Option<(u64, u32)>,
+}
+
+pub fn is_valid_name(name: &[u8]) -> bool {
+    !name.is_empty() && name != b"." && name != b".." && !name.iter().any(|b| *b == b'/' || *b == 0)
+}
+
+pub fn is_valid_tag(tag: &str) -> bool {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || "._:=@+-".contains(c);
+    !tag.is_empty() && tag.len() <= MAX_TAG_LEN && tag.chars().all(valid_char)
+}
+
+// Check tags and a description given for a snapshot.
+pub fn check_metadata(tags: &[String], description: &str) -> Result<(), RepoError> {
+    let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidInput, msg).into());
+    if let Some(t) = tags.iter().find(|t| !is_valid_tag(t)) {
+        return invalid(format!("invalid snapshot tag {:?}", t));
+    }
+    if description.len() > MAX_DESCRIPTION_LEN {
+        return invalid(format!(
+            "snapshot descriptions are at most {} bytes",
+            MAX_DESCRIPTION_LEN
+        ));
+    }
+    Ok(())
+}
+
+impl TreeEntry {
+    fn is_valid(&self) -> bool {
+        let xattr_names = self.xattrs.iter().map(|(n, _)| n);
+        is_valid_name(&self.name)
+            && xattr_names
+                .clone()
+                .all(|n| !n.is_empty() && !n.contains(&0))
+            && xattr_names
+                .clone()
+                .zip(xattr_names.skip(1))
+                .all(|(a, b)| a < b)
+            && match self.kind {
+                EntryKind::BlockDevice | EntryKind::CharDevice => true,
+                _ => self.device == (0, 0),
+            }
+            && match self.kind {
+                EntryKind::File => self.target.is_empty(),
+                EntryKind::Dir => self.refs.len() == 1 && self.target.is_empty() && self.link == 0,
+                EntryKind::Symlink => {
+                    self.refs.is_empty() && !self.target.is_empty() && self.link == 0
+                }
+                _ => self.refs.is_empty() && self.target.is_empty() && self.link == 0,
+            }
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Tree {
+    pub entries: Vec<TreeEntry>,
+}
+
+impl Tree {
+    pub fn encode(&self) -> Vec<u8> {
+        let refs: Vec<Address> = self
+            .entries
+            .iter()
+            .flat_map(|ent| ent.refs.iter().cloned())
+            .collect();
+        let mut e = Encoder::new();
+        encode_refs(&mut e, &refs);
+        e.u16(TREE_FORMAT_VERSION).u32(self.entries.len() as u32);
+        for ent in self.entries.iter() {
+            e.bytes(&ent.name)
+                .u8(ent.kind.to_u8())
+                .u32(ent.mode)
+                .u32(ent.uid)
+                .u32(ent.gid)
+                .u64(ent.mtime)
+                .u32(ent.mtime_nsec)
+                .u64(ent.size)
+                .u32(ent.refs.len() as u32)
+                .bytes(&ent.target)
+                .u64(ent.link)
+                .u32(ent.xattrs.len() as u32);
+            for (name, value) in ent.xattrs.iter() {
+                e.bytes(name).bytes(value);
+            }
+            e.u32(ent.device.0).u32(ent.device.1);
+            let times = [ent.atime, ent.birthtime];
+            e.u8(times
+                .iter()
+                .enumerate()
+                .fold(0, |bits, (i, t)| bits | ((t.is_some() as u8) << i)));
+            for (secs, nsec) in times.iter().flatten() {
+                e.u64(*secs).u32(*nsec);
+            }
+        }
+        e.into_vec()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Tree, RepoError> {
+        let refs = decode_refs(buf)?;
+        let mut d = Decoder::new(buf);
+        d.fixed(4 + 32 * refs.len())?;
+        if d.u16()? != TREE_FORMAT_VERSION {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        let n = d.count(MIN_ENTRY_SZ)?;
+        let mut entries: Vec<TreeEntry> = Vec::with_capacity(n);
+        let mut next_ref = 0;
+        for _ in 0..n {
+            let name = d.bytes()?.to_vec();
+            let kind = EntryKind::from_u8(d.u8()?)?;
+            let mode = d.u32()?;
+            let uid = d.u32()?;
+            let gid = d.u32()?;
+            let mtime = d.u64()?;
+            let mtime_nsec = d.u32()?;
+            let size = d.u64()?;
+            let n_refs = d.u32()? as usize;
+            if n_refs > refs.len() - next_ref {
+                return Err(RepoError::InvalidDataError);
+            }
+            let mut ent = TreeEntry {
+                name,
+                kind,
+                mode,
+                uid,
+                gid,
+                mtime,
+                mtime_nsec,
+                size,
+                refs: refs[next_ref..next_ref + n_refs].to_vec(),
+                target: d.bytes()?.to_vec(),
+                link: d.u64()?,
+                xattrs: Vec::new(),
+                device: (0, 0),
+                atime: None,
+                birthtime: None,
+            };
+            for _ in 0..d.count(MIN_XATTR_SZ)? {
+                let name = d.bytes()?.to_vec();
+                ent.xattrs.push((name, d.bytes()?.to_vec()));
+            }
+            ent.device = (d.u32()?, d.u32()?);
+            let times = d.u8()?;
+            if times & !3 != 0 {
+                return Err(RepoError::InvalidDataError);
+            }
+            if times & 1 != 0 {
+                ent.atime = Some((d.u64()?, d.u32()?));
+            }
+            if times & 2 != 0 {
+                ent.birthtime = Some((d.u64()?, d.u32()?));
+            }
+            next_ref += n_refs;
+            if !ent.is_valid() || entries.last().is_some_and(|last| last.name >= ent.name) {
+                return Err(RepoError::InvalidDataError);
+            }
+            entries.push(ent);
+        }
+        d.finish()?;
+        if next_ref != refs.len() {
+            return Err(RepoError::InvalidDataError);
+        }
+        Ok(Tree { entries })
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SnapshotError {
+    pub path: Vec<u8>,
+    pub message: String,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Snapshot {
+    pub root: Address,
+    pub time: u64,
+    pub source: Vec<u8>,
+    pub host: String,
+    pub tags: Vec<String>,
+    pub files: u64,
+    pub dirs: u64,
+    pub bytes: u64,
+    pub errors: Vec<SnapshotError>,
+    pub mounts: Vec<Vec<u8>>,
+    pub description: String,
+    // What file names are, see the module documentation.
+    pub name_encoding: u8,
+    // Files that may mix versions, see the module documentation.
+    pub changed: Vec<Vec<u8>>,
+    // Owner and group names by id, see the module documentation.
+    pub users: Vec<(u32, String)>,
+    pub groups: Vec<(u32, String)>,
+    // The namespace whose address key addressed the snapshot, empty for
+    // the repository's.
+    pub key_namespace: String,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Amendment {
+    pub snapshot: Address,
+    pub time: u64,
+    pub tags: Vec<String>,
+    pub description: String,
+    // The namespace the snapshot is listed under instead of its head's.
+    pub namespace: Option<Namespace>,
+}
+
+fn encode_tags(e: &mut Encoder, tags: &[String]) {
+    e.u32(tags.len() as u32);
+    for tag in tags.iter() {
+        e.str(tag);
+    }
+}
+
+fn decode_tags(d: &mut Decoder) -> Result<Vec<String>, RepoError> {
+    let n = d.count(MIN_TAG_SZ)?;
+    let mut tags: Vec<String> = Vec::with_capacity(n);
+    for _ in 0..n {
+        let tag = d.str()?;
+        if !is_valid_tag(tag) || tags.last().is_some_and(|last| &last[..] >= tag) {
+            return Err(RepoError::InvalidDataError);
+        }
+        tags.push(tag.to_string());
+    }
+    Ok(tags)
+}
+
+fn encode_names(e: &mut Encoder, names: &[(u32, String)]) {
+    e.u32(names.len() as u32);
+    for (id, name) in names.iter() {
+        e.u32(*id).str(name);
+    }
+}
+
+fn decode_names(d: &mut Decoder) -> Result<Vec<(u32, String)>, RepoError> {
+    let n = d.count(MIN_NAME_SZ)?;
+    let mut names: Vec<(u32, String)> = Vec::with_capacity(n);
+    for _ in 0..n {
+        let id = d.u32()?;
+        let name = d.str()?;
+        if name.is_empty() || names.last().is_some_and(|(last, _)| *last >= id) {
+            return Err(RepoError::InvalidDataError);
+        }
+        names.push((id, name.to_string()));
+    }
+    Ok(names)
+}
+
+fn decode_description(d: &mut Decoder) -> Result<String, RepoError> {
+    let description = d.str()?;
+    if description.len() > MAX_DESCRIPTION_LEN {
+        return Err(RepoError::InvalidDataError);
+    }
+    Ok(description.to_string())
+}
+
+impl Snapshot {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        encode_refs(&mut e, &[self.root]);
+        e.u16(SNAPSHOT_FORMAT_VERSION)
+            .u64(self.time)
+            .bytes(&self.source)
+            .str(&self.host);
+        encode_tags(&mut e, &self.tags);
+        e.u64(self.files)
+            .u64(self.dirs)
+            .u64(self.bytes)
+            .u32(self.errors.len() as u32);
+        for err in self.errors.iter() {
+            e.bytes(&err.path).str(&err.message);
+        }
+        e.u32(self.mounts.len() as u32);
+        for path in self.mounts.iter() {
+            e.bytes(path);
+        }
+        e.str(&self.description)
+            .u8(self.name_encoding)
+            .u32(self.changed.len() as u32);
+        for path in self.changed.iter() {
+            e.bytes(path);
+        }
+        encode_names(&mut e, &self.users);
+        encode_names(&mut e, &self.groups);
+        e.str(&self.key_namespace);
+        e.into_vec()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Snapshot, RepoError> {
+        let refs = decode_refs(buf)?;
+        if refs.len() != 1 {
+            return Err(RepoError::InvalidDataError);
+        }
+        let mut d = Decoder::new(buf);
+        d.fixed(4 + 32)?;
+        if d.u16()? != SNAPSHOT_FORMAT_VERSION {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        let time = d.u64()?;
+        let source = d.bytes()?.to_vec();
+        let host = d.str()?.to_string();
+        let tags = decode_tags(&mut d)?;
+        let files = d.u64()?;
+        let dirs = d.u64()?;
+        let bytes = d.u64()?;
+        let n = d.count(MIN_ERROR_SZ)?;
+        let mut errors = Vec::with_capacity(n);
+        for _ in 0..n {
+            errors.push(SnapshotError {
+                path: d.bytes()?.to_vec(),
+                message: d.str()?.to_string(),
+            });
+        }
+        let n = d.count(MIN_PATH_SZ)?;
+        let mut mounts = Vec::with_capacity(n);
+        for _ in 0..n {
+            mounts.push(d.bytes()?.to_vec());
+        }
+        let description = decode_description(&mut d)?;
+        let name_encoding = d.u8()?;
+        let n = d.count(MIN_PATH_SZ)?;
+        let mut changed = Vec::with_capacity(n);
+        for _ in 0..n {
+            changed.push(d.bytes()?.to_vec());
+        }
+        let users = decode_names(&mut d)?;
+        let groups = decode_names(&mut d)?;
+        let key_namespace = d.str()?.to_string();
+        if !key_namespace.is_empty() {
+            Namespace::new(&key_namespace)?;
+        }
+        d.finish()?;
+        Ok(Snapshot {
+            root: refs[0],
+            time,
+            source,
+            host,
+            tags,
+            files,
+            dirs,
+            bytes,
+            errors,
+            mounts,
+            description,
+            name_encoding,
+            changed,
+            users,
+            groups,
+            key_namespace,
+        })
+    }
+
+    // This snapshot with the tags and description of `amendment`.
+    pub fn amended(self, amendment: Amendment) -> Snapshot {
+        Snapshot {
+            tags: amendment.tags,
+            description: amendment.description,
+            ..self
+        }
+    }
+}
+
+impl Amendment {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        encode_refs(&mut e, &[self.snapshot]);
+        e.u16(AMENDMENT_FORMAT_VERSION).u64(self.time);
+        encode_tags(&mut e, &self.tags);
+        e.str(&self.description).bool(self.namespace.is_some());
+        if let Some(ref ns) = self.namespace {
+            ns.encode(&mut e);
+        }
+        e.into_vec()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Amendment, RepoError> {
+        let refs = decode_refs(buf)?;
+        if refs.len() != 1 {
+            return Err(RepoError::InvalidDataError);
+        }
+        let mut d = Decoder::new(buf);
+        d.fixed(4 + 32)?;
+        let version = d.u16()?;
+        if !(1..=AMENDMENT_FORMAT_VERSION).contains(&version) {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        let time = d.u64()?;
+        let tags = decode_tags(&mut d)?;
+        let description = decode_description(&mut d)?;
+        let namespace = if version >= 2 && d.bool()? {
+            Some(Namespace::decode(&mut d)?)
+        } else {
+            None
+        };
+        d.finish()?;
+        Ok(Amendment {
+            snapshot: refs[0],
+            time,
+            tags,
+            description,
+            namespace,
+        })
+    }
+}
+
+impl Repo {
+    pub fn read_tree(
+        &self,
+        index: &RepoIndex,
+        sk: &CryptoBoxSk,
+        address: &Address,
+    ) -> Result<Tree, RepoError> {
+        match self.read_object(index, sk, address)? {
+            (ObjectKind::Tree, buf) => Tree::decode(&buf),
+            _ => Err(RepoError::InvalidDataError),
+        }
+    }
+
+    pub fn read_snapshot(
+        &self,
+        index: &RepoIndex,
+        sk: &CryptoBoxSk,
+        address: &Address,
+    ) -> Result<Snapshot, RepoError> {
+        match self.read_object(index, sk, address)? {
+            (ObjectKind::Snapshot, buf) => Snapshot::decode(&buf),
+            _ => Err(RepoError::InvalidDataError),
+        }
+    }
+
+    // The snapshot `head` names, amended if it has an amendment.
+    pub fn read_head_snapshot(
+        &self,
+        index: &RepoIndex,
+        sk: &CryptoBoxSk,
+        head: &SnapshotHead,
+    ) -> Result<Snapshot, RepoError> {
+        let snapshot = self.read_snapshot(index, sk, &head.address)?;
+        match self.read_amendment(index, sk, head)? {
+            Some(amendment) => Ok(snapshot.amended(amendment)),
+            None => Ok(snapshot),
+        }
+    }
+
+    // The amendment `head` names, None if it has none.
+    pub fn read_amendment(
+        &self,
+        index: &RepoIndex,
+        sk: &CryptoBoxSk,
+        head: &SnapshotHead,
+    ) -> Result<Option<Amendment>, RepoError> {
+        let address = match head.amendment {
+            Some(ref address) => address,
+            None => return Ok(None),
+        };
+        let amendment = match self.read_object(index, sk, address)? {
+            (ObjectKind::Amendment, buf) => Amendment::decode(&buf)?,
+            _ => return Err(RepoError::InvalidDataError),
+        };
+        if amendment.snapshot != head.address {
+            return Err(RepoError::InvalidDataError);
+        }
+        Ok(Some(amendment))
+    }
+}
+
+// Tests --------------------
+
+#[cfg(test)]
+fn test_entry(name: &[u8], kind: EntryKind, refs: &[u8]) -> TreeEntry {
+    TreeEntry {
+        name: name.to_vec(),
+        kind,
+        mode: 0o644,
+        uid: 1000,
+        gid: 100,
+        mtime: 1_600_000_000,
+        mtime_nsec: 5,
+        size: 10,
+        refs: refs.iter().map(|b| Address { bytes: [*b; 32] }).collect(),
+        target: if kind == EntryKind::Symlink {
+            b"../elsewhere".to_vec()
+        } else {
+            Vec::new()
+        },
+        link: 0,
+        xattrs: Vec::new(),
+        device: (0, 0),
+        atime: None,
+        birthtime: None,
+    }
+}
+
+#[test]
+fn test_tree_round_trip() {
+    let tree = Tree {
+        entries: vec![
+            test_entry(b"a", EntryKind::File, &[1, 2, 1]),
+            test_entry(b"b", EntryKind::Dir, &[3]),
+            test_entry(b"c", EntryKind::Symlink, &[]),
+            test_entry(b"d", EntryKind::File, &[]),
+            test_entry(b"e", EntryKind::CharDevice, &[]),
+            test_entry(b"f", EntryKind::Fifo, &[]),
+        ],
+    };
+    let mut tree = tree;
+    tree.entries[4].device = (1, 3);
+    tree.entries[0].atime = Some((1_700_000_000, 999_999_999));
+    tree.entries[1].birthtime = Some((1_500_000_000, 1));
+    tree.entries[3].atime = Some((0, 0));
+    tree.entries[3].birthtime = Some((1, 2));
+    tree.entries[3].link = 77;
+    tree.entries[1].xattrs = vec![
+        (b"system.posix_acl_access".to_vec(), vec![2, 0, 0, 0]),
+        (b"user.mime".to_vec(), b"text/plain".to_vec()),
+    ];
+    let buf = tree.encode();
+    assert_eq!(Tree::decode(&buf).unwrap(), tree);
+    assert_eq!(decode_refs(&buf).unwrap().len(), 4);
+    assert!(Tree::decode(&buf[..buf.len() - 1]).is_err());
+
+    // Unknown bits in `times` are refused.
+    let one = Tree {
+        entries: vec![test_entry(b"d", EntryKind::File, &[])],
+    };
+    let mut bad = one.encode();
+    *bad.last_mut().unwrap() = 4;
+    assert!(Tree::decode(&bad).is_err());
+
+    // Names that could escape the restore directory, unsorted entries and
+    // directories without a tree are refused.
+    for name in [&b""[..], b".", b"..", b"a/b", b"a\0"].iter() {
+        let bad = Tree {
+            entries: vec![test_entry(name, EntryKind::File, &[])],
+        };
+        assert!(Tree::decode(&bad.encode()).is_err());
+    }
+    let mut unsorted = tree.clone();
+    unsorted.entries.swap(0, 3);
+    assert!(Tree::decode(&unsorted.encode()).is_err());
+    let bad = Tree {
+        entries: vec![test_entry(b"b", EntryKind::Dir, &[])],
+    };
+    assert!(Tree::decode(&bad.encode()).is_err());
+    let mut bad = tree.clone();
+    bad.entries[1].link = 1;
+    assert!(Tree::decode(&bad.encode()).is_err());
+    let mut bad = tree.clone();
+    bad.entries[1].xattrs.reverse();
+    assert!(Tree::decode(&bad.encode()).is_err());
+    bad.entries[1].xattrs = vec![(b"user.a\0".to_vec(), Vec::new())];
+    assert!(Tree::decode(&bad.encode()).is_err());
+    // Only devices have numbers, and specials have no data.
+    let mut bad = tree.clone();
+    bad.entries[5].device = (1, 3);
+    assert!(Tree::decode(&bad.encode()).is_err());
+    let mut bad = tree.clone();
+    bad.entries[4].refs = vec![Address { bytes: [1; 32] }];
+    assert!(Tree::decode(&bad.encode()).is_err());
+}
+
+#[test]
+fn test_snapshot_round_trip() {
+    let s = Snapshot {
+        root: Address { bytes: [9; 32] },
+        time: 1_600_000_000,
+        source: b"/home/user".to_vec(),
+        host: "laptop".to_string(),
+        tags: vec!["daily".to_string(), "home".to_string()],
+        files: 3,
+        dirs: 1,
+        bytes: 1234,
+        errors: vec![SnapshotError {
+            path: b"/home/user/secret".to_vec(),
+            message: "Permission denied".to_string(),
+        }],
+        mounts: vec![b"/home/user/nfs".to_vec()],
+        description: "before the upgrade".to_string(),
+        name_encoding: NAMES_UNIX,
+        changed: vec![b"/home/user/app.log".to_vec()],
+        users: vec![(0, "root".to_string()), (1000, "user".to_string())],
+        groups: vec![(100, "users".to_string())],
+        key_namespace: "laptop".to_string(),
+    };
+    let buf = s.encode();
+    assert_eq!(Snapshot::decode(&buf).unwrap(), s);
+    assert_eq!(decode_refs(&buf).unwrap(), vec![s.root]);
+    assert!(Snapshot::decode(&buf[..buf.len() - 1]).is_err());
+    for tags in [&["home", "daily"][..], &["a", "a"], &["bad tag"]].iter() {
+        let mut bad = s.clone();
+        bad.tags = tags.iter().map(|t| t.to_string()).collect();
+        assert!(Snapshot::decode(&bad.encode()).is_err());
+    }
+    for users in [&[(1, "a"), (1, "b")][..], &[(2, "a"), (1, "b")], &[(1, "")]].iter() {
+        let mut bad = s.clone();
+        bad.users = users.iter().map(|(id, n)| (*id, n.to_string())).collect();
+        assert!(Snapshot::decode(&bad.encode()).is_err());
+    }
+
+    // Names from other systems decode as they are.
+    let mut foreign = s.clone();
+    foreign.name_encoding = 7;
+    assert_eq!(Snapshot::decode(&foreign.encode()).unwrap(), foreign);
+}
+
+#[test]
+fn test_amendment_round_trip() {
+    let a = Amendment {
+        snapshot: Address { bytes: [9; 32] },
+        time: 1_600_000_000,
+        tags: vec!["env=prod".to_string(), "keep".to_string()],
+        description: "last good state".to_string(),
+        namespace: None,
+    };
+    let buf = a.encode();
+    assert_eq!(Amendment::decode(&buf).unwrap(), a);
+    let renamed = Amendment {
+        namespace: Some(Namespace::new("laptop").unwrap()),
+        ..a.clone()
+    };
+    assert_eq!(Amendment::decode(&renamed.encode()).unwrap(), renamed);
+    // Version 1 renamed nothing.
+    let mut v1 = buf[..buf.len() - 1].to_vec();
+    v1[36..38].copy_from_slice(&1u16.to_be_bytes());
+    assert_eq!(Amendment::decode(&v1).unwrap(), a);
+    assert_eq!(decode_refs(&buf).unwrap(), vec![a.snapshot]);
+    assert!(Amendment::decode(&buf[..buf.len() - 1]).is_err());
+    let mut bad = a.clone();
+    bad.description = "x".repeat(MAX_DESCRIPTION_LEN + 1);
+    assert!(Amendment::decode(&bad.encode()).is_err());
+}
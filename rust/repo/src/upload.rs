@@ -0,0 +1,509 @@
+//! Parallel upload of byte streams.
+//!
+//! `Transaction::add_stream` stores a stream as chunks in four stages
+//! joined by bounded queues:
+//!
+//! 1. The calling thread reads and chunks the stream, see `chunker`.
+//! 2. `workers` threads address each chunk, skip those the repository or
+//!    the stream already holds, and compress the rest, each with a zstd
+//!    context of its own, see `compress`.
+//! 3. `sealers` threads seal compressed chunks into packs of their own.
+//! 4. `uploads` threads put finished packs and their parity.
+//!
+//! `Transaction::add_objects` runs a batch of objects the caller already
+//! addressed through stages 2 to 4, so many small files and trees are
+//! compressed and sealed in parallel too. `UploadOptions::with_jobs`
+//! sizes the workers and sealers, it is what a `--jobs` flag sets.
+//!
+//! A stage waits while the queue to the next one is full, so however fast
+//! the stream or slow the storage, memory stays under about `queue_depth`
+//! chunks waiting for a worker and as many compressed for a sealer, a
+//! chunk and a zstd context per worker, a pack per sealer and two packs
+//! per upload thread, see `memory`.
+//!
+//! Chunk addresses are returned in stream order. On failure every stage
+//! stops early. Packs that were stored belong to the transaction either
+//! way, and are deleted if it is rolled back before they are checkpointed.
+
+use super::address::{Address, AddressKey};
+use super::chunker::Chunker;
+use super::compress::Compressor;
+use super::index::RepoIndex;
+use super::object::ObjectKind;
+use super::pack::{FinishedPack, PackId, PackWriter, PackerOptions, TocEntry, PACK_SLOTS};
+use super::parity::{parity_key, Parity};
+use super::progress::Progress;
+use super::transaction::Transaction;
+use super::{RepoError, PACKS_DIR};
+use std::collections::HashSet;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Clone, Debug)]
+pub struct UploadOptions {
+    // Threads addressing and compressing chunks.
+    pub workers: usize,
+    // Threads sealing compressed chunks into packs.
+    pub sealers: usize,
+    // Threads putting packs.
+    pub uploads: usize,
+    // Chunks waiting for a worker, and compressed ones for a sealer.
+    pub queue_depth: usize,
+    pub packer: PackerOptions,
+    // Counts the bytes of the packs put.
+    pub progress: Option<Arc<Progress>>,
+}
+
+impl UploadOptions {
+    // `jobs` workers, each with two chunks queued, and half as many
+    // sealers, sealing being faster than compressing.
+    pub fn with_jobs(jobs: usize) -> UploadOptions {
+        let jobs = jobs.max(1);
+        UploadOptions {
+            workers: jobs,
+            sealers: jobs.div_ceil(2),
+            uploads: 4,
+            queue_depth: 2 * jobs,
+            packer: Default::default(),
+            progress: None,
+        }
+    }
+}
+
+impl Default for UploadOptions {
+    fn default() -> UploadOptions {
+        UploadOptions::with_jobs(
+            thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(4),
+        )
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct StreamUpload {
+    pub addresses: Vec<Address>,
+    pub bytes: u64,
+    // Chunks not already in the repository or earlier in the stream.
+    pub new_chunks: usize,
+    pub new_bytes: u64,
+    // What the new chunks came to once compressed, see `compress`.
+    pub compressed_bytes: u64,
+}
+
+struct SealedPack {
+    id: PackId,
+    buf: Vec<u8>,
+    entries: Vec<TocEntry>,
+    size: u64,
+}
+
+// Shared by every stage, the first error stops them all.
+struct Pipeline<'p> {
+    failed: AtomicBool,
+    error: Mutex<Option<RepoError>>,
+    present: &'p RepoIndex,
+    // Addresses already taken by a worker.
+    seen: Mutex<HashSet<Address>>,
+}
+
+impl<'p> Pipeline<'p> {
+    fn fail(&self, err: RepoError) {
+        self.failed.store(true, Ordering::SeqCst);
+        let mut error = self.error.lock().unwrap();
+        if error.is_none() {
+            *error = Some(err);
+        }
+    }
+
+    fn failed(&self) -> bool {
+        self.failed.load(Ordering::SeqCst)
+    }
+}
+
+fn recv<T>(rx: &Mutex<Receiver<T>>) -> Option<T> {
+    rx.lock().unwrap().recv().ok()
+}
+
+// An object for the workers, with its address if the caller knows it.
+type Work = (ObjectKind, Option<Address>, Vec<u8>);
+
+// A new object for the sealers, with its size and as it is sealed.
+type Compressed = (ObjectKind, Address, usize, Vec<u8>);
+
+impl<'a> Transaction<'a> {
+    pub fn add_stream(
+        &mut self,
+        r: &mut dyn Read,
+        address_key: &AddressKey,
+        present: &RepoIndex,
+        opts: &UploadOptions,
+    ) -> Result<StreamUpload, RepoError> {
+        let mut chunker = Chunker::new(r, &self.repo().config().chunker)?;
+        let mut next = || Ok(chunker.next_chunk()?.map(|c| (ObjectKind::Chunk, None, c)));
+        self.upload(&mut next, address_key, present, opts)
+    }
+
+    // Store `objects`, each of `kind` at `address`, which must be their
+    // address under `address_key`.
+    pub fn add_objects(
+        &mut self,
+        objects: Vec<(Address, ObjectKind, Vec<u8>)>,
+        address_key: &AddressKey,
+        present: &RepoIndex,
+        opts: &UploadOptions,
+    ) -> Result<StreamUpload, RepoError> {
+        let mut objects = objects.into_iter();
+        let mut next = || Ok(objects.next().map(|(a, kind, data)| (kind, Some(a), data)));
+        self.upload(&mut next, address_key, present, opts)
+    }
+
+    fn upload(
+        &mut self,
+        next: &mut dyn FnMut() -> Result<Option<Work>, RepoError>,
+        address_key: &AddressKey,
+        present: &RepoIndex,
+        opts: &UploadOptions,
+    ) -> Result<StreamUpload, RepoError> {
+        let repo = self.repo();
+        let storage = repo.storage();
+        let recipients = &repo.recipients();
+        let p = Pipeline {
+            failed: AtomicBool::new(false),
+            error: Mutex::new(None),
+            present,
+            // Chunks the transaction already holds are not new either.
+            seen: Mutex::new(std::mem::take(&mut self.added)),
+        };
+        let mut upload: StreamUpload = Default::default();
+        let finished = Mutex::new(Vec::new());
+        // Each chunk's address, with its size and compressed size if new.
+        let (new_tx, new_rx) = channel::<(usize, Address, Option<(u64, u64)>)>();
+
+        thread::scope(|s| {
+            let (chunk_tx, chunk_rx) = sync_channel::<(usize, Work)>(opts.queue_depth);
+            let (compressed_tx, compressed_rx) = sync_channel::<Compressed>(opts.queue_depth);
+            let (pack_tx, pack_rx) = sync_channel::<SealedPack>(opts.uploads.max(1));
+            let chunk_rx = Arc::new(Mutex::new(chunk_rx));
+            let compressed_rx = Arc::new(Mutex::new(compressed_rx));
+            let pack_rx = Arc::new(Mutex::new(pack_rx));
+
+            for _ in 0..opts.workers.max(1) {
+                let chunk_rx = chunk_rx.clone();
+                let compressed_tx = compressed_tx.clone();
+                let new_tx = new_tx.clone();
+                let p = &p;
+                s.spawn(move || {
+                    let mut compressor = None;
+                    let mut compress = |seq: usize, (kind, address, data): Work| {
+                        let address = address.unwrap_or_else(|| address_key.address(&data));
+                        let new =
+                            !p.present.contains(&address) && p.seen.lock().unwrap().insert(address);
+                        if !new {
+                            let _ = new_tx.send((seq, address, None));
+                            return Ok(());
+                        }
+                        let compressor = match compressor {
+                            Some(ref mut c) => c,
+                            None => compressor.insert(Compressor::new()?),
+                        };
+                        let encoded = compressor.encode(&data)?;
+                        let sizes = (data.len() as u64, encoded.len() as u64);
+                        let _ = new_tx.send((seq, address, Some(sizes)));
+                        let _ = compressed_tx.send((kind, address, data.len(), encoded));
+                        Ok::<(), RepoError>(())
+                    };
+                    while let Some((seq, work)) = recv(&chunk_rx) {
+                        if p.failed() {
+                            return;
+                        }
+                        if let Err(err) = compress(seq, work) {
+                            return p.fail(err);
+                        }
+                    }
+                });
+            }
+            drop(compressed_tx);
+            drop(new_tx);
+
+            for _ in 0..opts.sealers.max(1) {
+                let compressed_rx = compressed_rx.clone();
+                let pack_tx = pack_tx.clone();
+                let p = &p;
+                s.spawn(move || {
+                    let mut w: [Option<(PackId, PackWriter<Vec<u8>>)>; PACK_SLOTS] =
+                        Default::default();
+                    let mut seal = |(kind, address, size, encoded): Compressed| {
+                        let slot = recipients.slot(kind);
+                        if w[slot].is_none() {
+                            w[slot] = Some((PackId::new(), recipients.writer(slot)?));
+                        }
+                        let (_, pw) = w[slot].as_mut().unwrap();
+                        pw.add_encoded(&address, kind, size, &encoded)?;
+                        if pw.size() >= opts.packer.target_size {
+                            let (id, pw) = w[slot].take().unwrap();
+                            let (buf, entries, size) = pw.finish()?;
+                            let _ = pack_tx.send(SealedPack {
+                                id,
+                                buf,
+                                entries,
+                                size,
+                            });
+                        }
+                        Ok::<(), RepoError>(())
+                    };
+                    while let Some(compressed) = recv(&compressed_rx) {
+                        if p.failed() {
+                            return;
+                        }
+                        if let Err(err) = seal(compressed) {
+                            return p.fail(err);
+                        }
+                    }
+                    for (id, pw) in w.iter_mut().filter_map(|w| w.take()) {
+                        match pw.finish() {
+                            Ok((buf, entries, size)) => {
+                                let _ = pack_tx.send(SealedPack {
+                                    id,
+                                    buf,
+                                    entries,
+                                    size,
+                                });
+                            }
+                            Err(err) => p.fail(err),
+                        }
+                    }
+                });
+            }
+            drop(pack_tx);
+
+            for _ in 0..opts.uploads.max(1) {
+                let pack_rx = pack_rx.clone();
+                let p = &p;
+                let finished = &finished;
+                s.spawn(move || {
+                    while let Some(sp) = recv(&pack_rx) {
+                        if p.failed() {
+                            return;
+                        }
+                        let put = || -> Result<(), RepoError> {
+                            storage.put(&format!("{}/{}", PACKS_DIR, sp.id.to_hex()), &sp.buf)?;
+                            if let Some(ref popts) = opts.packer.parity {
+                                let parity = Parity::generate(sp.id, &sp.buf, popts)?;
+                                storage.put(&parity_key(&sp.id), &parity.encode())?;
+                            }
+                            if let Some(ref progress) = opts.progress {
+                                progress.add_uploaded(sp.size);
+                            }
+                            Ok(())
+                        };
+                        // A failed put may still have stored the pack.
+                        let result = put();
+                        finished.lock().unwrap().push(FinishedPack {
+                            id: sp.id,
+                            size: sp.size,
+                            entries: sp.entries,
+                        });
+                        if let Err(err) = result {
+                            return p.fail(err);
+                        }
+                    }
+                });
+            }
+
+            let mut seq = 0;
+            while !p.failed() {
+                match next() {
+                    Ok(Some(work)) => {
+                        upload.bytes += work.2.len() as u64;
+                        if chunk_tx.send((seq, work)).is_err() {
+                            break;
+                        }
+                        seq += 1;
+                    }
+                    Ok(None) => break,
+                    Err(err) => p.fail(err),
+                }
+                if seq % 64 == 0 {
+                    // Packs put so far can be checkpointed.
+                    self.add_finished(std::mem::take(&mut *finished.lock().unwrap()));
+                    if let Err(err) = self.refresh() {
+                        p.fail(err);
+                    }
+                }
+            }
+            upload.addresses = vec![Default::default(); seq];
+        });
+
+        self.add_finished(finished.into_inner().unwrap());
+        self.added = p.seen.into_inner().unwrap();
+        if let Some(err) = p.error.into_inner().unwrap() {
+            return Err(err);
+        }
+        for (seq, address, new) in new_rx.try_iter() {
+            upload.addresses[seq] = address;
+            if let Some((size, compressed)) = new {
+                upload.new_chunks += 1;
+                upload.new_bytes += size;
+                upload.compressed_bytes += compressed;
+            }
+        }
+        Ok(upload)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_add_stream() {
+    use super::chunker::test_data;
+    use super::manifest::ChunkerParams;
+    let key = asymcrypt::Key::new();
+    let config = super::config::RepoConfig {
+        chunker: ChunkerParams {
+            min_size: 1024,
+            avg_size: 4096,
+            max_size: 16384,
+        },
+        ..Default::default()
+    };
+    let storage = Arc::new(super::storage::mem::MemStorage::new());
+    let r = super::Repo::init(storage, config, &key).unwrap();
+    let ak = AddressKey::new();
+    let opts = UploadOptions {
+        workers: 3,
+        sealers: 2,
+        uploads: 2,
+        queue_depth: 4,
+        packer: PackerOptions {
+            target_size: 50_000,
+            parity: Some(Default::default()),
+            ..Default::default()
+        },
+        progress: None,
+    };
+    let data = test_data(1 << 20, 7);
+    let mut doubled = data.clone();
+    doubled.extend_from_slice(&data);
+
+    let mut tx = r.begin(Default::default()).unwrap();
+    let up = tx
+        .add_stream(&mut &doubled[..], &ak, &Default::default(), &opts)
+        .unwrap();
+    assert_eq!(up.bytes, doubled.len() as u64);
+    // The second copy is all duplicates, bar the chunks at the join.
+    assert!(up.new_chunks <= up.addresses.len() / 2 + 2);
+    assert!(up.new_bytes <= data.len() as u64 + 2 * 16384);
+    // Bar a codec byte each, compressing never makes a chunk larger.
+    assert!(up.compressed_bytes <= up.new_bytes + up.new_chunks as u64);
+    let mut e = super::wire::Encoder::new();
+    super::object::encode_refs(&mut e, &up.addresses);
+    let tree = e.into_vec();
+    let head = super::manifest::SnapshotHead {
+        address: ak.address(&tree),
+        timestamp: 1,
+        namespace: super::namespace::Namespace::new("laptop").unwrap(),
+        retain_until: 0,
+        amendment: None,
+    };
+    tx.add(&head.address, ObjectKind::Snapshot, &tree).unwrap();
+    tx.commit(head, &key).unwrap();
+    assert!(r.list_packs().unwrap().len() > 3);
+
+    // Reading the chunks back in order gives the stream.
+    let index = r.load_index().unwrap();
+    let mut restored = Vec::new();
+    for a in up.addresses.iter() {
+        let loc = index.lookup(a).unwrap();
+        let mut pack = r.open_pack(&loc.pack_id, &key.box_sk).unwrap();
+        restored.extend(pack.read(loc.offset, loc.length).unwrap());
+    }
+    assert_eq!(restored, doubled);
+    let fsck = super::fsck::FsckOptions {
+        read_data: true,
+        ..Default::default()
+    };
+    assert!(r.fsck(Some(&key.box_sk), &fsck).unwrap().is_ok());
+
+    // Everything is present now.
+    let mut tx = r.begin(Default::default()).unwrap();
+    let again = tx.add_stream(&mut &data[..], &ak, &index, &opts).unwrap();
+    assert_eq!(again.new_chunks, 0);
+    tx.abort().unwrap();
+
+    // A failing read stops the pipeline and rolls back cleanly.
+    struct Failing(usize);
+    impl Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0 == 0 {
+                return Err(std::io::Error::other("disk error"));
+            }
+            let n = buf.len().min(self.0);
+            for b in buf[..n].iter_mut() {
+                *b = self.0 as u8;
+                self.0 -= 1;
+            }
+            Ok(n)
+        }
+    }
+    let packs = r.list_packs().unwrap().len();
+    let mut tx = r.begin(Default::default()).unwrap();
+    match tx.add_stream(&mut Failing(300_000), &ak, &index, &opts) {
+        Err(RepoError::IOError(_)) => (),
+        _ => panic!("expected the read error"),
+    }
+    drop(tx);
+    assert_eq!(r.list_packs().unwrap().len(), packs);
+    assert!(r.list_locks().unwrap().is_empty());
+}
+
+#[test]
+fn test_add_objects() {
+    let key = asymcrypt::Key::new();
+    let storage = Arc::new(super::storage::mem::MemStorage::new());
+    let r = super::Repo::init(storage, Default::default(), &key).unwrap();
+    let ak = AddressKey::new();
+    let opts = UploadOptions::with_jobs(3);
+    assert_eq!((opts.workers, opts.sealers, opts.queue_depth), (3, 2, 6));
+    let objects: Vec<(Address, ObjectKind, Vec<u8>)> = (0..100u32)
+        .map(|i| {
+            let data = i.to_le_bytes().repeat(i as usize + 1);
+            let kind = if i % 10 == 0 {
+                ObjectKind::Tree
+            } else {
+                ObjectKind::Chunk
+            };
+            (ak.address(&data), kind, data)
+        })
+        .collect();
+    let mut tx = r.begin(Default::default()).unwrap();
+    let up = tx
+        .add_objects(objects.clone(), &ak, &Default::default(), &opts)
+        .unwrap();
+    assert_eq!(up.new_chunks, 100);
+    // Each object repeats four bytes, so compresses well.
+    assert!(up.compressed_bytes < up.new_bytes / 4);
+    let again = tx
+        .add_objects(objects[..10].to_vec(), &ak, &Default::default(), &opts)
+        .unwrap();
+    assert_eq!(again.new_chunks, 0);
+    let head = super::manifest::SnapshotHead {
+        address: objects[0].0,
+        timestamp: 1,
+        namespace: super::namespace::Namespace::new("laptop").unwrap(),
+        retain_until: 0,
+        amendment: None,
+    };
+    tx.commit(head, &key).unwrap();
+
+    let index = r.load_index().unwrap();
+    for (address, kind, data) in objects.iter() {
+        assert_eq!(
+            r.read_object(&index, &key.box_sk, address).unwrap(),
+            (*kind, data.clone())
+        );
+    }
+}
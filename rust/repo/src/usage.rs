@@ -0,0 +1,177 @@
+//! Per client storage usage.
+//!
+//! When `serve` is told which client it is serving, see `ServeOptions`, it
+//! keeps a usage record for that client at `usage/<client>` and refuses
+//! uploads past the client's quota. Clients can read usage records but
+//! never write or delete them.
+//!
+//! A record counts the bytes and values the client stored, less what it
+//! deleted itself. Replacing a value counts the difference in size. Lock
+//! objects are not counted. Deletes made by another client, such as `gc`
+//! run with a maintenance identity, are not credited back to the client
+//! that stored the data, deleting a record starts it over. Usage is only
+//! approximate while one client runs several sessions at once.
+//!
+//! Client names are a single storage key component, `[a-zA-Z0-9._-]` not
+//! starting with '.'.
+//!
+//! With a quota on the whole repository the server also keeps a record of
+//! everything stored, by any client or none, at `repo-usage`. It starts
+//! from the sizes of what is stored when the quota is first enforced, and
+//! packs the server's gc sweeps are credited to it.
+//!
+//! ```text
+//! "PNBUSAGE" u16:format_version u64:stored_bytes u64:objects
+//! ```
+//!
+//! A put past a quota is refused with `QuotaExceededError`, telling the
+//! client which quota, the client's or the repository's, and how much of
+//! it is used. Clients can ask for the quotas that apply to them at any
+//! time, see `Repo::quotas`, and a backup reports the headroom it left.
+
+use super::progress::human_bytes;
+use super::storage::check_key;
+use super::wire::{Decoder, Encoder};
+use super::{Repo, RepoError, REPO_USAGE_FILE, USAGE_DIR};
+use std::fmt;
+
+pub const USAGE_FORMAT_VERSION: u16 = 1;
+const USAGE_MAGIC: &[u8] = b"PNBUSAGE";
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Usage {
+    pub stored_bytes: u64,
+    pub objects: u64,
+}
+
+impl Usage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.fixed(USAGE_MAGIC)
+            .u16(USAGE_FORMAT_VERSION)
+            .u64(self.stored_bytes)
+            .u64(self.objects);
+        e.into_vec()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Usage, RepoError> {
+        let mut d = Decoder::new(buf);
+        if d.fixed(USAGE_MAGIC.len())? != USAGE_MAGIC {
+            return Err(RepoError::InvalidDataError);
+        }
+        if d.u16()? != USAGE_FORMAT_VERSION {
+            return Err(RepoError::UnsupportedVersionError);
+        }
+        let u = Usage {
+            stored_bytes: d.u64()?,
+            objects: d.u64()?,
+        };
+        d.finish()?;
+        Ok(u)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum QuotaScope {
+    // The bytes the session's client stored.
+    Client,
+    // Everything stored in the repository.
+    Repository,
+}
+
+// A quota the server enforces, and how much of it is used.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Quota {
+    pub scope: QuotaScope,
+    pub quota: u64,
+    pub stored_bytes: u64,
+}
+
+impl QuotaScope {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            QuotaScope::Client => "client",
+            QuotaScope::Repository => "repository",
+        }
+    }
+}
+
+impl Quota {
+    // Bytes that may still be stored.
+    pub fn headroom(&self) -> u64 {
+        self.quota.saturating_sub(self.stored_bytes)
+    }
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of the {} {} quota used, {} left",
+            human_bytes(self.stored_bytes),
+            human_bytes(self.quota),
+            self.scope.name(),
+            human_bytes(self.headroom())
+        )
+    }
+}
+
+pub fn usage_key(client: &str) -> Result<String, RepoError> {
+    let key = format!("{}/{}", USAGE_DIR, client);
+    if client.contains('/') {
+        return Err(RepoError::InvalidKeyError);
+    }
+    check_key(&key)?;
+    Ok(key)
+}
+
+pub fn is_usage_key(key: &str) -> bool {
+    key == REPO_USAGE_FILE || key.starts_with(USAGE_DIR) && key[USAGE_DIR.len()..].starts_with('/')
+}
+
+impl Repo {
+    // Every usage record, by client name.
+    pub fn usage(&self) -> Result<Vec<(String, Usage)>, RepoError> {
+        let prefix = format!("{}/", USAGE_DIR);
+        let mut usage = Vec::new();
+        for k in self.storage().list_prefix(&prefix)? {
+            let u = Usage::decode(&self.storage().get(&k)?)?;
+            usage.push((k[prefix.len()..].to_string(), u));
+        }
+        Ok(usage)
+    }
+
+    // The quotas the server holds this client to, none for storage that
+    // enforces none.
+    pub fn quotas(&self) -> Result<Vec<Quota>, RepoError> {
+        match self.storage().quotas() {
+            Err(RepoError::UnsupportedOperationError) => Ok(Vec::new()),
+            result => result,
+        }
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_usage_keys() {
+    let u = Usage {
+        stored_bytes: 5,
+        objects: 1,
+    };
+    assert_eq!(Usage::decode(&u.encode()).unwrap(), u);
+    assert_eq!(usage_key("laptop").unwrap(), "usage/laptop");
+    assert!(usage_key("a/b").is_err());
+    assert!(usage_key(".hidden").is_err());
+    assert!(usage_key("").is_err());
+    assert!(is_usage_key("usage/laptop"));
+    assert!(!is_usage_key("usage"));
+    assert!(!is_usage_key("usagex/laptop"));
+    assert!(is_usage_key("repo-usage"));
+    let q = Quota {
+        scope: QuotaScope::Client,
+        quota: 2 << 30,
+        stored_bytes: 3 << 30,
+    };
+    assert_eq!(q.headroom(), 0);
+}
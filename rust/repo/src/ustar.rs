@@ -0,0 +1,176 @@
+//! The ustar header block, shared by `tar` and `archive`.
+//!
+//! A header is one 512 byte block of fixed fields. Numbers are octal
+//! digits ending in a NUL, names are NUL padded unless they fill their
+//! field. The checksum is the sum of the header's bytes with its own field
+//! taken as spaces, written as six octal digits, a NUL and a space. Data
+//! follows the header, padded to a whole block, and two zero blocks end a
+//! stream:
+//!
+//! ```text
+//! offset  size  field
+//!      0   100  name
+//!    100     8  mode
+//!    108     8  uid
+//!    116     8  gid
+//!    124    12  size
+//!    136    12  mtime
+//!    148     8  chksum
+//!    156     1  typeflag
+//!    157   100  linkname
+//!    257     8  magic "ustar\0" and version "00"
+//!    329     8  devmajor
+//!    337     8  devminor
+//!    345   155  prefix
+//! ```
+
+use std::io::{self, Write};
+
+pub const BLOCK_SZ: usize = 512;
+
+// Header field offsets and lengths.
+pub(crate) const NAME: (usize, usize) = (0, 100);
+pub(crate) const MODE: (usize, usize) = (100, 8);
+pub(crate) const UID: (usize, usize) = (108, 8);
+pub(crate) const GID: (usize, usize) = (116, 8);
+pub(crate) const SIZE: (usize, usize) = (124, 12);
+pub(crate) const MTIME: (usize, usize) = (136, 12);
+pub(crate) const CHKSUM: (usize, usize) = (148, 8);
+pub(crate) const TYPEFLAG: usize = 156;
+pub(crate) const LINKNAME: (usize, usize) = (157, 100);
+pub(crate) const MAGIC: (usize, usize) = (257, 8);
+pub(crate) const DEVMAJOR: (usize, usize) = (329, 8);
+pub(crate) const DEVMINOR: (usize, usize) = (337, 8);
+pub(crate) const PREFIX: (usize, usize) = (345, 155);
+
+// The largest value an octal field holds, one byte is the NUL.
+pub(crate) fn octal_max(field: (usize, usize)) -> u64 {
+    (1 << (3 * (field.1 - 1))) - 1
+}
+
+pub(crate) fn put(block: &mut [u8], field: (usize, usize), v: &[u8]) {
+    let n = v.len().min(field.1);
+    block[field.0..field.0 + n].copy_from_slice(&v[..n]);
+}
+
+// Values too large are left zero, for the caller to carry elsewhere.
+pub(crate) fn put_octal(block: &mut [u8], field: (usize, usize), v: u64) {
+    if v <= octal_max(field) {
+        put(
+            block,
+            field,
+            format!("{:0w$o}", v, w = field.1 - 1).as_bytes(),
+        );
+    }
+}
+
+// Write the magic and the checksum, last, once every other field is set.
+pub(crate) fn finish_header(block: &mut [u8; BLOCK_SZ]) {
+    put(block, MAGIC, b"ustar\x0000");
+    put(block, CHKSUM, b"        ");
+    let sum: u32 = block.iter().map(|b| *b as u32).sum();
+    put(block, CHKSUM, format!("{:06o}\0 ", sum).as_bytes());
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// The field up to its first NUL.
+pub(crate) fn field(block: &[u8], field: (usize, usize)) -> &[u8] {
+    let f = &block[field.0..field.0 + field.1];
+    &f[..f.iter().position(|b| *b == 0).unwrap_or(f.len())]
+}
+
+// Octal, or base-256 if the high bit of the first byte is set.
+pub(crate) fn numeric(block: &[u8], f: (usize, usize)) -> io::Result<u64> {
+    let raw = &block[f.0..f.0 + f.1];
+    if raw[0] & 0x80 != 0 {
+        if raw[0] != 0x80 {
+            return Err(invalid("negative or oversized number in tar header"));
+        }
+        return raw[1..].iter().try_fold(0u64, |v, b| {
+            v.checked_mul(256)
+                .map(|v| v + *b as u64)
+                .ok_or_else(|| invalid("oversized number in tar header"))
+        });
+    }
+    let digits = std::str::from_utf8(field(block, f))
+        .map_err(|_| invalid("bad number in tar header"))?
+        .trim_matches(' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| invalid("bad number in tar header"))
+}
+
+// Either sum, some old tars summed signed bytes.
+pub(crate) fn checksum_ok(block: &[u8]) -> bool {
+    let stored = match numeric(block, CHKSUM) {
+        Ok(v) => v,
+        Err(_) => return false,
+    };
+    let spaces = 8 * b' ' as u64;
+    let (mut unsigned, mut signed) = (spaces, spaces as i64);
+    for (i, b) in block.iter().enumerate() {
+        if i < CHKSUM.0 || i >= CHKSUM.0 + CHKSUM.1 {
+            unsigned += *b as u64;
+            signed += *b as i8 as i64;
+        }
+    }
+    stored == unsigned || stored as i64 == signed
+}
+
+// The padding after `size` bytes of data.
+pub(crate) fn padding(size: u64) -> u64 {
+    (BLOCK_SZ as u64 - size % BLOCK_SZ as u64) % BLOCK_SZ as u64
+}
+
+// Pad `size` bytes of data to a whole block.
+pub fn write_padding(w: &mut dyn Write, size: u64) -> io::Result<()> {
+    write_zeros(w, padding(size))
+}
+
+pub fn write_zeros(w: &mut dyn Write, mut n: u64) -> io::Result<()> {
+    let zeros = [0; BLOCK_SZ];
+    while n > 0 {
+        let k = n.min(BLOCK_SZ as u64) as usize;
+        w.write_all(&zeros[..k])?;
+        n -= k as u64;
+    }
+    Ok(())
+}
+
+pub fn write_end(w: &mut dyn Write) -> io::Result<()> {
+    write_zeros(w, 2 * BLOCK_SZ as u64)
+}
+
+// Tests --------------------
+
+#[test]
+fn test_ustar_header() {
+    let mut block = [0; BLOCK_SZ];
+    put(&mut block, NAME, b"a");
+    put_octal(&mut block, SIZE, octal_max(SIZE));
+    put_octal(&mut block, MTIME, octal_max(MTIME) + 1);
+    finish_header(&mut block);
+    assert!(checksum_ok(&block));
+    assert_eq!(field(&block, NAME), b"a");
+    assert_eq!(numeric(&block, SIZE).unwrap(), 0o77777777777);
+    assert_eq!(numeric(&block, MTIME).unwrap(), 0);
+    assert_eq!(&block[CHKSUM.0 + 6..CHKSUM.0 + 8], b"\0 ");
+    block[NAME.0] = b'b';
+    assert!(!checksum_ok(&block));
+
+    block[SIZE.0..SIZE.0 + SIZE.1].copy_from_slice(&[0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0]);
+    assert_eq!(numeric(&block, SIZE).unwrap(), 256);
+    block[SIZE.0] = 0xff;
+    assert!(numeric(&block, SIZE).is_err());
+
+    let mut out = Vec::new();
+    write_padding(&mut out, BLOCK_SZ as u64 + 1).unwrap();
+    assert_eq!(out.len(), BLOCK_SZ - 1);
+    write_padding(&mut out, BLOCK_SZ as u64).unwrap();
+    write_end(&mut out).unwrap();
+    assert_eq!(out.len(), 3 * BLOCK_SZ - 1);
+}
@@ -0,0 +1,468 @@
+//! Comparing a snapshot with the filesystem.
+//!
+//! `Repo::verify` walks the trees of a snapshot and a directory side by
+//! side, like `diff` walks two snapshots, and reports where they diverge
+//! as changes from the snapshot to the directory. Nothing is written, so
+//! it checks a restore, or what a backup left out since, in place:
+//!
+//! ```text
+//! added      on disk only
+//! removed    in the snapshot only
+//! modified   a file whose size or contents differ, a link whose target
+//!            does, a device whose numbers do
+//! metadata   only the mode or mtime differ, or the owner with
+//!            `VerifyOptions::owners`
+//! ```
+//!
+//! With the address key, files of the right size are also read, chunked
+//! and addressed as a backup would, and their chunks compared with those
+//! in the snapshot. That finds contents changed behind an unchanged size
+//! and mtime without fetching a single chunk, but only while the
+//! repository chunks as it did when the snapshot was taken. Without it
+//! only tree objects are read.
+//!
+//! The metadata of links is not compared, restores do not set it. A path
+//! on disk that cannot be read is recorded and not compared, only an
+//! unreadable directory to verify or errors reading the repository fail.
+//!
+//! `VerifyReport::write_ndjson` emits a record of the check, then one per
+//! divergence as `diff` does and one per error, see `json`:
+//!
+//! ```text
+//! {"type": "verify", "files": n, "dirs": n, "symlinks": n, "specials": n,
+//!  "bytes_hashed": n, "divergences": n, "errors": n}
+//! ```
+
+use super::address::{Address, AddressKey};
+use super::chunker::Chunker;
+use super::diff::{self, file_size, Change, ChangeKind};
+use super::index::RepoIndex;
+use super::json::{write_errors, Summary};
+use super::scan::{scan_dir, Listing};
+use super::special;
+use super::tree::{EntryKind, SnapshotError, TreeEntry};
+use super::{Repo, RepoError};
+use std::cmp::Ordering;
+use std::ffi::OsStr;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use tweetnacl::CryptoBoxSk;
+
+#[derive(Clone, Default, Debug)]
+pub struct VerifyOptions {
+    // Compare file owners and groups too.
+    pub owners: bool,
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct VerifyReport {
+    // Entries of the snapshot checked.
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    // Devices, fifos and sockets.
+    pub specials: u64,
+    // Bytes read from disk to compare contents.
+    pub bytes_hashed: u64,
+    // In path order.
+    pub changes: Vec<Change>,
+    pub errors: Vec<SnapshotError>,
+}
+
+impl VerifyReport {
+    pub fn write_ndjson(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            w,
+            "{{\"type\": \"verify\", \"files\": {}, \"dirs\": {}, \"symlinks\": {}, \
+             \"specials\": {}, \"bytes_hashed\": {}, \"divergences\": {}, \"errors\": {}}}",
+            self.files,
+            self.dirs,
+            self.symlinks,
+            self.specials,
+            self.bytes_hashed,
+            self.changes.len(),
+            self.errors.len()
+        )?;
+        diff::write_ndjson(w, &self.changes)?;
+        write_errors(w, &self.errors)
+    }
+
+    // Divergences count as errors, the snapshot does not match.
+    pub fn summary(&self) -> Summary {
+        Summary {
+            errors: (self.changes.len() + self.errors.len()) as u64,
+            ..Summary::new("verify")
+        }
+    }
+}
+
+fn disk_kind(meta: &Metadata) -> Option<EntryKind> {
+    let t = meta.file_type();
+    if t.is_file() {
+        Some(EntryKind::File)
+    } else if t.is_dir() {
+        Some(EntryKind::Dir)
+    } else if t.is_symlink() {
+        Some(EntryKind::Symlink)
+    } else {
+        special::kind(&t)
+    }
+}
+
+fn disk_size(meta: &Metadata) -> u64 {
+    if meta.is_file() {
+        meta.len()
+    } else {
+        0
+    }
+}
+
+struct Verifier<'a> {
+    repo: &'a Repo,
+    index: RepoIndex,
+    sk: &'a CryptoBoxSk,
+    address_key: Option<&'a AddressKey>,
+    opts: &'a VerifyOptions,
+    report: VerifyReport,
+}
+
+impl<'a> Verifier<'a> {
+    fn record(&mut self, path: &Path, message: &str) {
+        self.report.errors.push(SnapshotError {
+            path: path.as_os_str().as_bytes().to_vec(),
+            message: message.to_string(),
+        });
+    }
+
+    fn push(&mut self, path: Vec<u8>, kind: ChangeKind, entry_kind: EntryKind, sizes: (u64, u64)) {
+        self.report.changes.push(Change {
+            path,
+            kind,
+            entry_kind,
+            old_size: sizes.0,
+            new_size: sizes.1,
+        });
+    }
+
+    // Compare the tree `tree` with the directory `dir`, either may be
+    // absent. `prefix` is empty or ends in `/`.
+    fn dirs(
+        &mut self,
+        tree: Option<&Address>,
+        dir: Option<&Path>,
+        prefix: &[u8],
+    ) -> Result<(), RepoError> {
+        let a = match tree {
+            Some(address) => self.repo.read_tree(&self.index, self.sk, address)?.entries,
+            None => Vec::new(),
+        };
+        let b: Listing = match dir {
+            Some(dir) => match scan_dir(dir) {
+                Ok(listing) => listing,
+                Err(err) => {
+                    self.record(dir, &err.to_string());
+                    return Ok(());
+                }
+            },
+            None => Vec::new(),
+        };
+        // Leave out what cannot be stat'ed.
+        let b: Vec<(Vec<u8>, Metadata)> = b
+            .into_iter()
+            .filter_map(|(name, meta)| {
+                let meta = match meta {
+                    Ok(meta) => meta,
+                    Err(err) => {
+                        let path = dir.unwrap().join(&name);
+                        self.record(&path, &err.to_string());
+                        return None;
+                    }
+                };
+                disk_kind(&meta).map(|_| (name.as_bytes().to_vec(), meta))
+            })
+            .collect();
+
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len() {
+            let order = match (a.get(i), b.get(j)) {
+                (Some(x), Some(y)) => x.name.cmp(&y.0),
+                (Some(_), None) => Ordering::Less,
+                _ => Ordering::Greater,
+            };
+            match order {
+                Ordering::Less => {
+                    self.removed(&a[i], prefix)?;
+                    i += 1;
+                }
+                Ordering::Greater => {
+                    let path = dir.unwrap().join(OsStr::from_bytes(&b[j].0));
+                    self.added(&b[j].0, &b[j].1, &path, prefix)?;
+                    j += 1;
+                }
+                Ordering::Equal => {
+                    let path = dir.unwrap().join(OsStr::from_bytes(&b[j].0));
+                    self.compare(&a[i], &b[j].1, &path, prefix)?;
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn count(&mut self, ent: &TreeEntry) {
+        match ent.kind {
+            EntryKind::File => self.report.files += 1,
+            EntryKind::Dir => self.report.dirs += 1,
+            EntryKind::Symlink => self.report.symlinks += 1,
+            _ => self.report.specials += 1,
+        }
+    }
+
+    fn removed(&mut self, ent: &TreeEntry, prefix: &[u8]) -> Result<(), RepoError> {
+        self.count(ent);
+        let path = [prefix, &ent.name].concat();
+        let sizes = (file_size(ent), 0);
+        self.push(path.clone(), ChangeKind::Removed, ent.kind, sizes);
+        if ent.kind == EntryKind::Dir {
+            self.dirs(Some(&ent.refs[0]), None, &[&path[..], b"/"].concat())?;
+        }
+        Ok(())
+    }
+
+    fn added(
+        &mut self,
+        name: &[u8],
+        meta: &Metadata,
+        disk: &Path,
+        prefix: &[u8],
+    ) -> Result<(), RepoError> {
+        let kind = disk_kind(meta).unwrap();
+        let path = [prefix, name].concat();
+        self.push(path.clone(), ChangeKind::Added, kind, (0, disk_size(meta)));
+        if kind == EntryKind::Dir {
+            self.dirs(None, Some(disk), &[&path[..], b"/"].concat())?;
+        }
+        Ok(())
+    }
+
+    // Whether the file at `disk` chunks to the chunks of `ent`, None if
+    // it could not be read.
+    fn same_contents(&mut self, ent: &TreeEntry, disk: &Path, ak: &AddressKey) -> Option<bool> {
+        let mut refs = Vec::new();
+        let result = File::open(disk)
+            .map_err(RepoError::from)
+            .and_then(|f| Chunker::new(f, &self.repo.config().chunker))
+            .and_then(|mut chunker| {
+                while let Some(chunk) = chunker.next_chunk()? {
+                    self.report.bytes_hashed += chunk.len() as u64;
+                    refs.push(ak.address(&chunk));
+                }
+                Ok(())
+            });
+        match result {
+            Ok(()) => Some(refs == ent.refs),
+            Err(err) => {
+                self.record(disk, &err.to_string());
+                None
+            }
+        }
+    }
+
+    fn compare(
+        &mut self,
+        ent: &TreeEntry,
+        meta: &Metadata,
+        disk: &Path,
+        prefix: &[u8],
+    ) -> Result<(), RepoError> {
+        let kind = disk_kind(meta).unwrap();
+        if ent.kind != kind {
+            self.removed(ent, prefix)?;
+            let name = ent.name.clone();
+            return self.added(&name, meta, disk, prefix);
+        }
+        self.count(ent);
+        let path = [prefix, &ent.name].concat();
+        let sizes = (file_size(ent), disk_size(meta));
+        let content = match kind {
+            EntryKind::File if ent.size != meta.len() => true,
+            EntryKind::File => match self.address_key {
+                Some(ak) => match self.same_contents(ent, disk, ak) {
+                    Some(same) => !same,
+                    None => return Ok(()),
+                },
+                None => false,
+            },
+            EntryKind::Symlink => match fs::read_link(disk) {
+                Ok(target) => target.as_os_str().as_bytes() != &ent.target[..],
+                Err(err) => {
+                    self.record(disk, &err.to_string());
+                    return Ok(());
+                }
+            },
+            EntryKind::Dir => false,
+            _ => ent.device != special::device_numbers(meta.rdev()),
+        };
+        if content {
+            self.push(path, ChangeKind::Modified, kind, sizes);
+            return Ok(());
+        }
+        let mut metadata = kind != EntryKind::Symlink
+            && (ent.mode, ent.mtime, ent.mtime_nsec)
+                != (
+                    meta.mode() & 0o7777,
+                    meta.mtime().max(0) as u64,
+                    meta.mtime_nsec() as u32,
+                );
+        if self.opts.owners {
+            metadata |= (ent.uid, ent.gid) != (meta.uid(), meta.gid());
+        }
+        if metadata {
+            self.push(path.clone(), ChangeKind::Metadata, kind, sizes);
+        }
+        if kind == EntryKind::Dir {
+            self.dirs(Some(&ent.refs[0]), Some(disk), &[&path[..], b"/"].concat())?;
+        }
+        Ok(())
+    }
+}
+
+impl Repo {
+    // Compare `snapshot` with the directory `path`, see the module
+    // comment. Contents are only compared with `address_key`.
+    pub fn verify(
+        &self,
+        snapshot: &Address,
+        path: &Path,
+        sk: &CryptoBoxSk,
+        address_key: Option<&AddressKey>,
+        opts: &VerifyOptions,
+    ) -> Result<VerifyReport, RepoError> {
+        let index = self.load_index()?;
+        let root = self.read_snapshot(&index, sk, snapshot)?.root;
+        let own_key = self.snapshot_address_key(&index, sk, snapshot, address_key)?;
+        let address_key = own_key.as_ref().or(address_key);
+        // Fail early on a directory that cannot be read at all.
+        scan_dir(path)?;
+        let mut v = Verifier {
+            repo: self,
+            index,
+            sk,
+            address_key,
+            opts,
+            report: Default::default(),
+        };
+        v.dirs(Some(&root), Some(path), b"")?;
+        v.report.errors.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(v.report)
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_verify() {
+    use super::namespace::Namespace;
+    use std::fs::FileTimes;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    let (r, key) = super::test_repo();
+    let ak = AddressKey::new();
+    let ns = Namespace::new("laptop").unwrap();
+    let dir = super::storage::local::test_dir("verify");
+    let to = super::storage::local::test_dir("verify-to");
+    fs::create_dir_all(dir.join("sub/gone")).unwrap();
+    fs::write(dir.join("sub/gone/file"), b"bye").unwrap();
+    fs::write(dir.join("same-size"), b"hello").unwrap();
+    fs::write(dir.join("chmod"), b"mode").unwrap();
+    fs::write(dir.join("grows"), b"small").unwrap();
+    symlink("chmod", dir.join("link")).unwrap();
+    let (head, _) = r.backup(&dir, &ns, &ak, &key, &Default::default()).unwrap();
+    let verify = |path: &Path, ak: Option<&AddressKey>| {
+        r.verify(&head.address, path, &key.box_sk, ak, &Default::default())
+            .unwrap()
+    };
+
+    // The source and a restore of it match.
+    let report = verify(&dir, Some(&ak));
+    assert_eq!(report.changes, vec![]);
+    assert_eq!(
+        (
+            report.files,
+            report.dirs,
+            report.symlinks,
+            report.bytes_hashed
+        ),
+        (4, 2, 1, 17)
+    );
+    r.restore(&head.address, &to, &key.box_sk, None, &Default::default())
+        .unwrap();
+    assert_eq!(verify(&to, Some(&ak)).changes, vec![]);
+
+    let mtime = fs::metadata(dir.join("same-size"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    fs::write(dir.join("same-size"), b"HELLO").unwrap();
+    let f = File::options()
+        .write(true)
+        .open(dir.join("same-size"))
+        .unwrap();
+    f.set_times(FileTimes::new().set_modified(mtime)).unwrap();
+    fs::set_permissions(dir.join("chmod"), fs::Permissions::from_mode(0o600)).unwrap();
+    fs::write(dir.join("grows"), b"much larger now").unwrap();
+    fs::remove_dir_all(dir.join("sub/gone")).unwrap();
+    fs::remove_file(dir.join("link")).unwrap();
+    symlink("grows", dir.join("link")).unwrap();
+    fs::write(dir.join("new"), b"new").unwrap();
+
+    let summary = |report: &VerifyReport| -> Vec<(String, ChangeKind)> {
+        report
+            .changes
+            .iter()
+            .map(|c| (String::from_utf8_lossy(&c.path).into_owned(), c.kind))
+            .collect()
+    };
+    let want = |same_size: bool| {
+        let mut want = vec![
+            ("chmod".to_string(), ChangeKind::Metadata),
+            ("grows".to_string(), ChangeKind::Modified),
+            ("link".to_string(), ChangeKind::Modified),
+            ("new".to_string(), ChangeKind::Added),
+        ];
+        if same_size {
+            want.push(("same-size".to_string(), ChangeKind::Modified));
+        }
+        want.push(("sub".to_string(), ChangeKind::Metadata));
+        want.push(("sub/gone".to_string(), ChangeKind::Removed));
+        want.push(("sub/gone/file".to_string(), ChangeKind::Removed));
+        want
+    };
+    // A change behind the same size and mtime needs the contents read.
+    assert_eq!(summary(&verify(&dir, None)), want(false));
+    let report = verify(&dir, Some(&ak));
+    assert_eq!(summary(&report), want(true));
+    assert!(report.errors.is_empty());
+    assert_eq!(report.summary().exit_code(), 1);
+
+    let mut out = Vec::new();
+    report.write_ndjson(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with("{\"type\": \"verify\", \"files\": 4, \"dirs\": 2, "));
+    assert_eq!(out.lines().count(), 9);
+
+    assert!(r
+        .verify(
+            &head.address,
+            &dir.join("missing"),
+            &key.box_sk,
+            None,
+            &Default::default()
+        )
+        .is_err());
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&to).unwrap();
+}
@@ -0,0 +1,189 @@
+//! Binary encoding shared by every persistent and network object.
+//!
+//! All integers are big endian. Variable length byte strings are prefixed
+//! with their length as a u32. Decoding never panics on malformed input,
+//! it returns `RepoError::InvalidDataError` instead.
+
+use super::RepoError;
+
+#[derive(Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Default::default()
+    }
+
+    pub fn u8(&mut self, v: u8) -> &mut Encoder {
+        self.buf.push(v);
+        self
+    }
+
+    pub fn u16(&mut self, v: u16) -> &mut Encoder {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn u32(&mut self, v: u32) -> &mut Encoder {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, v: u64) -> &mut Encoder {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn bool(&mut self, v: bool) -> &mut Encoder {
+        self.u8(v as u8)
+    }
+
+    pub fn fixed(&mut self, v: &[u8]) -> &mut Encoder {
+        self.buf.extend_from_slice(v);
+        self
+    }
+
+    pub fn bytes(&mut self, v: &[u8]) -> &mut Encoder {
+        assert!(v.len() <= u32::MAX as usize);
+        self.u32(v.len() as u32);
+        self.fixed(v)
+    }
+
+    pub fn str(&mut self, v: &str) -> &mut Encoder {
+        self.bytes(v.as_bytes())
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Decoder<'a> {
+        Decoder { buf }
+    }
+
+    pub fn fixed(&mut self, n: usize) -> Result<&'a [u8], RepoError> {
+        if self.buf.len() < n {
+            return Err(RepoError::InvalidDataError);
+        }
+        let (v, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(v)
+    }
+
+    pub fn fixed_into(&mut self, out: &mut [u8]) -> Result<(), RepoError> {
+        let v = self.fixed(out.len())?;
+        out.copy_from_slice(v);
+        Ok(())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, RepoError> {
+        Ok(self.fixed(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, RepoError> {
+        let mut b = [0; 2];
+        self.fixed_into(&mut b)?;
+        Ok(u16::from_be_bytes(b))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, RepoError> {
+        let mut b = [0; 4];
+        self.fixed_into(&mut b)?;
+        Ok(u32::from_be_bytes(b))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, RepoError> {
+        let mut b = [0; 8];
+        self.fixed_into(&mut b)?;
+        Ok(u64::from_be_bytes(b))
+    }
+
+    pub fn bool(&mut self) -> Result<bool, RepoError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(RepoError::InvalidDataError),
+        }
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], RepoError> {
+        let n = self.u32()? as usize;
+        self.fixed(n)
+    }
+
+    pub fn str(&mut self) -> Result<&'a str, RepoError> {
+        std::str::from_utf8(self.bytes()?).map_err(|_| RepoError::InvalidDataError)
+    }
+
+    // Guards against allocating huge vectors from a forged count.
+    pub fn count(&mut self, min_item_size: usize) -> Result<usize, RepoError> {
+        let n = self.u32()? as usize;
+        if n.saturating_mul(min_item_size.max(1)) > self.buf.len() {
+            return Err(RepoError::InvalidDataError);
+        }
+        Ok(n)
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn finish(&self) -> Result<(), RepoError> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(RepoError::InvalidDataError)
+        }
+    }
+}
+
+// Tests --------------------
+
+#[test]
+fn test_round_trip() {
+    let mut e = Encoder::new();
+    e.u8(1)
+        .u16(2)
+        .u32(3)
+        .u64(4)
+        .bool(true)
+        .bytes(b"abc")
+        .str("def");
+    let buf = e.into_vec();
+    let mut d = Decoder::new(&buf);
+    assert_eq!(d.u8().unwrap(), 1);
+    assert_eq!(d.u16().unwrap(), 2);
+    assert_eq!(d.u32().unwrap(), 3);
+    assert_eq!(d.u64().unwrap(), 4);
+    assert!(d.bool().unwrap());
+    assert_eq!(d.bytes().unwrap(), b"abc");
+    assert_eq!(d.str().unwrap(), "def");
+    d.finish().unwrap();
+}
+
+#[test]
+fn test_truncated() {
+    let mut e = Encoder::new();
+    e.bytes(b"abcdef");
+    let buf = e.into_vec();
+    let mut d = Decoder::new(&buf[..buf.len() - 1]);
+    assert!(d.bytes().is_err());
+    let mut d = Decoder::new(&buf[..]);
+    assert!(d.count(2).is_err());
+}
@@ -0,0 +1,241 @@
+//! Extended attributes.
+//!
+//! Backups store the extended attributes of every file, directory and
+//! symlink in its tree entry, see `tree`, and restores set them again.
+//! POSIX ACLs are the `system.posix_acl_access` and
+//! `system.posix_acl_default` attributes on Linux, so they come along
+//! with the rest.
+//!
+//! `XattrOptions::skip` leaves attributes out by name, with the patterns
+//! of `exclude`: `security.selinux` or `user.*` say. Labels that only
+//! make sense on the machine backed up are typically skipped for its
+//! namespace, see `settings`. A filesystem without extended attributes
+//! has none to store, and one refusing them on restore fails only the
+//! attributes, which are recorded like any other metadata not restored.
+//!
+//! The attributes of a symlink are its own, never those of its target,
+//! unless a backup follows it.
+//!
+//! Attributes are only read and written on Linux. Elsewhere backups store
+//! none, and a restore fails the attributes of each entry that has some,
+//! as on a filesystem refusing them.
+
+use super::exclude::Pattern;
+use std::io;
+use std::path::Path;
+#[cfg(target_os = "linux")]
+use {
+    libc::{c_char, c_void, ENODATA, ERANGE},
+    std::ffi::CString,
+    std::os::unix::ffi::OsStrExt,
+};
+
+#[derive(Clone, Debug)]
+pub struct XattrOptions {
+    // Store, or restore, extended attributes at all.
+    pub enabled: bool,
+    // Names to leave out.
+    pub skip: Vec<Pattern>,
+}
+
+impl Default for XattrOptions {
+    fn default() -> XattrOptions {
+        XattrOptions {
+            enabled: true,
+            skip: Vec::new(),
+        }
+    }
+}
+
+impl XattrOptions {
+    // Options leaving out the names matching `patterns`.
+    pub fn skipping(patterns: &[String]) -> XattrOptions {
+        XattrOptions {
+            enabled: true,
+            skip: patterns
+                .iter()
+                .map(|p| Pattern::new(p.as_bytes()))
+                .collect(),
+        }
+    }
+
+    pub fn wanted(&self, name: &[u8]) -> bool {
+        let names = [name.to_vec()];
+        self.enabled && !self.skip.iter().any(|p| p.matches(&names, false))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn c_bytes(b: &[u8]) -> io::Result<CString> {
+    CString::new(b).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))
+}
+
+// Call `f` with a buffer of the size it asks for until what it reads
+// fits.
+#[cfg(target_os = "linux")]
+fn read_sized<F>(mut f: F) -> io::Result<Vec<u8>>
+where
+    F: FnMut(*mut c_void, usize) -> isize,
+{
+    loop {
+        let n = f(std::ptr::null_mut(), 0);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; n as usize];
+        let m = f(buf.as_mut_ptr() as *mut c_void, buf.len());
+        if m >= 0 {
+            buf.truncate(m as usize);
+            return Ok(buf);
+        }
+        // Grown meanwhile.
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+// The attributes of `path` that `opts` wants, sorted by name.
+#[cfg(target_os = "linux")]
+pub fn read(path: &Path, follow: bool, opts: &XattrOptions) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    if !opts.enabled {
+        return Ok(Vec::new());
+    }
+    let p = c_bytes(path.as_os_str().as_bytes())?;
+    let names = read_sized(|buf, size| unsafe {
+        if follow {
+            libc::listxattr(p.as_ptr(), buf as *mut c_char, size)
+        } else {
+            libc::llistxattr(p.as_ptr(), buf as *mut c_char, size)
+        }
+    });
+    let names = match names {
+        Ok(names) => names,
+        Err(ref e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut attrs = Vec::new();
+    for name in names.split(|b| *b == 0).filter(|n| !n.is_empty()) {
+        if !opts.wanted(name) {
+            continue;
+        }
+        let n = c_bytes(name)?;
+        let value = read_sized(|buf, size| unsafe {
+            if follow {
+                libc::getxattr(p.as_ptr(), n.as_ptr(), buf, size)
+            } else {
+                libc::lgetxattr(p.as_ptr(), n.as_ptr(), buf, size)
+            }
+        });
+        match value {
+            Ok(value) => attrs.push((name.to_vec(), value)),
+            // Removed meanwhile.
+            Err(ref e) if e.raw_os_error() == Some(ENODATA) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    attrs.sort();
+    attrs.dedup_by(|a, b| a.0 == b.0);
+    Ok(attrs)
+}
+
+// Set the attributes `opts` wants on `path`, not following a symlink.
+#[cfg(target_os = "linux")]
+pub fn write(path: &Path, attrs: &[(Vec<u8>, Vec<u8>)], opts: &XattrOptions) -> io::Result<()> {
+    let p = c_bytes(path.as_os_str().as_bytes())?;
+    for (name, value) in attrs.iter().filter(|(name, _)| opts.wanted(name)) {
+        let n = c_bytes(name)?;
+        let r = unsafe {
+            libc::lsetxattr(
+                p.as_ptr(),
+                n.as_ptr(),
+                value.as_ptr() as *const c_void,
+                value.len(),
+                0,
+            )
+        };
+        if r != 0 {
+            let err = io::Error::last_os_error();
+            return Err(io::Error::new(
+                err.kind(),
+                format!("setting {}: {}", String::from_utf8_lossy(name), err),
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn read(
+    _path: &Path,
+    _follow: bool,
+    _opts: &XattrOptions,
+) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    Ok(Vec::new())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn write(_path: &Path, attrs: &[(Vec<u8>, Vec<u8>)], opts: &XattrOptions) -> io::Result<()> {
+    if attrs.iter().any(|(name, _)| opts.wanted(name)) {
+        return Err(io::Error::from_raw_os_error(libc::ENOTSUP));
+    }
+    Ok(())
+}
+
+// Tests --------------------
+
+#[cfg(test)]
+pub(crate) fn test_supported(path: &Path) -> bool {
+    match write(
+        path,
+        &[(b"user.probe".to_vec(), Vec::new())],
+        &Default::default(),
+    ) {
+        Ok(()) => true,
+        Err(ref e) if e.raw_os_error() == Some(libc::ENOTSUP) => false,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+#[test]
+fn test_xattr() {
+    use std::fs;
+    let dir = super::storage::local::test_dir("xattr");
+    fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("file");
+    fs::write(&file, b"data").unwrap();
+    if !test_supported(&file) {
+        return fs::remove_dir_all(&dir).unwrap();
+    }
+    let attrs = vec![
+        (b"user.b".to_vec(), b"two".to_vec()),
+        (b"user.a".to_vec(), vec![0, 1, 2]),
+        (b"user.empty".to_vec(), Vec::new()),
+    ];
+    write(&file, &attrs, &Default::default()).unwrap();
+    let read_all = read(&file, false, &Default::default()).unwrap();
+    let names: Vec<&[u8]> = read_all.iter().map(|(n, _)| &n[..]).collect();
+    assert_eq!(
+        names,
+        vec![&b"user.a"[..], b"user.b", b"user.empty", b"user.probe"]
+    );
+    assert_eq!(read_all[0].1, vec![0, 1, 2]);
+
+    let opts = XattrOptions::skipping(&["user.[be]*".to_string()]);
+    let some = read(&file, false, &opts).unwrap();
+    assert_eq!(some.len(), 2);
+    let none = XattrOptions {
+        enabled: false,
+        ..Default::default()
+    };
+    assert!(read(&file, false, &none).unwrap().is_empty());
+
+    // A symlink's own attributes, or its target's when followed.
+    let link = dir.join("link");
+    std::os::unix::fs::symlink("file", &link).unwrap();
+    assert!(read(&link, false, &Default::default()).unwrap().is_empty());
+    assert_eq!(read(&link, true, &Default::default()).unwrap(), read_all);
+    assert!(read(&dir.join("missing"), false, &Default::default()).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -39,6 +39,7 @@ impl CryptoBoxNonce {
 
 #[derive(Clone)]
 #[derive(Default)]
+#[derive(PartialEq, Eq, Hash, Debug)]
 pub struct CryptoBoxPk {
     pub bytes: [u8; crypto_box_curve25519xsalsa20poly1305_PUBLICKEYBYTES as usize],
 }
@@ -76,6 +77,7 @@ pub fn boxed_crypto_box_keypair() -> (Box<CryptoBoxPk>, Box<CryptoBoxSk>) {
 
 #[derive(Clone)]
 #[derive(Default)]
+#[derive(PartialEq, Eq, Hash, Debug)]
 pub struct CryptoSignPk {
     pub bytes: [u8; crypto_sign_ed25519_PUBLICKEYBYTES as usize],
 }
@@ -207,12 +209,94 @@ pub fn crypto_box_open(
     }
 }
 
+pub const CRYPTO_SECRETBOX_ZEROBYTES: usize =
+    crypto_secretbox_xsalsa20poly1305_ZEROBYTES as usize;
+pub const CRYPTO_SECRETBOX_BOXZEROBYTES: usize =
+    crypto_secretbox_xsalsa20poly1305_BOXZEROBYTES as usize;
+
+#[derive(Default)]
+pub struct CryptoSecretboxKey {
+    pub bytes: [u8; crypto_secretbox_xsalsa20poly1305_KEYBYTES as usize],
+}
+
+impl CryptoSecretboxKey {
+    pub fn new() -> CryptoSecretboxKey {
+        let mut k: CryptoSecretboxKey = Default::default();
+        random_bytes(&mut k.bytes[..]);
+        k
+    }
+}
+
+impl Drop for CryptoSecretboxKey {
+    fn drop(&mut self) {
+        // XXX This may be optimized away, how to ensure wiping of memory
+        // It is not totally critical but nice to have.
+        self.bytes = [0; crypto_secretbox_xsalsa20poly1305_KEYBYTES as usize];
+    }
+}
+
+pub fn crypto_secretbox(c: &mut [u8], m: &[u8], n: &CryptoBoxNonce, k: &CryptoSecretboxKey) {
+    // Contract from nacl api.
+    assert!(c.len() >= m.len());
+    assert!(m.len() >= CRYPTO_SECRETBOX_ZEROBYTES);
+    for i in 0..CRYPTO_SECRETBOX_ZEROBYTES {
+        assert!(m[i] == 0);
+    }
+
+    unsafe {
+        assert!(
+            0 == crypto_secretbox_xsalsa20poly1305_tweet(
+                c.as_mut_ptr(),
+                m.as_ptr(),
+                m.len() as u64,
+                n.bytes.as_ptr(),
+                k.bytes.as_ptr()
+            )
+        );
+    }
+}
+
+pub fn crypto_secretbox_open(
+    m: &mut [u8],
+    c: &[u8],
+    n: &CryptoBoxNonce,
+    k: &CryptoSecretboxKey,
+) -> bool {
+    // Contract from nacl api.
+    assert!(m.len() >= c.len());
+    if c.len() < CRYPTO_SECRETBOX_ZEROBYTES {
+        return false;
+    }
+
+    unsafe {
+        0 == crypto_secretbox_xsalsa20poly1305_tweet_open(
+            m.as_mut_ptr(),
+            c.as_ptr(),
+            c.len() as u64,
+            n.bytes.as_ptr(),
+            k.bytes.as_ptr(),
+        )
+    }
+}
+
+pub const CRYPTO_HASH_BYTES: usize = crypto_hash_sha512_BYTES as usize;
+
+pub fn crypto_hash(out: &mut [u8; CRYPTO_HASH_BYTES], m: &[u8]) {
+    unsafe {
+        assert!(0 == crypto_hash_sha512_tweet(out.as_mut_ptr(), m.as_ptr(), m.len() as u64));
+    }
+}
+
+pub fn random_bytes(buf: &mut [u8]) {
+    let mut rng = OsRng::new().expect("Error opening random number generator");
+    rng.fill_bytes(buf);
+}
+
 // Defined for tweetnacl to call.
 #[no_mangle]
 pub extern "C" fn randombytes(p: *mut u8, sz: usize) -> usize {
-    let mut rng = OsRng::new().expect("Error opening random number generator");
     let buf = unsafe { std::slice::from_raw_parts_mut(p, sz) };
-    rng.fill_bytes(buf);
+    random_bytes(buf);
     0
 }
 
@@ -257,6 +341,37 @@ fn test_crypto_sign() {
     assert_eq!(m1, m2[0..m2sz]);
 }
 
+#[test]
+fn test_crypto_secretbox() {
+    const MSIZE: usize = CRYPTO_SECRETBOX_ZEROBYTES + 64;
+    let mut m1: [u8; MSIZE] = [5; MSIZE];
+    let mut m2: [u8; MSIZE] = [0; MSIZE];
+    let mut c: [u8; MSIZE] = [0; MSIZE];
+    let k = CryptoSecretboxKey::new();
+    let n = CryptoBoxNonce::new();
+
+    for b in m1[..CRYPTO_SECRETBOX_ZEROBYTES].iter_mut() {
+        *b = 0;
+    }
+    crypto_secretbox(&mut c[..], &m1, &n, &k);
+    assert!(crypto_secretbox_open(&mut m2[..], &c, &n, &k));
+    assert_eq!(
+        m1[CRYPTO_SECRETBOX_ZEROBYTES..],
+        m2[CRYPTO_SECRETBOX_ZEROBYTES..]
+    );
+
+    c[MSIZE - 1] ^= 1;
+    assert!(!crypto_secretbox_open(&mut m2[..], &c, &n, &k));
+}
+
+#[test]
+fn test_crypto_hash() {
+    let mut h = [0; CRYPTO_HASH_BYTES];
+    crypto_hash(&mut h, b"abc");
+    // First bytes of the FIPS 180-2 sha512("abc") test vector.
+    assert_eq!(h[..8], [0xdd, 0xaf, 0x35, 0xa1, 0x93, 0x61, 0x7a, 0xba]);
+}
+
 #[test]
 fn test_nonce_inc() {
     let mut n = CryptoBoxNonce::new();